@@ -0,0 +1,42 @@
+use std::{
+    env,
+    process::Command,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Embeds the git commit, build time and compiler version for `/version`.
+fn main() {
+    let sha = env::var("GIT_SHA")
+        .ok()
+        .or_else(|| run("git", &["rev-parse", "HEAD"]))
+        .unwrap_or_else(|| "unknown".into());
+    // honour reproducible-build timestamps when the packager sets one
+    let built_at = env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|s| s.parse::<u64>().ok())
+        .unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_secs())
+        });
+    let rustc = run(
+        &env::var("RUSTC").unwrap_or_else(|_| "rustc".into()),
+        &["--version"],
+    )
+    .unwrap_or_else(|| "unknown".into());
+
+    println!("cargo:rustc-env=BUILD_GIT_SHA={}", sha);
+    println!("cargo:rustc-env=BUILD_TIMESTAMP={}", built_at);
+    println!("cargo:rustc-env=BUILD_RUSTC={}", rustc);
+    println!("cargo:rerun-if-env-changed=GIT_SHA");
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs/heads");
+}
+
+fn run(program: &str, args: &[&str]) -> Option<String> {
+    let out = Command::new(program).args(args).output().ok()?;
+    out.status
+        .success()
+        .then(|| String::from_utf8_lossy(&out.stdout).trim().to_string())
+}
@@ -0,0 +1,210 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use serde_json::Value;
+use sqlx::PgPool;
+use tracing::warn;
+
+use crate::ShortenError;
+
+/// How often the plans are checked again after the one at startup.
+pub const INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// A query the listings and stats run, with a typical parameter, and the
+/// index created on startup that should keep it off a sequential scan.
+struct Probe {
+    query: &'static str,
+    sql: &'static str,
+    param: &'static str,
+    table: &'static str,
+    index: &'static str,
+}
+
+const PROBES: &[Probe] = &[
+    Probe {
+        query: "owned_links",
+        sql: "SELECT id FROM urls WHERE owner = $1 ORDER BY created_at DESC, id DESC",
+        param: "1",
+        table: "urls",
+        index: "urls_owner",
+    },
+    Probe {
+        query: "tagged_links",
+        sql: "SELECT link_id FROM link_tags WHERE tag = $1",
+        param: "campaign",
+        table: "link_tags",
+        index: "link_tags_tag",
+    },
+    Probe {
+        query: "link_aliases",
+        sql: "SELECT slug FROM slugs WHERE link_id = $1",
+        param: "abc123",
+        table: "slugs",
+        index: "slugs_link_id",
+    },
+    Probe {
+        query: "daily_stats",
+        sql: "SELECT day, clicks FROM link_uniques WHERE link_id = $1 ORDER BY day",
+        param: "abc123",
+        table: "link_uniques",
+        index: "link_uniques_pkey",
+    },
+    Probe {
+        query: "link_history",
+        sql: "SELECT old_url FROM url_history WHERE link_id = $1 ORDER BY id",
+        param: "abc123",
+        table: "url_history",
+        index: "url_history_link_id",
+    },
+];
+
+/// A query planned as a sequential scan over a table big enough for that
+/// to hurt.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub struct IndexWarning {
+    pub query: &'static str,
+    pub table: String,
+    /// The planner's estimate, as of the table's last analyze.
+    pub rows: i64,
+    /// The index that should serve the query. If it exists the planner
+    /// passed it over, often for stale statistics.
+    pub index: &'static str,
+    pub index_exists: bool,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub struct Health {
+    pub checked_at: DateTime<Utc>,
+    pub min_rows: i64,
+    pub warnings: Vec<IndexWarning>,
+}
+
+/// Explains a fixed set of queries now and then and warns of the ones
+/// scanning a table of at least `min_rows` rows. Only the plans are read,
+/// in a read-only transaction; nothing is run or changed.
+#[derive(Debug, Clone)]
+pub struct IndexAdvisor {
+    min_rows: i64,
+    latest: Arc<RwLock<Option<Health>>>,
+}
+
+impl IndexAdvisor {
+    pub fn new(min_rows: u64) -> Self {
+        Self {
+            min_rows: i64::try_from(min_rows).unwrap_or(i64::MAX),
+            latest: Default::default(),
+        }
+    }
+
+    /// The latest analysis, `None` before the first one.
+    pub fn latest(&self) -> Option<Health> {
+        self.latest.read().unwrap().clone()
+    }
+
+    /// Analyzes the plans, logs a warning for each finding and keeps the
+    /// result as the latest.
+    pub async fn check(&self, db: &PgPool) -> Result<Health, ShortenError> {
+        let mut tx = db.begin().await?;
+        sqlx::query("SET TRANSACTION READ ONLY")
+            .execute(&mut *tx)
+            .await?;
+        let rows: HashMap<String, i64> = sqlx::query_as(
+            "SELECT relname::TEXT, reltuples::BIGINT FROM pg_class
+             WHERE relkind = 'r' AND relnamespace = current_schema()::regnamespace",
+        )
+        .fetch_all(&mut *tx)
+        .await?
+        .into_iter()
+        .collect();
+        let indexes: HashSet<String> = sqlx::query_scalar(
+            "SELECT indexname::TEXT FROM pg_indexes WHERE schemaname = current_schema()",
+        )
+        .fetch_all(&mut *tx)
+        .await?
+        .into_iter()
+        .collect();
+        let mut warnings = Vec::new();
+        for probe in PROBES {
+            // tables of features never used may not exist on old databases
+            let Some(&table_rows) = rows.get(probe.table) else {
+                continue;
+            };
+            if table_rows < self.min_rows {
+                continue;
+            }
+            let sqlx::types::Json(plan): sqlx::types::Json<Value> =
+                sqlx::query_scalar(&format!("EXPLAIN (FORMAT JSON) {}", probe.sql))
+                    .bind(probe.param)
+                    .fetch_one(&mut *tx)
+                    .await?;
+            let mut scanned = Vec::new();
+            seq_scans(&plan, &mut scanned);
+            if !scanned.contains(&probe.table) {
+                continue;
+            }
+            let warning = IndexWarning {
+                query: probe.query,
+                table: probe.table.to_string(),
+                rows: table_rows,
+                index: probe.index,
+                index_exists: indexes.contains(probe.index),
+            };
+            warn!(
+                query = warning.query,
+                table = %warning.table,
+                rows = warning.rows,
+                index = warning.index,
+                index_exists = warning.index_exists,
+                "Query plans a sequential scan over a large table"
+            );
+            warnings.push(warning);
+        }
+        tx.rollback().await?;
+        let health = Health {
+            checked_at: Utc::now(),
+            min_rows: self.min_rows,
+            warnings,
+        };
+        *self.latest.write().unwrap() = Some(health.clone());
+        Ok(health)
+    }
+
+    /// Checks every `every`, starting now.
+    pub async fn run(self, db: PgPool, every: Duration) {
+        let mut interval = tokio::time::interval(every);
+        loop {
+            interval.tick().await;
+            if let Err(e) = self.check(&db).await {
+                warn!("Failed to check query plans: {}", e);
+            }
+        }
+    }
+}
+
+/// The relations of the sequential scans anywhere in an `EXPLAIN (FORMAT
+/// JSON)` plan.
+fn seq_scans<'a>(plan: &'a Value, scanned: &mut Vec<&'a str>) {
+    match plan {
+        Value::Array(nodes) => nodes.iter().for_each(|node| seq_scans(node, scanned)),
+        Value::Object(node) => {
+            if node.get("Node Type").and_then(Value::as_str) == Some("Seq Scan") {
+                if let Some(relation) = node.get("Relation Name").and_then(Value::as_str) {
+                    scanned.push(relation);
+                }
+            }
+            for key in ["Plan", "Plans"] {
+                if let Some(child) = node.get(key) {
+                    seq_scans(child, scanned);
+                }
+            }
+        }
+        _ => {}
+    }
+}
@@ -0,0 +1,126 @@
+use sqlx::PgPool;
+
+use crate::{ShortenError, RESERVED_IDS, RESERVED_SEGMENTS};
+
+pub const MAX_ALIAS_LEN: usize = 64;
+
+/// Every link answers to its own id plus any aliases added later. Each
+/// slug maps to the `urls` record it redirects to; a link's own id is the
+/// slug equal to its `link_id`.
+pub async fn init(db: &PgPool) -> Result<(), ShortenError> {
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS slugs (
+            slug TEXT PRIMARY KEY,
+            link_id TEXT NOT NULL
+        )",
+    )
+    .execute(db)
+    .await?;
+    sqlx::query("CREATE INDEX IF NOT EXISTS slugs_link_id ON slugs (link_id)")
+        .execute(db)
+        .await?;
+    // links created before slugs existed, or by an older instance mid-deploy
+    sqlx::query(
+        "INSERT INTO slugs (slug, link_id)
+         SELECT id, id FROM urls u
+         WHERE id IS NOT NULL AND NOT EXISTS (SELECT 1 FROM slugs s WHERE s.slug = u.id)
+         ON CONFLICT DO NOTHING",
+    )
+    .execute(db)
+    .await?;
+    Ok(())
+}
+
+/// Letters, digits, `-` and `_`, so aliases stay a single path segment.
+/// Without dots no alias ends in [`crate::slug::JSON_SUFFIX`], and none may
+/// be one of the segments links have routes under either.
+pub fn is_valid(alias: &str) -> bool {
+    problem(alias).is_none()
+}
+
+/// Why `alias` is no valid alias, see [`is_valid`]. Only ASCII is taken:
+/// Unicode would be percent-encoded in paths and `Location` headers, and
+/// `%` itself in one would make the alias and its encoding two slugs.
+pub fn problem(alias: &str) -> Option<&'static str> {
+    if alias.is_empty() {
+        Some("it is empty")
+    } else if !alias.is_ascii() || alias.contains('%') {
+        Some("only ASCII letters, digits, - and _ are taken, neither Unicode nor percent-encoding")
+    } else if alias.len() > MAX_ALIAS_LEN {
+        Some("it is longer than 64 characters")
+    } else if !alias
+        .bytes()
+        .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+    {
+        Some("only letters, digits, - and _ are taken")
+    } else if RESERVED_IDS.contains(&alias) || RESERVED_SEGMENTS.contains(&alias) {
+        Some("it is a reserved path")
+    } else {
+        None
+    }
+}
+
+/// Refuses an invalid alias, saying why.
+pub fn validate(alias: &str) -> Result<(), ShortenError> {
+    match problem(alias) {
+        Some(reason) => Err(ShortenError::InvalidAlias {
+            alias: alias.to_string(),
+            reason,
+        }),
+        None => Ok(()),
+    }
+}
+
+/// The link id a slug belongs to.
+pub async fn resolve(db: &PgPool, slug: &str) -> Result<Option<String>, ShortenError> {
+    let link: Option<(String,)> = sqlx::query_as("SELECT link_id FROM slugs WHERE slug = $1")
+        .bind(slug)
+        .fetch_optional(db)
+        .await?;
+    Ok(link.map(|(id,)| id))
+}
+
+/// Whether a link answers to `slug` already, as its id or an alias.
+pub async fn is_taken(db: &PgPool, slug: &str) -> Result<bool, ShortenError> {
+    let taken = sqlx::query_scalar(
+        "SELECT EXISTS (SELECT 1 FROM slugs WHERE slug = $1)
+            OR EXISTS (SELECT 1 FROM urls WHERE id = $1)",
+    )
+    .bind(slug)
+    .fetch_one(db)
+    .await?;
+    Ok(taken)
+}
+
+/// The link's own id first, then its aliases.
+pub async fn list(db: &PgPool, link_id: &str) -> Result<Vec<String>, ShortenError> {
+    let slugs: Vec<(String,)> =
+        sqlx::query_as("SELECT slug FROM slugs WHERE link_id = $1 ORDER BY slug <> link_id, slug")
+            .bind(link_id)
+            .fetch_all(db)
+            .await?;
+    Ok(slugs.into_iter().map(|(s,)| s).collect())
+}
+
+/// Returns false if the slug is already in use.
+pub async fn add(db: &PgPool, link_id: &str, alias: &str) -> Result<bool, ShortenError> {
+    let ret = sqlx::query(
+        "INSERT INTO slugs (slug, link_id) VALUES ($1, $2) ON CONFLICT (slug) DO NOTHING",
+    )
+    .bind(alias)
+    .bind(link_id)
+    .execute(db)
+    .await?;
+    Ok(ret.rows_affected() > 0)
+}
+
+/// Removes an alias; a link's own id can't be removed this way. Returns
+/// whether the alias existed.
+pub async fn remove(db: &PgPool, link_id: &str, alias: &str) -> Result<bool, ShortenError> {
+    let ret = sqlx::query("DELETE FROM slugs WHERE slug = $1 AND link_id = $2 AND slug <> link_id")
+        .bind(alias)
+        .bind(link_id)
+        .execute(db)
+        .await?;
+    Ok(ret.rows_affected() > 0)
+}
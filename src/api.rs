@@ -0,0 +1,194 @@
+//! The request and response bodies of the endpoints the client calls,
+//! shared by the server and the client so they can't drift apart.
+
+use std::collections::BTreeMap;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::{error::ErrorBody, platform::PlatformTargets};
+
+/// Statuses a link may redirect with.
+pub const REDIRECT_STATUSES: [u16; 4] = [301, 302, 307, 308];
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub struct ShortReq {
+    pub url: String,
+    /// Per-request override of `UPGRADE_INSECURE`.
+    #[serde(default)]
+    pub upgrade_insecure: Option<bool>,
+    /// The link stops resolving (410) this many seconds after creation.
+    /// Without a key it does after `ANONYMOUS_LINK_TTL_SECS` anyway, the
+    /// most it may ask for.
+    #[serde(default)]
+    pub expires_in_secs: Option<u64>,
+    /// Set to false to redirect without the short link's query string.
+    #[serde(default)]
+    pub forward_query: Option<bool>,
+    /// Labels to find the link by later; added to any it already has.
+    #[serde(default)]
+    pub tags: Vec<String>,
+    /// Per-request override of `DEDUPE`. With false the link is new even if
+    /// the url was shortened before, sharing neither the id nor its clicks.
+    #[serde(default)]
+    pub dedupe: Option<bool>,
+    /// Private context, only ever shown to authenticated callers. Kept as is
+    /// when the url already had a link with notes.
+    #[serde(default)]
+    pub notes: Option<String>,
+    /// Id for the new link instead of a generated one; 409 if it's taken.
+    /// Always creates a link, whatever `dedupe` says.
+    #[serde(default)]
+    pub alias: Option<String>,
+    /// Public blurb shown by `/:id/info` and listings. Kept as is when the
+    /// url already had a link with one.
+    #[serde(default)]
+    pub description: Option<String>,
+    /// Give the link a `<id>.<signature>` slug that can't be guessed or
+    /// forged. Always creates a new link.
+    #[serde(default)]
+    pub signed: bool,
+    /// Send iOS and Android visitors elsewhere than `url`. Always creates
+    /// a new link.
+    #[serde(default)]
+    pub platform_targets: Option<PlatformTargets>,
+    /// Send the visitors of some countries elsewhere than `url`: ISO 3166
+    /// codes like `DE` and `US` to their destinations, and `default`, which
+    /// is required, for everyone else. Can't go with `platform_targets`.
+    /// Always creates a new link.
+    #[serde(default)]
+    pub geo_targets: Option<BTreeMap<String, String>>,
+    /// One of [`REDIRECT_STATUSES`], 302 by default. 307 and 308 keep the
+    /// method and body of the request. Always creates a new link.
+    #[serde(default)]
+    pub redirect_status: Option<u16>,
+    /// Redirects the link takes before it answers 410, one for a one-time
+    /// link. Can't go with a permanent `redirect_status`, which browsers
+    /// would follow without asking again. Always creates a new link.
+    #[serde(default)]
+    pub max_uses: Option<u32>,
+    /// More destinations, picked by redirecting with `?i=1`, `?i=2` and so
+    /// on, `url` being `?i=0` and where the link goes without `i`. Always
+    /// creates a new link.
+    #[serde(default)]
+    pub urls: Vec<String>,
+    /// Clicks past which the link keeps working but alerts, once. Kept as
+    /// is when the url already had a link with one.
+    #[serde(default)]
+    pub click_cap: Option<u64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub struct ShortRes {
+    pub url: String,
+    /// Whether the submitted `http://` destination was stored as `https://`.
+    pub upgraded: bool,
+    /// Every id the link answers to, its own first, then any aliases.
+    pub slugs: Vec<String>,
+    /// All of the link's tags, including ones from earlier requests.
+    pub tags: Vec<String>,
+    /// False when the url already had a link and that one was returned.
+    pub created: bool,
+    pub created_at: DateTime<Utc>,
+    pub description: Option<String>,
+    /// When the link stops resolving, `null` if it never does.
+    #[serde(default)]
+    pub expires_at: Option<DateTime<Utc>>,
+    /// Whether the lifetime asked for was longer than a link created
+    /// without a key may have, and was cut to that.
+    #[serde(default)]
+    pub clamped: bool,
+    /// Authorizes changing and deleting this link, handed out once, when
+    /// `MANAGEMENT_TOKENS` is on and an anonymous call created it.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub management_token: Option<String>,
+    /// The link's `GET /api/links/:id/stats`, when shortened with
+    /// `?include=stats`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stats_url: Option<String>,
+    /// The link's `GET /api/links/:id/qr`, when shortened with
+    /// `?include=qr`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub qr_url: Option<String>,
+}
+
+/// A `POST /api/link-kits` body: the link to shorten and the assets to
+/// bundle with it.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub struct KitReq {
+    pub link: ShortReq,
+    #[serde(default)]
+    pub include: Vec<KitPart>,
+    /// Pixels per module of the QR code, capped at [`crate::qr::MAX_SCALE`].
+    #[serde(default)]
+    pub qr_scale: Option<u32>,
+}
+
+/// An asset a link kit can include.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum KitPart {
+    Qr,
+}
+
+/// A shortened link with the assets asked for. An asset that couldn't be
+/// made carries its error instead, without failing the rest.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub struct LinkKit {
+    pub id: String,
+    #[serde(flatten)]
+    pub link: ShortRes,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub qr: Option<KitAsset<QrImage>>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum KitAsset<T> {
+    // first, so an error is never read as an asset
+    Failed(ErrorBody),
+    Ready(T),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub struct QrImage {
+    pub scale: u32,
+    pub png_base64: String,
+}
+
+/// The answer to `/:id.json`, where the redirect would have gone.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub struct Resolved {
+    pub id: String,
+    pub url: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub struct LinkStats {
+    pub id: String,
+    pub slugs: Vec<String>,
+    /// Redirects through any of the slugs.
+    pub clicks: i64,
+    /// Estimated distinct visitors, within a few percent. `null` unless
+    /// `UNIQUES_SALT` is set.
+    pub uniques: Option<u64>,
+    pub notes: Option<String>,
+    /// The window repeated redirects of a visitor are counted once within,
+    /// `null` if every redirect counts. Only says how new clicks are
+    /// counted: those before it was turned on or changed counted as it was
+    /// then.
+    #[serde(default)]
+    pub click_dedup_secs: Option<u64>,
+    /// Clicks per destination of a link with geo targets: the country codes
+    /// visitors were sent to theirs by, and `default`. Absent until one of
+    /// them is clicked.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub geo_clicks: Option<BTreeMap<String, i64>>,
+}
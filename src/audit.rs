@@ -0,0 +1,36 @@
+use serde_json::Value;
+use sqlx::PgPool;
+
+use crate::ShortenError;
+
+pub async fn init(db: &PgPool) -> Result<(), ShortenError> {
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS audit_log (
+            id BIGSERIAL PRIMARY KEY,
+            actor TEXT NOT NULL,
+            action TEXT NOT NULL,
+            details JSONB NOT NULL,
+            created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+        )",
+    )
+    .execute(db)
+    .await?;
+    Ok(())
+}
+
+/// Records that `actor`, an [`owner`](crate::auth::ApiKey::owner), did
+/// `action`, with what it was asked and what it took in `details`.
+pub async fn record(
+    db: &PgPool,
+    actor: &str,
+    action: &str,
+    details: Value,
+) -> Result<(), ShortenError> {
+    sqlx::query("INSERT INTO audit_log (actor, action, details) VALUES ($1, $2, $3)")
+        .bind(actor)
+        .bind(action)
+        .bind(details)
+        .execute(db)
+        .await?;
+    Ok(())
+}
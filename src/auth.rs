@@ -0,0 +1,385 @@
+use argon2::{
+    password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Argon2,
+};
+use axum::{
+    async_trait,
+    extract::FromRequestParts,
+    http::{header::AUTHORIZATION, request::Parts, HeaderMap, StatusCode},
+};
+use chrono::{DateTime, Utc};
+use nanoid::nanoid;
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+
+use crate::{AppState, ShortenError, StatusCodeError};
+
+const KEY_PREFIX: &str = "sk";
+/// Starts management tokens, telling them apart from API keys.
+const MANAGEMENT_PREFIX: &str = "mt_";
+const LOOKUP_LEN: usize = 8;
+const SECRET_LEN: usize = 32;
+/// nanoid's default alphabet without `_`, which separates the key parts.
+const KEY_ALPHABET: [char; 63] = [
+    '-', '0', '1', '2', '3', '4', '5', '6', '7', '8', '9', 'a', 'b', 'c', 'd', 'e', 'f', 'g', 'h',
+    'i', 'j', 'k', 'l', 'm', 'n', 'o', 'p', 'q', 'r', 's', 't', 'u', 'v', 'w', 'x', 'y', 'z', 'A',
+    'B', 'C', 'D', 'E', 'F', 'G', 'H', 'I', 'J', 'K', 'L', 'M', 'N', 'O', 'P', 'Q', 'R', 'S', 'T',
+    'U', 'V', 'W', 'X', 'Y', 'Z',
+];
+
+/// Scopes are cumulative: `admin` implies `write`, which implies `read`.
+/// `direct` stands apart, implied by `admin` only.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Scope {
+    Read,
+    Write,
+    Admin,
+    /// Follows links past the confirmation pages of redirect policies.
+    Direct,
+}
+
+impl Scope {
+    fn as_str(self) -> &'static str {
+        match self {
+            Scope::Read => "read",
+            Scope::Write => "write",
+            Scope::Admin => "admin",
+            Scope::Direct => "direct",
+        }
+    }
+
+    fn parse(s: &str) -> Option<Self> {
+        match s {
+            "read" => Some(Scope::Read),
+            "write" => Some(Scope::Write),
+            "admin" => Some(Scope::Admin),
+            "direct" => Some(Scope::Direct),
+            _ => None,
+        }
+    }
+}
+
+/// A verified API key presented by the caller.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub struct ApiKey {
+    /// `None` for the legacy `API_KEY` from the environment.
+    pub id: Option<i64>,
+    pub label: String,
+    pub scopes: Vec<Scope>,
+}
+
+impl ApiKey {
+    pub fn has(&self, scope: Scope) -> bool {
+        self.scopes.iter().any(|s| match (*s, scope) {
+            (Scope::Direct, _) => scope == Scope::Direct,
+            (_, Scope::Direct) => *s == Scope::Admin,
+            _ => *s >= scope,
+        })
+    }
+
+    /// Recorded as the owner of links created with this key: its id, or
+    /// `API_KEY` for the legacy key.
+    pub fn owner(&self) -> String {
+        match self.id {
+            Some(id) => id.to_string(),
+            None => self.label.clone(),
+        }
+    }
+
+    pub fn require(&self, scope: Scope) -> Result<(), ShortenError> {
+        if self.has(scope) {
+            Ok(())
+        } else {
+            Err(StatusCodeError(StatusCode::FORBIDDEN).into())
+        }
+    }
+}
+
+#[derive(Debug, Serialize, sqlx::FromRow)]
+#[serde(rename_all = "snake_case")]
+pub struct KeyRecord {
+    pub id: i64,
+    pub label: String,
+    pub scopes: Vec<String>,
+    pub created_at: DateTime<Utc>,
+    pub revoked_at: Option<DateTime<Utc>>,
+}
+
+#[async_trait]
+impl FromRequestParts<AppState> for ApiKey {
+    type Rejection = ShortenError;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        ApiKey::presented(&parts.headers, state)
+            .await?
+            .ok_or_else(|| StatusCodeError(StatusCode::UNAUTHORIZED).into())
+    }
+}
+
+impl ApiKey {
+    /// The key `headers` present, for handlers that only sometimes need
+    /// one. `None` without a bearer token, 401 for one that isn't a key.
+    pub async fn presented(
+        headers: &HeaderMap,
+        state: &AppState,
+    ) -> Result<Option<Self>, ShortenError> {
+        let Some(token) = bearer_token(headers) else {
+            return Ok(None);
+        };
+        if let Some(legacy) = state.config.api_key.as_deref() {
+            if constant_time_eq(token.as_bytes(), legacy.as_bytes()) {
+                return Ok(Some(ApiKey {
+                    id: None,
+                    label: "API_KEY".into(),
+                    scopes: vec![Scope::Admin],
+                }));
+            }
+        }
+        match verify(&state.db.db, token).await? {
+            Some(key) => Ok(Some(key)),
+            None => Err(StatusCodeError(StatusCode::UNAUTHORIZED).into()),
+        }
+    }
+}
+
+/// The caller of a route managing one link: an API key, or a management
+/// token handed out with the link. The token is the handler's to check,
+/// as only it knows the link.
+pub enum Manager {
+    Key(ApiKey),
+    Token(String),
+}
+
+impl Manager {
+    /// Names the caller in a link's history: the key's owner, or
+    /// `management_token`.
+    pub fn actor(&self) -> String {
+        match self {
+            Manager::Key(key) => key.owner(),
+            Manager::Token(_) => "management_token".into(),
+        }
+    }
+}
+
+#[async_trait]
+impl FromRequestParts<AppState> for Manager {
+    type Rejection = ShortenError;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        if let Some(token) =
+            bearer_token(&parts.headers).filter(|t| t.starts_with(MANAGEMENT_PREFIX))
+        {
+            return Ok(Manager::Token(token.to_string()));
+        }
+        ApiKey::from_request_parts(parts, state)
+            .await
+            .map(Manager::Key)
+    }
+}
+
+/// Identifies the caller when a key is presented while letting anonymous
+/// requests through. A presented but invalid key is still a 401.
+pub struct OptionalApiKey(pub Option<ApiKey>);
+
+#[async_trait]
+impl FromRequestParts<AppState> for OptionalApiKey {
+    type Rejection = ShortenError;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        if !parts.headers.contains_key(AUTHORIZATION) {
+            return Ok(OptionalApiKey(None));
+        }
+        ApiKey::from_request_parts(parts, state)
+            .await
+            .map(|key| OptionalApiKey(Some(key)))
+    }
+}
+
+/// Extractor guarding admin-only routes: 401 without a valid key, 403 when
+/// the key lacks the `admin` scope.
+pub struct Admin;
+
+#[async_trait]
+impl FromRequestParts<AppState> for Admin {
+    type Rejection = ShortenError;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        ApiKey::from_request_parts(parts, state)
+            .await?
+            .require(Scope::Admin)?;
+        Ok(Admin)
+    }
+}
+
+pub async fn init(db: &PgPool) -> Result<(), ShortenError> {
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS api_keys (
+            id BIGSERIAL PRIMARY KEY,
+            lookup TEXT NOT NULL UNIQUE,
+            key_hash TEXT NOT NULL,
+            label TEXT NOT NULL,
+            scopes TEXT[] NOT NULL,
+            created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+            revoked_at TIMESTAMPTZ
+        )",
+    )
+    .execute(db)
+    .await?;
+    Ok(())
+}
+
+/// Creates a key and returns its id with the plaintext secret, which is
+/// never stored and can't be recovered later.
+pub async fn create(
+    db: &PgPool,
+    label: &str,
+    scopes: &[Scope],
+) -> Result<(i64, String), ShortenError> {
+    let lookup = nanoid!(LOOKUP_LEN, &KEY_ALPHABET);
+    let secret = nanoid!(SECRET_LEN, &KEY_ALPHABET);
+    let hash = hash_secret(&secret).await?;
+    let scopes: Vec<&str> = scopes.iter().map(|s| s.as_str()).collect();
+    let (id,): (i64,) = sqlx::query_as(
+        "INSERT INTO api_keys (lookup, key_hash, label, scopes) VALUES ($1, $2, $3, $4) RETURNING id",
+    )
+    .bind(&lookup)
+    .bind(hash)
+    .bind(label)
+    .bind(scopes)
+    .fetch_one(db)
+    .await?;
+    Ok((id, format!("{}_{}_{}", KEY_PREFIX, lookup, secret)))
+}
+
+pub async fn list(db: &PgPool) -> Result<Vec<KeyRecord>, ShortenError> {
+    let keys = sqlx::query_as(
+        "SELECT id, label, scopes, created_at, revoked_at FROM api_keys ORDER BY id",
+    )
+    .fetch_all(db)
+    .await?;
+    Ok(keys)
+}
+
+/// Returns whether an active key was revoked.
+pub async fn revoke(db: &PgPool, id: i64) -> Result<bool, ShortenError> {
+    let ret =
+        sqlx::query("UPDATE api_keys SET revoked_at = now() WHERE id = $1 AND revoked_at IS NULL")
+            .bind(id)
+            .execute(db)
+            .await?;
+    Ok(ret.rows_affected() > 0)
+}
+
+/// Whether `owner` names an active key, one that links can be given to.
+pub async fn is_active(db: &PgPool, owner: &str) -> Result<bool, ShortenError> {
+    let active = sqlx::query_scalar(
+        "SELECT EXISTS (SELECT 1 FROM api_keys WHERE id::TEXT = $1 AND revoked_at IS NULL)",
+    )
+    .bind(owner)
+    .fetch_one(db)
+    .await?;
+    Ok(active)
+}
+
+pub fn parse_scopes(scopes: &str) -> Result<Vec<Scope>, ShortenError> {
+    scopes
+        .split(',')
+        .map(|s| {
+            Scope::parse(s.trim())
+                .ok_or_else(|| ShortenError::Config(format!("unknown scope {:?}", s)))
+        })
+        .collect()
+}
+
+async fn verify(db: &PgPool, token: &str) -> Result<Option<ApiKey>, ShortenError> {
+    let Some((lookup, secret)) = token
+        .strip_prefix(KEY_PREFIX)
+        .and_then(|t| t.strip_prefix('_'))
+        .and_then(|t| t.split_once('_'))
+    else {
+        return Ok(None);
+    };
+    let row: Option<(i64, String, String, Vec<String>)> = sqlx::query_as(
+        "SELECT id, key_hash, label, scopes FROM api_keys WHERE lookup = $1 AND revoked_at IS NULL",
+    )
+    .bind(lookup)
+    .fetch_optional(db)
+    .await?;
+    let Some((id, hash, label, scopes)) = row else {
+        return Ok(None);
+    };
+    Ok(verify_secret(secret, &hash).await.then(|| ApiKey {
+        id: Some(id),
+        label,
+        scopes: scopes.iter().filter_map(|s| Scope::parse(s)).collect(),
+    }))
+}
+
+/// A new management token and the hash to store for it.
+pub async fn management_token() -> Result<(String, String), ShortenError> {
+    let token = format!(
+        "{}{}",
+        MANAGEMENT_PREFIX,
+        nanoid!(SECRET_LEN, &KEY_ALPHABET)
+    );
+    let hash = hash_secret(&token).await?;
+    Ok((token, hash))
+}
+
+/// Argon2 takes tens of milliseconds of CPU, so it runs on the blocking
+/// pool rather than stalling the redirects sharing a worker thread.
+async fn hash_secret(secret: &str) -> Result<String, ShortenError> {
+    let secret = secret.to_string();
+    tokio::task::spawn_blocking(move || {
+        let salt = SaltString::generate(&mut OsRng);
+        Ok(Argon2::default()
+            .hash_password(secret.as_bytes(), &salt)
+            .map_err(|e| ShortenError::Config(format!("failed to hash key: {}", e)))?
+            .to_string())
+    })
+    .await
+    .map_err(std::io::Error::other)?
+}
+
+/// Whether `secret` is the one `hash`, a PHC string, was made from. On the
+/// blocking pool, like [`hash_secret`].
+pub async fn verify_secret(secret: &str, hash: &str) -> bool {
+    let (secret, hash) = (secret.to_string(), hash.to_string());
+    tokio::task::spawn_blocking(move || {
+        PasswordHash::new(&hash)
+            .map(|hash| {
+                Argon2::default()
+                    .verify_password(secret.as_bytes(), &hash)
+                    .is_ok()
+            })
+            .unwrap_or(false)
+    })
+    .await
+    .unwrap_or(false)
+}
+
+fn bearer_token(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(AUTHORIZATION)?
+        .to_str()
+        .ok()?
+        .strip_prefix("Bearer ")
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
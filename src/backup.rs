@@ -0,0 +1,630 @@
+use std::{
+    collections::HashMap,
+    io::{self, Read},
+    path::Path,
+};
+
+use axum::{
+    body::{Body, Bytes},
+    http::StatusCode,
+};
+use chrono::{DateTime, Utc};
+use futures_util::{StreamExt, TryStreamExt};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use sqlx::PgPool;
+use tokio::{io::AsyncWriteExt, sync::mpsc};
+use tracing::{info, warn};
+
+use crate::{error::StatusCodeError, jobs::Job, ShortenError};
+
+/// Version of the archive layout, in the manifest.
+pub const FORMAT: u32 = 1;
+
+/// The last entry of an archive, listing the others.
+pub const MANIFEST: &str = "manifest.json";
+
+/// Rows per file. A part is built, checksummed and restored as a whole,
+/// so this bounds the memory either takes.
+pub const PART_ROWS: usize = 1000;
+
+/// The job kind restores are listed under in `/api/jobs`.
+pub const RESTORE_KIND: &str = "restore";
+
+const BLOCK: usize = 512;
+
+/// A table backed up, with the order its rows are dumped in so the same
+/// data always makes the same archive.
+#[derive(Debug, Clone, Copy)]
+pub struct Table {
+    pub name: &'static str,
+    order_by: &'static str,
+    /// Column whose sequence is moved past the restored rows.
+    serial: Option<&'static str>,
+}
+
+/// What a backup holds: the links, their aliases and tags, and the
+/// redirect policies. Keys, quotas and the logs and counters derived from
+/// traffic are left out.
+pub const TABLES: &[Table] = &[
+    Table {
+        name: "urls",
+        order_by: "id",
+        serial: None,
+    },
+    Table {
+        name: "slugs",
+        order_by: "slug",
+        serial: None,
+    },
+    Table {
+        name: "link_tags",
+        order_by: "link_id, tag",
+        serial: None,
+    },
+    Table {
+        name: "link_urls",
+        order_by: "link_id, idx",
+        serial: None,
+    },
+    Table {
+        name: "redirect_policies",
+        order_by: "id",
+        serial: Some("id"),
+    },
+];
+
+fn table(name: &str) -> Option<&'static Table> {
+    TABLES.iter().find(|t| t.name == name)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Manifest {
+    pub format: u32,
+    pub created_at: DateTime<Utc>,
+    /// Every other entry of the archive, in order.
+    pub files: Vec<FileEntry>,
+}
+
+/// A part of a table's rows, one JSON object per line.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileEntry {
+    pub name: String,
+    pub table: String,
+    pub rows: u64,
+    /// Hex SHA-256 of the file's contents.
+    pub sha256: String,
+}
+
+impl Manifest {
+    /// Rows of each table.
+    pub fn rows(&self) -> HashMap<&str, u64> {
+        let mut rows = HashMap::new();
+        for file in &self.files {
+            *rows.entry(file.table.as_str()).or_default() += file.rows;
+        }
+        rows
+    }
+}
+
+/// Dumped rows of `table`, as they'll be written to its `index`th file.
+#[derive(Debug)]
+pub struct Part {
+    pub table: &'static str,
+    pub index: usize,
+    pub data: Vec<u8>,
+    pub rows: u64,
+}
+
+impl Part {
+    fn new(table: &'static str, index: usize) -> Self {
+        Self {
+            table,
+            index,
+            data: Vec::new(),
+            rows: 0,
+        }
+    }
+
+    fn push(&mut self, row: &str) {
+        self.data.extend_from_slice(row.as_bytes());
+        self.data.push(b'\n');
+        self.rows += 1;
+    }
+
+    pub fn name(&self) -> String {
+        format!("{}/{:06}.ndjson", self.table, self.index)
+    }
+
+    pub fn entry(&self) -> FileEntry {
+        FileEntry {
+            name: self.name(),
+            table: self.table.to_string(),
+            rows: self.rows,
+            sha256: sha256(&self.data),
+        }
+    }
+}
+
+pub fn sha256(data: &[u8]) -> String {
+    Sha256::digest(data)
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// An entry of a tar archive: a ustar header, `data` and the padding to
+/// the next block. `name` fits in the header's 100 bytes.
+pub fn tar_entry(name: &str, data: &[u8], mtime: i64) -> Vec<u8> {
+    let mut header = [0u8; BLOCK];
+    header[..name.len()].copy_from_slice(name.as_bytes());
+    octal(&mut header[100..108], 0o644);
+    octal(&mut header[108..116], 0);
+    octal(&mut header[116..124], 0);
+    octal(&mut header[124..136], data.len() as u64);
+    octal(&mut header[136..148], mtime.max(0) as u64);
+    header[156] = b'0';
+    header[257..263].copy_from_slice(b"ustar\0");
+    header[263..265].copy_from_slice(b"00");
+    let checksum = checksum(&header);
+    octal(&mut header[148..155], checksum);
+    header[155] = b' ';
+    let mut entry = header.to_vec();
+    entry.extend_from_slice(data);
+    entry.resize(entry.len().next_multiple_of(BLOCK), 0);
+    entry
+}
+
+/// The two zero blocks ending an archive.
+pub fn tar_end() -> Vec<u8> {
+    vec![0; 2 * BLOCK]
+}
+
+/// Zero-padded octal, NUL terminated.
+fn octal(field: &mut [u8], value: u64) {
+    let width = field.len() - 1;
+    let digits = format!("{:0width$o}", value);
+    field[..width].copy_from_slice(digits.as_bytes());
+    field[width] = 0;
+}
+
+/// Sum of the header's bytes, those of the checksum field counted as
+/// spaces.
+fn checksum(header: &[u8; BLOCK]) -> u64 {
+    header
+        .iter()
+        .enumerate()
+        .map(|(i, &b)| if (148..156).contains(&i) { b' ' } else { b } as u64)
+        .sum()
+}
+
+/// Reads back the regular files of a tar archive, one at a time.
+pub struct TarReader<R> {
+    inner: R,
+}
+
+impl<R: Read> TarReader<R> {
+    pub fn new(inner: R) -> Self {
+        Self { inner }
+    }
+
+    /// The next file's name and contents, `None` at the end of the archive.
+    pub fn next_entry(&mut self) -> Result<Option<(String, Vec<u8>)>, String> {
+        let mut header = [0u8; BLOCK];
+        if let Err(e) = self.inner.read_exact(&mut header) {
+            return match e.kind() {
+                // archives cut short of their end blocks end here too
+                io::ErrorKind::UnexpectedEof => Err("archive ends without end blocks".into()),
+                _ => Err(e.to_string()),
+            };
+        }
+        if header.iter().all(|&b| b == 0) {
+            return Ok(None);
+        }
+        let stored = parse_octal(&header[148..156]).ok_or("invalid header checksum")?;
+        if stored != checksum(&header) {
+            return Err("header checksum mismatch".into());
+        }
+        let name = field(&header[..100]);
+        let prefix = field(&header[345..500]);
+        let name = match prefix.is_empty() {
+            true => name,
+            false => format!("{}/{}", prefix, name),
+        };
+        if !matches!(header[156], b'0' | 0) {
+            return Err(format!("{} isn't a regular file", name));
+        }
+        let size = parse_octal(&header[124..136]).ok_or("invalid entry size")?;
+        let mut data = Vec::new();
+        (&mut self.inner)
+            .take(size)
+            .read_to_end(&mut data)
+            .map_err(|e| e.to_string())?;
+        if data.len() as u64 != size {
+            return Err(format!("{} is cut short", name));
+        }
+        let padding = (size as usize).next_multiple_of(BLOCK) - size as usize;
+        io::copy(&mut (&mut self.inner).take(padding as u64), &mut io::sink())
+            .map_err(|e| e.to_string())?;
+        Ok(Some((name, data)))
+    }
+}
+
+fn field(bytes: &[u8]) -> String {
+    let end = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+    String::from_utf8_lossy(&bytes[..end]).into_owned()
+}
+
+fn parse_octal(bytes: &[u8]) -> Option<u64> {
+    let text = field(bytes);
+    u64::from_str_radix(text.trim_matches([' ', '\0']), 8).ok()
+}
+
+fn count_rows(data: &[u8]) -> u64 {
+    data.split(|&b| b == b'\n')
+        .filter(|line| !line.is_empty())
+        .count() as u64
+}
+
+/// Checks an archive against its manifest: every file listed is there,
+/// whole and with the rows it says, of a table backed up, and there is no
+/// other. Says what's wrong otherwise.
+pub fn verify(reader: impl Read) -> Result<Manifest, String> {
+    let mut tar = TarReader::new(reader);
+    let mut found = HashMap::new();
+    let mut manifest = None;
+    while let Some((name, data)) = tar.next_entry()? {
+        if manifest.is_some() {
+            return Err(format!("{} comes after the manifest", name));
+        }
+        if name == MANIFEST {
+            let parsed: Manifest =
+                serde_json::from_slice(&data).map_err(|e| format!("invalid manifest: {}", e))?;
+            manifest = Some(parsed);
+            continue;
+        }
+        if found
+            .insert(name.clone(), (sha256(&data), count_rows(&data)))
+            .is_some()
+        {
+            return Err(format!("{} is in the archive twice", name));
+        }
+    }
+    let manifest = manifest.ok_or("the archive has no manifest")?;
+    if manifest.format != FORMAT {
+        return Err(format!("unsupported format {}", manifest.format));
+    }
+    for file in &manifest.files {
+        if table(&file.table).is_none() {
+            return Err(format!("{} is of unknown table {}", file.name, file.table));
+        }
+        if !file.name.starts_with(&format!("{}/", file.table)) {
+            return Err(format!("{} isn't a file of {}", file.name, file.table));
+        }
+        let (sha256, rows) = found
+            .remove(&file.name)
+            .ok_or_else(|| format!("{} is missing", file.name))?;
+        if sha256 != file.sha256 {
+            return Err(format!("{} doesn't match its checksum", file.name));
+        }
+        if rows != file.rows {
+            return Err(format!(
+                "{} has {} rows, not {}",
+                file.name, rows, file.rows
+            ));
+        }
+    }
+    if let Some(name) = found.keys().min() {
+        return Err(format!("{} isn't in the manifest", name));
+    }
+    Ok(manifest)
+}
+
+/// Writes a backup to `tx`, a part at a time, ending the stream with an
+/// error if it fails so it can't pass for a whole one.
+///
+/// The tables are read in one snapshot, as they were when the backup
+/// started, however long it takes. Stops when the receiver goes away.
+pub async fn dump(db: PgPool, tx: mpsc::Sender<Result<Bytes, io::Error>>) {
+    match write_archive(&db, &tx).await {
+        Ok(true) => info!("Backup sent"),
+        Ok(false) => warn!("Backup abandoned by the client"),
+        Err(e) => {
+            warn!("Backup failed: {}", e);
+            let _ = tx.send(Err(io::Error::other(e.to_string()))).await;
+        }
+    }
+}
+
+/// Returns whether the whole archive was sent.
+async fn write_archive(
+    db: &PgPool,
+    tx: &mpsc::Sender<Result<Bytes, io::Error>>,
+) -> Result<bool, ShortenError> {
+    let created_at = Utc::now();
+    let mtime = created_at.timestamp();
+    let mut snapshot = db.begin().await?;
+    sqlx::query("SET TRANSACTION ISOLATION LEVEL REPEATABLE READ, READ ONLY")
+        .execute(&mut *snapshot)
+        .await?;
+    let mut files = Vec::new();
+    for table in TABLES {
+        let query = format!(
+            "SELECT row_to_json(t)::text FROM {} t ORDER BY {}",
+            table.name, table.order_by
+        );
+        let mut rows = sqlx::query_scalar::<_, String>(&query).fetch(&mut *snapshot);
+        let mut part = Part::new(table.name, 0);
+        loop {
+            let row = rows.try_next().await?;
+            if let Some(row) = &row {
+                part.push(row);
+            }
+            let full = part.rows as usize >= PART_ROWS;
+            if part.rows > 0 && (full || row.is_none()) {
+                let next = Part::new(table.name, part.index + 1);
+                let part = std::mem::replace(&mut part, next);
+                files.push(part.entry());
+                let entry = tar_entry(&part.name(), &part.data, mtime);
+                if tx.send(Ok(entry.into())).await.is_err() {
+                    return Ok(false);
+                }
+            }
+            if row.is_none() {
+                break;
+            }
+        }
+    }
+    snapshot.commit().await?;
+    let manifest = Manifest {
+        format: FORMAT,
+        created_at,
+        files,
+    };
+    let manifest = serde_json::to_vec_pretty(&manifest).map_err(io::Error::other)?;
+    let mut end = tar_entry(MANIFEST, &manifest, mtime);
+    end.extend(tar_end());
+    Ok(tx.send(Ok(end.into())).await.is_ok())
+}
+
+/// Whether any of the tables backed up has rows.
+pub async fn has_data(db: &PgPool) -> Result<bool, ShortenError> {
+    for table in TABLES {
+        let query = format!("SELECT EXISTS (SELECT 1 FROM {})", table.name);
+        if sqlx::query_scalar(&query).fetch_one(db).await? {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// Progress of a restore, the payload of its job.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Progress {
+    files: usize,
+    files_restored: usize,
+    rows: u64,
+    rows_restored: u64,
+}
+
+impl Job for Progress {
+    const KIND: &'static str = RESTORE_KIND;
+}
+
+/// Lists a restore of `manifest` as a running job, returning its id.
+///
+/// It's run by [`restore`], not the job worker, which only gets it if the
+/// restore stops making progress, as when the instance running it dies.
+/// It's out of attempts by then, so the worker fails it instead of
+/// retrying.
+pub async fn start(
+    db: &PgPool,
+    manifest: &Manifest,
+    max_attempts: u32,
+) -> Result<i64, ShortenError> {
+    let progress = Progress {
+        files: manifest.files.len(),
+        rows: manifest.rows().values().sum(),
+        ..Default::default()
+    };
+    let id = sqlx::query_scalar(
+        "INSERT INTO jobs (kind, payload, state, attempts, locked_at)
+         VALUES ($1, $2, 'running', $3, now()) RETURNING id",
+    )
+    .bind(RESTORE_KIND)
+    .bind(json!(progress))
+    .bind(max_attempts as i32)
+    .fetch_one(db)
+    .await?;
+    Ok(id)
+}
+
+/// Writes a request body to `path`, 413 once it's over `limit` bytes, in
+/// which case nothing is left at `path`.
+pub async fn spool(body: Body, path: &Path, limit: u64) -> Result<(), ShortenError> {
+    let written = write_body(body, path, limit).await;
+    if written.is_err() {
+        let _ = tokio::fs::remove_file(path).await;
+    }
+    written
+}
+
+async fn write_body(body: Body, path: &Path, limit: u64) -> Result<(), ShortenError> {
+    let mut file = tokio::fs::File::create(path).await?;
+    let mut stream = body.into_data_stream();
+    let mut size = 0;
+    while let Some(chunk) = stream.next().await {
+        let chunk = chunk.map_err(|_| StatusCodeError(StatusCode::BAD_REQUEST))?;
+        size += chunk.len() as u64;
+        if size > limit {
+            return Err(StatusCodeError(StatusCode::PAYLOAD_TOO_LARGE).into());
+        }
+        file.write_all(&chunk).await?;
+    }
+    file.flush().await?;
+    Ok(())
+}
+
+/// Restores the verified archive at `path` as job `job`, emptying the
+/// tables first if `force`, then deletes it.
+///
+/// Each file is restored in a transaction of its own, the job's payload
+/// counting those done, so a failed restore leaves the files before the
+/// one it failed on. See [`finish`] for ending the job.
+pub async fn restore(
+    db: &PgPool,
+    job: i64,
+    path: &Path,
+    manifest: &Manifest,
+    force: bool,
+) -> Result<(), ShortenError> {
+    let result = restore_files(db, job, path, manifest, force).await;
+    if let Err(e) = tokio::fs::remove_file(path).await {
+        warn!("Failed to remove {}: {}", path.display(), e);
+    }
+    result
+}
+
+/// Marks restore `job` `done`, or `failed` with the error.
+pub async fn finish(db: &PgPool, job: i64, result: Result<(), ShortenError>) {
+    let (state, error) = match result {
+        Ok(()) => ("done", None),
+        Err(e) => {
+            warn!("Restore {} failed: {}", job, e);
+            ("failed", Some(e.to_string()))
+        }
+    };
+    let finished =
+        sqlx::query("UPDATE jobs SET state = $2, last_error = $3, locked_at = NULL WHERE id = $1")
+            .bind(job)
+            .bind(state)
+            .bind(error)
+            .execute(db)
+            .await;
+    if let Err(e) = finished {
+        warn!("Failed to finish restore {}: {}", job, e);
+    }
+}
+
+async fn restore_files(
+    db: &PgPool,
+    job: i64,
+    path: &Path,
+    manifest: &Manifest,
+    force: bool,
+) -> Result<(), ShortenError> {
+    if force {
+        let tables: Vec<_> = TABLES.iter().map(|t| t.name).collect();
+        sqlx::query(&format!("TRUNCATE {}", tables.join(", ")))
+            .execute(db)
+            .await?;
+    }
+    // the archive is read on a thread of its own, a file ahead
+    let (tx, mut rx) = mpsc::channel::<Result<(String, Vec<u8>), String>>(1);
+    let file = std::fs::File::open(path)?;
+    tokio::task::spawn_blocking(move || {
+        let mut tar = TarReader::new(io::BufReader::new(file));
+        loop {
+            let entry = tar.next_entry().transpose();
+            let last = !matches!(entry, Some(Ok(_)));
+            if let Some(entry) = entry {
+                if tx.blocking_send(entry).is_err() {
+                    return;
+                }
+            }
+            if last {
+                return;
+            }
+        }
+    });
+    let mut progress = Progress {
+        files: manifest.files.len(),
+        rows: manifest.rows().values().sum(),
+        ..Default::default()
+    };
+    let mut columns = HashMap::new();
+    while let Some(entry) = rx.recv().await {
+        let (name, data) = entry.map_err(ShortenError::Job)?;
+        let Some(file) = manifest.files.iter().find(|f| f.name == name) else {
+            continue;
+        };
+        let table = table(&file.table).expect("verified");
+        if !columns.contains_key(table.name) {
+            let known: Vec<String> = sqlx::query_scalar(
+                "SELECT column_name::text FROM information_schema.columns
+                 WHERE table_schema = current_schema() AND table_name = $1",
+            )
+            .bind(table.name)
+            .fetch_all(db)
+            .await?;
+            columns.insert(table.name, known);
+        }
+        insert(db, table, &columns[table.name], &name, &data).await?;
+        progress.files_restored += 1;
+        progress.rows_restored += file.rows;
+        sqlx::query("UPDATE jobs SET payload = $2, locked_at = now() WHERE id = $1")
+            .bind(job)
+            .bind(json!(progress))
+            .execute(db)
+            .await?;
+    }
+    for table in TABLES {
+        if let Some(serial) = table.serial {
+            let query = format!(
+                "SELECT setval(pg_get_serial_sequence('{0}', '{1}'),
+                    COALESCE(MAX({1}), 1), MAX({1}) IS NOT NULL) FROM {0}",
+                table.name, serial
+            );
+            sqlx::query(&query).execute(db).await?;
+        }
+    }
+    info!(
+        "Restored {} rows of {} files",
+        progress.rows_restored, progress.files_restored
+    );
+    Ok(())
+}
+
+/// Inserts the rows of one file in a transaction, naming the columns they
+/// have so those added since the backup get their defaults.
+async fn insert(
+    db: &PgPool,
+    table: &Table,
+    known: &[String],
+    name: &str,
+    data: &[u8],
+) -> Result<(), ShortenError> {
+    let text = std::str::from_utf8(data).map_err(|e| ShortenError::Job(e.to_string()))?;
+    let rows = text
+        .lines()
+        .filter(|line| !line.is_empty())
+        .map(serde_json::from_str)
+        .collect::<Result<Vec<Value>, _>>()
+        .map_err(|e| ShortenError::Job(format!("{}: {}", name, e)))?;
+    let Some(Value::Object(first)) = rows.first() else {
+        return Err(ShortenError::Job(format!("{} has no rows", name)));
+    };
+    let mut names = Vec::new();
+    for column in first.keys() {
+        if !known.contains(column) {
+            return Err(ShortenError::Job(format!(
+                "{}: {} has no column {:?}",
+                name, table.name, column
+            )));
+        }
+        names.push(format!("\"{}\"", column));
+    }
+    let names = names.join(", ");
+    let query = format!(
+        "INSERT INTO {0} ({1}) SELECT {1} FROM jsonb_populate_recordset(NULL::{0}, $1)",
+        table.name, names
+    );
+    let mut tx = db.begin().await?;
+    sqlx::query(&query)
+        .bind(Value::Array(rows))
+        .execute(&mut *tx)
+        .await?;
+    tx.commit().await?;
+    Ok(())
+}
@@ -0,0 +1,138 @@
+use std::{
+    f64::consts::LN_2,
+    future::Future,
+    hash::{BuildHasher, RandomState},
+    sync::Mutex,
+};
+
+/// Share of slugs that don't exist still passing a filter holding as many
+/// slugs as it was sized for.
+const FALSE_POSITIVE_RATE: f64 = 0.01;
+/// Filters are sized for this many times the slugs they're built from, so
+/// ones added until the next rebuild don't wear them down.
+const HEADROOM: usize = 2;
+const MIN_CAPACITY: usize = 1024;
+
+#[derive(Debug)]
+struct Bits {
+    words: Vec<u64>,
+    hashes: u64,
+}
+
+impl Bits {
+    fn with_capacity(slugs: usize) -> Self {
+        let slugs = slugs.saturating_mul(HEADROOM).max(MIN_CAPACITY) as f64;
+        let bits = (-slugs * FALSE_POSITIVE_RATE.ln() / (LN_2 * LN_2)).ceil();
+        let hashes = (bits / slugs * LN_2).round().max(1.0);
+        Self {
+            words: vec![0; (bits as usize).div_ceil(64)],
+            hashes: hashes as u64,
+        }
+    }
+
+    fn get(&self, position: u64) -> bool {
+        self.words[(position / 64) as usize] & (1 << (position % 64)) != 0
+    }
+
+    fn set(&mut self, position: u64) {
+        self.words[(position / 64) as usize] |= 1 << (position % 64);
+    }
+}
+
+/// The slugs this instance knows of, so lookups of ones that surely don't
+/// exist can skip the database. Slugs removed stay in until the next
+/// rebuild, checks for them just fall through. Until the first build every
+/// slug may exist.
+#[derive(Debug, Default)]
+pub struct SlugFilter {
+    keys: (RandomState, RandomState),
+    state: Mutex<State>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    bits: Option<Bits>,
+    /// Rebuilds running, which need the slugs added meanwhile replayed.
+    rebuilds: usize,
+    added: Vec<String>,
+}
+
+impl SlugFilter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Bit positions of a slug, by double hashing.
+    fn positions(&self, bits: &Bits, slug: &str) -> impl Iterator<Item = u64> {
+        let len = bits.words.len() as u64 * 64;
+        let first = self.keys.0.hash_one(slug);
+        // odd, so it can't cycle through a few positions of the even length
+        let step = self.keys.1.hash_one(slug) | 1;
+        (0..bits.hashes).map(move |i| first.wrapping_add(i.wrapping_mul(step)) % len)
+    }
+
+    fn add(&self, bits: &mut Bits, slug: &str) {
+        for position in self.positions(bits, slug) {
+            bits.set(position);
+        }
+    }
+
+    /// False if the slug surely doesn't exist.
+    pub fn may_exist(&self, slug: &str) -> bool {
+        let state = self.state.lock().unwrap();
+        let Some(bits) = &state.bits else {
+            return true;
+        };
+        self.positions(bits, slug)
+            .all(|position| bits.get(position))
+    }
+
+    pub fn insert(&self, slug: &str) {
+        let mut state = self.state.lock().unwrap();
+        let state = &mut *state;
+        if let Some(bits) = &mut state.bits {
+            self.add(bits, slug);
+        }
+        if state.rebuilds > 0 {
+            state.added.push(slug.to_string());
+        }
+    }
+
+    /// Replaces the filter with one of the slugs `read` returns, plus any
+    /// inserted while it ran that it may have missed. Returns how many
+    /// slugs it read.
+    pub async fn rebuild<E>(
+        &self,
+        read: impl Future<Output = Result<Vec<String>, E>>,
+    ) -> Result<usize, E> {
+        self.state.lock().unwrap().rebuilds += 1;
+        // built without the lock, the slugs added meanwhile are kept aside
+        let built = read.await.map(|slugs| {
+            let mut bits = Bits::with_capacity(slugs.len());
+            for slug in &slugs {
+                self.add(&mut bits, slug);
+            }
+            (bits, slugs.len())
+        });
+        let mut state = self.state.lock().unwrap();
+        let state = &mut *state;
+        state.rebuilds -= 1;
+        let (mut bits, read) = match built {
+            Ok(built) => built,
+            Err(e) => {
+                if state.rebuilds == 0 {
+                    state.added.clear();
+                }
+                return Err(e);
+            }
+        };
+        for slug in &state.added {
+            self.add(&mut bits, slug);
+        }
+        if state.rebuilds == 0 {
+            state.added.clear();
+        }
+        state.bits = Some(bits);
+        Ok(read)
+    }
+}
@@ -0,0 +1,215 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use tracing::{info, warn};
+
+/// The window is counted in this many buckets, the oldest one dropped as
+/// a new one starts.
+const BUCKETS: usize = 10;
+/// `Retry-After` of calls turned away while the probe is out.
+const PROBE_WAIT: Duration = Duration::from_secs(1);
+
+/// Stops calling the database once too many calls to it fail, so requests
+/// get a 503 at once instead of each waiting out the timeouts.
+///
+/// Closed, calls go through and their outcomes are counted over a rolling
+/// window. Once at least `min_calls` were made in it and `error_rate` of
+/// them failed, the breaker opens: for `cooldown` every call is turned
+/// away. After that it's half-open, letting one call through as a probe,
+/// whose success closes it again and whose failure opens it for another
+/// cooldown.
+#[derive(Debug, Clone)]
+pub struct Breaker {
+    inner: Arc<Mutex<Inner>>,
+    error_rate: f64,
+    min_calls: u64,
+    bucket: Duration,
+    cooldown: Duration,
+    started: Instant,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BreakerState {
+    Closed,
+    Open,
+    HalfOpen,
+}
+
+#[derive(Debug)]
+struct Inner {
+    state: State,
+    /// Bucket `n`'s outcomes at `n % BUCKETS`.
+    buckets: [Bucket; BUCKETS],
+}
+
+#[derive(Debug, Clone, Copy)]
+enum State {
+    Closed,
+    Open {
+        until: Instant,
+    },
+    /// `probing` while the probe is out.
+    HalfOpen {
+        probing: bool,
+    },
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct Bucket {
+    index: u64,
+    calls: u64,
+    failures: u64,
+}
+
+/// A call let through, whose outcome [`record`](Self::record) reports.
+/// Dropped unreported, as when the request goes away, it counts for
+/// nothing, and a probe lets the next call probe instead.
+#[derive(Debug)]
+pub struct Permit {
+    breaker: Breaker,
+    probe: bool,
+    recorded: bool,
+}
+
+impl Breaker {
+    pub fn new(error_rate: f64, min_calls: u64, window: Duration, cooldown: Duration) -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(Inner {
+                state: State::Closed,
+                buckets: Default::default(),
+            })),
+            error_rate,
+            min_calls: min_calls.max(1),
+            bucket: (window / BUCKETS as u32).max(Duration::from_millis(1)),
+            cooldown,
+            started: Instant::now(),
+        }
+    }
+
+    /// Lets a call through, or says how long until the next one may be.
+    pub fn allow(&self) -> Result<Permit, Duration> {
+        self.allow_at(Instant::now())
+    }
+
+    pub fn allow_at(&self, now: Instant) -> Result<Permit, Duration> {
+        let mut inner = self.inner.lock().unwrap();
+        let probe = match inner.state {
+            State::Closed => false,
+            State::Open { until } if now < until => {
+                metrics::counter!("db_breaker_rejected_total").increment(1);
+                return Err(until - now);
+            }
+            State::HalfOpen { probing: true } => {
+                metrics::counter!("db_breaker_rejected_total").increment(1);
+                return Err(PROBE_WAIT);
+            }
+            State::Open { .. } | State::HalfOpen { probing: false } => {
+                if matches!(inner.state, State::Open { .. }) {
+                    transition(BreakerState::HalfOpen);
+                    info!("Database circuit half-open, probing");
+                }
+                inner.state = State::HalfOpen { probing: true };
+                true
+            }
+        };
+        Ok(Permit {
+            breaker: self.clone(),
+            probe,
+            recorded: false,
+        })
+    }
+
+    pub fn state(&self) -> BreakerState {
+        match self.inner.lock().unwrap().state {
+            State::Closed => BreakerState::Closed,
+            State::Open { .. } => BreakerState::Open,
+            State::HalfOpen { .. } => BreakerState::HalfOpen,
+        }
+    }
+
+    fn record_at(&self, probe: bool, ok: bool, now: Instant) {
+        let mut inner = self.inner.lock().unwrap();
+        if probe {
+            if ok {
+                inner.buckets = Default::default();
+                inner.state = State::Closed;
+                transition(BreakerState::Closed);
+                info!("Database circuit closed");
+            } else {
+                self.open(&mut inner, now);
+                warn!("Database circuit probe failed, open again");
+            }
+            return;
+        }
+        // calls let through before it opened don't count after
+        if !matches!(inner.state, State::Closed) {
+            return;
+        }
+        let index = now.saturating_duration_since(self.started).as_millis() as u64
+            / self.bucket.as_millis() as u64;
+        let bucket = &mut inner.buckets[index as usize % BUCKETS];
+        if bucket.index != index {
+            *bucket = Bucket {
+                index,
+                ..Default::default()
+            };
+        }
+        bucket.calls += 1;
+        bucket.failures += !ok as u64;
+        let (calls, failures) = inner
+            .buckets
+            .iter()
+            .filter(|b| b.index <= index && index - b.index < BUCKETS as u64)
+            .fold((0, 0), |(c, f), b| (c + b.calls, f + b.failures));
+        if calls >= self.min_calls && failures as f64 >= calls as f64 * self.error_rate {
+            self.open(&mut inner, now);
+            warn!(calls, failures, "Database circuit open, failing fast");
+        }
+    }
+
+    fn open(&self, inner: &mut Inner, now: Instant) {
+        inner.state = State::Open {
+            until: now + self.cooldown,
+        };
+        transition(BreakerState::Open);
+    }
+
+    fn release_probe(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        if let State::HalfOpen { probing: true } = inner.state {
+            inner.state = State::HalfOpen { probing: false };
+        }
+    }
+}
+
+impl Permit {
+    /// Reports whether the call succeeded.
+    pub fn record(self, ok: bool) {
+        self.record_at(ok, Instant::now())
+    }
+
+    pub fn record_at(mut self, ok: bool, now: Instant) {
+        self.recorded = true;
+        self.breaker.record_at(self.probe, ok, now);
+    }
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        if self.probe && !self.recorded {
+            self.breaker.release_probe();
+        }
+    }
+}
+
+fn transition(to: BreakerState) {
+    let (label, open) = match to {
+        BreakerState::Closed => ("closed", 0.0),
+        BreakerState::Open => ("open", 1.0),
+        BreakerState::HalfOpen => ("half_open", 1.0),
+    };
+    metrics::counter!("db_breaker_transitions_total", "to" => label).increment(1);
+    metrics::gauge!("db_breaker_open").set(open);
+}
@@ -0,0 +1,123 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{PgConnection, PgPool};
+
+use crate::{tags, ShortenError};
+
+/// Links changed per transaction, so a large operation doesn't hold locks
+/// on all of them until it's done.
+pub const BATCH: usize = 500;
+
+/// The links a bulk operation takes: those matching every field given.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub struct Filter {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created_after: Option<DateTime<Utc>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created_before: Option<DateTime<Utc>>,
+    /// An [`owner`](crate::auth::ApiKey::owner), the id of the key that
+    /// created the links.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub owner: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tag: Option<String>,
+    /// Matches urls as stored, which compressed ones aren't.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub url_contains: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ids: Option<Vec<String>>,
+    /// Required to match every link, which an empty filter would.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub all: bool,
+}
+
+impl Filter {
+    /// Whether nothing narrows the filter down.
+    pub fn is_empty(&self) -> bool {
+        self.created_after.is_none()
+            && self.created_before.is_none()
+            && self.owner.is_none()
+            && self.tag.is_none()
+            && self.url_contains.is_none()
+            && self.ids.is_none()
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum Action {
+    Delete,
+    Disable,
+    /// Links already carrying `MAX_TAGS_PER_LINK` other tags are skipped.
+    AddTag {
+        tag: String,
+    },
+}
+
+impl Action {
+    /// Name of the operation in the audit log.
+    pub fn audit_name(&self) -> &'static str {
+        match self {
+            Action::Delete => "bulk_delete",
+            Action::Disable => "bulk_disable",
+            Action::AddTag { .. } => "bulk_add_tag",
+        }
+    }
+}
+
+/// Ids of the links `filter` matches, in order.
+pub async fn matching(db: &PgPool, filter: &Filter) -> Result<Vec<String>, ShortenError> {
+    let ids = sqlx::query_scalar(
+        "SELECT id FROM urls u
+         WHERE ($1::TIMESTAMPTZ IS NULL OR created_at > $1)
+           AND ($2::TIMESTAMPTZ IS NULL OR created_at < $2)
+           AND ($3::TEXT IS NULL OR owner = $3)
+           AND ($4::TEXT IS NULL OR EXISTS (
+                SELECT 1 FROM link_tags t WHERE t.link_id = u.id AND t.tag = $4))
+           AND ($5::TEXT IS NULL OR strpos(url, $5) > 0)
+           AND ($6::TEXT[] IS NULL OR id = ANY($6))
+         ORDER BY id",
+    )
+    .bind(filter.created_after)
+    .bind(filter.created_before)
+    .bind(&filter.owner)
+    .bind(filter.tag.as_deref().map(str::to_ascii_lowercase))
+    .bind(&filter.url_contains)
+    .bind(&filter.ids)
+    .fetch_all(db)
+    .await?;
+    Ok(ids)
+}
+
+/// Disables the enabled links of `ids`, returning those.
+pub async fn disable(conn: &mut PgConnection, ids: &[String]) -> Result<Vec<String>, ShortenError> {
+    let ids = sqlx::query_scalar(
+        "UPDATE urls SET enabled = false WHERE id = ANY($1) AND enabled RETURNING id",
+    )
+    .bind(ids)
+    .fetch_all(conn)
+    .await?;
+    Ok(ids)
+}
+
+/// Tags the links of `ids` that don't have `tag` yet and have room for
+/// it, returning those.
+pub async fn add_tag(
+    conn: &mut PgConnection,
+    ids: &[String],
+    tag: &str,
+) -> Result<Vec<String>, ShortenError> {
+    let ids = sqlx::query_scalar(
+        "INSERT INTO link_tags (link_id, tag)
+         SELECT u.id, $2 FROM urls u WHERE u.id = ANY($1)
+           AND (SELECT COUNT(*) FROM link_tags t WHERE t.link_id = u.id) < $3
+         ON CONFLICT DO NOTHING RETURNING link_id",
+    )
+    .bind(ids)
+    .bind(tag)
+    .bind(tags::MAX_TAGS_PER_LINK as i64)
+    .fetch_all(conn)
+    .await?;
+    Ok(ids)
+}
@@ -0,0 +1,89 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::{OriginalUri, Request, State},
+    http::{
+        header::{HOST, LOCATION},
+        uri::Authority,
+        HeaderMap, StatusCode, Uri,
+    },
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use url::Url;
+
+const X_FORWARDED_PROTO: &str = "x-forwarded-proto";
+
+/// Served on any host so probes keep working however they address us.
+const ANY_HOST_PATHS: &[&str] = &["/healthz", "/metrics"];
+
+/// Where a request that didn't address `canonical` should go, `None` if it
+/// did.
+///
+/// Hosts compare case-insensitively, and a missing port stands for the
+/// default one of the scheme, `https` when `X-Forwarded-Proto` says so and
+/// `http` otherwise. A request with no host at all is let through.
+pub fn canonical_redirect(headers: &HeaderMap, uri: &Uri, canonical: &str) -> Option<String> {
+    let host = match headers.get(HOST) {
+        Some(host) => host.to_str().ok()?.to_string(),
+        None => uri.authority()?.to_string(),
+    };
+    let https = headers
+        .get(X_FORWARDED_PROTO)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.split(',').next())
+        .is_some_and(|proto| proto.trim().eq_ignore_ascii_case("https"));
+    let (scheme, default_port) = if https { ("https", 443) } else { ("http", 80) };
+    let canonical_authority: Authority = canonical.parse().ok()?;
+    let same = host.parse::<Authority>().is_ok_and(|host| {
+        host.host().eq_ignore_ascii_case(canonical_authority.host())
+            && host.port_u16().unwrap_or(default_port)
+                == canonical_authority.port_u16().unwrap_or(default_port)
+    });
+    if same {
+        return None;
+    }
+    let path = uri.path_and_query().map_or("/", |p| p.as_str());
+    Some(format!("{}://{}{}", scheme, canonical, path))
+}
+
+/// The path `url` asks of us, `None` if it's for none of `hosts`. Hosts
+/// compare as in [`canonical_redirect`], a missing port meaning the default
+/// one of the url's scheme.
+pub fn own_path(url: &str, hosts: &[&str]) -> Option<String> {
+    let url = Url::parse(url).ok()?;
+    let host = url.host_str()?;
+    let default_port = match url.scheme() {
+        "https" => Some(443),
+        "http" => Some(80),
+        _ => None,
+    };
+    let ours = hosts
+        .iter()
+        .filter_map(|h| h.parse::<Authority>().ok())
+        .any(|ours| {
+            ours.host().eq_ignore_ascii_case(host)
+                && ours.port_u16().or(default_port) == url.port_or_known_default()
+        });
+    ours.then(|| url.path().to_string())
+}
+
+/// Answers requests for any other host than `CANONICAL_HOST` with a 301
+/// to the same path on it.
+pub async fn redirect_to_canonical(
+    State(canonical): State<Arc<str>>,
+    req: Request,
+    next: Next,
+) -> Response {
+    if !ANY_HOST_PATHS.contains(&req.uri().path()) {
+        // nested in another router, the path has its prefix stripped
+        let uri = req
+            .extensions()
+            .get::<OriginalUri>()
+            .map_or(req.uri(), |OriginalUri(uri)| uri);
+        if let Some(location) = canonical_redirect(req.headers(), uri, &canonical) {
+            return (StatusCode::MOVED_PERMANENTLY, [(LOCATION, location)]).into_response();
+        }
+    }
+    next.run(req).await
+}
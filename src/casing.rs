@@ -0,0 +1,150 @@
+//! The transition to snake_case for clients that speak camelCase, turned on
+//! by `COMPAT_CAMEL_CASE`. Bodies are snake_case either way: this only
+//! renames the keys of requests on the way in and mirrors the keys of
+//! responses on the way out, so the handlers never see the difference.
+
+use axum::{
+    body::Body,
+    extract::{Request, State},
+    http::{
+        header::{CONTENT_LENGTH, CONTENT_TYPE},
+        HeaderMap, HeaderValue, StatusCode,
+    },
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use serde_json::{Map, Value};
+
+use crate::{error::StatusCodeError, ShortenError};
+
+/// `expiresInSecs` as `expires_in_secs`, `None` for keys that aren't
+/// camelCase. One starting with a capital, like `Url`, isn't, so a typo
+/// stays an unknown field.
+pub fn snake_case(key: &str) -> Option<String> {
+    let camel = key.starts_with(|c: char| c.is_ascii_lowercase())
+        && key.contains(|c: char| c.is_ascii_uppercase())
+        && !key.contains('_');
+    if !camel {
+        return None;
+    }
+    let mut snake = String::with_capacity(key.len() + 4);
+    for c in key.chars() {
+        if c.is_ascii_uppercase() {
+            snake.push('_');
+            snake.push(c.to_ascii_lowercase());
+        } else {
+            snake.push(c);
+        }
+    }
+    Some(snake)
+}
+
+/// `expires_in_secs` as `expiresInSecs`, `None` for keys without an
+/// underscore between words.
+pub fn camel_case(key: &str) -> Option<String> {
+    let words: Vec<&str> = key.split('_').collect();
+    if words.len() < 2 || words.iter().any(|w| w.is_empty()) {
+        return None;
+    }
+    let mut camel = words[0].to_string();
+    for word in &words[1..] {
+        let mut chars = word.chars();
+        camel.extend(chars.next().map(|c| c.to_ascii_uppercase()));
+        camel.push_str(chars.as_str());
+    }
+    Some(camel)
+}
+
+/// Renames the camelCase keys of every object in `value` to snake_case.
+/// A key whose snake_case form is sent as well is kept, for the handler
+/// to refuse as unknown.
+pub fn accept_camel(value: &mut Value) {
+    match value {
+        Value::Object(map) => {
+            let renames: Vec<(String, String)> = map
+                .keys()
+                .filter_map(|key| Some((key.clone(), snake_case(key)?)))
+                .filter(|(_, snake)| !map.contains_key(snake))
+                .collect();
+            for (key, snake) in renames {
+                let v = map.remove(&key).unwrap();
+                map.insert(snake, v);
+            }
+            map.values_mut().for_each(accept_camel);
+        }
+        Value::Array(items) => items.iter_mut().for_each(accept_camel),
+        _ => {}
+    }
+}
+
+/// Adds the camelCase form of every snake_case key of every object in
+/// `value` next to it.
+pub fn mirror_camel(value: &mut Value) {
+    match value {
+        Value::Object(map) => {
+            map.values_mut().for_each(mirror_camel);
+            let aliases: Map<String, Value> = map
+                .iter()
+                .filter_map(|(key, v)| Some((camel_case(key)?, v.clone())))
+                .filter(|(camel, _)| !map.contains_key(camel))
+                .collect();
+            map.extend(aliases);
+        }
+        Value::Array(items) => items.iter_mut().for_each(mirror_camel),
+        _ => {}
+    }
+}
+
+fn is_json(headers: &HeaderMap) -> bool {
+    headers
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.split(';').next())
+        .is_some_and(|mime| mime.trim().eq_ignore_ascii_case("application/json"))
+}
+
+/// Applies [`accept_camel`] to JSON request bodies of up to
+/// `max_body_bytes` and [`mirror_camel`] to JSON responses. Bodies that
+/// aren't valid JSON pass through for the handler to refuse.
+pub async fn compat(State(max_body_bytes): State<usize>, req: Request, next: Next) -> Response {
+    let req = if is_json(req.headers()) {
+        let (mut parts, body) = req.into_parts();
+        let Ok(bytes) = axum::body::to_bytes(body, max_body_bytes).await else {
+            return ShortenError::from(StatusCodeError(StatusCode::PAYLOAD_TOO_LARGE))
+                .into_response();
+        };
+        let bytes = match serde_json::from_slice::<Value>(&bytes) {
+            Ok(mut value) => {
+                accept_camel(&mut value);
+                serde_json::to_vec(&value).unwrap().into()
+            }
+            Err(_) => bytes,
+        };
+        parts
+            .headers
+            .insert(CONTENT_LENGTH, HeaderValue::from(bytes.len()));
+        Request::from_parts(parts, Body::from(bytes))
+    } else {
+        req
+    };
+    let res = next.run(req).await;
+    if !is_json(res.headers()) {
+        return res;
+    }
+    let (mut parts, body) = res.into_parts();
+    let Ok(bytes) = axum::body::to_bytes(body, usize::MAX).await else {
+        return ShortenError::from(StatusCodeError(StatusCode::INTERNAL_SERVER_ERROR))
+            .into_response();
+    };
+    let bytes = match serde_json::from_slice::<Value>(&bytes) {
+        Ok(mut value) => {
+            mirror_camel(&mut value);
+            serde_json::to_vec(&value).unwrap().into()
+        }
+        Err(_) => bytes,
+    };
+    parts
+        .headers
+        .insert(CONTENT_LENGTH, HeaderValue::from(bytes.len()));
+    Response::from_parts(parts, Body::from(bytes))
+}
@@ -0,0 +1,279 @@
+use std::time::Duration;
+
+use chrono::{DateTime, TimeDelta, Utc};
+use serde::Serialize;
+use sqlx::{PgConnection, PgPool};
+use url::Url;
+
+use crate::{error::StatusCodeError, fetch::Fetcher, ShortenError};
+
+/// Where the site of a destination lists the tokens of the claims on links
+/// to it that it vouches for, one per line.
+pub const WELL_KNOWN_PATH: &str = "/.well-known/shortener-claim.txt";
+
+/// How long a claim token can be proven before another one is handed out.
+pub const CLAIM_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Of the well-known file, the part looked through for the token.
+const MAX_PROOF_BYTES: usize = 64 * 1024;
+
+pub async fn init(db: &PgPool) -> Result<(), ShortenError> {
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS link_claims (
+            link_id TEXT NOT NULL,
+            owner TEXT NOT NULL,
+            token TEXT NOT NULL,
+            created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+            PRIMARY KEY (link_id, owner)
+        )",
+    )
+    .execute(db)
+    .await?;
+    // a link is offered to one owner at a time
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS link_transfers (
+            link_id TEXT PRIMARY KEY,
+            from_owner TEXT NOT NULL,
+            to_owner TEXT NOT NULL,
+            created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+        )",
+    )
+    .execute(db)
+    .await?;
+    Ok(())
+}
+
+/// What a claimant is to serve at `verification_url` before claiming again.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub struct Challenge {
+    pub token: String,
+    pub verification_url: String,
+    pub expires_at: DateTime<Utc>,
+}
+
+/// Where the site of `destination` proves claims on links to it, `None`
+/// for a destination that isn't a site.
+pub fn verification_url(destination: &str) -> Option<Url> {
+    let url = Url::parse(destination).ok()?;
+    if !matches!(url.scheme(), "http" | "https") || url.host_str().is_none() {
+        return None;
+    }
+    url.join(WELL_KNOWN_PATH).ok()
+}
+
+/// Whether a well-known file lists `token`.
+pub fn lists_token(body: &str, token: &str) -> bool {
+    body.lines().any(|line| line.trim() == token)
+}
+
+/// The token `owner` is to prove for link `id`, and whether it was handed
+/// out just now, there having been none or only an expired one.
+pub async fn challenge(
+    db: &PgPool,
+    id: &str,
+    owner: &str,
+) -> Result<(String, DateTime<Utc>, bool), ShortenError> {
+    let ttl = TimeDelta::from_std(CLAIM_TTL).unwrap_or(TimeDelta::MAX);
+    let pending: Option<(String, DateTime<Utc>)> = sqlx::query_as(
+        "SELECT token, created_at FROM link_claims
+         WHERE link_id = $1 AND owner = $2 AND created_at > $3",
+    )
+    .bind(id)
+    .bind(owner)
+    .bind(Utc::now() - ttl)
+    .fetch_optional(db)
+    .await?;
+    if let Some((token, created_at)) = pending {
+        return Ok((token, created_at + ttl, false));
+    }
+    let token = format!("shortener-claim-{}", nanoid::nanoid!(32));
+    let created_at: DateTime<Utc> = sqlx::query_scalar(
+        "INSERT INTO link_claims (link_id, owner, token) VALUES ($1, $2, $3)
+         ON CONFLICT (link_id, owner) DO UPDATE SET token = EXCLUDED.token, created_at = now()
+         RETURNING created_at",
+    )
+    .bind(id)
+    .bind(owner)
+    .bind(&token)
+    .fetch_one(db)
+    .await?;
+    Ok((token, created_at + ttl, true))
+}
+
+/// Checks that `url` lists `token`, saying what's wrong if it doesn't.
+/// Redirects aren't followed, so the file has to be served where the
+/// destination points, scheme and all.
+pub async fn prove(fetcher: &Fetcher, url: &Url, token: &str) -> Result<(), String> {
+    let mut res = fetcher
+        .get(url)
+        .await
+        .map_err(|e| format!("fetching {} failed: {}", url, e))?;
+    if !res.status().is_success() {
+        return Err(format!("{} answered {}", url, res.status()));
+    }
+    let mut body = Vec::new();
+    while body.len() < MAX_PROOF_BYTES {
+        match res.chunk().await {
+            Ok(Some(chunk)) => body.extend_from_slice(&chunk),
+            Ok(None) => break,
+            Err(e) => return Err(format!("reading {} failed: {}", url, e)),
+        }
+    }
+    body.truncate(MAX_PROOF_BYTES);
+    match lists_token(&String::from_utf8_lossy(&body), token) {
+        true => Ok(()),
+        false => Err(format!("{} doesn't list the claim token", url)),
+    }
+}
+
+/// Gives unowned link `id` to `owner`, `false` if it has an owner by now.
+///
+/// Concurrent claims queue on the row: the first one takes the link, and
+/// the others find it owned once they get to it. The link's management
+/// token stops working, and the pending claims on it are dropped.
+pub async fn take(db: &PgPool, id: &str, owner: &str) -> Result<bool, ShortenError> {
+    let mut tx = db.begin().await?;
+    let taken = set_owner(&mut tx, id, Some(owner), None).await?;
+    if taken {
+        forget(&mut tx, id).await?;
+    }
+    tx.commit().await?;
+    Ok(taken)
+}
+
+/// Makes `owner` the owner of link `id`, whoever had it, returning who
+/// that was or `None` if there is no such link. Pending claims and
+/// transfers of the link are dropped.
+pub async fn assign(
+    db: &PgPool,
+    id: &str,
+    owner: Option<&str>,
+) -> Result<Option<Option<String>>, ShortenError> {
+    let mut tx = db.begin().await?;
+    let previous: Option<Option<String>> =
+        sqlx::query_scalar("SELECT owner FROM urls WHERE id = $1 FOR UPDATE")
+            .bind(id)
+            .fetch_optional(&mut *tx)
+            .await?;
+    let Some(previous) = previous else {
+        return Ok(None);
+    };
+    // locked, so it's still theirs
+    set_owner(&mut tx, id, owner, previous.as_deref()).await?;
+    forget(&mut tx, id).await?;
+    tx.commit().await?;
+    Ok(Some(previous))
+}
+
+/// An offer of a link by its owner to another, until the other accepts.
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+#[serde(rename_all = "snake_case")]
+pub struct Transfer {
+    pub link_id: String,
+    pub from_owner: String,
+    pub to_owner: String,
+    pub created_at: DateTime<Utc>,
+}
+
+/// Offers link `id` from `from` to `to`, replacing an earlier offer of it.
+pub async fn offer(db: &PgPool, id: &str, from: &str, to: &str) -> Result<Transfer, ShortenError> {
+    let transfer = sqlx::query_as(
+        "INSERT INTO link_transfers (link_id, from_owner, to_owner) VALUES ($1, $2, $3)
+         ON CONFLICT (link_id) DO UPDATE SET
+            from_owner = EXCLUDED.from_owner,
+            to_owner = EXCLUDED.to_owner,
+            created_at = now()
+         RETURNING link_id, from_owner, to_owner, created_at",
+    )
+    .bind(id)
+    .bind(from)
+    .bind(to)
+    .fetch_one(db)
+    .await?;
+    Ok(transfer)
+}
+
+/// The offers made by or to `owner`, oldest first.
+pub async fn pending(db: &PgPool, owner: &str) -> Result<Vec<Transfer>, ShortenError> {
+    let transfers = sqlx::query_as(
+        "SELECT link_id, from_owner, to_owner, created_at FROM link_transfers
+         WHERE from_owner = $1 OR to_owner = $1 ORDER BY created_at, link_id",
+    )
+    .bind(owner)
+    .fetch_all(db)
+    .await?;
+    Ok(transfers)
+}
+
+/// Hands link `id` over to `to` as offered, `None` if it wasn't offered to
+/// them. 409 if the link changed hands since the offer, which is dropped.
+pub async fn accept(db: &PgPool, id: &str, to: &str) -> Result<Option<Transfer>, ShortenError> {
+    let mut tx = db.begin().await?;
+    let transfer: Option<Transfer> = sqlx::query_as(
+        "DELETE FROM link_transfers WHERE link_id = $1 AND to_owner = $2
+         RETURNING link_id, from_owner, to_owner, created_at",
+    )
+    .bind(id)
+    .bind(to)
+    .fetch_optional(&mut *tx)
+    .await?;
+    let Some(transfer) = transfer else {
+        return Ok(None);
+    };
+    let moved = set_owner(&mut tx, id, Some(to), Some(&transfer.from_owner)).await?;
+    tx.commit().await?;
+    if !moved {
+        return Err(StatusCodeError(axum::http::StatusCode::CONFLICT).into());
+    }
+    Ok(Some(transfer))
+}
+
+/// Drops the offer of link `id` if `owner` made or got it: cancelled by
+/// the one, declined by the other.
+pub async fn withdraw(db: &PgPool, id: &str, owner: &str) -> Result<bool, ShortenError> {
+    let ret = sqlx::query(
+        "DELETE FROM link_transfers WHERE link_id = $1 AND (from_owner = $2 OR to_owner = $2)",
+    )
+    .bind(id)
+    .bind(owner)
+    .execute(db)
+    .await?;
+    Ok(ret.rows_affected() > 0)
+}
+
+/// Moves link `id` to `owner` if `from` still owns it, or nobody does
+/// with `None`. A link deduped for its new owner's url already stops being
+/// deduped, so each owner keeps one link per url.
+async fn set_owner(
+    conn: &mut PgConnection,
+    id: &str,
+    owner: Option<&str>,
+    from: Option<&str>,
+) -> Result<bool, ShortenError> {
+    let ret = sqlx::query(
+        "UPDATE urls SET owner = $2, management_token_hash = NULL,
+            deduped = deduped AND NOT EXISTS (
+                SELECT 1 FROM urls o WHERE o.owner IS NOT DISTINCT FROM $2
+                    AND o.url = urls.url AND o.deduped AND o.id <> urls.id)
+         WHERE id = $1 AND owner IS NOT DISTINCT FROM $3",
+    )
+    .bind(id)
+    .bind(owner)
+    .bind(from)
+    .execute(conn)
+    .await?;
+    Ok(ret.rows_affected() > 0)
+}
+
+async fn forget(conn: &mut PgConnection, id: &str) -> Result<(), ShortenError> {
+    sqlx::query("DELETE FROM link_claims WHERE link_id = $1")
+        .bind(id)
+        .execute(&mut *conn)
+        .await?;
+    sqlx::query("DELETE FROM link_transfers WHERE link_id = $1")
+        .bind(id)
+        .execute(&mut *conn)
+        .await?;
+    Ok(())
+}
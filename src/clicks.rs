@@ -0,0 +1,226 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, RwLock,
+    },
+    time::Duration,
+};
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use sqlx::PgPool;
+use tokio::sync::{broadcast, Notify};
+use tracing::{info, warn};
+
+use crate::{
+    jobs,
+    platform::Platform,
+    throttle::TokenBucket,
+    webhook::{self, Event},
+    ShortenError,
+};
+
+/// Events buffered per subscriber before a slow one starts missing some.
+const FEED_CAPACITY: usize = 1024;
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub struct ClickEvent {
+    pub id: String,
+    pub ts: DateTime<Utc>,
+    /// The branch a link with platform targets took.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub platform: Option<Platform>,
+}
+
+/// Fans successful redirects out to live subscribers. Publishing with no
+/// subscribers is a no-op.
+#[derive(Debug, Clone)]
+pub struct ClickFeed {
+    tx: broadcast::Sender<ClickEvent>,
+}
+
+impl ClickFeed {
+    pub fn new() -> Self {
+        let (tx, _) = broadcast::channel(FEED_CAPACITY);
+        Self { tx }
+    }
+
+    pub fn publish(&self, id: &str, platform: Option<Platform>) {
+        let _ = self.tx.send(ClickEvent {
+            id: id.to_string(),
+            ts: Utc::now(),
+            platform,
+        });
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<ClickEvent> {
+        self.tx.subscribe()
+    }
+}
+
+/// A link written by a flush: its id, clicks before and after, and cap.
+type Flushed = (String, i64, i64, Option<i64>);
+
+/// Aggregates redirect counts in memory so a viral link costs one batched
+/// UPDATE per flush instead of one per click.
+#[derive(Debug, Clone)]
+pub struct ClickCounter {
+    pending: Arc<RwLock<HashMap<String, AtomicU64>>>,
+    /// Woken once `threshold` distinct links are pending.
+    full: Arc<Notify>,
+    threshold: usize,
+    /// Queue a webhook for links whose clicks reach a milestone or their
+    /// cap.
+    milestones: bool,
+    /// Paces the links written, at most its burst in one statement.
+    throttle: Option<TokenBucket>,
+}
+
+impl ClickCounter {
+    pub fn new(threshold: usize, milestones: bool, throttle: Option<TokenBucket>) -> Self {
+        Self {
+            pending: Default::default(),
+            full: Default::default(),
+            threshold,
+            milestones,
+            throttle,
+        }
+    }
+
+    pub fn record(&self, id: &str) {
+        // the common case, a link that's already hot, only takes the read lock
+        if let Some(count) = self.pending.read().unwrap().get(id) {
+            count.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        let mut pending = self.pending.write().unwrap();
+        pending
+            .entry(id.to_string())
+            .or_default()
+            .fetch_add(1, Ordering::Relaxed);
+        if pending.len() >= self.threshold {
+            self.full.notify_one();
+        }
+    }
+
+    /// Clicks on `id` not yet written to the database.
+    pub fn unflushed(&self, id: &str) -> u64 {
+        self.pending
+            .read()
+            .unwrap()
+            .get(id)
+            .map_or(0, |count| count.load(Ordering::Relaxed))
+    }
+
+    /// Writes every pending delta in one statement, or with a throttle in
+    /// statements of at most its burst, each waiting for its tokens. On
+    /// failure the deltas not written are put back for the next attempt.
+    /// Milestones of the deltas written are queued after, so failing to
+    /// queue one doesn't count the clicks twice.
+    pub async fn flush(&self, db: &PgPool) -> Result<(), ShortenError> {
+        let batch = std::mem::take(&mut *self.pending.write().unwrap());
+        if batch.is_empty() {
+            return Ok(());
+        }
+        let (ids, counts): (Vec<String>, Vec<i64>) = batch
+            .into_iter()
+            .map(|(id, count)| (id, count.into_inner() as i64))
+            .unzip();
+        let chunk = self
+            .throttle
+            .as_ref()
+            .map_or(ids.len(), |t| t.burst() as usize);
+        let mut flushed = Vec::with_capacity(ids.len());
+        let mut failed = None;
+        for (i, (ids_chunk, counts_chunk)) in
+            ids.chunks(chunk).zip(counts.chunks(chunk)).enumerate()
+        {
+            if let Some(throttle) = &self.throttle {
+                throttle.take(ids_chunk.len() as u32).await;
+            }
+            let ret: Result<Vec<Flushed>, _> = sqlx::query_as(
+                "UPDATE urls u SET clicks = u.clicks + d.n
+                 FROM UNNEST($1::TEXT[], $2::BIGINT[]) AS d(id, n) WHERE u.id = d.id
+                 RETURNING u.id, u.clicks - d.n, u.clicks, u.click_cap",
+            )
+            .bind(ids_chunk)
+            .bind(counts_chunk)
+            .fetch_all(db)
+            .await;
+            match ret {
+                Ok(ret) => flushed.extend(ret),
+                Err(e) => {
+                    let mut pending = self.pending.write().unwrap();
+                    let unwritten = ids.iter().zip(&counts).skip(i * chunk);
+                    for (id, count) in unwritten {
+                        pending
+                            .entry(id.clone())
+                            .or_default()
+                            .fetch_add(*count as u64, Ordering::Relaxed);
+                    }
+                    failed = Some(e);
+                    break;
+                }
+            }
+        }
+        for (id, before, clicks, cap) in flushed {
+            reached_cap(db, &id, cap, before, clicks, self.milestones).await?;
+            if !self.milestones {
+                continue;
+            }
+            let Some(milestone) = webhook::milestone(before, clicks) else {
+                continue;
+            };
+            let event = Event::LinkClickMilestone {
+                id,
+                milestone,
+                clicks,
+            };
+            jobs::enqueue(db, &event).await?;
+        }
+        failed.map_or(Ok(()), |e| Err(e.into()))
+    }
+
+    /// Flushes every `every`, or sooner once the threshold is reached.
+    pub async fn run(self, db: PgPool, every: Duration) {
+        let mut interval = tokio::time::interval(every);
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {}
+                _ = self.full.notified() => {}
+            }
+            if let Err(e) = self.flush(&db).await {
+                warn!("Failed to flush click counts: {}", e);
+            }
+        }
+    }
+}
+
+/// Alerts of link `id` going from `before` to `after` clicks past its
+/// `cap`, logging it and with `webhooks` queueing one. The increments are
+/// atomic, so only one of them crosses and it alerts once.
+pub async fn reached_cap(
+    db: &PgPool,
+    id: &str,
+    cap: Option<i64>,
+    before: i64,
+    after: i64,
+    webhooks: bool,
+) -> Result<(), ShortenError> {
+    let Some(cap) = cap.filter(|&cap| before < cap && cap <= after) else {
+        return Ok(());
+    };
+    info!("Link {} reached its click cap of {}", id, cap);
+    metrics::counter!("click_caps_reached_total").increment(1);
+    if webhooks {
+        let event = Event::LinkClickCapReached {
+            id: id.to_string(),
+            cap,
+            clicks: after,
+        };
+        jobs::enqueue(db, &event).await?;
+    }
+    Ok(())
+}
@@ -0,0 +1,263 @@
+//! A typed client of the shortener's API, for Rust services calling it,
+//! built on the request and response types the server itself uses.
+//!
+//! ```no_run
+//! # async fn run() -> Result<(), shortener::client::ClientError> {
+//! use shortener::{api::ShortReq, client::ShortenerClient};
+//!
+//! let client = ShortenerClient::new("https://sho.rt", "my-api-key");
+//! let link = client
+//!     .shorten(&ShortReq {
+//!         url: "https://www.rust-lang.org".into(),
+//!         ..Default::default()
+//!     })
+//!     .await?;
+//! println!("{}", link.url);
+//! # Ok(())
+//! # }
+//! ```
+
+use std::time::Duration;
+
+use futures_util::{stream, StreamExt};
+use reqwest::{header::RETRY_AFTER, redirect::Policy, Client, Method, Response, StatusCode};
+use serde::{de::DeserializeOwned, Serialize};
+use thiserror::Error;
+
+use crate::{
+    api::{LinkStats, Resolved, ShortReq, ShortRes},
+    error::ErrorBody,
+};
+
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+const DEFAULT_RETRIES: u32 = 2;
+/// The first retry without a `Retry-After` waits this long, doubling after
+/// that.
+const FIRST_BACKOFF: Duration = Duration::from_millis(200);
+/// Longer a `Retry-After` isn't waited for, so a spent quota fails at once
+/// instead of holding the caller for hours.
+const MAX_RETRY_WAIT: Duration = Duration::from_secs(10);
+/// Links a batch shortens at once.
+const BATCH_CONCURRENCY: usize = 8;
+
+/// A failed call, from the error body the server answered with where
+/// there is one.
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum ClientError {
+    #[error("Request failed: {0}")]
+    Request(#[from] reqwest::Error),
+    #[error("Not found")]
+    NotFound,
+    #[error("Link expired")]
+    Gone,
+    #[error("Missing or invalid api key")]
+    Unauthorized,
+    #[error("Forbidden: {}", .0.message)]
+    Forbidden(ErrorBody),
+    #[error("Alias already in use")]
+    AliasTaken,
+    /// Carries what the server was left to parse of the submission.
+    #[error("Invalid url {0:?}")]
+    InvalidUrl(String),
+    /// `retry_after` is when the quota resets.
+    #[error("Quota exceeded: {}", .body.message)]
+    QuotaExceeded {
+        retry_after: Option<Duration>,
+        body: ErrorBody,
+    },
+    #[error("Rate limited")]
+    RateLimited { retry_after: Option<Duration> },
+    #[error("Service unavailable: {}", .body.message)]
+    Unavailable {
+        retry_after: Option<Duration>,
+        body: ErrorBody,
+    },
+    /// Any other failure, `body.error` saying which.
+    #[error("{status}: {}", .body.message)]
+    Api { status: StatusCode, body: ErrorBody },
+}
+
+impl ClientError {
+    fn from_body(status: StatusCode, retry_after: Option<Duration>, body: ErrorBody) -> Self {
+        match body.error.as_str() {
+            "not_found" => ClientError::NotFound,
+            "gone" => ClientError::Gone,
+            "unauthorized" => ClientError::Unauthorized,
+            "forbidden" | "unverified" => ClientError::Forbidden(body),
+            "alias_taken" => ClientError::AliasTaken,
+            "invalid_url" => ClientError::InvalidUrl(
+                body.details
+                    .as_ref()
+                    .and_then(|d| d["attempted"].as_str())
+                    .unwrap_or_default()
+                    .to_string(),
+            ),
+            "quota_exceeded" => ClientError::QuotaExceeded { retry_after, body },
+            "too_many_requests" => ClientError::RateLimited { retry_after },
+            "service_unavailable" | "id_space_exhausted" => {
+                ClientError::Unavailable { retry_after, body }
+            }
+            _ => ClientError::Api { status, body },
+        }
+    }
+}
+
+/// Calls one deployment of the shortener with one api key.
+///
+/// Requests answered 429 or 503 are sent again, up to
+/// [`with_retries`](Self::with_retries) times, after the `Retry-After` the
+/// server gave. Shortening is retried too, deduping handing back the link
+/// if a timed out attempt made it after all; with `dedupe` off that can
+/// leave a second one.
+#[derive(Debug, Clone)]
+pub struct ShortenerClient {
+    client: Client,
+    base: String,
+    api_key: String,
+    timeout: Duration,
+    retries: u32,
+}
+
+impl ShortenerClient {
+    /// `base_url` is where the shortener is served, like `PUBLIC_URL`:
+    /// `https://sho.rt`, or `https://example.com/s` when nested.
+    pub fn new(base_url: impl Into<String>, api_key: impl Into<String>) -> Self {
+        let client = Client::builder()
+            // links answer with redirects, which are what's asked for
+            .redirect(Policy::none())
+            .build()
+            .expect("failed to build http client");
+        Self {
+            client,
+            base: base_url.into().trim_end_matches('/').to_string(),
+            api_key: api_key.into(),
+            timeout: DEFAULT_TIMEOUT,
+            retries: DEFAULT_RETRIES,
+        }
+    }
+
+    /// How long each attempt may take, 10 seconds by default.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Attempts after the first one is answered 429 or 503, 2 by default.
+    pub fn with_retries(mut self, retries: u32) -> Self {
+        self.retries = retries;
+        self
+    }
+
+    pub async fn shorten(&self, req: &ShortReq) -> Result<ShortRes, ClientError> {
+        self.call(Method::POST, "/", Some(req)).await
+    }
+
+    /// Shortens each of `reqs`, a few at a time, answering in their order.
+    /// Each link is a request of its own, so some may fail while others
+    /// are created.
+    pub async fn shorten_batch(&self, reqs: &[ShortReq]) -> Vec<Result<ShortRes, ClientError>> {
+        stream::iter(reqs)
+            .map(|req| self.shorten(req))
+            .buffered(BATCH_CONCURRENCY)
+            .collect()
+            .await
+    }
+
+    /// Where link `id` redirects. Counts as a redirect, clicks and uses
+    /// included.
+    pub async fn resolve(&self, id: &str) -> Result<Resolved, ClientError> {
+        self.call(Method::GET, &format!("/{}.json", id), None::<&()>)
+            .await
+    }
+
+    pub async fn delete(&self, id: &str) -> Result<(), ClientError> {
+        self.send(Method::DELETE, &format!("/{}", id), None::<&()>)
+            .await?;
+        Ok(())
+    }
+
+    pub async fn stats(&self, id: &str) -> Result<LinkStats, ClientError> {
+        let path = format!("/api/links/{}/stats", id);
+        self.call(Method::GET, &path, None::<&()>).await
+    }
+
+    async fn call<T: DeserializeOwned>(
+        &self,
+        method: Method,
+        path: &str,
+        body: Option<&impl Serialize>,
+    ) -> Result<T, ClientError> {
+        Ok(self.send(method, path, body).await?.json().await?)
+    }
+
+    /// Sends a request until it succeeds, fails for good or runs out of
+    /// retries.
+    async fn send(
+        &self,
+        method: Method,
+        path: &str,
+        body: Option<&impl Serialize>,
+    ) -> Result<Response, ClientError> {
+        let url = format!("{}{}", self.base, path);
+        let mut backoff = FIRST_BACKOFF;
+        let mut attempt = 0;
+        loop {
+            let mut req = self
+                .client
+                .request(method.clone(), &url)
+                .bearer_auth(&self.api_key)
+                .timeout(self.timeout);
+            if let Some(body) = body {
+                req = req.json(body);
+            }
+            let res = req.send().await?;
+            let status = res.status();
+            if status.is_success() {
+                return Ok(res);
+            }
+            let retry_after = retry_after(&res);
+            let retryable = matches!(
+                status,
+                StatusCode::TOO_MANY_REQUESTS | StatusCode::SERVICE_UNAVAILABLE
+            );
+            let wait = retry_after.unwrap_or(backoff);
+            if !retryable || attempt >= self.retries || wait > MAX_RETRY_WAIT {
+                return Err(failure(status, retry_after, res).await);
+            }
+            tokio::time::sleep(wait).await;
+            backoff *= 2;
+            attempt += 1;
+        }
+    }
+}
+
+/// The error of a failed response, made up from the status if its body
+/// isn't one of ours, as from a proxy in front of the server.
+async fn failure(status: StatusCode, retry_after: Option<Duration>, res: Response) -> ClientError {
+    let body = match res.json::<ErrorBody>().await {
+        Ok(mut body) => {
+            // what's left over once the named fields are read, if anything
+            body.details = body
+                .details
+                .filter(|d| d.as_object().is_none_or(|d| !d.is_empty()));
+            body
+        }
+        Err(_) => {
+            let reason = status.canonical_reason().unwrap_or("Error");
+            ErrorBody {
+                error: reason.to_ascii_lowercase().replace([' ', '-'], "_"),
+                message: reason.to_string(),
+                path: None,
+                details: None,
+            }
+        }
+    };
+    ClientError::from_body(status, retry_after, body)
+}
+
+/// The server only gives `Retry-After` in seconds.
+fn retry_after(res: &Response) -> Option<Duration> {
+    let secs = res.headers().get(RETRY_AFTER)?.to_str().ok()?;
+    secs.trim().parse().ok().map(Duration::from_secs)
+}
@@ -0,0 +1,74 @@
+use std::net::{IpAddr, SocketAddr};
+
+use axum::{
+    async_trait,
+    extract::{ConnectInfo, FromRequestParts},
+    http::{request::Parts, HeaderMap, StatusCode},
+};
+use ipnet::IpNet;
+
+use crate::{AppState, ShortenError, StatusCodeError};
+
+const X_FORWARDED_FOR: &str = "x-forwarded-for";
+
+/// The address of the client behind any trusted proxies.
+///
+/// Requests from an untrusted peer are taken at face value. From a trusted
+/// one, `X-Forwarded-For` is walked right to left, skipping the trusted
+/// proxies that appended to it; the first other address is the client.
+/// Entries left of it were supplied by the client and can't be believed. An
+/// unparsable entry stops the walk at the hop that forwarded it.
+pub fn real_client_ip(headers: &HeaderMap, peer: IpAddr, trusted: &[IpNet]) -> IpAddr {
+    let is_trusted = |ip: &IpAddr| trusted.iter().any(|net| net.contains(ip));
+    if !is_trusted(&peer) {
+        return peer;
+    }
+    let hops: Vec<&str> = headers
+        .get_all(X_FORWARDED_FOR)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(str::trim)
+        .collect();
+    let mut client = peer;
+    for hop in hops.into_iter().rev() {
+        let Some(ip) = parse_hop(hop) else {
+            break;
+        };
+        client = ip;
+        if !is_trusted(&ip) {
+            break;
+        }
+    }
+    client
+}
+
+/// Accepts a bare address or one with a port, `1.2.3.4:80` or `[::1]:80`.
+fn parse_hop(hop: &str) -> Option<IpAddr> {
+    hop.parse::<IpAddr>()
+        .ok()
+        .or_else(|| hop.parse::<SocketAddr>().ok().map(|addr| addr.ip()))
+}
+
+/// Extracts [`real_client_ip`] using `TRUSTED_PROXIES`.
+pub struct ClientIp(pub IpAddr);
+
+#[async_trait]
+impl FromRequestParts<AppState> for ClientIp {
+    type Rejection = ShortenError;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        let ConnectInfo(peer) = parts
+            .extensions
+            .get::<ConnectInfo<SocketAddr>>()
+            .ok_or(StatusCodeError(StatusCode::INTERNAL_SERVER_ERROR))?;
+        Ok(ClientIp(real_client_ip(
+            &parts.headers,
+            peer.ip(),
+            &state.config.trusted_proxies,
+        )))
+    }
+}
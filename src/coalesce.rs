@@ -0,0 +1,67 @@
+use std::{
+    collections::HashMap,
+    future::Future,
+    hash::Hash,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use tokio::sync::OnceCell;
+
+#[derive(Debug)]
+struct Entry<V> {
+    started: Instant,
+    result: Arc<OnceCell<V>>,
+}
+
+/// Runs one of a burst of identical calls and hands its result to the rest.
+///
+/// Calls with the same key while one is running wait for it, and ones
+/// within `window` of its start get its result right away. A failed call
+/// isn't shared: the next waiter runs its own. A zero window turns this off.
+#[derive(Debug, Clone)]
+pub struct Coalescer<K, V> {
+    entries: Arc<Mutex<HashMap<K, Entry<V>>>>,
+    window: Duration,
+}
+
+impl<K: Eq + Hash + Clone, V: Clone> Coalescer<K, V> {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            entries: Default::default(),
+            window,
+        }
+    }
+
+    /// The result of `call`, or of an identical one made shortly before.
+    /// The flag is whether `call` was the one that ran.
+    pub async fn run<E, F>(&self, key: K, call: F) -> Result<(V, bool), E>
+    where
+        F: Future<Output = Result<V, E>>,
+    {
+        if self.window.is_zero() {
+            return call.await.map(|v| (v, true));
+        }
+        let result = {
+            let mut entries = self.entries.lock().unwrap();
+            let now = Instant::now();
+            // running calls are kept however long they take
+            entries.retain(|_, e| {
+                now.duration_since(e.started) < self.window || Arc::strong_count(&e.result) > 1
+            });
+            let entry = entries.entry(key).or_insert_with(|| Entry {
+                started: now,
+                result: Default::default(),
+            });
+            entry.result.clone()
+        };
+        let mut ran = false;
+        let value = result
+            .get_or_try_init(|| {
+                ran = true;
+                call
+            })
+            .await?;
+        Ok((value.clone(), ran))
+    }
+}
@@ -0,0 +1,76 @@
+use std::io::{Read, Write};
+
+use flate2::{read::DeflateDecoder, write::DeflateEncoder, Compression};
+use sha2::{Digest, Sha256};
+
+/// Starts what `urls.url` holds for a compressed url, followed by the hex
+/// SHA-256 of the url. Urls are absolute, so none starts like this.
+const DIGEST_PREFIX: &str = "deflate:sha256:";
+
+/// A url as written to `urls`: `url`, and with `deflated` the compressed
+/// url the row's `url_deflated` holds, `url` then being its digest.
+///
+/// The digest keeps `url` short and still unique per url, so the dedupe
+/// indexes and lookups by url work on compressed rows as they do on others.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Stored {
+    pub url: String,
+    pub deflated: Option<Vec<u8>>,
+}
+
+/// How to store `url`, compressed if it's longer than `over` bytes. Zero
+/// never compresses.
+pub fn store(url: &str, over: usize) -> Stored {
+    if over == 0 || url.len() <= over {
+        return Stored {
+            url: url.to_string(),
+            deflated: None,
+        };
+    }
+    let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+    // writing to a Vec can't fail
+    encoder.write_all(url.as_bytes()).unwrap();
+    Stored {
+        url: digest(url),
+        deflated: Some(encoder.finish().unwrap()),
+    }
+}
+
+/// What `urls.url` holds for `url`, for looking links up by their url.
+pub fn key(url: &str, over: usize) -> String {
+    if over == 0 || url.len() <= over {
+        url.to_string()
+    } else {
+        digest(url)
+    }
+}
+
+/// The url of a row, from its `url` and `url_deflated`.
+pub fn load(url: String, deflated: Option<&[u8]>) -> Result<String, sqlx::Error> {
+    let Some(deflated) = deflated else {
+        return Ok(url);
+    };
+    let mut out = String::new();
+    DeflateDecoder::new(deflated)
+        .read_to_string(&mut out)
+        .map_err(|e| sqlx::Error::Decode(format!("compressed url of {}: {}", url, e).into()))?;
+    Ok(out)
+}
+
+/// Replaces the stored form of a compressed url with the url.
+pub fn inflate(url: &mut String, deflated: Option<Vec<u8>>) -> Result<(), sqlx::Error> {
+    if deflated.is_some() {
+        *url = load(std::mem::take(url), deflated.as_deref())?;
+    }
+    Ok(())
+}
+
+fn digest(url: &str) -> String {
+    let digest = Sha256::digest(url.as_bytes());
+    let mut key = String::with_capacity(DIGEST_PREFIX.len() + 64);
+    key.push_str(DIGEST_PREFIX);
+    for byte in digest {
+        key.push_str(&format!("{:02x}", byte));
+    }
+    key
+}
@@ -0,0 +1,63 @@
+//! Caps on the requests answered at once. Past one, requests are turned
+//! away with a 503 rather than queued up in front of the database.
+
+use std::sync::Arc;
+
+use axum::{
+    extract::{Request, State},
+    http::Method,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use tokio::sync::Semaphore;
+
+use crate::ShortenError;
+
+/// At most so many requests of a kind at once.
+#[derive(Debug, Clone)]
+pub struct Limit {
+    /// `None` for no limit.
+    permits: Option<Arc<Semaphore>>,
+    /// What the requests are, for the metric and the 503.
+    kind: &'static str,
+    retry_after: u64,
+}
+
+impl Limit {
+    /// Zero sets no limit.
+    pub fn new(kind: &'static str, max: usize, retry_after: u64) -> Self {
+        Self {
+            permits: (max > 0).then(|| Arc::new(Semaphore::new(max))),
+            kind,
+            retry_after,
+        }
+    }
+
+    async fn run(&self, req: Request, next: Next) -> Response {
+        let Some(permits) = &self.permits else {
+            return next.run(req).await;
+        };
+        let Ok(_permit) = permits.try_acquire() else {
+            metrics::counter!("requests_shed_total", "kind" => self.kind).increment(1);
+            return ShortenError::Unavailable {
+                reason: format!("too many {} at once", self.kind),
+                retry_after: self.retry_after,
+            }
+            .into_response();
+        };
+        next.run(req).await
+    }
+}
+
+/// Limits the requests that may write: reads, GET, HEAD and OPTIONS, pass.
+pub async fn writes(State(limit): State<Limit>, req: Request, next: Next) -> Response {
+    if matches!(*req.method(), Method::GET | Method::HEAD | Method::OPTIONS) {
+        return next.run(req).await;
+    }
+    limit.run(req, next).await
+}
+
+/// Limits every request.
+pub async fn all(State(limit): State<Limit>, req: Request, next: Next) -> Response {
+    limit.run(req, next).await
+}
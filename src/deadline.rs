@@ -0,0 +1,138 @@
+use std::{
+    future::Future,
+    ops::{Deref, DerefMut},
+    time::{Duration, Instant},
+};
+
+use axum::{
+    extract::{Request, State},
+    middleware::Next,
+    response::Response,
+};
+use sqlx::{pool::PoolConnection, PgPool, Postgres};
+
+use crate::ShortenError;
+
+#[derive(Debug, Clone, Copy)]
+struct Deadline {
+    at: Instant,
+    retry_after: u64,
+}
+
+tokio::task_local! {
+    static DEADLINE: Deadline;
+}
+
+/// The request timeout and the `Retry-After` of requests running out of it.
+#[derive(Debug, Clone, Copy)]
+pub struct Budget {
+    pub timeout: Duration,
+    pub retry_after: u64,
+}
+
+/// Gives the rest of the request the request timeout as its budget, which
+/// [`bounded`] queries get what's left of. Goes outside the timeout layer,
+/// so the budget runs out first.
+pub async fn track(State(budget): State<Budget>, req: Request, next: Next) -> Response {
+    let deadline = Deadline {
+        at: Instant::now() + budget.timeout,
+        retry_after: budget.retry_after,
+    };
+    DEADLINE.scope(deadline, next.run(req)).await
+}
+
+/// `Retry-After` of queries timing out outside of a request.
+const DEFAULT_RETRY_AFTER: u64 = 1;
+
+/// Waits for `query` up to the request's remaining budget, answering 503
+/// once it's spent, or once `limit` is, `DB_QUERY_TIMEOUT_MS`, however much
+/// of the budget is left. Zero sets no limit. `query` is dropped when
+/// either runs out.
+pub async fn bounded<T>(
+    limit: Duration,
+    query: impl Future<Output = T>,
+) -> Result<T, ShortenError> {
+    let deadline = DEADLINE.try_with(|deadline| *deadline).ok();
+    let remaining = deadline.map(|d| d.at.saturating_duration_since(Instant::now()));
+    let limited = !limit.is_zero() && remaining.is_none_or(|remaining| limit < remaining);
+    let wait = match (limited, remaining) {
+        (true, _) => limit,
+        (false, Some(remaining)) => remaining,
+        (false, None) => return Ok(query.await),
+    };
+    tokio::time::timeout(wait, query).await.map_err(|_| {
+        let (reason, message) = match limited {
+            true => ("query_timeout", "database query timed out"),
+            false => ("deadline", "request timed out"),
+        };
+        metrics::counter!("queries_aborted_total", "reason" => reason).increment(1);
+        ShortenError::Unavailable {
+            reason: message.into(),
+            retry_after: deadline.map_or(DEFAULT_RETRY_AFTER, |d| d.retry_after),
+        }
+    })
+}
+
+/// A pooled connection for a query that may be abandoned: by the client
+/// going away, which drops the request, or by running out of budget.
+///
+/// A query dropped mid-flight leaves its connection busy until the server
+/// finishes it, and the pool would wait for that before taking it back.
+/// Unless [`done`](Self::done) was called, the connection is closed instead,
+/// giving its slot back at once; the server stops the query at its next
+/// check of the socket, see `client_connection_check_interval`.
+#[derive(Debug)]
+pub struct Guarded {
+    conn: Option<PoolConnection<Postgres>>,
+    /// When [`bounded`] gives up on the query, the budget's or the
+    /// limit's.
+    deadline: Option<Instant>,
+}
+
+impl Guarded {
+    /// `limit` is the one the query is [`bounded`] by.
+    pub async fn acquire(db: &PgPool, limit: Duration) -> Result<Self, sqlx::Error> {
+        let conn = db.acquire().await?;
+        let budget = DEADLINE.try_with(|deadline| deadline.at).ok();
+        let limit = (!limit.is_zero()).then(|| Instant::now() + limit);
+        Ok(Self {
+            conn: Some(conn),
+            deadline: budget.into_iter().chain(limit).min(),
+        })
+    }
+
+    /// The query completed, the connection can go back to the pool.
+    pub fn done(mut self) {
+        drop(self.conn.take());
+    }
+}
+
+impl Deref for Guarded {
+    type Target = PoolConnection<Postgres>;
+
+    fn deref(&self) -> &Self::Target {
+        self.conn.as_ref().unwrap()
+    }
+}
+
+impl DerefMut for Guarded {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.conn.as_mut().unwrap()
+    }
+}
+
+impl Drop for Guarded {
+    fn drop(&mut self) {
+        let Some(conn) = self.conn.take() else {
+            return;
+        };
+        // `bounded` counted the ones it gave up on
+        if self
+            .deadline
+            .is_none_or(|deadline| deadline > Instant::now())
+        {
+            metrics::counter!("queries_aborted_total", "reason" => "disconnect").increment(1);
+        }
+        drop(conn.detach());
+    }
+}
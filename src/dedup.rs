@@ -0,0 +1,100 @@
+use std::{
+    collections::{hash_map::RandomState, HashMap, VecDeque},
+    hash::BuildHasher,
+    net::IpAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// Each shard has a lock of its own, so concurrent redirects rarely wait
+/// on each other.
+const SHARDS: usize = 16;
+
+/// Tells a redirect repeating one of the same visitor for the same link
+/// within the window, as double clicks and prefetches do, from a click.
+///
+/// A visitor is their address and user agent, hashed along with the link
+/// with a key made up at startup, so only the hashes are kept, and only in
+/// memory. At most `capacity` of them are remembered: once full the oldest
+/// are forgotten first, whose repeats then count again.
+#[derive(Debug, Clone)]
+pub struct ClickDedup {
+    shards: Arc<[Mutex<Shard>]>,
+    hasher: RandomState,
+    window: Duration,
+    shard_capacity: usize,
+}
+
+#[derive(Debug, Default)]
+struct Shard {
+    /// When each visitor's last counted click on the link was.
+    counted: HashMap<u64, Instant>,
+    /// The same in the order they were counted, the oldest first. An entry
+    /// whose visitor was counted again since is skipped when it comes up.
+    order: VecDeque<(u64, Instant)>,
+}
+
+impl ClickDedup {
+    pub fn new(window: Duration, capacity: usize) -> Self {
+        Self {
+            shards: (0..SHARDS).map(|_| Default::default()).collect(),
+            hasher: RandomState::new(),
+            window,
+            shard_capacity: capacity.div_ceil(SHARDS).max(1),
+        }
+    }
+
+    /// Whether a redirect of link `id` is a repeat, to be left uncounted.
+    pub fn is_repeat(&self, id: &str, ip: IpAddr, user_agent: Option<&str>) -> bool {
+        self.is_repeat_at(id, ip, user_agent, Instant::now())
+    }
+
+    pub fn is_repeat_at(
+        &self,
+        id: &str,
+        ip: IpAddr,
+        user_agent: Option<&str>,
+        now: Instant,
+    ) -> bool {
+        let key = self.hasher.hash_one((id, ip, user_agent));
+        let mut shard = self.shards[key as usize % SHARDS].lock().unwrap();
+        if let Some(&at) = shard.counted.get(&key) {
+            if now.saturating_duration_since(at) < self.window {
+                return true;
+            }
+        }
+        shard.counted.insert(key, now);
+        shard.order.push_back((key, now));
+        shard.forget(now, self.window, self.shard_capacity);
+        false
+    }
+
+    /// Visitors remembered now.
+    pub fn len(&self) -> usize {
+        self.shards
+            .iter()
+            .map(|s| s.lock().unwrap().counted.len())
+            .sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl Shard {
+    /// Drops the visitors counted longer ago than the window, then the
+    /// oldest ones past `capacity`.
+    fn forget(&mut self, now: Instant, window: Duration, capacity: usize) {
+        while let Some(&(key, at)) = self.order.front() {
+            let expired = now.saturating_duration_since(at) >= window;
+            if !expired && self.counted.len() <= capacity {
+                break;
+            }
+            self.order.pop_front();
+            if self.counted.get(&key) == Some(&at) {
+                self.counted.remove(&key);
+            }
+        }
+    }
+}
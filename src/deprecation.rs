@@ -0,0 +1,133 @@
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+};
+
+use axum::{
+    extract::{MatchedPath, Request, State},
+    http::{HeaderName, HeaderValue, Method},
+    middleware::Next,
+    response::Response,
+};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+
+const DEPRECATION: HeaderName = HeaderName::from_static("deprecation");
+const SUNSET: HeaderName = HeaderName::from_static("sunset");
+const LINK: HeaderName = HeaderName::from_static("link");
+
+/// A route on its way out: when it stops working, and what replaces it.
+#[derive(Debug, Clone)]
+pub struct Deprecation {
+    pub method: Method,
+    /// The route pattern, as routed: `/:id`, not `/abc123`.
+    pub route: &'static str,
+    pub sunset: DateTime<Utc>,
+    pub successor: Option<String>,
+}
+
+/// A deprecated route and the requests it got since the instance started.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub struct DeprecatedRoute {
+    pub method: String,
+    pub route: &'static str,
+    pub sunset: DateTime<Utc>,
+    pub successor: Option<String>,
+    pub hits: u64,
+}
+
+/// The deprecated routes, telling their callers so in every response and
+/// counting the calls they still get, in `deprecated_requests_total` and
+/// for [`Deprecations::list`].
+#[derive(Debug, Clone, Default)]
+pub struct Deprecations {
+    routes: Arc<[Route]>,
+}
+
+#[derive(Debug)]
+struct Route {
+    deprecation: Deprecation,
+    /// The headers every response of the route gets.
+    headers: Vec<(HeaderName, HeaderValue)>,
+    hits: AtomicU64,
+}
+
+impl Deprecations {
+    pub fn new(deprecations: Vec<Deprecation>) -> Self {
+        let routes = deprecations
+            .into_iter()
+            .map(|deprecation| {
+                let mut headers = vec![
+                    (DEPRECATION, HeaderValue::from_static("true")),
+                    (SUNSET, http_date(deprecation.sunset)),
+                ];
+                let link = deprecation.successor.as_ref().and_then(|successor| {
+                    HeaderValue::from_str(&format!("<{}>; rel=\"successor-version\"", successor))
+                        .ok()
+                });
+                headers.extend(link.map(|link| (LINK, link)));
+                Route {
+                    deprecation,
+                    headers,
+                    hits: AtomicU64::new(0),
+                }
+            })
+            .collect();
+        Self { routes }
+    }
+
+    /// Every deprecated route, soonest sunset first.
+    pub fn list(&self) -> Vec<DeprecatedRoute> {
+        let mut list: Vec<DeprecatedRoute> = self
+            .routes
+            .iter()
+            .map(|route| DeprecatedRoute {
+                method: route.deprecation.method.to_string(),
+                route: route.deprecation.route,
+                sunset: route.deprecation.sunset,
+                successor: route.deprecation.successor.clone(),
+                hits: route.hits.load(Ordering::Relaxed),
+            })
+            .collect();
+        list.sort_by(|a, b| (a.sunset, a.route).cmp(&(b.sunset, b.route)));
+        list
+    }
+}
+
+/// `Sunset` takes an HTTP-date, as `Date` does.
+fn http_date(at: DateTime<Utc>) -> HeaderValue {
+    let date = at.format("%a, %d %b %Y %H:%M:%S GMT").to_string();
+    HeaderValue::from_str(&date).expect("dates are plain ASCII")
+}
+
+/// Marks the responses of deprecated routes with `Deprecation`, `Sunset`
+/// and, with a successor, `Link`, counting the call. A `HEAD` counts as
+/// the `GET` it stands for.
+pub async fn mark(State(deprecations): State<Deprecations>, req: Request, next: Next) -> Response {
+    let method = match *req.method() {
+        Method::HEAD => Method::GET,
+        ref method => method.clone(),
+    };
+    let route = req.extensions().get::<MatchedPath>().and_then(|path| {
+        deprecations
+            .routes
+            .iter()
+            .find(|r| r.deprecation.method == method && r.deprecation.route == path.as_str())
+    });
+    let Some(route) = route else {
+        return next.run(req).await;
+    };
+    route.hits.fetch_add(1, Ordering::Relaxed);
+    metrics::counter!(
+        "deprecated_requests_total",
+        "route" => route.deprecation.route,
+        "method" => route.deprecation.method.to_string()
+    )
+    .increment(1);
+    let mut res = next.run(req).await;
+    for (name, value) in &route.headers {
+        res.headers_mut().insert(name, value.clone());
+    }
+    res
+}
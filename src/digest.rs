@@ -0,0 +1,279 @@
+use std::{fmt, str::FromStr};
+
+use chrono::{DateTime, Datelike, Days, NaiveDate, NaiveTime, TimeZone, Utc, Weekday};
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+
+use crate::{
+    compress,
+    interstitial::escape,
+    jobs::{self, Job},
+    smtp::{Mail, Relay},
+    ShortenError,
+};
+
+/// Links the digest lists, the busiest first.
+const TOP_LINKS: i64 = 10;
+
+/// When the digest goes out, in UTC: `daily@09:00`, or
+/// `weekly@monday 09:00` for one on a weekday. It covers the days since the
+/// previous one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Schedule {
+    Daily(NaiveTime),
+    Weekly(Weekday, NaiveTime),
+}
+
+impl Default for Schedule {
+    fn default() -> Self {
+        Schedule::Weekly(Weekday::Mon, NaiveTime::from_hms_opt(9, 0, 0).unwrap())
+    }
+}
+
+impl FromStr for Schedule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("expected daily@09:00 or weekly@monday 09:00, got {:?}", s);
+        let time = |t: &str| NaiveTime::parse_from_str(t.trim(), "%H:%M").map_err(|_| invalid());
+        match s.trim().to_ascii_lowercase().split_once('@') {
+            Some(("daily", at)) => Ok(Schedule::Daily(time(at)?)),
+            Some(("weekly", at)) => {
+                let (day, at) = at.trim().split_once(' ').ok_or_else(invalid)?;
+                Ok(Schedule::Weekly(
+                    day.parse().map_err(|_| invalid())?,
+                    time(at)?,
+                ))
+            }
+            _ => Err(invalid()),
+        }
+    }
+}
+
+impl fmt::Display for Schedule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Schedule::Daily(at) => write!(f, "daily@{}", at.format("%H:%M")),
+            Schedule::Weekly(day, at) => {
+                let day = [
+                    "monday",
+                    "tuesday",
+                    "wednesday",
+                    "thursday",
+                    "friday",
+                    "saturday",
+                    "sunday",
+                ][day.num_days_from_monday() as usize];
+                write!(f, "weekly@{} {}", day, at.format("%H:%M"))
+            }
+        }
+    }
+}
+
+impl Schedule {
+    /// The first time the digest goes out after `now`.
+    pub fn next_after(self, now: DateTime<Utc>) -> DateTime<Utc> {
+        let (at, every) = match self {
+            Schedule::Daily(at) => (at, 1),
+            Schedule::Weekly(_, at) => (at, 7),
+        };
+        let mut day = now.date_naive();
+        if let Schedule::Weekly(weekday, _) = self {
+            let ahead = (7 + weekday.num_days_from_monday() as i64
+                - day.weekday().num_days_from_monday() as i64)
+                % 7;
+            day = day + Days::new(ahead as u64);
+        }
+        let mut next = Utc.from_utc_datetime(&day.and_time(at));
+        while next <= now {
+            next += chrono::Duration::days(every);
+        }
+        next
+    }
+
+    /// Days a digest covers.
+    pub fn days(self) -> u64 {
+        match self {
+            Schedule::Daily(_) => 1,
+            Schedule::Weekly(..) => 7,
+        }
+    }
+}
+
+/// Sends the digest of the days up to `until`, then schedules the next one.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SendDigest {
+    pub until: DateTime<Utc>,
+}
+
+impl Job for SendDigest {
+    const KIND: &'static str = "digest";
+}
+
+/// How the links did over a digest's days, and the same many days before.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Digest {
+    /// The first day covered.
+    pub from: NaiveDate,
+    /// The day after the last one covered.
+    pub until: NaiveDate,
+    pub new_links: i64,
+    pub clicks: i64,
+    pub previous_clicks: i64,
+    pub top: Vec<TopLink>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, sqlx::FromRow)]
+pub struct TopLink {
+    pub id: String,
+    pub url: String,
+    pub clicks: i64,
+    #[serde(skip)]
+    #[sqlx(default)]
+    pub url_deflated: Option<Vec<u8>>,
+}
+
+impl Digest {
+    /// Change in clicks from the days before, in percent, `None` if there
+    /// were none then.
+    pub fn change_percent(&self) -> Option<f64> {
+        (self.previous_clicks > 0).then(|| {
+            (self.clicks - self.previous_clicks) as f64 * 100.0 / self.previous_clicks as f64
+        })
+    }
+
+    /// The subject, plain text and HTML bodies of the mail, linking to the
+    /// links with `short_url`.
+    pub fn render(&self, short_url: impl Fn(&str) -> String) -> (String, String, String) {
+        let last = self.until.pred_opt().unwrap_or(self.until);
+        let subject = format!("Link digest {} to {}", self.from, last);
+        let change = match self.change_percent() {
+            Some(change) => format!("{:+.0}% on the {} days before", change, self.days()),
+            None => "none the days before".into(),
+        };
+        let mut text = format!(
+            "{}\n\nNew links: {}\nClicks: {} ({})\n",
+            subject, self.new_links, self.clicks, change
+        );
+        let mut html = format!(
+            "<!doctype html>\n<html><body>\n<h1>{}</h1>\n\
+             <p>New links: {}<br>Clicks: {} ({})</p>\n",
+            escape(&subject),
+            self.new_links,
+            self.clicks,
+            escape(&change)
+        );
+        if !self.top.is_empty() {
+            text.push_str("\nTop links:\n");
+            html.push_str("<h2>Top links</h2>\n<ol>\n");
+            for link in &self.top {
+                let short = short_url(&link.id);
+                text.push_str(&format!("- {} -> {}: {}\n", short, link.url, link.clicks));
+                html.push_str(&format!(
+                    "<li><a href=\"{0}\">{0}</a> &rarr; {1}: {2}</li>\n",
+                    escape(&short),
+                    escape(&link.url),
+                    link.clicks
+                ));
+            }
+            html.push_str("</ol>\n");
+        }
+        html.push_str("</body></html>\n");
+        (subject, text, html)
+    }
+
+    fn days(&self) -> i64 {
+        (self.until - self.from).num_days()
+    }
+}
+
+/// Sums up the `days` days before `until`'s.
+pub async fn gather(db: &PgPool, until: DateTime<Utc>, days: u64) -> Result<Digest, ShortenError> {
+    let until = until.date_naive();
+    let from = until - Days::new(days);
+    let before = from - Days::new(days);
+    let new_links = sqlx::query_scalar(
+        "SELECT COUNT(*) FROM urls WHERE created_at >= $1::DATE AND created_at < $2::DATE",
+    )
+    .bind(from)
+    .bind(until)
+    .fetch_one(db)
+    .await?;
+    let (clicks, previous_clicks): (i64, i64) = sqlx::query_as(
+        "SELECT COALESCE(SUM(clicks) FILTER (WHERE day >= $2), 0)::BIGINT,
+                COALESCE(SUM(clicks) FILTER (WHERE day < $2), 0)::BIGINT
+         FROM link_uniques WHERE day >= $1 AND day < $3",
+    )
+    .bind(before)
+    .bind(from)
+    .bind(until)
+    .fetch_one(db)
+    .await?;
+    let top: Vec<TopLink> = sqlx::query_as(
+        "SELECT u.id, u.url, u.url_deflated, d.clicks FROM (
+            SELECT link_id, SUM(clicks)::BIGINT AS clicks FROM link_uniques
+            WHERE day >= $1 AND day < $2 GROUP BY link_id
+         ) d JOIN urls u ON u.id = d.link_id
+         ORDER BY d.clicks DESC, u.id LIMIT $3",
+    )
+    .bind(from)
+    .bind(until)
+    .bind(TOP_LINKS)
+    .fetch_all(db)
+    .await?;
+    let top = top
+        .into_iter()
+        .map(|mut link| {
+            compress::inflate(&mut link.url, link.url_deflated.take())?;
+            Ok(link)
+        })
+        .collect::<Result<_, ShortenError>>()?;
+    Ok(Digest {
+        from,
+        until,
+        new_links,
+        clicks,
+        previous_clicks,
+        top,
+    })
+}
+
+/// Queues the digest going out next after `now`, unless it already is.
+pub async fn schedule(
+    db: &PgPool,
+    schedule: Schedule,
+    now: DateTime<Utc>,
+) -> Result<(), ShortenError> {
+    let until = schedule.next_after(now);
+    jobs::enqueue_once_at(db, &SendDigest { until }, until).await?;
+    Ok(())
+}
+
+/// Sends the digest of `job` to `to`, once the next one is queued, so a
+/// digest failing for good doesn't stop the ones after it.
+pub async fn send(
+    db: &PgPool,
+    relay: &Relay,
+    from: &str,
+    to: &[String],
+    schedule: Schedule,
+    job: SendDigest,
+    short_url: impl Fn(&str) -> String,
+) -> Result<(), String> {
+    self::schedule(db, schedule, job.until)
+        .await
+        .map_err(|e| e.to_string())?;
+    let digest = gather(db, job.until, schedule.days())
+        .await
+        .map_err(|e| e.to_string())?;
+    let (subject, text, html) = digest.render(short_url);
+    relay
+        .send(&Mail {
+            from,
+            to,
+            subject: &subject,
+            text: &text,
+            html: &html,
+        })
+        .await
+}
@@ -0,0 +1,376 @@
+use core::fmt;
+
+use axum::{
+    async_trait,
+    extract::{rejection::JsonRejection, FromRequest, Request},
+    http::{
+        header::{CONTENT_LENGTH, RETRY_AFTER},
+        StatusCode,
+    },
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::Value;
+use thiserror::Error;
+use tracing::{error, warn};
+
+use crate::quota::Exceeded;
+
+/// `Retry-After` of 503s for an unreachable database, which the pool
+/// reconnects to by itself.
+const DB_RETRY_AFTER: u64 = 1;
+
+#[derive(Debug)]
+pub struct StatusCodeError(pub StatusCode);
+impl fmt::Display for StatusCodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Status code error: {}", self.0)
+    }
+}
+impl std::error::Error for StatusCodeError {}
+
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum ShortenError {
+    #[error("Sql error: {0}")]
+    SqlError(#[from] sqlx::Error),
+    #[error("Io error: {0}")]
+    IoError(#[from] std::io::Error),
+    #[error("Axum error: {0}")]
+    StatusCode(#[from] StatusCodeError),
+    #[error("Config error: {0}")]
+    Config(String),
+    #[error("Job error: {0}")]
+    Job(String),
+    #[error("Destination flagged as {0}")]
+    Flagged(String),
+    #[error("Quota exceeded: {} per {:?}", .0.limit, .0.period)]
+    QuotaExceeded(Exceeded),
+    #[error("Too many items: {received} of at most {limit}")]
+    TooManyItems { limit: usize, received: usize },
+    #[error("Gave up generating an unused id")]
+    IdSpaceExhausted,
+    #[error("Alias already in use")]
+    AliasTaken,
+    /// Says why the alias can't be used.
+    #[error("Invalid alias {alias:?}: {reason}")]
+    InvalidAlias { alias: String, reason: &'static str },
+    /// A write clashed with a unique key, naming the constraint if known.
+    #[error("Unique violation on {}", .constraint.as_deref().unwrap_or("unknown constraint"))]
+    UniqueViolation { constraint: Option<String> },
+    /// A write referred to a row that's gone, e.g. a link deleted meanwhile.
+    #[error("Foreign key violation on {}", .constraint.as_deref().unwrap_or("unknown constraint"))]
+    ForeignKeyViolation { constraint: Option<String> },
+    #[error("Destination is a short link that doesn't lead elsewhere")]
+    SelfReference,
+    #[error("Destination {0} is not a public address")]
+    BlockedDomain(String),
+    /// Carries what was left to parse after cleaning up the submission.
+    #[error("Invalid url {0:?}")]
+    InvalidUrl(String),
+    /// Says why the claim on a link could not be verified.
+    #[error("Claim not verified: {0}")]
+    Unverified(String),
+    /// Says what's wrong with a link's geo targets, listing the keys that
+    /// aren't countries.
+    #[error("Invalid geo targets: {reason}")]
+    InvalidGeoTargets {
+        reason: String,
+        invalid: Vec<String>,
+    },
+    /// The link's owner is suspended: `SUSPENDED_OWNER_STATUS` and the
+    /// message visitors are told.
+    #[error("Owner suspended: {message}")]
+    OwnerSuspended { status: StatusCode, message: String },
+    /// Says what's wrong with an uploaded backup.
+    #[error("Invalid archive: {0}")]
+    InvalidArchive(String),
+    #[error("Service unavailable: {reason}")]
+    Unavailable { reason: String, retry_after: u64 },
+    #[error("Invalid request body: {0}")]
+    InvalidBody(JsonRejection),
+    /// An unexpected failure, answered with the request id it's logged
+    /// under.
+    #[error("{source} (request {request_id})")]
+    Internal {
+        source: Box<ShortenError>,
+        request_id: String,
+    },
+    #[error("Self test failed: {0}")]
+    SelfTest(String),
+    #[error("Merge failed: {0}")]
+    Merge(String),
+}
+
+/// The `{"error": ..., "message": ...}` body every failed request carries.
+/// `error` is a stable machine-readable code, `message` is for humans.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub struct ErrorBody {
+    pub error: String,
+    pub message: String,
+    /// Serde path of the offending field for body deserialization errors.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+    #[serde(flatten, skip_serializing_if = "Option::is_none")]
+    pub details: Option<Value>,
+}
+
+impl ErrorBody {
+    pub fn new(error: impl Into<String>, message: impl Into<String>) -> Self {
+        Self {
+            error: error.into(),
+            message: message.into(),
+            path: None,
+            details: None,
+        }
+    }
+
+    fn from_status(status: StatusCode) -> Self {
+        let reason = status.canonical_reason().unwrap_or("Error");
+        Self::new(reason.to_ascii_lowercase().replace([' ', '-'], "_"), reason)
+    }
+}
+
+/// Gives the router's bare 405s, which already list the supported methods
+/// in `Allow`, the same JSON body as every other error.
+pub async fn method_not_allowed_body(res: Response) -> Response {
+    if res.status() != StatusCode::METHOD_NOT_ALLOWED {
+        return res;
+    }
+    let (mut parts, _) = res.into_parts();
+    parts.headers.remove(CONTENT_LENGTH);
+    let body = ErrorBody::from_status(StatusCode::METHOD_NOT_ALLOWED);
+    (parts, Json(body)).into_response()
+}
+
+impl IntoResponse for ShortenError {
+    fn into_response(self) -> axum::response::Response {
+        let (status, body) = match self {
+            ShortenError::SqlError(e) => {
+                let classified = classify_sql_error(&e);
+                match classified {
+                    ShortenError::StatusCode(_) => error!("Request failed: {}", e),
+                    _ => warn!("Request failed: {}", e),
+                }
+                return classified.into_response();
+            }
+            ShortenError::IoError(_)
+            | ShortenError::Config(_)
+            | ShortenError::Job(_)
+            | ShortenError::SelfTest(_)
+            | ShortenError::Merge(_) => {
+                error!("Request failed: {}", self);
+                let status = StatusCode::INTERNAL_SERVER_ERROR;
+                (status, ErrorBody::from_status(status))
+            }
+            ShortenError::Internal { ref request_id, .. } => {
+                error!("Request failed: {}", self);
+                let status = StatusCode::INTERNAL_SERVER_ERROR;
+                let mut body = ErrorBody::from_status(status);
+                body.details = Some(serde_json::json!({ "request_id": request_id }));
+                (status, body)
+            }
+            ShortenError::StatusCode(e) => (e.0, ErrorBody::from_status(e.0)),
+            ShortenError::Flagged(threat) => (
+                StatusCode::UNPROCESSABLE_ENTITY,
+                ErrorBody::new("flagged", format!("destination flagged as {}", threat)),
+            ),
+            ShortenError::QuotaExceeded(exceeded) => {
+                let retry_after = (exceeded.reset_at - chrono::Utc::now())
+                    .num_seconds()
+                    .max(0);
+                let mut body = ErrorBody::new(
+                    "quota_exceeded",
+                    format!(
+                        "creation quota of {} per {} exhausted",
+                        exceeded.limit,
+                        exceeded.period.as_str()
+                    ),
+                );
+                body.details = serde_json::to_value(&exceeded).ok();
+                return (
+                    StatusCode::TOO_MANY_REQUESTS,
+                    [(RETRY_AFTER, retry_after.to_string())],
+                    Json(body),
+                )
+                    .into_response();
+            }
+            ShortenError::TooManyItems { limit, received } => {
+                let mut body = ErrorBody::new(
+                    "too_many_items",
+                    format!("{} items sent, at most {} are taken", received, limit),
+                );
+                body.details = Some(serde_json::json!({ "limit": limit, "received": received }));
+                (StatusCode::PAYLOAD_TOO_LARGE, body)
+            }
+            ShortenError::IdSpaceExhausted => {
+                error!("{}, consider a longer id", self);
+                (
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    ErrorBody::new("id_space_exhausted", "no unused id could be generated"),
+                )
+            }
+            ShortenError::AliasTaken => (
+                StatusCode::CONFLICT,
+                ErrorBody::new("alias_taken", "the alias is already in use"),
+            ),
+            ShortenError::InvalidAlias { alias, reason } => {
+                let mut body = ErrorBody::new(
+                    "invalid_alias",
+                    format!("{:?} is not a valid alias: {}", alias, reason),
+                );
+                body.details = Some(serde_json::json!({ "attempted": alias }));
+                (StatusCode::UNPROCESSABLE_ENTITY, body)
+            }
+            ShortenError::BlockedDomain(host) => (
+                StatusCode::UNPROCESSABLE_ENTITY,
+                ErrorBody::new(
+                    "blocked_domain",
+                    format!("{} is a private or internal address", host),
+                ),
+            ),
+            ShortenError::InvalidUrl(attempted) => {
+                let mut body = ErrorBody::new(
+                    "invalid_url",
+                    format!("{:?} is not an absolute url", attempted),
+                );
+                body.details = Some(serde_json::json!({ "attempted": attempted }));
+                (StatusCode::UNPROCESSABLE_ENTITY, body)
+            }
+            ShortenError::InvalidGeoTargets { reason, invalid } => {
+                let mut body = ErrorBody::new("invalid_geo_targets", reason);
+                body.details = Some(serde_json::json!({ "invalid": invalid }));
+                (StatusCode::UNPROCESSABLE_ENTITY, body)
+            }
+            ShortenError::Unverified(reason) => {
+                (StatusCode::FORBIDDEN, ErrorBody::new("unverified", reason))
+            }
+            ShortenError::OwnerSuspended { status, message } => {
+                (status, ErrorBody::new("owner_suspended", message))
+            }
+            ShortenError::InvalidArchive(reason) => (
+                StatusCode::UNPROCESSABLE_ENTITY,
+                ErrorBody::new("invalid_archive", reason),
+            ),
+            ShortenError::UniqueViolation { .. } => (
+                StatusCode::CONFLICT,
+                ErrorBody::new("conflict", "a record with the same key already exists"),
+            ),
+            ShortenError::ForeignKeyViolation { .. } => (
+                StatusCode::CONFLICT,
+                ErrorBody::new(
+                    "missing_reference",
+                    "a record it refers to no longer exists",
+                ),
+            ),
+            ShortenError::SelfReference => (
+                StatusCode::UNPROCESSABLE_ENTITY,
+                ErrorBody::new(
+                    "self_reference",
+                    "the destination is one of our short links that doesn't lead elsewhere",
+                ),
+            ),
+            ShortenError::Unavailable {
+                reason,
+                retry_after,
+            } => {
+                return (
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    [(RETRY_AFTER, retry_after.to_string())],
+                    Json(ErrorBody::new("service_unavailable", reason)),
+                )
+                    .into_response();
+            }
+            ShortenError::InvalidBody(rejection) => rejection_body(rejection),
+        };
+        (status, Json(body)).into_response()
+    }
+}
+
+/// What a database error is answered with: 409 for unique and foreign key
+/// violations, 503 when the database can't be reached, retrying soon, and
+/// a plain 500 for anything else.
+pub fn classify_sql_error(e: &sqlx::Error) -> ShortenError {
+    let unreachable = || ShortenError::Unavailable {
+        reason: "the database is unreachable".into(),
+        retry_after: DB_RETRY_AFTER,
+    };
+    match e {
+        sqlx::Error::Database(db) => {
+            let constraint = db.constraint().map(String::from);
+            match db.code().as_deref().unwrap_or_default() {
+                "23505" => ShortenError::UniqueViolation { constraint },
+                "23503" => ShortenError::ForeignKeyViolation { constraint },
+                // connection_exception, too_many_connections, admin_shutdown,
+                // cannot_connect_now
+                code if code.starts_with("08") => unreachable(),
+                "53300" | "57P01" | "57P03" => unreachable(),
+                _ => StatusCodeError(StatusCode::INTERNAL_SERVER_ERROR).into(),
+            }
+        }
+        sqlx::Error::Io(_)
+        | sqlx::Error::Tls(_)
+        | sqlx::Error::PoolTimedOut
+        | sqlx::Error::PoolClosed
+        | sqlx::Error::WorkerCrashed => unreachable(),
+        _ => StatusCodeError(StatusCode::INTERNAL_SERVER_ERROR).into(),
+    }
+}
+
+fn rejection_body(rejection: JsonRejection) -> (StatusCode, ErrorBody) {
+    match rejection {
+        JsonRejection::JsonDataError(e) => {
+            let mut body = ErrorBody::new("invalid_body", e.body_text());
+            body.path = serde_path(&e).filter(|p| p != ".");
+            (StatusCode::UNPROCESSABLE_ENTITY, body)
+        }
+        JsonRejection::JsonSyntaxError(e) => (
+            StatusCode::BAD_REQUEST,
+            ErrorBody::new("malformed_json", e.body_text()),
+        ),
+        JsonRejection::MissingJsonContentType(e) => (
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            ErrorBody::new("unsupported_media_type", e.body_text()),
+        ),
+        other if other.status() == StatusCode::PAYLOAD_TOO_LARGE => (
+            StatusCode::PAYLOAD_TOO_LARGE,
+            ErrorBody::new("payload_too_large", other.body_text()),
+        ),
+        other => (
+            other.status(),
+            ErrorBody::new("invalid_body", other.body_text()),
+        ),
+    }
+}
+
+fn serde_path(err: &(dyn std::error::Error + 'static)) -> Option<String> {
+    let mut source = err.source();
+    while let Some(e) = source {
+        if let Some(e) = e.downcast_ref::<serde_path_to_error::Error<serde_json::Error>>() {
+            return Some(e.path().to_string());
+        }
+        source = e.source();
+    }
+    None
+}
+
+/// `Json` extractor whose rejections use the standard error body.
+pub struct AppJson<T>(pub T);
+
+#[async_trait]
+impl<T, S> FromRequest<S> for AppJson<T>
+where
+    T: DeserializeOwned,
+    S: Send + Sync,
+{
+    type Rejection = ShortenError;
+
+    async fn from_request(req: Request, state: &S) -> Result<Self, Self::Rejection> {
+        match Json::<T>::from_request(req, state).await {
+            Ok(Json(value)) => Ok(AppJson(value)),
+            Err(rejection) => Err(ShortenError::InvalidBody(rejection)),
+        }
+    }
+}
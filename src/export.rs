@@ -0,0 +1,172 @@
+use axum::{
+    async_trait,
+    extract::FromRequestParts,
+    http::{
+        header::{ACCEPT, CONTENT_DISPOSITION, CONTENT_TYPE, VARY},
+        request::Parts,
+        HeaderValue, StatusCode,
+    },
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::Serialize;
+use serde_json::Value;
+use tracing::error;
+
+use crate::{
+    error::{ShortenError, StatusCodeError},
+    AppState,
+};
+
+const CSV: &str = "text/csv";
+
+/// How a listing is answered: CSV for `?format=csv` or an `Accept` that
+/// prefers `text/csv`, JSON otherwise. `?format=json` wins over `Accept`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Json,
+    Csv,
+}
+
+#[async_trait]
+impl FromRequestParts<AppState> for Format {
+    type Rejection = ShortenError;
+
+    async fn from_request_parts(parts: &mut Parts, _: &AppState) -> Result<Self, Self::Rejection> {
+        let query = parts.uri.query().unwrap_or("");
+        let asked = url::form_urlencoded::parse(query.as_bytes())
+            .find(|(k, _)| k == "format")
+            .map(|(_, v)| v.to_ascii_lowercase());
+        match asked.as_deref() {
+            Some("csv") => return Ok(Format::Csv),
+            Some("json") => return Ok(Format::Json),
+            Some(_) => return Err(StatusCodeError(StatusCode::BAD_REQUEST).into()),
+            None => {}
+        }
+        let accept = parts
+            .headers
+            .get_all(ACCEPT)
+            .iter()
+            .filter_map(|v| v.to_str().ok());
+        Ok(if prefers_csv(accept) {
+            Format::Csv
+        } else {
+            Format::Json
+        })
+    }
+}
+
+/// Whether `text/csv` gets a higher quality than JSON, counting wildcards
+/// as JSON since that's the default.
+fn prefers_csv<'a>(accept: impl Iterator<Item = &'a str>) -> bool {
+    let (mut csv, mut json) = (0.0_f32, 0.0_f32);
+    for range in accept.flat_map(|v| v.split(',')) {
+        let mut params = range.split(';').map(str::trim);
+        let media = params.next().unwrap_or("").to_ascii_lowercase();
+        let q = params
+            .find_map(|p| p.strip_prefix("q="))
+            .and_then(|q| q.parse().ok())
+            .unwrap_or(1.0);
+        match media.as_str() {
+            CSV | "text/*" => csv = csv.max(q),
+            "application/json" | "application/*" | "*/*" => json = json.max(q),
+            _ => {}
+        }
+    }
+    csv > json
+}
+
+impl Format {
+    /// `rows` as JSON, or as a CSV download named `filename`.
+    pub fn respond<T: Serialize>(self, filename: &str, rows: Vec<T>) -> Response {
+        let mut res = match self {
+            Format::Json => Json(rows).into_response(),
+            Format::Csv => CsvResponse::new(filename, rows).into_response(),
+        };
+        res.headers_mut()
+            .insert(VARY, HeaderValue::from_static("accept"));
+        res
+    }
+}
+
+/// Rows as an RFC 4180 CSV attachment: a header row of the field names of
+/// the first row, CRLF line ends and quoting where needed.
+///
+/// Rows serialize as structs or maps. Missing and null fields become empty
+/// cells, and nested lists and objects are written as JSON. Rows that
+/// aren't structs get a single `value` column. Without rows the body is
+/// empty, there being no fields to name.
+pub struct CsvResponse<T> {
+    filename: String,
+    rows: Vec<T>,
+}
+
+impl<T: Serialize> CsvResponse<T> {
+    pub fn new(filename: &str, rows: Vec<T>) -> Self {
+        Self {
+            filename: filename.to_string(),
+            rows,
+        }
+    }
+
+    fn render(&self) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        let mut out = csv::WriterBuilder::new()
+            .terminator(csv::Terminator::CRLF)
+            .from_writer(Vec::new());
+        let rows = self
+            .rows
+            .iter()
+            .map(serde_json::to_value)
+            .collect::<Result<Vec<_>, _>>()?;
+        let columns: Vec<String> = match rows.first() {
+            Some(Value::Object(first)) => first.keys().cloned().collect(),
+            Some(_) => vec!["value".into()],
+            None => Vec::new(),
+        };
+        if !columns.is_empty() {
+            out.write_record(&columns)?;
+        }
+        for row in &rows {
+            let record = columns.iter().map(|column| match row {
+                Value::Object(fields) => cell(fields.get(column)),
+                value => cell(Some(value)),
+            });
+            out.write_record(record)?;
+        }
+        Ok(out.into_inner()?)
+    }
+}
+
+fn cell(value: Option<&Value>) -> String {
+    match value {
+        None | Some(Value::Null) => String::new(),
+        Some(Value::String(s)) => s.clone(),
+        Some(value) => value.to_string(),
+    }
+}
+
+impl<T: Serialize> IntoResponse for CsvResponse<T> {
+    fn into_response(self) -> Response {
+        let body = match self.render() {
+            Ok(body) => body,
+            Err(e) => {
+                error!("Failed to write CSV: {}", e);
+                let e: ShortenError = StatusCodeError(StatusCode::INTERNAL_SERVER_ERROR).into();
+                return e.into_response();
+            }
+        };
+        let filename = self.filename.replace(['"', '\\'], "_");
+        let disposition = format!("attachment; filename=\"{}\"", filename);
+        (
+            [
+                (
+                    CONTENT_TYPE,
+                    "text/csv; charset=utf-8; header=present".to_string(),
+                ),
+                (CONTENT_DISPOSITION, disposition),
+            ],
+            body,
+        )
+            .into_response()
+    }
+}
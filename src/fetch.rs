@@ -0,0 +1,185 @@
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+
+use ipnet::IpNet;
+use reqwest::{
+    dns::{Addrs, Name, Resolve, Resolving},
+    redirect::Policy,
+    Client, Response,
+};
+use url::{Host, Url};
+
+const FETCH_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Outbound HTTP client that refuses to talk to private, loopback or
+/// link-local addresses, so server-side fetches can't be aimed at internal
+/// services. Redirects are never followed automatically.
+#[derive(Debug, Clone)]
+pub struct Fetcher {
+    client: Client,
+    allowed: Arc<[IpNet]>,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum FetchError {
+    #[error("destination {0} resolves to a non-public address")]
+    Blocked(String),
+    #[error("unsupported url: {0}")]
+    Unsupported(String),
+    #[error("request failed: {0}")]
+    Request(#[from] reqwest::Error),
+}
+
+impl Fetcher {
+    pub fn new() -> Self {
+        Self::allowing(Vec::new())
+    }
+
+    /// A fetcher that may also reach the non-public addresses in `allowed`,
+    /// for services it's meant to talk to on a private network.
+    pub fn allowing(allowed: Vec<IpNet>) -> Self {
+        let allowed: Arc<[IpNet]> = allowed.into();
+        let client = Client::builder()
+            .redirect(Policy::none())
+            .timeout(FETCH_TIMEOUT)
+            .dns_resolver(Arc::new(PublicResolver {
+                allowed: allowed.clone(),
+            }))
+            .build()
+            .expect("failed to build http client");
+        Self { client, allowed }
+    }
+
+    pub async fn head(&self, url: &Url) -> Result<Response, FetchError> {
+        self.check_url(url)?;
+        Ok(self.client.head(url.clone()).send().await?)
+    }
+
+    pub async fn get(&self, url: &Url) -> Result<Response, FetchError> {
+        self.check_url(url)?;
+        Ok(self.client.get(url.clone()).send().await?)
+    }
+
+    fn check_url(&self, url: &Url) -> Result<(), FetchError> {
+        match check_url(url) {
+            Err(FetchError::Blocked(_)) if is_allowed(&self.allowed, url) => Ok(()),
+            checked => checked,
+        }
+    }
+}
+
+impl Default for Fetcher {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Whether `url` has a literal IP host in one of `allowed`.
+fn is_allowed(allowed: &[IpNet], url: &Url) -> bool {
+    let ip = match url.host() {
+        Some(Host::Ipv4(ip)) => IpAddr::V4(ip),
+        Some(Host::Ipv6(ip)) => IpAddr::V6(ip),
+        _ => return false,
+    };
+    allowed.iter().any(|net| net.contains(&ip))
+}
+
+/// Refuses destinations on non-public addresses before anything is
+/// fetched from them: literal IPs, and any address a hostname resolves to.
+/// Hosts that don't resolve pass, there being nothing to reach, and so do
+/// urls that aren't http(s), which are never fetched.
+pub async fn check_destination(url: &Url) -> Result<(), FetchError> {
+    if !matches!(url.scheme(), "http" | "https") {
+        return Ok(());
+    }
+    match url.host() {
+        Some(Host::Domain(domain)) => {
+            let port = url.port_or_known_default().unwrap_or(0);
+            let Ok(addrs) = tokio::net::lookup_host((domain, port)).await else {
+                return Ok(());
+            };
+            for addr in addrs {
+                if !is_public(addr.ip()) {
+                    return Err(FetchError::Blocked(domain.to_string()));
+                }
+            }
+            Ok(())
+        }
+        _ => check_url(url),
+    }
+}
+
+/// Rejects non-http(s) urls and literal IP hosts outside public ranges.
+/// Hostnames are checked by the resolver at connect time instead.
+fn check_url(url: &Url) -> Result<(), FetchError> {
+    if !matches!(url.scheme(), "http" | "https") {
+        return Err(FetchError::Unsupported(url.to_string()));
+    }
+    match url.host() {
+        Some(Host::Ipv4(ip)) if !is_public(IpAddr::V4(ip)) => {
+            Err(FetchError::Blocked(ip.to_string()))
+        }
+        Some(Host::Ipv6(ip)) if !is_public(IpAddr::V6(ip)) => {
+            Err(FetchError::Blocked(ip.to_string()))
+        }
+        Some(_) => Ok(()),
+        None => Err(FetchError::Unsupported(url.to_string())),
+    }
+}
+
+pub fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            !(ip.is_private()
+                || ip.is_loopback()
+                || ip.is_link_local()
+                || ip.is_broadcast()
+                || ip.is_documentation()
+                || ip.is_unspecified()
+                || ip.is_multicast()
+                || a == 0
+                // 100.64.0.0/10 carrier-grade NAT
+                || (a == 100 && (b & 0xc0) == 64))
+        }
+        IpAddr::V6(ip) => {
+            if let Some(v4) = ip.to_ipv4_mapped() {
+                return is_public(IpAddr::V4(v4));
+            }
+            let first = ip.segments()[0];
+            !(ip.is_loopback()
+                || ip.is_unspecified()
+                || ip.is_multicast()
+                // fc00::/7 unique local
+                || (first & 0xfe00) == 0xfc00
+                // fe80::/10 link local
+                || (first & 0xffc0) == 0xfe80)
+        }
+    }
+}
+
+struct PublicResolver {
+    allowed: Arc<[IpNet]>,
+}
+
+impl Resolve for PublicResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let allowed = self.allowed.clone();
+        Box::pin(async move {
+            let host = name.as_str().to_string();
+            let addrs: Vec<SocketAddr> = tokio::net::lookup_host((host.as_str(), 0))
+                .await?
+                .filter(|addr| {
+                    is_public(addr.ip()) || allowed.iter().any(|net| net.contains(&addr.ip()))
+                })
+                .collect();
+            if addrs.is_empty() {
+                return Err(Box::new(FetchError::Blocked(host)) as _);
+            }
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
@@ -0,0 +1,101 @@
+use std::{convert::Infallible, net::SocketAddr};
+
+use axum::{
+    async_trait,
+    extract::{ConnectInfo, FromRequestParts},
+    http::{request::Parts, uri::Authority, HeaderMap},
+};
+use url::Url;
+
+use crate::AppState;
+
+const X_FORWARDED_HOST: &str = "x-forwarded-host";
+const X_FORWARDED_PROTO: &str = "x-forwarded-proto";
+const X_FORWARDED_PREFIX: &str = "x-forwarded-prefix";
+
+/// The first of the comma-separated values of `name`, which the proxy
+/// nearest the client put there.
+fn first<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    let value = headers.get(name)?.to_str().ok()?;
+    Some(value.split(',').next()?.trim()).filter(|v| !v.is_empty())
+}
+
+/// `base` as the client addressed it through a proxy: with the scheme of
+/// `X-Forwarded-Proto`, the host of `X-Forwarded-Host`, and the path of
+/// `X-Forwarded-Prefix` in place of its own, for a gateway that strips the
+/// prefix before passing requests on. Headers that are missing or don't
+/// hold a plain scheme, host or path leave that part of `base` be. Has no
+/// trailing `/`.
+pub fn external_base(headers: &HeaderMap, base: &str) -> String {
+    let Ok(mut url) = Url::parse(base) else {
+        return base.to_string();
+    };
+    if let Some(proto) = first(headers, X_FORWARDED_PROTO) {
+        if proto.eq_ignore_ascii_case("http") || proto.eq_ignore_ascii_case("https") {
+            let _ = url.set_scheme(&proto.to_ascii_lowercase());
+        }
+    }
+    if let Some(host) = first(headers, X_FORWARDED_HOST) {
+        if let Ok(authority) = host.parse::<Authority>() {
+            if url.set_host(Some(authority.host())).is_ok() {
+                let _ = url.set_port(authority.port_u16());
+            }
+        }
+    }
+    if let Some(prefix) = first(headers, X_FORWARDED_PREFIX) {
+        let plain = prefix.starts_with('/')
+            && !prefix.contains(['?', '#', '\\'])
+            && !prefix.contains(char::is_whitespace)
+            && !prefix.split('/').any(|segment| segment == "..");
+        if plain {
+            url.set_path(prefix);
+        }
+    }
+    url.as_str().trim_end_matches('/').to_string()
+}
+
+/// Where the short links live as the caller sees them: [`external_base`]
+/// of `PUBLIC_URL` for requests from `TRUSTED_PROXIES`, `PUBLIC_URL` as is
+/// for others, whose forwarding headers can't be believed.
+pub struct LinkBase(pub String);
+
+impl LinkBase {
+    pub fn short_url(&self, id: &str) -> String {
+        format!("{}/{}", self.0, id)
+    }
+
+    pub fn stats_url(&self, id: &str) -> String {
+        format!("{}/api/links/{}/stats", self.0, id)
+    }
+
+    pub fn qr_url(&self, id: &str) -> String {
+        format!("{}/api/links/{}/qr", self.0, id)
+    }
+}
+
+#[async_trait]
+impl FromRequestParts<AppState> for LinkBase {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        let base = state.config.base_url();
+        let trusted = parts
+            .extensions
+            .get::<ConnectInfo<SocketAddr>>()
+            .is_some_and(|ConnectInfo(peer)| {
+                let ip = peer.ip();
+                state
+                    .config
+                    .trusted_proxies
+                    .iter()
+                    .any(|net| net.contains(&ip))
+            });
+        if !trusted {
+            return Ok(LinkBase(base));
+        }
+        Ok(LinkBase(external_base(&parts.headers, &base)))
+    }
+}
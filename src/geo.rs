@@ -0,0 +1,265 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    net::IpAddr,
+    path::Path,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use ipnet::IpNet;
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use tracing::{info, warn};
+
+use crate::ShortenError;
+
+/// The key of the destination of visitors no country matched.
+pub const DEFAULT: &str = "default";
+
+/// ISO 3166-1 alpha-2 codes, and `XK`, which GeoIP databases give Kosovo.
+const COUNTRIES: &str = "AD AE AF AG AI AL AM AO AQ AR AS AT AU AW AX AZ \
+    BA BB BD BE BF BG BH BI BJ BL BM BN BO BQ BR BS BT BV BW BY BZ \
+    CA CC CD CF CG CH CI CK CL CM CN CO CR CU CV CW CX CY CZ \
+    DE DJ DK DM DO DZ EC EE EG EH ER ES ET FI FJ FK FM FO FR \
+    GA GB GD GE GF GG GH GI GL GM GN GP GQ GR GS GT GU GW GY \
+    HK HM HN HR HT HU ID IE IL IM IN IO IQ IR IS IT JE JM JO JP \
+    KE KG KH KI KM KN KP KR KW KY KZ LA LB LC LI LK LR LS LT LU LV LY \
+    MA MC MD ME MF MG MH MK ML MM MN MO MP MQ MR MS MT MU MV MW MX MY MZ \
+    NA NC NE NF NG NI NL NO NP NR NU NZ OM PA PE PF PG PH PK PL PM PN PR PS PT PW PY \
+    QA RE RO RS RU RW SA SB SC SD SE SG SH SI SJ SK SL SM SN SO SR SS ST SV SX SY SZ \
+    TC TD TF TG TH TJ TK TL TM TN TO TR TT TV TW TZ UA UG UM US UY UZ \
+    VA VC VE VG VI VN VU WF WS XK YE YT ZA ZM ZW";
+
+/// Whether `code` is an assigned country code, in upper case.
+pub fn is_country(code: &str) -> bool {
+    code.len() == 2 && COUNTRIES.split_ascii_whitespace().any(|c| c == code)
+}
+
+/// Per-country destinations of one link, e.g. a store's regional sites.
+/// Stored and sent as one object of upper-case country codes and
+/// `default`, where the visitors of other countries go, and all of them
+/// when the country can't be told.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct GeoTargets {
+    pub default: String,
+    #[serde(flatten)]
+    pub countries: BTreeMap<String, String>,
+}
+
+impl GeoTargets {
+    /// Checks the targets of a shorten request: every key but `default` a
+    /// country code, in any case, and `default` present. The error lists
+    /// each key that isn't a country.
+    pub fn parse(mut targets: BTreeMap<String, String>) -> Result<Self, ShortenError> {
+        let default = targets.remove(DEFAULT);
+        let mut countries = BTreeMap::new();
+        let mut invalid = Vec::new();
+        for (code, url) in targets {
+            let upper = code.to_ascii_uppercase();
+            match is_country(&upper) {
+                true => {
+                    countries.insert(upper, url);
+                }
+                false => invalid.push(code),
+            }
+        }
+        if !invalid.is_empty() {
+            return Err(ShortenError::InvalidGeoTargets {
+                reason: format!("{} aren't ISO 3166 country codes", invalid.join(", ")),
+                invalid,
+            });
+        }
+        let default = default.ok_or_else(|| ShortenError::InvalidGeoTargets {
+            reason: "geo targets need a default destination".into(),
+            invalid: Vec::new(),
+        })?;
+        Ok(Self { default, countries })
+    }
+
+    /// The key and url of where visitors from `country` go.
+    pub fn pick(&self, country: Option<&str>) -> (&str, &str) {
+        country
+            .and_then(|c| self.countries.get_key_value(c))
+            .map(|(code, url)| (code.as_str(), url.as_str()))
+            .unwrap_or((DEFAULT, &self.default))
+    }
+
+    /// Every destination, to check them all like the link's url.
+    pub fn targets_mut(&mut self) -> impl Iterator<Item = &mut String> {
+        std::iter::once(&mut self.default).chain(self.countries.values_mut())
+    }
+}
+
+/// Countries of address ranges, from a CSV file of `network,country`
+/// lines, e.g. `81.2.69.0/24,GB`. Blank lines and ones starting with `#`
+/// are skipped. Ranges are taken not to overlap, as in the country
+/// databases these files are exported from.
+#[derive(Debug)]
+pub struct GeoIp {
+    /// First and last address, IPv4 ones mapped into IPv6, sorted by the
+    /// first.
+    ranges: Vec<(u128, u128, [u8; 2])>,
+}
+
+impl GeoIp {
+    pub fn load(path: &Path) -> Result<Self, ShortenError> {
+        let problem = |line: usize, what: &str| {
+            ShortenError::Config(format!("{}:{}: {}", path.display(), line, what))
+        };
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| ShortenError::Config(format!("{}: {}", path.display(), e)))?;
+        let mut ranges = Vec::new();
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let Some((network, country)) = line.split_once(',') else {
+                return Err(problem(i + 1, "expected network,country"));
+            };
+            let network: IpNet = network
+                .trim()
+                .parse()
+                .map_err(|_| problem(i + 1, "not a network"))?;
+            let country = country.trim().to_ascii_uppercase();
+            if !is_country(&country) {
+                return Err(problem(i + 1, "not a country code"));
+            }
+            let code = country.as_bytes();
+            ranges.push((
+                key(network.network()),
+                key(network.broadcast()),
+                [code[0], code[1]],
+            ));
+        }
+        ranges.sort_unstable_by_key(|&(first, ..)| first);
+        info!(
+            "Loaded {} GeoIP ranges from {}",
+            ranges.len(),
+            path.display()
+        );
+        Ok(Self { ranges })
+    }
+
+    /// The country of `ip`, `None` if no range has it.
+    pub fn country(&self, ip: IpAddr) -> Option<&str> {
+        let ip = key(ip);
+        let idx = self.ranges.partition_point(|&(first, ..)| first <= ip);
+        let (_, last, code) = self.ranges.get(idx.checked_sub(1)?)?;
+        if ip > *last {
+            return None;
+        }
+        std::str::from_utf8(code).ok()
+    }
+}
+
+fn key(ip: IpAddr) -> u128 {
+    match ip {
+        IpAddr::V4(ip) => u128::from(ip.to_ipv6_mapped()),
+        IpAddr::V6(ip) => u128::from(ip),
+    }
+}
+
+pub async fn init(db: &PgPool) -> Result<(), ShortenError> {
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS link_geo_clicks (
+            link_id TEXT NOT NULL,
+            destination TEXT NOT NULL,
+            clicks BIGINT NOT NULL DEFAULT 0,
+            PRIMARY KEY (link_id, destination)
+        )",
+    )
+    .execute(db)
+    .await?;
+    Ok(())
+}
+
+/// Counts the clicks of links with geo targets per destination they were
+/// sent to, a country code or `default`. Like the click counter, counts
+/// build up in memory and are added to `link_geo_clicks` on every flush.
+#[derive(Debug, Clone, Default)]
+pub struct GeoClicks {
+    pending: Arc<Mutex<HashMap<(String, String), u64>>>,
+}
+
+impl GeoClicks {
+    pub fn record(&self, id: &str, destination: &str) {
+        *self
+            .pending
+            .lock()
+            .unwrap()
+            .entry((id.to_string(), destination.to_string()))
+            .or_default() += 1;
+    }
+
+    /// Adds every pending count in one statement. On failure the counts are
+    /// put back for the next attempt.
+    pub async fn flush(&self, db: &PgPool) -> Result<(), ShortenError> {
+        let batch = std::mem::take(&mut *self.pending.lock().unwrap());
+        if batch.is_empty() {
+            return Ok(());
+        }
+        let mut ids = Vec::with_capacity(batch.len());
+        let mut destinations = Vec::with_capacity(batch.len());
+        let mut clicks = Vec::with_capacity(batch.len());
+        for ((id, destination), count) in &batch {
+            ids.push(id.as_str());
+            destinations.push(destination.as_str());
+            clicks.push(*count as i64);
+        }
+        // counts of deleted links are dropped
+        let added = sqlx::query(
+            "INSERT INTO link_geo_clicks (link_id, destination, clicks)
+             SELECT d.id, d.destination, d.clicks
+             FROM UNNEST($1::TEXT[], $2::TEXT[], $3::BIGINT[]) AS d(id, destination, clicks)
+             WHERE EXISTS (SELECT 1 FROM urls WHERE id = d.id)
+             ON CONFLICT (link_id, destination)
+             DO UPDATE SET clicks = link_geo_clicks.clicks + EXCLUDED.clicks",
+        )
+        .bind(&ids)
+        .bind(&destinations)
+        .bind(&clicks)
+        .execute(db)
+        .await;
+        if let Err(e) = added {
+            let mut pending = self.pending.lock().unwrap();
+            for (key, count) in batch {
+                *pending.entry(key).or_default() += count;
+            }
+            return Err(e.into());
+        }
+        Ok(())
+    }
+
+    /// Clicks of `id` per destination, counting ones not yet flushed.
+    /// Empty for a link without geo targets, or none clicked yet.
+    pub async fn breakdown(
+        &self,
+        db: &PgPool,
+        id: &str,
+    ) -> Result<BTreeMap<String, i64>, ShortenError> {
+        let stored: Vec<(String, i64)> =
+            sqlx::query_as("SELECT destination, clicks FROM link_geo_clicks WHERE link_id = $1")
+                .bind(id)
+                .fetch_all(db)
+                .await?;
+        let mut clicks: BTreeMap<String, i64> = stored.into_iter().collect();
+        for ((link, destination), count) in self.pending.lock().unwrap().iter() {
+            if link == id {
+                *clicks.entry(destination.clone()).or_default() += *count as i64;
+            }
+        }
+        Ok(clicks)
+    }
+
+    /// Flushes every `every`.
+    pub async fn run(self, db: PgPool, every: Duration) {
+        let mut interval = tokio::time::interval(every);
+        loop {
+            interval.tick().await;
+            if let Err(e) = self.flush(&db).await {
+                warn!("Failed to flush geo clicks: {}", e);
+            }
+        }
+    }
+}
@@ -0,0 +1,194 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{PgConnection, PgPool};
+
+use crate::{compress, ShortenError};
+
+/// Changes kept per link; older ones are trimmed as new ones come in.
+pub const MAX_CHANGES_PER_LINK: i64 = 50;
+
+pub async fn init(db: &PgPool) -> Result<(), ShortenError> {
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS url_history (
+            id BIGSERIAL PRIMARY KEY,
+            link_id TEXT NOT NULL,
+            old_url TEXT NOT NULL,
+            new_url TEXT NOT NULL,
+            changed_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+            actor TEXT NOT NULL
+        )",
+    )
+    .execute(db)
+    .await?;
+    // marks the change whose `old_url` is the one the link was created with
+    sqlx::query(
+        "ALTER TABLE url_history ADD COLUMN IF NOT EXISTS first_change BOOLEAN NOT NULL DEFAULT false",
+    )
+    .execute(db)
+    .await?;
+    sqlx::query("CREATE INDEX IF NOT EXISTS url_history_link_id ON url_history (link_id, id)")
+        .execute(db)
+        .await?;
+    Ok(())
+}
+
+/// A change of a link's destination, `actor` being the owner string of the
+/// key that made it. The link's creation is the one without an `old_url`,
+/// its actor the link's owner.
+#[derive(Debug, Serialize, sqlx::FromRow)]
+#[serde(rename_all = "snake_case")]
+pub struct Change {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub old_url: Option<String>,
+    pub new_url: String,
+    pub changed_at: DateTime<Utc>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub actor: Option<String>,
+}
+
+/// `?order=asc|desc` of a link's history, newest first unless asked.
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub struct Order {
+    #[serde(default)]
+    order: Direction,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Direction {
+    Asc,
+    #[default]
+    Desc,
+}
+
+/// Points a link at `url` and records the change, returning the url it had.
+/// Setting the url it already has records nothing. `None` if there's no
+/// such link. Runs on the caller's transaction, so the change is recorded
+/// with whatever else it commits.
+///
+/// The link stops being handed out for re-shortens: its url no longer is
+/// the one it was created for, and may be another deduplicated link's.
+pub async fn set_url(
+    tx: &mut PgConnection,
+    link_id: &str,
+    url: &str,
+    compress_over: usize,
+    actor: &str,
+) -> Result<Option<String>, ShortenError> {
+    let old: Option<(String, Option<Vec<u8>>)> =
+        sqlx::query_as("SELECT url, url_deflated FROM urls WHERE id = $1 FOR UPDATE")
+            .bind(link_id)
+            .fetch_optional(&mut *tx)
+            .await?;
+    let Some((old, deflated)) = old else {
+        return Ok(None);
+    };
+    let old = compress::load(old, deflated.as_deref())?;
+    if old == url {
+        return Ok(Some(old));
+    }
+    let stored = compress::store(url, compress_over);
+    sqlx::query(
+        "UPDATE urls SET url = $2, url_deflated = $3, url_compressed = $3 IS NOT NULL,
+         deduped = false WHERE id = $1",
+    )
+    .bind(link_id)
+    .bind(&stored.url)
+    .bind(&stored.deflated)
+    .execute(&mut *tx)
+    .await?;
+    sqlx::query(
+        "INSERT INTO url_history (link_id, old_url, new_url, actor, first_change)
+         VALUES ($1, $2, $3, $4, NOT EXISTS (SELECT 1 FROM url_history WHERE link_id = $1))",
+    )
+    .bind(link_id)
+    .bind(&old)
+    .bind(url)
+    .bind(actor)
+    .execute(&mut *tx)
+    .await?;
+    sqlx::query(
+        "DELETE FROM url_history WHERE link_id = $1 AND id NOT IN (
+            SELECT id FROM url_history WHERE link_id = $1 ORDER BY id DESC LIMIT $2
+         )",
+    )
+    .bind(link_id)
+    .bind(MAX_CHANGES_PER_LINK)
+    .execute(&mut *tx)
+    .await?;
+    Ok(Some(old))
+}
+
+/// What the creation entry of a link's history is made of.
+#[derive(sqlx::FromRow)]
+struct Created {
+    url: String,
+    url_deflated: Option<Vec<u8>>,
+    created_at: DateTime<Utc>,
+    owner: Option<String>,
+}
+
+/// The changes of a link in `order`, its creation among them while the
+/// kept changes reach back to it. Empty if there's no such link.
+pub async fn list(db: &PgPool, link_id: &str, order: Order) -> Result<Vec<Change>, ShortenError> {
+    // one snapshot, so a change committed meanwhile is in all reads or none
+    let mut tx = db.begin().await?;
+    sqlx::query("SET TRANSACTION ISOLATION LEVEL REPEATABLE READ, READ ONLY")
+        .execute(&mut *tx)
+        .await?;
+    let link: Option<Created> =
+        sqlx::query_as("SELECT url, url_deflated, created_at, owner FROM urls WHERE id = $1")
+            .bind(link_id)
+            .fetch_optional(&mut *tx)
+            .await?;
+    let Some(link) = link else {
+        return Ok(Vec::new());
+    };
+    let mut changes: Vec<Change> = sqlx::query_as(
+        "SELECT old_url, new_url, changed_at, actor FROM url_history
+         WHERE link_id = $1 ORDER BY id",
+    )
+    .bind(link_id)
+    .fetch_all(&mut *tx)
+    .await?;
+    let first: Option<String> =
+        sqlx::query_scalar("SELECT old_url FROM url_history WHERE link_id = $1 AND first_change")
+            .bind(link_id)
+            .fetch_optional(&mut *tx)
+            .await?;
+    tx.commit().await?;
+    // once the first change is trimmed, so is the url it replaced; changes
+    // from before the first was marked can't tell either
+    let original = match first {
+        Some(url) => Some(url),
+        None if changes.is_empty() => Some(compress::load(link.url, link.url_deflated.as_deref())?),
+        None => None,
+    };
+    if let Some(url) = original {
+        changes.insert(
+            0,
+            Change {
+                old_url: None,
+                new_url: url,
+                changed_at: link.created_at,
+                actor: link.owner,
+            },
+        );
+    }
+    if order.order == Direction::Desc {
+        changes.reverse();
+    }
+    Ok(changes)
+}
+
+/// The url a link had before its latest change, `None` if it never changed.
+pub async fn previous(db: &PgPool, link_id: &str) -> Result<Option<String>, ShortenError> {
+    let url = sqlx::query_scalar(
+        "SELECT old_url FROM url_history WHERE link_id = $1 ORDER BY id DESC LIMIT 1",
+    )
+    .bind(link_id)
+    .fetch_optional(db)
+    .await?;
+    Ok(url)
+}
@@ -0,0 +1,96 @@
+//! The headers every redirect and page gets whatever `SECURITY_HEADERS`
+//! says: what destinations learn of the short link through `Referer`, and
+//! what a browser lets our HTML do.
+
+use axum::{
+    extract::{Request, State},
+    http::{
+        header::{
+            CONTENT_SECURITY_POLICY, CONTENT_TYPE, LOCATION, REFERRER_POLICY,
+            X_CONTENT_TYPE_OPTIONS, X_FRAME_OPTIONS,
+        },
+        HeaderMap, HeaderName, HeaderValue,
+    },
+    middleware::Next,
+    response::Response,
+};
+
+use crate::config::Config;
+
+/// Marks a response whose page styles itself inline, so it gets
+/// `HEADERS_PAGE_CONTENT_SECURITY_POLICY` instead of the stricter default.
+/// Put it in the response's extensions.
+#[derive(Debug, Clone, Copy)]
+pub struct InlineStyles;
+
+/// The header values, from the `HEADERS_*` settings.
+#[derive(Debug, Clone)]
+pub struct Hygiene {
+    referrer_policy: HeaderValue,
+    content_security_policy: HeaderValue,
+    page_content_security_policy: HeaderValue,
+    frame_options: HeaderValue,
+}
+
+impl Hygiene {
+    /// The values were validated with the config, those that aren't valid
+    /// header values fall back to the strictest.
+    pub fn new(config: &Config) -> Self {
+        let value = |v: &str, strictest: &'static str| {
+            HeaderValue::from_str(v).unwrap_or(HeaderValue::from_static(strictest))
+        };
+        Self {
+            referrer_policy: value(&config.headers_redirect_referrer_policy, "no-referrer"),
+            content_security_policy: value(
+                &config.headers_content_security_policy,
+                "default-src 'none'",
+            ),
+            page_content_security_policy: value(
+                &config.headers_page_content_security_policy,
+                "default-src 'none'",
+            ),
+            frame_options: value(&config.headers_frame_options, "DENY"),
+        }
+    }
+}
+
+fn is_html(headers: &HeaderMap) -> bool {
+    headers
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.split(';').next())
+        .is_some_and(|mime| mime.trim().eq_ignore_ascii_case("text/html"))
+}
+
+fn set_default(headers: &mut HeaderMap, name: HeaderName, value: &HeaderValue) {
+    if !headers.contains_key(&name) {
+        headers.insert(name, value.clone());
+    }
+}
+
+/// Gives redirects and HTML pages `Referrer-Policy`, pages linking out to
+/// destinations too, and pages `X-Content-Type-Options`, `X-Frame-Options`
+/// and a `Content-Security-Policy`. A header the route set itself is kept.
+pub async fn apply(State(hygiene): State<Hygiene>, req: Request, next: Next) -> Response {
+    let mut res = next.run(req).await;
+    let redirect = res.status().is_redirection() && res.headers().contains_key(LOCATION);
+    let html = is_html(res.headers());
+    let csp = match res.extensions().get::<InlineStyles>() {
+        Some(_) => &hygiene.page_content_security_policy,
+        None => &hygiene.content_security_policy,
+    };
+    let headers = res.headers_mut();
+    if redirect || html {
+        set_default(headers, REFERRER_POLICY, &hygiene.referrer_policy);
+    }
+    if html {
+        set_default(
+            headers,
+            X_CONTENT_TYPE_OPTIONS,
+            &HeaderValue::from_static("nosniff"),
+        );
+        set_default(headers, X_FRAME_OPTIONS, &hygiene.frame_options);
+        set_default(headers, CONTENT_SECURITY_POLICY, csp);
+    }
+    res
+}
@@ -0,0 +1,121 @@
+use std::borrow::Cow;
+
+use idna::domain_to_unicode;
+use percent_encoding::percent_decode_str;
+use url::{Position, Url};
+
+/// Quotes and brackets that come with urls pasted from prose, email
+/// clients (`<https://...>`) and Markdown code spans.
+const WRAPPERS: &[char] = &[
+    '"', '\'', '`', '<', '>', '“', '”', '„', '‘', '’', '‚', '«', '»', '‹', '›',
+];
+
+/// The form a destination is stored in: [`clean`]ed, then parsed and
+/// reserialized so the host is punycode and the path, query and fragment
+/// are percent-encoded, a `%` that starts no escape included. The result is
+/// plain ASCII and always a valid `Location`. Urls already in that form
+/// come back unchanged, so nothing gets encoded twice.
+pub fn normalize(url: &str) -> Option<String> {
+    Url::parse(&escape_stray_percents(&clean(url)))
+        .ok()
+        .map(String::from)
+}
+
+/// A relative destination in the form it's stored in, [`clean`]ed: a path
+/// from the root or from where the short link is, with any query and
+/// fragment. `None` for anything else, a protocol-relative `//host`
+/// included, and for characters a `Location` can't carry.
+pub fn relative(url: &str) -> Option<String> {
+    let url = clean(url);
+    let path = is_relative(&url) && !url.starts_with("//");
+    (path && url.bytes().all(|b| b.is_ascii_graphic())).then_some(url)
+}
+
+/// Whether a stored destination is relative rather than absolute, by its
+/// start alone: stored ones were [`normalize`]d or [`relative`] already,
+/// so unlike either this parses nothing and is cheap enough to ask on
+/// every redirect. A protocol-relative `//host` counts as relative.
+pub fn is_relative(url: &str) -> bool {
+    url.starts_with('/') || url.starts_with("./") || url.starts_with("../")
+}
+
+/// A pasted url without what came along with it unseen or by habit:
+/// invisible characters anywhere, and surrounding whitespace, quotes,
+/// brackets and the `URL:` of `<URL:https://...>`.
+pub fn clean(url: &str) -> String {
+    let url: String = url.chars().filter(|c| !is_invisible(*c)).collect();
+    let mut rest = url.as_str();
+    loop {
+        let trimmed = rest.trim().trim_matches(WRAPPERS);
+        let trimmed = match trimmed.get(..4) {
+            Some(prefix) if prefix.eq_ignore_ascii_case("url:") => &trimmed[4..],
+            _ => trimmed,
+        };
+        if trimmed == rest {
+            return rest.to_string();
+        }
+        rest = trimmed;
+    }
+}
+
+/// Zero-width, formatting and direction marks, which word processors and
+/// chat apps slip into text.
+fn is_invisible(c: char) -> bool {
+    matches!(
+        c,
+        '\u{00AD}'
+            | '\u{180E}'
+            | '\u{200B}'..='\u{200F}'
+            | '\u{202A}'..='\u{202E}'
+            | '\u{2060}'..='\u{2064}'
+            | '\u{2066}'..='\u{2069}'
+            | '\u{FEFF}'
+    )
+}
+
+/// Encodes the `%`s not followed by two hex digits, which the `url` crate
+/// leaves alone, as `%25`.
+fn escape_stray_percents(url: &str) -> Cow<'_, str> {
+    let bytes = url.as_bytes();
+    let stray = |i: usize| {
+        bytes[i] == b'%'
+            && !(i + 2 < bytes.len()
+                && bytes[i + 1].is_ascii_hexdigit()
+                && bytes[i + 2].is_ascii_hexdigit())
+    };
+    if !(0..bytes.len()).any(stray) {
+        return Cow::Borrowed(url);
+    }
+    let mut out = String::with_capacity(url.len() + 2);
+    for (i, c) in url.char_indices() {
+        if stray(i) {
+            out.push_str("%25");
+        } else {
+            out.push(c);
+        }
+    }
+    Cow::Owned(out)
+}
+
+/// The human-readable form of a stored url: a Unicode host and the path,
+/// query and fragment decoded wherever they decode to valid UTF-8.
+pub fn display(url: &str) -> String {
+    let Ok(parsed) = Url::parse(url) else {
+        return url.to_string();
+    };
+    let Some(host) = parsed.host_str() else {
+        return url.to_string();
+    };
+    let (host, _) = domain_to_unicode(host);
+    let rest = &parsed[Position::BeforePath..];
+    let rest = percent_decode_str(rest)
+        .decode_utf8()
+        .map_or_else(|_| rest.to_string(), |decoded| decoded.into_owned());
+    format!(
+        "{}{}{}{}",
+        &parsed[..Position::BeforeHost],
+        host,
+        &parsed[Position::AfterHost..Position::BeforePath],
+        rest
+    )
+}
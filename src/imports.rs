@@ -0,0 +1,258 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+
+use crate::{aliases, compress, idn, jobs::Job, ShortenError};
+
+pub async fn init(db: &PgPool) -> Result<(), ShortenError> {
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS imports (
+            id BIGSERIAL PRIMARY KEY,
+            state TEXT NOT NULL DEFAULT 'running',
+            total_rows INT NOT NULL,
+            created_rows INT NOT NULL DEFAULT 0,
+            unchanged_rows INT NOT NULL DEFAULT 0,
+            failed_rows INT NOT NULL DEFAULT 0,
+            error TEXT,
+            started_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+            finished_at TIMESTAMPTZ
+        )",
+    )
+    .execute(db)
+    .await?;
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS import_errors (
+            import_id BIGINT NOT NULL,
+            line INT NOT NULL,
+            link_id TEXT NOT NULL,
+            url TEXT NOT NULL,
+            code TEXT NOT NULL,
+            message TEXT NOT NULL,
+            PRIMARY KEY (import_id, line)
+        )",
+    )
+    .execute(db)
+    .await?;
+    Ok(())
+}
+
+/// A legacy mapping: the link `id` should answer to and where it goes.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub struct ImportRow {
+    pub id: String,
+    pub url: String,
+}
+
+/// Imports of more rows than the synchronous limit, run by the job worker.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Import {
+    pub import_id: i64,
+    pub rows: Vec<ImportRow>,
+}
+
+impl Job for Import {
+    const KIND: &'static str = "import";
+}
+
+/// An import: `running` until every row was tried, `completed` then even
+/// if some rows failed, `failed` if the import itself did, e.g. when the
+/// database went away. A failed import runs again as its job is retried.
+#[derive(Debug, Serialize, sqlx::FromRow)]
+#[serde(rename_all = "snake_case")]
+pub struct ImportRecord {
+    pub id: i64,
+    pub state: String,
+    pub total_rows: i32,
+    pub created_rows: i32,
+    /// Rows whose id already pointed at their url.
+    pub unchanged_rows: i32,
+    pub failed_rows: i32,
+    pub error: Option<String>,
+    pub started_at: DateTime<Utc>,
+    pub finished_at: Option<DateTime<Utc>>,
+}
+
+/// Why a row wasn't imported. `line` counts rows from 1.
+#[derive(Debug, Serialize, sqlx::FromRow)]
+#[serde(rename_all = "snake_case")]
+pub struct RowError {
+    pub line: i32,
+    #[sqlx(rename = "link_id")]
+    pub id: String,
+    pub url: String,
+    pub code: String,
+    pub message: String,
+}
+
+const COLUMNS: &str = "id, state, total_rows, created_rows, unchanged_rows, failed_rows, error,
+    started_at, finished_at";
+
+pub async fn start(db: &PgPool, total_rows: usize) -> Result<i64, ShortenError> {
+    let total = i32::try_from(total_rows).unwrap_or(i32::MAX);
+    let id = sqlx::query_scalar("INSERT INTO imports (total_rows) VALUES ($1) RETURNING id")
+        .bind(total)
+        .fetch_one(db)
+        .await?;
+    Ok(id)
+}
+
+pub async fn get(db: &PgPool, id: i64) -> Result<Option<ImportRecord>, ShortenError> {
+    let import = sqlx::query_as(&format!("SELECT {} FROM imports WHERE id = $1", COLUMNS))
+        .bind(id)
+        .fetch_optional(db)
+        .await?;
+    Ok(import)
+}
+
+/// The latest imports, newest first.
+pub async fn list(db: &PgPool) -> Result<Vec<ImportRecord>, ShortenError> {
+    let imports = sqlx::query_as(&format!(
+        "SELECT {} FROM imports ORDER BY id DESC LIMIT 100",
+        COLUMNS
+    ))
+    .fetch_all(db)
+    .await?;
+    Ok(imports)
+}
+
+/// The rows of an import that failed, in file order.
+pub async fn errors(db: &PgPool, id: i64) -> Result<Vec<RowError>, ShortenError> {
+    let errors = sqlx::query_as(
+        "SELECT line, link_id, url, code, message FROM import_errors
+         WHERE import_id = $1 ORDER BY line",
+    )
+    .bind(id)
+    .fetch_all(db)
+    .await?;
+    Ok(errors)
+}
+
+/// Imports the rows, keeping the ones that failed and why, and completes
+/// the import. Returns the ids it created. Rows imported by an earlier
+/// attempt count as unchanged.
+pub async fn run(
+    db: &PgPool,
+    import_id: i64,
+    rows: &[ImportRow],
+    compress_over: usize,
+) -> Result<Vec<String>, ShortenError> {
+    sqlx::query(
+        "UPDATE imports SET state = 'running', created_rows = 0, unchanged_rows = 0,
+         failed_rows = 0, error = NULL, finished_at = NULL WHERE id = $1",
+    )
+    .bind(import_id)
+    .execute(db)
+    .await?;
+    sqlx::query("DELETE FROM import_errors WHERE import_id = $1")
+        .bind(import_id)
+        .execute(db)
+        .await?;
+    let (mut created, mut unchanged, mut failed) = (Vec::new(), 0, 0);
+    for (line, row) in (1..).zip(rows) {
+        match import_row(db, row, compress_over).await? {
+            Ok(true) => created.push(row.id.clone()),
+            Ok(false) => unchanged += 1,
+            Err((code, message)) => {
+                failed += 1;
+                sqlx::query(
+                    "INSERT INTO import_errors (import_id, line, link_id, url, code, message)
+                     VALUES ($1, $2, $3, $4, $5, $6)",
+                )
+                .bind(import_id)
+                .bind(line)
+                .bind(&row.id)
+                .bind(&row.url)
+                .bind(code)
+                .bind(message)
+                .execute(db)
+                .await?;
+            }
+        }
+    }
+    sqlx::query(
+        "UPDATE imports SET state = 'completed', created_rows = $2, unchanged_rows = $3,
+         failed_rows = $4, finished_at = now() WHERE id = $1",
+    )
+    .bind(import_id)
+    .bind(created.len() as i32)
+    .bind(unchanged)
+    .bind(failed)
+    .execute(db)
+    .await?;
+    Ok(created)
+}
+
+pub async fn fail(db: &PgPool, import_id: i64, error: &str) -> Result<(), ShortenError> {
+    sqlx::query(
+        "UPDATE imports SET state = 'failed', error = $2, finished_at = now() WHERE id = $1",
+    )
+    .bind(import_id)
+    .bind(error)
+    .execute(db)
+    .await?;
+    Ok(())
+}
+
+/// Whether the row created a link, or the code and message it failed with.
+async fn import_row(
+    db: &PgPool,
+    row: &ImportRow,
+    compress_over: usize,
+) -> Result<Result<bool, (&'static str, String)>, ShortenError> {
+    // ids follow the alias rules, as legacy ones often don't look generated
+    if !aliases::is_valid(&row.id) {
+        return Ok(Err((
+            "invalid_id",
+            "ids are up to 64 letters, digits, - and _, and not a reserved path".into(),
+        )));
+    }
+    let Some(url) = idn::normalize(&row.url) else {
+        return Ok(Err(("invalid_url", "not a valid url".into())));
+    };
+    let mut tx = db.begin().await?;
+    let taken: Option<(String, String, Option<Vec<u8>>)> = sqlx::query_as(
+        "SELECT s.link_id, u.url, u.url_deflated FROM slugs s JOIN urls u ON u.id = s.link_id
+         WHERE s.slug = $1",
+    )
+    .bind(&row.id)
+    .fetch_optional(&mut *tx)
+    .await?;
+    let taken = match taken {
+        Some((link_id, existing, deflated)) => {
+            Some((link_id, compress::load(existing, deflated.as_deref())?))
+        }
+        None => None,
+    };
+    match taken {
+        Some((link_id, existing)) if link_id == row.id && existing == url => return Ok(Ok(false)),
+        Some((_, existing)) => {
+            return Ok(Err((
+                "id_taken",
+                format!("the id already points to {}", existing),
+            )))
+        }
+        None => {}
+    }
+    let stored = compress::store(&url, compress_over);
+    let inserted = sqlx::query(
+        "INSERT INTO urls (id, url, url_deflated, url_compressed, deduped)
+         VALUES ($1, $2, $3, $3 IS NOT NULL, false) ON CONFLICT DO NOTHING",
+    )
+    .bind(&row.id)
+    .bind(&stored.url)
+    .bind(&stored.deflated)
+    .execute(&mut *tx)
+    .await?;
+    let slug =
+        sqlx::query("INSERT INTO slugs (slug, link_id) VALUES ($1, $1) ON CONFLICT DO NOTHING")
+            .bind(&row.id)
+            .execute(&mut *tx)
+            .await?;
+    // taken since the check
+    if inserted.rows_affected() == 0 || slug.rows_affected() == 0 {
+        return Ok(Err(("id_taken", "the id is taken".into())));
+    }
+    tx.commit().await?;
+    Ok(Ok(true))
+}
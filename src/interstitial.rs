@@ -0,0 +1,379 @@
+use std::{collections::HashMap, fs, path::Path};
+
+use axum::{
+    http::{
+        header::{ACCEPT, ACCEPT_LANGUAGE, CACHE_CONTROL, CONTENT_LANGUAGE, CONTENT_TYPE, VARY},
+        HeaderMap, HeaderValue, StatusCode,
+    },
+    response::{IntoResponse, Response},
+};
+use toml::{Table, Value};
+use tracing::warn;
+
+use crate::{hygiene::InlineStyles, ShortenError};
+
+const LAYOUT: &str = include_str!("pages/layout.html");
+const CONFIRM: &str = include_str!("pages/confirm.html");
+const BUILT_IN: &[(&str, &str)] = &[
+    ("en", include_str!("pages/en.toml")),
+    ("es", include_str!("pages/es.toml")),
+    ("zh", include_str!("pages/zh.toml")),
+];
+/// `Vary` of responses that may be either a page or a JSON error.
+pub const VARIES_BY: &str = "accept, accept-language";
+/// Served when the layout doesn't render, so a broken override still
+/// answers with a page.
+const FALLBACK: &str = "<!doctype html>
+<html><head><meta charset=\"utf-8\"><title>Link unavailable</title></head>
+<body><h1>Link unavailable</h1><p>This short link doesn't lead anywhere.</p></body></html>
+";
+
+/// Why a short link didn't redirect.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Page {
+    NotFound,
+    Expired,
+    Disabled,
+    /// A redirect policy asks to confirm leaving for the destination.
+    Confirm,
+}
+
+impl Page {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Page::NotFound => "not_found",
+            Page::Expired => "expired",
+            Page::Disabled => "disabled",
+            Page::Confirm => "confirm",
+        }
+    }
+}
+
+/// The HTML pages browsers get for links that don't redirect: one layout
+/// filled with the texts of the language `Accept-Language` prefers.
+///
+/// The layout and the `en`, `es` and `zh` texts are built in. A directory
+/// can override the layout with a `layout.html` and add or change texts
+/// with `<language>.toml` files, missing texts falling back to the default
+/// language's. The layout's `{{lang}}`, `{{title}}`, `{{message}}`,
+/// `{{outcome}}` and `{{status}}` are replaced, HTML-escaped.
+///
+/// The confirmation page of redirect policies has its own layout, which a
+/// `confirm.html` overrides. It also gets `{{destination}}`,
+/// `{{continue_url}}` and the `{{continue}}` text of its button.
+///
+/// A miss template, when there is one, replaces the layout for links that
+/// aren't found or are gone. Besides the layout's placeholders it gets the
+/// `{{id}}` asked for and a `{{homepage}}` link.
+#[derive(Debug, Clone)]
+pub struct Interstitials {
+    layout: String,
+    confirm: String,
+    miss: Option<String>,
+    /// Where the `{{homepage}}` of miss pages links to.
+    homepage: String,
+    /// Language, then `outcome.field`.
+    texts: HashMap<String, HashMap<String, String>>,
+    default_language: String,
+}
+
+impl Interstitials {
+    pub fn new(dir: Option<&Path>, default_language: &str) -> Result<Self, ShortenError> {
+        let mut texts = HashMap::new();
+        for (language, source) in BUILT_IN {
+            merge(&mut texts, language, source)
+                .map_err(|e| ShortenError::Config(format!("built-in {} texts: {}", language, e)))?;
+        }
+        let mut layout = LAYOUT.to_string();
+        let mut confirm = CONFIRM.to_string();
+        if let Some(dir) = dir {
+            let invalid = |e: &dyn std::fmt::Display| {
+                ShortenError::Config(format!("{}: {}", dir.display(), e))
+            };
+            for entry in fs::read_dir(dir).map_err(|e| invalid(&e))? {
+                let path = entry.map_err(|e| invalid(&e))?.path();
+                let name = path.file_name().and_then(|n| n.to_str()).unwrap_or("");
+                if name == "layout.html" {
+                    layout = fs::read_to_string(&path).map_err(|e| invalid(&e))?;
+                } else if name == "confirm.html" {
+                    confirm = fs::read_to_string(&path).map_err(|e| invalid(&e))?;
+                } else if let Some(language) = name.strip_suffix(".toml") {
+                    let source = fs::read_to_string(&path).map_err(|e| invalid(&e))?;
+                    merge(&mut texts, &language.to_ascii_lowercase(), &source)
+                        .map_err(|e| invalid(&format!("{}: {}", name, e)))?;
+                }
+            }
+        }
+        let default_language = default_language.to_ascii_lowercase();
+        if !texts.contains_key(&default_language) {
+            return Err(ShortenError::Config(format!(
+                "no interstitial texts for the default language {:?}",
+                default_language
+            )));
+        }
+        Ok(Self {
+            layout,
+            confirm,
+            miss: None,
+            homepage: "./".to_string(),
+            texts,
+            default_language,
+        })
+    }
+
+    /// Renders misses with the template at `path`, linking `homepage` or
+    /// else the landing page. An unreadable template is logged and the
+    /// layout kept, a missing page being no reason not to start.
+    pub fn with_miss_template(mut self, path: Option<&Path>, homepage: Option<&str>) -> Self {
+        if let Some(path) = path {
+            match fs::read_to_string(path) {
+                Ok(miss) => self.miss = Some(miss),
+                Err(e) => warn!(
+                    "Failed to read {}, using the built-in page: {}",
+                    path.display(),
+                    e
+                ),
+            }
+        }
+        if let Some(homepage) = homepage {
+            self.homepage = homepage.to_string();
+        }
+        self
+    }
+
+    /// The page for a browser that asked for `id`, `None` for clients that
+    /// asked for JSON and get the structured error instead.
+    pub fn respond(
+        &self,
+        page: Page,
+        status: StatusCode,
+        id: &str,
+        headers: &HeaderMap,
+    ) -> Option<Response> {
+        let accept = headers
+            .get_all(ACCEPT)
+            .iter()
+            .filter_map(|v| v.to_str().ok());
+        if !prefers_html(accept) {
+            return None;
+        }
+        let asked = headers
+            .get_all(ACCEPT_LANGUAGE)
+            .iter()
+            .filter_map(|v| v.to_str().ok());
+        let language = self.language(asked);
+        let values = [("id", id), ("homepage", self.homepage.as_str())];
+        let miss = self.miss.as_deref().and_then(|miss| {
+            self.fill(miss, page, status, language, &values)
+                .map_err(|e| warn!("Failed to render the miss template: {}", e))
+                .ok()
+        });
+        let body = match miss {
+            Some(body) => body,
+            None => self
+                .fill(&self.layout, page, status, language, &values)
+                .unwrap_or_else(|e| {
+                    warn!("Failed to render the {} page: {}", page.as_str(), e);
+                    FALLBACK.to_string()
+                }),
+        };
+        Some(html(status, language, body))
+    }
+
+    /// The page asking to confirm leaving for `destination`, linking to
+    /// `continue_url`. Every client gets it, there being nothing to redirect
+    /// to without confirming.
+    pub fn confirm(&self, headers: &HeaderMap, destination: &str, continue_url: &str) -> Response {
+        let asked = headers
+            .get_all(ACCEPT_LANGUAGE)
+            .iter()
+            .filter_map(|v| v.to_str().ok());
+        let language = self.language(asked);
+        let body = self
+            .render_confirm(language, destination, continue_url)
+            .unwrap_or_else(|e| {
+                warn!("Failed to render the confirm page: {}", e);
+                // still lets the visitor through
+                format!(
+                    "<!doctype html>\n<html><head><meta charset=\"utf-8\"><title>Leaving</title></head>\n\
+                     <body><p>{}</p><p><a href=\"{}\">Continue</a></p></body></html>\n",
+                    escape(destination),
+                    escape(continue_url)
+                )
+            });
+        let mut res = html(StatusCode::OK, language, body);
+        // the continuation url expires
+        res.headers_mut()
+            .insert(CACHE_CONTROL, HeaderValue::from_static("no-store"));
+        res
+    }
+
+    /// The language of the texts to use, by `Accept-Language` quality and
+    /// then order, a regional tag matching its primary language.
+    pub fn language<'a>(&self, accept_language: impl Iterator<Item = &'a str>) -> &str {
+        let mut ranges: Vec<(f32, String)> = accept_language
+            .flat_map(|v| v.split(','))
+            .filter_map(|range| {
+                let mut params = range.split(';').map(str::trim);
+                let tag = params.next()?.to_ascii_lowercase();
+                let q = params
+                    .find_map(|p| p.strip_prefix("q="))
+                    .and_then(|q| q.parse().ok())
+                    .unwrap_or(1.0);
+                (!tag.is_empty() && q > 0.0).then_some((q, tag))
+            })
+            .collect();
+        // stable, so equal qualities keep their order
+        ranges.sort_by(|a, b| b.0.total_cmp(&a.0));
+        for (_, tag) in &ranges {
+            let primary = tag.split('-').next().unwrap_or(tag);
+            for candidate in [tag.as_str(), primary] {
+                if let Some((language, _)) = self.texts.get_key_value(candidate) {
+                    return language;
+                }
+            }
+            if tag == "*" {
+                break;
+            }
+        }
+        &self.default_language
+    }
+
+    pub fn render(&self, page: Page, status: StatusCode, language: &str) -> Result<String, String> {
+        self.fill(&self.layout, page, status, language, &[])
+    }
+
+    pub fn render_confirm(
+        &self,
+        language: &str,
+        destination: &str,
+        continue_url: &str,
+    ) -> Result<String, String> {
+        self.fill(
+            &self.confirm,
+            Page::Confirm,
+            StatusCode::OK,
+            language,
+            &[("destination", destination), ("continue_url", continue_url)],
+        )
+    }
+
+    /// Replaces the placeholders of `layout`, `values` before the texts.
+    fn fill(
+        &self,
+        layout: &str,
+        page: Page,
+        status: StatusCode,
+        language: &str,
+        values: &[(&str, &str)],
+    ) -> Result<String, String> {
+        let text = |field: &str| {
+            let key = format!("{}.{}", page.as_str(), field);
+            [language, &self.default_language, "en"]
+                .iter()
+                .find_map(|l| self.texts.get(*l)?.get(&key))
+                .cloned()
+                .ok_or_else(|| format!("no text for {}", key))
+        };
+        let mut out = String::with_capacity(layout.len());
+        let mut rest = layout;
+        while let Some(start) = rest.find("{{") {
+            out.push_str(&rest[..start]);
+            let end = rest[start..]
+                .find("}}")
+                .ok_or("unclosed placeholder in the layout")?;
+            let name = rest[start + 2..start + end].trim();
+            let value = match name {
+                "lang" => language.to_string(),
+                "outcome" => page.as_str().to_string(),
+                "status" => status.as_u16().to_string(),
+                "title" | "message" => text(name)?,
+                "continue" if page == Page::Confirm => text(name)?,
+                other => match values.iter().find(|(name, _)| *name == other) {
+                    Some((_, value)) => value.to_string(),
+                    None => return Err(format!("unknown placeholder {{{{{}}}}}", other)),
+                },
+            };
+            out.push_str(&escape(&value));
+            rest = &rest[start + end + 2..];
+        }
+        out.push_str(rest);
+        Ok(out)
+    }
+}
+
+fn html(status: StatusCode, language: &str, body: String) -> Response {
+    let mut res = (
+        status,
+        [(
+            CONTENT_TYPE,
+            HeaderValue::from_static("text/html; charset=utf-8"),
+        )],
+        body,
+    )
+        .into_response();
+    res.extensions_mut().insert(InlineStyles);
+    let headers = res.headers_mut();
+    headers.insert(VARY, HeaderValue::from_static(VARIES_BY));
+    if let Ok(language) = HeaderValue::from_str(language) {
+        headers.insert(CONTENT_LANGUAGE, language);
+    }
+    res
+}
+
+/// Adds the texts of a translation file to `language`'s, replacing ones
+/// it already had.
+fn merge(
+    texts: &mut HashMap<String, HashMap<String, String>>,
+    language: &str,
+    source: &str,
+) -> Result<(), toml::de::Error> {
+    let table: Table = source.parse()?;
+    let language = texts.entry(language.to_string()).or_default();
+    for (outcome, fields) in table {
+        let Value::Table(fields) = fields else {
+            continue;
+        };
+        for (field, text) in fields {
+            if let Value::String(text) = text {
+                language.insert(format!("{}.{}", outcome, field), text);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Whether HTML gets a higher quality than JSON, counting wildcards as
+/// JSON so clients that don't say get what they always got.
+fn prefers_html<'a>(accept: impl Iterator<Item = &'a str>) -> bool {
+    let (mut html, mut json) = (0.0_f32, 0.0_f32);
+    for range in accept.flat_map(|v| v.split(',')) {
+        let mut params = range.split(';').map(str::trim);
+        let media = params.next().unwrap_or("").to_ascii_lowercase();
+        let q = params
+            .find_map(|p| p.strip_prefix("q="))
+            .and_then(|q| q.parse().ok())
+            .unwrap_or(1.0);
+        match media.as_str() {
+            "text/html" | "application/xhtml+xml" => html = html.max(q),
+            "application/json" | "application/*" | "*/*" => json = json.max(q),
+            _ => {}
+        }
+    }
+    html > json
+}
+
+pub(crate) fn escape(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            c => out.push(c),
+        }
+    }
+    out
+}
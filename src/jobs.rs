@@ -0,0 +1,253 @@
+use std::{collections::HashMap, future::Future, pin::Pin, sync::Arc, time::Duration};
+
+use chrono::{DateTime, Utc};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::Value;
+use sqlx::PgPool;
+use tracing::{info, warn};
+
+use crate::ShortenError;
+
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+const BASE_BACKOFF: Duration = Duration::from_secs(2);
+const MAX_BACKOFF: Duration = Duration::from_secs(60 * 60);
+/// A job still `running` after this long is assumed to belong to a dead
+/// worker and becomes claimable again.
+const STALE_AFTER_SECS: i64 = 5 * 60;
+
+/// A unit of deferred work. The payload is stored as JSONB and handed back to
+/// the handler registered for `KIND`.
+pub trait Job: Serialize + DeserializeOwned + Send + 'static {
+    const KIND: &'static str;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum JobState {
+    Pending,
+    Running,
+    Done,
+    Failed,
+}
+
+impl JobState {
+    fn as_str(self) -> &'static str {
+        match self {
+            JobState::Pending => "pending",
+            JobState::Running => "running",
+            JobState::Done => "done",
+            JobState::Failed => "failed",
+        }
+    }
+}
+
+#[derive(Debug, Serialize, sqlx::FromRow)]
+#[serde(rename_all = "snake_case")]
+pub struct JobRecord {
+    pub id: i64,
+    pub kind: String,
+    pub payload: Value,
+    pub state: String,
+    pub run_at: DateTime<Utc>,
+    pub attempts: i32,
+    pub last_error: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+type BoxFuture = Pin<Box<dyn Future<Output = Result<(), String>> + Send>>;
+type Handler = Arc<dyn Fn(Value) -> BoxFuture + Send + Sync>;
+
+pub async fn init(db: &PgPool) -> Result<(), ShortenError> {
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS jobs (
+            id BIGSERIAL PRIMARY KEY,
+            kind TEXT NOT NULL,
+            payload JSONB NOT NULL,
+            state TEXT NOT NULL DEFAULT 'pending',
+            run_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+            attempts INT NOT NULL DEFAULT 0,
+            last_error TEXT,
+            locked_at TIMESTAMPTZ,
+            created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+        )",
+    )
+    .execute(db)
+    .await?;
+    sqlx::query("CREATE INDEX IF NOT EXISTS jobs_state_run_at ON jobs (state, run_at)")
+        .execute(db)
+        .await?;
+    Ok(())
+}
+
+pub async fn enqueue<J: Job>(db: &PgPool, job: &J) -> Result<i64, ShortenError> {
+    let payload = serde_json::to_value(job).map_err(|e| ShortenError::Job(e.to_string()))?;
+    let (id,): (i64,) =
+        sqlx::query_as("INSERT INTO jobs (kind, payload) VALUES ($1, $2) RETURNING id")
+            .bind(J::KIND)
+            .bind(payload)
+            .fetch_one(db)
+            .await?;
+    Ok(id)
+}
+
+/// Queues `job` to run at `run_at`, unless a job just like it was queued
+/// before, whatever became of it. Whether it was queued.
+pub async fn enqueue_once_at<J: Job>(
+    db: &PgPool,
+    job: &J,
+    run_at: DateTime<Utc>,
+) -> Result<bool, ShortenError> {
+    let payload = serde_json::to_value(job).map_err(|e| ShortenError::Job(e.to_string()))?;
+    let queued = sqlx::query(
+        "INSERT INTO jobs (kind, payload, run_at) SELECT $1, $2, $3
+         WHERE NOT EXISTS (SELECT 1 FROM jobs WHERE kind = $1 AND payload = $2)",
+    )
+    .bind(J::KIND)
+    .bind(payload)
+    .bind(run_at)
+    .execute(db)
+    .await?
+    .rows_affected();
+    Ok(queued > 0)
+}
+
+pub async fn list(db: &PgPool, state: JobState) -> Result<Vec<JobRecord>, ShortenError> {
+    let jobs = sqlx::query_as(
+        "SELECT id, kind, payload, state, run_at, attempts, last_error, created_at
+         FROM jobs WHERE state = $1 ORDER BY run_at DESC, id DESC LIMIT 100",
+    )
+    .bind(state.as_str())
+    .fetch_all(db)
+    .await?;
+    Ok(jobs)
+}
+
+/// Delay before the next attempt after `attempts` failures: 2s, 4s, 8s, ...
+/// capped at one hour.
+pub fn backoff(attempts: u32) -> Duration {
+    BASE_BACKOFF
+        .checked_mul(2u32.saturating_pow(attempts.saturating_sub(1)))
+        .map_or(MAX_BACKOFF, |d| d.min(MAX_BACKOFF))
+}
+
+#[derive(Clone)]
+pub struct Worker {
+    db: PgPool,
+    max_attempts: i32,
+    handlers: HashMap<&'static str, Handler>,
+}
+
+impl Worker {
+    pub fn new(db: PgPool, max_attempts: u32) -> Self {
+        Self {
+            db,
+            max_attempts: max_attempts as i32,
+            handlers: HashMap::new(),
+        }
+    }
+
+    pub fn register<J, F, Fut>(mut self, handler: F) -> Self
+    where
+        J: Job,
+        F: Fn(J) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<(), String>> + Send + 'static,
+    {
+        let handler = Arc::new(handler);
+        self.handlers.insert(
+            J::KIND,
+            Arc::new(move |payload| {
+                let handler = handler.clone();
+                Box::pin(async move {
+                    let job = serde_json::from_value::<J>(payload).map_err(|e| e.to_string())?;
+                    handler(job).await
+                })
+            }),
+        );
+        self
+    }
+
+    pub async fn run(self) {
+        info!("Job worker started");
+        loop {
+            match self.run_once().await {
+                Ok(true) => continue,
+                Ok(false) => {}
+                Err(e) => warn!("Job worker error: {}", e),
+            }
+            tokio::time::sleep(POLL_INTERVAL).await;
+        }
+    }
+
+    /// Claims and runs at most one due job. Returns whether a job was found.
+    pub async fn run_once(&self) -> Result<bool, ShortenError> {
+        let Some(job) = self.claim().await? else {
+            return Ok(false);
+        };
+        let result = match self.handlers.get(job.kind.as_str()) {
+            Some(handler) => handler(job.payload).await,
+            None => Err(format!("no handler registered for job kind {:?}", job.kind)),
+        };
+        match result {
+            Ok(()) => {
+                sqlx::query("UPDATE jobs SET state = 'done', locked_at = NULL WHERE id = $1")
+                    .bind(job.id)
+                    .execute(&self.db)
+                    .await?;
+            }
+            Err(e) if job.attempts >= self.max_attempts => {
+                warn!("Job {} ({}) failed permanently: {}", job.id, job.kind, e);
+                sqlx::query(
+                    "UPDATE jobs SET state = 'failed', last_error = $2, locked_at = NULL WHERE id = $1",
+                )
+                .bind(job.id)
+                .bind(e)
+                .execute(&self.db)
+                .await?;
+            }
+            Err(e) => {
+                let delay = backoff(job.attempts as u32);
+                warn!(
+                    "Job {} ({}) failed, retrying in {:?}: {}",
+                    job.id, job.kind, delay, e
+                );
+                sqlx::query(
+                    "UPDATE jobs SET state = 'pending', last_error = $2, locked_at = NULL,
+                     run_at = now() + make_interval(secs => $3) WHERE id = $1",
+                )
+                .bind(job.id)
+                .bind(e)
+                .bind(delay.as_secs_f64())
+                .execute(&self.db)
+                .await?;
+            }
+        }
+        Ok(true)
+    }
+
+    async fn claim(&self) -> Result<Option<ClaimedJob>, ShortenError> {
+        let job = sqlx::query_as(
+            "UPDATE jobs SET state = 'running', attempts = attempts + 1, locked_at = now()
+             WHERE id = (
+                SELECT id FROM jobs
+                WHERE (state = 'pending' AND run_at <= now())
+                   OR (state = 'running' AND locked_at < now() - make_interval(secs => $1))
+                ORDER BY run_at
+                FOR UPDATE SKIP LOCKED
+                LIMIT 1
+             )
+             RETURNING id, kind, payload, attempts",
+        )
+        .bind(STALE_AFTER_SECS as f64)
+        .fetch_optional(&self.db)
+        .await?;
+        Ok(job)
+    }
+}
+
+#[derive(sqlx::FromRow)]
+struct ClaimedJob {
+    id: i64,
+    kind: String,
+    payload: Value,
+    attempts: i32,
+}
@@ -0,0 +1,224 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use serde::Serialize;
+
+/// Each link's latencies are kept per slot of this long, the window being
+/// made of as many slots as fit.
+pub const SLOT: Duration = Duration::from_secs(60);
+
+/// Per-link series of `redirect_duration_seconds` left unrecorded this long
+/// are dropped from the exporter, so links that stop being tracked don't
+/// each keep a series forever.
+pub const SERIES_IDLE_TIMEOUT: Duration = Duration::from_secs(60 * 60);
+
+/// Latencies are counted in buckets this many times wider than the one
+/// before, so a percentile is off by a tenth at most.
+const GROWTH: f64 = 1.2;
+/// The first bucket holds everything up to a microsecond, the last one
+/// everything from about a minute on.
+const BUCKETS: usize = 100;
+
+/// Redirect latency percentiles of the busiest links, over a sliding
+/// window, for spotting links slower than the rest.
+///
+/// At most `capacity` links are tracked, each with a histogram per
+/// [`SLOT`] of the window, bounding the memory taken however many links
+/// are followed. Recordings for other links are ignored, and labeled
+/// `other` in the metric. Once a slot, the links without traffic in the
+/// window are dropped and, if that leaves no room, the quietest quarter.
+#[derive(Debug, Clone)]
+pub struct LatencyTracker {
+    links: Arc<Mutex<HashMap<String, Slots>>>,
+    capacity: usize,
+    slots: usize,
+    started: Instant,
+}
+
+/// A ring of histograms, the one of slot `n` at `n % len`.
+#[derive(Debug)]
+struct Slots {
+    ring: Vec<Histogram>,
+}
+
+#[derive(Debug, Clone, Default)]
+struct Histogram {
+    slot: u64,
+    /// Empty until something is recorded in the slot.
+    counts: Vec<u32>,
+}
+
+/// Latencies of one link, in milliseconds, `None` without samples.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub struct Percentiles {
+    pub samples: u64,
+    pub p50_ms: Option<f64>,
+    pub p95_ms: Option<f64>,
+    pub p99_ms: Option<f64>,
+}
+
+impl LatencyTracker {
+    pub fn new(capacity: usize, window: Duration) -> Self {
+        Self {
+            links: Default::default(),
+            capacity: capacity.max(1),
+            slots: window.as_secs().div_ceil(SLOT.as_secs()).max(1) as usize,
+            started: Instant::now(),
+        }
+    }
+
+    /// Records a redirect of link `id` that took `latency`.
+    pub fn record(&self, id: &str, latency: Duration) {
+        let tracked = self.record_at(id, latency, Instant::now());
+        let link = if tracked {
+            id.to_string()
+        } else {
+            "other".into()
+        };
+        metrics::histogram!("redirect_duration_seconds", "link" => link)
+            .record(latency.as_secs_f64());
+    }
+
+    /// Like [`record`](Self::record) at `now`, without the metric. Returns
+    /// whether the link is tracked.
+    pub fn record_at(&self, id: &str, latency: Duration, now: Instant) -> bool {
+        let slot = self.slot(now);
+        let mut links = self.links.lock().unwrap();
+        if !links.contains_key(id) && links.len() >= self.capacity {
+            return false;
+        }
+        let slots = links.entry(id.to_string()).or_insert_with(|| Slots {
+            ring: vec![Histogram::default(); self.slots],
+        });
+        let len = slots.ring.len();
+        let histogram = &mut slots.ring[(slot % len as u64) as usize];
+        if histogram.slot != slot || histogram.counts.is_empty() {
+            histogram.slot = slot;
+            histogram.counts.clear();
+            histogram.counts.resize(BUCKETS, 0);
+        }
+        let count = &mut histogram.counts[bucket(latency)];
+        *count = count.saturating_add(1);
+        true
+    }
+
+    /// Link `id`'s percentiles over the last `window`, at most the whole
+    /// window, `None` unless it's tracked.
+    pub fn percentiles(&self, id: &str, window: Duration) -> Option<Percentiles> {
+        self.percentiles_at(id, window, Instant::now())
+    }
+
+    pub fn percentiles_at(&self, id: &str, window: Duration, now: Instant) -> Option<Percentiles> {
+        let slot = self.slot(now);
+        let wanted = window
+            .as_secs()
+            .div_ceil(SLOT.as_secs())
+            .clamp(1, self.slots as u64);
+        let links = self.links.lock().unwrap();
+        let slots = links.get(id)?;
+        let mut counts = vec![0u64; BUCKETS];
+        for histogram in slots.recent(slot, wanted) {
+            for (total, count) in counts.iter_mut().zip(&histogram.counts) {
+                *total += *count as u64;
+            }
+        }
+        let samples = counts.iter().sum();
+        let at = |quantile: f64| {
+            let rank = ((samples as f64 * quantile).ceil() as u64).max(1);
+            let mut seen = 0;
+            counts.iter().position(|count| {
+                seen += count;
+                seen >= rank
+            })
+        };
+        let ms = |quantile| {
+            (samples > 0)
+                .then(|| at(quantile).map(midpoint_ms))
+                .flatten()
+        };
+        Some(Percentiles {
+            samples,
+            p50_ms: ms(0.5),
+            p95_ms: ms(0.95),
+            p99_ms: ms(0.99),
+        })
+    }
+
+    /// Links tracked now.
+    pub fn len(&self) -> usize {
+        self.links.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Drops the links without traffic in the window and, if that leaves
+    /// no room, the quietest quarter.
+    pub fn evict_at(&self, now: Instant) {
+        let slot = self.slot(now);
+        let all = self.slots as u64;
+        let mut links = self.links.lock().unwrap();
+        links.retain(|_, slots| slots.recent(slot, all).next().is_some());
+        if links.len() < self.capacity {
+            return;
+        }
+        let mut ranked: Vec<(u64, String)> = links
+            .iter()
+            .map(|(id, slots)| {
+                let samples = slots
+                    .recent(slot, all)
+                    .flat_map(|h| &h.counts)
+                    .map(|&c| c as u64)
+                    .sum();
+                (samples, id.clone())
+            })
+            .collect();
+        ranked.sort();
+        for (_, id) in ranked.into_iter().take(self.capacity.div_ceil(4)) {
+            links.remove(&id);
+        }
+    }
+
+    /// Evicts once a slot.
+    pub async fn run(self) {
+        let mut interval = tokio::time::interval(SLOT);
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            self.evict_at(Instant::now());
+        }
+    }
+
+    fn slot(&self, now: Instant) -> u64 {
+        now.saturating_duration_since(self.started).as_secs() / SLOT.as_secs()
+    }
+}
+
+impl Slots {
+    /// The histograms of the `count` slots up to `slot` that have samples.
+    fn recent(&self, slot: u64, count: u64) -> impl Iterator<Item = &Histogram> {
+        self.ring.iter().filter(move |histogram| {
+            !histogram.counts.is_empty() && histogram.slot <= slot && slot - histogram.slot < count
+        })
+    }
+}
+
+fn bucket(latency: Duration) -> usize {
+    let micros = latency.as_secs_f64() * 1e6;
+    if micros <= 1.0 {
+        return 0;
+    }
+    ((micros.ln() / GROWTH.ln()).ceil() as usize).min(BUCKETS - 1)
+}
+
+/// Halfway between the bounds of `bucket`, in milliseconds.
+fn midpoint_ms(bucket: usize) -> f64 {
+    let upper = GROWTH.powi(bucket as i32);
+    let lower = if bucket == 0 { 0.0 } else { upper / GROWTH };
+    (lower + upper) / 2.0 / 1000.0
+}
@@ -0,0 +1,4410 @@
+mod advisor;
+mod aliases;
+pub mod api;
+mod audit;
+pub mod auth;
+pub mod backup;
+pub mod bloom;
+pub mod breaker;
+mod bulk;
+pub mod canonical;
+pub mod casing;
+pub mod claims;
+mod clicks;
+#[cfg(feature = "client")]
+pub mod client;
+pub mod client_ip;
+mod coalesce;
+mod compress;
+mod concurrency;
+pub mod config;
+mod deadline;
+pub mod dedup;
+pub mod deprecation;
+pub mod digest;
+pub mod error;
+mod export;
+pub mod fetch;
+pub mod forwarded;
+pub mod geo;
+mod history;
+pub mod hygiene;
+pub mod idn;
+mod imports;
+pub mod interstitial;
+pub mod jobs;
+pub mod latency;
+pub mod linkcheck;
+mod links;
+pub mod logging;
+mod maintenance;
+pub mod merge;
+mod multiplex;
+mod owners;
+pub mod platform;
+pub mod policies;
+pub mod qr;
+pub mod query;
+mod quota;
+mod ratelimit;
+mod repair;
+mod replicas;
+mod reports;
+mod retry;
+mod route_metrics;
+mod schema;
+mod screen;
+pub mod selftest;
+mod shadow;
+pub mod signing;
+pub mod slug;
+pub mod smtp;
+mod snapshots;
+mod spikes;
+mod tags;
+pub mod throttle;
+pub mod trace;
+mod uniques;
+mod upgrade;
+pub mod version;
+mod webhook;
+
+use std::{
+    collections::HashSet,
+    convert::Infallible,
+    future::Future,
+    net::IpAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use axum::{
+    body::Body,
+    error_handling::HandleErrorLayer,
+    extract::{DefaultBodyLimit, Extension, Path, Query, RawQuery, State},
+    http::{
+        header::{
+            AUTHORIZATION, CACHE_CONTROL, CONTENT_DISPOSITION, CONTENT_TYPE, LOCATION,
+            REFERRER_POLICY, SERVER, STRICT_TRANSPORT_SECURITY, USER_AGENT, VARY,
+            X_CONTENT_TYPE_OPTIONS, X_FRAME_OPTIONS,
+        },
+        HeaderMap, HeaderName, HeaderValue, Method, StatusCode,
+    },
+    middleware,
+    response::{
+        sse::{self, KeepAlive, Sse},
+        Html, IntoResponse, Response,
+    },
+    routing::{delete, get, patch, post, MethodRouter},
+    Json, Router,
+};
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use chrono::{DateTime, Utc};
+use metrics_exporter_prometheus::{PrometheusBuilder, PrometheusHandle};
+use serde::{Deserialize, Serialize};
+use sqlx::{postgres::PgPoolOptions, Connection as _, PgConnection, PgPool};
+use tokio::sync::watch;
+use tokio_stream::{
+    wrappers::{errors::BroadcastStreamRecvError, BroadcastStream, ReceiverStream},
+    Stream, StreamExt as _,
+};
+use tower::{BoxError, ServiceBuilder};
+use tower_http::{
+    cors::{AllowOrigin, CorsLayer},
+    request_id::{MakeRequestUuid, PropagateRequestIdLayer, RequestId, SetRequestIdLayer},
+    set_header::SetResponseHeaderLayer,
+    trace::TraceLayer,
+};
+use tracing::{info, warn};
+
+use crate::{
+    advisor::IndexAdvisor,
+    api::{
+        KitAsset, KitPart, KitReq, LinkKit, LinkStats, QrImage, Resolved, ShortReq, ShortRes,
+        REDIRECT_STATUSES,
+    },
+    auth::{Admin, ApiKey, KeyRecord, Manager, OptionalApiKey, Scope},
+    bloom::SlugFilter,
+    breaker::Breaker,
+    claims::{Challenge, Transfer},
+    clicks::{ClickCounter, ClickFeed},
+    client_ip::ClientIp,
+    coalesce::Coalescer,
+    concurrency::Limit,
+    config::ShadowRoute,
+    deadline::{Budget, Guarded},
+    dedup::ClickDedup,
+    deprecation::{DeprecatedRoute, Deprecation, Deprecations},
+    error::{AppJson, ErrorBody, StatusCodeError},
+    export::Format,
+    fetch::{FetchError, Fetcher},
+    forwarded::LinkBase,
+    geo::{GeoClicks, GeoIp, GeoTargets},
+    hygiene::Hygiene,
+    imports::{Import, ImportRecord, ImportRow},
+    interstitial::{Interstitials, Page},
+    jobs::{JobRecord, JobState, Worker},
+    latency::{LatencyTracker, Percentiles},
+    links::{AdminLink, Sort},
+    logging::Logging,
+    maintenance::Maintenance,
+    owners::{OwnerStatus, Suspensions},
+    platform::{Platform, PlatformTargets},
+    policies::{Continuations, NewPolicy, Policies, Policy},
+    qr::QrCode,
+    quota::{Quota, Usage},
+    ratelimit::RateLimiter,
+    replicas::Replicas,
+    reports::ReportSummary,
+    retry::Transient,
+    screen::{FlaggedLink, Rescreen, Screener, Verdict},
+    shadow::Shadow,
+    signing::{ResponseSigner, Signer, SIGNATURE_HEADER},
+    slug::{IdCase, IdGenerator, IdStrategy, RedirectSlug, Slug},
+    snapshots::{Granularity, Snapshot},
+    spikes::SpikeDetector,
+    throttle::TokenBucket,
+    trace::Trace,
+    uniques::{Interval, VisitorCounter},
+    upgrade::Upgrader,
+    version::BuildInfo,
+    webhook::{Event, Webhook},
+};
+
+pub use crate::{config::Config, error::ShortenError};
+
+/// The fields of a signed redirect, `signature` being the `v1` hex of its
+/// header and `timestamp` its `t`.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "snake_case")]
+struct VerifyReq {
+    id: String,
+    url: String,
+    status: u16,
+    timestamp: i64,
+    signature: String,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+struct VerifyRes {
+    valid: bool,
+}
+
+/// What anyone may know about a link, which leaves out its notes.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+struct LinkInfo {
+    id: String,
+    url: String,
+    description: Option<String>,
+    created_at: DateTime<Utc>,
+}
+
+/// What a chat bot expanding a link is told about it.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+struct LinkPreview {
+    id: String,
+    url: String,
+    description: Option<String>,
+    created_at: DateTime<Utc>,
+    owner: Option<String>,
+    tags: Vec<String>,
+    enabled: bool,
+    /// `active`, `expired`, `exhausted` or `disabled`.
+    status: &'static str,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "snake_case")]
+struct PreviewReq {
+    /// Comma-separated fields to answer with, all of them by default.
+    #[serde(default)]
+    fields: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+struct AliasReq {
+    alias: String,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+struct AliasesRes {
+    slugs: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+struct AliasAvailableRes {
+    available: bool,
+}
+
+/// Exactly one filter is required.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "snake_case")]
+struct LinksQuery {
+    tag: Option<String>,
+    url: Option<String>,
+    #[serde(flatten)]
+    sort: Sort,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "snake_case")]
+struct StatsHistoryQuery {
+    from: Option<DateTime<Utc>>,
+    to: Option<DateTime<Utc>>,
+    #[serde(default)]
+    granularity: Granularity,
+}
+
+/// Longest range of a timeseries, ten years.
+const MAX_TIMESERIES_DAYS: u32 = 3660;
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "snake_case")]
+struct TimeseriesQuery {
+    #[serde(default)]
+    interval: Interval,
+    #[serde(default = "default_timeseries_days")]
+    days: u32,
+}
+
+fn default_timeseries_days() -> u32 {
+    30
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "snake_case")]
+struct JobsQuery {
+    state: JobState,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "snake_case")]
+struct RestoreQuery {
+    /// Restores over the existing data, which is emptied first.
+    #[serde(default)]
+    force: bool,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+struct RestoreRes {
+    job_id: i64,
+    files: usize,
+    rows: u64,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+struct ReportReq {
+    reason: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "action", rename_all = "lowercase", deny_unknown_fields)]
+enum ReportAction {
+    /// Disable the link, keeping the reports for reference.
+    Disable,
+    /// Delete the link along with its reports.
+    Delete,
+    /// Drop the reports and leave the link alone.
+    Dismiss,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+struct CreateKeyReq {
+    label: String,
+    scopes: Vec<Scope>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+struct CreateKeyRes {
+    id: i64,
+    /// Shown once; only a hash is stored.
+    key: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+struct UpdateLinkReq {
+    /// A new destination, recorded in the link's history.
+    url: Option<String>,
+    enabled: Option<bool>,
+    forward_query: Option<bool>,
+    /// One of [`REDIRECT_STATUSES`].
+    redirect_status: Option<u16>,
+    /// `null` clears the notes; leaving the field out keeps them.
+    #[serde(default, deserialize_with = "present")]
+    notes: Option<Option<String>>,
+    /// Clicks past which the link keeps working but alerts, once. `null`
+    /// clears it.
+    #[serde(default, deserialize_with = "present")]
+    click_cap: Option<Option<u64>>,
+}
+
+/// Tells a field explicitly set to `null` apart from a missing one.
+fn present<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    T::deserialize(deserializer).map(Some)
+}
+
+/// An [`UpdateLinkReq`] that passed its checks, `None` leaving a field as
+/// it is.
+#[derive(Debug, Default)]
+struct LinkUpdate<'a> {
+    url: Option<&'a str>,
+    enabled: Option<bool>,
+    forward_query: Option<bool>,
+    redirect_status: Option<u16>,
+    notes: Option<Option<&'a str>>,
+    click_cap: Option<Option<i64>>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+struct ExpiryReq {
+    /// From now on; `null` makes the link permanent. Required, so an empty
+    /// body can't clear it by accident.
+    #[serde(deserialize_with = "present")]
+    expires_in_secs: Option<Option<u64>>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+struct ExpiryRes {
+    expires_at: Option<DateTime<Utc>>,
+}
+
+/// How many seconds a new link lives, `None` for ever, and whether the
+/// lifetime asked for was cut short. Links created without a key live
+/// `anonymous_ttl` at most, and that long unless they ask for less; zero
+/// leaves them be. Links created with a key live as long as they ask.
+fn lifetime(
+    requested: Option<u64>,
+    anonymous: bool,
+    anonymous_ttl: Duration,
+) -> (Option<u64>, bool) {
+    let cap = anonymous_ttl.as_secs();
+    if !anonymous || cap == 0 {
+        return (requested, false);
+    }
+    match requested {
+        Some(secs) if secs > cap => (Some(cap), true),
+        Some(secs) => (Some(secs), false),
+        None => (Some(cap), false),
+    }
+}
+
+/// When a link living `secs` from now expires, 422 if that's out of range.
+/// Always by this server's clock: clients only ever send a lifetime, so
+/// theirs being off can't move the expiry.
+fn expiry_in(secs: u64) -> Result<DateTime<Utc>, ShortenError> {
+    i64::try_from(secs)
+        .ok()
+        .and_then(chrono::Duration::try_seconds)
+        .and_then(|ttl| Utc::now().checked_add_signed(ttl))
+        .ok_or(StatusCodeError(StatusCode::UNPROCESSABLE_ENTITY).into())
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+struct ImportReq {
+    rows: Vec<ImportRow>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+struct CountRes {
+    total: i64,
+}
+
+#[derive(Debug, Clone)]
+pub struct AppState {
+    db: PgState,
+    config: Arc<Config>,
+    upgrader: Upgrader,
+    report_limiter: RateLimiter,
+    /// Fetches the proofs of link claims and traces destinations.
+    fetcher: Fetcher,
+    screener: Screener,
+    metrics: PrometheusHandle,
+    clicks: ClickFeed,
+    counter: ClickCounter,
+    spikes: SpikeDetector,
+    /// `None` unless `LINK_LATENCY` is on.
+    latency: Option<LatencyTracker>,
+    /// `None` unless `CLICK_DEDUP_WINDOW_SECS` is set.
+    dedup: Option<ClickDedup>,
+    visitors: VisitorCounter,
+    /// Countries of visitors, `None` without `GEOIP_PATH`.
+    geoip: Option<Arc<GeoIp>>,
+    geo_clicks: GeoClicks,
+    /// What the request log keeps of redirects and urls.
+    logging: Logging,
+    maintenance: Maintenance,
+    /// Checks query plans when `INDEX_ADVISOR` is set.
+    advisor: Option<IndexAdvisor>,
+    /// Pages for browsers following links that don't redirect.
+    interstitials: Arc<Interstitials>,
+    /// Which redirects are confirmed first, and the continuations of their
+    /// confirmation pages.
+    policies: Policies,
+    /// Owners whose links don't redirect.
+    suspensions: Suspensions,
+    continuations: Continuations,
+    /// Routes on their way out, and the calls they still get.
+    deprecations: Deprecations,
+    /// Mirrors sampled traffic when `SHADOW_BASE_URL` is set.
+    shadow: Option<Shadow>,
+    robots_txt: Arc<str>,
+    /// Signs redirects when `RESPONSE_SIGNING_KEY` is set.
+    response_signer: Option<ResponseSigner>,
+    /// Collapses bursts of identical shorten requests.
+    shortens: Coalescer<ShortenAttempt, (Shortened, bool, Option<String>)>,
+    /// Flipped on shutdown so long-lived streams end and let the server
+    /// drain.
+    closing: Arc<watch::Sender<bool>>,
+}
+
+/// Writes use the primary `db`. The redirect lookup and reads that are fine
+/// being slightly stale go to the replicas when there are any, in turn, see
+/// [`read`](Self::read).
+#[derive(Debug, Clone)]
+pub struct PgState {
+    db: PgPool,
+    replicas: Replicas,
+    ids: IdGenerator,
+    signer: Signer,
+    max_generation_attempts: u32,
+    slow_query: Duration,
+    query_timeout: Duration,
+    /// Fails link lookups and inserts fast while the database is in
+    /// trouble, when `DB_BREAKER_ERROR_PERCENT` is set.
+    breaker: Option<Breaker>,
+    slugs: Option<Arc<SlugFilter>>,
+    compress_urls_over: usize,
+}
+
+#[derive(Debug, sqlx::FromRow)]
+struct Records {
+    #[sqlx(default)]
+    id: String,
+    #[sqlx(default)]
+    url: String,
+    #[sqlx(default)]
+    enabled: bool,
+    #[sqlx(default)]
+    expires_at: Option<DateTime<Utc>>,
+    #[sqlx(default)]
+    forward_query: bool,
+    #[sqlx(default)]
+    owner: Option<String>,
+    #[sqlx(default)]
+    description: Option<String>,
+    #[sqlx(default)]
+    created_at: DateTime<Utc>,
+    #[sqlx(default)]
+    platform_targets: Option<sqlx::types::Json<PlatformTargets>>,
+    #[sqlx(default)]
+    geo_targets: Option<sqlx::types::Json<GeoTargets>>,
+    #[sqlx(default)]
+    url_deflated: Option<Vec<u8>>,
+    #[sqlx(default)]
+    redirect_status: Option<i16>,
+    #[sqlx(default)]
+    max_uses: Option<i32>,
+    #[sqlx(default)]
+    uses: i32,
+    #[sqlx(default)]
+    destinations: Option<i32>,
+}
+
+impl Records {
+    /// Replaces the stored form of a compressed url with the url.
+    fn inflated(mut self) -> Result<Self, sqlx::Error> {
+        compress::inflate(&mut self.url, self.url_deflated.take())?;
+        Ok(self)
+    }
+}
+
+/// What a shorten call asks the store for.
+struct NewLink<'a> {
+    url: &'a str,
+    expires_at: Option<DateTime<Utc>>,
+    forward_query: bool,
+    /// Hand back an existing deduped link to the same url and of the same
+    /// owner instead, anonymous ones sharing theirs. Ignored with an alias.
+    dedupe: bool,
+    notes: Option<&'a str>,
+    owner: Option<String>,
+    /// Id picked by the caller instead of a generated one.
+    alias: Option<&'a str>,
+    description: Option<&'a str>,
+    /// Sign the generated id. Ignored with an alias.
+    signed: bool,
+    /// Never deduped: the url alone doesn't say where the link goes.
+    platform_targets: Option<&'a PlatformTargets>,
+    /// Never deduped, like platform targets.
+    geo_targets: Option<&'a GeoTargets>,
+    /// `None` redirects with 302. Never deduped.
+    redirect_status: Option<u16>,
+    /// Never deduped, each link's uses being its own.
+    max_uses: Option<u32>,
+    /// Destinations from `?i=1` on. Never deduped.
+    urls: &'a [String],
+    click_cap: Option<i64>,
+    /// Stored for a created link, see [`auth::management_token`].
+    management_token_hash: Option<&'a str>,
+}
+
+/// The link a shorten call ended up with.
+#[derive(Debug, Clone, sqlx::FromRow)]
+struct Shortened {
+    id: String,
+    created_at: DateTime<Utc>,
+    /// Whether the insert went through rather than hitting an existing link.
+    created: bool,
+    description: Option<String>,
+    expires_at: Option<DateTime<Utc>>,
+}
+
+/// What makes two shorten requests the same, so a burst of them creates
+/// one link. Tags aren't part of it, they're added to the link either way.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct ShortenAttempt {
+    owner: Option<String>,
+    url: String,
+    upgrade_insecure: Option<bool>,
+    expires_in_secs: Option<u64>,
+    forward_query: Option<bool>,
+    dedupe: Option<bool>,
+    notes: Option<String>,
+    alias: Option<String>,
+    description: Option<String>,
+    signed: bool,
+    platform_targets: Option<PlatformTargets>,
+    geo_targets: Option<GeoTargets>,
+    redirect_status: Option<u16>,
+    max_uses: Option<u32>,
+    urls: Vec<String>,
+    click_cap: Option<i64>,
+}
+
+/// How a redirect request was resolved, used as the `outcome` metric label.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum RedirectOutcome {
+    Found,
+    NotFound,
+    Expired,
+    Disabled,
+    /// Used up its `max_uses`.
+    Exhausted,
+}
+
+impl RedirectOutcome {
+    /// A link expires `grace` after its `expires_at`, see
+    /// `EXPIRY_SKEW_GRACE_SECS`.
+    fn of(link: Option<&Records>, grace: Duration) -> Self {
+        let expired = |at: DateTime<Utc>| {
+            chrono::Duration::from_std(grace)
+                .ok()
+                .and_then(|grace| at.checked_add_signed(grace))
+                .is_some_and(|until| until <= Utc::now())
+        };
+        match link {
+            None => RedirectOutcome::NotFound,
+            Some(link) if !link.enabled => RedirectOutcome::Disabled,
+            Some(link) if link.expires_at.is_some_and(expired) => RedirectOutcome::Expired,
+            Some(link) if link.max_uses.is_some_and(|max| link.uses >= max) => {
+                RedirectOutcome::Exhausted
+            }
+            Some(_) => RedirectOutcome::Found,
+        }
+    }
+
+    fn as_str(self) -> &'static str {
+        match self {
+            RedirectOutcome::Found => "found",
+            RedirectOutcome::NotFound => "not_found",
+            RedirectOutcome::Expired => "expired",
+            RedirectOutcome::Disabled => "disabled",
+            RedirectOutcome::Exhausted => "exhausted",
+        }
+    }
+
+    /// Counts a redirect answered with this outcome.
+    fn record(self) {
+        metrics::counter!("redirect_total", "outcome" => self.as_str()).increment(1);
+    }
+}
+
+/// Paths served by dedicated routes that must never be handed out as ids.
+const RESERVED_IDS: &[&str] = &[
+    "api",
+    "apple-touch-icon.png",
+    "apple-touch-icon-precomposed.png",
+    "favicon.ico",
+    "healthz",
+    "metrics",
+    "robots.txt",
+    "version",
+];
+/// Segments after a link's slug that name something about the link rather
+/// than another link, kept from aliases so `/:alias` never reads as one.
+/// `qr` and `stats` are held back for the routes planned under them.
+const RESERVED_SEGMENTS: &[&str] = &["expiry", "info", "qr", "report", "rotate", "stats"];
+
+/// The constraints keeping ids and aliases unique. A legacy `urls` table may
+/// lack its primary key; every slug is in `slugs` either way.
+const ID_CONSTRAINTS: &[&str] = &["urls_pkey", "slugs_pkey"];
+
+/// Tables referring to a link by its id in `link_id`.
+const LINK_TABLES: &[&str] = &[
+    "slugs",
+    "link_tags",
+    "link_urls",
+    "link_uniques",
+    "link_geo_clicks",
+    "reports",
+    "url_history",
+    "link_claims",
+    "link_transfers",
+];
+
+/// The links a deletion takes.
+#[derive(Debug, Clone, Copy)]
+enum Doomed<'a> {
+    Link(&'a str),
+    Links(&'a [String]),
+    /// Those expired longer ago than the grace.
+    Expired(Duration),
+}
+
+/// Ids listed in an [`Affected`] answer, the count covers the rest.
+const AFFECTED_SAMPLE: usize = 20;
+
+/// `?dry_run=true` previews a destructive admin operation: it answers
+/// with what would go and leaves everything in place.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "snake_case")]
+struct DryRunQuery {
+    #[serde(default)]
+    dry_run: bool,
+}
+
+/// What a destructive admin operation took, or would have.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+struct Affected {
+    dry_run: bool,
+    count: usize,
+    sample: Vec<String>,
+}
+
+impl Affected {
+    fn new(dry_run: bool, mut ids: Vec<String>) -> Self {
+        let count = ids.len();
+        ids.truncate(AFFECTED_SAMPLE);
+        Self {
+            dry_run,
+            count,
+            sample: ids,
+        }
+    }
+}
+
+/// Deletes the `doomed` links along with what [`LINK_TABLES`] keep about
+/// them, returning their ids.
+async fn delete_in(
+    conn: &mut PgConnection,
+    doomed: Doomed<'_>,
+) -> Result<Vec<String>, ShortenError> {
+    let ids: Vec<String> = match doomed {
+        Doomed::Link(id) => {
+            sqlx::query_scalar("DELETE FROM urls WHERE id = $1 RETURNING id").bind(id)
+        }
+        Doomed::Links(ids) => {
+            sqlx::query_scalar("DELETE FROM urls WHERE id = ANY($1) RETURNING id").bind(ids)
+        }
+        // as a redirect tells them apart, see `RedirectOutcome`
+        Doomed::Expired(grace) => sqlx::query_scalar(
+            "DELETE FROM urls WHERE expires_at <= now() - $1::BIGINT * INTERVAL '1 second'
+             RETURNING id",
+        )
+        .bind(grace.as_secs() as i64),
+    }
+    .fetch_all(&mut *conn)
+    .await?;
+    for table in LINK_TABLES {
+        sqlx::query(&format!("DELETE FROM {} WHERE link_id = ANY($1)", table))
+            .bind(&ids)
+            .execute(&mut *conn)
+            .await?;
+    }
+    Ok(ids)
+}
+
+/// Whether `e` is a unique violation of an id or alias, as opposed to any
+/// other constraint.
+fn is_id_taken(e: &sqlx::Error) -> bool {
+    match e {
+        sqlx::Error::Database(e) => {
+            e.is_unique_violation() && e.constraint().is_some_and(|c| ID_CONSTRAINTS.contains(&c))
+        }
+        _ => false,
+    }
+}
+
+impl AppState {
+    pub fn new(
+        db: PgState,
+        config: Config,
+        metrics: PrometheusHandle,
+    ) -> Result<Self, ShortenError> {
+        let screener = Screener::new(
+            config.blocklist_path.clone(),
+            config.safe_browsing_key.clone(),
+        )?;
+        let interstitials = Interstitials::new(
+            config.interstitial_dir.as_deref(),
+            &config.interstitial_language,
+        )?
+        .with_miss_template(
+            config.miss_template_path.as_deref(),
+            config.homepage_url.as_deref(),
+        );
+        let robots_txt = match &config.robots_txt_path {
+            Some(path) => std::fs::read_to_string(path)
+                .map_err(|e| ShortenError::Config(format!("{}: {}", path.display(), e)))?,
+            None => ROBOTS_TXT.to_string(),
+        };
+        let shadow = Shadow::new(&config, db.db.clone());
+        let geoip = config
+            .geoip_path
+            .as_deref()
+            .map(GeoIp::load)
+            .transpose()?
+            .map(Arc::new);
+        Ok(Self {
+            db,
+            upgrader: Upgrader::new(config.upgrade_insecure, Fetcher::new()),
+            fetcher: Fetcher::new(),
+            report_limiter: RateLimiter::new(config.report_rate_limit, Duration::from_secs(3600)),
+            screener,
+            metrics,
+            clicks: ClickFeed::new(),
+            counter: ClickCounter::new(
+                config.click_flush_threshold,
+                config.webhook_url.is_some(),
+                (config.click_flush_rate > 0.0).then(|| {
+                    TokenBucket::new(
+                        "click_flush",
+                        config.click_flush_rate,
+                        config.click_flush_burst,
+                    )
+                }),
+            ),
+            spikes: SpikeDetector::new(&config),
+            latency: config.link_latency.then(|| {
+                LatencyTracker::new(
+                    config.link_latency_tracked_links,
+                    config.link_latency_window,
+                )
+            }),
+            dedup: (!config.click_dedup_window.is_zero()).then(|| {
+                ClickDedup::new(config.click_dedup_window, config.click_dedup_max_entries)
+            }),
+            visitors: VisitorCounter::new(config.uniques_salt.as_deref()),
+            geoip,
+            geo_clicks: GeoClicks::default(),
+            logging: Logging::new(&config),
+            maintenance: Maintenance::new(config.maintenance.map(|enabled| maintenance::Status {
+                enabled,
+                message: config.maintenance_message.clone(),
+            })),
+            advisor: config
+                .index_advisor
+                .then(|| IndexAdvisor::new(config.index_advisor_min_rows)),
+            interstitials: Arc::new(interstitials),
+            policies: Policies::new(config.confirm_domains.clone()),
+            suspensions: Suspensions::default(),
+            continuations: Continuations::new(config.confirm_signing_key.as_deref()),
+            deprecations: Deprecations::new(deprecated_routes()),
+            shadow,
+            robots_txt: robots_txt.into(),
+            response_signer: config
+                .response_signing_key
+                .as_deref()
+                .map(ResponseSigner::new),
+            shortens: Coalescer::new(config.shorten_coalesce_window),
+            closing: Arc::new(watch::channel(false).0),
+            config: Arc::new(config),
+        })
+    }
+
+    /// Starts the job worker, click count and visitor flushing, spike
+    /// detection, the maintenance mode and redirect policy polls and,
+    /// when screening is enabled, the periodic rescreen and the SIGHUP
+    /// blocklist reload. The link preload, slug filter rebuilds, query
+    /// plan checks and the digest mail start when configured.
+    pub fn spawn_workers(&self) {
+        let mut worker = Worker::new(self.db.db.clone(), self.config.job_max_attempts);
+        if let Some(url) = &self.config.webhook_url {
+            let throttle = (self.config.webhook_rate > 0.0).then(|| {
+                TokenBucket::new(
+                    "webhook",
+                    self.config.webhook_rate,
+                    self.config.webhook_burst,
+                )
+            });
+            let webhook =
+                Webhook::new(url.clone(), self.config.webhook_secret.as_deref(), throttle);
+            worker = worker.register(move |event: Event| {
+                let webhook = webhook.clone();
+                async move { webhook.deliver(event).await }
+            });
+        }
+        let db = self.db.clone();
+        worker = worker.register(|_: backup::Progress| async {
+            Err("the restore was interrupted, see the data that was left".to_string())
+        });
+        worker = worker.register(move |job: Import| {
+            let db = db.clone();
+            async move {
+                db.import(job.import_id, &job.rows)
+                    .await
+                    .map_err(|e| e.to_string())
+            }
+        });
+        if self.screener.is_enabled() {
+            let (job_screener, pool) = (self.screener.clone(), self.db.db.clone());
+            worker = worker.register(move |_: Rescreen| {
+                let (screener, pool) = (job_screener.clone(), pool.clone());
+                async move { screener.rescreen(&pool).await.map_err(|e| e.to_string()) }
+            });
+            tokio::spawn(schedule_rescreen(
+                self.db.db.clone(),
+                self.config.screening_interval,
+            ));
+            #[cfg(unix)]
+            tokio::spawn(reload_on_sighup(self.screener.clone()));
+        }
+        if let Some(url) = &self.config.smtp_url {
+            match smtp::Relay::from_url(url) {
+                Ok(relay) => {
+                    let (pool, config) = (self.db.db.clone(), self.config.clone());
+                    worker = worker.register(move |job: digest::SendDigest| {
+                        let (pool, config, relay) = (pool.clone(), config.clone(), relay.clone());
+                        async move {
+                            let from = config.digest_from.as_deref().unwrap_or_default();
+                            digest::send(
+                                &pool,
+                                &relay,
+                                from,
+                                &config.digest_to,
+                                config.digest_schedule,
+                                job,
+                                |id| config.short_url(id),
+                            )
+                            .await
+                        }
+                    });
+                    let (pool, schedule) = (self.db.db.clone(), self.config.digest_schedule);
+                    tokio::spawn(async move {
+                        if let Err(e) = digest::schedule(&pool, schedule, Utc::now()).await {
+                            warn!("Failed to schedule the digest: {}", e);
+                        }
+                    });
+                }
+                Err(e) => warn!("Digest disabled: {}", e),
+            }
+        }
+        tokio::spawn(worker.run());
+        tokio::spawn(
+            self.counter
+                .clone()
+                .run(self.db.db.clone(), self.config.click_flush_interval),
+        );
+        tokio::spawn(
+            self.spikes
+                .clone()
+                .run(self.db.db.clone(), self.config.webhook_url.is_some()),
+        );
+        if let Some(latency) = &self.latency {
+            tokio::spawn(latency.clone().run());
+        }
+        tokio::spawn(
+            self.visitors
+                .clone()
+                .run(self.db.db.clone(), self.config.click_flush_interval),
+        );
+        tokio::spawn(
+            self.geo_clicks
+                .clone()
+                .run(self.db.db.clone(), self.config.click_flush_interval),
+        );
+        tokio::spawn(
+            self.maintenance
+                .clone()
+                .run(self.db.db.clone(), self.config.maintenance_poll),
+        );
+        tokio::spawn(
+            self.policies
+                .clone()
+                .run(self.db.db.clone(), self.config.policy_poll),
+        );
+        tokio::spawn(
+            self.suspensions
+                .clone()
+                .run(self.db.db.clone(), self.config.policy_poll),
+        );
+        if self.config.warm_links > 0 {
+            tokio::spawn(warm_links(self.db.clone(), self.config.warm_links));
+        }
+        if let Some(advisor) = &self.advisor {
+            tokio::spawn(advisor.clone().run(self.db.db.clone(), advisor::INTERVAL));
+        }
+        if !self.config.metrics_snapshot_interval.is_zero() {
+            tokio::spawn(snapshots::run(
+                self.db.db.clone(),
+                self.config.metrics_snapshot_interval,
+            ));
+        }
+        if !self.config.slug_filter_refresh.is_zero() {
+            tokio::spawn(rebuild_slug_filter(
+                self.db.clone(),
+                self.config.slug_filter_refresh,
+            ));
+        }
+    }
+
+    /// Ends open event streams; graceful shutdown would otherwise wait on
+    /// them forever.
+    pub fn close_streams(&self) {
+        self.closing.send_replace(true);
+    }
+
+    /// Writes out state buffered in memory. Call once the server has stopped
+    /// taking requests.
+    pub async fn shutdown(&self) -> Result<(), ShortenError> {
+        self.counter.flush(&self.db.db).await?;
+        self.visitors.flush(&self.db.db).await?;
+        self.geo_clicks.flush(&self.db.db).await
+    }
+}
+
+/// The router with its workers started, for embedding in another axum app:
+/// nest it wherever the links should live, e.g. `Router::new().nest("/s",
+/// shortener::router(store, config)?)`. Everything, the redirects and the
+/// landing page included, answers under that prefix. Creates no global
+/// state: `/metrics` renders a recorder of its own that stays empty, to
+/// export the metrics use [`AppState::new`] with the handle of an
+/// installed one and [`app`], which also allows [`AppState::shutdown`] to
+/// flush the clicks buffered since the last interval. Must be called on a
+/// tokio runtime.
+pub fn router(store: PgState, config: Config) -> Result<Router, ShortenError> {
+    let metrics = PrometheusBuilder::new().build_recorder().handle();
+    let state = AppState::new(store, config, metrics)?;
+    state.spawn_workers();
+    app(state)
+}
+
+/// Builds the full router. Serve it with
+/// `into_make_service_with_connect_info::<SocketAddr>()`, reports need the
+/// peer address.
+pub fn app(state: AppState) -> Result<Router, ShortenError> {
+    let cors = cors_layer(&state.config)?;
+    // CORS only applies to the JSON API; redirects stay plain 302s
+    let shadow = state.shadow.clone();
+    let mirrored = |route: ShadowRoute, handler: MethodRouter<AppState>| match &shadow {
+        Some(shadow) => handler.layer(middleware::from_fn_with_state(
+            (shadow.clone(), route),
+            shadow::mirror,
+        )),
+        None => handler,
+    };
+    let mut api = Router::new()
+        .route(
+            "/",
+            mirrored(ShadowRoute::Shorten, get(landing).post(shorten)),
+        )
+        .route("/api/link-kits", post(create_link_kit))
+        .route("/api/alias-available", get(alias_available))
+        .route("/api/count", get(count))
+        .route("/api/jobs", get(list_jobs))
+        .route("/api/links", get(list_links))
+        .route(
+            "/api/maintenance",
+            get(get_maintenance).post(set_maintenance),
+        )
+        .route("/api/links/hot", get(hot_links))
+        .route("/api/links/bulk", post(bulk_links))
+        .route("/api/links/batch-delete", post(batch_delete))
+        .route("/api/links/healthcheck", post(check_links))
+        .route("/api/admin/backup", get(backup_archive))
+        .route("/api/admin/restore", post(restore_archive))
+        .route("/api/admin/sweep-expired", post(sweep_expired))
+        .route("/api/admin/reindex", post(reindex))
+        .route("/api/admin/db-health", get(db_health))
+        .route("/api/admin/deprecations", get(list_deprecations))
+        .route("/api/admin/merged-ids", get(merged_ids))
+        .route("/api/admin/shadow-diffs", get(shadow_diffs))
+        .route("/api/my/links", get(my_links))
+        .route("/api/links/:id/aliases", post(add_alias))
+        .route("/api/links/:id/claim", post(claim_link))
+        .route("/api/links/:id/owner", post(assign_owner))
+        .route(
+            "/api/links/:id/transfer",
+            post(offer_transfer).delete(withdraw_transfer),
+        )
+        .route("/api/links/:id/transfer/accept", post(accept_transfer))
+        .route("/api/transfers", get(list_transfers))
+        .route("/api/links/:id/aliases/:alias", delete(remove_alias))
+        .route("/api/links/:id/history", get(link_history))
+        .route("/api/links/:id/rollback", post(rollback_link))
+        .route("/api/links/:id/stats", get(link_stats))
+        .route("/api/links/:id/qr", get(link_qr))
+        .route("/api/links/:id/preview", get(link_preview))
+        .route("/api/links/:id/latency", get(link_latency))
+        .route("/api/links/:id/trace", get(trace_link))
+        .route("/api/links/:id/stats/daily", get(daily_stats))
+        .route("/api/links/:id/timeseries", get(link_timeseries))
+        .route("/api/imports", get(list_imports).post(create_import))
+        .route("/api/imports/:id", get(get_import))
+        .route("/api/imports/:id/errors", get(import_errors))
+        .route("/api/keys", get(list_keys).post(create_key))
+        .route("/api/keys/:id", delete(revoke_key))
+        .route("/api/keys/:id/quota", get(get_quota).put(set_quota))
+        .route("/api/policies", get(list_policies).post(create_policy))
+        .route("/api/policies/:id", delete(delete_policy))
+        .route(
+            "/api/owners/:owner/status",
+            get(get_owner_status).put(set_owner_status),
+        )
+        .route("/api/reports", get(list_reports))
+        .route("/api/stream/clicks", get(stream_clicks))
+        .route("/api/reports/:id", post(resolve_report))
+        .route("/api/screening", get(list_flagged))
+        .route("/api/stats/history", get(stats_history))
+        .route("/api/usage", get(usage))
+        .route("/api/verify", get(verify_signature))
+        .route("/:id", patch(update_link).delete(delete_link))
+        .route("/:id/expiry", patch(set_expiry))
+        .route("/:id/report", post(report))
+        .route("/:id/rotate", post(rotate_link))
+        .route("/:id/info", get(link_info))
+        .route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            refuse_writes_in_maintenance,
+        ))
+        .route_layer(middleware::from_fn_with_state(
+            Limit::new(
+                "writes",
+                state.config.write_concurrency_limit,
+                state.config.retry_after_secs,
+            ),
+            concurrency::writes,
+        ));
+    if state.config.compat_camel_case {
+        api = api.layer(middleware::from_fn_with_state(
+            state.config.max_body_bytes,
+            casing::compat,
+        ));
+    }
+    let api = api
+        .layer(DefaultBodyLimit::max(state.config.max_body_bytes))
+        .layer(cors);
+    let server_header = state.config.server_header;
+    let hygiene = Hygiene::new(&state.config);
+    let security_headers = match state.config.security_headers {
+        true => security_headers(&state.config),
+        false => Vec::new(),
+    };
+    let retry_after = state.config.retry_after_secs;
+    let budget = Budget {
+        timeout: state.config.request_timeout,
+        retry_after,
+    };
+    let timeout = ServiceBuilder::new()
+        .layer(HandleErrorLayer::new(move |_: BoxError| async move {
+            ShortenError::Unavailable {
+                reason: "request timed out".into(),
+                retry_after,
+            }
+        }))
+        .timeout(state.config.request_timeout);
+    let trusted = state.config.trusted_proxies.clone();
+    let logging = state.logging;
+    let redirect_limit = Limit::new(
+        "redirects",
+        state.config.redirect_concurrency_limit,
+        retry_after,
+    );
+    let canonical_host = state.config.canonical_host.clone();
+    let deprecations = state.deprecations.clone();
+    let mut routes = Router::new()
+        .route("/favicon.ico", get(favicon))
+        .route("/robots.txt", get(robots_txt))
+        .route("/apple-touch-icon.png", get(no_such_file))
+        .route("/apple-touch-icon-precomposed.png", get(no_such_file))
+        .route("/.well-known/*path", get(no_such_file))
+        .route("/healthz", get(healthz))
+        .route("/metrics", get(render_metrics))
+        .route("/version", get(build_version))
+        .route(
+            "/:id",
+            mirrored(
+                ShadowRoute::Redirect,
+                get(redirect)
+                    .layer(middleware::map_response(logging::sampled))
+                    .layer(middleware::from_fn_with_state(
+                        redirect_limit,
+                        concurrency::all,
+                    )),
+            ),
+        )
+        .route("/:id/continue", get(continue_redirect))
+        .merge(api)
+        .with_state(state)
+        .layer(middleware::map_response(error::method_not_allowed_body))
+        .layer(middleware::from_fn_with_state(
+            deprecations,
+            deprecation::mark,
+        ));
+    if let Some(host) = canonical_host {
+        routes = routes.layer(middleware::from_fn_with_state(
+            Arc::<str>::from(host),
+            canonical::redirect_to_canonical,
+        ));
+    }
+    let mut router = routes
+        .layer(middleware::from_fn(route_metrics::track))
+        .layer(timeout)
+        .layer(middleware::from_fn_with_state(budget, deadline::track))
+        .layer(PropagateRequestIdLayer::x_request_id())
+        .layer(
+            TraceLayer::new_for_http()
+                .make_span_with(logging.spans(trusted))
+                .on_response(logging),
+        )
+        // outermost, so the span and every response have it
+        .layer(SetRequestIdLayer::x_request_id(MakeRequestUuid));
+    // inside the security headers, so redirects and pages keep their own
+    router = router.layer(middleware::from_fn_with_state(hygiene, hygiene::apply));
+    if server_header {
+        router = router.layer(SetResponseHeaderLayer::overriding(
+            SERVER,
+            HeaderValue::from_static(version::SERVER),
+        ));
+    }
+    for (name, value) in security_headers {
+        router = router.layer(SetResponseHeaderLayer::if_not_present(name, value));
+    }
+    Ok(router)
+}
+
+/// The routes on their way out, none yet. `POST /` joins them, with
+/// `/api/v1` as its successor, once that exists.
+fn deprecated_routes() -> Vec<Deprecation> {
+    Vec::new()
+}
+
+/// The hardening headers `SECURITY_HEADERS` sends.
+fn security_headers(config: &Config) -> Vec<(HeaderName, HeaderValue)> {
+    let mut headers = vec![
+        (X_CONTENT_TYPE_OPTIONS, HeaderValue::from_static("nosniff")),
+        (X_FRAME_OPTIONS, HeaderValue::from_static("DENY")),
+    ];
+    // validated to be one of the policy tokens
+    if let Ok(policy) = HeaderValue::from_str(&config.referrer_policy) {
+        headers.push((REFERRER_POLICY, policy));
+    }
+    if !config.hsts_max_age.is_zero() {
+        let hsts = format!("max-age={}", config.hsts_max_age.as_secs());
+        headers.push((STRICT_TRANSPORT_SECURITY, hsts.parse().unwrap()));
+    }
+    headers
+}
+
+/// Answers anything but reads with a 503 in maintenance mode, except for
+/// turning it off again.
+async fn refuse_writes_in_maintenance(
+    State(state): State<AppState>,
+    req: axum::extract::Request,
+    next: middleware::Next,
+) -> axum::response::Response {
+    let read = matches!(*req.method(), Method::GET | Method::HEAD | Method::OPTIONS);
+    if read || req.uri().path() == "/api/maintenance" {
+        return next.run(req).await;
+    }
+    match state.maintenance.refusal() {
+        Some(reason) => ShortenError::Unavailable {
+            reason,
+            retry_after: state.config.retry_after_secs,
+        }
+        .into_response(),
+        None => next.run(req).await,
+    }
+}
+
+/// Warms the database for the most clicked links in the background, as
+/// serving a cold instance beats not serving one: failures are only logged.
+async fn warm_links(db: PgState, links: u32) {
+    match db.warm_links(links).await {
+        Ok(warmed) => {
+            metrics::gauge!("warmed_links").set(warmed as f64);
+            info!("Preloaded the {} most clicked links", warmed);
+        }
+        Err(e) => warn!("Failed to preload the most clicked links: {}", e),
+    }
+}
+
+/// Builds the slug filter, then rebuilds it every `every` for the slugs
+/// other instances added. Until the first build succeeds every lookup goes
+/// to the database.
+async fn rebuild_slug_filter(db: PgState, every: Duration) {
+    let mut interval = tokio::time::interval(every);
+    loop {
+        interval.tick().await;
+        match db.rebuild_slug_filter().await {
+            Ok(slugs) => metrics::gauge!("slug_filter_slugs").set(slugs as f64),
+            Err(e) => warn!("Failed to rebuild the slug filter: {}", e),
+        }
+    }
+}
+
+async fn schedule_rescreen(db: PgPool, every: Duration) {
+    let mut interval = tokio::time::interval(every);
+    // the first tick fires immediately; links were just screened on creation
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        if let Err(e) = jobs::enqueue(&db, &Rescreen {}).await {
+            warn!("Failed to schedule rescreen: {}", e);
+        }
+    }
+}
+
+#[cfg(unix)]
+async fn reload_on_sighup(screener: Screener) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let Ok(mut hangup) = signal(SignalKind::hangup()) else {
+        warn!("Failed to install SIGHUP handler, blocklist reload disabled");
+        return;
+    };
+    while hangup.recv().await.is_some() {
+        if let Err(e) = screener.reload() {
+            warn!("Failed to reload blocklist: {}", e);
+        }
+    }
+}
+
+fn cors_layer(config: &Config) -> Result<CorsLayer, ShortenError> {
+    let origins = if config.cors_allow_origins.is_empty() {
+        AllowOrigin::any()
+    } else {
+        let origins = config
+            .cors_allow_origins
+            .iter()
+            .map(|o| {
+                o.parse::<HeaderValue>()
+                    .map_err(|_| ShortenError::Config(format!("invalid CORS origin {:?}", o)))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        AllowOrigin::list(origins)
+    };
+    Ok(CorsLayer::new()
+        .allow_origin(origins)
+        .allow_methods([Method::GET, Method::POST])
+        .allow_headers([CONTENT_TYPE, AUTHORIZATION]))
+}
+
+/// `?include=` of a shorten call: a comma-separated list of the related
+/// urls to answer with besides the short one.
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+struct ShortenQuery {
+    #[serde(default)]
+    include: Option<String>,
+}
+
+/// The related urls a shorten call asked for.
+#[derive(Debug, Default, Clone, Copy)]
+struct Includes {
+    stats: bool,
+    qr: bool,
+}
+
+impl ShortenQuery {
+    /// What `include` names. 400 for anything but `stats` and `qr`.
+    fn includes(&self) -> Result<Includes, ShortenError> {
+        let mut includes = Includes::default();
+        for name in self.include.iter().flat_map(|i| i.split(',')) {
+            match name.trim() {
+                "stats" => includes.stats = true,
+                "qr" => includes.qr = true,
+                "" => {}
+                _ => return Err(StatusCodeError(StatusCode::BAD_REQUEST).into()),
+            }
+        }
+        Ok(includes)
+    }
+}
+
+async fn shorten(
+    State(state): State<AppState>,
+    Extension(request_id): Extension<RequestId>,
+    OptionalApiKey(key): OptionalApiKey,
+    base: LinkBase,
+    Query(query): Query<ShortenQuery>,
+    AppJson(req): AppJson<ShortReq>,
+) -> Result<impl IntoResponse, ShortenError> {
+    let includes = query.includes()?;
+    let (_, res) = Box::pin(shorten_link(&state, &request_id, key, &base, req, includes)).await?;
+    Ok((created_status(res.created), Json(res)))
+}
+
+fn created_status(created: bool) -> StatusCode {
+    if created {
+        StatusCode::CREATED
+    } else {
+        StatusCode::OK
+    }
+}
+
+/// Everything a shorten call does between reading its body and answering,
+/// returning the link's id with the answer. Callers box the future, which
+/// is too big for a debug build's stack.
+async fn shorten_link(
+    state: &AppState,
+    request_id: &RequestId,
+    key: Option<ApiKey>,
+    base: &LinkBase,
+    mut req: ShortReq,
+    includes: Includes,
+) -> Result<(String, ShortRes), ShortenError> {
+    req.alias = req.alias.map(|alias| state.db.normalize(&alias));
+    let tags =
+        tags::normalize(&req.tags).ok_or(StatusCodeError(StatusCode::UNPROCESSABLE_ENTITY))?;
+    if !req.notes.as_deref().is_none_or(links::notes_fit) {
+        return Err(StatusCodeError(StatusCode::UNPROCESSABLE_ENTITY).into());
+    }
+    if let Some(alias) = &req.alias {
+        aliases::validate(alias)?;
+    }
+    if req.signed && (req.alias.is_some() || !state.db.can_sign()) {
+        return Err(StatusCodeError(StatusCode::UNPROCESSABLE_ENTITY).into());
+    }
+    if !req
+        .redirect_status
+        .is_none_or(|s| REDIRECT_STATUSES.contains(&s))
+    {
+        return Err(StatusCodeError(StatusCode::UNPROCESSABLE_ENTITY).into());
+    }
+    if req.max_uses == Some(0)
+        || (req.max_uses.is_some() && matches!(req.redirect_status, Some(301 | 308)))
+    {
+        return Err(StatusCodeError(StatusCode::UNPROCESSABLE_ENTITY).into());
+    }
+    let click_cap = match req.click_cap.map(i64::try_from) {
+        Some(Ok(cap)) if cap > 0 => Some(cap),
+        Some(_) => return Err(StatusCodeError(StatusCode::UNPROCESSABLE_ENTITY).into()),
+        None => None,
+    };
+    let description = match req.description.as_deref().map(links::clean_description) {
+        Some(None) => return Err(StatusCodeError(StatusCode::UNPROCESSABLE_ENTITY).into()),
+        Some(Some(d)) if !d.is_empty() => Some(d),
+        _ => None,
+    };
+    let (expires_in_secs, clamped) = lifetime(
+        req.expires_in_secs,
+        key.is_none(),
+        state.config.anonymous_link_ttl,
+    );
+    let expires_at = expires_in_secs.map(expiry_in).transpose()?;
+    let url = destination(&state.config, &req.url)?;
+    let platform_targets = match req.platform_targets {
+        Some(mut targets) if !targets.is_empty() => {
+            for target in targets.targets_mut() {
+                *target = idn::normalize(target)
+                    .ok_or_else(|| ShortenError::InvalidUrl(idn::clean(target)))?;
+            }
+            Some(targets)
+        }
+        _ => None,
+    };
+    let geo_targets = match req.geo_targets {
+        Some(targets) => {
+            let mut targets = GeoTargets::parse(targets)?;
+            for target in targets.targets_mut() {
+                *target = idn::normalize(target)
+                    .ok_or_else(|| ShortenError::InvalidUrl(idn::clean(target)))?;
+            }
+            Some(targets)
+        }
+        None => None,
+    };
+    // the two would disagree on where a visitor goes
+    if platform_targets.is_some() && geo_targets.is_some() {
+        return Err(StatusCodeError(StatusCode::UNPROCESSABLE_ENTITY).into());
+    }
+    if req.urls.len() > multiplex::MAX_URLS {
+        return Err(StatusCodeError(StatusCode::UNPROCESSABLE_ENTITY).into());
+    }
+    let urls = req
+        .urls
+        .iter()
+        .map(|url| idn::normalize(url).ok_or_else(|| ShortenError::InvalidUrl(idn::clean(url))))
+        .collect::<Result<Vec<_>, _>>()?;
+    let owner = key.as_ref().map(|k| k.owner());
+    let attempt = ShortenAttempt {
+        owner: owner.clone(),
+        url: url.clone(),
+        upgrade_insecure: req.upgrade_insecure,
+        expires_in_secs,
+        forward_query: req.forward_query,
+        dedupe: req.dedupe,
+        notes: req.notes.clone(),
+        alias: req.alias.clone(),
+        description: description.clone(),
+        signed: req.signed,
+        platform_targets: platform_targets.clone(),
+        geo_targets: geo_targets.clone(),
+        redirect_status: req.redirect_status,
+        max_uses: req.max_uses,
+        urls: urls.clone(),
+        click_cap,
+    };
+    let create = async {
+        let reserved = match key.as_ref().and_then(|k| k.id) {
+            Some(key_id) => Some(
+                quota::consume(&state.db.db, key_id)
+                    .await?
+                    .map_err(ShortenError::QuotaExceeded)?,
+            ),
+            None => None,
+        };
+        let made: Result<_, ShortenError> = async {
+            let url = collapse_own_links(state, url).await?;
+            if state.upgrader.fetches(req.upgrade_insecure) {
+                guard_destination(&url).await?;
+            }
+            let (url, upgraded) = state.upgrader.upgrade(&url, req.upgrade_insecure).await;
+            let verdict = if state.screener.is_enabled() {
+                state.screener.check(&url).await
+            } else {
+                Verdict::Unscreened
+            };
+            if let Verdict::Flagged(threat) = verdict {
+                return Err(ShortenError::Flagged(threat));
+            }
+            let mut platform_targets = platform_targets;
+            for target in platform_targets.iter_mut().flat_map(|t| t.targets_mut()) {
+                *target = collapse_own_links(state, std::mem::take(target)).await?;
+                if !state.screener.is_enabled() {
+                    continue;
+                }
+                if let Verdict::Flagged(threat) = state.screener.check(target).await {
+                    return Err(ShortenError::Flagged(threat));
+                }
+            }
+            let mut geo_targets = geo_targets;
+            for target in geo_targets.iter_mut().flat_map(|t| t.targets_mut()) {
+                *target = collapse_own_links(state, std::mem::take(target)).await?;
+                if !state.screener.is_enabled() {
+                    continue;
+                }
+                if let Verdict::Flagged(threat) = state.screener.check(target).await {
+                    return Err(ShortenError::Flagged(threat));
+                }
+            }
+            let mut urls = urls;
+            for url in &mut urls {
+                *url = collapse_own_links(state, std::mem::take(url)).await?;
+                if !state.screener.is_enabled() {
+                    continue;
+                }
+                if let Verdict::Flagged(threat) = state.screener.check(url).await {
+                    return Err(ShortenError::Flagged(threat));
+                }
+            }
+            let management_token = match state.config.management_tokens && owner.is_none() {
+                true => Some(auth::management_token().await?),
+                false => None,
+            };
+            let shortened = state
+                .db
+                .shorten(NewLink {
+                    url: &url,
+                    expires_at,
+                    forward_query: req.forward_query.unwrap_or(true),
+                    dedupe: management_token.is_none() && req.dedupe.unwrap_or(state.config.dedupe),
+                    notes: req.notes.as_deref(),
+                    owner,
+                    alias: req.alias.as_deref(),
+                    description: description.as_deref(),
+                    signed: req.signed,
+                    platform_targets: platform_targets.as_ref(),
+                    geo_targets: geo_targets.as_ref(),
+                    management_token_hash: management_token.as_ref().map(|(_, hash)| hash.as_str()),
+                    redirect_status: req.redirect_status,
+                    max_uses: req.max_uses,
+                    urls: &urls,
+                    click_cap,
+                })
+                .await
+                .map_err(|e| shorten_failure(e, state, req.alias.is_some(), request_id))?;
+            if verdict == Verdict::Clean {
+                screen::record(&state.db.db, &shortened.id, None).await?;
+            }
+            if shortened.created {
+                info!("Shortened {} as {}", state.logging.url(&url), shortened.id);
+            }
+            if shortened.created && state.config.webhook_url.is_some() {
+                let event = Event::LinkCreated {
+                    id: shortened.id.clone(),
+                    url: url.clone(),
+                    short_url: state.config.short_url(&shortened.id),
+                    created_at: shortened.created_at,
+                };
+                // the link exists either way, so the call still succeeds
+                if let Err(e) = jobs::enqueue(&state.db.db, &event).await {
+                    warn!(
+                        "Failed to queue the created webhook of {}: {}",
+                        shortened.id, e
+                    );
+                }
+            }
+            let management_token = management_token.map(|(token, _)| token);
+            Ok((shortened, upgraded, management_token))
+        }
+        .await;
+        // only a link created counts, not one refused or already there
+        let counted = made
+            .as_ref()
+            .is_ok_and(|(shortened, _, _)| shortened.created);
+        if let (Some(reserved), false) = (reserved, counted) {
+            if let Err(e) = quota::release(&state.db.db, reserved).await {
+                warn!("Failed to give back a creation of the quota: {}", e);
+            }
+        }
+        made
+    };
+    let ((shortened, upgraded, management_token), ran) =
+        state.shortens.run(attempt, create).await?;
+    // the calls that waited on another didn't create anything
+    let created = ran && shortened.created;
+    let id = shortened.id;
+    // a new link starts without tags, so only a re-shortened one can overflow
+    if !tags::add(&state.db.db, &id, &tags).await? {
+        return Err(StatusCodeError(StatusCode::UNPROCESSABLE_ENTITY).into());
+    }
+    let slugs = aliases::list(&state.db.db, &id).await?;
+    let tags = tags::list(&state.db.db, &id).await?;
+    let res = ShortRes {
+        url: base.short_url(&id),
+        upgraded,
+        slugs,
+        tags,
+        created,
+        created_at: shortened.created_at,
+        description: shortened.description,
+        expires_at: shortened.expires_at,
+        clamped,
+        management_token: management_token.filter(|_| created),
+        stats_url: includes.stats.then(|| base.stats_url(&id)),
+        qr_url: includes.qr.then(|| base.qr_url(&id)),
+    };
+    Ok((id, res))
+}
+
+/// Shortens a link and makes the assets `include` names in the same call.
+/// A link that can't be shortened fails the call, an asset that can't be
+/// made only reports its error.
+async fn create_link_kit(
+    State(state): State<AppState>,
+    Extension(request_id): Extension<RequestId>,
+    OptionalApiKey(key): OptionalApiKey,
+    base: LinkBase,
+    AppJson(req): AppJson<KitReq>,
+) -> Result<impl IntoResponse, ShortenError> {
+    let (id, link) = Box::pin(shorten_link(
+        &state,
+        &request_id,
+        key,
+        &base,
+        req.link,
+        Includes::default(),
+    ))
+    .await?;
+    let qr = req.include.contains(&KitPart::Qr).then(|| {
+        let scale = req
+            .qr_scale
+            .unwrap_or(qr::DEFAULT_SCALE)
+            .clamp(1, qr::MAX_SCALE);
+        match QrCode::encode(&link.url) {
+            Some(code) => KitAsset::Ready(QrImage {
+                scale,
+                png_base64: STANDARD.encode(code.png(scale)),
+            }),
+            None => KitAsset::Failed(ErrorBody::new(
+                "too_long",
+                "the short url is too long for a QR code",
+            )),
+        }
+    });
+    Ok((created_status(link.created), Json(LinkKit { id, link, qr })))
+}
+
+/// What a failed [`PgState::shorten`] is answered with. Transient failures
+/// have been retried by then, and leave the client to try again later.
+fn shorten_failure(
+    e: ShortenError,
+    state: &AppState,
+    alias: bool,
+    request_id: &RequestId,
+) -> ShortenError {
+    let code = match &e {
+        ShortenError::IdSpaceExhausted
+        | ShortenError::AliasTaken
+        | ShortenError::Unavailable { .. } => return e,
+        _ if e.is_transient() => {
+            return ShortenError::Unavailable {
+                reason: "the database is busy".into(),
+                retry_after: state.config.retry_after_secs,
+            }
+        }
+        ShortenError::SqlError(sqlx::Error::Database(db)) => {
+            db.code().unwrap_or_default().to_string()
+        }
+        _ => String::new(),
+    };
+    // integrity_constraint_violation, data_exception
+    if alias && code.starts_with("23") {
+        return StatusCodeError(StatusCode::CONFLICT).into();
+    }
+    if code.starts_with("22") {
+        return StatusCodeError(StatusCode::UNPROCESSABLE_ENTITY).into();
+    }
+    ShortenError::Internal {
+        source: Box::new(e),
+        request_id: request_id
+            .header_value()
+            .to_str()
+            .unwrap_or_default()
+            .to_string(),
+    }
+}
+
+/// Hops followed through our own links before giving up on a chain.
+const MAX_OWN_HOPS: usize = 5;
+
+/// Replaces a destination on our own host by where the link there leads, so
+/// shortening a short link doesn't make redirects go through both, and
+/// deduplication returns the existing link. Chains are followed up to
+/// `MAX_OWN_HOPS`. A path that isn't a live link, or a chain that doesn't
+/// leave our host by then, is refused with `self_reference`.
+async fn collapse_own_links(state: &AppState, mut url: String) -> Result<String, ShortenError> {
+    let public = state
+        .config
+        .public_url
+        .as_deref()
+        .and_then(|url| url::Url::parse(url).ok());
+    let hosts: Vec<&str> = std::iter::once(state.config.listen_addr.as_str())
+        .chain(state.config.canonical_host.as_deref())
+        .chain(
+            public
+                .as_ref()
+                .map(|url| &url[url::Position::BeforeHost..url::Position::AfterPort]),
+        )
+        .collect();
+    // nested in another app, only the paths under its prefix are ours
+    let prefix = public
+        .as_ref()
+        .map_or("", |url| url.path().trim_end_matches('/'));
+    let own_path = |url: &str| {
+        canonical::own_path(url, &hosts)
+            .and_then(|path| path.strip_prefix(prefix).map(String::from))
+            .filter(|path| path.starts_with('/'))
+    };
+    for _ in 0..MAX_OWN_HOPS {
+        let Some(path) = own_path(&url) else {
+            return Ok(url);
+        };
+        let slug = path
+            .strip_prefix('/')
+            .filter(|slug| !slug.contains('/') && state.db.is_plausible(slug))
+            .ok_or(ShortenError::SelfReference)?;
+        let link = state
+            .db
+            .get_link(slug)
+            .await?
+            .filter(|link| {
+                RedirectOutcome::of(Some(link), state.config.expiry_skew_grace)
+                    == RedirectOutcome::Found
+            })
+            .ok_or(ShortenError::SelfReference)?;
+        let query = url::Url::parse(&url)
+            .ok()
+            .and_then(|u| u.query().map(str::to_string));
+        url = match query {
+            Some(query) if state.config.forward_query && link.forward_query => {
+                query::merge(&link.url, &query, state.config.query_precedence)
+            }
+            _ => link.url,
+        };
+    }
+    match own_path(&url) {
+        None => Ok(url),
+        Some(_) => Err(ShortenError::SelfReference),
+    }
+}
+
+/// Redirects to the target of `/:id`, or answers with it as JSON for
+/// `/:id.json`.
+async fn redirect(
+    State(state): State<AppState>,
+    slug: RedirectSlug,
+    ClientIp(ip): ClientIp,
+    headers: HeaderMap,
+    RawQuery(query): RawQuery,
+) -> Result<Response, ShortenError> {
+    let started = Instant::now();
+    let link = match &slug.exact {
+        Some(id) => state.db.redirect_link(id).await?,
+        None => None,
+    };
+    let (link, as_json) = match (link, &slug.json) {
+        (None, Some(id)) => (state.db.redirect_link(id).await?, true),
+        (link, _) => (link, false),
+    };
+    let asked = Asked {
+        // the extractor found at least the one asked for
+        slug: match as_json {
+            true => slug.json.as_deref(),
+            false => slug.exact.as_deref(),
+        }
+        .unwrap_or_default(),
+        as_json,
+        confirm: true,
+    };
+    let Some(latency) = &state.latency else {
+        return follow(&state, link, asked, ip, &headers, query).await;
+    };
+    let id = link.as_ref().map(|link| link.id.clone());
+    let res = follow(&state, link, asked, ip, &headers, query).await;
+    if let Some(id) = id {
+        latency.record(&id, started.elapsed());
+    }
+    res
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "snake_case")]
+struct ContinueReq {
+    exp: i64,
+    sig: String,
+    /// The query of the request that showed the confirmation page.
+    #[serde(default)]
+    q: Option<String>,
+}
+
+/// Follows a link past its confirmation page, with the continuation the
+/// page links to. 403 unless it was signed by us and hasn't expired.
+async fn continue_redirect(
+    State(state): State<AppState>,
+    Path(slug): Path<String>,
+    Query(req): Query<ContinueReq>,
+    ClientIp(ip): ClientIp,
+    headers: HeaderMap,
+) -> Result<Response, ShortenError> {
+    let now = Utc::now().timestamp();
+    if !state
+        .continuations
+        .verify(&slug, req.q.as_deref(), req.exp, &req.sig, now)
+    {
+        return Err(StatusCodeError(StatusCode::FORBIDDEN).into());
+    }
+    metrics::counter!("redirect_confirmations_total", "step" => "continued").increment(1);
+    let link = state.db.redirect_link(&slug).await?;
+    let asked = Asked {
+        slug: &slug,
+        as_json: false,
+        confirm: false,
+    };
+    follow(&state, link, asked, ip, &headers, req.q).await
+}
+
+/// How a redirect was asked for.
+#[derive(Debug, Clone, Copy)]
+struct Asked<'a> {
+    /// The slug in the path, without `.json`.
+    slug: &'a str,
+    as_json: bool,
+    /// Whether a redirect policy may have the link confirmed first, unless
+    /// the caller's key has the `direct` scope.
+    confirm: bool,
+}
+
+/// Answers a redirect of `link`: to its target, with the target as JSON,
+/// or with the reason it doesn't redirect.
+async fn follow(
+    state: &AppState,
+    link: Option<Records>,
+    asked: Asked<'_>,
+    ip: IpAddr,
+    headers: &HeaderMap,
+    query: Option<String>,
+) -> Result<Response, ShortenError> {
+    let as_json = asked.as_json;
+    let outcome = RedirectOutcome::of(link.as_ref(), state.config.expiry_skew_grace);
+    // only a live link tells that its owner is suspended
+    let refusal = match (outcome, link.as_ref().and_then(|l| l.owner.as_deref())) {
+        (RedirectOutcome::Found, Some(owner)) => state.suspensions.refusal(owner),
+        _ => None,
+    };
+    if let Some(message) = refusal {
+        metrics::counter!("redirect_total", "outcome" => "suspended").increment(1);
+        return Err(ShortenError::OwnerSuspended {
+            status: StatusCode::from_u16(state.config.suspended_owner_status)
+                .unwrap_or(StatusCode::PAYMENT_REQUIRED),
+            message: message.unwrap_or_else(|| state.config.suspended_owner_message.clone()),
+        });
+    }
+    let link = match (outcome, link) {
+        (RedirectOutcome::Found, Some(link)) => link,
+        (outcome, _) => {
+            outcome.record();
+            return Ok(unavailable(state, outcome, asked, headers));
+        }
+    };
+    let status = link
+        .redirect_status
+        .and_then(|s| StatusCode::from_u16(s as u16).ok())
+        .unwrap_or(StatusCode::FOUND);
+    let delay = state.config.anonymous_redirect_delay;
+    if link.owner.is_none() && !delay.is_zero() {
+        tokio::time::sleep(delay).await;
+    }
+    // a link with several destinations is told which by `?i=`, which isn't
+    // passed on
+    let (index, forwarded) = match (link.destinations, query.as_deref()) {
+        (Some(_), Some(query)) => query::take(query, multiplex::PARAM),
+        (_, query) => (None, query.map(String::from)),
+    };
+    let count = link.destinations.unwrap_or(1);
+    let picked = match index.map(|i| i.parse().ok().filter(|i| (0..count).contains(i))) {
+        None | Some(Some(0)) => None,
+        Some(Some(index)) => multiplex::get(&state.db.db, &link.id, index).await?,
+        Some(None) => {
+            let outcome = RedirectOutcome::NotFound;
+            outcome.record();
+            return Ok(unavailable(state, outcome, asked, headers));
+        }
+    };
+    let user_agent = headers.get(USER_AGENT).and_then(|v| v.to_str().ok());
+    // links with a single destination don't look at the agent, nor does
+    // picking one of the others
+    let (platform, target) = match (&picked, &link.platform_targets) {
+        (None, Some(sqlx::types::Json(targets))) => {
+            let platform = Platform::detect(user_agent.unwrap_or(""));
+            (Some(platform), targets.pick(platform).map(String::from))
+        }
+        _ => (None, picked),
+    };
+    // and only links with geo targets look the visitor's country up
+    let (country, target) = match (&target, &link.geo_targets) {
+        (None, Some(sqlx::types::Json(targets))) => {
+            let country = state.geoip.as_ref().and_then(|geoip| geoip.country(ip));
+            let (key, url) = targets.pick(country);
+            (Some(key.to_string()), Some(url.to_string()))
+        }
+        _ => (None, target),
+    };
+    let target = target.unwrap_or_else(|| link.url.clone());
+    let url = match forwarded.as_deref() {
+        Some(query) if state.config.forward_query && link.forward_query => {
+            query::merge(&target, query, state.config.query_precedence)
+        }
+        _ => target,
+    };
+    let url = match state.config.force_https_targets {
+        true => upgrade::force_https(&url).unwrap_or(url),
+        false => url,
+    };
+    // refused as links are written, but one may have been stored while
+    // relative targets were allowed, or written around the API
+    if state.config.require_absolute_targets && idn::is_relative(&url) {
+        warn!(
+            "Link {} has the relative target {:?}, not redirecting",
+            link.id, url
+        );
+        let outcome = RedirectOutcome::NotFound;
+        outcome.record();
+        return Ok(unavailable(state, outcome, asked, headers));
+    }
+    // found, even if what follows is a confirmation page or the last use
+    // is taken meanwhile
+    RedirectOutcome::Found.record();
+    if asked.confirm && state.policies.applies(&link.id, &url) {
+        let direct = ApiKey::presented(headers, state)
+            .await?
+            .is_some_and(|key| key.has(Scope::Direct));
+        if !direct {
+            metrics::counter!("redirect_confirmations_total", "step" => "shown").increment(1);
+            if as_json {
+                return Err(StatusCodeError(StatusCode::FORBIDDEN).into());
+            }
+            let now = Utc::now().timestamp();
+            let continue_url = state.continuations.url(asked.slug, query.as_deref(), now);
+            return Ok(state.interstitials.confirm(headers, &url, &continue_url));
+        }
+        metrics::counter!("redirect_confirmations_total", "step" => "skipped").increment(1);
+    }
+    if let Some(platform) = platform {
+        metrics::counter!("redirect_platform_total", "platform" => platform.as_str()).increment(1);
+    }
+    if let Some(country) = &country {
+        metrics::counter!("redirect_geo_total", "destination" => country.clone()).increment(1);
+    }
+    // the count of a link with uses decides who gets through, so it's
+    // written here rather than buffered, and every use counts
+    let counted = if link.max_uses.is_some() {
+        let webhooks = state.config.webhook_url.is_some();
+        if !state.db.use_once(&link.id, webhooks).await? {
+            return Ok(unavailable(
+                state,
+                RedirectOutcome::Exhausted,
+                asked,
+                headers,
+            ));
+        }
+        true
+    } else if state
+        .dedup
+        .as_ref()
+        .is_some_and(|dedup| dedup.is_repeat(&link.id, ip, user_agent))
+    {
+        metrics::counter!("redirect_repeats_total").increment(1);
+        false
+    } else {
+        state.counter.record(&link.id);
+        true
+    };
+    if counted {
+        state.spikes.record(&link.id);
+        state.visitors.record(&link.id, ip, user_agent);
+        state.clicks.publish(&link.id, platform);
+        if let Some(country) = &country {
+            state.geo_clicks.record(&link.id, country);
+        }
+    }
+    let mut header = HeaderMap::new();
+    if platform.is_some() {
+        header.insert(VARY, HeaderValue::from_static("user-agent"));
+    }
+    // no header tells a shared cache the country
+    if country.is_some() {
+        header.insert(CACHE_CONTROL, HeaderValue::from_static("private"));
+    }
+    let id = link.id;
+    if as_json {
+        return Ok((header, Json(Resolved { id, url })).into_response());
+    }
+    if let Some(signer) = &state.response_signer {
+        let signature = signer.sign(&id, &url, status.as_u16(), Utc::now().timestamp());
+        header.insert(SIGNATURE_HEADER, signature.parse().unwrap());
+    }
+    header.insert(LOCATION, url.parse().unwrap());
+    Ok((status, header).into_response())
+}
+
+/// The answer to a redirect that didn't find a live link: 410 for an
+/// expired one and 404 otherwise, as a page for browsers and the usual
+/// error for everyone else.
+fn unavailable(
+    state: &AppState,
+    outcome: RedirectOutcome,
+    asked: Asked<'_>,
+    headers: &HeaderMap,
+) -> Response {
+    let as_json = asked.as_json;
+    if let (RedirectOutcome::Exhausted, Some(url), false) =
+        (outcome, &state.config.exhausted_link_url, as_json)
+    {
+        return (StatusCode::FOUND, [(LOCATION, url.as_str())]).into_response();
+    }
+    let (page, status) = match outcome {
+        RedirectOutcome::Expired | RedirectOutcome::Exhausted => (Page::Expired, StatusCode::GONE),
+        RedirectOutcome::Disabled => (Page::Disabled, StatusCode::NOT_FOUND),
+        _ => (Page::NotFound, StatusCode::NOT_FOUND),
+    };
+    if as_json {
+        return ShortenError::from(StatusCodeError(status)).into_response();
+    }
+    state
+        .interstitials
+        .respond(page, status, asked.slug, headers)
+        .unwrap_or_else(|| {
+            let mut res = ShortenError::from(StatusCodeError(status)).into_response();
+            res.headers_mut()
+                .insert(VARY, HeaderValue::from_static(interstitial::VARIES_BY));
+            res
+        })
+}
+
+/// Where a link goes and what's known about it, for expanding it without
+/// following it: no click, use or visitor is counted. 404 for an unknown
+/// link, and for a disabled one unless the key is an admin's. Expired and
+/// used up links are answered, their `status` saying so.
+async fn link_preview(
+    State(state): State<AppState>,
+    key: ApiKey,
+    Slug(id): Slug,
+    Query(req): Query<PreviewReq>,
+) -> Result<Json<serde_json::Map<String, serde_json::Value>>, ShortenError> {
+    key.require(Scope::Read)?;
+    let link = state.db.get_info(&id).await?;
+    let status = match RedirectOutcome::of(link.as_ref(), state.config.expiry_skew_grace) {
+        RedirectOutcome::Found => "active",
+        RedirectOutcome::Expired => "expired",
+        RedirectOutcome::Exhausted => "exhausted",
+        RedirectOutcome::Disabled if key.has(Scope::Admin) => "disabled",
+        RedirectOutcome::Disabled | RedirectOutcome::NotFound => {
+            return Err(StatusCodeError(StatusCode::NOT_FOUND).into())
+        }
+    };
+    let link = link.ok_or(StatusCodeError(StatusCode::NOT_FOUND))?;
+    let tags = state.db.read(|db| tags::list(db, &link.id)).await?;
+    let preview = LinkPreview {
+        id: link.id,
+        url: idn::display(&link.url),
+        description: link.description,
+        created_at: link.created_at,
+        owner: link.owner,
+        tags,
+        enabled: link.enabled,
+        status,
+    };
+    let Ok(serde_json::Value::Object(mut preview)) = serde_json::to_value(preview) else {
+        unreachable!("a preview serializes to an object");
+    };
+    if let Some(fields) = &req.fields {
+        let wanted: Vec<&str> = fields.split(',').map(str::trim).collect();
+        if wanted.iter().any(|field| !preview.contains_key(*field)) {
+            return Err(StatusCodeError(StatusCode::BAD_REQUEST).into());
+        }
+        preview.retain(|field, _| wanted.contains(&field.as_str()));
+    }
+    Ok(Json(preview))
+}
+
+/// Public details of a link, answering like its redirect would: 404 for an
+/// unknown or disabled link and 410 for an expired or used up one.
+async fn link_info(
+    State(state): State<AppState>,
+    Slug(id): Slug,
+) -> Result<Json<LinkInfo>, ShortenError> {
+    let link = state.db.get_info(&id).await?;
+    match (
+        RedirectOutcome::of(link.as_ref(), state.config.expiry_skew_grace),
+        link,
+    ) {
+        (RedirectOutcome::Found, Some(link)) => Ok(Json(LinkInfo {
+            id: link.id,
+            url: idn::display(&link.url),
+            description: link.description,
+            created_at: link.created_at,
+        })),
+        (RedirectOutcome::Expired | RedirectOutcome::Exhausted, _) => {
+            Err(StatusCodeError(StatusCode::GONE).into())
+        }
+        _ => Err(StatusCodeError(StatusCode::NOT_FOUND).into()),
+    }
+}
+
+/// Whether a redirect signature is ours for the fields it was given with,
+/// to spot a proxy that altered or made up a redirect. 404 unless
+/// `RESPONSE_SIGNING_KEY` is set.
+async fn verify_signature(
+    State(state): State<AppState>,
+    Query(req): Query<VerifyReq>,
+) -> Result<Json<VerifyRes>, ShortenError> {
+    let signer = state
+        .response_signer
+        .as_ref()
+        .ok_or(StatusCodeError(StatusCode::NOT_FOUND))?;
+    let valid = signer.verify(&req.id, &req.url, req.status, req.timestamp, &req.signature);
+    Ok(Json(VerifyRes { valid }))
+}
+
+/// Live feed of successful redirects. The stream ends when the client goes
+/// away; a subscriber too slow to keep up skips the events it missed.
+async fn stream_clicks(
+    State(state): State<AppState>,
+    key: ApiKey,
+) -> Result<Sse<impl Stream<Item = Result<sse::Event, Infallible>>>, ShortenError> {
+    key.require(Scope::Read)?;
+    let mut closing = state.closing.subscribe();
+    let events = BroadcastStream::new(state.clicks.subscribe()).filter_map(|click| match click {
+        Ok(click) => sse::Event::default()
+            .event("click")
+            .json_data(click)
+            .ok()
+            .map(Ok),
+        Err(BroadcastStreamRecvError::Lagged(missed)) => {
+            warn!("Click stream subscriber lagged, {} events dropped", missed);
+            None
+        }
+    });
+    let events = futures_util::StreamExt::take_until(events, async move {
+        let _ = closing.wait_for(|closing| *closing).await;
+    });
+    Ok(Sse::new(events).keep_alive(KeepAlive::default()))
+}
+
+/// 200 while the database answers, 503 with `Retry-After` otherwise.
+async fn healthz(State(state): State<AppState>) -> Result<impl IntoResponse, ShortenError> {
+    if let Err(e) = sqlx::query("SELECT 1").execute(&state.db.db).await {
+        warn!("Health check failed: {}", e);
+        return Err(ShortenError::Unavailable {
+            reason: "database unavailable".into(),
+            retry_after: state.config.retry_after_secs,
+        });
+    }
+    // still ready: redirects keep being served
+    if let Some(message) = state.maintenance.refusal() {
+        return Ok(Json(
+            serde_json::json!({ "status": "read-only", "message": message }),
+        ));
+    }
+    Ok(Json(serde_json::json!({ "status": "ok" })))
+}
+
+async fn render_metrics(State(state): State<AppState>) -> String {
+    state.metrics.render()
+}
+
+async fn build_version(
+    State(state): State<AppState>,
+    OptionalApiKey(key): OptionalApiKey,
+) -> Result<Json<BuildInfo>, ShortenError> {
+    if state.config.version_requires_auth {
+        key.ok_or(StatusCodeError(StatusCode::UNAUTHORIZED))?
+            .require(Scope::Admin)?;
+    }
+    Ok(Json(version::build_info()))
+}
+
+const LANDING_PAGE: &str = "<!doctype html>
+<html><head><meta charset=\"utf-8\"><title>shortener</title></head>
+<body><h1>shortener</h1><p>POST a JSON body like <code>{\"url\": \"https://example.com\"}</code> to this address to get a short link.</p></body></html>
+";
+
+/// Someone opening the bare host in a browser gets a page instead of a 405.
+async fn landing(State(state): State<AppState>) -> impl IntoResponse {
+    match &state.config.homepage_url {
+        // serialized by `Url`, so always ASCII
+        Some(homepage) => (StatusCode::FOUND, [(LOCATION, homepage.clone())]).into_response(),
+        None => Html(LANDING_PAGE).into_response(),
+    }
+}
+
+const FAVICON: &[u8] = include_bytes!("pages/favicon.ico");
+const ROBOTS_TXT: &str = "User-agent: *\nDisallow:\n";
+
+/// Browsers ask for this alongside every short link; answer without a
+/// lookup instead of logging a 404 for the `favicon.ico` id.
+async fn favicon() -> impl IntoResponse {
+    metrics::counter!("noise_requests_total", "path" => "favicon").increment(1);
+    (
+        [
+            (CONTENT_TYPE, "image/x-icon"),
+            (CACHE_CONTROL, "public, max-age=86400"),
+        ],
+        FAVICON,
+    )
+}
+
+async fn robots_txt(State(state): State<AppState>) -> impl IntoResponse {
+    metrics::counter!("noise_requests_total", "path" => "robots").increment(1);
+    (
+        [
+            (CONTENT_TYPE, "text/plain; charset=utf-8"),
+            (CACHE_CONTROL, "public, max-age=86400"),
+        ],
+        state.robots_txt.to_string(),
+    )
+}
+
+/// Paths browsers and scanners probe that no link can have, answered
+/// without a lookup.
+async fn no_such_file() -> impl IntoResponse {
+    metrics::counter!("noise_requests_total", "path" => "other").increment(1);
+    (
+        StatusCode::NOT_FOUND,
+        [(CACHE_CONTROL, "public, max-age=86400")],
+    )
+}
+
+/// Lets admin keys, or the link's management token, through to a link.
+/// A token that isn't the link's is a 403, even for a link that doesn't
+/// exist.
+async fn may_manage(state: &AppState, manager: &Manager, id: &str) -> Result<(), ShortenError> {
+    match manager {
+        Manager::Key(key) => key.require(Scope::Admin),
+        Manager::Token(token) => {
+            let hash: Option<String> =
+                sqlx::query_scalar("SELECT management_token_hash FROM urls WHERE id = $1")
+                    .bind(id)
+                    .fetch_optional(&state.db.db)
+                    .await?
+                    .flatten();
+            match hash {
+                Some(hash) if auth::verify_secret(token, &hash).await => Ok(()),
+                _ => Err(StatusCodeError(StatusCode::FORBIDDEN).into()),
+            }
+        }
+    }
+}
+
+async fn update_link(
+    State(state): State<AppState>,
+    manager: Manager,
+    Slug(id): Slug,
+    AppJson(req): AppJson<UpdateLinkReq>,
+) -> Result<impl IntoResponse, ShortenError> {
+    let id = state.db.resolve(&id).await?;
+    may_manage(&state, &manager, &id).await?;
+    // everything is checked before anything is written, so a refused
+    // change leaves the link as it was
+    if !req
+        .redirect_status
+        .is_none_or(|s| REDIRECT_STATUSES.contains(&s))
+    {
+        return Err(StatusCodeError(StatusCode::UNPROCESSABLE_ENTITY).into());
+    }
+    // browsers keep a permanent redirect and skip the count, as at creation
+    if matches!(req.redirect_status, Some(301 | 308)) {
+        let link = state
+            .db
+            .get_info(&id)
+            .await?
+            .ok_or(StatusCodeError(StatusCode::NOT_FOUND))?;
+        if link.max_uses.is_some() {
+            return Err(StatusCodeError(StatusCode::UNPROCESSABLE_ENTITY).into());
+        }
+    }
+    if !req.notes.iter().flatten().all(|n| links::notes_fit(n)) {
+        return Err(StatusCodeError(StatusCode::UNPROCESSABLE_ENTITY).into());
+    }
+    let click_cap = match req.click_cap {
+        Some(Some(cap)) => match i64::try_from(cap) {
+            Ok(cap) if cap > 0 => Some(Some(cap)),
+            _ => return Err(StatusCodeError(StatusCode::UNPROCESSABLE_ENTITY).into()),
+        },
+        Some(None) => Some(None),
+        None => None,
+    };
+    let destination = match req.url {
+        Some(url) => {
+            let url = destination(&state.config, &url)?;
+            Some(screened_destination(&state, url).await?)
+        }
+        None => None,
+    };
+    let update = LinkUpdate {
+        url: destination.as_ref().map(|(url, _)| url.as_str()),
+        enabled: req.enabled,
+        forward_query: req.forward_query,
+        redirect_status: req.redirect_status,
+        notes: req.notes.as_ref().map(Option::as_deref),
+        click_cap,
+    };
+    let changed = state
+        .db
+        .update_link(
+            &id,
+            &update,
+            state.config.compress_urls_over,
+            &manager.actor(),
+        )
+        .await?;
+    if !changed {
+        return Err(StatusCodeError(StatusCode::NOT_FOUND).into());
+    }
+    if let Some((_, Verdict::Clean)) = destination {
+        screen::record(&state.db.db, &id, None).await?;
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Renews a link, or makes it permanent. Admin keys can change any link,
+/// write keys the ones they created.
+async fn set_expiry(
+    State(state): State<AppState>,
+    key: ApiKey,
+    Slug(slug): Slug,
+    AppJson(req): AppJson<ExpiryReq>,
+) -> Result<Json<ExpiryRes>, ShortenError> {
+    key.require(Scope::Write)?;
+    let expires_at = req.expires_in_secs.flatten().map(expiry_in).transpose()?;
+    let link = state
+        .db
+        .get_link(&slug)
+        .await?
+        .ok_or(StatusCodeError(StatusCode::NOT_FOUND))?;
+    if !key.has(Scope::Admin) && link.owner != Some(key.owner()) {
+        return Err(StatusCodeError(StatusCode::FORBIDDEN).into());
+    }
+    if !state.db.set_expires_at(&link.id, expires_at).await? {
+        return Err(StatusCodeError(StatusCode::NOT_FOUND).into());
+    }
+    Ok(Json(ExpiryRes { expires_at }))
+}
+
+/// The stored form of a link's destination, see [`idn::normalize`]. With
+/// `REQUIRE_ABSOLUTE_TARGETS` off a relative one is taken too, as it is.
+fn destination(config: &Config, url: &str) -> Result<String, ShortenError> {
+    idn::normalize(url)
+        .or_else(|| match config.require_absolute_targets {
+            true => None,
+            false => idn::relative(url),
+        })
+        .ok_or_else(|| ShortenError::InvalidUrl(idn::clean(url)))
+}
+
+/// Refuses destinations on private or internal addresses, for when the
+/// server is about to fetch from them. The fetch checks again as it
+/// connects, in case the host resolves differently by then.
+async fn guard_destination(url: &str) -> Result<(), ShortenError> {
+    let Ok(parsed) = url::Url::parse(url) else {
+        return Ok(());
+    };
+    match fetch::check_destination(&parsed).await {
+        Err(FetchError::Blocked(host)) => Err(ShortenError::BlockedDomain(host)),
+        _ => Ok(()),
+    }
+}
+
+/// `url` after the checks the url of a new link gets, short of the upgrade,
+/// which is the admin's to make.
+async fn screened_destination(
+    state: &AppState,
+    url: String,
+) -> Result<(String, Verdict), ShortenError> {
+    let url = collapse_own_links(state, url).await?;
+    let verdict = if state.screener.is_enabled() {
+        state.screener.check(&url).await
+    } else {
+        Verdict::Unscreened
+    };
+    if let Verdict::Flagged(threat) = verdict {
+        return Err(ShortenError::Flagged(threat));
+    }
+    Ok((url, verdict))
+}
+
+/// Points a link at `url` after [`screened_destination`]. Returns the url
+/// it had, `None` if there's no such link.
+async fn change_destination(
+    state: &AppState,
+    id: &str,
+    url: String,
+    actor: &str,
+) -> Result<Option<String>, ShortenError> {
+    let (url, verdict) = screened_destination(state, url).await?;
+    let mut tx = state.db.db.begin().await?;
+    let old = history::set_url(&mut tx, id, &url, state.config.compress_urls_over, actor).await?;
+    tx.commit().await?;
+    if old.is_some() && verdict == Verdict::Clean {
+        screen::record(&state.db.db, id, None).await?;
+    }
+    Ok(old)
+}
+
+/// The destination changes of a link, newest first.
+async fn link_history(
+    State(state): State<AppState>,
+    key: ApiKey,
+    Slug(id): Slug,
+    Query(order): Query<history::Order>,
+) -> Result<Json<Vec<history::Change>>, ShortenError> {
+    key.require(Scope::Read)?;
+    let id = state.db.resolve(&id).await?;
+    if state.db.stats(&id).await?.is_none() {
+        return Err(StatusCodeError(StatusCode::NOT_FOUND).into());
+    }
+    Ok(Json(history::list(&state.db.db, &id, order).await?))
+}
+
+/// Restores the destination a link had before its latest change, which is
+/// a change of its own: rolling back twice gets back to where it started.
+/// 409 for a link that never changed.
+async fn rollback_link(
+    State(state): State<AppState>,
+    key: ApiKey,
+    Slug(id): Slug,
+) -> Result<impl IntoResponse, ShortenError> {
+    key.require(Scope::Admin)?;
+    let id = state.db.resolve(&id).await?;
+    let Some(url) = history::previous(&state.db.db, &id).await? else {
+        return match state.db.stats(&id).await? {
+            Some(_) => Err(StatusCodeError(StatusCode::CONFLICT).into()),
+            None => Err(StatusCodeError(StatusCode::NOT_FOUND).into()),
+        };
+    };
+    // what it had may be relative from before they were refused
+    if state.config.require_absolute_targets && idn::is_relative(&url) {
+        return Err(ShortenError::InvalidUrl(url));
+    }
+    if change_destination(&state, &id, url, &key.owner())
+        .await?
+        .is_none()
+    {
+        return Err(StatusCodeError(StatusCode::NOT_FOUND).into());
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+struct OwnerRes {
+    id: String,
+    owner: Option<String>,
+}
+
+/// Claims a link nobody owns for the calling key, once the destination's
+/// site vouches for it. The first call hands out a token for the site to
+/// list at [`claims::WELL_KNOWN_PATH`], answering 202; calls after that
+/// fetch the list and take the link if it's there, 403 if it isn't. 409
+/// for a link with an owner, whoever got there first.
+async fn claim_link(
+    State(state): State<AppState>,
+    key: ApiKey,
+    Slug(slug): Slug,
+) -> Result<Response, ShortenError> {
+    key.require(Scope::Write)?;
+    let link = state
+        .db
+        .get_link(&slug)
+        .await?
+        .ok_or(StatusCodeError(StatusCode::NOT_FOUND))?;
+    if link.owner.is_some() {
+        return Err(StatusCodeError(StatusCode::CONFLICT).into());
+    }
+    let Some(proof) = claims::verification_url(&link.url) else {
+        return Err(ShortenError::Unverified(
+            "the destination isn't a site that can vouch for the claim".into(),
+        ));
+    };
+    let owner = key.owner();
+    let (token, expires_at, fresh) = claims::challenge(&state.db.db, &link.id, &owner).await?;
+    if fresh {
+        let challenge = Challenge {
+            token,
+            verification_url: proof.to_string(),
+            expires_at,
+        };
+        return Ok((StatusCode::ACCEPTED, Json(challenge)).into_response());
+    }
+    claims::prove(&state.fetcher, &proof, &token)
+        .await
+        .map_err(ShortenError::Unverified)?;
+    if !claims::take(&state.db.db, &link.id, &owner).await? {
+        return Err(StatusCodeError(StatusCode::CONFLICT).into());
+    }
+    audit::record(
+        &state.db.db,
+        &owner,
+        "claim_link",
+        serde_json::json!({ "id": link.id, "proof": proof.as_str() }),
+    )
+    .await?;
+    let owner = Some(owner);
+    Ok(Json(OwnerRes { id: link.id, owner }).into_response())
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+struct AssignOwnerReq {
+    /// An active key's id, or `null` to leave the link unowned.
+    owner: Option<String>,
+}
+
+/// Gives a link to another owner, or none, without asking anyone. Pending
+/// claims and transfers of the link are dropped. 422 for an owner that
+/// isn't an active key.
+async fn assign_owner(
+    State(state): State<AppState>,
+    key: ApiKey,
+    Slug(slug): Slug,
+    AppJson(req): AppJson<AssignOwnerReq>,
+) -> Result<Json<OwnerRes>, ShortenError> {
+    key.require(Scope::Admin)?;
+    let id = state.db.resolve(&slug).await?;
+    if let Some(owner) = &req.owner {
+        if !auth::is_active(&state.db.db, owner).await? {
+            return Err(StatusCodeError(StatusCode::UNPROCESSABLE_ENTITY).into());
+        }
+    }
+    let previous = claims::assign(&state.db.db, &id, req.owner.as_deref())
+        .await?
+        .ok_or(StatusCodeError(StatusCode::NOT_FOUND))?;
+    audit::record(
+        &state.db.db,
+        &key.owner(),
+        "assign_owner",
+        serde_json::json!({ "id": id, "from": previous, "to": req.owner }),
+    )
+    .await?;
+    Ok(Json(OwnerRes {
+        id,
+        owner: req.owner,
+    }))
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+struct TransferReq {
+    /// The id of the key to receive the link.
+    to: String,
+}
+
+/// Offers a link to another key, which gets it once it accepts. Write keys
+/// offer the links they own, admin keys any owned link. 422 for a
+/// receiver that isn't an active key or already owns the link.
+async fn offer_transfer(
+    State(state): State<AppState>,
+    key: ApiKey,
+    Slug(slug): Slug,
+    AppJson(req): AppJson<TransferReq>,
+) -> Result<impl IntoResponse, ShortenError> {
+    key.require(Scope::Write)?;
+    let link = state
+        .db
+        .get_link(&slug)
+        .await?
+        .ok_or(StatusCodeError(StatusCode::NOT_FOUND))?;
+    let Some(from) = link.owner else {
+        // unowned links are claimed instead
+        return Err(StatusCodeError(StatusCode::CONFLICT).into());
+    };
+    if !key.has(Scope::Admin) && from != key.owner() {
+        return Err(StatusCodeError(StatusCode::FORBIDDEN).into());
+    }
+    if req.to == from || !auth::is_active(&state.db.db, &req.to).await? {
+        return Err(StatusCodeError(StatusCode::UNPROCESSABLE_ENTITY).into());
+    }
+    let transfer = claims::offer(&state.db.db, &link.id, &from, &req.to).await?;
+    audit::record(
+        &state.db.db,
+        &key.owner(),
+        "offer_transfer",
+        serde_json::json!({ "id": link.id, "from": from, "to": req.to }),
+    )
+    .await?;
+    Ok((StatusCode::ACCEPTED, Json(transfer)))
+}
+
+/// Takes a link offered to the calling key. 404 if it wasn't, 409 if the
+/// link changed hands since it was offered.
+async fn accept_transfer(
+    State(state): State<AppState>,
+    key: ApiKey,
+    Slug(slug): Slug,
+) -> Result<Json<OwnerRes>, ShortenError> {
+    key.require(Scope::Write)?;
+    let id = state.db.resolve(&slug).await?;
+    let owner = key.owner();
+    let transfer = claims::accept(&state.db.db, &id, &owner)
+        .await?
+        .ok_or(StatusCodeError(StatusCode::NOT_FOUND))?;
+    audit::record(
+        &state.db.db,
+        &owner,
+        "accept_transfer",
+        serde_json::json!({ "id": id, "from": transfer.from_owner, "to": owner }),
+    )
+    .await?;
+    Ok(Json(OwnerRes {
+        id,
+        owner: Some(owner),
+    }))
+}
+
+/// Cancels an offer of the calling key's, or declines one made to it.
+async fn withdraw_transfer(
+    State(state): State<AppState>,
+    key: ApiKey,
+    Slug(slug): Slug,
+) -> Result<impl IntoResponse, ShortenError> {
+    key.require(Scope::Write)?;
+    let id = state.db.resolve(&slug).await?;
+    if !claims::withdraw(&state.db.db, &id, &key.owner()).await? {
+        return Err(StatusCodeError(StatusCode::NOT_FOUND).into());
+    }
+    audit::record(
+        &state.db.db,
+        &key.owner(),
+        "withdraw_transfer",
+        serde_json::json!({ "id": id }),
+    )
+    .await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// The pending offers of links made by or to the calling key.
+async fn list_transfers(
+    State(state): State<AppState>,
+    key: ApiKey,
+) -> Result<Json<Vec<Transfer>>, ShortenError> {
+    key.require(Scope::Read)?;
+    Ok(Json(claims::pending(&state.db.db, &key.owner()).await?))
+}
+
+/// Moves a link to a newly generated id, for when the old one leaked. The
+/// destination, aliases, tags and stats stay with it; the old id answers 404
+/// like any unknown one, so it gives away nothing about the new one.
+async fn rotate_link(
+    _: Admin,
+    State(state): State<AppState>,
+    base: LinkBase,
+    Slug(id): Slug,
+) -> Result<Json<ShortRes>, ShortenError> {
+    let id = state.db.resolve(&id).await?;
+    // clicks still buffered are keyed by the old id
+    state.counter.flush(&state.db.db).await?;
+    state.visitors.flush(&state.db.db).await?;
+    state.geo_clicks.flush(&state.db.db).await?;
+    let rotated = state
+        .db
+        .rotate(&id)
+        .await?
+        .ok_or(StatusCodeError(StatusCode::NOT_FOUND))?;
+    let id = rotated.id;
+    let slugs = aliases::list(&state.db.db, &id).await?;
+    let tags = tags::list(&state.db.db, &id).await?;
+    Ok(Json(ShortRes {
+        url: base.short_url(&id),
+        upgraded: false,
+        slugs,
+        tags,
+        created: false,
+        created_at: rotated.created_at,
+        description: rotated.description,
+        expires_at: rotated.expires_at,
+        clamped: false,
+        management_token: None,
+        stats_url: None,
+        qr_url: None,
+    }))
+}
+
+/// Deletes a link, or with `?dry_run=true` only says whether it would.
+async fn delete_link(
+    manager: Manager,
+    State(state): State<AppState>,
+    Slug(id): Slug,
+    Query(DryRunQuery { dry_run }): Query<DryRunQuery>,
+) -> Result<Response, ShortenError> {
+    let id = state.db.resolve(&id).await?;
+    may_manage(&state, &manager, &id).await?;
+    let deleted = state.db.delete_links(Doomed::Link(&id), dry_run).await?;
+    if deleted.is_empty() {
+        return Err(StatusCodeError(StatusCode::NOT_FOUND).into());
+    }
+    if dry_run {
+        return Ok(Json(Affected::new(true, deleted)).into_response());
+    }
+    Ok(StatusCode::NO_CONTENT.into_response())
+}
+
+/// Deletes every expired link now, or with `?dry_run=true` lists the ones
+/// it would.
+async fn sweep_expired(
+    _: Admin,
+    State(state): State<AppState>,
+    Query(DryRunQuery { dry_run }): Query<DryRunQuery>,
+) -> Result<Json<Affected>, ShortenError> {
+    let deleted = state
+        .db
+        .delete_links(Doomed::Expired(state.config.expiry_skew_grace), dry_run)
+        .await?;
+    if !dry_run {
+        info!("Swept {} expired links", deleted.len());
+    }
+    Ok(Json(Affected::new(dry_run, deleted)))
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+struct ReindexRes {
+    /// Slugs in the slug filter, 0 without one.
+    slugs: usize,
+    /// Redirect policy rules, those from `CONFIRM_DOMAINS` included.
+    policies: usize,
+}
+
+/// Rebuilds what's kept in memory from the tables, the slug filter and the
+/// redirect policies, for after changes made to them directly, say an
+/// import run in SQL.
+async fn reindex(
+    _: Admin,
+    State(state): State<AppState>,
+) -> Result<Json<ReindexRes>, ShortenError> {
+    let slugs = state.db.rebuild_slug_filter().await?;
+    metrics::gauge!("slug_filter_slugs").set(slugs as f64);
+    state.policies.reload(&state.db.db).await?;
+    let policies = state.policies.rule_count();
+    info!("Reindexed {} slugs and {} policy rules", slugs, policies);
+    Ok(Json(ReindexRes { slugs, policies }))
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "snake_case")]
+struct BulkReq {
+    filter: bulk::Filter,
+    #[serde(flatten)]
+    action: bulk::Action,
+}
+
+/// Deletes, disables or tags every link a filter matches and records the
+/// operation in the audit log. An empty filter is refused with 400 unless
+/// it says `"all": true`. With `?dry_run=true` nothing changes and nothing
+/// is recorded.
+async fn bulk_links(
+    State(state): State<AppState>,
+    key: ApiKey,
+    Query(DryRunQuery { dry_run }): Query<DryRunQuery>,
+    AppJson(req): AppJson<BulkReq>,
+) -> Result<Json<Affected>, ShortenError> {
+    key.require(Scope::Admin)?;
+    if req.filter.is_empty() && !req.filter.all {
+        return Err(StatusCodeError(StatusCode::BAD_REQUEST).into());
+    }
+    let action = match req.action {
+        bulk::Action::AddTag { tag } => match tags::normalize(&[tag]).as_deref() {
+            Some([tag]) => bulk::Action::AddTag { tag: tag.clone() },
+            _ => return Err(StatusCodeError(StatusCode::UNPROCESSABLE_ENTITY).into()),
+        },
+        action => action,
+    };
+    let changed = state.db.bulk(&req.filter, &action, dry_run).await?;
+    if !dry_run {
+        info!(
+            "{} of {} links by {}",
+            action.audit_name(),
+            changed.len(),
+            key.owner()
+        );
+        let mut details = serde_json::to_value(&action).unwrap_or_default();
+        details["filter"] = serde_json::to_value(&req.filter).unwrap_or_default();
+        details["count"] = changed.len().into();
+        audit::record(&state.db.db, &key.owner(), action.audit_name(), details).await?;
+    }
+    Ok(Json(Affected::new(dry_run, changed)))
+}
+
+/// Deletes the links of a list of ids in one transaction, counting those
+/// that existed; ids of no link are skipped. Records the deletion in the
+/// audit log unless `?dry_run=true`. The slug filter keeps the ids until
+/// its next rebuild, which only costs their redirects a lookup.
+async fn batch_delete(
+    State(state): State<AppState>,
+    key: ApiKey,
+    Query(DryRunQuery { dry_run }): Query<DryRunQuery>,
+    AppJson(ids): AppJson<Vec<String>>,
+) -> Result<Json<Affected>, ShortenError> {
+    key.require(Scope::Admin)?;
+    if ids.len() > bulk::BATCH {
+        return Err(ShortenError::TooManyItems {
+            limit: bulk::BATCH,
+            received: ids.len(),
+        });
+    }
+    let deleted = state.db.delete_links(Doomed::Links(&ids), dry_run).await?;
+    if !dry_run {
+        info!("batch_delete of {} links by {}", deleted.len(), key.owner());
+        let details = serde_json::json!({ "ids": deleted, "count": deleted.len() });
+        audit::record(&state.db.db, &key.owner(), "batch_delete", details).await?;
+    }
+    Ok(Json(Affected::new(dry_run, deleted)))
+}
+
+/// Metrics snapshots from `from` up to `to`, by default the 30 days up to
+/// now, per hour or day.
+async fn stats_history(
+    _: Admin,
+    State(state): State<AppState>,
+    format: Format,
+    Query(query): Query<StatsHistoryQuery>,
+) -> Result<Response, ShortenError> {
+    let to = query.to.unwrap_or_else(Utc::now);
+    let from = query.from.unwrap_or(to - chrono::TimeDelta::days(30));
+    if from >= to {
+        return Err(StatusCodeError(StatusCode::BAD_REQUEST).into());
+    }
+    let snapshots = state
+        .db
+        .read(|db| snapshots::history(db, from, to, query.granularity))
+        .await?;
+    Ok(format.respond("stats-history.csv", snapshots))
+}
+
+/// The deprecated routes with the calls each got since this instance
+/// started, to tell when removing one will go unnoticed.
+async fn list_deprecations(_: Admin, State(state): State<AppState>) -> Json<Vec<DeprecatedRoute>> {
+    Json(state.deprecations.list())
+}
+
+/// The slugs of merged instances now known by another name, for the old
+/// instances to redirect: CSV with `?format=csv`.
+async fn merged_ids(
+    _: Admin,
+    State(state): State<AppState>,
+    format: Format,
+) -> Result<Response, ShortenError> {
+    let renamed = merge::renamed(&state.db.db).await?;
+    Ok(format.respond("merged-ids.csv", renamed))
+}
+
+/// The latest query plan check, run now if there hasn't been one yet.
+async fn db_health(
+    _: Admin,
+    State(state): State<AppState>,
+) -> Result<Json<advisor::Health>, ShortenError> {
+    let Some(advisor) = &state.advisor else {
+        return Err(StatusCodeError(StatusCode::NOT_FOUND).into());
+    };
+    let health = match advisor.latest() {
+        Some(health) => health,
+        None => advisor.check(&state.db.db).await?,
+    };
+    Ok(Json(health))
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "snake_case")]
+struct LatencyQuery {
+    #[serde(default)]
+    window_secs: Option<u64>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+struct LinkLatency {
+    id: String,
+    window_secs: u64,
+    /// Whether the link is among the busiest ones, whose latency is
+    /// tracked. Without, the percentiles are `null`.
+    tracked: bool,
+    #[serde(flatten)]
+    percentiles: Percentiles,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "snake_case")]
+struct ShadowDiffsQuery {
+    #[serde(default = "default_shadow_diffs")]
+    limit: i64,
+}
+
+fn default_shadow_diffs() -> i64 {
+    100
+}
+
+/// The latest requests the shadow deployment answered differently, 404
+/// without one.
+/// Redirect latency percentiles of a link over the last `window_secs`, by
+/// default the whole `LINK_LATENCY_WINDOW_SECS`. 404 unless `LINK_LATENCY`
+/// is on.
+async fn link_latency(
+    State(state): State<AppState>,
+    key: ApiKey,
+    Slug(id): Slug,
+    Query(query): Query<LatencyQuery>,
+) -> Result<Json<LinkLatency>, ShortenError> {
+    key.require(Scope::Read)?;
+    let latency = state
+        .latency
+        .as_ref()
+        .ok_or(StatusCodeError(StatusCode::NOT_FOUND))?;
+    let id = state.db.resolve(&id).await?;
+    if state.db.stats(&id).await?.is_none() {
+        return Err(StatusCodeError(StatusCode::NOT_FOUND).into());
+    }
+    let window = query
+        .window_secs
+        .map(Duration::from_secs)
+        .unwrap_or(state.config.link_latency_window)
+        .min(state.config.link_latency_window);
+    let percentiles = latency.percentiles(&id, window);
+    Ok(Json(LinkLatency {
+        tracked: percentiles.is_some(),
+        percentiles: percentiles.unwrap_or_default(),
+        window_secs: window.as_secs(),
+        id,
+    }))
+}
+
+/// Follows a link's destination through its redirects, up to
+/// `TRACE_MAX_HOPS`, reporting each hop and where the chain ended. Disabled
+/// links are traced too.
+async fn trace_link(
+    _: Admin,
+    State(state): State<AppState>,
+    Slug(id): Slug,
+) -> Result<Json<Trace>, ShortenError> {
+    let link = state
+        .db
+        .get_info(&id)
+        .await?
+        .ok_or(StatusCodeError(StatusCode::NOT_FOUND))?;
+    let url = url::Url::parse(&link.url)
+        .map_err(|_| StatusCodeError(StatusCode::UNPROCESSABLE_ENTITY))?;
+    Ok(Json(
+        trace::follow(
+            &state.fetcher,
+            url,
+            state.config.trace_max_hops,
+            state.config.trace_hop_timeout,
+        )
+        .await,
+    ))
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+struct CheckLinksReq {
+    #[serde(default)]
+    ids: Option<Vec<String>>,
+    /// Checks every enabled link instead, which `ids` can't be given with.
+    #[serde(default)]
+    all: bool,
+}
+
+/// Asks the destinations of the links listed whether they still answer,
+/// `LINK_CHECK_CONCURRENCY` at a time, reporting each one's status or why
+/// it has none. Ids of no link are left out. 400 without either `ids` or
+/// `"all": true`.
+async fn check_links(
+    _: Admin,
+    State(state): State<AppState>,
+    AppJson(req): AppJson<CheckLinksReq>,
+) -> Result<Json<Vec<linkcheck::Checked>>, ShortenError> {
+    let ids = match (req.ids, req.all) {
+        (Some(ids), false) => Some(ids),
+        (None, true) => None,
+        _ => return Err(StatusCodeError(StatusCode::BAD_REQUEST).into()),
+    };
+    let links = linkcheck::destinations(&state.db.db, ids.as_deref()).await?;
+    let checked = linkcheck::check(
+        &state.fetcher,
+        links,
+        state.config.link_check_concurrency,
+        state.config.trace_hop_timeout,
+    )
+    .await;
+    let failed = checked.iter().filter(|c| c.status.is_none()).count();
+    info!(
+        "Checked {} links, {} without an answer",
+        checked.len(),
+        failed
+    );
+    Ok(Json(checked))
+}
+
+async fn shadow_diffs(
+    _: Admin,
+    State(state): State<AppState>,
+    Query(query): Query<ShadowDiffsQuery>,
+) -> Result<Json<Vec<shadow::Diff>>, ShortenError> {
+    if state.shadow.is_none() {
+        return Err(StatusCodeError(StatusCode::NOT_FOUND).into());
+    }
+    let limit = query.limit.clamp(1, 1000);
+    Ok(Json(state.db.read(|db| shadow::latest(db, limit)).await?))
+}
+
+async fn list_links(
+    State(state): State<AppState>,
+    key: ApiKey,
+    format: Format,
+    Query(query): Query<LinksQuery>,
+) -> Result<Response, ShortenError> {
+    key.require(Scope::Read)?;
+    let mut links = match (query.tag, query.url) {
+        (Some(tag), None) => {
+            state
+                .db
+                .read(|db| links::with_tag(db, &tag, query.sort))
+                .await?
+        }
+        (None, Some(url)) => match idn::normalize(&url) {
+            Some(url) => {
+                let key = state.db.url_key(&url);
+                state
+                    .db
+                    .read(|db| links::to_url(db, &key, query.sort))
+                    .await?
+            }
+            None => Vec::new(),
+        },
+        _ => return Err(StatusCodeError(StatusCode::BAD_REQUEST).into()),
+    };
+    for link in &mut links {
+        link.url = idn::display(&link.url);
+    }
+    Ok(format.respond("links.csv", links))
+}
+
+/// Links created with the calling key.
+async fn my_links(
+    State(state): State<AppState>,
+    key: ApiKey,
+    Query(sort): Query<Sort>,
+) -> Result<Json<Vec<AdminLink>>, ShortenError> {
+    key.require(Scope::Read)?;
+    let owner = key.owner();
+    let mut links = state
+        .db
+        .read(|db| links::owned_by(db, &owner, sort))
+        .await?;
+    for link in &mut links {
+        link.url = idn::display(&link.url);
+    }
+    Ok(Json(links))
+}
+
+async fn hot_links(_: Admin, format: Format, State(state): State<AppState>) -> Response {
+    format.respond("hot-links.csv", state.spikes.hot())
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct QrReq {
+    #[serde(default)]
+    scale: Option<u32>,
+}
+
+/// The short url as a QR code PNG, `?scale=` pixels per module. Public like
+/// the redirect it encodes, so a disabled link is as missing here as there.
+async fn link_qr(
+    State(state): State<AppState>,
+    base: LinkBase,
+    Slug(id): Slug,
+    Query(req): Query<QrReq>,
+) -> Result<impl IntoResponse, ShortenError> {
+    let link = state.db.get_info(&id).await?;
+    if let RedirectOutcome::Disabled | RedirectOutcome::NotFound =
+        RedirectOutcome::of(link.as_ref(), state.config.expiry_skew_grace)
+    {
+        return Err(StatusCodeError(StatusCode::NOT_FOUND).into());
+    }
+    // the slug asked for, so an alias's code carries the alias
+    let code = QrCode::encode(&base.short_url(&id))
+        .ok_or(StatusCodeError(StatusCode::UNPROCESSABLE_ENTITY))?;
+    Ok((
+        [
+            (CONTENT_TYPE, "image/png"),
+            (CACHE_CONTROL, "public, max-age=86400"),
+        ],
+        code.png(req.scale.unwrap_or(qr::DEFAULT_SCALE)),
+    ))
+}
+
+async fn link_stats(
+    State(state): State<AppState>,
+    key: ApiKey,
+    Slug(id): Slug,
+) -> Result<Json<LinkStats>, ShortenError> {
+    key.require(Scope::Read)?;
+    let id = state.db.resolve(&id).await?;
+    let (stored, notes) = state
+        .db
+        .stats(&id)
+        .await?
+        .ok_or(StatusCodeError(StatusCode::NOT_FOUND))?;
+    let clicks = stored + state.counter.unflushed(&id) as i64;
+    let slugs = state.db.read(|db| aliases::list(db, &id)).await?;
+    let uniques = state.db.read(|db| state.visitors.total(db, &id)).await?;
+    let geo_clicks = state
+        .db
+        .read(|db| state.geo_clicks.breakdown(db, &id))
+        .await?;
+    Ok(Json(LinkStats {
+        id,
+        slugs,
+        clicks,
+        uniques,
+        notes,
+        click_dedup_secs: state
+            .dedup
+            .is_some()
+            .then(|| state.config.click_dedup_window.as_secs()),
+        geo_clicks: (!geo_clicks.is_empty()).then_some(geo_clicks),
+    }))
+}
+
+/// Per-day clicks and visitors, counted from when daily stats were
+/// introduced; `clicks` on the link itself also has older redirects.
+async fn daily_stats(
+    State(state): State<AppState>,
+    key: ApiKey,
+    format: Format,
+    Slug(id): Slug,
+) -> Result<Response, ShortenError> {
+    key.require(Scope::Read)?;
+    let id = state.db.resolve(&id).await?;
+    if state.db.stats(&id).await?.is_none() {
+        return Err(StatusCodeError(StatusCode::NOT_FOUND).into());
+    }
+    let daily = state.db.read(|db| state.visitors.daily(db, &id)).await?;
+    Ok(format.respond(&format!("daily-stats-{}.csv", id), daily))
+}
+
+/// Clicks per day, week or month over the last `days` days.
+async fn link_timeseries(
+    State(state): State<AppState>,
+    key: ApiKey,
+    format: Format,
+    Slug(id): Slug,
+    Query(query): Query<TimeseriesQuery>,
+) -> Result<Response, ShortenError> {
+    key.require(Scope::Read)?;
+    if !(1..=MAX_TIMESERIES_DAYS).contains(&query.days) {
+        return Err(StatusCodeError(StatusCode::BAD_REQUEST).into());
+    }
+    let id = state.db.resolve(&id).await?;
+    if state.db.stats(&id).await?.is_none() {
+        return Err(StatusCodeError(StatusCode::NOT_FOUND).into());
+    }
+    let buckets = state
+        .db
+        .read(|db| {
+            state
+                .visitors
+                .timeseries(db, &id, query.interval, query.days)
+        })
+        .await?;
+    Ok(format.respond(&format!("timeseries-{}.csv", id), buckets))
+}
+
+async fn add_alias(
+    _: Admin,
+    State(state): State<AppState>,
+    Slug(id): Slug,
+    AppJson(req): AppJson<AliasReq>,
+) -> Result<impl IntoResponse, ShortenError> {
+    let id = aliases::resolve(&state.db.db, &id)
+        .await?
+        .ok_or(StatusCodeError(StatusCode::NOT_FOUND))?;
+    let alias = state.db.normalize(&req.alias);
+    aliases::validate(&alias)?;
+    if aliases::add(&state.db.db, &id, &alias).await? {
+        state.db.added_slug(&alias);
+    } else {
+        return Err(StatusCodeError(StatusCode::CONFLICT).into());
+    }
+    let slugs = aliases::list(&state.db.db, &id).await?;
+    Ok((StatusCode::CREATED, Json(AliasesRes { slugs })))
+}
+
+/// Whether `alias` could be given to a link now, on shortening or as an
+/// extra slug: 400 if it's no valid alias whether taken or not.
+async fn alias_available(
+    State(state): State<AppState>,
+    Query(req): Query<AliasReq>,
+) -> Result<Json<AliasAvailableRes>, ShortenError> {
+    let alias = state.db.normalize(&req.alias);
+    if !aliases::is_valid(&alias) {
+        return Err(StatusCodeError(StatusCode::BAD_REQUEST).into());
+    }
+    let available = !aliases::is_taken(&state.db.db, &alias).await?;
+    Ok(Json(AliasAvailableRes { available }))
+}
+
+async fn remove_alias(
+    _: Admin,
+    State(state): State<AppState>,
+    Path((id, alias)): Path<(String, String)>,
+) -> Result<impl IntoResponse, ShortenError> {
+    if !state.db.is_plausible(&id) || !state.db.is_plausible(&alias) {
+        return Err(StatusCodeError(StatusCode::NOT_FOUND).into());
+    }
+    let (id, alias) = (state.db.normalize(&id), state.db.normalize(&alias));
+    let id = state.db.resolve(&id).await?;
+    if !aliases::remove(&state.db.db, &id, &alias).await? {
+        return Err(StatusCodeError(StatusCode::NOT_FOUND).into());
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Always answers 202 so the endpoint can't be used to probe which ids exist.
+async fn report(
+    State(state): State<AppState>,
+    ClientIp(ip): ClientIp,
+    Slug(id): Slug,
+    body: Option<AppJson<ReportReq>>,
+) -> Result<impl IntoResponse, ShortenError> {
+    if !state.report_limiter.check(ip) {
+        return Err(StatusCodeError(StatusCode::TOO_MANY_REQUESTS).into());
+    }
+    let reason = body.and_then(|AppJson(req)| req.reason);
+    let id = state.db.resolve(&id).await?;
+    let disabled = reports::submit(
+        &state.db.db,
+        &id,
+        &ip.to_string(),
+        reason.as_deref(),
+        state.config.report_threshold,
+        state.config.report_window,
+    )
+    .await?;
+    if let Some(reports) = disabled {
+        warn!("Link {} disabled after {} abuse reports", id, reports);
+        if state.config.webhook_url.is_some() {
+            jobs::enqueue(&state.db.db, &Event::LinkAutoDisabled { id, reports }).await?;
+        }
+    }
+    Ok(StatusCode::ACCEPTED)
+}
+
+async fn list_reports(
+    _: Admin,
+    State(state): State<AppState>,
+) -> Result<Json<Vec<ReportSummary>>, ShortenError> {
+    Ok(Json(reports::list(&state.db.db).await?))
+}
+
+async fn resolve_report(
+    _: Admin,
+    State(state): State<AppState>,
+    Slug(id): Slug,
+    AppJson(action): AppJson<ReportAction>,
+) -> Result<impl IntoResponse, ShortenError> {
+    let found = match action {
+        ReportAction::Disable => state.db.set_enabled(&id, false).await?,
+        ReportAction::Delete => state.db.delete(&id).await?,
+        ReportAction::Dismiss => {
+            reports::dismiss(&state.db.db, &id).await?;
+            true
+        }
+    };
+    if !found {
+        return Err(StatusCodeError(StatusCode::NOT_FOUND).into());
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn create_key(
+    _: Admin,
+    State(state): State<AppState>,
+    AppJson(req): AppJson<CreateKeyReq>,
+) -> Result<impl IntoResponse, ShortenError> {
+    if req.scopes.is_empty() {
+        return Err(StatusCodeError(StatusCode::UNPROCESSABLE_ENTITY).into());
+    }
+    let (id, key) = auth::create(&state.db.db, &req.label, &req.scopes).await?;
+    Ok((StatusCode::CREATED, Json(CreateKeyRes { id, key })))
+}
+
+async fn list_keys(
+    _: Admin,
+    State(state): State<AppState>,
+) -> Result<Json<Vec<KeyRecord>>, ShortenError> {
+    Ok(Json(auth::list(&state.db.db).await?))
+}
+
+/// Imports legacy mappings: 201 with the completed import when there are
+/// few enough rows to run here, 202 with the running one otherwise.
+async fn create_import(
+    _: Admin,
+    State(state): State<AppState>,
+    AppJson(req): AppJson<ImportReq>,
+) -> Result<impl IntoResponse, ShortenError> {
+    let limit = state.config.import_max_rows;
+    if req.rows.len() > limit {
+        return Err(ShortenError::TooManyItems {
+            limit,
+            received: req.rows.len(),
+        });
+    }
+    let import_id = imports::start(&state.db.db, req.rows.len()).await?;
+    let status = if req.rows.len() <= state.config.import_sync_rows {
+        state.db.import(import_id, &req.rows).await?;
+        StatusCode::CREATED
+    } else {
+        let job = Import {
+            import_id,
+            rows: req.rows,
+        };
+        jobs::enqueue(&state.db.db, &job).await?;
+        StatusCode::ACCEPTED
+    };
+    let import = imports::get(&state.db.db, import_id)
+        .await?
+        .ok_or(StatusCodeError(StatusCode::INTERNAL_SERVER_ERROR))?;
+    Ok((status, Json(import)))
+}
+
+async fn list_imports(
+    _: Admin,
+    State(state): State<AppState>,
+) -> Result<Json<Vec<ImportRecord>>, ShortenError> {
+    Ok(Json(imports::list(&state.db.db).await?))
+}
+
+async fn get_import(
+    _: Admin,
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+) -> Result<Json<ImportRecord>, ShortenError> {
+    let import = imports::get(&state.db.db, id)
+        .await?
+        .ok_or(StatusCodeError(StatusCode::NOT_FOUND))?;
+    Ok(Json(import))
+}
+
+/// Every row an import failed on, as JSON or as a CSV to fix the source
+/// data with.
+async fn import_errors(
+    _: Admin,
+    State(state): State<AppState>,
+    format: Format,
+    Path(id): Path<i64>,
+) -> Result<Response, ShortenError> {
+    if imports::get(&state.db.db, id).await?.is_none() {
+        return Err(StatusCodeError(StatusCode::NOT_FOUND).into());
+    }
+    let errors = imports::errors(&state.db.db, id).await?;
+    Ok(format.respond(&format!("import-{}-errors.csv", id), errors))
+}
+
+async fn revoke_key(
+    _: Admin,
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+) -> Result<impl IntoResponse, ShortenError> {
+    if !auth::revoke(&state.db.db, id).await? {
+        return Err(StatusCodeError(StatusCode::NOT_FOUND).into());
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn list_policies(
+    _: Admin,
+    State(state): State<AppState>,
+) -> Result<Json<Vec<Policy>>, ShortenError> {
+    Ok(Json(policies::list(&state.db.db).await?))
+}
+
+/// Adds a redirect policy, in effect here at once: 422 unless it names
+/// either a link or a valid pattern, 404 for a link that doesn't exist.
+async fn get_owner_status(
+    _: Admin,
+    State(state): State<AppState>,
+    Path(owner): Path<String>,
+) -> Json<OwnerStatus> {
+    Json(state.suspensions.status(&owner))
+}
+
+/// Suspends an owner, whose links then answer with
+/// `SUSPENDED_OWNER_STATUS`, or makes them active again. Other instances
+/// follow within `POLICY_POLL_SECS`.
+async fn set_owner_status(
+    State(state): State<AppState>,
+    key: ApiKey,
+    Path(owner): Path<String>,
+    AppJson(status): AppJson<OwnerStatus>,
+) -> Result<Json<OwnerStatus>, ShortenError> {
+    key.require(Scope::Admin)?;
+    let status = state.suspensions.set(&state.db.db, &owner, status).await?;
+    let mut details = serde_json::to_value(&status).unwrap_or_default();
+    details["owner"] = owner.into();
+    audit::record(&state.db.db, &key.owner(), "owner_status_set", details).await?;
+    Ok(Json(status))
+}
+
+async fn create_policy(
+    State(state): State<AppState>,
+    key: ApiKey,
+    AppJson(mut req): AppJson<NewPolicy>,
+) -> Result<impl IntoResponse, ShortenError> {
+    key.require(Scope::Admin)?;
+    match (&req.link_id, &req.pattern) {
+        (Some(slug), None) => {
+            let link = state
+                .db
+                .get_link(slug)
+                .await?
+                .ok_or(StatusCodeError(StatusCode::NOT_FOUND))?;
+            // aliases name the link they lead to
+            req.link_id = Some(link.id);
+        }
+        (None, Some(pattern)) if policies::is_valid_pattern(pattern) => {}
+        _ => return Err(StatusCodeError(StatusCode::UNPROCESSABLE_ENTITY).into()),
+    }
+    let policy = policies::create(&state.db.db, &req).await?;
+    audit::record(
+        &state.db.db,
+        &key.owner(),
+        "policy_created",
+        serde_json::to_value(&policy).unwrap_or_default(),
+    )
+    .await?;
+    state.policies.reload(&state.db.db).await?;
+    Ok((StatusCode::CREATED, Json(policy)))
+}
+
+async fn delete_policy(
+    State(state): State<AppState>,
+    key: ApiKey,
+    Path(id): Path<i64>,
+) -> Result<impl IntoResponse, ShortenError> {
+    key.require(Scope::Admin)?;
+    if !policies::delete(&state.db.db, id).await? {
+        return Err(StatusCodeError(StatusCode::NOT_FOUND).into());
+    }
+    audit::record(
+        &state.db.db,
+        &key.owner(),
+        "policy_deleted",
+        serde_json::json!({ "id": id }),
+    )
+    .await?;
+    state.policies.reload(&state.db.db).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn list_flagged(
+    _: Admin,
+    State(state): State<AppState>,
+) -> Result<Json<Vec<FlaggedLink>>, ShortenError> {
+    Ok(Json(screen::list_flagged(&state.db.db).await?))
+}
+
+async fn get_quota(
+    _: Admin,
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+) -> Result<Json<Quota>, ShortenError> {
+    Ok(Json(quota::get(&state.db.db, id).await?))
+}
+
+async fn set_quota(
+    _: Admin,
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+    AppJson(quota): AppJson<Quota>,
+) -> Result<impl IntoResponse, ShortenError> {
+    quota::set(&state.db.db, id, quota).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// The caller's own creation counts for the current UTC day and month.
+async fn usage(State(state): State<AppState>, key: ApiKey) -> Result<Json<Usage>, ShortenError> {
+    // the legacy env key isn't metered
+    let id = key.id.ok_or(StatusCodeError(StatusCode::NOT_FOUND))?;
+    Ok(Json(quota::usage(&state.db.db, id).await?))
+}
+
+/// Live links only: disabled and expired ones aren't counted.
+async fn count(_: Admin, State(state): State<AppState>) -> Result<Json<CountRes>, ShortenError> {
+    let total = state.db.count_live().await?;
+    Ok(Json(CountRes { total }))
+}
+
+async fn get_maintenance(_: Admin, State(state): State<AppState>) -> Json<maintenance::Status> {
+    Json(state.maintenance.status())
+}
+
+/// Turns maintenance mode on or off for every instance sharing the
+/// database. Others follow within `MAINTENANCE_POLL_SECS`.
+async fn set_maintenance(
+    _: Admin,
+    State(state): State<AppState>,
+    AppJson(status): AppJson<maintenance::Status>,
+) -> Result<Json<maintenance::Status>, ShortenError> {
+    state.maintenance.set(&state.db.db, status).await?;
+    Ok(Json(state.maintenance.status()))
+}
+
+/// A backup of the links, their tags and aliases and the redirect policies,
+/// streamed as a tar archive while it's read, see [`backup`].
+async fn backup_archive(_: Admin, State(state): State<AppState>) -> Response {
+    let (tx, rx) = tokio::sync::mpsc::channel(4);
+    tokio::spawn(backup::dump(state.db.db.clone(), tx));
+    let filename = format!("shortener-{}.tar", Utc::now().format("%Y%m%dT%H%M%SZ"));
+    (
+        [
+            (CONTENT_TYPE, "application/x-tar".to_string()),
+            (
+                CONTENT_DISPOSITION,
+                format!("attachment; filename=\"{}\"", filename),
+            ),
+        ],
+        Body::from_stream(ReceiverStream::new(rx)),
+    )
+        .into_response()
+}
+
+/// Restores a backup: 409 if there's data already unless `force`, 413 past
+/// `RESTORE_MAX_BYTES`, 422 if the archive doesn't match its manifest.
+/// Otherwise 202 with the job the restore runs as, whose payload counts
+/// the files and rows restored.
+async fn restore_archive(
+    _: Admin,
+    State(state): State<AppState>,
+    Query(query): Query<RestoreQuery>,
+    body: Body,
+) -> Result<impl IntoResponse, ShortenError> {
+    if !query.force && backup::has_data(&state.db.db).await? {
+        return Err(StatusCodeError(StatusCode::CONFLICT).into());
+    }
+    let path = std::env::temp_dir().join(format!("shortener-restore-{}.tar", nanoid::nanoid!()));
+    backup::spool(body, &path, state.config.restore_max_bytes).await?;
+    let file = std::fs::File::open(&path)?;
+    let verified =
+        tokio::task::spawn_blocking(move || backup::verify(std::io::BufReader::new(file)))
+            .await
+            .map_err(std::io::Error::other)?;
+    let manifest = match verified {
+        Ok(manifest) => manifest,
+        Err(e) => {
+            let _ = tokio::fs::remove_file(&path).await;
+            return Err(ShortenError::InvalidArchive(e));
+        }
+    };
+    let job = backup::start(&state.db.db, &manifest, state.config.job_max_attempts).await?;
+    let res = RestoreRes {
+        job_id: job,
+        files: manifest.files.len(),
+        rows: manifest.rows().values().sum(),
+    };
+    tokio::spawn(async move {
+        let db = &state.db.db;
+        let mut result = backup::restore(db, job, &path, &manifest, query.force).await;
+        if result.is_ok() {
+            // what's read from the tables changed under them
+            result = async {
+                state.db.rebuild_slug_filter().await?;
+                state.policies.reload(db).await
+            }
+            .await;
+        }
+        backup::finish(db, job, result).await;
+    });
+    Ok((StatusCode::ACCEPTED, Json(res)))
+}
+
+async fn list_jobs(
+    _: Admin,
+    State(state): State<AppState>,
+    Query(query): Query<JobsQuery>,
+) -> Result<Json<Vec<JobRecord>>, ShortenError> {
+    Ok(Json(jobs::list(&state.db.db, query.state).await?))
+}
+
+impl PgState {
+    /// Connects and brings the schema up to date.
+    pub async fn try_new(config: &Config) -> Result<Self, ShortenError> {
+        let mut ids = IdGenerator::new(
+            config.id_strategy,
+            &config.id_alphabet,
+            config.id_length,
+            config.id_words,
+            config.id_separator.clone(),
+        )
+        .with_case(config.id_case);
+        if let Some(seed) = config.id_seed {
+            warn!("ID_SEED is set, new ids are predictable");
+            ids = ids.seeded(seed);
+        }
+        let db = PgPoolOptions::new()
+            .min_connections(config.db_min_connections)
+            .max_connections(config.db_max_connections)
+            // so queries of abandoned requests stop soon after their
+            // connection is closed, on servers that can tell
+            .after_connect(|conn, _| {
+                Box::pin(async move {
+                    sqlx::query(
+                        "SELECT set_config('client_connection_check_interval', '1000', false)
+                         WHERE current_setting('server_version_num')::INT >= 140000",
+                    )
+                    .execute(conn)
+                    .await?;
+                    Ok(())
+                })
+            })
+            .connect(&config.db_url)
+            .await?;
+        if config.skip_schema_init {
+            info!("Leaving the schema to migrations, SKIP_SCHEMA_INIT is set");
+        } else {
+            Self::init_schema(&db, config, &ids).await?;
+        }
+        if config.skip_schema_check {
+            warn!("Skipping schema check");
+        } else if config.skip_schema_init {
+            schema::verify_migrated(&db).await?;
+        } else {
+            schema::verify(&db).await?;
+        }
+        let fold = match config.id_case {
+            IdCase::Lower => Some("lower"),
+            IdCase::Mixed => None,
+            IdCase::Upper => Some("upper"),
+        };
+        if let Some(fold) = fold {
+            let query = format!("SELECT COUNT(*) FROM slugs WHERE slug <> {}(slug)", fold);
+            let unreachable: i64 = sqlx::query_scalar(&query).fetch_one(&db).await?;
+            if unreachable > 0 {
+                warn!(
+                    "{} ids and aliases aren't {}case, so with ID_CASE={} no request reaches them",
+                    unreachable, fold, fold
+                );
+            }
+        }
+        // connected on first use so a replica that's down doesn't block
+        // startup, and given up on quickly so reads fall back to the primary
+        // without stalling
+        let replicas = config
+            .replica_urls
+            .iter()
+            .map(|url| {
+                PgPoolOptions::new()
+                    .acquire_timeout(Duration::from_secs(1))
+                    .connect_lazy(url)
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Self {
+            db,
+            replicas: Replicas::new(replicas),
+            ids,
+            signer: Signer::new(
+                config.signing_key.as_deref(),
+                config.previous_signing_key.as_deref(),
+            ),
+            max_generation_attempts: config.max_generation_attempts,
+            slow_query: config.slow_query,
+            query_timeout: config.db_query_timeout,
+            breaker: (config.db_breaker_error_percent > 0.0).then(|| {
+                Breaker::new(
+                    config.db_breaker_error_percent / 100.0,
+                    config.db_breaker_min_calls,
+                    config.db_breaker_window,
+                    config.db_breaker_cooldown,
+                )
+            }),
+            slugs: (!config.slug_filter_refresh.is_zero()).then(Default::default),
+            compress_urls_over: config.compress_urls_over,
+        })
+    }
+    /// Creates the tables and indexes that don't exist yet and adds the
+    /// columns newer versions need, repairing the ids of rows older
+    /// versions left behind with `repair` and migrating the legacy `urls`
+    /// id with `fix_schema`.
+    async fn init_schema(
+        db: &PgPool,
+        config: &Config,
+        ids: &IdGenerator,
+    ) -> Result<(), ShortenError> {
+        sqlx::query("CREATE TABLE IF NOT EXISTS urls (id TEXT PRIMARY KEY, url TEXT NOT NULL)")
+            .execute(db)
+            .await?;
+        sqlx::query(
+            "ALTER TABLE urls ADD COLUMN IF NOT EXISTS enabled BOOLEAN NOT NULL DEFAULT true,
+             ADD COLUMN IF NOT EXISTS expires_at TIMESTAMPTZ,
+             ADD COLUMN IF NOT EXISTS forward_query BOOLEAN NOT NULL DEFAULT true,
+             ADD COLUMN IF NOT EXISTS clicks BIGINT NOT NULL DEFAULT 0,
+             ADD COLUMN IF NOT EXISTS deduped BOOLEAN NOT NULL DEFAULT true,
+             ADD COLUMN IF NOT EXISTS created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+             ADD COLUMN IF NOT EXISTS notes TEXT,
+             ADD COLUMN IF NOT EXISTS owner TEXT,
+             ADD COLUMN IF NOT EXISTS description TEXT,
+             ADD COLUMN IF NOT EXISTS platform_targets JSONB,
+             ADD COLUMN IF NOT EXISTS url_compressed BOOLEAN NOT NULL DEFAULT false,
+             ADD COLUMN IF NOT EXISTS url_deflated BYTEA,
+             ADD COLUMN IF NOT EXISTS management_token_hash TEXT,
+             ADD COLUMN IF NOT EXISTS redirect_status SMALLINT,
+             ADD COLUMN IF NOT EXISTS max_uses INTEGER,
+             ADD COLUMN IF NOT EXISTS uses INTEGER NOT NULL DEFAULT 0,
+             ADD COLUMN IF NOT EXISTS destinations INTEGER,
+             ADD COLUMN IF NOT EXISTS click_cap BIGINT,
+             ADD COLUMN IF NOT EXISTS geo_targets JSONB",
+        )
+        .execute(db)
+        .await?;
+        sqlx::query("CREATE INDEX IF NOT EXISTS urls_owner ON urls (owner)")
+            .execute(db)
+            .await?;
+        // urls used to be unique outright; now only links that opted into
+        // dedupe are, so opted-out ones may repeat a url. Deduped links were
+        // shared by every owner, now each owner has its own and anonymous
+        // ones share theirs.
+        sqlx::query("DROP INDEX IF EXISTS urls_url_deduped")
+            .execute(db)
+            .await?;
+        sqlx::query(
+            "CREATE UNIQUE INDEX IF NOT EXISTS urls_anonymous_url_deduped ON urls (url)
+             WHERE deduped AND owner IS NULL",
+        )
+        .execute(db)
+        .await?;
+        sqlx::query(
+            "CREATE UNIQUE INDEX IF NOT EXISTS urls_owner_url_deduped ON urls (owner, url)
+             WHERE deduped AND owner IS NOT NULL",
+        )
+        .execute(db)
+        .await?;
+        sqlx::query("ALTER TABLE urls DROP CONSTRAINT IF EXISTS urls_url_key")
+            .execute(db)
+            .await?;
+        aliases::init(db).await?;
+        audit::init(db).await?;
+        auth::init(db).await?;
+        claims::init(db).await?;
+        geo::init(db).await?;
+        history::init(db).await?;
+        imports::init(db).await?;
+        jobs::init(db).await?;
+        maintenance::init(db).await?;
+        merge::init(db).await?;
+        multiplex::init(db).await?;
+        owners::init(db).await?;
+        policies::init(db).await?;
+        shadow::init(db).await?;
+        quota::init(db).await?;
+        repair::init(db).await?;
+        reports::init(db).await?;
+        screen::init(db).await?;
+        tags::init(db).await?;
+        snapshots::init(db).await?;
+        uniques::init(db).await?;
+        if let Some(mode) = config.repair {
+            let alphabet =
+                (config.id_strategy == IdStrategy::Nanoid).then_some(config.id_alphabet.as_str());
+            repair::run(db, mode, ids, alphabet).await?;
+        }
+        // tables created before the id became a primary key
+        if schema::is_legacy(db).await? {
+            if config.fix_schema {
+                schema::fix_legacy(db).await?;
+            } else {
+                warn!(
+                    "urls.id is the legacy VARCHAR(6) without a primary key, \
+                     run with --fix-schema to migrate it"
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks out `connections` connections at once, opening any the pool
+    /// doesn't have yet, and runs `SELECT 1` on each before returning them
+    /// idle. Returns how long that took.
+    pub async fn warm_up(&self, connections: u32) -> Result<Duration, ShortenError> {
+        let started = Instant::now();
+        let acquired =
+            futures_util::future::join_all((0..connections).map(|_| self.db.acquire())).await;
+        for conn in acquired {
+            let mut conn = conn?;
+            sqlx::query("SELECT 1").execute(&mut *conn).await?;
+            // dropping would return it in the background, maybe after we're
+            // done
+            conn.return_to_pool().await;
+        }
+        Ok(started.elapsed())
+    }
+    /// What `urls.url` holds for `url`, see [`compress::key`].
+    fn url_key(&self, url: &str) -> String {
+        compress::key(url, self.compress_urls_over)
+    }
+    /// Reads the `links` most clicked live links along with their slugs,
+    /// so what redirects look up first is in the database's cache before
+    /// they do. Returns how many links there were.
+    pub async fn warm_links(&self, links: u32) -> Result<usize, ShortenError> {
+        let rows: Vec<(String, String, String)> = sqlx::query_as(
+            "SELECT u.id, u.url, s.slug FROM (
+                SELECT id, url, clicks FROM urls
+                WHERE enabled AND (expires_at IS NULL OR expires_at > now())
+                ORDER BY clicks DESC LIMIT $1
+             ) u JOIN slugs s ON s.link_id = u.id",
+        )
+        .bind(i64::from(links))
+        .fetch_all(&self.db)
+        .await?;
+        let ids: HashSet<&str> = rows.iter().map(|(id, _, _)| id.as_str()).collect();
+        Ok(ids.len())
+    }
+    /// Writes the metrics snapshot of the interval that ended last, `None`
+    /// if another instance is taking one.
+    pub async fn snapshot_metrics(
+        &self,
+        interval: Duration,
+    ) -> Result<Option<Snapshot>, ShortenError> {
+        snapshots::take(&self.db, interval).await
+    }
+    /// Rebuilds the filter of existing slugs from the database, if there is
+    /// one. Returns how many slugs it holds.
+    pub async fn rebuild_slug_filter(&self) -> Result<usize, ShortenError> {
+        let Some(filter) = &self.slugs else {
+            return Ok(0);
+        };
+        let read = sqlx::query_scalar("SELECT slug FROM slugs").fetch_all(&self.db);
+        Ok(filter.rebuild(read).await?)
+    }
+    /// Runs an import, marking it failed if it fails.
+    async fn import(&self, import_id: i64, rows: &[ImportRow]) -> Result<(), ShortenError> {
+        let folded: Vec<ImportRow>;
+        let rows = match self.ids.case() {
+            IdCase::Mixed => rows,
+            _ => {
+                folded = rows
+                    .iter()
+                    .map(|row| ImportRow {
+                        id: self.normalize(&row.id),
+                        url: row.url.clone(),
+                    })
+                    .collect();
+                &folded
+            }
+        };
+        match imports::run(&self.db, import_id, rows, self.compress_urls_over).await {
+            Ok(created) => {
+                for id in &created {
+                    self.added_slug(id);
+                }
+                info!(
+                    "Import {} created {} of {} links",
+                    import_id,
+                    created.len(),
+                    rows.len()
+                );
+                Ok(())
+            }
+            Err(e) => {
+                imports::fail(&self.db, import_id, &e.to_string()).await?;
+                Err(e)
+            }
+        }
+    }
+    /// Lets the slug filter know of a slug just added.
+    fn added_slug(&self, slug: &str) {
+        if let Some(filter) = &self.slugs {
+            filter.insert(slug);
+        }
+    }
+    /// Replaces the id generator picked from the config.
+    pub fn with_id_generator(mut self, ids: IdGenerator) -> Self {
+        self.ids = ids;
+        self
+    }
+    /// Replaces the `SLOW_QUERY_MS` threshold.
+    pub fn with_slow_query(mut self, threshold: Duration) -> Self {
+        self.slow_query = threshold;
+        self
+    }
+    /// `slug` in the case of `ID_CASE`, as typed when it's mixed. Signed
+    /// slugs are kept as they are, their signatures being mixed-case.
+    pub fn normalize(&self, slug: &str) -> String {
+        match self.ids.case() {
+            IdCase::Mixed => slug.to_string(),
+            _ if slug.contains(signing::SEPARATOR) => slug.to_string(),
+            case => case.apply(slug),
+        }
+    }
+    /// See [`IdGenerator::is_plausible`]. A signed slug is only plausible
+    /// with a valid signature.
+    pub fn is_plausible(&self, slug: &str) -> bool {
+        if slug.contains(signing::SEPARATOR) {
+            self.signer.verify(slug)
+        } else {
+            self.ids.is_plausible(slug)
+        }
+    }
+    /// Whether links can be signed, which takes `LINK_SIGNING_KEY`.
+    pub fn can_sign(&self) -> bool {
+        self.signer.can_sign()
+    }
+    /// A fresh candidate id, signed if `signed`. `None` for one that can't
+    /// be used, which still counts as an attempt.
+    fn candidate(&self, signed: bool) -> Option<String> {
+        let id = self.ids.generate();
+        if RESERVED_IDS.contains(&id.as_str()) || id.contains(signing::SEPARATOR) {
+            return None;
+        }
+        if signed {
+            self.signer.sign(&id)
+        } else {
+            Some(id)
+        }
+    }
+    pub fn pool(&self) -> &PgPool {
+        &self.db
+    }
+    /// Runs `query` on the next replica, or on the primary if there are no
+    /// replicas or the query failed there.
+    async fn read<'a, T, Fut>(
+        &'a self,
+        query: impl Fn(&'a PgPool) -> Fut,
+    ) -> Result<T, ShortenError>
+    where
+        Fut: Future<Output = Result<T, ShortenError>>,
+    {
+        if let Some(replica) = self.replicas.next() {
+            match query(replica.pool).await {
+                Err(ShortenError::SqlError(e)) => {
+                    if e.is_transient() {
+                        self.replicas.failed(replica);
+                    }
+                    warn!("Replica read failed, retrying on the primary: {}", e)
+                }
+                ret => return ret,
+            }
+        }
+        query(&self.db).await
+    }
+    /// Awaits `query`, logging a warning if it takes longer than the slow
+    /// query threshold.
+    async fn timed<T>(&self, name: &'static str, query: impl Future<Output = T>) -> T {
+        let started = Instant::now();
+        let ret = query.await;
+        let elapsed = started.elapsed();
+        if elapsed > self.slow_query {
+            warn!(
+                query = name,
+                elapsed_ms = elapsed.as_millis() as u64,
+                "Slow query"
+            );
+        }
+        ret
+    }
+    /// Runs `call` unless the breaker is open, reporting to it whether the
+    /// database failed it.
+    async fn protected<T>(
+        &self,
+        call: impl Future<Output = Result<T, ShortenError>>,
+    ) -> Result<T, ShortenError> {
+        let Some(breaker) = &self.breaker else {
+            return call.await;
+        };
+        let permit = breaker.allow().map_err(|wait| ShortenError::Unavailable {
+            reason: "database unavailable".into(),
+            retry_after: wait.as_secs_f64().ceil().max(1.0) as u64,
+        })?;
+        let ret = call.await;
+        let failed = ret
+            .as_ref()
+            .is_err_and(|e| matches!(e, ShortenError::Unavailable { .. }) || e.is_transient());
+        permit.record(!failed);
+        ret
+    }
+    async fn shorten(&self, link: NewLink<'_>) -> Result<Shortened, ShortenError> {
+        self.protected(self.try_shorten(link)).await
+    }
+    async fn try_shorten(&self, link: NewLink<'_>) -> Result<Shortened, ShortenError> {
+        let limit = self.query_timeout;
+        if let Some(alias) = link.alias {
+            let attempts = retry::transient(|| self.insert(&link, alias));
+            return match deadline::bounded(limit, attempts).await? {
+                Err(e) if is_id_taken(&e) => Err(ShortenError::AliasTaken),
+                ret => Ok(ret?),
+            };
+        }
+        // a generated id that's taken is retried with another one
+        for _ in 0..self.max_generation_attempts {
+            let Some(id) = self.candidate(link.signed) else {
+                continue;
+            };
+            let attempts = retry::transient(|| self.insert(&link, &id));
+            match deadline::bounded(limit, attempts).await? {
+                Err(e) if is_id_taken(&e) => {}
+                ret => return Ok(ret?),
+            }
+        }
+        Err(ShortenError::IdSpaceExhausted)
+    }
+    /// Inserts the link as `id` along with the slug it answers to, in one
+    /// transaction so a clash on either leaves nothing behind.
+    async fn insert(&self, link: &NewLink<'_>, id: &str) -> Result<Shortened, sqlx::Error> {
+        // re-shortening a url whose link has expired revives it with the new
+        // expiry instead of handing back a dead id. A row the upsert inserted
+        // has no deleting transaction yet, so `xmax = 0` tells the two apart.
+        let deduped = link.dedupe
+            && link.alias.is_none()
+            && !link.signed
+            && link.platform_targets.is_none()
+            && link.geo_targets.is_none()
+            && link.redirect_status.is_none()
+            && link.max_uses.is_none()
+            && link.urls.is_empty();
+        let query = if deduped && link.owner.is_some() {
+            "INSERT INTO urls
+                (id, url, expires_at, forward_query, notes, owner, description, platform_targets,
+                 url_deflated, url_compressed, management_token_hash, redirect_status, max_uses,
+                 click_cap)
+             VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $9 IS NOT NULL, $10, $11, $12, $15)
+             ON CONFLICT (owner, url) WHERE deduped AND owner IS NOT NULL
+             DO UPDATE SET url = EXCLUDED.url,
+             expires_at = CASE WHEN urls.expires_at <= now() THEN EXCLUDED.expires_at
+                          ELSE urls.expires_at END,
+             notes = COALESCE(urls.notes, EXCLUDED.notes),
+             description = COALESCE(urls.description, EXCLUDED.description),
+             click_cap = COALESCE(urls.click_cap, EXCLUDED.click_cap)
+             RETURNING id, created_at, xmax = 0 AS created, description, expires_at"
+        } else if deduped {
+            "INSERT INTO urls
+                (id, url, expires_at, forward_query, notes, owner, description, platform_targets,
+                 url_deflated, url_compressed, management_token_hash, redirect_status, max_uses,
+                 click_cap)
+             VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $9 IS NOT NULL, $10, $11, $12, $15)
+             ON CONFLICT (url) WHERE deduped AND owner IS NULL DO UPDATE SET url = EXCLUDED.url,
+             expires_at = CASE WHEN urls.expires_at <= now() THEN EXCLUDED.expires_at
+                          ELSE urls.expires_at END,
+             notes = COALESCE(urls.notes, EXCLUDED.notes),
+             description = COALESCE(urls.description, EXCLUDED.description),
+             click_cap = COALESCE(urls.click_cap, EXCLUDED.click_cap)
+             RETURNING id, created_at, xmax = 0 AS created, description, expires_at"
+        } else {
+            "INSERT INTO urls (id, url, expires_at, forward_query, notes, owner, description,
+                               platform_targets, url_deflated, url_compressed,
+                               management_token_hash, redirect_status, max_uses, deduped,
+                               destinations, geo_targets, click_cap)
+             VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $9 IS NOT NULL, $10, $11, $12, false,
+                     $13, $14, $15)
+             RETURNING id, created_at, true AS created, description, expires_at"
+        };
+        let stored = compress::store(link.url, self.compress_urls_over);
+        let mut conn = Guarded::acquire(&self.db, self.query_timeout).await?;
+        let mut tx = conn.begin().await?;
+        let insert = sqlx::query_as(query)
+            .bind(id)
+            .bind(&stored.url)
+            .bind(link.expires_at)
+            .bind(link.forward_query)
+            .bind(link.notes)
+            .bind(&link.owner)
+            .bind(link.description)
+            .bind(link.platform_targets.map(sqlx::types::Json))
+            .bind(&stored.deflated)
+            .bind(link.management_token_hash)
+            .bind(link.redirect_status.map(|s| s as i16))
+            .bind(link.max_uses.map(|n| n as i32))
+            .bind((!link.urls.is_empty()).then(|| link.urls.len() as i32 + 1))
+            .bind(link.geo_targets.map(sqlx::types::Json))
+            .bind(link.click_cap)
+            .fetch_one(&mut *tx);
+        let ret: Shortened = self.timed("shorten", insert).await?;
+        if ret.created {
+            sqlx::query("INSERT INTO slugs (slug, link_id) VALUES ($1, $1)")
+                .bind(&ret.id)
+                .execute(&mut *tx)
+                .await?;
+            if !link.urls.is_empty() {
+                multiplex::add(&mut tx, &ret.id, link.urls).await?;
+            }
+        }
+        tx.commit().await?;
+        conn.done();
+        if ret.created {
+            self.added_slug(&ret.id);
+        }
+        Ok(ret)
+    }
+    /// Moves the link to a freshly generated id, signed again if it was,
+    /// `None` if it doesn't exist.
+    async fn rotate(&self, id: &str) -> Result<Option<Shortened>, ShortenError> {
+        let signed = id.contains(signing::SEPARATOR);
+        for _ in 0..self.max_generation_attempts {
+            let Some(new_id) = self.candidate(signed) else {
+                continue;
+            };
+            match self.rename(id, &new_id).await {
+                Err(e) if is_id_taken(&e) => {}
+                ret => {
+                    let ret = ret?;
+                    if ret.is_some() {
+                        self.added_slug(&new_id);
+                    }
+                    return Ok(ret);
+                }
+            }
+        }
+        Err(ShortenError::IdSpaceExhausted)
+    }
+    async fn rename(&self, id: &str, new_id: &str) -> Result<Option<Shortened>, sqlx::Error> {
+        let mut tx = self.db.begin().await?;
+        let renamed = sqlx::query_as(
+            "UPDATE urls SET id = $2 WHERE id = $1
+             RETURNING id, created_at, false AS created, description, expires_at",
+        )
+        .bind(id)
+        .bind(new_id)
+        .fetch_optional(&mut *tx)
+        .await?;
+        if renamed.is_none() {
+            return Ok(None);
+        }
+        // the old id goes with its slug, the aliases stay
+        sqlx::query("UPDATE slugs SET slug = $2 WHERE slug = $1")
+            .bind(id)
+            .bind(new_id)
+            .execute(&mut *tx)
+            .await?;
+        for table in LINK_TABLES {
+            sqlx::query(&format!(
+                "UPDATE {} SET link_id = $2 WHERE link_id = $1",
+                table
+            ))
+            .bind(id)
+            .bind(new_id)
+            .execute(&mut *tx)
+            .await?;
+        }
+        tx.commit().await?;
+        Ok(renamed)
+    }
+    /// Looks a link up by its id or any of its aliases.
+    async fn get_link(&self, slug: &str) -> Result<Option<Records>, ShortenError> {
+        if self.filtered_out(slug) {
+            return Ok(None);
+        }
+        self.protected(self.select_link(&self.db, slug)).await
+    }
+    /// Whether the slug filter knows `slug` can't exist, without a query.
+    fn filtered_out(&self, slug: &str) -> bool {
+        let out = self.slugs.as_ref().is_some_and(|f| !f.may_exist(slug));
+        if out {
+            metrics::counter!("slug_filter_misses_total").increment(1);
+        }
+        out
+    }
+    /// Like [`get_link`](Self::get_link) for redirects, reading from the
+    /// next replica when there are any. A link the replica fails or doesn't
+    /// have, such as one shortened within the replication lag, is looked up
+    /// on the primary, while changes to a link apply once replicas catch up.
+    async fn redirect_link(&self, slug: &str) -> Result<Option<Records>, ShortenError> {
+        if self.filtered_out(slug) {
+            return Ok(None);
+        }
+        if let Some(replica) = self.replicas.next() {
+            match self.select_link(replica.pool, slug).await {
+                Ok(Some(link)) => return Ok(Some(link)),
+                Ok(None) => {}
+                Err(e) => {
+                    if e.is_transient() {
+                        self.replicas.failed(replica);
+                    }
+                    warn!("Replica lookup failed, retrying on the primary: {}", e)
+                }
+            }
+        }
+        self.protected(self.select_link(&self.db, slug)).await
+    }
+    async fn select_link(&self, db: &PgPool, slug: &str) -> Result<Option<Records>, ShortenError> {
+        let mut conn = Guarded::acquire(db, self.query_timeout).await?;
+        let select = sqlx::query_as(
+            "SELECT u.id, u.url, u.enabled, u.expires_at, u.forward_query, u.owner,
+                    u.platform_targets, u.url_deflated, u.redirect_status, u.max_uses, u.uses,
+                    u.destinations, u.geo_targets
+             FROM slugs s JOIN urls u ON u.id = s.link_id WHERE s.slug = $1",
+        )
+        .bind(slug)
+        .fetch_optional(&mut **conn);
+        let ret = deadline::bounded(self.query_timeout, self.timed("get_link", select)).await?;
+        conn.done();
+        Ok(ret?.map(Records::inflated).transpose()?)
+    }
+    /// Like [`get_link`](Self::get_link), with the public details too.
+    async fn get_info(&self, slug: &str) -> Result<Option<Records>, ShortenError> {
+        self.protected(async {
+            let mut conn = Guarded::acquire(&self.db, self.query_timeout).await?;
+            let info = sqlx::query_as(
+                "SELECT u.id, u.url, u.enabled, u.expires_at, u.description, u.created_at,
+                        u.owner, u.url_deflated, u.max_uses, u.uses
+                 FROM slugs s JOIN urls u ON u.id = s.link_id WHERE s.slug = $1",
+            )
+            .bind(slug)
+            .fetch_optional(&mut **conn);
+            let ret = deadline::bounded(self.query_timeout, info).await?;
+            conn.done();
+            Ok(ret?.map(Records::inflated).transpose()?)
+        })
+        .await
+    }
+    /// Returns whether the link exists.
+    async fn set_enabled(&self, id: &str, enabled: bool) -> Result<bool, ShortenError> {
+        let ret = sqlx::query("UPDATE urls SET enabled = $2 WHERE id = $1")
+            .bind(id)
+            .bind(enabled)
+            .execute(&self.db)
+            .await?;
+        Ok(ret.rows_affected() > 0)
+    }
+    /// Applies `update` in one transaction, so it's all or nothing. Returns
+    /// whether the link exists.
+    async fn update_link(
+        &self,
+        id: &str,
+        update: &LinkUpdate<'_>,
+        compress_over: usize,
+        actor: &str,
+    ) -> Result<bool, ShortenError> {
+        let mut tx = self.db.begin().await?;
+        if let Some(url) = update.url {
+            if history::set_url(&mut tx, id, url, compress_over, actor)
+                .await?
+                .is_none()
+            {
+                return Ok(false);
+            }
+        }
+        let ret = sqlx::query(
+            "UPDATE urls SET enabled = COALESCE($2, enabled),
+                forward_query = COALESCE($3, forward_query),
+                redirect_status = COALESCE($4, redirect_status),
+                notes = CASE WHEN $5 THEN $6 ELSE notes END,
+                click_cap = CASE WHEN $7 THEN $8 ELSE click_cap END
+             WHERE id = $1",
+        )
+        .bind(id)
+        .bind(update.enabled)
+        .bind(update.forward_query)
+        .bind(update.redirect_status.map(|s| s as i16))
+        .bind(update.notes.is_some())
+        .bind(update.notes.flatten())
+        .bind(update.click_cap.is_some())
+        .bind(update.click_cap.flatten())
+        .execute(&mut *tx)
+        .await?;
+        if ret.rows_affected() == 0 {
+            return Ok(false);
+        }
+        tx.commit().await?;
+        Ok(true)
+    }
+    /// Counts a redirect of a link with `max_uses`, `false` once they are
+    /// used up. Concurrent redirects queue on the row, so no more than
+    /// `max_uses` get through.
+    async fn use_once(&self, id: &str, webhooks: bool) -> Result<bool, ShortenError> {
+        let ret: Option<(i64, Option<i64>)> = sqlx::query_as(
+            "UPDATE urls SET uses = uses + 1, clicks = clicks + 1
+             WHERE id = $1 AND (max_uses IS NULL OR uses < max_uses)
+             RETURNING clicks, click_cap",
+        )
+        .bind(id)
+        .fetch_optional(&self.db)
+        .await?;
+        let Some((clicks, cap)) = ret else {
+            return Ok(false);
+        };
+        clicks::reached_cap(&self.db, id, cap, clicks - 1, clicks, webhooks).await?;
+        Ok(true)
+    }
+    /// Returns whether the link exists.
+    async fn set_expires_at(
+        &self,
+        id: &str,
+        expires_at: Option<DateTime<Utc>>,
+    ) -> Result<bool, ShortenError> {
+        let ret = sqlx::query("UPDATE urls SET expires_at = $2 WHERE id = $1")
+            .bind(id)
+            .bind(expires_at)
+            .execute(&self.db)
+            .await?;
+        Ok(ret.rows_affected() > 0)
+    }
+    /// Flushed click count and notes, `None` if the link doesn't exist.
+    async fn stats(&self, id: &str) -> Result<Option<(i64, Option<String>)>, ShortenError> {
+        self.read(|db| async move {
+            let stats = sqlx::query_as("SELECT clicks, notes FROM urls WHERE id = $1")
+                .bind(id)
+                .fetch_optional(db)
+                .await?;
+            Ok(stats)
+        })
+        .await
+    }
+    async fn count_live(&self) -> Result<i64, ShortenError> {
+        self.read(|db| async move {
+            let (total,): (i64,) = sqlx::query_as(
+                "SELECT COUNT(*) FROM urls
+                 WHERE enabled AND (expires_at IS NULL OR expires_at > now())",
+            )
+            .fetch_one(db)
+            .await?;
+            Ok(total)
+        })
+        .await
+    }
+    /// Returns whether the link existed.
+    async fn delete(&self, id: &str) -> Result<bool, ShortenError> {
+        let deleted = self.delete_links(Doomed::Link(id), false).await?;
+        Ok(!deleted.is_empty())
+    }
+    /// Deletes the `doomed` links, see [`delete_in`]. With `dry_run` the
+    /// transaction is rolled back instead, so a preview runs the very statements of the
+    /// deletion.
+    async fn delete_links(
+        &self,
+        doomed: Doomed<'_>,
+        dry_run: bool,
+    ) -> Result<Vec<String>, ShortenError> {
+        let mut tx = self.db.begin().await?;
+        let mut ids = delete_in(&mut tx, doomed).await?;
+        if dry_run {
+            tx.rollback().await?;
+        } else {
+            tx.commit().await?;
+        }
+        ids.sort();
+        Ok(ids)
+    }
+    /// Applies `action` to the links `filter` matches, [`bulk::BATCH`] of
+    /// them per transaction, returning the ids it changed. With `dry_run`
+    /// each transaction is rolled back instead, like a deletion preview.
+    async fn bulk(
+        &self,
+        filter: &bulk::Filter,
+        action: &bulk::Action,
+        dry_run: bool,
+    ) -> Result<Vec<String>, ShortenError> {
+        let ids = bulk::matching(&self.db, filter).await?;
+        let mut changed = Vec::new();
+        for batch in ids.chunks(bulk::BATCH) {
+            let mut tx = self.db.begin().await?;
+            changed.extend(match action {
+                bulk::Action::Delete => delete_in(&mut tx, Doomed::Links(batch)).await?,
+                bulk::Action::Disable => bulk::disable(&mut tx, batch).await?,
+                bulk::Action::AddTag { tag } => bulk::add_tag(&mut tx, batch, tag).await?,
+            });
+            if dry_run {
+                tx.rollback().await?;
+            } else {
+                tx.commit().await?;
+            }
+        }
+        changed.sort();
+        Ok(changed)
+    }
+    /// Maps an alias to its link's id. Anything else comes back unchanged so
+    /// lookups by it simply find nothing.
+    async fn resolve(&self, slug: &str) -> Result<String, ShortenError> {
+        Ok(aliases::resolve(&self.db, slug)
+            .await?
+            .unwrap_or_else(|| slug.to_string()))
+    }
+}
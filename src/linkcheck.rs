@@ -0,0 +1,106 @@
+//! Asking destinations whether they still answer, to find dead links.
+
+use std::time::{Duration, Instant};
+
+use futures_util::{stream, StreamExt};
+use serde::Serialize;
+use sqlx::PgPool;
+use url::Url;
+
+use crate::{compress, fetch::Fetcher, ShortenError};
+
+/// Most links one check takes.
+pub const MAX_LINKS: usize = 500;
+
+/// What a link's destination answered.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub struct Checked {
+    pub id: String,
+    pub url: String,
+    /// `None` when the request failed, `error` saying why.
+    pub status: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    pub elapsed_ms: u64,
+}
+
+/// Sends a HEAD to the destination of each of `links`, `(id, url)` pairs,
+/// `concurrency` at a time and each given `timeout` to answer. Answers in
+/// the order of `links`. Redirects aren't followed, their status being the
+/// answer, and every request goes through `fetcher`, so destinations on
+/// non-public addresses are errors rather than reached.
+pub async fn check(
+    fetcher: &Fetcher,
+    links: Vec<(String, String)>,
+    concurrency: usize,
+    timeout: Duration,
+) -> Vec<Checked> {
+    stream::iter(links)
+        .map(|(id, url)| one(fetcher, id, url, timeout))
+        .buffered(concurrency.max(1))
+        .collect()
+        .await
+}
+
+async fn one(fetcher: &Fetcher, id: String, url: String, timeout: Duration) -> Checked {
+    let started = Instant::now();
+    let answer = match Url::parse(&url) {
+        Ok(parsed) => match tokio::time::timeout(timeout, fetcher.head(&parsed)).await {
+            Ok(Ok(res)) => Ok(res.status().as_u16()),
+            Ok(Err(e)) => Err(e.to_string()),
+            Err(_) => Err(format!("no answer within {:?}", timeout)),
+        },
+        Err(e) => Err(format!("invalid url: {}", e)),
+    };
+    let (status, error) = match answer {
+        Ok(status) => (Some(status), None),
+        Err(e) => (None, Some(e)),
+    };
+    Checked {
+        id,
+        url,
+        status,
+        error,
+        elapsed_ms: started.elapsed().as_millis() as u64,
+    }
+}
+
+/// The `(id, url)` of the links of `ids` there are, by id, or of every
+/// enabled link without `ids`. More than [`MAX_LINKS`] are refused.
+pub async fn destinations(
+    db: &PgPool,
+    ids: Option<&[String]>,
+) -> Result<Vec<(String, String)>, ShortenError> {
+    let too_many = |received| ShortenError::TooManyItems {
+        limit: MAX_LINKS,
+        received,
+    };
+    let rows: Vec<(String, String, Option<Vec<u8>>)> = match ids {
+        Some(ids) if ids.len() > MAX_LINKS => return Err(too_many(ids.len())),
+        Some(ids) => {
+            sqlx::query_as("SELECT id, url, url_deflated FROM urls WHERE id = ANY($1) ORDER BY id")
+                .bind(ids)
+                .fetch_all(db)
+                .await?
+        }
+        None => {
+            sqlx::query_as(
+                "SELECT id, url, url_deflated FROM urls WHERE enabled ORDER BY id LIMIT $1",
+            )
+            .bind(MAX_LINKS as i64 + 1)
+            .fetch_all(db)
+            .await?
+        }
+    };
+    if rows.len() > MAX_LINKS {
+        let (total,): (i64,) = sqlx::query_as("SELECT COUNT(*) FROM urls WHERE enabled")
+            .fetch_one(db)
+            .await?;
+        return Err(too_many(total as usize));
+    }
+    Ok(rows
+        .into_iter()
+        .map(|(id, url, deflated)| Ok((id, compress::load(url, deflated.as_deref())?)))
+        .collect::<Result<Vec<_>, sqlx::Error>>()?)
+}
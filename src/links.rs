@@ -0,0 +1,134 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+
+use crate::{compress, ShortenError};
+
+/// Bytes of notes a link may carry.
+pub const MAX_NOTES_BYTES: usize = 4096;
+/// Characters of a link's public description.
+pub const MAX_DESCRIPTION_CHARS: usize = 500;
+
+/// A link as shown to authenticated callers, private notes included. Public
+/// responses are built separately and never see these fields.
+#[derive(Debug, Serialize, sqlx::FromRow)]
+#[serde(rename_all = "snake_case")]
+pub struct AdminLink {
+    pub id: String,
+    pub url: String,
+    pub enabled: bool,
+    pub tags: Vec<String>,
+    pub clicks: i64,
+    pub notes: Option<String>,
+    pub description: Option<String>,
+    /// The key that created the link, `None` for anonymous ones.
+    pub owner: Option<String>,
+    pub created_at: DateTime<Utc>,
+    #[serde(skip)]
+    #[sqlx(default)]
+    pub url_deflated: Option<Vec<u8>>,
+}
+
+const SELECT: &str = "SELECT u.id, u.url, u.enabled, u.clicks, u.notes, u.description, u.owner,
+        u.created_at, u.url_deflated,
+        ARRAY(SELECT tag FROM link_tags t WHERE t.link_id = u.id ORDER BY tag) AS tags
+    FROM urls u";
+
+/// Listing order, `?sort=created_at|clicks&order=asc|desc`. Ties are broken
+/// by id in the same direction so pages don't shift between calls.
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub struct Sort {
+    #[serde(default)]
+    sort: SortKey,
+    #[serde(default)]
+    order: SortOrder,
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum SortKey {
+    #[default]
+    CreatedAt,
+    /// Flushed clicks; the last few seconds of buffered ones don't count.
+    Clicks,
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum SortOrder {
+    Asc,
+    #[default]
+    Desc,
+}
+
+impl Sort {
+    fn clause(self) -> String {
+        let column = match self.sort {
+            SortKey::CreatedAt => "created_at",
+            SortKey::Clicks => "clicks",
+        };
+        let direction = match self.order {
+            SortOrder::Asc => "ASC",
+            SortOrder::Desc => "DESC",
+        };
+        format!("ORDER BY u.{} {1}, u.id {1}", column, direction)
+    }
+}
+
+/// Links carrying `tag`.
+pub async fn with_tag(db: &PgPool, tag: &str, sort: Sort) -> Result<Vec<AdminLink>, ShortenError> {
+    let query = format!(
+        "{} WHERE EXISTS (SELECT 1 FROM link_tags t WHERE t.link_id = u.id AND t.tag = $1) {}",
+        SELECT,
+        sort.clause()
+    );
+    let links = sqlx::query_as(&query)
+        .bind(tag.to_ascii_lowercase())
+        .fetch_all(db)
+        .await?;
+    inflated(links)
+}
+
+/// Every link to `url`; more than one if some opted out of dedupe. `key`
+/// is what [`compress::key`] stores for it.
+pub async fn to_url(db: &PgPool, key: &str, sort: Sort) -> Result<Vec<AdminLink>, ShortenError> {
+    let query = format!("{} WHERE u.url = $1 {}", SELECT, sort.clause());
+    let links = sqlx::query_as(&query).bind(key).fetch_all(db).await?;
+    inflated(links)
+}
+
+/// Links created with the key whose [`owner`](crate::auth::ApiKey::owner)
+/// is `owner`.
+pub async fn owned_by(
+    db: &PgPool,
+    owner: &str,
+    sort: Sort,
+) -> Result<Vec<AdminLink>, ShortenError> {
+    let query = format!("{} WHERE u.owner = $1 {}", SELECT, sort.clause());
+    let links = sqlx::query_as(&query).bind(owner).fetch_all(db).await?;
+    inflated(links)
+}
+
+fn inflated(mut links: Vec<AdminLink>) -> Result<Vec<AdminLink>, ShortenError> {
+    for link in &mut links {
+        compress::inflate(&mut link.url, link.url_deflated.take())?;
+    }
+    Ok(links)
+}
+
+pub fn notes_fit(notes: &str) -> bool {
+    notes.len() <= MAX_NOTES_BYTES
+}
+
+/// The description with control characters, line breaks included, turned
+/// into spaces and surrounding whitespace trimmed. `None` if it's longer
+/// than `MAX_DESCRIPTION_CHARS` even so.
+pub fn clean_description(description: &str) -> Option<String> {
+    let cleaned: String = description
+        .chars()
+        .map(|c| if c.is_control() { ' ' } else { c })
+        .collect();
+    let cleaned = cleaned.trim();
+    (cleaned.chars().count() <= MAX_DESCRIPTION_CHARS).then(|| cleaned.to_string())
+}
@@ -0,0 +1,127 @@
+//! What the request log keeps. A viral link's redirects are logged one in
+//! `LOG_REDIRECT_SAMPLE` times, and with `LOG_REDACT_QUERIES` urls are
+//! logged without the queries tokens travel in.
+
+use std::{net::SocketAddr, time::Duration};
+
+use axum::{
+    extract::{ConnectInfo, Request},
+    http::Response,
+};
+use ipnet::IpNet;
+use tower_http::{
+    request_id::RequestId,
+    trace::{MakeSpan, OnResponse},
+};
+use tracing::{info, info_span, Span};
+
+use crate::{client_ip::real_client_ip, config::Config};
+
+const X_REQUEST_ID: &str = "x-request-id";
+
+/// Marks a response of the redirect route, only a sample of which is
+/// logged unless it failed. Put it in the response's extensions.
+#[derive(Debug, Clone, Copy)]
+pub struct Sampled;
+
+/// Marks a response as [`Sampled`], run on those of the redirect route.
+pub async fn sampled<B>(mut res: Response<B>) -> Response<B> {
+    res.extensions_mut().insert(Sampled);
+    res
+}
+
+/// The `LOG_*` settings.
+#[derive(Debug, Clone, Copy)]
+pub struct Logging {
+    redirect_sample: u32,
+    redact_queries: bool,
+}
+
+impl Logging {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            redirect_sample: config.log_redirect_sample.max(1),
+            redact_queries: config.log_redact_queries,
+        }
+    }
+
+    /// `url` as it may be logged: without its query and fragment when
+    /// redacting, as is otherwise.
+    pub fn url<'a>(&self, url: &'a str) -> &'a str {
+        match url.find(['?', '#']) {
+            Some(end) if self.redact_queries => &url[..end],
+            _ => url,
+        }
+    }
+
+    /// Whether the sampled request `request_id` is logged. Decided by a
+    /// hash of the id, so the same request always gets the same answer.
+    pub fn keeps(&self, request_id: &str) -> bool {
+        // FNV-1a, stable across builds unlike the std hasher
+        let hash = request_id.bytes().fold(0xcbf29ce484222325_u64, |hash, b| {
+            (hash ^ u64::from(b)).wrapping_mul(0x100000001b3)
+        });
+        hash % u64::from(self.redirect_sample) == 0
+    }
+
+    /// The span of every request, its uri and client.
+    pub fn spans(self, trusted: Vec<IpNet>) -> RequestSpan {
+        RequestSpan {
+            logging: self,
+            trusted,
+        }
+    }
+}
+
+/// Opens the `request` span, which every event while answering is logged
+/// in.
+#[derive(Debug, Clone)]
+pub struct RequestSpan {
+    logging: Logging,
+    trusted: Vec<IpNet>,
+}
+
+impl<B> MakeSpan<B> for RequestSpan {
+    fn make_span(&mut self, req: &Request<B>) -> Span {
+        let client = req
+            .extensions()
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|ConnectInfo(peer)| real_client_ip(req.headers(), peer.ip(), &self.trusted));
+        let request_id = req
+            .extensions()
+            .get::<RequestId>()
+            .and_then(|id| id.header_value().to_str().ok());
+        info_span!(
+            "request",
+            method = %req.method(),
+            uri = %self.logging.url(&req.uri().to_string()),
+            client = ?client,
+            request_id,
+            version = env!("CARGO_PKG_VERSION"),
+        )
+    }
+}
+
+/// Logs every answer, but only the sample of [`Sampled`] ones that
+/// succeeded. Server errors are logged as failures besides.
+impl<B> OnResponse<B> for Logging {
+    fn on_response(self, res: &Response<B>, latency: Duration, _: &Span) {
+        let status = res.status();
+        let failed = status.is_client_error() || status.is_server_error();
+        if res.extensions().get::<Sampled>().is_some() && !failed {
+            let request_id = res
+                .headers()
+                .get(X_REQUEST_ID)
+                .and_then(|v| v.to_str().ok())
+                .unwrap_or_default();
+            if !self.keeps(request_id) {
+                return;
+            }
+        }
+        info!(
+            status = status.as_u16(),
+            latency_ms = latency.as_millis() as u64,
+            "finished processing request"
+        );
+    }
+}
@@ -0,0 +1,137 @@
+use std::{
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use tracing::{info, warn};
+
+use crate::ShortenError;
+
+const DEFAULT_MESSAGE: &str = "down for maintenance, try again later";
+
+pub async fn init(db: &PgPool) -> Result<(), ShortenError> {
+    // a single row, shared by every instance on the database
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS maintenance (
+            id BOOLEAN PRIMARY KEY DEFAULT TRUE CHECK (id),
+            enabled BOOLEAN NOT NULL,
+            message TEXT,
+            updated_at TIMESTAMPTZ NOT NULL DEFAULT now()
+        )",
+    )
+    .execute(db)
+    .await?;
+    Ok(())
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub struct Status {
+    pub enabled: bool,
+    /// Sent with the 503s refusing writes. A generic one is used if unset.
+    #[serde(default)]
+    pub message: Option<String>,
+}
+
+#[derive(Debug)]
+struct State {
+    status: Status,
+    /// When the status was last set. Rows in the table from before this are
+    /// ignored, `None` takes whatever is there.
+    since: Option<DateTime<Utc>>,
+}
+
+/// Whether writes are refused, e.g. while the database is being migrated.
+///
+/// Set from `MAINTENANCE` at startup and through `POST /api/maintenance`
+/// at runtime. Changes made through the API are stored in the
+/// `maintenance` table and picked up by other instances on their next
+/// poll. An instance started with `MAINTENANCE` set keeps that until the
+/// table is changed after its start.
+#[derive(Debug, Clone)]
+pub struct Maintenance {
+    state: Arc<RwLock<State>>,
+}
+
+impl Maintenance {
+    /// `initial` from the environment, `None` to follow the table.
+    pub fn new(initial: Option<Status>) -> Self {
+        let since = initial.is_some().then(Utc::now);
+        Self {
+            state: Arc::new(RwLock::new(State {
+                status: initial.unwrap_or_default(),
+                since,
+            })),
+        }
+    }
+
+    pub fn status(&self) -> Status {
+        self.state.read().unwrap().status.clone()
+    }
+
+    /// The message writes are refused with, `None` when they aren't.
+    pub fn refusal(&self) -> Option<String> {
+        let state = self.state.read().unwrap();
+        state.status.enabled.then(|| {
+            state
+                .status
+                .message
+                .clone()
+                .unwrap_or_else(|| DEFAULT_MESSAGE.into())
+        })
+    }
+
+    /// Stores `status` for every instance and applies it here at once.
+    pub async fn set(&self, db: &PgPool, status: Status) -> Result<(), ShortenError> {
+        let (updated_at,): (DateTime<Utc>,) = sqlx::query_as(
+            "INSERT INTO maintenance (enabled, message) VALUES ($1, $2)
+             ON CONFLICT (id) DO UPDATE
+             SET enabled = EXCLUDED.enabled, message = EXCLUDED.message, updated_at = now()
+             RETURNING updated_at",
+        )
+        .bind(status.enabled)
+        .bind(&status.message)
+        .fetch_one(db)
+        .await?;
+        let mut state = self.state.write().unwrap();
+        state.status = status;
+        state.since = Some(updated_at);
+        Ok(())
+    }
+
+    /// Takes over the stored status if it changed since it was last set.
+    pub async fn sync(&self, db: &PgPool) -> Result<(), ShortenError> {
+        let stored: Option<(bool, Option<String>, DateTime<Utc>)> =
+            sqlx::query_as("SELECT enabled, message, updated_at FROM maintenance")
+                .fetch_optional(db)
+                .await?;
+        let Some((enabled, message, updated_at)) = stored else {
+            return Ok(());
+        };
+        let mut state = self.state.write().unwrap();
+        if state.since.is_some_and(|since| updated_at <= since) {
+            return Ok(());
+        }
+        let status = Status { enabled, message };
+        if status != state.status {
+            info!("Maintenance mode {}", if enabled { "on" } else { "off" });
+        }
+        state.status = status;
+        state.since = Some(updated_at);
+        Ok(())
+    }
+
+    /// Syncs every `every`.
+    pub async fn run(self, db: PgPool, every: Duration) {
+        let mut interval = tokio::time::interval(every);
+        loop {
+            interval.tick().await;
+            if let Err(e) = self.sync(&db).await {
+                warn!("Failed to read maintenance mode: {}", e);
+            }
+        }
+    }
+}
@@ -0,0 +1,402 @@
+//! Merging the links of another instance's database into this one's, for
+//! consolidating instances whose random ids partly collide.
+
+use std::{collections::HashMap, str::FromStr};
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use serde_json::{json, Map, Value};
+use sqlx::{
+    postgres::{PgConnectOptions, PgPoolOptions},
+    PgConnection, PgPool,
+};
+use tracing::{info, warn};
+
+use crate::{config::redact_url, PgState, ShortenError};
+
+/// Links copied per transaction, the progress a merge resumes from.
+pub const DEFAULT_BATCH: usize = 500;
+
+/// What's copied along with a link, all keyed by `link_id`. Reports,
+/// claims, transfers and the url history stay behind: they name keys and
+/// owners of the other instance.
+const RELATED: &[&str] = &["link_tags", "link_urls", "link_uniques"];
+
+pub async fn init(db: &PgPool) -> Result<(), ShortenError> {
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS merges (
+            source TEXT PRIMARY KEY,
+            state TEXT NOT NULL DEFAULT 'running',
+            last_id TEXT NOT NULL DEFAULT '',
+            copied BIGINT NOT NULL DEFAULT 0,
+            renamed BIGINT NOT NULL DEFAULT 0,
+            started_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+            finished_at TIMESTAMPTZ
+        )",
+    )
+    .execute(db)
+    .await?;
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS merged_ids (
+            source TEXT NOT NULL,
+            old_id TEXT NOT NULL,
+            new_id TEXT NOT NULL,
+            merged_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+            PRIMARY KEY (source, old_id)
+        )",
+    )
+    .execute(db)
+    .await?;
+    Ok(())
+}
+
+/// A slug of the source now known by another name here: a link's id that
+/// was taken, or an alias that was, which now leads to the link's id.
+#[derive(Debug, Serialize, sqlx::FromRow)]
+#[serde(rename_all = "snake_case")]
+pub struct MergedId {
+    pub source: String,
+    pub old_id: String,
+    pub new_id: String,
+    pub merged_at: DateTime<Utc>,
+}
+
+/// What one run of a merge did.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Summary {
+    /// Links copied, under their own id or a fresh one.
+    pub copied: u64,
+    /// Ids and aliases that were taken here, now in `merged_ids`.
+    pub renamed: u64,
+    /// Links an earlier run already copied.
+    pub skipped: u64,
+}
+
+/// Connects to the source database so that it can't be written to, every
+/// transaction being read-only.
+pub async fn connect_source(url: &str) -> Result<PgPool, ShortenError> {
+    let options =
+        PgConnectOptions::from_str(url)?.options([("default_transaction_read_only", "on")]);
+    let pool = PgPoolOptions::new()
+        .max_connections(1)
+        .connect_with(options)
+        .await?;
+    Ok(pool)
+}
+
+/// Every renamed slug, by source and old id.
+pub async fn renamed(db: &PgPool) -> Result<Vec<MergedId>, ShortenError> {
+    let rows = sqlx::query_as(
+        "SELECT source, old_id, new_id, merged_at FROM merged_ids
+         WHERE old_id <> new_id ORDER BY source, old_id",
+    )
+    .fetch_all(db)
+    .await?;
+    Ok(rows)
+}
+
+/// Copies the links of `source`, named `source_url`, into `db`, `batch` in
+/// a transaction: with their aliases, tags, destinations and stats, under
+/// their own ids where those are free and fresh ones where not. Aliases
+/// taken here are left out, their links reached by id. Every link copied
+/// and slug renamed is written to `merged_ids`.
+///
+/// The links are read from `source` in id order and where a merge got to
+/// is kept in `merges`, so an interrupted one resumes from its last batch.
+/// Running a finished one again goes through the source anew, copying
+/// only the links created there since.
+///
+/// The links come without an owner, the keys being another instance's,
+/// and aren't handed back for shortens of their url.
+pub async fn run(
+    db: &PgState,
+    source: &PgPool,
+    source_url: &str,
+    batch: usize,
+) -> Result<Summary, ShortenError> {
+    let name = redact_url(source_url);
+    let last_id: String = sqlx::query_scalar(
+        "INSERT INTO merges (source) VALUES ($1)
+         ON CONFLICT (source) DO UPDATE SET
+             last_id = CASE WHEN merges.state = 'completed' THEN '' ELSE merges.last_id END,
+             state = 'running', finished_at = NULL
+         RETURNING last_id",
+    )
+    .bind(&name)
+    .fetch_one(&db.db)
+    .await?;
+    if !last_id.is_empty() {
+        info!("Resuming the merge of {} after {:?}", name, last_id);
+    }
+    let columns = columns(&db.db).await?;
+    let mut summary = Summary::default();
+    let mut last_id = last_id;
+    loop {
+        let links = read_batch(source, &last_id, batch).await?;
+        let Some(last) = links.last() else {
+            break;
+        };
+        last_id = last.id.clone();
+        let mut tx = db.db.begin().await?;
+        // nothing may add a link with an id picked meanwhile
+        sqlx::query("LOCK TABLE urls IN SHARE ROW EXCLUSIVE MODE")
+            .execute(&mut *tx)
+            .await?;
+        let mut done = Summary::default();
+        for link in &links {
+            copy(&mut tx, db, &columns, &name, link, &mut done).await?;
+        }
+        sqlx::query(
+            "UPDATE merges SET last_id = $2, copied = copied + $3, renamed = renamed + $4
+             WHERE source = $1",
+        )
+        .bind(&name)
+        .bind(&last_id)
+        .bind(done.copied as i64)
+        .bind(done.renamed as i64)
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        summary.copied += done.copied;
+        summary.renamed += done.renamed;
+        summary.skipped += done.skipped;
+        info!(
+            "Merged {} links of {} up to {:?}",
+            links.len(),
+            name,
+            last_id
+        );
+        if links.len() < batch {
+            break;
+        }
+    }
+    sqlx::query("UPDATE merges SET state = 'completed', finished_at = now() WHERE source = $1")
+        .bind(&name)
+        .execute(&db.db)
+        .await?;
+    info!(
+        "Merged {}: copied {} links, renamed {} slugs, skipped {} copied before",
+        name, summary.copied, summary.renamed, summary.skipped
+    );
+    if summary.copied > 0 {
+        warn!(
+            "Running instances find the merged links once their slug filter is rebuilt, \
+             e.g. by POST /api/admin/reindex"
+        );
+    }
+    Ok(summary)
+}
+
+/// A link of the source with the rows kept about it, as JSON objects.
+#[derive(Debug)]
+struct Link {
+    id: String,
+    row: Map<String, Value>,
+    slugs: Vec<String>,
+    related: Vec<(&'static str, Map<String, Value>)>,
+}
+
+/// The next `batch` links after `last_id`, read in one snapshot.
+async fn read_batch(
+    source: &PgPool,
+    last_id: &str,
+    batch: usize,
+) -> Result<Vec<Link>, ShortenError> {
+    let mut snapshot = source.begin().await?;
+    sqlx::query("SET TRANSACTION ISOLATION LEVEL REPEATABLE READ, READ ONLY")
+        .execute(&mut *snapshot)
+        .await?;
+    let rows: Vec<(String, Value)> = sqlx::query_as(
+        "SELECT id, row_to_json(u)::jsonb FROM urls u WHERE id > $1 ORDER BY id LIMIT $2",
+    )
+    .bind(last_id)
+    .bind(batch as i64)
+    .fetch_all(&mut *snapshot)
+    .await?;
+    let ids: Vec<&str> = rows.iter().map(|(id, _)| id.as_str()).collect();
+    let mut links: Vec<Link> = rows
+        .iter()
+        .map(|(id, row)| Link {
+            id: id.clone(),
+            row: row.as_object().cloned().unwrap_or_default(),
+            slugs: Vec::new(),
+            related: Vec::new(),
+        })
+        .collect();
+    let index: HashMap<String, usize> = links
+        .iter()
+        .enumerate()
+        .map(|(i, link)| (link.id.clone(), i))
+        .collect();
+    let slugs: Vec<(String, String)> = sqlx::query_as(
+        "SELECT slug, link_id FROM slugs WHERE link_id = ANY($1) AND slug <> link_id
+         ORDER BY slug",
+    )
+    .bind(&ids)
+    .fetch_all(&mut *snapshot)
+    .await?;
+    for (slug, link_id) in slugs {
+        links[index[&link_id]].slugs.push(slug);
+    }
+    for table in RELATED {
+        let query = format!(
+            "SELECT link_id, row_to_json(t)::jsonb FROM {} t WHERE link_id = ANY($1)",
+            table
+        );
+        let rows: Vec<(String, Value)> = sqlx::query_as(&query)
+            .bind(&ids)
+            .fetch_all(&mut *snapshot)
+            .await?;
+        for (link_id, row) in rows {
+            if let Value::Object(row) = row {
+                links[index[&link_id]].related.push((table, row));
+            }
+        }
+    }
+    snapshot.commit().await?;
+    Ok(links)
+}
+
+/// The columns of the tables written, to refuse rows of a newer schema
+/// rather than drop what this one has no place for.
+async fn columns(db: &PgPool) -> Result<HashMap<String, Vec<String>>, ShortenError> {
+    let rows: Vec<(String, String)> = sqlx::query_as(
+        "SELECT table_name::text, column_name::text FROM information_schema.columns
+         WHERE table_schema = current_schema() AND table_name = ANY($1)",
+    )
+    .bind(
+        ["urls"]
+            .iter()
+            .chain(RELATED)
+            .map(|t| t.to_string())
+            .collect::<Vec<_>>(),
+    )
+    .fetch_all(db)
+    .await?;
+    let mut columns: HashMap<String, Vec<String>> = HashMap::new();
+    for (table, column) in rows {
+        columns.entry(table).or_default().push(column);
+    }
+    Ok(columns)
+}
+
+async fn taken(conn: &mut PgConnection, slug: &str) -> Result<bool, ShortenError> {
+    let taken = sqlx::query_scalar(
+        "SELECT EXISTS (SELECT 1 FROM urls WHERE id = $1)
+             OR EXISTS (SELECT 1 FROM slugs WHERE slug = $1)",
+    )
+    .bind(slug)
+    .fetch_one(conn)
+    .await?;
+    Ok(taken)
+}
+
+/// Copies `link` unless an earlier run did, counting it in `done`.
+async fn copy(
+    conn: &mut PgConnection,
+    db: &PgState,
+    columns: &HashMap<String, Vec<String>>,
+    source: &str,
+    link: &Link,
+    done: &mut Summary,
+) -> Result<(), ShortenError> {
+    let merged: bool = sqlx::query_scalar(
+        "SELECT EXISTS (SELECT 1 FROM merged_ids WHERE source = $1 AND old_id = $2)",
+    )
+    .bind(source)
+    .bind(&link.id)
+    .fetch_one(&mut *conn)
+    .await?;
+    if merged {
+        done.skipped += 1;
+        return Ok(());
+    }
+    let mut new_id = link.id.clone();
+    if taken(conn, &new_id).await? {
+        let mut fresh = None;
+        for _ in 0..db.max_generation_attempts {
+            let candidate = db.ids.generate();
+            if !taken(conn, &candidate).await? {
+                fresh = Some(candidate);
+                break;
+            }
+        }
+        new_id = fresh.ok_or_else(|| {
+            ShortenError::Merge(format!(
+                "no free id for link {} after {} attempts",
+                link.id, db.max_generation_attempts
+            ))
+        })?;
+    }
+    let mut row = link.row.clone();
+    row.insert("id".into(), json!(new_id));
+    row.insert("owner".into(), Value::Null);
+    row.insert("deduped".into(), json!(false));
+    insert(conn, columns, "urls", row).await?;
+    sqlx::query("INSERT INTO slugs (slug, link_id) VALUES ($1, $1)")
+        .bind(&new_id)
+        .execute(&mut *conn)
+        .await?;
+    let mut renames = vec![(link.id.as_str(), new_id.as_str())];
+    for slug in &link.slugs {
+        if taken(conn, slug).await? {
+            renames.push((slug, new_id.as_str()));
+        } else {
+            sqlx::query("INSERT INTO slugs (slug, link_id) VALUES ($1, $2)")
+                .bind(slug)
+                .bind(&new_id)
+                .execute(&mut *conn)
+                .await?;
+        }
+    }
+    for (table, row) in &link.related {
+        let mut row = row.clone();
+        row.insert("link_id".into(), json!(new_id));
+        insert(conn, columns, table, row).await?;
+    }
+    for (old_id, new_id) in renames {
+        sqlx::query("INSERT INTO merged_ids (source, old_id, new_id) VALUES ($1, $2, $3)")
+            .bind(source)
+            .bind(old_id)
+            .bind(new_id)
+            .execute(&mut *conn)
+            .await?;
+        if old_id != new_id {
+            info!("Merged {} of {} as {}", old_id, source, new_id);
+            done.renamed += 1;
+        }
+    }
+    done.copied += 1;
+    Ok(())
+}
+
+/// Inserts `row` into `table`, the columns it doesn't name getting their
+/// defaults.
+async fn insert(
+    conn: &mut PgConnection,
+    columns: &HashMap<String, Vec<String>>,
+    table: &str,
+    row: Map<String, Value>,
+) -> Result<(), ShortenError> {
+    let known = columns.get(table).map(Vec::as_slice).unwrap_or_default();
+    let mut names = Vec::with_capacity(row.len());
+    for column in row.keys() {
+        if !known.contains(column) {
+            return Err(ShortenError::Merge(format!(
+                "the source's {} has a column {:?} this database lacks, upgrade it first",
+                table, column
+            )));
+        }
+        names.push(format!("\"{}\"", column));
+    }
+    let names = names.join(", ");
+    let query = format!(
+        "INSERT INTO {0} ({1}) SELECT {1} FROM jsonb_populate_record(NULL::{0}, $1)",
+        table, names
+    );
+    sqlx::query(&query)
+        .bind(Value::Object(row))
+        .execute(conn)
+        .await?;
+    Ok(())
+}
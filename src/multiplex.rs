@@ -0,0 +1,45 @@
+use sqlx::{PgConnection, PgPool};
+
+use crate::ShortenError;
+
+/// Destinations a link may have besides its url.
+pub const MAX_URLS: usize = 20;
+/// The query parameter picking one of a link's destinations, from `0`, its
+/// url.
+pub const PARAM: &str = "i";
+
+pub async fn init(db: &PgPool) -> Result<(), ShortenError> {
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS link_urls (
+            link_id TEXT NOT NULL,
+            idx INTEGER NOT NULL,
+            url TEXT NOT NULL,
+            PRIMARY KEY (link_id, idx)
+        )",
+    )
+    .execute(db)
+    .await?;
+    Ok(())
+}
+
+/// Stores `urls` as link `id`'s destinations from `?i=1` on.
+pub async fn add(conn: &mut PgConnection, id: &str, urls: &[String]) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "INSERT INTO link_urls (link_id, idx, url)
+         SELECT $1, idx::INTEGER, url FROM unnest($2::TEXT[]) WITH ORDINALITY AS t (url, idx)",
+    )
+    .bind(id)
+    .bind(urls)
+    .execute(conn)
+    .await?;
+    Ok(())
+}
+
+/// Link `id`'s destination `index`, from 1 on.
+pub async fn get(db: &PgPool, id: &str, index: i32) -> Result<Option<String>, sqlx::Error> {
+    sqlx::query_scalar("SELECT url FROM link_urls WHERE link_id = $1 AND idx = $2")
+        .bind(id)
+        .bind(index)
+        .fetch_optional(db)
+        .await
+}
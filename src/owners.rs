@@ -0,0 +1,180 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use tracing::{info, warn};
+
+use crate::ShortenError;
+
+pub async fn init(db: &PgPool) -> Result<(), ShortenError> {
+    // owners without a row are active
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS suspended_owners (
+            owner TEXT PRIMARY KEY,
+            message TEXT,
+            suspended_at TIMESTAMPTZ NOT NULL DEFAULT now()
+        )",
+    )
+    .execute(db)
+    .await?;
+    Ok(())
+}
+
+/// Whether an owner's links redirect.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Status {
+    Active,
+    /// Their links are answered with `SUSPENDED_OWNER_STATUS` instead,
+    /// e.g. while their bill is unpaid.
+    Suspended,
+}
+
+/// An owner's status, as set through `PUT /api/owners/:owner/status`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub struct OwnerStatus {
+    pub status: Status,
+    /// What visitors of a suspended owner's links are told, instead of
+    /// `SUSPENDED_OWNER_MESSAGE`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+    #[serde(default, skip_deserializing, skip_serializing_if = "Option::is_none")]
+    pub suspended_at: Option<DateTime<Utc>>,
+}
+
+impl OwnerStatus {
+    fn active() -> Self {
+        Self {
+            status: Status::Active,
+            message: None,
+            suspended_at: None,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct State {
+    /// The status of each suspended owner.
+    suspended: HashMap<String, OwnerStatus>,
+    /// Count and latest `suspended_at` of the rows read, `None` before the
+    /// first read. Suspending moves the latest, lifting drops the count.
+    version: Option<(i64, Option<DateTime<Utc>>)>,
+}
+
+/// The suspended owners of the `suspended_owners` table, checked on every
+/// redirect of an owned link.
+///
+/// Changes made through the API apply here at once and are picked up by
+/// other instances on their next poll.
+#[derive(Debug, Clone, Default)]
+pub struct Suspensions {
+    state: Arc<RwLock<State>>,
+}
+
+impl Suspensions {
+    /// The status of `owner`, active unless suspended.
+    pub fn status(&self, owner: &str) -> OwnerStatus {
+        self.state
+            .read()
+            .unwrap()
+            .suspended
+            .get(owner)
+            .cloned()
+            .unwrap_or_else(OwnerStatus::active)
+    }
+
+    /// Whether the links of `owner` are refused, and with what message if
+    /// the owner has one.
+    pub fn refusal(&self, owner: &str) -> Option<Option<String>> {
+        let state = self.state.read().unwrap();
+        state.suspended.get(owner).map(|s| s.message.clone())
+    }
+
+    /// Stores the status of `owner` for every instance and applies it here
+    /// at once.
+    pub async fn set(
+        &self,
+        db: &PgPool,
+        owner: &str,
+        status: OwnerStatus,
+    ) -> Result<OwnerStatus, ShortenError> {
+        match status.status {
+            Status::Suspended => {
+                sqlx::query(
+                    "INSERT INTO suspended_owners (owner, message) VALUES ($1, $2)
+                     ON CONFLICT (owner) DO UPDATE
+                     SET message = EXCLUDED.message, suspended_at = now()",
+                )
+                .bind(owner)
+                .bind(&status.message)
+                .execute(db)
+                .await?;
+            }
+            Status::Active => {
+                sqlx::query("DELETE FROM suspended_owners WHERE owner = $1")
+                    .bind(owner)
+                    .execute(db)
+                    .await?;
+            }
+        }
+        self.reload(db).await?;
+        Ok(self.status(owner))
+    }
+
+    /// Re-reads the table if it changed since it was last read.
+    pub async fn sync(&self, db: &PgPool) -> Result<(), ShortenError> {
+        let version: (i64, Option<DateTime<Utc>>) =
+            sqlx::query_as("SELECT COUNT(*), MAX(suspended_at) FROM suspended_owners")
+                .fetch_one(db)
+                .await?;
+        if self.state.read().unwrap().version == Some(version) {
+            return Ok(());
+        }
+        self.reload(db).await
+    }
+
+    pub async fn reload(&self, db: &PgPool) -> Result<(), ShortenError> {
+        let rows: Vec<(String, Option<String>, DateTime<Utc>)> =
+            sqlx::query_as("SELECT owner, message, suspended_at FROM suspended_owners")
+                .fetch_all(db)
+                .await?;
+        let version = (rows.len() as i64, rows.iter().map(|(_, _, at)| *at).max());
+        let suspended: HashMap<String, OwnerStatus> = rows
+            .into_iter()
+            .map(|(owner, message, suspended_at)| {
+                let status = OwnerStatus {
+                    status: Status::Suspended,
+                    message,
+                    suspended_at: Some(suspended_at),
+                };
+                (owner, status)
+            })
+            .collect();
+        let mut state = self.state.write().unwrap();
+        if state.suspended.len() != suspended.len() {
+            info!("{} owners suspended", suspended.len());
+        }
+        *state = State {
+            suspended,
+            version: Some(version),
+        };
+        Ok(())
+    }
+
+    /// Syncs every `every`.
+    pub async fn run(self, db: PgPool, every: Duration) {
+        let mut interval = tokio::time::interval(every);
+        loop {
+            interval.tick().await;
+            if let Err(e) = self.sync(&db).await {
+                warn!("Failed to read suspended owners: {}", e);
+            }
+        }
+    }
+}
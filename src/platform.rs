@@ -0,0 +1,75 @@
+use serde::{Deserialize, Serialize};
+
+/// The kind of device a redirect is for, as far as its `User-Agent` tells.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Platform {
+    Ios,
+    Android,
+    Other,
+}
+
+impl Platform {
+    /// Sniffs the `User-Agent`. Windows Phone claims to be Android and iOS
+    /// both, and iPads asking for desktop sites pass for Macs, so those
+    /// count as other.
+    pub fn detect(user_agent: &str) -> Self {
+        if user_agent.contains("Windows Phone") {
+            Platform::Other
+        } else if ["iPhone", "iPad", "iPod"]
+            .iter()
+            .any(|device| user_agent.contains(device))
+        {
+            Platform::Ios
+        } else if user_agent.contains("Android") {
+            Platform::Android
+        } else {
+            Platform::Other
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Platform::Ios => "ios",
+            Platform::Android => "android",
+            Platform::Other => "other",
+        }
+    }
+}
+
+/// Per-platform destinations of one link, e.g. the app stores. Platforms
+/// without one of their own go to `default`, or to the link's url without
+/// that.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub struct PlatformTargets {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ios: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub android: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub default: Option<String>,
+}
+
+impl PlatformTargets {
+    pub fn is_empty(&self) -> bool {
+        self == &Self::default()
+    }
+
+    /// Where `platform` goes, `None` for the link's url.
+    pub fn pick(&self, platform: Platform) -> Option<&str> {
+        let own = match platform {
+            Platform::Ios => self.ios.as_deref(),
+            Platform::Android => self.android.as_deref(),
+            Platform::Other => None,
+        };
+        own.or(self.default.as_deref())
+    }
+
+    /// Every destination, to check them all like the link's url.
+    pub fn targets_mut(&mut self) -> impl Iterator<Item = &mut String> {
+        [&mut self.ios, &mut self.android, &mut self.default]
+            .into_iter()
+            .flatten()
+    }
+}
@@ -0,0 +1,372 @@
+use std::{
+    collections::HashSet,
+    fmt,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use sqlx::PgPool;
+use tracing::{info, warn};
+use url::{form_urlencoded, Url};
+
+use crate::ShortenError;
+
+/// How long the continuation url of a confirmation page is followed.
+pub const CONTINUE_TTL: Duration = Duration::from_secs(60 * 60);
+
+pub async fn init(db: &PgPool) -> Result<(), ShortenError> {
+    // a rule names either a link or a destination pattern
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS redirect_policies (
+            id BIGSERIAL PRIMARY KEY,
+            link_id TEXT,
+            pattern TEXT,
+            reason TEXT,
+            created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+            CHECK ((link_id IS NULL) <> (pattern IS NULL))
+        )",
+    )
+    .execute(db)
+    .await?;
+    Ok(())
+}
+
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+#[serde(rename_all = "snake_case")]
+pub struct Policy {
+    pub id: i64,
+    pub link_id: Option<String>,
+    pub pattern: Option<String>,
+    pub reason: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+/// A rule to add: `link_id` or `pattern`, see [`Rules`].
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub struct NewPolicy {
+    #[serde(default)]
+    pub link_id: Option<String>,
+    #[serde(default)]
+    pub pattern: Option<String>,
+    /// Why the rule exists, for whoever finds it later.
+    #[serde(default)]
+    pub reason: Option<String>,
+}
+
+/// Which redirects show a confirmation page instead of redirecting.
+///
+/// Those of the links named by a rule do, and those whose destination a
+/// pattern matches unless an exception, a pattern starting with `!`,
+/// matches too. `*` in a pattern stands for any run of characters. A
+/// pattern without `/` is matched against the host, `*.example.com`
+/// covering the subdomains, one with `/` against the host and path, as
+/// `*/*.exe` for downloads of executables. Matching ignores case.
+#[derive(Debug, Clone, Default)]
+pub struct Rules {
+    links: HashSet<String>,
+    confirm: Vec<Glob>,
+    except: Vec<Glob>,
+}
+
+impl Rules {
+    /// Skips invalid patterns, which [`is_valid_pattern`] keeps out of the
+    /// table.
+    pub fn new<'a>(
+        links: impl IntoIterator<Item = &'a str>,
+        patterns: impl IntoIterator<Item = &'a str>,
+    ) -> Self {
+        let mut rules = Self {
+            links: links.into_iter().map(String::from).collect(),
+            ..Default::default()
+        };
+        for pattern in patterns.into_iter().filter(|p| is_valid_pattern(p)) {
+            match pattern.strip_prefix('!') {
+                Some(except) => rules.except.push(Glob::new(except)),
+                None => rules.confirm.push(Glob::new(pattern)),
+            }
+        }
+        rules
+    }
+
+    pub fn len(&self) -> usize {
+        self.links.len() + self.confirm.len() + self.except.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Whether following link `id` to `url` is to be confirmed first.
+    pub fn applies(&self, id: &str, url: &str) -> bool {
+        if self.links.contains(id) {
+            return true;
+        }
+        if self.confirm.is_empty() {
+            return false;
+        }
+        let Ok(url) = Url::parse(url) else {
+            return false;
+        };
+        let host = url
+            .host_str()
+            .unwrap_or("")
+            .trim_end_matches('.')
+            .to_ascii_lowercase();
+        let with_path = format!("{}{}", host, url.path().to_lowercase());
+        let matches = |glob: &Glob| glob.matches(if glob.path { &with_path } else { &host });
+        self.confirm.iter().any(matches) && !self.except.iter().any(matches)
+    }
+}
+
+/// Whether `pattern` can be stored as a rule.
+pub fn is_valid_pattern(pattern: &str) -> bool {
+    let glob = pattern.strip_prefix('!').unwrap_or(pattern);
+    !glob.is_empty()
+        && !glob.contains("://")
+        && !glob.starts_with('!')
+        && !glob.chars().any(char::is_whitespace)
+}
+
+/// A pattern split at its `*`s.
+#[derive(Debug, Clone)]
+struct Glob {
+    parts: Vec<String>,
+    path: bool,
+}
+
+impl Glob {
+    fn new(pattern: &str) -> Self {
+        Self {
+            parts: pattern
+                .to_lowercase()
+                .split('*')
+                .map(String::from)
+                .collect(),
+            path: pattern.contains('/'),
+        }
+    }
+
+    fn matches(&self, text: &str) -> bool {
+        let Some((first, rest)) = self.parts.split_first() else {
+            return false;
+        };
+        let Some(mut text) = text.strip_prefix(first.as_str()) else {
+            return false;
+        };
+        let Some((last, middle)) = rest.split_last() else {
+            return text.is_empty();
+        };
+        // the leftmost match of each part leaves the most for the next
+        for part in middle {
+            match text.find(part.as_str()) {
+                Some(at) => text = &text[at + part.len()..],
+                None => return false,
+            }
+        }
+        text.ends_with(last.as_str())
+    }
+}
+
+#[derive(Debug, Default)]
+struct State {
+    rules: Rules,
+    /// Row count and highest id of the table when the rules were read.
+    /// Rules are only added and deleted, so any change moves one of them.
+    version: Option<(i64, i64)>,
+}
+
+/// The rules of the `redirect_policies` table, compiled once and shared,
+/// along with patterns from the config that apply whatever the table holds.
+///
+/// Changes made through the API apply here at once and are picked up by
+/// other instances on their next poll.
+#[derive(Debug, Clone, Default)]
+pub struct Policies {
+    state: Arc<RwLock<State>>,
+    configured: Arc<[String]>,
+}
+
+impl Policies {
+    /// Applies `configured` patterns from the start, before the table is
+    /// first read.
+    pub fn new(configured: Vec<String>) -> Self {
+        let rules = Rules::new([], configured.iter().map(String::as_str));
+        Self {
+            state: Arc::new(RwLock::new(State {
+                rules,
+                version: None,
+            })),
+            configured: configured.into(),
+        }
+    }
+
+    /// How many rules there are, those from the config included.
+    pub fn rule_count(&self) -> usize {
+        self.state.read().unwrap().rules.len()
+    }
+
+    /// See [`Rules::applies`].
+    pub fn applies(&self, id: &str, url: &str) -> bool {
+        self.state.read().unwrap().rules.applies(id, url)
+    }
+
+    /// Re-reads the rules if the table changed since they were last read.
+    pub async fn sync(&self, db: &PgPool) -> Result<(), ShortenError> {
+        let version: (i64, i64) =
+            sqlx::query_as("SELECT COUNT(*), COALESCE(MAX(id), 0) FROM redirect_policies")
+                .fetch_one(db)
+                .await?;
+        if self.state.read().unwrap().version == Some(version) {
+            return Ok(());
+        }
+        self.reload(db).await
+    }
+
+    pub async fn reload(&self, db: &PgPool) -> Result<(), ShortenError> {
+        let policies = list(db).await?;
+        let version = (
+            policies.len() as i64,
+            policies.iter().map(|p| p.id).max().unwrap_or(0),
+        );
+        let rules = Rules::new(
+            policies.iter().filter_map(|p| p.link_id.as_deref()),
+            policies
+                .iter()
+                .filter_map(|p| p.pattern.as_deref())
+                .chain(self.configured.iter().map(String::as_str)),
+        );
+        info!("Loaded {} redirect policies", rules.len());
+        *self.state.write().unwrap() = State {
+            rules,
+            version: Some(version),
+        };
+        Ok(())
+    }
+
+    /// Syncs every `every`.
+    pub async fn run(self, db: PgPool, every: Duration) {
+        let mut interval = tokio::time::interval(every);
+        loop {
+            interval.tick().await;
+            if let Err(e) = self.sync(&db).await {
+                warn!("Failed to read redirect policies: {}", e);
+            }
+        }
+    }
+}
+
+pub async fn list(db: &PgPool) -> Result<Vec<Policy>, ShortenError> {
+    let policies = sqlx::query_as(
+        "SELECT id, link_id, pattern, reason, created_at FROM redirect_policies ORDER BY id",
+    )
+    .fetch_all(db)
+    .await?;
+    Ok(policies)
+}
+
+pub async fn create(db: &PgPool, policy: &NewPolicy) -> Result<Policy, ShortenError> {
+    let policy = sqlx::query_as(
+        "INSERT INTO redirect_policies (link_id, pattern, reason) VALUES ($1, $2, $3)
+         RETURNING id, link_id, pattern, reason, created_at",
+    )
+    .bind(&policy.link_id)
+    .bind(&policy.pattern)
+    .bind(&policy.reason)
+    .fetch_one(db)
+    .await?;
+    Ok(policy)
+}
+
+/// Returns whether the policy existed.
+pub async fn delete(db: &PgPool, id: i64) -> Result<bool, ShortenError> {
+    let ret = sqlx::query("DELETE FROM redirect_policies WHERE id = $1")
+        .bind(id)
+        .execute(db)
+        .await?;
+    Ok(ret.rows_affected() > 0)
+}
+
+/// Signs the continuation urls of confirmation pages, so following a link
+/// past its page takes having been shown it: `<slug>/continue` with the
+/// expiry, the query of the original request and an HMAC-SHA256 of them.
+#[derive(Clone)]
+pub struct Continuations {
+    key: Hmac<Sha256>,
+}
+
+impl Continuations {
+    /// Without a key, one is made up, and only the instance that showed a
+    /// page follows its continuation.
+    pub fn new(key: Option<&str>) -> Self {
+        let key = match key {
+            Some(key) => key.as_bytes().to_vec(),
+            None => {
+                let mut key = vec![0; 32];
+                rand::thread_rng().fill_bytes(&mut key);
+                key
+            }
+        };
+        Self {
+            key: Hmac::new_from_slice(&key).expect("HMAC takes any key length"),
+        }
+    }
+
+    /// The continuation of `slug` requested with `query`, relative to the
+    /// page so it works wherever the shortener is mounted.
+    pub fn url(&self, slug: &str, query: Option<&str>, now: i64) -> String {
+        let expires = now + CONTINUE_TTL.as_secs() as i64;
+        let mut params = form_urlencoded::Serializer::new(String::new());
+        params.append_pair("exp", &expires.to_string());
+        if let Some(query) = query {
+            params.append_pair("q", query);
+        }
+        params.append_pair("sig", &self.sign(slug, query, expires));
+        format!("./{}/continue?{}", slug, params.finish())
+    }
+
+    /// Whether `signature` was made for these fields and hasn't expired.
+    pub fn verify(
+        &self,
+        slug: &str,
+        query: Option<&str>,
+        expires: i64,
+        signature: &str,
+        now: i64,
+    ) -> bool {
+        let expected = self.sign(slug, query, expires);
+        expires >= now
+            && expected.len() == signature.len()
+            && expected
+                .bytes()
+                .zip(signature.bytes())
+                .fold(0, |acc, (a, b)| acc | (a ^ b))
+                == 0
+    }
+
+    fn sign(&self, slug: &str, query: Option<&str>, expires: i64) -> String {
+        let mut mac = self.key.clone();
+        // the query is last, so no field can end up in another
+        mac.update(format!("{}\n{}\n", slug, expires).as_bytes());
+        if let Some(query) = query {
+            mac.update(b"?");
+            mac.update(query.as_bytes());
+        }
+        mac.finalize()
+            .into_bytes()
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect()
+    }
+}
+
+impl fmt::Debug for Continuations {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Continuations").finish_non_exhaustive()
+    }
+}
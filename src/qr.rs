@@ -0,0 +1,492 @@
+//! QR codes of short links, drawn as PNG images.
+//!
+//! Only what a short url needs: byte mode, error correction level M and
+//! versions 1 to 10, which hold up to 213 bytes.
+
+use std::io::Write;
+
+use flate2::{write::ZlibEncoder, Compression, Crc};
+
+/// Pixels per module unless asked otherwise.
+pub const DEFAULT_SCALE: u32 = 8;
+/// Most pixels per module, which keeps a version 10 code under 2000 pixels
+/// across.
+pub const MAX_SCALE: u32 = 32;
+
+const MAX_VERSION: usize = 10;
+/// Light modules around the code, as scanners expect.
+const QUIET_ZONE: usize = 4;
+
+/// Error correction codewords per block and blocks, at level M, by version.
+const ECC_PER_BLOCK: [usize; MAX_VERSION + 1] = [0, 10, 16, 26, 18, 24, 16, 18, 22, 22, 26];
+const BLOCKS: [usize; MAX_VERSION + 1] = [0, 1, 1, 1, 2, 2, 4, 4, 4, 5, 5];
+
+/// A QR code, dark modules `true`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QrCode {
+    size: usize,
+    modules: Vec<bool>,
+}
+
+impl QrCode {
+    /// Encodes `text` in the smallest version it fits, `None` if it's
+    /// longer than a version 10 code holds.
+    pub fn encode(text: &str) -> Option<Self> {
+        let data = text.as_bytes();
+        let version = (1..=MAX_VERSION).find(|&v| data.len() <= capacity(v))?;
+        let mut grid = Grid::new(version);
+        grid.draw_function_patterns();
+        let codewords = interleave(version, &data_codewords(version, data));
+        grid.draw_codewords(&codewords);
+        let mask = (0..8)
+            .min_by_key(|&mask| {
+                let mut masked = grid.clone();
+                masked.apply_mask(mask);
+                masked.draw_format_bits(mask);
+                masked.penalty()
+            })
+            .expect("there are masks");
+        grid.apply_mask(mask);
+        grid.draw_format_bits(mask);
+        Some(Self {
+            size: grid.size,
+            modules: grid.modules,
+        })
+    }
+
+    /// Modules across, without the quiet zone.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub fn is_dark(&self, x: usize, y: usize) -> bool {
+        self.modules[y * self.size + x]
+    }
+
+    /// The code as a grayscale PNG, `scale` pixels per module and with the
+    /// quiet zone around it.
+    pub fn png(&self, scale: u32) -> Vec<u8> {
+        let scale = scale.clamp(1, MAX_SCALE) as usize;
+        let side = (self.size + 2 * QUIET_ZONE) * scale;
+        let mut pixels = Vec::with_capacity((side + 1) * side);
+        for y in 0..side {
+            // each row starts with its filter, none
+            pixels.push(0);
+            let y = (y / scale).checked_sub(QUIET_ZONE);
+            for x in 0..side {
+                let x = (x / scale).checked_sub(QUIET_ZONE);
+                let dark = match (x, y) {
+                    (Some(x), Some(y)) if x < self.size && y < self.size => self.is_dark(x, y),
+                    _ => false,
+                };
+                pixels.push(if dark { 0 } else { 255 });
+            }
+        }
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+        // writing to a Vec can't fail
+        encoder.write_all(&pixels).unwrap();
+        let compressed = encoder.finish().unwrap();
+
+        let mut header = Vec::with_capacity(13);
+        header.extend((side as u32).to_be_bytes());
+        header.extend((side as u32).to_be_bytes());
+        // 8 bit grayscale, deflate, no filtering beyond each row's, not
+        // interlaced
+        header.extend([8, 0, 0, 0, 0]);
+        let mut png = b"\x89PNG\r\n\x1a\n".to_vec();
+        chunk(&mut png, b"IHDR", &header);
+        chunk(&mut png, b"IDAT", &compressed);
+        chunk(&mut png, b"IEND", &[]);
+        png
+    }
+}
+
+fn chunk(png: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    png.extend((data.len() as u32).to_be_bytes());
+    let mut crc = Crc::new();
+    crc.update(kind);
+    crc.update(data);
+    png.extend(kind);
+    png.extend(data);
+    png.extend(crc.sum().to_be_bytes());
+}
+
+/// Modules left for data and error correction once the function patterns
+/// are drawn.
+fn raw_modules(version: usize) -> usize {
+    let mut modules = (16 * version + 128) * version + 64;
+    if version >= 2 {
+        let aligns = version / 7 + 2;
+        modules -= (25 * aligns - 10) * aligns - 55;
+        if version >= 7 {
+            // the two version blocks
+            modules -= 36;
+        }
+    }
+    modules
+}
+
+fn data_codeword_count(version: usize) -> usize {
+    raw_modules(version) / 8 - ECC_PER_BLOCK[version] * BLOCKS[version]
+}
+
+/// Bits of the character count in byte mode.
+fn count_bits(version: usize) -> usize {
+    if version <= 9 {
+        8
+    } else {
+        16
+    }
+}
+
+/// Bytes a code of `version` holds.
+fn capacity(version: usize) -> usize {
+    (data_codeword_count(version) * 8 - 4 - count_bits(version)) / 8
+}
+
+/// The mode, count and bytes of `data`, terminated and padded to the data
+/// codewords of `version`.
+fn data_codewords(version: usize, data: &[u8]) -> Vec<u8> {
+    let mut bits = Bits::default();
+    bits.push(0b0100, 4);
+    bits.push(data.len() as u32, count_bits(version));
+    for &byte in data {
+        bits.push(byte.into(), 8);
+    }
+    let room = data_codeword_count(version) * 8;
+    bits.push(0, 4.min(room - bits.len));
+    bits.push(0, (8 - bits.len % 8) % 8);
+    let mut codewords = bits.bytes;
+    for pad in [0xec, 0x11].into_iter().cycle() {
+        if codewords.len() == room / 8 {
+            break;
+        }
+        codewords.push(pad);
+    }
+    codewords
+}
+
+#[derive(Default)]
+struct Bits {
+    bytes: Vec<u8>,
+    len: usize,
+}
+
+impl Bits {
+    fn push(&mut self, value: u32, count: usize) {
+        for i in (0..count).rev() {
+            if self.len.is_multiple_of(8) {
+                self.bytes.push(0);
+            }
+            let bit = ((value >> i) & 1) as u8;
+            *self.bytes.last_mut().unwrap() |= bit << (7 - self.len % 8);
+            self.len += 1;
+        }
+    }
+}
+
+/// Splits `data` into the blocks of `version`, adds each block's error
+/// correction and interleaves them.
+fn interleave(version: usize, data: &[u8]) -> Vec<u8> {
+    let blocks = BLOCKS[version];
+    let ecc_len = ECC_PER_BLOCK[version];
+    let raw = raw_modules(version) / 8;
+    let short_blocks = blocks - raw % blocks;
+    let short_len = raw / blocks;
+    let divisor = rs_divisor(ecc_len);
+    let mut split = Vec::with_capacity(blocks);
+    let mut taken = 0;
+    for i in 0..blocks {
+        let len = short_len - ecc_len + usize::from(i >= short_blocks);
+        let part = &data[taken..taken + len];
+        taken += len;
+        let mut block = part.to_vec();
+        // a gap in the short blocks, so all line up
+        if i < short_blocks {
+            block.push(0);
+        }
+        block.extend(rs_remainder(part, &divisor));
+        split.push(block);
+    }
+    let mut out = Vec::with_capacity(raw);
+    for i in 0..split[0].len() {
+        for (j, block) in split.iter().enumerate() {
+            if i != short_len - ecc_len || j >= short_blocks {
+                out.push(block[i]);
+            }
+        }
+    }
+    out
+}
+
+/// Multiplies in GF(2^8) modulo x^8 + x^4 + x^3 + x^2 + 1.
+fn gf_mul(x: u8, y: u8) -> u8 {
+    let mut z: u32 = 0;
+    for i in (0..8).rev() {
+        z = (z << 1) ^ ((z >> 7) * 0x11d);
+        z ^= ((u32::from(y) >> i) & 1) * u32::from(x);
+    }
+    z as u8
+}
+
+/// The Reed-Solomon generator polynomial of `degree`, leading term left
+/// out, highest powers first.
+fn rs_divisor(degree: usize) -> Vec<u8> {
+    let mut poly = vec![0u8; degree];
+    poly[degree - 1] = 1;
+    let mut root = 1u8;
+    for _ in 0..degree {
+        for j in 0..degree {
+            poly[j] = gf_mul(poly[j], root);
+            if j + 1 < degree {
+                poly[j] ^= poly[j + 1];
+            }
+        }
+        root = gf_mul(root, 2);
+    }
+    poly
+}
+
+fn rs_remainder(data: &[u8], divisor: &[u8]) -> Vec<u8> {
+    let mut rem = vec![0u8; divisor.len()];
+    for &byte in data {
+        let factor = byte ^ rem.remove(0);
+        rem.push(0);
+        for (r, &d) in rem.iter_mut().zip(divisor) {
+            *r ^= gf_mul(d, factor);
+        }
+    }
+    rem
+}
+
+#[derive(Clone)]
+struct Grid {
+    version: usize,
+    size: usize,
+    modules: Vec<bool>,
+    /// Modules of the finder, timing, alignment, format and version
+    /// patterns, which data and masks leave alone.
+    function: Vec<bool>,
+}
+
+impl Grid {
+    fn new(version: usize) -> Self {
+        let size = version * 4 + 17;
+        Self {
+            version,
+            size,
+            modules: vec![false; size * size],
+            function: vec![false; size * size],
+        }
+    }
+
+    fn set(&mut self, x: usize, y: usize, dark: bool) {
+        self.modules[y * self.size + x] = dark;
+        self.function[y * self.size + x] = true;
+    }
+
+    fn draw_function_patterns(&mut self) {
+        let size = self.size;
+        for i in 0..size {
+            self.set(6, i, i % 2 == 0);
+            self.set(i, 6, i % 2 == 0);
+        }
+        for (x, y) in [(3, 3), (size - 4, 3), (3, size - 4)] {
+            self.draw_finder(x, y);
+        }
+        let aligns = self.alignment_positions();
+        let last = aligns.len().saturating_sub(1);
+        for (i, &x) in aligns.iter().enumerate() {
+            for (j, &y) in aligns.iter().enumerate() {
+                // the corners with finders
+                if (i, j) == (0, 0) || (i, j) == (0, last) || (i, j) == (last, 0) {
+                    continue;
+                }
+                for dy in -2i32..=2 {
+                    for dx in -2i32..=2 {
+                        let dark = dx.abs().max(dy.abs()) != 1;
+                        self.set(offset(x, dx), offset(y, dy), dark);
+                    }
+                }
+            }
+        }
+        // reserved here, drawn once the mask is known
+        self.draw_format_bits(0);
+        self.draw_version();
+    }
+
+    /// A finder centered on `(x, y)`, with its separator.
+    fn draw_finder(&mut self, x: usize, y: usize) {
+        for dy in -4i32..=4 {
+            for dx in -4i32..=4 {
+                let (fx, fy) = (x as i32 + dx, y as i32 + dy);
+                if (0..self.size as i32).contains(&fx) && (0..self.size as i32).contains(&fy) {
+                    let ring = dx.abs().max(dy.abs());
+                    self.set(fx as usize, fy as usize, ring != 2 && ring != 4);
+                }
+            }
+        }
+    }
+
+    fn alignment_positions(&self) -> Vec<usize> {
+        if self.version == 1 {
+            return Vec::new();
+        }
+        let count = self.version / 7 + 2;
+        let step = (self.version * 4 + count * 2 + 1) / (count * 2 - 2) * 2;
+        let mut positions: Vec<usize> = (0..count - 1).map(|i| self.size - 7 - i * step).collect();
+        positions.push(6);
+        positions.reverse();
+        positions
+    }
+
+    /// Both copies of the level M format information for `mask`, and the
+    /// dark module.
+    fn draw_format_bits(&mut self, mask: u32) {
+        // level M is 00
+        let data = mask;
+        let mut rem = data;
+        for _ in 0..10 {
+            rem = (rem << 1) ^ ((rem >> 9) * 0x537);
+        }
+        let bits = ((data << 10) | rem) ^ 0x5412;
+        let bit = |i: usize| (bits >> i) & 1 == 1;
+        let size = self.size;
+        for i in 0..=5 {
+            self.set(8, i, bit(i));
+        }
+        self.set(8, 7, bit(6));
+        self.set(8, 8, bit(7));
+        self.set(7, 8, bit(8));
+        for i in 9..15 {
+            self.set(14 - i, 8, bit(i));
+        }
+        for i in 0..8 {
+            self.set(size - 1 - i, 8, bit(i));
+        }
+        for i in 8..15 {
+            self.set(8, size - 15 + i, bit(i));
+        }
+        self.set(8, size - 8, true);
+    }
+
+    fn draw_version(&mut self) {
+        if self.version < 7 {
+            return;
+        }
+        let mut rem = self.version as u32;
+        for _ in 0..12 {
+            rem = (rem << 1) ^ ((rem >> 11) * 0x1f25);
+        }
+        let bits = ((self.version as u32) << 12) | rem;
+        for i in 0..18 {
+            let dark = (bits >> i) & 1 == 1;
+            let (a, b) = (self.size - 11 + i % 3, i / 3);
+            self.set(a, b, dark);
+            self.set(b, a, dark);
+        }
+    }
+
+    /// Fills the modules outside the function patterns in the zigzag
+    /// order, two columns at a time from the bottom right.
+    fn draw_codewords(&mut self, codewords: &[u8]) {
+        let size = self.size;
+        let mut i = 0;
+        let mut right = size - 1;
+        loop {
+            // the vertical timing pattern takes a column of its own
+            if right == 6 {
+                right = 5;
+            }
+            let upward = ((right + 1) & 2) == 0;
+            for vert in 0..size {
+                let y = if upward { size - 1 - vert } else { vert };
+                for x in [right, right - 1] {
+                    if !self.function[y * size + x] && i < codewords.len() * 8 {
+                        self.modules[y * size + x] = (codewords[i / 8] >> (7 - i % 8)) & 1 == 1;
+                        i += 1;
+                    }
+                }
+            }
+            if right < 2 {
+                break;
+            }
+            right -= 2;
+        }
+    }
+
+    fn apply_mask(&mut self, mask: u32) {
+        for y in 0..self.size {
+            for x in 0..self.size {
+                let flip = match mask {
+                    0 => (x + y) % 2 == 0,
+                    1 => y % 2 == 0,
+                    2 => x % 3 == 0,
+                    3 => (x + y) % 3 == 0,
+                    4 => (x / 3 + y / 2) % 2 == 0,
+                    5 => x * y % 2 + x * y % 3 == 0,
+                    6 => (x * y % 2 + x * y % 3) % 2 == 0,
+                    _ => ((x + y) % 2 + x * y % 3) % 2 == 0,
+                };
+                let i = y * self.size + x;
+                if flip && !self.function[i] {
+                    self.modules[i] = !self.modules[i];
+                }
+            }
+        }
+    }
+
+    /// How hard the code is to scan, by the four rules of the standard:
+    /// long runs, 2x2 blocks, finder lookalikes and imbalance.
+    fn penalty(&self) -> usize {
+        let size = self.size;
+        let dark = |x: usize, y: usize| self.modules[y * size + x];
+        let mut penalty = 0;
+        let lookalikes = [
+            [
+                true, false, true, true, true, false, true, false, false, false, false,
+            ],
+            [
+                false, false, false, false, true, false, true, true, true, false, true,
+            ],
+        ];
+        for across in [true, false] {
+            for a in 0..size {
+                let line: Vec<bool> = (0..size)
+                    .map(|b| if across { dark(b, a) } else { dark(a, b) })
+                    .collect();
+                let mut run = 1;
+                for b in 1..=size {
+                    if b < size && line[b] == line[b - 1] {
+                        run += 1;
+                        continue;
+                    }
+                    if run >= 5 {
+                        penalty += run - 2;
+                    }
+                    run = 1;
+                }
+                for window in line.windows(11) {
+                    if lookalikes.iter().any(|l| window == l) {
+                        penalty += 40;
+                    }
+                }
+            }
+        }
+        for y in 0..size - 1 {
+            for x in 0..size - 1 {
+                let c = dark(x, y);
+                if c == dark(x + 1, y) && c == dark(x, y + 1) && c == dark(x + 1, y + 1) {
+                    penalty += 3;
+                }
+            }
+        }
+        let total = size * size;
+        let darks = self.modules.iter().filter(|&&d| d).count();
+        penalty + (darks * 100 / total).abs_diff(50) / 5 * 10
+    }
+}
+
+fn offset(at: usize, by: i32) -> usize {
+    (at as i32 + by) as usize
+}
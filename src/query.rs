@@ -0,0 +1,112 @@
+use std::{collections::HashSet, str::FromStr};
+
+use url::form_urlencoded;
+
+use crate::ShortenError;
+
+/// Which side wins when the short link's query and the destination's query
+/// carry the same parameter.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum QueryPrecedence {
+    /// The parameters on the short link replace the destination's.
+    Incoming,
+    /// The destination keeps its own values; only new parameters are added.
+    #[default]
+    Destination,
+}
+
+impl FromStr for QueryPrecedence {
+    type Err = ShortenError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "incoming" => Ok(Self::Incoming),
+            "destination" => Ok(Self::Destination),
+            _ => Err(ShortenError::Config(format!(
+                "QUERY_PRECEDENCE must be one of incoming|destination, got {:?}",
+                s
+            ))),
+        }
+    }
+}
+
+/// Appends the `incoming` raw query string to `destination`, merging with any
+/// query it already has.
+///
+/// Parameters are compared by their decoded name but copied over verbatim, so
+/// encoded values (`a=x%26y`) and empty ones (`a=`, `a`) survive untouched.
+/// Repeated names within one side are all kept. A fragment on the destination
+/// stays at the end.
+pub fn merge(destination: &str, incoming: &str, precedence: QueryPrecedence) -> String {
+    let incoming = pairs(incoming);
+    if incoming.is_empty() {
+        return destination.to_string();
+    }
+    let (rest, fragment) = match destination.split_once('#') {
+        Some((rest, fragment)) => (rest, Some(fragment)),
+        None => (destination, None),
+    };
+    let (base, existing) = match rest.split_once('?') {
+        Some((base, query)) => (base, pairs(query)),
+        None => (rest, Vec::new()),
+    };
+
+    let merged: Vec<&str> = match precedence {
+        QueryPrecedence::Incoming => {
+            let names: HashSet<_> = incoming.iter().map(|p| name(p)).collect();
+            existing
+                .into_iter()
+                .filter(|p| !names.contains(&name(p)))
+                .chain(incoming)
+                .collect()
+        }
+        QueryPrecedence::Destination => {
+            let names: HashSet<_> = existing.iter().map(|p| name(p)).collect();
+            let added: Vec<&str> = incoming
+                .into_iter()
+                .filter(|p| !names.contains(&name(p)))
+                .collect();
+            existing.into_iter().chain(added).collect()
+        }
+    };
+
+    let mut url = String::with_capacity(destination.len() + merged.len() * 8);
+    url.push_str(base);
+    if !merged.is_empty() {
+        url.push('?');
+        url.push_str(&merged.join("&"));
+    }
+    if let Some(fragment) = fragment {
+        url.push('#');
+        url.push_str(fragment);
+    }
+    url
+}
+
+fn pairs(query: &str) -> Vec<&str> {
+    query.split('&').filter(|p| !p.is_empty()).collect()
+}
+
+/// Decoded parameter name, so `utm%5Fsource` and `utm_source` collide.
+fn name(pair: &str) -> String {
+    form_urlencoded::parse(pair.as_bytes())
+        .next()
+        .map(|(k, _)| k.into_owned())
+        .unwrap_or_default()
+}
+
+/// Splits parameter `name` off the raw `query`: its first value, decoded,
+/// and the rest of the query, `None` if nothing is left.
+pub fn take(query: &str, name: &str) -> (Option<String>, Option<String>) {
+    let (taken, rest): (Vec<&str>, Vec<&str>) = pairs(query)
+        .into_iter()
+        .partition(|p| self::name(p) == name);
+    let value = taken.first().map(|pair| {
+        form_urlencoded::parse(pair.as_bytes())
+            .next()
+            .map(|(_, v)| v.into_owned())
+            .unwrap_or_default()
+    });
+    let rest = (!rest.is_empty()).then(|| rest.join("&"));
+    (value, rest)
+}
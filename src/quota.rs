@@ -0,0 +1,222 @@
+use chrono::{DateTime, Datelike, Duration, NaiveDate, TimeZone, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+
+use crate::ShortenError;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Period {
+    Day,
+    Month,
+}
+
+impl Period {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Period::Day => "day",
+            Period::Month => "month",
+        }
+    }
+
+    /// First UTC day of the period containing `now`.
+    fn start(self, now: DateTime<Utc>) -> NaiveDate {
+        let today = now.date_naive();
+        match self {
+            Period::Day => today,
+            Period::Month => today.with_day(1).expect("day 1 always exists"),
+        }
+    }
+
+    /// Start of the next period, i.e. when the counter resets.
+    pub fn reset_at(self, now: DateTime<Utc>) -> DateTime<Utc> {
+        let start = self.start(now);
+        let next = match self {
+            Period::Day => start + Duration::days(1),
+            Period::Month => {
+                let (y, m) = if start.month() == 12 {
+                    (start.year() + 1, 1)
+                } else {
+                    (start.year(), start.month() + 1)
+                };
+                NaiveDate::from_ymd_opt(y, m, 1).expect("valid month start")
+            }
+        };
+        Utc.from_utc_datetime(&next.and_hms_opt(0, 0, 0).expect("midnight is valid"))
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, sqlx::FromRow)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub struct Quota {
+    /// `None` means unlimited.
+    pub daily_limit: Option<i64>,
+    pub monthly_limit: Option<i64>,
+}
+
+impl Quota {
+    fn limit(&self, period: Period) -> Option<i64> {
+        match period {
+            Period::Day => self.daily_limit,
+            Period::Month => self.monthly_limit,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub struct Exceeded {
+    pub period: Period,
+    pub limit: i64,
+    pub reset_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub struct PeriodUsage {
+    pub count: i64,
+    pub limit: Option<i64>,
+    pub reset_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub struct Usage {
+    pub day: PeriodUsage,
+    pub month: PeriodUsage,
+}
+
+const PERIODS: [Period; 2] = [Period::Day, Period::Month];
+
+pub async fn init(db: &PgPool) -> Result<(), ShortenError> {
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS quotas (
+            key_id BIGINT PRIMARY KEY,
+            daily_limit BIGINT,
+            monthly_limit BIGINT
+        )",
+    )
+    .execute(db)
+    .await?;
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS key_usage (
+            key_id BIGINT NOT NULL,
+            period TEXT NOT NULL,
+            period_start DATE NOT NULL,
+            count BIGINT NOT NULL,
+            PRIMARY KEY (key_id, period, period_start)
+        )",
+    )
+    .execute(db)
+    .await?;
+    Ok(())
+}
+
+pub async fn get(db: &PgPool, key_id: i64) -> Result<Quota, ShortenError> {
+    let quota = sqlx::query_as("SELECT daily_limit, monthly_limit FROM quotas WHERE key_id = $1")
+        .bind(key_id)
+        .fetch_optional(db)
+        .await?;
+    Ok(quota.unwrap_or_default())
+}
+
+pub async fn set(db: &PgPool, key_id: i64, quota: Quota) -> Result<(), ShortenError> {
+    sqlx::query(
+        "INSERT INTO quotas (key_id, daily_limit, monthly_limit) VALUES ($1, $2, $3)
+         ON CONFLICT (key_id) DO UPDATE
+         SET daily_limit = EXCLUDED.daily_limit, monthly_limit = EXCLUDED.monthly_limit",
+    )
+    .bind(key_id)
+    .bind(quota.daily_limit)
+    .bind(quota.monthly_limit)
+    .execute(db)
+    .await?;
+    Ok(())
+}
+
+/// A creation counted by [`consume`], to be given back with [`release`] if
+/// it doesn't happen after all.
+#[derive(Debug, Clone, Copy)]
+pub struct Reserved {
+    key_id: i64,
+    at: DateTime<Utc>,
+}
+
+/// Counts one creation against every period, or none if any period is
+/// already at its limit. The conditional upsert locks the counter row, so
+/// concurrent callers can't both squeeze under the limit.
+pub async fn consume(db: &PgPool, key_id: i64) -> Result<Result<Reserved, Exceeded>, ShortenError> {
+    let now = Utc::now();
+    let mut tx = db.begin().await?;
+    let quota = get(db, key_id).await?;
+    for period in PERIODS {
+        let limit = quota.limit(period);
+        let counted: Option<(i64,)> = sqlx::query_as(
+            "INSERT INTO key_usage (key_id, period, period_start, count)
+             SELECT $1, $2, $3, 1 WHERE $4::BIGINT IS NULL OR $4 > 0
+             ON CONFLICT (key_id, period, period_start)
+             DO UPDATE SET count = key_usage.count + 1
+             WHERE $4::BIGINT IS NULL OR key_usage.count < $4
+             RETURNING count",
+        )
+        .bind(key_id)
+        .bind(period.as_str())
+        .bind(period.start(now))
+        .bind(limit)
+        .fetch_optional(&mut *tx)
+        .await?;
+        if counted.is_none() {
+            tx.rollback().await?;
+            return Ok(Err(Exceeded {
+                period,
+                limit: limit.unwrap_or_default(),
+                reset_at: period.reset_at(now),
+            }));
+        }
+    }
+    tx.commit().await?;
+    Ok(Ok(Reserved { key_id, at: now }))
+}
+
+/// Takes back the creation counted for `reserved`, from the periods it was
+/// counted in even if they have ended since.
+pub async fn release(db: &PgPool, reserved: Reserved) -> Result<(), ShortenError> {
+    let mut tx = db.begin().await?;
+    for period in PERIODS {
+        sqlx::query(
+            "UPDATE key_usage SET count = count - 1
+             WHERE key_id = $1 AND period = $2 AND period_start = $3 AND count > 0",
+        )
+        .bind(reserved.key_id)
+        .bind(period.as_str())
+        .bind(period.start(reserved.at))
+        .execute(&mut *tx)
+        .await?;
+    }
+    tx.commit().await?;
+    Ok(())
+}
+
+pub async fn usage(db: &PgPool, key_id: i64) -> Result<Usage, ShortenError> {
+    let now = Utc::now();
+    let quota = get(db, key_id).await?;
+    let mut counts = Vec::with_capacity(PERIODS.len());
+    for period in PERIODS {
+        let count: Option<(i64,)> = sqlx::query_as(
+            "SELECT count FROM key_usage WHERE key_id = $1 AND period = $2 AND period_start = $3",
+        )
+        .bind(key_id)
+        .bind(period.as_str())
+        .bind(period.start(now))
+        .fetch_optional(db)
+        .await?;
+        counts.push(PeriodUsage {
+            count: count.map_or(0, |(c,)| c),
+            limit: quota.limit(period),
+            reset_at: period.reset_at(now),
+        });
+    }
+    let month = counts.pop().expect("two periods");
+    let day = counts.pop().expect("two periods");
+    Ok(Usage { day, month })
+}
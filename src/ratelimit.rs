@@ -0,0 +1,43 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// Fixed-window per-IP limiter. Expired windows are swept lazily whenever the
+/// map grows past `SWEEP_AT` entries, so memory stays proportional to the
+/// number of clients seen within one window.
+#[derive(Debug, Clone)]
+pub struct RateLimiter {
+    max: u32,
+    window: Duration,
+    hits: Arc<Mutex<HashMap<IpAddr, (u32, Instant)>>>,
+}
+
+const SWEEP_AT: usize = 10_000;
+
+impl RateLimiter {
+    pub fn new(max: u32, window: Duration) -> Self {
+        Self {
+            max,
+            window,
+            hits: Default::default(),
+        }
+    }
+
+    /// Records a hit for `ip` and returns whether it is within the limit.
+    pub fn check(&self, ip: IpAddr) -> bool {
+        let now = Instant::now();
+        let mut hits = self.hits.lock().unwrap();
+        if hits.len() >= SWEEP_AT {
+            hits.retain(|_, (_, start)| now.duration_since(*start) < self.window);
+        }
+        let entry = hits.entry(ip).or_insert((0, now));
+        if now.duration_since(entry.1) >= self.window {
+            *entry = (0, now);
+        }
+        entry.0 += 1;
+        entry.0 <= self.max
+    }
+}
@@ -0,0 +1,247 @@
+use sqlx::{PgConnection, PgPool};
+use tracing::{info, warn};
+
+use crate::{config::RepairMode, slug::IdGenerator, ShortenError, LINK_TABLES};
+
+/// Fresh ids tried for a row before giving up on the repair.
+const MAX_ATTEMPTS: usize = 20;
+
+pub async fn init(db: &PgPool) -> Result<(), ShortenError> {
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS repairs (
+            id BIGSERIAL PRIMARY KEY,
+            repaired_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+            problem TEXT NOT NULL,
+            old_id TEXT,
+            new_id TEXT,
+            url TEXT NOT NULL
+        )",
+    )
+    .execute(db)
+    .await?;
+    Ok(())
+}
+
+/// What's wrong with a row's id.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Problem {
+    /// NULL or empty, so no path reaches it.
+    Missing,
+    /// Another row has the same id, the oldest one keeping it.
+    Duplicate,
+    /// Characters no slug may have, so no path reaches it either.
+    Unreachable,
+    /// Characters outside the id alphabet. Only reported: existing short
+    /// links still reach it.
+    Foreign,
+}
+
+impl Problem {
+    fn as_str(self) -> &'static str {
+        match self {
+            Problem::Missing => "missing",
+            Problem::Duplicate => "duplicate",
+            Problem::Unreachable => "unreachable",
+            Problem::Foreign => "foreign",
+        }
+    }
+}
+
+/// A row found by the scan, with the id it got unless it's only reported
+/// or this was a dry run.
+#[derive(Debug, Clone)]
+pub struct Repair {
+    pub problem: Problem,
+    pub old_id: Option<String>,
+    pub new_id: Option<String>,
+    pub url: String,
+}
+
+#[derive(Debug, sqlx::FromRow)]
+struct Found {
+    tid: String,
+    id: Option<String>,
+    url: String,
+    problem: String,
+    /// Whether the rows of [`LINK_TABLES`] under its id go with it: those
+    /// of a duplicate id stay with the row keeping it, and of an empty one
+    /// go with the first.
+    carries: bool,
+}
+
+/// Scans `urls` for rows older versions left with an id that's missing,
+/// duplicate or has characters no slug may have, and gives them fresh ids
+/// from `ids`, their url, counts and stats staying as they are. Ids merely
+/// outside `alphabet` are reported and kept. Every change is written to
+/// `repairs`; a dry run only logs what would change.
+pub async fn run(
+    db: &PgPool,
+    mode: RepairMode,
+    ids: &IdGenerator,
+    alphabet: Option<&str>,
+) -> Result<Vec<Repair>, ShortenError> {
+    let mut tx = db.begin().await?;
+    // nothing may add a row with an id picked meanwhile
+    sqlx::query("LOCK TABLE urls IN SHARE ROW EXCLUSIVE MODE")
+        .execute(&mut *tx)
+        .await?;
+    let found = scan(&mut tx, alphabet).await?;
+    if mode == RepairMode::Apply && !found.is_empty() {
+        // fresh ids may not fit the legacy VARCHAR(6)
+        sqlx::query("ALTER TABLE urls ALTER COLUMN id TYPE TEXT")
+            .execute(&mut *tx)
+            .await?;
+    }
+    let mut repairs = Vec::with_capacity(found.len());
+    for found in found {
+        let problem = match found.problem.as_str() {
+            "missing" => Problem::Missing,
+            "duplicate" => Problem::Duplicate,
+            "unreachable" => Problem::Unreachable,
+            _ => Problem::Foreign,
+        };
+        let new_id = match (mode, problem) {
+            (RepairMode::Apply, Problem::Missing | Problem::Duplicate | Problem::Unreachable) => {
+                Some(reassign(&mut tx, ids, &found).await?)
+            }
+            _ => None,
+        };
+        let repair = Repair {
+            problem,
+            old_id: found.id,
+            new_id,
+            url: found.url,
+        };
+        log(mode, &repair);
+        if repair.new_id.is_some() {
+            sqlx::query(
+                "INSERT INTO repairs (problem, old_id, new_id, url) VALUES ($1, $2, $3, $4)",
+            )
+            .bind(problem.as_str())
+            .bind(&repair.old_id)
+            .bind(&repair.new_id)
+            .bind(&repair.url)
+            .execute(&mut *tx)
+            .await?;
+        }
+        repairs.push(repair);
+    }
+    tx.commit().await?;
+    let fixed = repairs.iter().filter(|r| r.problem != Problem::Foreign);
+    match (mode, fixed.count()) {
+        (_, 0) => info!("Repair found no rows with a missing or duplicate id"),
+        (RepairMode::DryRun, n) => info!("Repair would give {} rows new ids", n),
+        (RepairMode::Apply, n) => info!("Repair gave {} rows new ids", n),
+    }
+    Ok(repairs)
+}
+
+async fn scan(conn: &mut PgConnection, alphabet: Option<&str>) -> Result<Vec<Found>, ShortenError> {
+    // alphabets are letters, digits, `-` and `_`; a trailing `-` is literal
+    let foreign = alphabet.map(|a| {
+        let mut class: String = a.chars().filter(|c| *c != '-').collect();
+        if a.contains('-') {
+            class.push('-');
+        }
+        format!("^[{}]+$", class)
+    });
+    let found = sqlx::query_as(
+        "SELECT tid, id, url, problem, n = 1 AS carries FROM (
+            SELECT ctid::TEXT AS tid, id, url,
+                   row_number() OVER (PARTITION BY id ORDER BY created_at, ctid) AS n,
+                   CASE
+                       WHEN id IS NULL OR id = '' THEN 'missing'
+                       WHEN row_number() OVER (PARTITION BY id ORDER BY created_at, ctid) > 1
+                           THEN 'duplicate'
+                       WHEN id !~ '^[A-Za-z0-9_-]+$' THEN 'unreachable'
+                       WHEN $1::TEXT IS NOT NULL AND id !~ $1 THEN 'foreign'
+                   END AS problem
+            FROM urls
+         ) u WHERE problem IS NOT NULL ORDER BY id NULLS FIRST, n",
+    )
+    .bind(foreign)
+    .fetch_all(conn)
+    .await?;
+    Ok(found)
+}
+
+/// Gives row `found` a fresh id, moving what's kept about the old one
+/// along if it carries it.
+async fn reassign(
+    conn: &mut PgConnection,
+    ids: &IdGenerator,
+    found: &Found,
+) -> Result<String, ShortenError> {
+    let mut new_id = None;
+    for _ in 0..MAX_ATTEMPTS {
+        let candidate = ids.generate();
+        let taken: bool = sqlx::query_scalar(
+            "SELECT EXISTS (SELECT 1 FROM urls WHERE id = $1)
+                 OR EXISTS (SELECT 1 FROM slugs WHERE slug = $1)",
+        )
+        .bind(&candidate)
+        .fetch_one(&mut *conn)
+        .await?;
+        if !taken {
+            new_id = Some(candidate);
+            break;
+        }
+    }
+    let Some(new_id) = new_id else {
+        return Err(ShortenError::Config(format!(
+            "no free id for the row of {} after {} attempts",
+            found.url, MAX_ATTEMPTS
+        )));
+    };
+    sqlx::query("UPDATE urls SET id = $2 WHERE ctid = $1::TID")
+        .bind(&found.tid)
+        .bind(&new_id)
+        .execute(&mut *conn)
+        .await?;
+    if let (Some(old), true) = (&found.id, found.carries) {
+        // the slug that was the id itself reached nothing
+        sqlx::query("DELETE FROM slugs WHERE slug = $1")
+            .bind(old)
+            .execute(&mut *conn)
+            .await?;
+        for table in LINK_TABLES {
+            sqlx::query(&format!(
+                "UPDATE {} SET link_id = $2 WHERE link_id = $1",
+                table
+            ))
+            .bind(old)
+            .bind(&new_id)
+            .execute(&mut *conn)
+            .await?;
+        }
+    }
+    sqlx::query("INSERT INTO slugs (slug, link_id) VALUES ($1, $1)")
+        .bind(&new_id)
+        .execute(&mut *conn)
+        .await?;
+    Ok(new_id)
+}
+
+fn log(mode: RepairMode, repair: &Repair) {
+    let old = repair.old_id.as_deref().unwrap_or("NULL");
+    match (mode, &repair.new_id) {
+        (_, Some(new)) => info!(
+            "Repaired a {} id {:?} of {}: now {}",
+            repair.problem.as_str(),
+            old,
+            repair.url,
+            new
+        ),
+        (_, None) if repair.problem == Problem::Foreign => warn!(
+            "Id {:?} of {} is outside the id alphabet, kept",
+            old, repair.url
+        ),
+        (RepairMode::DryRun, None) => info!(
+            "Would repair a {} id {:?} of {}",
+            repair.problem.as_str(),
+            old,
+            repair.url
+        ),
+        (RepairMode::Apply, None) => {}
+    }
+}
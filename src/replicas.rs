@@ -0,0 +1,72 @@
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use sqlx::PgPool;
+use tracing::warn;
+
+/// How long a replica that couldn't be reached is left out of the turns.
+const BACKOFF: Duration = Duration::from_secs(30);
+
+/// The read replicas, taken in turn. One that fails to connect sits out
+/// [`BACKOFF`], so while it's down reads go to the others or the primary
+/// instead of each waiting out its acquire timeout.
+#[derive(Debug, Clone, Default)]
+pub struct Replicas {
+    pools: Vec<PgPool>,
+    /// Which of `pools` the next read goes to.
+    next: Arc<AtomicUsize>,
+    /// Per pool, until when it's left out.
+    down_until: Arc<[Mutex<Option<Instant>>]>,
+}
+
+/// A replica picked for a read, whose failure [`Replicas::failed`] reports.
+#[derive(Debug, Clone, Copy)]
+pub struct Replica<'a> {
+    pub pool: &'a PgPool,
+    index: usize,
+}
+
+impl Replicas {
+    pub fn new(pools: Vec<PgPool>) -> Self {
+        let down_until = pools.iter().map(|_| Mutex::new(None)).collect();
+        Self {
+            pools,
+            next: Arc::default(),
+            down_until,
+        }
+    }
+
+    /// The replica whose turn it is, passing over any sitting out. `None`
+    /// without replicas or while all of them are.
+    pub fn next(&self) -> Option<Replica<'_>> {
+        let now = Instant::now();
+        for _ in 0..self.pools.len() {
+            let index = self.next.fetch_add(1, Ordering::Relaxed) % self.pools.len();
+            let down = self.down_until[index].lock().unwrap();
+            if down.is_none_or(|until| until <= now) {
+                return Some(Replica {
+                    pool: &self.pools[index],
+                    index,
+                });
+            }
+        }
+        None
+    }
+
+    /// Leaves `replica` out for [`BACKOFF`].
+    pub fn failed(&self, replica: Replica<'_>) {
+        let mut down = self.down_until[replica.index].lock().unwrap();
+        if down.is_none_or(|until| until <= Instant::now()) {
+            warn!(
+                "Replica {} unreachable, reading elsewhere for {:?}",
+                replica.index, BACKOFF
+            );
+        }
+        *down = Some(Instant::now() + BACKOFF);
+    }
+}
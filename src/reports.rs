@@ -0,0 +1,110 @@
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use sqlx::PgPool;
+
+use crate::{compress, ShortenError};
+
+#[derive(Debug, Serialize, sqlx::FromRow)]
+#[serde(rename_all = "snake_case")]
+pub struct ReportSummary {
+    pub id: String,
+    pub url: String,
+    pub enabled: bool,
+    pub reports: i64,
+    pub last_reported_at: DateTime<Utc>,
+    pub reasons: Vec<String>,
+    #[serde(skip)]
+    pub url_deflated: Option<Vec<u8>>,
+}
+
+pub async fn init(db: &PgPool) -> Result<(), ShortenError> {
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS reports (
+            link_id TEXT NOT NULL,
+            reporter_ip TEXT NOT NULL,
+            reason TEXT,
+            created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+            UNIQUE (link_id, reporter_ip)
+        )",
+    )
+    .execute(db)
+    .await?;
+    Ok(())
+}
+
+/// Records a report and disables the link once more than `threshold` distinct
+/// IPs reported it within `window`. A repeat report from the same IP only
+/// refreshes its timestamp. Unknown ids are silently ignored.
+///
+/// Returns the report count if this call is the one that disabled the link.
+pub async fn submit(
+    db: &PgPool,
+    id: &str,
+    ip: &str,
+    reason: Option<&str>,
+    threshold: u32,
+    window: Duration,
+) -> Result<Option<i64>, ShortenError> {
+    let inserted = sqlx::query(
+        "INSERT INTO reports (link_id, reporter_ip, reason)
+         SELECT $1, $2, $3 WHERE EXISTS (SELECT 1 FROM urls WHERE id = $1)
+         ON CONFLICT (link_id, reporter_ip)
+         DO UPDATE SET created_at = now(), reason = COALESCE(EXCLUDED.reason, reports.reason)",
+    )
+    .bind(id)
+    .bind(ip)
+    .bind(reason)
+    .execute(db)
+    .await?
+    .rows_affected();
+    if inserted == 0 {
+        return Ok(None);
+    }
+    let (count,): (i64,) = sqlx::query_as(
+        "SELECT COUNT(*) FROM reports
+         WHERE link_id = $1 AND created_at > now() - make_interval(secs => $2)",
+    )
+    .bind(id)
+    .bind(window.as_secs_f64())
+    .fetch_one(db)
+    .await?;
+    if count <= threshold as i64 {
+        return Ok(None);
+    }
+    // only the request that flips the flag reports back, so the notification
+    // fires once
+    let disabled = sqlx::query("UPDATE urls SET enabled = false WHERE id = $1 AND enabled")
+        .bind(id)
+        .execute(db)
+        .await?
+        .rows_affected();
+    Ok((disabled > 0).then_some(count))
+}
+
+pub async fn list(db: &PgPool) -> Result<Vec<ReportSummary>, ShortenError> {
+    let reports = sqlx::query_as(
+        "SELECT u.id, u.url, u.enabled, u.url_deflated, COUNT(*) AS reports,
+                MAX(r.created_at) AS last_reported_at,
+                ARRAY_REMOVE(ARRAY_AGG(r.reason), NULL) AS reasons
+         FROM reports r JOIN urls u ON u.id = r.link_id
+         GROUP BY u.id
+         ORDER BY reports DESC, last_reported_at DESC",
+    )
+    .fetch_all(db)
+    .await?;
+    let mut reports: Vec<ReportSummary> = reports;
+    for report in &mut reports {
+        compress::inflate(&mut report.url, report.url_deflated.take())?;
+    }
+    Ok(reports)
+}
+
+pub async fn dismiss(db: &PgPool, id: &str) -> Result<(), ShortenError> {
+    sqlx::query("DELETE FROM reports WHERE link_id = $1")
+        .bind(id)
+        .execute(db)
+        .await?;
+    Ok(())
+}
@@ -0,0 +1,58 @@
+use std::{future::Future, time::Duration};
+
+use rand::Rng;
+
+use crate::ShortenError;
+
+/// Attempts after the first one fails transiently.
+pub const MAX_RETRIES: u32 = 2;
+/// The first retry waits up to this long, doubling after that.
+const MAX_FIRST_DELAY: Duration = Duration::from_millis(50);
+
+/// Failures that may well not happen again: lost serialization races,
+/// deadlocks and trouble reaching the database.
+pub trait Transient {
+    fn is_transient(&self) -> bool;
+}
+
+impl Transient for sqlx::Error {
+    fn is_transient(&self) -> bool {
+        match self {
+            sqlx::Error::Database(e) => e.code().is_some_and(|code| {
+                // serialization_failure, deadlock_detected, connection_exception
+                code == "40001" || code == "40P01" || code.starts_with("08")
+            }),
+            sqlx::Error::Io(_) | sqlx::Error::PoolTimedOut | sqlx::Error::WorkerCrashed => true,
+            _ => false,
+        }
+    }
+}
+
+impl Transient for ShortenError {
+    fn is_transient(&self) -> bool {
+        matches!(self, ShortenError::SqlError(e) if e.is_transient())
+    }
+}
+
+/// Runs `op`, and again up to `MAX_RETRIES` times while it fails
+/// transiently, after a random delay so clashing callers drift apart. `op`
+/// has to be safe to repeat, e.g. a single statement or a transaction.
+pub async fn transient<T, E, F, Fut>(mut op: F) -> Result<T, E>
+where
+    E: Transient,
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, E>>,
+{
+    let mut max_delay = MAX_FIRST_DELAY;
+    for _ in 0..MAX_RETRIES {
+        match op().await {
+            Err(e) if e.is_transient() => {
+                let delay = rand::thread_rng().gen_range(Duration::ZERO..=max_delay);
+                tokio::time::sleep(delay).await;
+                max_delay *= 2;
+            }
+            ret => return ret,
+        }
+    }
+    op().await
+}
@@ -0,0 +1,40 @@
+use axum::{
+    body::HttpBody,
+    extract::{MatchedPath, Request},
+    http::Method,
+    middleware::Next,
+    response::Response,
+};
+
+/// Counts requests in `requests_total` and records body sizes, labeled by
+/// the route pattern rather than the path so ids don't each get a series.
+/// Requests no route matched count as `unmatched`.
+pub async fn track(req: Request, next: Next) -> Response {
+    let route = req
+        .extensions()
+        .get::<MatchedPath>()
+        .map_or("unmatched", MatchedPath::as_str)
+        .to_string();
+    // anything goes as an extension method, so those share one series
+    let method = match *req.method() {
+        Method::GET
+        | Method::HEAD
+        | Method::POST
+        | Method::PUT
+        | Method::PATCH
+        | Method::DELETE
+        | Method::OPTIONS => req.method().as_str(),
+        _ => "other",
+    };
+    metrics::counter!("requests_total", "route" => route.clone(), "method" => method.to_string())
+        .increment(1);
+    // chunked bodies have no size up front
+    if let Some(size) = req.body().size_hint().exact() {
+        metrics::histogram!("request_size_bytes", "route" => route.clone()).record(size as f64);
+    }
+    let res = next.run(req).await;
+    if let Some(size) = res.body().size_hint().exact() {
+        metrics::histogram!("response_size_bytes", "route" => route).record(size as f64);
+    }
+    res
+}
@@ -0,0 +1,418 @@
+use sqlx::PgPool;
+use tracing::info;
+
+use crate::ShortenError;
+
+/// Columns the queries rely on: table, column, `information_schema` data
+/// type and whether it may be NULL.
+const COLUMNS: &[(&str, &str, &str, bool)] = &[
+    ("urls", "id", "text", false),
+    ("urls", "url", "text", false),
+    ("urls", "enabled", "boolean", false),
+    ("urls", "expires_at", "timestamp with time zone", true),
+    ("urls", "forward_query", "boolean", false),
+    ("urls", "screened", "boolean", false),
+    ("urls", "threat_type", "text", true),
+    ("urls", "clicks", "bigint", false),
+    ("urls", "deduped", "boolean", false),
+    ("urls", "created_at", "timestamp with time zone", false),
+    ("urls", "notes", "text", true),
+    ("urls", "owner", "text", true),
+    ("urls", "description", "text", true),
+    ("urls", "platform_targets", "jsonb", true),
+    ("urls", "url_compressed", "boolean", false),
+    ("urls", "url_deflated", "bytea", true),
+    ("urls", "management_token_hash", "text", true),
+    ("urls", "redirect_status", "smallint", true),
+    ("urls", "max_uses", "integer", true),
+    ("urls", "uses", "integer", false),
+    ("urls", "destinations", "integer", true),
+    ("urls", "click_cap", "bigint", true),
+    ("urls", "geo_targets", "jsonb", true),
+    ("slugs", "slug", "text", false),
+    ("slugs", "link_id", "text", false),
+    ("api_keys", "id", "bigint", false),
+    ("api_keys", "lookup", "text", false),
+    ("api_keys", "key_hash", "text", false),
+    ("api_keys", "label", "text", false),
+    ("api_keys", "scopes", "ARRAY", false),
+    ("api_keys", "created_at", "timestamp with time zone", false),
+    ("api_keys", "revoked_at", "timestamp with time zone", true),
+    ("jobs", "id", "bigint", false),
+    ("jobs", "kind", "text", false),
+    ("jobs", "payload", "jsonb", false),
+    ("jobs", "state", "text", false),
+    ("jobs", "run_at", "timestamp with time zone", false),
+    ("jobs", "attempts", "integer", false),
+    ("jobs", "last_error", "text", true),
+    ("jobs", "locked_at", "timestamp with time zone", true),
+    ("jobs", "created_at", "timestamp with time zone", false),
+    ("quotas", "key_id", "bigint", false),
+    ("quotas", "daily_limit", "bigint", true),
+    ("quotas", "monthly_limit", "bigint", true),
+    ("key_usage", "key_id", "bigint", false),
+    ("key_usage", "period", "text", false),
+    ("key_usage", "period_start", "date", false),
+    ("key_usage", "count", "bigint", false),
+    ("reports", "link_id", "text", false),
+    ("reports", "reporter_ip", "text", false),
+    ("reports", "reason", "text", true),
+    ("reports", "created_at", "timestamp with time zone", false),
+    ("link_tags", "link_id", "text", false),
+    ("link_tags", "tag", "text", false),
+    ("link_urls", "link_id", "text", false),
+    ("link_urls", "idx", "integer", false),
+    ("link_urls", "url", "text", false),
+    ("link_uniques", "link_id", "text", false),
+    ("link_uniques", "day", "date", false),
+    ("link_uniques", "clicks", "bigint", false),
+    ("link_uniques", "registers", "bytea", true),
+    ("link_geo_clicks", "link_id", "text", false),
+    ("link_geo_clicks", "destination", "text", false),
+    ("link_geo_clicks", "clicks", "bigint", false),
+    ("repairs", "id", "bigint", false),
+    ("repairs", "repaired_at", "timestamp with time zone", false),
+    ("repairs", "problem", "text", false),
+    ("repairs", "old_id", "text", true),
+    ("repairs", "new_id", "text", true),
+    ("repairs", "url", "text", false),
+    ("url_history", "id", "bigint", false),
+    ("url_history", "link_id", "text", false),
+    ("url_history", "old_url", "text", false),
+    ("url_history", "new_url", "text", false),
+    (
+        "url_history",
+        "changed_at",
+        "timestamp with time zone",
+        false,
+    ),
+    ("url_history", "actor", "text", false),
+    ("url_history", "first_change", "boolean", false),
+    ("audit_log", "id", "bigint", false),
+    ("audit_log", "actor", "text", false),
+    ("audit_log", "action", "text", false),
+    ("audit_log", "details", "jsonb", false),
+    ("audit_log", "created_at", "timestamp with time zone", false),
+    ("redirect_policies", "id", "bigint", false),
+    ("redirect_policies", "link_id", "text", true),
+    ("redirect_policies", "pattern", "text", true),
+    ("redirect_policies", "reason", "text", true),
+    (
+        "redirect_policies",
+        "created_at",
+        "timestamp with time zone",
+        false,
+    ),
+    ("shadow_diffs", "id", "bigint", false),
+    ("shadow_diffs", "route", "text", false),
+    ("shadow_diffs", "path", "text", false),
+    ("shadow_diffs", "primary_status", "smallint", false),
+    ("shadow_diffs", "shadow_status", "smallint", true),
+    ("shadow_diffs", "primary_location", "text", true),
+    ("shadow_diffs", "shadow_location", "text", true),
+    ("shadow_diffs", "error", "text", true),
+    (
+        "shadow_diffs",
+        "created_at",
+        "timestamp with time zone",
+        false,
+    ),
+    ("link_claims", "link_id", "text", false),
+    ("link_claims", "owner", "text", false),
+    ("link_claims", "token", "text", false),
+    (
+        "link_claims",
+        "created_at",
+        "timestamp with time zone",
+        false,
+    ),
+    ("link_transfers", "link_id", "text", false),
+    ("link_transfers", "from_owner", "text", false),
+    ("link_transfers", "to_owner", "text", false),
+    (
+        "link_transfers",
+        "created_at",
+        "timestamp with time zone",
+        false,
+    ),
+    ("imports", "id", "bigint", false),
+    ("imports", "state", "text", false),
+    ("imports", "total_rows", "integer", false),
+    ("imports", "created_rows", "integer", false),
+    ("imports", "unchanged_rows", "integer", false),
+    ("imports", "failed_rows", "integer", false),
+    ("imports", "error", "text", true),
+    ("imports", "started_at", "timestamp with time zone", false),
+    ("imports", "finished_at", "timestamp with time zone", true),
+    ("import_errors", "import_id", "bigint", false),
+    ("import_errors", "line", "integer", false),
+    ("import_errors", "link_id", "text", false),
+    ("import_errors", "url", "text", false),
+    ("import_errors", "code", "text", false),
+    ("import_errors", "message", "text", false),
+    ("merges", "source", "text", false),
+    ("merges", "state", "text", false),
+    ("merges", "last_id", "text", false),
+    ("merges", "copied", "bigint", false),
+    ("merges", "renamed", "bigint", false),
+    ("merges", "started_at", "timestamp with time zone", false),
+    ("merges", "finished_at", "timestamp with time zone", true),
+    ("merged_ids", "source", "text", false),
+    ("merged_ids", "old_id", "text", false),
+    ("merged_ids", "new_id", "text", false),
+    ("merged_ids", "merged_at", "timestamp with time zone", false),
+    (
+        "metrics_snapshots",
+        "bucket",
+        "timestamp with time zone",
+        false,
+    ),
+    ("metrics_snapshots", "total_links", "bigint", false),
+    ("metrics_snapshots", "total_clicks", "bigint", false),
+    ("metrics_snapshots", "clicks", "bigint", true),
+    ("metrics_snapshots", "new_links", "bigint", false),
+    (
+        "metrics_snapshots",
+        "taken_at",
+        "timestamp with time zone",
+        false,
+    ),
+    ("suspended_owners", "owner", "text", false),
+    ("suspended_owners", "message", "text", true),
+    (
+        "suspended_owners",
+        "suspended_at",
+        "timestamp with time zone",
+        false,
+    ),
+    ("maintenance", "id", "boolean", false),
+    ("maintenance", "enabled", "boolean", false),
+    ("maintenance", "message", "text", true),
+    (
+        "maintenance",
+        "updated_at",
+        "timestamp with time zone",
+        false,
+    ),
+];
+
+/// Primary key and unique constraints: table, kind and comma-separated
+/// columns in key order.
+const CONSTRAINTS: &[(&str, &str, &str)] = &[
+    ("urls", "PRIMARY KEY", "id"),
+    ("slugs", "PRIMARY KEY", "slug"),
+    ("api_keys", "PRIMARY KEY", "id"),
+    ("api_keys", "UNIQUE", "lookup"),
+    ("jobs", "PRIMARY KEY", "id"),
+    ("quotas", "PRIMARY KEY", "key_id"),
+    ("key_usage", "PRIMARY KEY", "key_id,period,period_start"),
+    ("reports", "UNIQUE", "link_id,reporter_ip"),
+    ("link_tags", "PRIMARY KEY", "link_id,tag"),
+    ("link_urls", "PRIMARY KEY", "link_id,idx"),
+    ("link_uniques", "PRIMARY KEY", "link_id,day"),
+    ("link_geo_clicks", "PRIMARY KEY", "link_id,destination"),
+    ("url_history", "PRIMARY KEY", "id"),
+    ("repairs", "PRIMARY KEY", "id"),
+    ("imports", "PRIMARY KEY", "id"),
+    ("import_errors", "PRIMARY KEY", "import_id,line"),
+    ("merges", "PRIMARY KEY", "source"),
+    ("merged_ids", "PRIMARY KEY", "source,old_id"),
+    ("maintenance", "PRIMARY KEY", "id"),
+    ("suspended_owners", "PRIMARY KEY", "owner"),
+    ("metrics_snapshots", "PRIMARY KEY", "bucket"),
+    ("link_claims", "PRIMARY KEY", "link_id,owner"),
+    ("link_transfers", "PRIMARY KEY", "link_id"),
+];
+
+/// Indexes that back an `ON CONFLICT` but aren't constraints: table and
+/// index name.
+const INDEXES: &[(&str, &str)] = &[
+    ("urls", "urls_anonymous_url_deduped"),
+    ("urls", "urls_owner_url_deduped"),
+];
+
+#[derive(Debug, sqlx::FromRow)]
+struct ColumnInfo {
+    table_name: String,
+    column_name: String,
+    data_type: String,
+    character_maximum_length: Option<i32>,
+    is_nullable: String,
+}
+
+impl ColumnInfo {
+    fn describe_type(&self) -> String {
+        match self.character_maximum_length {
+            Some(len) => format!("{}({})", self.data_type, len),
+            None => self.data_type.clone(),
+        }
+    }
+}
+
+/// Compares the live schema against what the queries expect and fails with
+/// every difference found.
+pub async fn verify(db: &PgPool) -> Result<(), ShortenError> {
+    let problems = differences(db).await?;
+    if problems.is_empty() {
+        return Ok(());
+    }
+    Err(ShortenError::Config(format!(
+        "database schema doesn't match, rerun with --fix-schema for the legacy urls table \
+         or --skip-schema-check to start anyway:\n  {}",
+        problems.join("\n  ")
+    )))
+}
+
+/// Like [`verify`], for a schema left to migrations run elsewhere.
+pub async fn verify_migrated(db: &PgPool) -> Result<(), ShortenError> {
+    let problems = differences(db).await?;
+    if problems.is_empty() {
+        return Ok(());
+    }
+    Err(ShortenError::Config(format!(
+        "SKIP_SCHEMA_INIT is set but the database schema doesn't match, apply the \
+         migrations or unset it to have the tables created:\n  {}",
+        problems.join("\n  ")
+    )))
+}
+
+/// What's missing from or different in the live schema. A table that's
+/// missing altogether is one problem, not one per column.
+async fn differences(db: &PgPool) -> Result<Vec<String>, ShortenError> {
+    let columns: Vec<ColumnInfo> = sqlx::query_as(
+        "SELECT table_name::TEXT, column_name::TEXT, data_type::TEXT,
+                character_maximum_length::INT, is_nullable::TEXT
+         FROM information_schema.columns WHERE table_schema = current_schema()",
+    )
+    .fetch_all(db)
+    .await?;
+    let constraints = constraints(db).await?;
+    let indexes: Vec<(String, String)> = sqlx::query_as(
+        "SELECT tablename::TEXT, indexname::TEXT FROM pg_indexes
+         WHERE schemaname = current_schema()",
+    )
+    .fetch_all(db)
+    .await?;
+
+    let mut problems = Vec::new();
+    let mut missing_tables = Vec::new();
+    for &(table, column, data_type, nullable) in COLUMNS {
+        if missing_tables.contains(&table) {
+            continue;
+        }
+        if !columns.iter().any(|c| c.table_name == table) {
+            problems.push(format!("missing table {}", table));
+            missing_tables.push(table);
+            continue;
+        }
+        let Some(actual) = columns
+            .iter()
+            .find(|c| c.table_name == table && c.column_name == column)
+        else {
+            problems.push(format!("missing column {}.{}", table, column));
+            continue;
+        };
+        if actual.data_type != data_type || actual.character_maximum_length.is_some() {
+            problems.push(format!(
+                "{}.{} is {}, expected {}",
+                table,
+                column,
+                actual.describe_type(),
+                data_type
+            ));
+        }
+        if !nullable && actual.is_nullable == "YES" {
+            problems.push(format!("{}.{} allows NULL", table, column));
+        }
+    }
+    for &(table, kind, keys) in CONSTRAINTS {
+        if !missing_tables.contains(&table)
+            && !constraints
+                .iter()
+                .any(|(t, k, c)| t == table && k == kind && c == keys)
+        {
+            problems.push(format!("missing {} ({}) on {}", kind, keys, table));
+        }
+    }
+    for &(table, index) in INDEXES {
+        if !missing_tables.contains(&table)
+            && !indexes.iter().any(|(t, i)| t == table && i == index)
+        {
+            problems.push(format!("missing index {} on {}", index, table));
+        }
+    }
+
+    Ok(problems)
+}
+
+/// Whether `urls` still has the original ad hoc `VARCHAR(6)` id without a
+/// primary key.
+pub async fn is_legacy(db: &PgPool) -> Result<bool, ShortenError> {
+    let (width,): (Option<i32>,) = sqlx::query_as(
+        "SELECT character_maximum_length::INT FROM information_schema.columns
+         WHERE table_schema = current_schema() AND table_name = 'urls' AND column_name = 'id'",
+    )
+    .fetch_one(db)
+    .await?;
+    let has_pk = constraints(db)
+        .await?
+        .iter()
+        .any(|(t, k, _)| t == "urls" && k == "PRIMARY KEY");
+    Ok(width.is_some() || !has_pk)
+}
+
+/// Widens the legacy id to TEXT and makes it the primary key. Refuses,
+/// changing nothing, when existing rows have NULL, empty or duplicate ids.
+pub async fn fix_legacy(db: &PgPool) -> Result<(), ShortenError> {
+    let (nulls, duplicates): (i64, i64) = sqlx::query_as(
+        "SELECT COUNT(*) FILTER (WHERE id IS NULL OR id = ''),
+                COUNT(*) - COUNT(DISTINCT id) - COUNT(*) FILTER (WHERE id IS NULL)
+                    - GREATEST(COUNT(*) FILTER (WHERE id = '') - 1, 0)
+         FROM urls",
+    )
+    .fetch_one(db)
+    .await?;
+    if nulls > 0 || duplicates > 0 {
+        return Err(ShortenError::Config(format!(
+            "can't add a primary key to urls.id: {} rows without an id, {} duplicate ids, \
+             run with --repair to give them new ids",
+            nulls, duplicates
+        )));
+    }
+    let mut tx = db.begin().await?;
+    sqlx::query("ALTER TABLE urls ALTER COLUMN id TYPE TEXT, ALTER COLUMN id SET NOT NULL")
+        .execute(&mut *tx)
+        .await?;
+    let has_pk = sqlx::query(
+        "SELECT 1 FROM information_schema.table_constraints
+         WHERE table_schema = current_schema() AND table_name = 'urls'
+           AND constraint_type = 'PRIMARY KEY'",
+    )
+    .fetch_optional(&mut *tx)
+    .await?
+    .is_some();
+    if !has_pk {
+        sqlx::query("ALTER TABLE urls ADD PRIMARY KEY (id)")
+            .execute(&mut *tx)
+            .await?;
+    }
+    tx.commit().await?;
+    info!("Migrated legacy urls.id to TEXT PRIMARY KEY");
+    Ok(())
+}
+
+async fn constraints(db: &PgPool) -> Result<Vec<(String, String, String)>, ShortenError> {
+    let constraints = sqlx::query_as(
+        "SELECT tc.table_name::TEXT, tc.constraint_type::TEXT,
+                string_agg(k.column_name::TEXT, ',' ORDER BY k.ordinal_position)
+         FROM information_schema.table_constraints tc
+         JOIN information_schema.key_column_usage k
+           USING (constraint_schema, constraint_name, table_name)
+         WHERE tc.table_schema = current_schema()
+           AND tc.constraint_type IN ('PRIMARY KEY', 'UNIQUE')
+         GROUP BY tc.table_name, tc.constraint_type, tc.constraint_name",
+    )
+    .fetch_all(db)
+    .await?;
+    Ok(constraints)
+}
@@ -0,0 +1,335 @@
+use std::{
+    collections::HashSet,
+    path::PathBuf,
+    sync::{Arc, RwLock},
+};
+
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use tracing::{info, warn};
+use url::Url;
+
+use crate::{compress, jobs::Job, ShortenError};
+
+/// Links re-checked per query during a rescreen pass.
+const RESCREEN_BATCH: i64 = 500;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Verdict {
+    Clean,
+    /// Matched a threat list; carries the threat type.
+    Flagged(String),
+    /// The check couldn't be performed. Links are created anyway.
+    Unscreened,
+}
+
+/// Checks destinations against a local blocklist file and, with the
+/// `safe-browsing` feature, Google Safe Browsing.
+#[derive(Debug, Clone)]
+pub struct Screener {
+    blocklist_path: Option<PathBuf>,
+    blocklist: Arc<RwLock<Blocklist>>,
+    #[cfg(feature = "safe-browsing")]
+    safe_browsing: Option<safe_browsing::SafeBrowsing>,
+}
+
+#[derive(Debug, Default)]
+struct Blocklist {
+    domains: HashSet<String>,
+    prefixes: Vec<String>,
+}
+
+/// Re-screens every enabled link. Enqueued periodically from `main`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Rescreen {}
+
+impl Job for Rescreen {
+    const KIND: &'static str = "rescreen";
+}
+
+#[derive(Debug, Serialize, sqlx::FromRow)]
+pub struct FlaggedLink {
+    pub id: String,
+    pub url: String,
+    pub threat_type: String,
+    pub enabled: bool,
+    #[serde(skip)]
+    pub url_deflated: Option<Vec<u8>>,
+}
+
+impl Screener {
+    pub fn new(
+        blocklist_path: Option<PathBuf>,
+        #[allow(unused_variables)] safe_browsing_key: Option<String>,
+    ) -> Result<Self, ShortenError> {
+        let screener = Self {
+            blocklist_path,
+            blocklist: Default::default(),
+            #[cfg(feature = "safe-browsing")]
+            safe_browsing: safe_browsing_key.map(safe_browsing::SafeBrowsing::new),
+        };
+        screener.reload()?;
+        Ok(screener)
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        #[cfg(feature = "safe-browsing")]
+        if self.safe_browsing.is_some() {
+            return true;
+        }
+        self.blocklist_path.is_some()
+    }
+
+    /// Re-reads the blocklist file. Lines containing `://` are url
+    /// prefixes, anything else is a domain that also blocks its subdomains.
+    pub fn reload(&self) -> Result<(), ShortenError> {
+        let Some(path) = &self.blocklist_path else {
+            return Ok(());
+        };
+        let mut list = Blocklist::default();
+        for line in std::fs::read_to_string(path)?.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            if line.contains("://") {
+                list.prefixes.push(line.to_string());
+            } else {
+                list.domains
+                    .insert(line.trim_end_matches('.').to_ascii_lowercase());
+            }
+        }
+        info!(
+            "Loaded blocklist {}: {} domains, {} prefixes",
+            path.display(),
+            list.domains.len(),
+            list.prefixes.len()
+        );
+        *self.blocklist.write().unwrap() = list;
+        Ok(())
+    }
+
+    pub async fn check(&self, url: &str) -> Verdict {
+        self.check_many(&[url]).await.remove(0)
+    }
+
+    pub async fn check_many(&self, urls: &[&str]) -> Vec<Verdict> {
+        #[cfg_attr(not(feature = "safe-browsing"), allow(unused_mut))]
+        let mut verdicts: Vec<Verdict> = urls.iter().map(|u| self.check_local(u)).collect();
+        #[cfg(feature = "safe-browsing")]
+        if let Some(sb) = &self.safe_browsing {
+            let pending: Vec<&str> = urls
+                .iter()
+                .zip(&verdicts)
+                .filter(|(_, v)| **v == Verdict::Clean)
+                .map(|(u, _)| *u)
+                .collect();
+            match sb.lookup(&pending).await {
+                Ok(threats) => {
+                    for (url, verdict) in urls.iter().zip(verdicts.iter_mut()) {
+                        if let Some(threat) = threats.get(*url) {
+                            *verdict = Verdict::Flagged(threat.clone());
+                        }
+                    }
+                }
+                Err(e) => {
+                    warn!("Safe Browsing lookup failed, links left unscreened: {}", e);
+                    for verdict in verdicts.iter_mut().filter(|v| **v == Verdict::Clean) {
+                        *verdict = Verdict::Unscreened;
+                    }
+                }
+            }
+        }
+        let skipped = verdicts
+            .iter()
+            .filter(|v| **v == Verdict::Unscreened)
+            .count();
+        if skipped > 0 {
+            metrics::counter!("screening_skipped_total").increment(skipped as u64);
+        }
+        verdicts
+    }
+
+    fn check_local(&self, url: &str) -> Verdict {
+        let list = self.blocklist.read().unwrap();
+        if list.prefixes.iter().any(|p| url.starts_with(p.as_str())) {
+            return Verdict::Flagged("BLOCKLIST".into());
+        }
+        let Some(host) = Url::parse(url)
+            .ok()
+            .and_then(|u| u.host_str().map(str::to_ascii_lowercase))
+        else {
+            return Verdict::Clean;
+        };
+        // walk up the labels so blocking example.com also covers a.example.com
+        let mut domain = host.as_str();
+        loop {
+            if list.domains.contains(domain) {
+                return Verdict::Flagged("BLOCKLIST".into());
+            }
+            match domain.split_once('.') {
+                Some((_, parent)) => domain = parent,
+                None => return Verdict::Clean,
+            }
+        }
+    }
+
+    /// Re-checks all enabled links, disabling any that are now flagged.
+    pub async fn rescreen(&self, db: &PgPool) -> Result<(), ShortenError> {
+        let mut after = String::new();
+        let mut flagged = 0;
+        loop {
+            let batch: Vec<(String, String, Option<Vec<u8>>)> = sqlx::query_as(
+                "SELECT id, url, url_deflated FROM urls WHERE enabled AND id > $1
+                 ORDER BY id LIMIT $2",
+            )
+            .bind(&after)
+            .bind(RESCREEN_BATCH)
+            .fetch_all(db)
+            .await?;
+            let batch = batch
+                .into_iter()
+                .map(|(id, url, deflated)| Ok((id, compress::load(url, deflated.as_deref())?)))
+                .collect::<Result<Vec<_>, sqlx::Error>>()?;
+            let Some((last, _)) = batch.last() else {
+                break;
+            };
+            after = last.clone();
+            let urls: Vec<&str> = batch.iter().map(|(_, url)| url.as_str()).collect();
+            for ((id, _), verdict) in batch.iter().zip(self.check_many(&urls).await) {
+                match verdict {
+                    Verdict::Flagged(threat) => {
+                        warn!("Link {} flagged as {}, disabling", id, threat);
+                        record(db, id, Some(&threat)).await?;
+                        flagged += 1;
+                    }
+                    Verdict::Clean => record(db, id, None).await?,
+                    Verdict::Unscreened => {}
+                }
+            }
+        }
+        info!("Rescreen finished, {} links flagged", flagged);
+        Ok(())
+    }
+}
+
+/// Stores a screening result; a threat also disables the link.
+pub async fn record(db: &PgPool, id: &str, threat: Option<&str>) -> Result<(), ShortenError> {
+    sqlx::query(
+        "UPDATE urls SET screened = true, threat_type = $2,
+         enabled = enabled AND $2::TEXT IS NULL WHERE id = $1",
+    )
+    .bind(id)
+    .bind(threat)
+    .execute(db)
+    .await?;
+    Ok(())
+}
+
+pub async fn init(db: &PgPool) -> Result<(), ShortenError> {
+    sqlx::query(
+        "ALTER TABLE urls ADD COLUMN IF NOT EXISTS screened BOOLEAN NOT NULL DEFAULT false,
+         ADD COLUMN IF NOT EXISTS threat_type TEXT",
+    )
+    .execute(db)
+    .await?;
+    Ok(())
+}
+
+pub async fn list_flagged(db: &PgPool) -> Result<Vec<FlaggedLink>, ShortenError> {
+    let links = sqlx::query_as(
+        "SELECT id, url, threat_type, enabled, url_deflated FROM urls
+         WHERE threat_type IS NOT NULL ORDER BY id",
+    )
+    .fetch_all(db)
+    .await?;
+    let mut links: Vec<FlaggedLink> = links;
+    for link in &mut links {
+        compress::inflate(&mut link.url, link.url_deflated.take())?;
+    }
+    Ok(links)
+}
+
+#[cfg(feature = "safe-browsing")]
+mod safe_browsing {
+    use std::{collections::HashMap, time::Duration};
+
+    use reqwest::Client;
+    use serde::{Deserialize, Serialize};
+    use serde_json::json;
+
+    const ENDPOINT: &str = "https://safebrowsing.googleapis.com/v4/threatMatches:find";
+    /// The API accepts at most 500 entries per request.
+    const MAX_ENTRIES: usize = 500;
+
+    #[derive(Debug, Clone)]
+    pub struct SafeBrowsing {
+        client: Client,
+        key: String,
+    }
+
+    #[derive(Deserialize)]
+    struct Response {
+        #[serde(default)]
+        matches: Vec<Match>,
+    }
+
+    #[derive(Deserialize)]
+    #[serde(rename_all = "camelCase")]
+    struct Match {
+        threat_type: String,
+        threat: Entry,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    struct Entry {
+        url: String,
+    }
+
+    impl SafeBrowsing {
+        pub fn new(key: String) -> Self {
+            let client = Client::builder()
+                .timeout(Duration::from_secs(5))
+                .build()
+                .expect("failed to build http client");
+            Self { client, key }
+        }
+
+        /// Returns the threat type for each matching url.
+        pub async fn lookup(
+            &self,
+            urls: &[&str],
+        ) -> Result<HashMap<String, String>, reqwest::Error> {
+            let mut threats = HashMap::new();
+            for chunk in urls.chunks(MAX_ENTRIES) {
+                let entries: Vec<Entry> =
+                    chunk.iter().map(|u| Entry { url: u.to_string() }).collect();
+                let body = json!({
+                    "client": { "clientId": "shortener", "clientVersion": env!("CARGO_PKG_VERSION") },
+                    "threatInfo": {
+                        "threatTypes": ["MALWARE", "SOCIAL_ENGINEERING", "UNWANTED_SOFTWARE", "POTENTIALLY_HARMFUL_APPLICATION"],
+                        "platformTypes": ["ANY_PLATFORM"],
+                        "threatEntryTypes": ["URL"],
+                        "threatEntries": entries,
+                    }
+                });
+                let res: Response = self
+                    .client
+                    .post(ENDPOINT)
+                    .query(&[("key", &self.key)])
+                    .json(&body)
+                    .send()
+                    .await?
+                    .error_for_status()?
+                    .json()
+                    .await?;
+                threats.extend(
+                    res.matches
+                        .into_iter()
+                        .map(|m| (m.threat.url, m.threat_type)),
+                );
+            }
+            Ok(threats)
+        }
+    }
+}
@@ -0,0 +1,70 @@
+use std::time::Duration;
+
+use nanoid::nanoid;
+
+use crate::{NewLink, PgState, RedirectOutcome, ShortenError};
+
+/// Creates a link, resolves it, checks it points where it should and deletes
+/// it again, all through the same store methods the server uses. The link is
+/// removed even when a check fails.
+pub async fn run(db: &PgState) -> Result<(), ShortenError> {
+    let url = format!("https://selftest.invalid/{}", nanoid!());
+    let created = db
+        .shorten(NewLink {
+            url: &url,
+            expires_at: None,
+            forward_query: true,
+            dedupe: false,
+            notes: Some("created by shortener selftest"),
+            owner: None,
+            alias: None,
+            description: None,
+            signed: false,
+            platform_targets: None,
+            geo_targets: None,
+            management_token_hash: None,
+            redirect_status: None,
+            max_uses: None,
+            urls: &[],
+            click_cap: None,
+        })
+        .await?;
+    let checked = check(db, &created.id, &url).await;
+    let deleted = db.delete(&created.id).await;
+    checked?;
+    if !deleted? {
+        return Err(ShortenError::SelfTest(format!(
+            "link {} was gone before it could be deleted",
+            created.id
+        )));
+    }
+    if db.get_link(&created.id).await?.is_some() {
+        return Err(ShortenError::SelfTest(format!(
+            "link {} still resolves after deletion",
+            created.id
+        )));
+    }
+    Ok(())
+}
+
+async fn check(db: &PgState, id: &str, url: &str) -> Result<(), ShortenError> {
+    let link = db.get_link(id).await?;
+    match RedirectOutcome::of(link.as_ref(), Duration::ZERO) {
+        RedirectOutcome::Found => {}
+        outcome => {
+            return Err(ShortenError::SelfTest(format!(
+                "new link {} resolved as {}",
+                id,
+                outcome.as_str()
+            )))
+        }
+    }
+    let target = link.map(|l| l.url).unwrap_or_default();
+    if target != url {
+        return Err(ShortenError::SelfTest(format!(
+            "link {} points to {:?} instead of {:?}",
+            id, target, url
+        )));
+    }
+    Ok(())
+}
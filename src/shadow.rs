@@ -0,0 +1,287 @@
+use std::{
+    net::{IpAddr, Ipv4Addr},
+    sync::Arc,
+    time::Duration,
+};
+
+use axum::{
+    body::{Body, Bytes},
+    extract::{Request, State},
+    http::{
+        header::{CONTENT_TYPE, LOCATION, USER_AGENT},
+        HeaderValue, Method, StatusCode,
+    },
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use chrono::{DateTime, Utc};
+use rand::Rng;
+use reqwest::{redirect::Policy, Client};
+use serde::Serialize;
+use sqlx::PgPool;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tracing::warn;
+
+use crate::{
+    config::{Config, ShadowRoute},
+    error::StatusCodeError,
+    ratelimit::RateLimiter,
+    ShortenError,
+};
+
+/// Marks mirrored requests, which a shadow that mirrors too passes on no
+/// further.
+pub const SHADOW_HEADER: &str = "x-shortener-shadow";
+
+const SHADOW_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Mirrored requests awaiting the shadow's answer at most. A slow shadow
+/// has the rest go unmirrored instead of piling up.
+const MAX_IN_FLIGHT: usize = 64;
+
+pub async fn init(db: &PgPool) -> Result<(), ShortenError> {
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS shadow_diffs (
+            id BIGSERIAL PRIMARY KEY,
+            route TEXT NOT NULL,
+            path TEXT NOT NULL,
+            primary_status SMALLINT NOT NULL,
+            shadow_status SMALLINT,
+            primary_location TEXT,
+            shadow_location TEXT,
+            error TEXT,
+            created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+        )",
+    )
+    .execute(db)
+    .await?;
+    Ok(())
+}
+
+/// A mirrored request the shadow answered differently, or not at all, in
+/// which case `error` says why.
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+#[serde(rename_all = "snake_case")]
+pub struct Diff {
+    pub id: i64,
+    pub route: String,
+    pub path: String,
+    pub primary_status: i16,
+    pub shadow_status: Option<i16>,
+    pub primary_location: Option<String>,
+    pub shadow_location: Option<String>,
+    pub error: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+/// What's compared of an answer: its status and, for redirects, where it
+/// sends the client.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Answer {
+    status: u16,
+    location: Option<String>,
+}
+
+impl Answer {
+    fn of(status: StatusCode, location: Option<&HeaderValue>) -> Self {
+        Self {
+            status: status.as_u16(),
+            location: location.and_then(|l| l.to_str().ok()).map(String::from),
+        }
+    }
+}
+
+/// Mirrors a sample of the redirect and shorten requests to another
+/// deployment, `SHADOW_BASE_URL`, and records where it answers otherwise.
+///
+/// The client gets its answer as if there were no shadow: the mirrored
+/// request is sent once that's ready, in the background, and dropped if
+/// it would exceed `SHADOW_MAX_PER_SEC` or too many are still waiting on
+/// the shadow. API keys aren't passed on, so the shadow sees anonymous
+/// requests.
+#[derive(Debug, Clone)]
+pub struct Shadow {
+    client: Client,
+    base: Arc<str>,
+    percent: f64,
+    routes: Vec<ShadowRoute>,
+    limiter: RateLimiter,
+    in_flight: Arc<Semaphore>,
+    max_body_bytes: usize,
+    db: PgPool,
+}
+
+impl Shadow {
+    /// `None` unless `SHADOW_BASE_URL` is set.
+    pub fn new(config: &Config, db: PgPool) -> Option<Self> {
+        let base = config.shadow_base_url.as_deref()?;
+        let client = Client::builder()
+            .timeout(SHADOW_TIMEOUT)
+            // the redirect itself is what's compared
+            .redirect(Policy::none())
+            .build()
+            .expect("failed to build http client");
+        Some(Self {
+            client,
+            base: base.trim_end_matches('/').into(),
+            percent: config.shadow_sample_percent,
+            routes: config.shadow_routes.clone(),
+            limiter: RateLimiter::new(config.shadow_max_per_sec, Duration::from_secs(1)),
+            in_flight: Arc::new(Semaphore::new(MAX_IN_FLIGHT)),
+            max_body_bytes: config.max_body_bytes,
+            db,
+        })
+    }
+
+    /// Whether `req`, a request of a handler of `route`, is one to mirror
+    /// if sampled.
+    fn wants(&self, route: ShadowRoute, req: &Request) -> bool {
+        let method = match route {
+            ShadowRoute::Redirect => Method::GET,
+            ShadowRoute::Shorten => Method::POST,
+        };
+        req.method() == method
+            && self.routes.contains(&route)
+            && !req.headers().contains_key(SHADOW_HEADER)
+    }
+
+    /// Whether to mirror a request, holding its place among those in flight
+    /// if so.
+    fn admit(&self) -> Option<OwnedSemaphorePermit> {
+        if !rand::thread_rng().gen_bool(self.percent / 100.0) {
+            return None;
+        }
+        // one window for every mirrored request, whoever sent it
+        let admitted = self
+            .limiter
+            .check(IpAddr::V4(Ipv4Addr::UNSPECIFIED))
+            .then(|| self.in_flight.clone().try_acquire_owned().ok())
+            .flatten();
+        if admitted.is_none() {
+            metrics::counter!("shadow_requests_total", "outcome" => "dropped").increment(1);
+        }
+        admitted
+    }
+
+    async fn compare(
+        &self,
+        route: ShadowRoute,
+        path: String,
+        user_agent: Option<HeaderValue>,
+        body: Option<Bytes>,
+        primary: Answer,
+    ) {
+        let url = format!("{}{}", self.base, path);
+        let mut req = match body {
+            Some(body) => self
+                .client
+                .post(url)
+                .header(CONTENT_TYPE, "application/json")
+                .body(body),
+            None => self.client.get(url),
+        };
+        req = req.header(SHADOW_HEADER, "1");
+        if let Some(user_agent) = user_agent {
+            req = req.header(USER_AGENT, user_agent);
+        }
+        let (shadow, error) = match req.send().await {
+            Ok(res) => (
+                Some(Answer::of(res.status(), res.headers().get(LOCATION))),
+                None,
+            ),
+            Err(e) => (None, Some(e.to_string())),
+        };
+        // links made on the shadow have ids of their own
+        let same = shadow.as_ref().is_some_and(|shadow| match route {
+            ShadowRoute::Redirect => *shadow == primary,
+            ShadowRoute::Shorten => shadow.status == primary.status,
+        });
+        let outcome = match (&error, same) {
+            (Some(_), _) => "failed",
+            (None, true) => "matched",
+            (None, false) => "differed",
+        };
+        metrics::counter!("shadow_requests_total", "outcome" => outcome).increment(1);
+        if same {
+            return;
+        }
+        let (shadow_status, shadow_location) = match shadow {
+            Some(shadow) => (Some(shadow.status as i16), shadow.location),
+            None => (None, None),
+        };
+        let recorded = sqlx::query(
+            "INSERT INTO shadow_diffs (route, path, primary_status, shadow_status,
+                primary_location, shadow_location, error)
+             VALUES ($1, $2, $3, $4, $5, $6, $7)",
+        )
+        .bind(route.as_str())
+        .bind(&path)
+        .bind(primary.status as i16)
+        .bind(shadow_status)
+        .bind(&primary.location)
+        .bind(&shadow_location)
+        .bind(&error)
+        .execute(&self.db)
+        .await;
+        if let Err(e) = recorded {
+            warn!("Failed to record shadow diff for {}: {}", path, e);
+        }
+    }
+}
+
+/// Mirrors the sampled requests of the handlers of `route`, whichever
+/// path they are mounted at.
+pub async fn mirror(
+    State((shadow, route)): State<(Shadow, ShadowRoute)>,
+    req: Request,
+    next: Next,
+) -> Response {
+    if !shadow.wants(route, &req) {
+        return next.run(req).await;
+    }
+    let Some(permit) = shadow.admit() else {
+        return next.run(req).await;
+    };
+    let (parts, body) = req.into_parts();
+    // the body goes to both, so it's read up front, up to the limit the
+    // handler would have stopped at
+    let (req, body) = match route {
+        ShadowRoute::Shorten => match axum::body::to_bytes(body, shadow.max_body_bytes).await {
+            Ok(bytes) => (
+                Request::from_parts(parts, Body::from(bytes.clone())),
+                Some(bytes),
+            ),
+            Err(_) => {
+                return ShortenError::from(StatusCodeError(StatusCode::PAYLOAD_TOO_LARGE))
+                    .into_response()
+            }
+        },
+        ShadowRoute::Redirect => (Request::from_parts(parts, body), None),
+    };
+    let path = req
+        .uri()
+        .path_and_query()
+        .map_or("/", |p| p.as_str())
+        .to_string();
+    let user_agent = req.headers().get(USER_AGENT).cloned();
+    let res = next.run(req).await;
+    let primary = Answer::of(res.status(), res.headers().get(LOCATION));
+    tokio::spawn(async move {
+        let _permit = permit;
+        shadow.compare(route, path, user_agent, body, primary).await;
+    });
+    res
+}
+
+/// The latest `limit` diffs, newest first.
+pub async fn latest(db: &PgPool, limit: i64) -> Result<Vec<Diff>, ShortenError> {
+    let diffs = sqlx::query_as(
+        "SELECT id, route, path, primary_status, shadow_status, primary_location,
+            shadow_location, error, created_at
+         FROM shadow_diffs ORDER BY id DESC LIMIT $1",
+    )
+    .bind(limit)
+    .fetch_all(db)
+    .await?;
+    Ok(diffs)
+}
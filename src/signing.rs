@@ -0,0 +1,153 @@
+use std::fmt;
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+/// Separates a signed link's id from its signature. Never part of a
+/// generated id or an alias.
+pub const SEPARATOR: char = '.';
+/// Bytes of the HMAC kept in the slug, as twice as many hex digits.
+const SIGNATURE_BYTES: usize = 8;
+
+/// Signs ids into `<id>.<signature>` slugs that only this server can mint,
+/// so signed links can be turned away without a database lookup unless
+/// they were issued by us.
+///
+/// New links are signed with the current key. Slugs signed with the
+/// previous one keep validating so links survive a key rotation.
+#[derive(Clone)]
+pub struct Signer {
+    /// Current key first.
+    keys: Vec<Hmac<Sha256>>,
+    can_sign: bool,
+}
+
+impl Signer {
+    pub fn new(current: Option<&str>, previous: Option<&str>) -> Self {
+        let keys = [current, previous]
+            .into_iter()
+            .flatten()
+            .map(|key| Hmac::new_from_slice(key.as_bytes()).expect("HMAC takes any key length"))
+            .collect();
+        Self {
+            keys,
+            can_sign: current.is_some(),
+        }
+    }
+
+    pub fn can_sign(&self) -> bool {
+        self.can_sign
+    }
+
+    /// The signed slug for `id`, `None` without a current key.
+    pub fn sign(&self, id: &str) -> Option<String> {
+        if !self.can_sign {
+            return None;
+        }
+        let mut mac = self.keys[0].clone();
+        mac.update(id.as_bytes());
+        let tag = mac.finalize().into_bytes();
+        let hex: String = tag[..SIGNATURE_BYTES]
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+        Some(format!("{}{}{}", id, SEPARATOR, hex))
+    }
+
+    /// Whether `slug` is a signed slug issued with the current or previous
+    /// key.
+    pub fn verify(&self, slug: &str) -> bool {
+        let Some((id, hex)) = slug.rsplit_once(SEPARATOR) else {
+            return false;
+        };
+        if hex.len() != SIGNATURE_BYTES * 2 {
+            return false;
+        }
+        let Some(tag) = decode_hex(hex) else {
+            return false;
+        };
+        self.keys.iter().any(|key| {
+            let mut mac = key.clone();
+            mac.update(id.as_bytes());
+            mac.verify_truncated_left(&tag).is_ok()
+        })
+    }
+}
+
+impl fmt::Debug for Signer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Signer")
+            .field("keys", &self.keys.len())
+            .field("can_sign", &self.can_sign)
+            .finish()
+    }
+}
+
+/// Response header of a signed redirect, see [`ResponseSigner`].
+pub const SIGNATURE_HEADER: &str = "x-shortener-signature";
+
+/// Signs redirects so a proxy in front that alters or makes one up can be
+/// caught: the header is `t=<unix seconds>,v1=<hex>`, an HMAC-SHA256 of the
+/// id, destination, status code and that timestamp.
+#[derive(Clone)]
+pub struct ResponseSigner {
+    key: Hmac<Sha256>,
+}
+
+impl ResponseSigner {
+    pub fn new(key: &str) -> Self {
+        Self {
+            key: Hmac::new_from_slice(key.as_bytes()).expect("HMAC takes any key length"),
+        }
+    }
+
+    /// The header value for a redirect of `id` to `url` with `status` at
+    /// `timestamp`.
+    pub fn sign(&self, id: &str, url: &str, status: u16, timestamp: i64) -> String {
+        let tag = self.mac(id, url, status, timestamp).finalize().into_bytes();
+        let hex: String = tag.iter().map(|b| format!("{:02x}", b)).collect();
+        format!("t={},v1={}", timestamp, hex)
+    }
+
+    /// Whether `signature`, the hex of the header's `v1`, was made by us
+    /// for these fields.
+    pub fn verify(
+        &self,
+        id: &str,
+        url: &str,
+        status: u16,
+        timestamp: i64,
+        signature: &str,
+    ) -> bool {
+        decode_hex(signature).is_some_and(|tag| {
+            self.mac(id, url, status, timestamp)
+                .verify_slice(&tag)
+                .is_ok()
+        })
+    }
+
+    fn mac(&self, id: &str, url: &str, status: u16, timestamp: i64) -> Hmac<Sha256> {
+        let mut mac = self.key.clone();
+        // none of the fields can hold a newline, so the message splits one way
+        mac.update(format!("{}\n{}\n{}\n{}", id, url, status, timestamp).as_bytes());
+        mac
+    }
+}
+
+impl fmt::Debug for ResponseSigner {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ResponseSigner").finish_non_exhaustive()
+    }
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    // lowercase only, so each signature has exactly one spelling
+    if !hex.len().is_multiple_of(2) || !hex.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+    {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+        .collect()
+}
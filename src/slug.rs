@@ -0,0 +1,367 @@
+use std::{
+    fmt,
+    str::FromStr,
+    sync::{Arc, Mutex},
+};
+
+use axum::{
+    async_trait,
+    extract::{FromRequestParts, Path},
+    http::{request::Parts, StatusCode},
+};
+use rand::{rngs::StdRng, seq::SliceRandom, Rng, SeedableRng};
+
+use crate::{aliases::MAX_ALIAS_LEN, AppState, ShortenError, StatusCodeError};
+
+/// nanoid's own.
+pub const DEFAULT_ALPHABET: &str =
+    "_-0123456789abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ";
+pub const DEFAULT_ID_LEN: usize = 6;
+/// Shortest generated id allowed, below which even the default alphabet
+/// runs out of ids within a few million links.
+pub const MIN_ID_LEN: usize = 4;
+/// Fewest distinct characters an id alphabet may have. With fewer, short
+/// ids run out soon and generation keeps colliding.
+pub const MIN_ALPHABET_LEN: usize = 16;
+/// Longest id accepted from a custom generator, whose output can't be known
+/// up front.
+const MAX_CUSTOM_LEN: usize = 256;
+const ADJECTIVES: &str = include_str!("words/adjectives.txt");
+const NOUNS: &str = include_str!("words/nouns.txt");
+
+/// How new link ids are generated.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum IdStrategy {
+    /// Random characters of the configured alphabet, by default six
+    /// url-safe ones.
+    #[default]
+    Nanoid,
+    /// Dictionary words and a two digit number, e.g. `brave-otter-42`.
+    Words,
+}
+
+impl FromStr for IdStrategy {
+    type Err = ShortenError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "nanoid" => Ok(Self::Nanoid),
+            "words" => Ok(Self::Words),
+            _ => Err(ShortenError::Config(format!(
+                "ID_STRATEGY must be one of nanoid|words, got {:?}",
+                s
+            ))),
+        }
+    }
+}
+
+/// The case of generated ids, set by `ID_CASE`. Unless it's `Mixed`,
+/// slugs are looked up in it too, so they resolve however they're typed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum IdCase {
+    Lower,
+    /// The alphabet as it is, upper and lower case telling ids apart.
+    #[default]
+    Mixed,
+    Upper,
+}
+
+impl FromStr for IdCase {
+    type Err = ShortenError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "lower" => Ok(Self::Lower),
+            "mixed" => Ok(Self::Mixed),
+            "upper" => Ok(Self::Upper),
+            _ => Err(ShortenError::Config(format!(
+                "ID_CASE must be one of lower|mixed|upper, got {:?}",
+                s
+            ))),
+        }
+    }
+}
+
+impl IdCase {
+    /// `slug` in this case.
+    pub fn apply(self, slug: &str) -> String {
+        match self {
+            Self::Lower => slug.to_ascii_lowercase(),
+            Self::Mixed => slug.to_string(),
+            Self::Upper => slug.to_ascii_uppercase(),
+        }
+    }
+
+    /// `alphabet` in this case with every character once, so ids drawn
+    /// from it stay uniformly random.
+    pub fn alphabet(self, alphabet: &str) -> String {
+        let mut folded = String::with_capacity(alphabet.len());
+        for c in self.apply(alphabet).chars() {
+            if !folded.contains(c) {
+                folded.push(c);
+            }
+        }
+        folded
+    }
+}
+
+/// Produces candidate ids; uniqueness is checked by the caller.
+#[derive(Clone)]
+pub struct IdGenerator {
+    source: Source,
+    case: IdCase,
+    /// Drawn from instead of the OS-seeded generator when seeded, which
+    /// clones share.
+    seeded: Option<Arc<Mutex<StdRng>>>,
+}
+
+#[derive(Clone)]
+enum Source {
+    Nanoid {
+        alphabet: Arc<[char]>,
+        len: usize,
+    },
+    /// `words` words, the last of which is a noun.
+    Words {
+        words: usize,
+        separator: String,
+    },
+    Custom(Arc<dyn Fn() -> String + Send + Sync>),
+}
+
+impl IdGenerator {
+    /// `alphabet` and `len` are for the nanoid strategy, `words` and
+    /// `separator` for the words one. See [`validate_id_config`].
+    pub fn new(
+        strategy: IdStrategy,
+        alphabet: &str,
+        len: usize,
+        words: usize,
+        separator: String,
+    ) -> Self {
+        let source = match strategy {
+            IdStrategy::Nanoid => Source::Nanoid {
+                alphabet: alphabet.chars().collect(),
+                len,
+            },
+            IdStrategy::Words => Source::Words {
+                words: words.max(1),
+                separator,
+            },
+        };
+        Self {
+            source,
+            case: IdCase::Mixed,
+            seeded: None,
+        }
+    }
+
+    /// Uses `f` to produce candidates, e.g. to plug in an external scheme.
+    pub fn custom(f: impl Fn() -> String + Send + Sync + 'static) -> Self {
+        Self {
+            source: Source::Custom(Arc::new(f)),
+            case: IdCase::Mixed,
+            seeded: None,
+        }
+    }
+
+    /// Draws ids from a generator seeded with `seed`, so the same seed
+    /// always gives the same sequence of ids. For tests only: anyone who
+    /// knows the seed can tell every id. Custom sources ignore it.
+    pub fn seeded(mut self, seed: u64) -> Self {
+        self.seeded = Some(Arc::new(Mutex::new(StdRng::seed_from_u64(seed))));
+        self
+    }
+
+    /// Generates ids in `case`, drawing nanoid ones from the alphabet in
+    /// that case.
+    pub fn with_case(mut self, case: IdCase) -> Self {
+        if let Source::Nanoid { alphabet, .. } = &mut self.source {
+            let chars: String = alphabet.iter().collect();
+            *alphabet = case.alphabet(&chars).chars().collect();
+        }
+        self.case = case;
+        self
+    }
+
+    pub fn case(&self) -> IdCase {
+        self.case
+    }
+
+    /// Whether `slug` could be an id or alias at all, so garbage can be
+    /// turned away without a database lookup. Nanoid characters are always
+    /// allowed so ids from before a strategy change keep resolving.
+    pub fn is_plausible(&self, slug: &str) -> bool {
+        let (max_len, separator) = match &self.source {
+            Source::Nanoid { len, .. } => (*len, ""),
+            Source::Words { words, separator } => {
+                let longest = ADJECTIVES.lines().chain(NOUNS.lines()).map(str::len).max();
+                // the words, their separators and the two digit number
+                let len = words * (longest.unwrap_or(0) + separator.len()) + 2;
+                (len, separator.as_str())
+            }
+            Source::Custom(_) => {
+                return !slug.is_empty()
+                    && slug.len() <= MAX_CUSTOM_LEN
+                    && slug.bytes().all(|b| b.is_ascii_graphic());
+            }
+        };
+        !slug.is_empty()
+            && slug.len() <= max_len.max(MAX_ALIAS_LEN)
+            && slug.bytes().all(|b| {
+                b.is_ascii_alphanumeric()
+                    || b == b'-'
+                    || b == b'_'
+                    || separator.as_bytes().contains(&b)
+            })
+    }
+
+    pub fn generate(&self) -> String {
+        let id = match (&self.source, &self.seeded) {
+            // the alphabet is in the case already
+            (Source::Nanoid { alphabet, len }, None) => {
+                return nanoid::format(nanoid::rngs::default, alphabet, *len)
+            }
+            (Source::Nanoid { alphabet, len }, Some(rng)) => {
+                let mut rng = rng.lock().unwrap();
+                return (0..*len)
+                    .map(|_| alphabet[rng.gen_range(0..alphabet.len())])
+                    .collect();
+            }
+            (Source::Words { words, separator }, None) => {
+                words_slug(&mut rand::thread_rng(), *words, separator)
+            }
+            (Source::Words { words, separator }, Some(rng)) => {
+                words_slug(&mut *rng.lock().unwrap(), *words, separator)
+            }
+            (Source::Custom(f), _) => f(),
+        };
+        match self.case {
+            IdCase::Mixed => id,
+            case => case.apply(&id),
+        }
+    }
+}
+
+impl fmt::Debug for IdGenerator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let source = match &self.source {
+            Source::Nanoid { .. } => "nanoid",
+            Source::Words { .. } => "words",
+            Source::Custom(_) => "custom",
+        };
+        f.debug_struct("IdGenerator")
+            .field("source", &source)
+            .field("case", &self.case)
+            .field("seeded", &self.seeded.is_some())
+            .finish()
+    }
+}
+
+/// Checks the alphabet and length of nanoid ids: at least
+/// [`MIN_ALPHABET_LEN`] distinct characters, each a letter, digit, `-` or `_` so ids need no escaping
+/// and pass [`IdGenerator::is_plausible`], and a length from
+/// [`MIN_ID_LEN`] to the longest alias.
+pub fn validate_id_config(alphabet: &str, len: usize) -> Result<(), ShortenError> {
+    let mut seen = Vec::new();
+    for c in alphabet.chars() {
+        if !(c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+            return Err(ShortenError::Config(format!(
+                "ID_ALPHABET can only contain letters, digits, - and _, got {:?}",
+                c
+            )));
+        }
+        if seen.contains(&c) {
+            return Err(ShortenError::Config(format!(
+                "ID_ALPHABET contains {:?} more than once",
+                c
+            )));
+        }
+        seen.push(c);
+    }
+    if seen.len() < MIN_ALPHABET_LEN {
+        return Err(ShortenError::Config(format!(
+            "ID_ALPHABET needs at least {} characters",
+            MIN_ALPHABET_LEN
+        )));
+    }
+    if !(MIN_ID_LEN..=MAX_ALIAS_LEN).contains(&len) {
+        return Err(ShortenError::Config(format!(
+            "ID_LENGTH must be from {} to {}, got {}",
+            MIN_ID_LEN, MAX_ALIAS_LEN, len
+        )));
+    }
+    Ok(())
+}
+
+fn words_slug(rng: &mut impl Rng, words: usize, separator: &str) -> String {
+    let adjectives: Vec<&str> = ADJECTIVES.lines().collect();
+    let nouns: Vec<&str> = NOUNS.lines().collect();
+    let mut parts: Vec<String> = (1..words)
+        .map(|_| adjectives.choose(rng).unwrap().to_string())
+        .collect();
+    parts.push(nouns.choose(rng).unwrap().to_string());
+    parts.push(rng.gen_range(10..100).to_string());
+    parts.join(separator)
+}
+
+/// A link id or alias from the path, answering 404 up front for anything
+/// [`IdGenerator::is_plausible`] rules out.
+pub struct Slug(pub String);
+
+#[async_trait]
+impl FromRequestParts<AppState> for Slug {
+    type Rejection = ShortenError;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        // undecodable segments, e.g. invalid UTF-8, can't name a link either
+        let Ok(Path(slug)) = Path::<String>::from_request_parts(parts, state).await else {
+            return Err(StatusCodeError(StatusCode::NOT_FOUND).into());
+        };
+        if !state.db.is_plausible(&slug) {
+            return Err(StatusCodeError(StatusCode::NOT_FOUND).into());
+        }
+        Ok(Slug(state.db.normalize(&slug)))
+    }
+}
+
+/// Suffix of `/:id.json`, which answers with a link's target as JSON
+/// instead of redirecting to it.
+pub const JSON_SUFFIX: &str = ".json";
+
+/// The path of `/:id`: the slug itself and, for one ending in
+/// [`JSON_SUFFIX`], the slug before it, each if plausible. A link whose id
+/// or alias really ends in `.json` wins over the suffix. 404 if neither
+/// could name a link.
+pub struct RedirectSlug {
+    pub exact: Option<String>,
+    pub json: Option<String>,
+}
+
+#[async_trait]
+impl FromRequestParts<AppState> for RedirectSlug {
+    type Rejection = ShortenError;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        let Ok(Path(slug)) = Path::<String>::from_request_parts(parts, state).await else {
+            return Err(StatusCodeError(StatusCode::NOT_FOUND).into());
+        };
+        let json = slug
+            .strip_suffix(JSON_SUFFIX)
+            .filter(|bare| state.db.is_plausible(bare))
+            .map(|bare| state.db.normalize(bare));
+        let exact = Some(slug)
+            .filter(|slug| state.db.is_plausible(slug))
+            .map(|slug| state.db.normalize(&slug));
+        if exact.is_none() && json.is_none() {
+            return Err(StatusCodeError(StatusCode::NOT_FOUND).into());
+        }
+        Ok(RedirectSlug { exact, json })
+    }
+}
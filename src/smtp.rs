@@ -0,0 +1,263 @@
+use std::{sync::Arc, time::Duration};
+
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use chrono::Utc;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufStream},
+    net::TcpStream,
+};
+use tokio_rustls::{
+    rustls::{pki_types::ServerName, ClientConfig, RootCertStore},
+    TlsConnector,
+};
+use url::Url;
+
+/// A whole delivery, connecting included, gives up after this long.
+const SEND_TIMEOUT: Duration = Duration::from_secs(60);
+/// Base64 lines of a body are at most this long, as MIME wants.
+const LINE_LEN: usize = 76;
+
+/// Where mail is handed over, from `SMTP_URL`: `smtps://` speaks TLS from
+/// the start, port 465 by default, and `smtp://` switches to it with
+/// `STARTTLS` when the server offers it, port 587 by default. Credentials
+/// in the url are only ever sent over TLS.
+#[derive(Clone)]
+pub struct Relay {
+    host: String,
+    port: u16,
+    implicit_tls: bool,
+    credentials: Option<(String, String)>,
+    tls: TlsConnector,
+}
+
+/// A message of a plain text and an HTML alternative.
+#[derive(Debug, Clone)]
+pub struct Mail<'a> {
+    pub from: &'a str,
+    pub to: &'a [String],
+    pub subject: &'a str,
+    pub text: &'a str,
+    pub html: &'a str,
+}
+
+impl Relay {
+    pub fn from_url(url: &str) -> Result<Self, String> {
+        let url = Url::parse(url).map_err(|e| format!("SMTP_URL is invalid: {}", e))?;
+        let (implicit_tls, default_port) = match url.scheme() {
+            "smtps" => (true, 465),
+            "smtp" => (false, 587),
+            _ => return Err("SMTP_URL must be an smtp:// or smtps:// url".into()),
+        };
+        let host = url
+            .host_str()
+            .filter(|h| !h.is_empty())
+            .ok_or("SMTP_URL has no host")?;
+        let decode = |s: &str| {
+            percent_encoding::percent_decode_str(s)
+                .decode_utf8_lossy()
+                .into_owned()
+        };
+        let credentials = (!url.username().is_empty()).then(|| {
+            (
+                decode(url.username()),
+                decode(url.password().unwrap_or_default()),
+            )
+        });
+        let mut roots = RootCertStore::empty();
+        roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+        let config = ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        Ok(Self {
+            host: host.trim_start_matches('[').trim_end_matches(']').into(),
+            port: url.port().unwrap_or(default_port),
+            implicit_tls,
+            credentials,
+            tls: TlsConnector::from(Arc::new(config)),
+        })
+    }
+
+    /// Hands `mail` over to the relay, the error saying what it answered
+    /// if it didn't take it.
+    pub async fn send(&self, mail: &Mail<'_>) -> Result<(), String> {
+        tokio::time::timeout(SEND_TIMEOUT, self.deliver(mail))
+            .await
+            .map_err(|_| format!("the mail relay took longer than {:?}", SEND_TIMEOUT))?
+    }
+
+    async fn deliver(&self, mail: &Mail<'_>) -> Result<(), String> {
+        let tcp = TcpStream::connect((self.host.as_str(), self.port))
+            .await
+            .map_err(|e| format!("failed to reach {}:{}: {}", self.host, self.port, e))?;
+        if self.implicit_tls {
+            let mut conn = BufStream::new(self.handshake(tcp).await?);
+            expect(&mut conn, 220).await?;
+            self.ehlo(&mut conn).await?;
+            return self.transact(&mut conn, mail, true).await;
+        }
+        let mut conn = BufStream::new(tcp);
+        expect(&mut conn, 220).await?;
+        let offers = self.ehlo(&mut conn).await?;
+        if !offers
+            .iter()
+            .any(|ext| ext.eq_ignore_ascii_case("STARTTLS"))
+        {
+            return self.transact(&mut conn, mail, false).await;
+        }
+        command(&mut conn, "STARTTLS", 220).await?;
+        let mut conn = BufStream::new(self.handshake(conn.into_inner()).await?);
+        self.ehlo(&mut conn).await?;
+        self.transact(&mut conn, mail, true).await
+    }
+
+    async fn handshake(
+        &self,
+        tcp: TcpStream,
+    ) -> Result<tokio_rustls::client::TlsStream<TcpStream>, String> {
+        let name = ServerName::try_from(self.host.clone())
+            .map_err(|e| format!("{} can't be verified: {}", self.host, e))?;
+        self.tls
+            .connect(name, tcp)
+            .await
+            .map_err(|e| format!("TLS with {} failed: {}", self.host, e))
+    }
+
+    /// The extensions the server offers.
+    async fn ehlo<S: AsyncRead + AsyncWrite + Unpin>(
+        &self,
+        conn: &mut BufStream<S>,
+    ) -> Result<Vec<String>, String> {
+        let lines = command(conn, "EHLO shortener", 250).await?;
+        Ok(lines
+            .iter()
+            .skip(1)
+            .filter_map(|line| line.split_whitespace().next())
+            .map(String::from)
+            .collect())
+    }
+
+    async fn transact<S: AsyncRead + AsyncWrite + Unpin>(
+        &self,
+        conn: &mut BufStream<S>,
+        mail: &Mail<'_>,
+        secure: bool,
+    ) -> Result<(), String> {
+        if let Some((user, password)) = &self.credentials {
+            if !secure {
+                return Err(format!(
+                    "{} doesn't offer STARTTLS, the credentials aren't sent in the clear",
+                    self.host
+                ));
+            }
+            let token = STANDARD.encode(format!("\0{}\0{}", user, password));
+            command(conn, &format!("AUTH PLAIN {}", token), 235).await?;
+        }
+        command(conn, &format!("MAIL FROM:<{}>", mail.from), 250).await?;
+        for to in mail.to {
+            command(conn, &format!("RCPT TO:<{}>", to), 250).await?;
+        }
+        command(conn, "DATA", 354).await?;
+        let message = message(mail);
+        conn.write_all(message.as_bytes())
+            .await
+            .map_err(|e| e.to_string())?;
+        command(conn, ".", 250).await?;
+        // the mail was taken, whatever the goodbye
+        let _ = command(conn, "QUIT", 221).await;
+        Ok(())
+    }
+}
+
+impl std::fmt::Debug for Relay {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Relay")
+            .field("host", &self.host)
+            .field("port", &self.port)
+            .field("implicit_tls", &self.implicit_tls)
+            .field("credentials", &self.credentials.is_some())
+            .finish()
+    }
+}
+
+/// The headers and MIME body of `mail`, ending in a line break. The parts
+/// are base64, so no line of it starts with a dot.
+pub fn message(mail: &Mail<'_>) -> String {
+    let boundary = format!("=_{}", nanoid::nanoid!(24));
+    let mut out = format!(
+        "From: {}\r\nTo: {}\r\nSubject: {}\r\nDate: {}\r\nMIME-Version: 1.0\r\n\
+         Content-Type: multipart/alternative; boundary=\"{}\"\r\n\r\n",
+        mail.from,
+        mail.to.join(", "),
+        header_text(mail.subject),
+        Utc::now().to_rfc2822(),
+        boundary,
+    );
+    for (kind, body) in [("text/plain", mail.text), ("text/html", mail.html)] {
+        out.push_str(&format!(
+            "--{}\r\nContent-Type: {}; charset=utf-8\r\nContent-Transfer-Encoding: base64\r\n\r\n",
+            boundary, kind
+        ));
+        let encoded = STANDARD.encode(body);
+        for line in encoded.as_bytes().chunks(LINE_LEN) {
+            out.push_str(std::str::from_utf8(line).unwrap_or_default());
+            out.push_str("\r\n");
+        }
+    }
+    out.push_str(&format!("--{}--\r\n", boundary));
+    out
+}
+
+/// `text` as is if it's plain ASCII, else as an encoded word.
+fn header_text(text: &str) -> String {
+    let text = text.replace(['\r', '\n'], " ");
+    match text.is_ascii() {
+        true => text,
+        false => format!("=?utf-8?B?{}?=", STANDARD.encode(text)),
+    }
+}
+
+/// Sends `line` and reads the reply, an error unless it has `code`.
+async fn command<S: AsyncRead + AsyncWrite + Unpin>(
+    conn: &mut BufStream<S>,
+    line: &str,
+    code: u16,
+) -> Result<Vec<String>, String> {
+    conn.write_all(format!("{}\r\n", line).as_bytes())
+        .await
+        .map_err(|e| e.to_string())?;
+    conn.flush().await.map_err(|e| e.to_string())?;
+    // leaving out the credentials AUTH carries
+    let shown = match line.starts_with("AUTH") {
+        true => "AUTH",
+        false => line,
+    };
+    expect(conn, code)
+        .await
+        .map_err(|e| format!("{}: {}", shown, e))
+}
+
+/// Reads a reply, all of its lines, an error unless it has `code`.
+async fn expect<S: AsyncRead + AsyncWrite + Unpin>(
+    conn: &mut BufStream<S>,
+    code: u16,
+) -> Result<Vec<String>, String> {
+    let mut lines = Vec::new();
+    loop {
+        let mut line = String::new();
+        let read = conn.read_line(&mut line).await.map_err(|e| e.to_string())?;
+        if read == 0 {
+            return Err("the mail relay hung up".into());
+        }
+        let line = line.trim_end();
+        let (status, rest) = line.split_at(line.len().min(3));
+        let last = !rest.starts_with('-');
+        lines.push(rest.get(1..).unwrap_or_default().to_string());
+        if !last {
+            continue;
+        }
+        return match status.parse::<u16>() {
+            Ok(status) if status == code => Ok(lines),
+            _ => Err(format!("the mail relay answered {:?}", line)),
+        };
+    }
+}
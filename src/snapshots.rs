@@ -0,0 +1,144 @@
+use std::time::Duration;
+
+use chrono::{DateTime, TimeDelta, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use tracing::{debug, warn};
+
+use crate::ShortenError;
+
+/// Held while taking a snapshot, so instances sharing the database take
+/// each bucket once between them.
+const LOCK_KEY: i64 = 0x736e_6170_7368_6f74;
+
+pub async fn init(db: &PgPool) -> Result<(), ShortenError> {
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS metrics_snapshots (
+            bucket TIMESTAMPTZ PRIMARY KEY,
+            total_links BIGINT NOT NULL,
+            total_clicks BIGINT NOT NULL,
+            clicks BIGINT,
+            new_links BIGINT NOT NULL,
+            taken_at TIMESTAMPTZ NOT NULL DEFAULT now()
+        )",
+    )
+    .execute(db)
+    .await?;
+    Ok(())
+}
+
+/// Aggregates of the interval ending at `bucket`: the totals when it was
+/// taken, shortly after, and what the interval added. `clicks` is `None`
+/// when the previous bucket has no snapshot to count from.
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+#[serde(rename_all = "snake_case")]
+pub struct Snapshot {
+    pub bucket: DateTime<Utc>,
+    pub total_links: i64,
+    pub total_clicks: i64,
+    pub clicks: Option<i64>,
+    pub new_links: i64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Granularity {
+    #[default]
+    Hour,
+    Day,
+}
+
+impl Granularity {
+    fn as_str(self) -> &'static str {
+        match self {
+            Granularity::Hour => "hour",
+            Granularity::Day => "day",
+        }
+    }
+}
+
+/// The latest bucket boundary at or before `at`, buckets being `interval`
+/// long from the epoch.
+fn bucket_of(at: DateTime<Utc>, interval: Duration) -> DateTime<Utc> {
+    let secs = interval.as_secs().max(1) as i64;
+    let start = at.timestamp().div_euclid(secs) * secs;
+    DateTime::from_timestamp(start, 0).unwrap_or(at)
+}
+
+/// Writes the snapshot of the interval that ended last, replacing the one
+/// of that bucket if it was taken already. `None` if another instance is
+/// taking one right now.
+pub async fn take(db: &PgPool, interval: Duration) -> Result<Option<Snapshot>, ShortenError> {
+    let bucket = bucket_of(Utc::now(), interval);
+    let previous = bucket - TimeDelta::from_std(interval).unwrap_or(TimeDelta::MAX);
+    let mut tx = db.begin().await?;
+    let locked: bool = sqlx::query_scalar("SELECT pg_try_advisory_xact_lock($1)")
+        .bind(LOCK_KEY)
+        .fetch_one(&mut *tx)
+        .await?;
+    if !locked {
+        return Ok(None);
+    }
+    let snapshot = sqlx::query_as(
+        "INSERT INTO metrics_snapshots (bucket, total_links, total_clicks, clicks, new_links)
+         SELECT $1, t.links, t.clicks, t.clicks - p.total_clicks, n.links
+         FROM (SELECT COUNT(*) AS links, COALESCE(SUM(clicks), 0)::BIGINT AS clicks
+               FROM urls) t
+         CROSS JOIN (SELECT COUNT(*) AS links FROM urls
+                     WHERE created_at >= $2 AND created_at < $1) n
+         LEFT JOIN metrics_snapshots p ON p.bucket = $2
+         ON CONFLICT (bucket) DO UPDATE SET
+            total_links = EXCLUDED.total_links,
+            total_clicks = EXCLUDED.total_clicks,
+            clicks = EXCLUDED.clicks,
+            new_links = EXCLUDED.new_links,
+            taken_at = now()
+         RETURNING bucket, total_links, total_clicks, clicks, new_links",
+    )
+    .bind(bucket)
+    .bind(previous)
+    .fetch_one(&mut *tx)
+    .await?;
+    tx.commit().await?;
+    Ok(Some(snapshot))
+}
+
+/// Snapshots of buckets from `from` up to `to`, oldest first. By day, the
+/// totals are the day's last and the intervals' additions are summed.
+pub async fn history(
+    db: &PgPool,
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+    granularity: Granularity,
+) -> Result<Vec<Snapshot>, ShortenError> {
+    let snapshots = sqlx::query_as(
+        "SELECT date_trunc($3, bucket AT TIME ZONE 'UTC') AT TIME ZONE 'UTC' AS bucket,
+            (array_agg(total_links ORDER BY bucket DESC))[1] AS total_links,
+            (array_agg(total_clicks ORDER BY bucket DESC))[1] AS total_clicks,
+            SUM(clicks)::BIGINT AS clicks,
+            SUM(new_links)::BIGINT AS new_links
+         FROM metrics_snapshots WHERE bucket >= $1 AND bucket < $2
+         GROUP BY 1 ORDER BY 1",
+    )
+    .bind(from)
+    .bind(to)
+    .bind(granularity.as_str())
+    .fetch_all(db)
+    .await?;
+    Ok(snapshots)
+}
+
+/// Takes a snapshot every `every`, starting now. Retaking a bucket is
+/// harmless, so restarts and overlapping instances need no coordination
+/// beyond the lock.
+pub async fn run(db: PgPool, every: Duration) {
+    let mut interval = tokio::time::interval(every);
+    loop {
+        interval.tick().await;
+        match take(&db, every).await {
+            Ok(Some(snapshot)) => debug!(bucket = %snapshot.bucket, "Took metrics snapshot"),
+            Ok(None) => debug!("Another instance is taking the metrics snapshot"),
+            Err(e) => warn!("Failed to take metrics snapshot: {}", e),
+        }
+    }
+}
@@ -0,0 +1,193 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use sqlx::PgPool;
+use tracing::warn;
+
+use crate::{config::Config, jobs, webhook::Event};
+
+/// Flags links whose traffic over the last window jumps far above their
+/// usual rate.
+///
+/// Each tracked link keeps a click count for the current window and a
+/// baseline, a moving average of its per-window counts over roughly
+/// `spike_baseline`. At most `spike_tracked_links` links are tracked: clicks
+/// on other links are ignored until the next window, when the quietest
+/// entries are dropped to make room again.
+#[derive(Debug, Clone)]
+pub struct SpikeDetector {
+    links: Arc<Mutex<HashMap<String, LinkRate>>>,
+    settings: Settings,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Settings {
+    window: Duration,
+    /// Weight of the latest window in the baseline.
+    alpha: f64,
+    ratio: f64,
+    min_clicks: u64,
+    cooldown: Duration,
+    capacity: usize,
+}
+
+#[derive(Debug, Default)]
+struct LinkRate {
+    current: u64,
+    /// Unset until the link's first window closes.
+    baseline: Option<f64>,
+    last_alert: Option<(Instant, HotLink)>,
+}
+
+impl LinkRate {
+    fn in_cooldown(&self, now: Instant, cooldown: Duration) -> bool {
+        self.last_alert
+            .as_ref()
+            .is_some_and(|(at, _)| now.duration_since(*at) < cooldown)
+    }
+}
+
+/// A link that spiked within the cooldown period.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub struct HotLink {
+    pub id: String,
+    /// Clicks in the window that triggered the alert.
+    pub clicks: u64,
+    /// Usual clicks per window before the spike.
+    pub baseline: f64,
+    pub detected_at: DateTime<Utc>,
+}
+
+impl SpikeDetector {
+    pub fn new(config: &Config) -> Self {
+        let alpha = config.spike_window.as_secs_f64() / config.spike_baseline.as_secs_f64();
+        Self {
+            links: Default::default(),
+            settings: Settings {
+                window: config.spike_window,
+                alpha: alpha.clamp(f64::MIN_POSITIVE, 1.0),
+                ratio: config.spike_ratio,
+                min_clicks: config.spike_min_clicks,
+                cooldown: config.spike_cooldown,
+                capacity: config.spike_tracked_links.max(1),
+            },
+        }
+    }
+
+    pub fn record(&self, id: &str) {
+        let mut links = self.links.lock().unwrap();
+        if let Some(rate) = links.get_mut(id) {
+            rate.current += 1;
+        } else if links.len() < self.settings.capacity {
+            links.insert(
+                id.to_string(),
+                LinkRate {
+                    current: 1,
+                    ..Default::default()
+                },
+            );
+        }
+    }
+
+    /// Links that spiked within the cooldown, most recent first.
+    pub fn hot(&self) -> Vec<HotLink> {
+        let now = Instant::now();
+        let links = self.links.lock().unwrap();
+        let mut hot: Vec<HotLink> = links
+            .values()
+            .filter(|rate| rate.in_cooldown(now, self.settings.cooldown))
+            .filter_map(|rate| rate.last_alert.as_ref().map(|(_, link)| link.clone()))
+            .collect();
+        hot.sort_by_key(|link| std::cmp::Reverse(link.detected_at));
+        hot
+    }
+
+    /// Closes the current window, returning the links that spiked in it and
+    /// aren't in their cooldown.
+    fn rotate(&self) -> Vec<HotLink> {
+        let Settings {
+            alpha,
+            ratio,
+            min_clicks,
+            cooldown,
+            capacity,
+            ..
+        } = self.settings;
+        let now = Instant::now();
+        let mut links = self.links.lock().unwrap();
+        let mut spikes = Vec::new();
+        for (id, rate) in links.iter_mut() {
+            // a link without history counts as one click per window, so it
+            // needs both `min_clicks` and `ratio` clicks to be flagged
+            let baseline = rate.baseline.unwrap_or(0.0);
+            let spiking =
+                rate.current >= min_clicks && rate.current as f64 >= ratio * baseline.max(1.0);
+            if spiking && !rate.in_cooldown(now, cooldown) {
+                let hot = HotLink {
+                    id: id.clone(),
+                    clicks: rate.current,
+                    baseline,
+                    detected_at: Utc::now(),
+                };
+                rate.last_alert = Some((now, hot.clone()));
+                spikes.push(hot);
+            }
+            let current = rate.current as f64;
+            rate.baseline = Some(rate.baseline.map_or(current, |b| b + alpha * (current - b)));
+            rate.current = 0;
+        }
+
+        links.retain(|_, rate| {
+            rate.baseline.is_some_and(|b| b >= 0.5) || rate.in_cooldown(now, cooldown)
+        });
+        // keep half the room for links that start getting traffic next window
+        let keep = capacity / 2;
+        if links.len() > keep {
+            let mut ranked: Vec<(bool, f64, String)> = links
+                .iter()
+                .map(|(id, rate)| {
+                    let baseline = rate.baseline.unwrap_or(0.0);
+                    (rate.in_cooldown(now, cooldown), baseline, id.clone())
+                })
+                .collect();
+            ranked.sort_by(|a, b| b.0.cmp(&a.0).then(b.1.total_cmp(&a.1)));
+            for (_, _, id) in ranked.into_iter().skip(keep) {
+                links.remove(&id);
+            }
+        }
+        spikes
+    }
+
+    /// Closes a window every `spike_window`, logging each spike and, when
+    /// `notify` is set, sending it to the webhook.
+    pub async fn run(self, db: PgPool, notify: bool) {
+        let mut interval = tokio::time::interval(self.settings.window);
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            for spike in self.rotate() {
+                warn!(
+                    "Traffic spike on {}: {} clicks against a usual {:.1}",
+                    spike.id, spike.clicks, spike.baseline
+                );
+                if !notify {
+                    continue;
+                }
+                let event = Event::LinkTrafficSpike {
+                    id: spike.id,
+                    clicks: spike.clicks,
+                    baseline: spike.baseline,
+                };
+                if let Err(e) = jobs::enqueue(&db, &event).await {
+                    warn!("Failed to queue spike notification: {}", e);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,82 @@
+use sqlx::PgPool;
+
+use crate::ShortenError;
+
+const MAX_TAG_LEN: usize = 32;
+/// Tags a single link may carry, counting ones added on earlier requests.
+pub const MAX_TAGS_PER_LINK: usize = 10;
+
+pub async fn init(db: &PgPool) -> Result<(), ShortenError> {
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS link_tags (
+            link_id TEXT NOT NULL,
+            tag TEXT NOT NULL,
+            PRIMARY KEY (link_id, tag)
+        )",
+    )
+    .execute(db)
+    .await?;
+    sqlx::query("CREATE INDEX IF NOT EXISTS link_tags_tag ON link_tags (tag)")
+        .execute(db)
+        .await?;
+    Ok(())
+}
+
+/// Lowercases the tags and drops duplicates. `None` if any of them falls
+/// outside lowercase letters, digits, `-` and `_`, or there are too many.
+pub fn normalize(tags: &[String]) -> Option<Vec<String>> {
+    let mut normalized: Vec<String> = Vec::with_capacity(tags.len());
+    for tag in tags {
+        let tag = tag.to_ascii_lowercase();
+        let valid = !tag.is_empty()
+            && tag.len() <= MAX_TAG_LEN
+            && tag
+                .bytes()
+                .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_');
+        if !valid {
+            return None;
+        }
+        if !normalized.contains(&tag) {
+            normalized.push(tag);
+        }
+    }
+    (normalized.len() <= MAX_TAGS_PER_LINK).then_some(normalized)
+}
+
+/// Adds tags to a link, keeping the ones it has. Adds nothing and returns
+/// false if the result would exceed `MAX_TAGS_PER_LINK`.
+pub async fn add(db: &PgPool, link_id: &str, tags: &[String]) -> Result<bool, ShortenError> {
+    if tags.is_empty() {
+        return Ok(true);
+    }
+    let (total,): (i64,) = sqlx::query_as(
+        "SELECT COUNT(*) FROM (
+            SELECT tag FROM link_tags WHERE link_id = $1 UNION SELECT unnest($2::TEXT[])
+         ) t",
+    )
+    .bind(link_id)
+    .bind(tags)
+    .fetch_one(db)
+    .await?;
+    if total > MAX_TAGS_PER_LINK as i64 {
+        return Ok(false);
+    }
+    sqlx::query(
+        "INSERT INTO link_tags (link_id, tag) SELECT $1, unnest($2::TEXT[])
+         ON CONFLICT DO NOTHING",
+    )
+    .bind(link_id)
+    .bind(tags)
+    .execute(db)
+    .await?;
+    Ok(true)
+}
+
+pub async fn list(db: &PgPool, link_id: &str) -> Result<Vec<String>, ShortenError> {
+    let tags: Vec<(String,)> =
+        sqlx::query_as("SELECT tag FROM link_tags WHERE link_id = $1 ORDER BY tag")
+            .bind(link_id)
+            .fetch_all(db)
+            .await?;
+    Ok(tags.into_iter().map(|(t,)| t).collect())
+}
@@ -0,0 +1,73 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// Spreads a background sender's work out over time: tokens come in at
+/// `rate` a second up to `burst` saved up, and each unit of work takes
+/// one. Work beyond what's saved up waits for the tokens it takes, so a
+/// burst goes out at `rate` instead of all at once.
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+    sender: &'static str,
+    rate: f64,
+    burst: u32,
+    inner: Arc<Mutex<Inner>>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    /// Below zero while work waits for tokens already taken.
+    tokens: f64,
+    at: Instant,
+}
+
+impl TokenBucket {
+    /// A full bucket for `sender`, the label of its metrics. `rate` must
+    /// be positive.
+    pub fn new(sender: &'static str, rate: f64, burst: u32) -> Self {
+        Self::new_at(sender, rate, burst, Instant::now())
+    }
+
+    pub fn new_at(sender: &'static str, rate: f64, burst: u32, now: Instant) -> Self {
+        let burst = burst.max(1);
+        Self {
+            sender,
+            rate,
+            burst,
+            inner: Arc::new(Mutex::new(Inner {
+                tokens: burst as f64,
+                at: now,
+            })),
+        }
+    }
+
+    /// Most units of work that go out at once.
+    pub fn burst(&self) -> u32 {
+        self.burst
+    }
+
+    /// Waits until `n` units of work may go out.
+    pub async fn take(&self, n: u32) {
+        let wait = self.reserve_at(n, Instant::now());
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    /// Takes `n` tokens, saying how long after `now` the work they're for
+    /// may go out. Later callers wait behind it.
+    pub fn reserve_at(&self, n: u32, now: Instant) -> Duration {
+        let mut inner = self.inner.lock().unwrap();
+        let elapsed = now.saturating_duration_since(inner.at).as_secs_f64();
+        inner.tokens = (inner.tokens + elapsed * self.rate).min(self.burst as f64);
+        inner.at = inner.at.max(now);
+        inner.tokens -= n as f64;
+        metrics::counter!("throttle_tokens_total", "sender" => self.sender).increment(n.into());
+        if inner.tokens >= 0.0 {
+            return Duration::ZERO;
+        }
+        metrics::counter!("throttle_throttled_total", "sender" => self.sender).increment(1);
+        Duration::from_secs_f64(-inner.tokens / self.rate)
+    }
+}
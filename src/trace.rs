@@ -0,0 +1,112 @@
+//! Following a destination's redirects server-side, to tell a broken or
+//! looping target from one that works.
+
+use std::time::{Duration, Instant};
+
+use reqwest::header::LOCATION;
+use serde::Serialize;
+use url::Url;
+
+use crate::fetch::Fetcher;
+
+/// A url the trace fetched, and what it answered.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub struct Hop {
+    pub url: String,
+    /// `None` when the fetch failed, `error` saying why.
+    pub status: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub location: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    pub elapsed_ms: u64,
+}
+
+/// How a trace ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Outcome {
+    /// The last hop answered without redirecting further.
+    Completed,
+    /// A redirect led back to a url already fetched.
+    Loop,
+    /// The hops ran out while still being redirected.
+    TooManyHops,
+    /// A hop couldn't be fetched, refused or timed out, or redirected
+    /// somewhere that isn't a url.
+    Failed,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub struct Trace {
+    pub hops: Vec<Hop>,
+    /// The status of the last hop that answered.
+    pub final_status: Option<u16>,
+    pub outcome: Outcome,
+}
+
+/// Follows `url` through at most `max_hops` fetches, each given
+/// `hop_timeout` to answer. Every hop goes through `fetcher`, so
+/// destinations on non-public addresses fail the trace rather than being
+/// reached.
+pub async fn follow(fetcher: &Fetcher, url: Url, max_hops: usize, hop_timeout: Duration) -> Trace {
+    let mut hops: Vec<Hop> = Vec::new();
+    let mut next = url;
+    let outcome = loop {
+        if hops.len() >= max_hops {
+            break Outcome::TooManyHops;
+        }
+        let started = Instant::now();
+        let answer = tokio::time::timeout(hop_timeout, fetcher.get(&next)).await;
+        let mut hop = Hop {
+            url: next.to_string(),
+            status: None,
+            location: None,
+            error: None,
+            elapsed_ms: started.elapsed().as_millis() as u64,
+        };
+        let res = match answer {
+            Ok(Ok(res)) => res,
+            Ok(Err(e)) => {
+                hop.error = Some(e.to_string());
+                hops.push(hop);
+                break Outcome::Failed;
+            }
+            Err(_) => {
+                hop.error = Some(format!("no answer within {:?}", hop_timeout));
+                hops.push(hop);
+                break Outcome::Failed;
+            }
+        };
+        hop.status = Some(res.status().as_u16());
+        let location = res
+            .headers()
+            .get(LOCATION)
+            .filter(|_| res.status().is_redirection())
+            .map(|v| String::from_utf8_lossy(v.as_bytes()).into_owned());
+        hop.location = location.clone();
+        hops.push(hop);
+        let Some(location) = location else {
+            break Outcome::Completed;
+        };
+        match next.join(&location) {
+            Ok(target) if hops.iter().any(|hop| hop.url == target.as_str()) => {
+                break Outcome::Loop;
+            }
+            Ok(target) => next = target,
+            Err(e) => {
+                if let Some(hop) = hops.last_mut() {
+                    hop.error = Some(format!("invalid location: {}", e));
+                }
+                break Outcome::Failed;
+            }
+        }
+    };
+    Trace {
+        final_status: hops.iter().rev().find_map(|hop| hop.status),
+        hops,
+        outcome,
+    }
+}
@@ -0,0 +1,377 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use chrono::{Datelike, Days, NaiveDate, Utc};
+use hyperloglog::HyperLogLog;
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use tracing::warn;
+
+use crate::ShortenError;
+
+/// Sizes sketches at 1024 one-byte registers, for estimates within about 3%.
+const ERROR_RATE: f64 = 0.01;
+
+pub async fn init(db: &PgPool) -> Result<(), ShortenError> {
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS link_uniques (
+            link_id TEXT NOT NULL,
+            day DATE NOT NULL,
+            clicks BIGINT NOT NULL DEFAULT 0,
+            registers BYTEA,
+            PRIMARY KEY (link_id, day)
+        )",
+    )
+    .execute(db)
+    .await?;
+    Ok(())
+}
+
+/// A link's traffic on one UTC day.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub struct DailyStats {
+    pub day: NaiveDate,
+    pub clicks: i64,
+    /// Estimated distinct visitors, absent when unique counting is off.
+    pub uniques: Option<u64>,
+}
+
+/// Width of the buckets of a click timeseries. Only these reach
+/// `date_trunc`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Interval {
+    #[default]
+    Day,
+    Week,
+    Month,
+}
+
+impl Interval {
+    fn as_str(self) -> &'static str {
+        match self {
+            Interval::Day => "day",
+            Interval::Week => "week",
+            Interval::Month => "month",
+        }
+    }
+
+    /// The bucket of `day`, like `date_trunc`: weeks start on Monday.
+    fn truncate(self, day: NaiveDate) -> NaiveDate {
+        match self {
+            Interval::Day => day,
+            Interval::Week => day - Days::new(day.weekday().num_days_from_monday().into()),
+            Interval::Month => day.with_day(1).unwrap_or(day),
+        }
+    }
+}
+
+/// Clicks in the bucket starting on `bucket`.
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+#[serde(rename_all = "snake_case")]
+pub struct Bucket {
+    pub bucket: NaiveDate,
+    pub count: i64,
+}
+
+#[derive(Debug, Default)]
+struct Day {
+    clicks: u64,
+    sketch: Option<HyperLogLog>,
+}
+
+/// Counts clicks and estimates distinct visitors per link per day.
+///
+/// A visitor is their client address and user agent, hashed with a key
+/// derived from `UNIQUES_SALT` into a HyperLogLog sketch. Only the sketch
+/// registers are stored, never the addresses or the key, so the table can't
+/// be walked back to visitors. Without a salt only daily clicks are kept.
+/// Changing the salt counts returning visitors again for the rest of the
+/// day.
+///
+/// Like the click counter, sketches build up in memory and are merged into
+/// `link_uniques` on every flush.
+#[derive(Debug, Clone)]
+pub struct VisitorCounter {
+    pending: Arc<Mutex<HashMap<(String, NaiveDate), Day>>>,
+    /// Empty sketch keyed by the salt, `None` when unique counting is off.
+    template: Option<Arc<HyperLogLog>>,
+}
+
+impl VisitorCounter {
+    pub fn new(salt: Option<&str>) -> Self {
+        Self {
+            pending: Default::default(),
+            template: salt.map(|s| Arc::new(HyperLogLog::new_deterministic(ERROR_RATE, seed(s)))),
+        }
+    }
+
+    pub fn record(&self, id: &str, ip: IpAddr, user_agent: Option<&str>) {
+        let day = Utc::now().date_naive();
+        let mut pending = self.pending.lock().unwrap();
+        let entry = pending.entry((id.to_string(), day)).or_default();
+        entry.clicks += 1;
+        if let Some(template) = &self.template {
+            entry
+                .sketch
+                .get_or_insert_with(|| HyperLogLog::new_from_template(template))
+                .insert(&(ip, user_agent));
+        }
+    }
+
+    /// Merges every pending day into the table in one transaction. On
+    /// failure the days are put back for the next attempt.
+    pub async fn flush(&self, db: &PgPool) -> Result<(), ShortenError> {
+        let batch = std::mem::take(&mut *self.pending.lock().unwrap());
+        if batch.is_empty() {
+            return Ok(());
+        }
+        let mut ids = Vec::with_capacity(batch.len());
+        let mut days = Vec::with_capacity(batch.len());
+        let mut clicks = Vec::with_capacity(batch.len());
+        let mut registers = Vec::with_capacity(batch.len());
+        for ((id, day), pending) in &batch {
+            ids.push(id.clone());
+            days.push(*day);
+            clicks.push(pending.clicks as i64);
+            registers.push(pending.sketch.as_ref().map(registers_of));
+        }
+        match self.merge(db, &ids, &days, &clicks, registers).await {
+            Ok(()) => Ok(()),
+            Err(e) => {
+                let mut pending = self.pending.lock().unwrap();
+                for (key, day) in batch {
+                    let entry = pending.entry(key).or_default();
+                    entry.clicks += day.clicks;
+                    match (&mut entry.sketch, day.sketch) {
+                        (Some(sketch), Some(earlier)) => sketch.merge(&earlier),
+                        (sketch @ None, earlier) => *sketch = earlier,
+                        _ => {}
+                    }
+                }
+                Err(e)
+            }
+        }
+    }
+
+    async fn merge(
+        &self,
+        db: &PgPool,
+        ids: &[String],
+        days: &[NaiveDate],
+        clicks: &[i64],
+        mut registers: Vec<Option<Vec<u8>>>,
+    ) -> Result<(), ShortenError> {
+        let mut tx = db.begin().await?;
+        // rows for deleted links are skipped; the placeholder rows make
+        // the lock below cover days seen for the first time too
+        sqlx::query(
+            "INSERT INTO link_uniques (link_id, day)
+             SELECT d.id, d.day FROM UNNEST($1::TEXT[], $2::DATE[]) AS d(id, day)
+             WHERE EXISTS (SELECT 1 FROM urls WHERE id = d.id)
+             ON CONFLICT DO NOTHING",
+        )
+        .bind(ids)
+        .bind(days)
+        .execute(&mut *tx)
+        .await?;
+        let stored: Vec<(String, NaiveDate, Option<Vec<u8>>)> = sqlx::query_as(
+            "SELECT link_id, day, registers FROM link_uniques
+             WHERE (link_id, day) IN (SELECT * FROM UNNEST($1::TEXT[], $2::DATE[]))
+             FOR UPDATE",
+        )
+        .bind(ids)
+        .bind(days)
+        .fetch_all(&mut *tx)
+        .await?;
+        let stored: HashMap<(&str, NaiveDate), Vec<u8>> = stored
+            .iter()
+            .filter_map(|(id, day, regs)| Some(((id.as_str(), *day), regs.clone()?)))
+            .collect();
+        for ((id, day), regs) in ids.iter().zip(days).zip(&mut registers) {
+            if let (Some(regs), Some(earlier)) = (regs, stored.get(&(id.as_str(), *day))) {
+                merge_registers(regs, earlier);
+            }
+        }
+        sqlx::query(
+            "UPDATE link_uniques u
+             SET clicks = u.clicks + d.clicks, registers = COALESCE(d.registers, u.registers)
+             FROM UNNEST($1::TEXT[], $2::DATE[], $3::BIGINT[], $4::BYTEA[])
+                AS d(id, day, clicks, registers)
+             WHERE u.link_id = d.id AND u.day = d.day",
+        )
+        .bind(ids)
+        .bind(days)
+        .bind(clicks)
+        .bind(&registers)
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    /// Estimated distinct visitors to `id` over its lifetime, counting ones
+    /// not yet flushed. `None` when unique counting is off.
+    pub async fn total(&self, db: &PgPool, id: &str) -> Result<Option<u64>, ShortenError> {
+        let Some(template) = &self.template else {
+            return Ok(None);
+        };
+        let stored: Vec<(Vec<u8>,)> = sqlx::query_as(
+            "SELECT registers FROM link_uniques WHERE link_id = $1 AND registers IS NOT NULL",
+        )
+        .bind(id)
+        .fetch_all(db)
+        .await?;
+        let mut regs = registers_of(template);
+        for (earlier,) in &stored {
+            merge_registers(&mut regs, earlier);
+        }
+        let pending = self.pending.lock().unwrap();
+        let sketches = pending
+            .iter()
+            .filter(|((link, _), _)| link == id)
+            .filter_map(|(_, day)| day.sketch.as_ref());
+        for sketch in sketches {
+            merge_registers(&mut regs, &registers_of(sketch));
+        }
+        Ok(Some(estimate(template, regs)))
+    }
+
+    /// Clicks and estimated visitors of `id` per day, oldest first,
+    /// counting ones not yet flushed.
+    pub async fn daily(&self, db: &PgPool, id: &str) -> Result<Vec<DailyStats>, ShortenError> {
+        let stored: Vec<(NaiveDate, i64, Option<Vec<u8>>)> = sqlx::query_as(
+            "SELECT day, clicks, registers FROM link_uniques WHERE link_id = $1 ORDER BY day",
+        )
+        .bind(id)
+        .fetch_all(db)
+        .await?;
+        let mut days = stored;
+        for ((link, day), pending) in self.pending.lock().unwrap().iter() {
+            if link != id {
+                continue;
+            }
+            let idx = match days.iter().position(|(d, ..)| d == day) {
+                Some(idx) => idx,
+                None => {
+                    days.push((*day, 0, None));
+                    days.len() - 1
+                }
+            };
+            let (_, clicks, regs) = &mut days[idx];
+            *clicks += pending.clicks as i64;
+            if let Some(sketch) = &pending.sketch {
+                let pending = registers_of(sketch);
+                match regs {
+                    Some(regs) => merge_registers(regs, &pending),
+                    None => *regs = Some(pending),
+                }
+            }
+        }
+        days.sort_by_key(|(day, ..)| *day);
+        Ok(days
+            .into_iter()
+            .map(|(day, clicks, regs)| DailyStats {
+                day,
+                clicks,
+                uniques: self.template.as_ref().map(|template| {
+                    estimate(template, regs.unwrap_or_else(|| registers_of(template)))
+                }),
+            })
+            .collect())
+    }
+
+    /// Clicks of `id` over the last `days` UTC days, today included, per
+    /// `interval`, oldest first, counting ones not yet flushed. Buckets
+    /// without clicks are left out; the first one may start before the
+    /// range.
+    pub async fn timeseries(
+        &self,
+        db: &PgPool,
+        id: &str,
+        interval: Interval,
+        days: u32,
+    ) -> Result<Vec<Bucket>, ShortenError> {
+        let since = Utc::now().date_naive() - Days::new(days.saturating_sub(1).into());
+        let mut buckets: Vec<Bucket> = sqlx::query_as(
+            "SELECT date_trunc($2, day::TIMESTAMP)::DATE AS bucket, SUM(clicks)::BIGINT AS count
+             FROM link_uniques WHERE link_id = $1 AND day >= $3
+             GROUP BY 1 ORDER BY 1",
+        )
+        .bind(id)
+        .bind(interval.as_str())
+        .bind(since)
+        .fetch_all(db)
+        .await?;
+        for ((link, day), pending) in self.pending.lock().unwrap().iter() {
+            if link != id || *day < since || pending.clicks == 0 {
+                continue;
+            }
+            let bucket = interval.truncate(*day);
+            match buckets.iter_mut().find(|b| b.bucket == bucket) {
+                Some(b) => b.count += pending.clicks as i64,
+                None => buckets.push(Bucket {
+                    bucket,
+                    count: pending.clicks as i64,
+                }),
+            }
+        }
+        buckets.sort_by_key(|b| b.bucket);
+        Ok(buckets)
+    }
+
+    /// Flushes every `every`.
+    pub async fn run(self, db: PgPool, every: Duration) {
+        let mut interval = tokio::time::interval(every);
+        loop {
+            interval.tick().await;
+            if let Err(e) = self.flush(&db).await {
+                warn!("Failed to flush unique visitors: {}", e);
+            }
+        }
+    }
+}
+
+/// FNV-1a, stable across builds so sketches from before a restart keep
+/// merging with new ones.
+fn seed(salt: &str) -> u128 {
+    const OFFSET: u128 = 0x6c62272e07bb014262b821756295c58d;
+    const PRIME: u128 = 0x0000000001000000000000000000013b;
+    salt.bytes()
+        .fold(OFFSET, |h, b| (h ^ b as u128).wrapping_mul(PRIME))
+}
+
+// The sketch doesn't expose its registers, only serde, and the serialized
+// form also carries the hash key. Going through a `Value` keeps the key out
+// of the table.
+
+fn registers_of(sketch: &HyperLogLog) -> Vec<u8> {
+    let value = serde_json::to_value(sketch).expect("sketches serialize");
+    serde_json::from_value(value["M"].clone()).expect("registers are bytes")
+}
+
+fn estimate(template: &HyperLogLog, regs: Vec<u8>) -> u64 {
+    let mut value = serde_json::to_value(template).expect("sketches serialize");
+    if value["M"].as_array().map(Vec::len) != Some(regs.len()) {
+        return 0;
+    }
+    value["M"] = regs.into();
+    let sketch: HyperLogLog = serde_json::from_value(value).expect("sketches deserialize");
+    sketch.len().round() as u64
+}
+
+/// Register-wise max, the union of the two sketches. Registers sized for
+/// another error rate are ignored.
+fn merge_registers(into: &mut [u8], from: &[u8]) {
+    if into.len() != from.len() {
+        return;
+    }
+    for (r, f) in into.iter_mut().zip(from) {
+        *r = (*r).max(*f);
+    }
+}
@@ -0,0 +1,112 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use url::Url;
+
+use crate::{config::UpgradeMode, fetch::Fetcher};
+
+const PROBE_CACHE_TTL: Duration = Duration::from_secs(60);
+
+/// `url` with `https` for `http`, for `FORCE_HTTPS_TARGETS`. `None` for any
+/// other scheme, which is left alone.
+pub fn force_https(url: &str) -> Option<String> {
+    let mut parsed = Url::parse(url).ok()?;
+    to_https(&mut parsed).then(|| parsed.into())
+}
+
+/// Switches an `http` url to `https`, returning whether it was one.
+fn to_https(url: &mut Url) -> bool {
+    if url.scheme() != "http" || url.set_scheme("https").is_err() {
+        return false;
+    }
+    // an explicit :80 would survive the scheme change and point https at
+    // the plaintext port
+    if url.port() == Some(80) {
+        let _ = url.set_port(None);
+    }
+    true
+}
+
+/// Rewrites `http://` destinations to `https://` according to the configured
+/// [`UpgradeMode`]. Probe results are cached per host for a short while so a
+/// batch of urls on the same host only costs one round trip.
+#[derive(Debug, Clone)]
+pub struct Upgrader {
+    mode: UpgradeMode,
+    fetcher: Fetcher,
+    cache: Arc<Mutex<HashMap<String, (bool, Instant)>>>,
+}
+
+impl Upgrader {
+    pub fn new(mode: UpgradeMode, fetcher: Fetcher) -> Self {
+        Self {
+            mode,
+            fetcher,
+            cache: Default::default(),
+        }
+    }
+
+    /// Returns the url to store and whether it was upgraded. `requested`
+    /// is the per-request flag: `Some(false)` opts out, `Some(true)` opts in
+    /// (probing when the global mode is `never`), `None` uses the global mode.
+    pub async fn upgrade(&self, url: &str, requested: Option<bool>) -> (String, bool) {
+        let mode = self.mode(requested);
+        let Ok(mut parsed) = Url::parse(url) else {
+            return (url.to_string(), false);
+        };
+        if mode == UpgradeMode::Never || !to_https(&mut parsed) {
+            return (url.to_string(), false);
+        }
+        if mode == UpgradeMode::Probe && !self.probe(&parsed).await {
+            return (url.to_string(), false);
+        }
+        (parsed.to_string(), true)
+    }
+
+    /// Whether upgrading with the per-request flag `requested` may fetch
+    /// from the destination.
+    pub fn fetches(&self, requested: Option<bool>) -> bool {
+        self.mode(requested) == UpgradeMode::Probe
+    }
+
+    fn mode(&self, requested: Option<bool>) -> UpgradeMode {
+        match (requested, self.mode) {
+            (Some(false), _) => UpgradeMode::Never,
+            (Some(true), UpgradeMode::Never) => UpgradeMode::Probe,
+            (_, mode) => mode,
+        }
+    }
+
+    async fn probe(&self, url: &Url) -> bool {
+        let Some(host) = url.host_str().map(str::to_ascii_lowercase) else {
+            return false;
+        };
+        if let Some(&(ok, at)) = self.cache.lock().unwrap().get(&host) {
+            if at.elapsed() < PROBE_CACHE_TTL {
+                return ok;
+            }
+        }
+        let ok = match self.fetcher.head(url).await {
+            Ok(res) if res.status().is_success() => true,
+            // a redirect is fine as long as it stays on the same host
+            Ok(res) if res.status().is_redirection() => res
+                .headers()
+                .get(reqwest::header::LOCATION)
+                .and_then(|v| v.to_str().ok())
+                .and_then(|loc| url.join(loc).ok())
+                .is_some_and(|loc| {
+                    loc.scheme() == "https"
+                        && loc.host_str().map(str::to_ascii_lowercase).as_deref()
+                            == Some(host.as_str())
+                }),
+            _ => false,
+        };
+        let mut cache = self.cache.lock().unwrap();
+        cache.retain(|_, (_, at)| at.elapsed() < PROBE_CACHE_TTL);
+        cache.insert(host, (ok, Instant::now()));
+        ok
+    }
+}
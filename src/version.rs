@@ -0,0 +1,27 @@
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+
+/// Crate version, with the `shortener/` prefix used for the `server` header.
+pub const SERVER: &str = concat!("shortener/", env!("CARGO_PKG_VERSION"));
+
+/// What was built, from where, and with what; filled in by `build.rs`.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub struct BuildInfo {
+    pub version: &'static str,
+    pub git_sha: &'static str,
+    pub build_time: Option<DateTime<Utc>>,
+    pub rustc: &'static str,
+}
+
+pub fn build_info() -> BuildInfo {
+    BuildInfo {
+        version: env!("CARGO_PKG_VERSION"),
+        git_sha: env!("BUILD_GIT_SHA"),
+        build_time: env!("BUILD_TIMESTAMP")
+            .parse()
+            .ok()
+            .and_then(|secs| DateTime::from_timestamp(secs, 0)),
+        rustc: env!("BUILD_RUSTC"),
+    }
+}
@@ -0,0 +1,136 @@
+use std::{fmt, time::Duration};
+
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use reqwest::{header::CONTENT_TYPE, Client};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+
+use crate::{jobs::Job, throttle::TokenBucket};
+
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+/// Header of a signed delivery: `t=<unix seconds>,v1=<hex>`, an
+/// HMAC-SHA256 with `WEBHOOK_SECRET` of the timestamp, a `.` and the body.
+pub const SIGNATURE_HEADER: &str = "x-shortener-webhook-signature";
+/// Total clicks that notify when a link reaches them: 100, then every
+/// power of ten after.
+const FIRST_MILESTONE: i64 = 100;
+
+/// Notification POSTed as JSON to `WEBHOOK_URL`. Delivery goes through the job
+/// queue so failures are retried with backoff.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+// the variants name the events receivers see
+#[allow(clippy::enum_variant_names)]
+pub enum Event {
+    /// A link crossed the abuse report threshold and was disabled.
+    LinkAutoDisabled { id: String, reports: i64 },
+    /// A link's clicks over the last window far exceeded its baseline.
+    LinkTrafficSpike {
+        id: String,
+        clicks: u64,
+        baseline: f64,
+    },
+    /// A shorten call created a link, rather than handing back one.
+    LinkCreated {
+        id: String,
+        url: String,
+        short_url: String,
+        created_at: DateTime<Utc>,
+    },
+    /// A link's flushed clicks reached a milestone.
+    LinkClickMilestone {
+        id: String,
+        milestone: i64,
+        clicks: i64,
+    },
+    /// A link's clicks went past its `click_cap`. It keeps redirecting.
+    LinkClickCapReached { id: String, cap: i64, clicks: i64 },
+}
+
+impl Job for Event {
+    const KIND: &'static str = "webhook";
+}
+
+/// The highest milestone a link going from `before` to `after` clicks
+/// reached, if any. Several at once, in a burst, notify the highest only.
+pub fn milestone(before: i64, after: i64) -> Option<i64> {
+    let mut reached = None;
+    let mut milestone = FIRST_MILESTONE;
+    while milestone <= after {
+        if milestone > before {
+            reached = Some(milestone);
+        }
+        milestone = milestone.checked_mul(10)?;
+    }
+    reached
+}
+
+#[derive(Clone)]
+pub struct Webhook {
+    client: Client,
+    url: String,
+    key: Option<Hmac<Sha256>>,
+    /// Paces deliveries, one token each.
+    throttle: Option<TokenBucket>,
+}
+
+impl Webhook {
+    pub fn new(url: String, secret: Option<&str>, throttle: Option<TokenBucket>) -> Self {
+        let client = Client::builder()
+            .timeout(WEBHOOK_TIMEOUT)
+            .build()
+            .expect("failed to build http client");
+        let key =
+            secret.map(|s| Hmac::new_from_slice(s.as_bytes()).expect("HMAC takes any key length"));
+        Self {
+            client,
+            url,
+            key,
+            throttle,
+        }
+    }
+
+    pub async fn deliver(&self, event: Event) -> Result<(), String> {
+        if let Some(throttle) = &self.throttle {
+            throttle.take(1).await;
+        }
+        // signed as sent, so the receiver checks the bytes it got
+        let body = serde_json::to_vec(&event).map_err(|e| e.to_string())?;
+        let mut req = self
+            .client
+            .post(&self.url)
+            .header(CONTENT_TYPE, "application/json");
+        if let Some(key) = &self.key {
+            req = req.header(SIGNATURE_HEADER, sign(key, Utc::now().timestamp(), &body));
+        }
+        let res = req.body(body).send().await.map_err(|e| e.to_string())?;
+        if !res.status().is_success() {
+            return Err(format!("webhook responded with {}", res.status()));
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for Webhook {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Webhook")
+            .field("url", &self.url)
+            .field("signed", &self.key.is_some())
+            .field("throttled", &self.throttle.is_some())
+            .finish()
+    }
+}
+
+fn sign(key: &Hmac<Sha256>, timestamp: i64, body: &[u8]) -> String {
+    let mut mac = key.clone();
+    mac.update(format!("{}.", timestamp).as_bytes());
+    mac.update(body);
+    let hex: String = mac
+        .finalize()
+        .into_bytes()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect();
+    format!("t={},v1={}", timestamp, hex)
+}
@@ -0,0 +1,135 @@
+use chrono::Utc;
+use shortener::backup::{
+    sha256, tar_end, tar_entry, verify, FileEntry, Manifest, TarReader, FORMAT, MANIFEST,
+};
+
+const URLS: &[u8] = b"{\"id\":\"abc\",\"url\":\"https://example.com/\"}\n{\"id\":\"def\",\"url\":\"https://example.org/\"}\n";
+const TAGS: &[u8] = b"{\"link_id\":\"abc\",\"tag\":\"news\"}\n";
+
+fn file(name: &str, table: &str, data: &[u8]) -> FileEntry {
+    FileEntry {
+        name: name.into(),
+        table: table.into(),
+        rows: data
+            .split(|&b| b == b'\n')
+            .filter(|l| !l.is_empty())
+            .count() as u64,
+        sha256: sha256(data),
+    }
+}
+
+/// An archive of `entries` with a manifest listing `files`.
+fn archive(entries: &[(&str, &[u8])], files: Vec<FileEntry>) -> Vec<u8> {
+    let manifest = Manifest {
+        format: FORMAT,
+        created_at: Utc::now(),
+        files,
+    };
+    let mut archive = Vec::new();
+    for (name, data) in entries {
+        archive.extend(tar_entry(name, data, 0));
+    }
+    archive.extend(tar_entry(
+        MANIFEST,
+        &serde_json::to_vec(&manifest).unwrap(),
+        0,
+    ));
+    archive.extend(tar_end());
+    archive
+}
+
+fn valid() -> Vec<u8> {
+    archive(
+        &[
+            ("urls/000000.ndjson", URLS),
+            ("link_tags/000000.ndjson", TAGS),
+        ],
+        vec![
+            file("urls/000000.ndjson", "urls", URLS),
+            file("link_tags/000000.ndjson", "link_tags", TAGS),
+        ],
+    )
+}
+
+#[test]
+fn entries_are_read_back() {
+    let archive = valid();
+    assert_eq!(archive.len() % 512, 0);
+    let mut tar = TarReader::new(archive.as_slice());
+    let (name, data) = tar.next_entry().unwrap().unwrap();
+    assert_eq!(
+        (name.as_str(), data.as_slice()),
+        ("urls/000000.ndjson", URLS)
+    );
+    let (name, data) = tar.next_entry().unwrap().unwrap();
+    assert_eq!(
+        (name.as_str(), data.as_slice()),
+        ("link_tags/000000.ndjson", TAGS)
+    );
+    assert_eq!(tar.next_entry().unwrap().unwrap().0, MANIFEST);
+    assert_eq!(tar.next_entry().unwrap(), None);
+}
+
+#[test]
+fn a_whole_archive_verifies() {
+    let manifest = verify(valid().as_slice()).unwrap();
+    assert_eq!(manifest.files.len(), 2);
+    assert_eq!(manifest.rows()["urls"], 2);
+    assert_eq!(manifest.rows()["link_tags"], 1);
+}
+
+#[test]
+fn damaged_archives_are_refused() {
+    let tampered = URLS.to_ascii_uppercase();
+    let cases = [
+        (
+            archive(
+                &[("urls/000000.ndjson", &tampered)],
+                vec![file("urls/000000.ndjson", "urls", URLS)],
+            ),
+            "doesn't match its checksum",
+        ),
+        (
+            archive(&[], vec![file("urls/000000.ndjson", "urls", URLS)]),
+            "is missing",
+        ),
+        (
+            archive(&[("urls/000000.ndjson", URLS)], vec![]),
+            "isn't in the manifest",
+        ),
+        (
+            archive(
+                &[("keys/000000.ndjson", URLS)],
+                vec![file("keys/000000.ndjson", "api_keys", URLS)],
+            ),
+            "unknown table",
+        ),
+        (
+            archive(
+                &[("urls/000000.ndjson", URLS)],
+                vec![FileEntry {
+                    rows: 3,
+                    ..file("urls/000000.ndjson", "urls", URLS)
+                }],
+            ),
+            "has 2 rows, not 3",
+        ),
+        (
+            valid()[..valid().len() - 1024].to_vec(),
+            "without end blocks",
+        ),
+        (tar_end(), "has no manifest"),
+    ];
+    for (archive, expected) in cases {
+        let err = verify(archive.as_slice()).unwrap_err();
+        assert!(err.contains(expected), "{:?} in {:?}", expected, err);
+    }
+}
+
+#[test]
+fn corrupt_headers_are_refused() {
+    let mut archive = valid();
+    archive[0] = b'x';
+    let err = verify(archive.as_slice()).unwrap_err();
+    assert!(err.contains("checksum mismatch"), "{}", err);
+}
@@ -0,0 +1,56 @@
+//! The filter of existing slugs.
+
+use std::convert::Infallible;
+
+use shortener::bloom::SlugFilter;
+
+fn slugs(prefix: &str, count: usize) -> Vec<String> {
+    (0..count).map(|i| format!("{}{}", prefix, i)).collect()
+}
+
+#[tokio::test]
+async fn everything_may_exist_until_built() {
+    let filter = SlugFilter::new();
+    filter.insert("abc");
+    assert!(filter.may_exist("abc"));
+    assert!(filter.may_exist("never"));
+}
+
+#[tokio::test]
+async fn rejects_most_missing_slugs() {
+    let filter = SlugFilter::new();
+    let existing = slugs("link", 10_000);
+    let read = async { Ok::<_, Infallible>(existing.clone()) };
+    assert_eq!(filter.rebuild(read).await.unwrap(), 10_000);
+    filter.insert("added");
+    assert!(existing.iter().all(|slug| filter.may_exist(slug)));
+    assert!(filter.may_exist("added"));
+    let passed = slugs("missing", 10_000)
+        .iter()
+        .filter(|slug| filter.may_exist(slug))
+        .count();
+    assert!(passed < 300, "{} false positives", passed);
+}
+
+#[tokio::test]
+async fn keeps_slugs_added_while_rebuilding() {
+    let filter = SlugFilter::new();
+    let read = async {
+        filter.insert("meanwhile");
+        Ok::<_, Infallible>(vec!["old".to_string()])
+    };
+    filter.rebuild(read).await.unwrap();
+    assert!(filter.may_exist("old"));
+    assert!(filter.may_exist("meanwhile"));
+    assert!(!filter.may_exist("never"));
+}
+
+#[tokio::test]
+async fn failed_rebuilds_keep_the_filter() {
+    let filter = SlugFilter::new();
+    let read = async { Ok::<_, ()>(vec!["old".to_string()]) };
+    filter.rebuild(read).await.unwrap();
+    assert!(filter.rebuild(async { Err(()) }).await.is_err());
+    assert!(filter.may_exist("old"));
+    assert!(!filter.may_exist("never"));
+}
@@ -0,0 +1,83 @@
+use std::time::{Duration, Instant};
+
+use shortener::breaker::{Breaker, BreakerState};
+
+const WINDOW: Duration = Duration::from_secs(10);
+const COOLDOWN: Duration = Duration::from_secs(5);
+
+/// Opens once half of at least 4 calls failed.
+fn breaker() -> Breaker {
+    Breaker::new(0.5, 4, WINDOW, COOLDOWN)
+}
+
+fn call(breaker: &Breaker, ok: bool, now: Instant) {
+    breaker.allow_at(now).unwrap().record_at(ok, now);
+}
+
+#[test]
+fn goes_through_every_state() {
+    let breaker = breaker();
+    let now = Instant::now();
+    call(&breaker, true, now);
+    call(&breaker, false, now);
+    call(&breaker, true, now);
+    assert_eq!(breaker.state(), BreakerState::Closed);
+    call(&breaker, false, now);
+    assert_eq!(breaker.state(), BreakerState::Open);
+
+    // turned away for the rest of the cooldown
+    let later = now + Duration::from_secs(2);
+    assert_eq!(
+        breaker.allow_at(later).unwrap_err(),
+        COOLDOWN - (later - now)
+    );
+
+    // one probe at a time once it's over, failing reopens it
+    let later = now + COOLDOWN;
+    let probe = breaker.allow_at(later).unwrap();
+    assert_eq!(breaker.state(), BreakerState::HalfOpen);
+    assert!(breaker.allow_at(later).is_err());
+    probe.record_at(false, later);
+    assert_eq!(breaker.state(), BreakerState::Open);
+    assert!(breaker.allow_at(later + Duration::from_secs(1)).is_err());
+
+    let later = later + COOLDOWN;
+    call(&breaker, true, later);
+    assert_eq!(breaker.state(), BreakerState::Closed);
+    // starting over with a clean window
+    call(&breaker, false, later);
+    call(&breaker, false, later);
+    assert_eq!(breaker.state(), BreakerState::Closed);
+}
+
+#[test]
+fn only_failures_within_the_window_count() {
+    let breaker = breaker();
+    let now = Instant::now();
+    for _ in 0..3 {
+        call(&breaker, false, now);
+    }
+    assert_eq!(breaker.state(), BreakerState::Closed, "too few calls");
+    let later = now + WINDOW + Duration::from_secs(1);
+    call(&breaker, false, later);
+    call(&breaker, true, later);
+    call(&breaker, true, later);
+    call(&breaker, true, later);
+    assert_eq!(breaker.state(), BreakerState::Closed);
+    call(&breaker, false, later);
+    assert_eq!(breaker.state(), BreakerState::Closed, "2 of 5 failed");
+}
+
+#[test]
+fn a_dropped_probe_lets_another_one_through() {
+    let breaker = breaker();
+    let now = Instant::now();
+    for _ in 0..4 {
+        call(&breaker, false, now);
+    }
+    let later = now + COOLDOWN;
+    drop(breaker.allow_at(later).unwrap());
+    assert_eq!(breaker.state(), BreakerState::HalfOpen);
+    call(&breaker, true, later);
+    assert_eq!(breaker.state(), BreakerState::Closed);
+}
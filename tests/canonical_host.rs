@@ -0,0 +1,88 @@
+//! Redirects to `CANONICAL_HOST`.
+
+use axum::http::{HeaderMap, HeaderValue, Uri};
+use shortener::canonical::{canonical_redirect, own_path};
+
+fn request(host: &str, proto: Option<&str>) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    headers.insert("host", HeaderValue::from_str(host).unwrap());
+    if let Some(proto) = proto {
+        headers.insert("x-forwarded-proto", HeaderValue::from_str(proto).unwrap());
+    }
+    headers
+}
+
+fn redirect(host: &str, proto: Option<&str>, canonical: &str) -> Option<String> {
+    let uri: Uri = "/abc?ref=mail".parse().unwrap();
+    canonical_redirect(&request(host, proto), &uri, canonical)
+}
+
+#[test]
+fn other_hosts_are_redirected() {
+    assert_eq!(
+        redirect("short.example.com", None, "sho.rt").as_deref(),
+        Some("http://sho.rt/abc?ref=mail")
+    );
+    assert_eq!(redirect("sho.rt", None, "sho.rt"), None);
+}
+
+#[test]
+fn hosts_compare_case_insensitively() {
+    assert_eq!(redirect("SHO.rt", None, "sho.rt"), None);
+    assert_eq!(redirect("sho.rt", None, "Sho.RT"), None);
+}
+
+#[test]
+fn ports() {
+    // the scheme's default port is the same as none
+    assert_eq!(redirect("sho.rt:80", None, "sho.rt"), None);
+    assert_eq!(redirect("sho.rt:443", Some("https"), "sho.rt"), None);
+    assert_eq!(redirect("sho.rt:8443", Some("https"), "sho.rt:8443"), None);
+    assert_eq!(
+        redirect("sho.rt:8080", None, "sho.rt").as_deref(),
+        Some("http://sho.rt/abc?ref=mail")
+    );
+    assert_eq!(
+        redirect("sho.rt", None, "sho.rt:8080").as_deref(),
+        Some("http://sho.rt:8080/abc?ref=mail")
+    );
+}
+
+#[test]
+fn forwarded_proto_picks_the_scheme() {
+    assert_eq!(
+        redirect("short.example.com", Some("https"), "sho.rt").as_deref(),
+        Some("https://sho.rt/abc?ref=mail")
+    );
+    // the first proxy's view counts
+    assert_eq!(
+        redirect("short.example.com", Some("HTTPS, http"), "sho.rt").as_deref(),
+        Some("https://sho.rt/abc?ref=mail")
+    );
+    assert_eq!(
+        redirect("short.example.com", Some("http"), "sho.rt").as_deref(),
+        Some("http://sho.rt/abc?ref=mail")
+    );
+}
+
+#[test]
+fn missing_host_is_let_through() {
+    let uri: Uri = "/abc".parse().unwrap();
+    assert_eq!(canonical_redirect(&HeaderMap::new(), &uri, "sho.rt"), None);
+}
+
+#[test]
+fn own_links_are_recognized() {
+    let hosts = ["sho.rt", "127.0.0.1:8080"];
+    assert_eq!(
+        own_path("https://SHO.RT/abc?x=1", &hosts).as_deref(),
+        Some("/abc")
+    );
+    assert_eq!(
+        own_path("http://127.0.0.1:8080/abc", &hosts).as_deref(),
+        Some("/abc")
+    );
+    assert_eq!(own_path("https://sho.rt:8443/abc", &hosts), None);
+    assert_eq!(own_path("http://127.0.0.1/abc", &hosts), None);
+    assert_eq!(own_path("https://example.com/abc", &hosts), None);
+}
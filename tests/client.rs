@@ -0,0 +1,98 @@
+#![cfg(feature = "client")]
+
+use std::{
+    future::IntoFuture,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use axum::{http::StatusCode, response::IntoResponse, routing::get, Json, Router};
+use serde_json::json;
+use shortener::client::{ClientError, ShortenerClient};
+use tokio::net::TcpListener;
+
+/// Serves `/abc.json`, answering the first `failures` requests with
+/// `status` and `retry_after`, and counting them all.
+async fn flaky(
+    failures: usize,
+    status: StatusCode,
+    retry_after: &'static str,
+) -> (String, Arc<AtomicUsize>) {
+    let calls = Arc::new(AtomicUsize::new(0));
+    let seen = calls.clone();
+    let app = Router::new().route(
+        "/abc.json",
+        get(move || async move {
+            if seen.fetch_add(1, Ordering::SeqCst) < failures {
+                let error = match status {
+                    StatusCode::TOO_MANY_REQUESTS => "too_many_requests",
+                    _ => "service_unavailable",
+                };
+                let body = json!({ "error": error, "message": "busy" });
+                return (status, [("retry-after", retry_after)], Json(body)).into_response();
+            }
+            Json(json!({ "id": "abc", "url": "https://example.com/" })).into_response()
+        }),
+    );
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let base = format!("http://{}", listener.local_addr().unwrap());
+    tokio::spawn(axum::serve(listener, app).into_future());
+    (base, calls)
+}
+
+#[tokio::test]
+async fn unavailable_answers_are_retried() {
+    let (base, calls) = flaky(2, StatusCode::SERVICE_UNAVAILABLE, "0").await;
+    let client = ShortenerClient::new(base, "key");
+    let resolved = client.resolve("abc").await.unwrap();
+    assert_eq!(resolved.url, "https://example.com/");
+    assert_eq!(calls.load(Ordering::SeqCst), 3);
+}
+
+#[tokio::test]
+async fn retries_run_out() {
+    let (base, calls) = flaky(5, StatusCode::SERVICE_UNAVAILABLE, "0").await;
+    let client = ShortenerClient::new(base, "key").with_retries(1);
+    let err = client.resolve("abc").await.unwrap_err();
+    assert!(
+        matches!(&err, ClientError::Unavailable { retry_after: Some(d), body }
+            if d.is_zero() && body.message == "busy"),
+        "{:?}",
+        err
+    );
+    assert_eq!(calls.load(Ordering::SeqCst), 2);
+}
+
+#[tokio::test]
+async fn long_retry_afters_are_not_waited_for() {
+    let (base, calls) = flaky(1, StatusCode::TOO_MANY_REQUESTS, "3600").await;
+    let client = ShortenerClient::new(base, "key");
+    let started = Instant::now();
+    let err = client.resolve("abc").await.unwrap_err();
+    assert!(
+        matches!(err, ClientError::RateLimited { retry_after: Some(d) } if d.as_secs() == 3600),
+        "{:?}",
+        err
+    );
+    assert!(started.elapsed() < Duration::from_secs(5));
+    assert_eq!(calls.load(Ordering::SeqCst), 1);
+}
+
+#[tokio::test]
+async fn foreign_error_bodies_are_mapped_by_status() {
+    let app = Router::new().route(
+        "/abc.json",
+        get(|| async { (StatusCode::NOT_FOUND, "nope") }),
+    );
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let base = format!("http://{}", listener.local_addr().unwrap());
+    tokio::spawn(axum::serve(listener, app).into_future());
+    let client = ShortenerClient::new(base, "key");
+    assert!(matches!(
+        client.resolve("abc").await,
+        Err(ClientError::NotFound)
+    ));
+}
@@ -0,0 +1,87 @@
+//! `X-Forwarded-For` handling behind trusted proxies.
+
+use std::net::IpAddr;
+
+use axum::http::{HeaderMap, HeaderValue};
+use ipnet::IpNet;
+use shortener::client_ip::real_client_ip;
+
+fn ip(s: &str) -> IpAddr {
+    s.parse().unwrap()
+}
+
+fn forwarded(values: &[&str]) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    for v in values {
+        headers.append("x-forwarded-for", HeaderValue::from_str(v).unwrap());
+    }
+    headers
+}
+
+fn trusted() -> Vec<IpNet> {
+    vec!["10.0.0.0/8".parse().unwrap(), "fd00::/8".parse().unwrap()]
+}
+
+#[test]
+fn untrusted_peer_is_the_client() {
+    let headers = forwarded(&["203.0.113.7"]);
+    assert_eq!(
+        real_client_ip(&headers, ip("198.51.100.1"), &trusted()),
+        ip("198.51.100.1")
+    );
+    // nothing is trusted by default
+    assert_eq!(
+        real_client_ip(&headers, ip("10.0.0.1"), &[]),
+        ip("10.0.0.1")
+    );
+}
+
+#[test]
+fn single_proxy() {
+    let headers = forwarded(&["203.0.113.7"]);
+    assert_eq!(
+        real_client_ip(&headers, ip("10.0.0.1"), &trusted()),
+        ip("203.0.113.7")
+    );
+    // a proxy that didn't add the header
+    assert_eq!(
+        real_client_ip(&HeaderMap::new(), ip("10.0.0.1"), &trusted()),
+        ip("10.0.0.1")
+    );
+}
+
+#[test]
+fn multiple_proxies() {
+    let headers = forwarded(&["203.0.113.7, 10.1.2.3", "fd00::5"]);
+    assert_eq!(
+        real_client_ip(&headers, ip("10.0.0.1"), &trusted()),
+        ip("203.0.113.7")
+    );
+    let headers = forwarded(&["[2001:db8::1]:443, 10.1.2.3:8080"]);
+    assert_eq!(
+        real_client_ip(&headers, ip("10.0.0.1"), &trusted()),
+        ip("2001:db8::1")
+    );
+}
+
+#[test]
+fn spoofed_entries_are_ignored() {
+    // the client prepended a fake address; the proxy appended the real one
+    let headers = forwarded(&["1.1.1.1, 203.0.113.7"]);
+    assert_eq!(
+        real_client_ip(&headers, ip("10.0.0.1"), &trusted()),
+        ip("203.0.113.7")
+    );
+    // a trusted-looking address left of the client is never reached
+    let headers = forwarded(&["10.9.9.9, 203.0.113.7, 10.1.2.3"]);
+    assert_eq!(
+        real_client_ip(&headers, ip("10.0.0.1"), &trusted()),
+        ip("203.0.113.7")
+    );
+    // garbage stops the walk at the proxy that forwarded it
+    let headers = forwarded(&["203.0.113.7, not-an-ip, 10.1.2.3"]);
+    assert_eq!(
+        real_client_ip(&headers, ip("10.0.0.1"), &trusted()),
+        ip("10.1.2.3")
+    );
+}
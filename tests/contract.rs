@@ -0,0 +1,354 @@
+//! The exact JSON of the API's bodies, so a refactor can't change the wire
+//! format without a test saying so.
+
+use chrono::{DateTime, TimeZone, Utc};
+use serde_json::{json, Value};
+use shortener::{
+    api::{KitAsset, KitReq, LinkKit, LinkStats, QrImage, Resolved, ShortReq, ShortRes},
+    auth::{ApiKey, KeyRecord, Scope},
+    casing::{accept_camel, camel_case, mirror_camel, snake_case},
+    claims::{Challenge, Transfer},
+    error::ErrorBody,
+    latency::Percentiles,
+    platform::PlatformTargets,
+    policies::Policy,
+};
+
+fn at() -> DateTime<Utc> {
+    Utc.with_ymd_and_hms(2024, 5, 1, 12, 0, 0).unwrap()
+}
+
+fn wire<T: serde::Serialize>(body: &T) -> Value {
+    serde_json::to_value(body).unwrap()
+}
+
+#[test]
+fn short_res() {
+    let mut res = ShortRes {
+        url: "http://localhost:8080/abc123".into(),
+        upgraded: false,
+        slugs: vec!["abc123".into(), "promo".into()],
+        tags: vec!["launch".into()],
+        created: true,
+        created_at: at(),
+        description: None,
+        expires_at: Some(at()),
+        clamped: true,
+        management_token: None,
+        stats_url: None,
+        qr_url: None,
+    };
+    assert_eq!(
+        wire(&res),
+        json!({
+            "url": "http://localhost:8080/abc123",
+            "upgraded": false,
+            "slugs": ["abc123", "promo"],
+            "tags": ["launch"],
+            "created": true,
+            "created_at": "2024-05-01T12:00:00Z",
+            "description": null,
+            "expires_at": "2024-05-01T12:00:00Z",
+            "clamped": true,
+        })
+    );
+    res.management_token = Some("secret".into());
+    assert_eq!(wire(&res)["management_token"], "secret");
+    res.stats_url = Some("http://localhost:8080/api/links/abc123/stats".into());
+    assert_eq!(
+        wire(&res)["stats_url"],
+        "http://localhost:8080/api/links/abc123/stats"
+    );
+    res.qr_url = Some("http://localhost:8080/api/links/abc123/qr".into());
+    assert_eq!(
+        wire(&res)["qr_url"],
+        "http://localhost:8080/api/links/abc123/qr"
+    );
+}
+
+#[test]
+fn link_kit() {
+    let req: KitReq = serde_json::from_value(json!({
+        "link": { "url": "https://example.com/" },
+        "include": ["qr"],
+        "qr_scale": 4,
+    }))
+    .unwrap();
+    assert_eq!(wire(&req)["include"], json!(["qr"]));
+    let mut kit = LinkKit {
+        id: "abc123".into(),
+        link: ShortRes {
+            url: "http://localhost:8080/abc123".into(),
+            upgraded: false,
+            slugs: vec!["abc123".into()],
+            tags: vec![],
+            created: true,
+            created_at: at(),
+            description: None,
+            expires_at: None,
+            clamped: false,
+            management_token: None,
+            stats_url: None,
+            qr_url: None,
+        },
+        qr: None,
+    };
+    assert_eq!(
+        wire(&kit),
+        json!({
+            "id": "abc123",
+            "url": "http://localhost:8080/abc123",
+            "upgraded": false,
+            "slugs": ["abc123"],
+            "tags": [],
+            "created": true,
+            "created_at": "2024-05-01T12:00:00Z",
+            "description": null,
+            "expires_at": null,
+            "clamped": false,
+        })
+    );
+    kit.qr = Some(KitAsset::Ready(QrImage {
+        scale: 4,
+        png_base64: "iVBORw0K".into(),
+    }));
+    let body = wire(&kit);
+    assert_eq!(body["qr"], json!({ "scale": 4, "png_base64": "iVBORw0K" }));
+    let read: LinkKit = serde_json::from_value(body).unwrap();
+    assert!(matches!(read.qr, Some(KitAsset::Ready(_))));
+    kit.qr = Some(KitAsset::Failed(ErrorBody::new(
+        "too_long",
+        "the short url is too long for a QR code",
+    )));
+    let body = wire(&kit);
+    assert_eq!(body["qr"]["error"], "too_long");
+    let read: LinkKit = serde_json::from_value(body).unwrap();
+    assert!(matches!(read.qr, Some(KitAsset::Failed(_))));
+}
+
+#[test]
+fn resolved_and_link_stats() {
+    let resolved = Resolved {
+        id: "abc123".into(),
+        url: "https://example.com/".into(),
+    };
+    assert_eq!(
+        wire(&resolved),
+        json!({ "id": "abc123", "url": "https://example.com/" })
+    );
+    let mut stats = LinkStats {
+        id: "abc123".into(),
+        slugs: vec!["abc123".into()],
+        clicks: 7,
+        uniques: None,
+        notes: Some("for the newsletter".into()),
+        click_dedup_secs: Some(60),
+        geo_clicks: None,
+    };
+    assert_eq!(
+        wire(&stats),
+        json!({
+            "id": "abc123",
+            "slugs": ["abc123"],
+            "clicks": 7,
+            "uniques": null,
+            "notes": "for the newsletter",
+            "click_dedup_secs": 60,
+        })
+    );
+    stats.geo_clicks = Some([("DE".into(), 3), ("default".into(), 4)].into());
+    assert_eq!(wire(&stats)["geo_clicks"], json!({ "DE": 3, "default": 4 }));
+}
+
+#[test]
+fn error_body() {
+    let body = ErrorBody {
+        error: "invalid_body".into(),
+        message: "unknown field `Url`".into(),
+        path: None,
+        details: None,
+    };
+    assert_eq!(
+        wire(&body),
+        json!({ "error": "invalid_body", "message": "unknown field `Url`" })
+    );
+    let body = ErrorBody {
+        error: "internal_server_error".into(),
+        message: "Internal Server Error".into(),
+        path: Some("tags[0]".into()),
+        details: Some(json!({ "request_id": "r-1" })),
+    };
+    assert_eq!(
+        wire(&body),
+        json!({
+            "error": "internal_server_error",
+            "message": "Internal Server Error",
+            "path": "tags[0]",
+            "request_id": "r-1",
+        })
+    );
+}
+
+#[test]
+fn keys() {
+    let key = ApiKey {
+        id: Some(3),
+        label: "ci".into(),
+        scopes: vec![Scope::Read, Scope::Write],
+    };
+    assert_eq!(
+        wire(&key),
+        json!({ "id": 3, "label": "ci", "scopes": ["read", "write"] })
+    );
+    let record = KeyRecord {
+        id: 3,
+        label: "ci".into(),
+        scopes: vec!["read".into()],
+        created_at: at(),
+        revoked_at: None,
+    };
+    assert_eq!(
+        wire(&record),
+        json!({
+            "id": 3,
+            "label": "ci",
+            "scopes": ["read"],
+            "created_at": "2024-05-01T12:00:00Z",
+            "revoked_at": null,
+        })
+    );
+}
+
+#[test]
+fn policies_claims_and_latency() {
+    let policy = Policy {
+        id: 1,
+        link_id: None,
+        pattern: Some("*.example.com".into()),
+        reason: None,
+        created_at: at(),
+    };
+    assert_eq!(
+        wire(&policy),
+        json!({
+            "id": 1,
+            "link_id": null,
+            "pattern": "*.example.com",
+            "reason": null,
+            "created_at": "2024-05-01T12:00:00Z",
+        })
+    );
+    let challenge = Challenge {
+        token: "t0k3n".into(),
+        verification_url: "https://example.com/.well-known/shortener-claim".into(),
+        expires_at: at(),
+    };
+    assert_eq!(
+        wire(&challenge),
+        json!({
+            "token": "t0k3n",
+            "verification_url": "https://example.com/.well-known/shortener-claim",
+            "expires_at": "2024-05-01T12:00:00Z",
+        })
+    );
+    let transfer = Transfer {
+        link_id: "abc123".into(),
+        from_owner: "1".into(),
+        to_owner: "2".into(),
+        created_at: at(),
+    };
+    assert_eq!(
+        wire(&transfer),
+        json!({
+            "link_id": "abc123",
+            "from_owner": "1",
+            "to_owner": "2",
+            "created_at": "2024-05-01T12:00:00Z",
+        })
+    );
+    let percentiles = Percentiles {
+        samples: 2,
+        p50_ms: Some(1.5),
+        p95_ms: Some(3.0),
+        p99_ms: None,
+    };
+    assert_eq!(
+        wire(&percentiles),
+        json!({ "samples": 2, "p50_ms": 1.5, "p95_ms": 3.0, "p99_ms": null })
+    );
+    let targets = PlatformTargets {
+        ios: Some("https://apps.apple.com/app/id1".into()),
+        ..Default::default()
+    };
+    assert_eq!(
+        wire(&targets),
+        json!({ "ios": "https://apps.apple.com/app/id1" })
+    );
+}
+
+#[test]
+fn requests_refuse_unknown_fields() {
+    let req: ShortReq = serde_json::from_value(json!({
+        "url": "https://example.com/",
+        "expires_in_secs": 60,
+    }))
+    .unwrap();
+    assert_eq!(req.expires_in_secs, Some(60));
+    for typo in [
+        json!({ "Url": "https://example.com/" }),
+        json!({ "url": "https://example.com/", "expiresInSecs": 60 }),
+    ] {
+        let err = serde_json::from_value::<ShortReq>(typo).unwrap_err();
+        assert!(err.to_string().starts_with("unknown field"), "{}", err);
+    }
+}
+
+#[test]
+fn casing() {
+    assert_eq!(snake_case("expiresInSecs").unwrap(), "expires_in_secs");
+    assert_eq!(snake_case("p99Ms").unwrap(), "p99_ms");
+    for kept in ["url", "Url", "created_at", "URL"] {
+        assert_eq!(snake_case(kept), None, "{}", kept);
+    }
+    assert_eq!(camel_case("expires_in_secs").unwrap(), "expiresInSecs");
+    assert_eq!(camel_case("p99_ms").unwrap(), "p99Ms");
+    for kept in ["url", "_private", "trailing_", "double__underscore"] {
+        assert_eq!(camel_case(kept), None, "{}", kept);
+    }
+}
+
+#[test]
+fn camel_case_compat() {
+    let mut req = json!({
+        "url": "https://example.com/",
+        "expiresInSecs": 60,
+        "platformTargets": { "ios": "https://apps.apple.com/app/id1" },
+        "Url": "typo",
+        "forwardQuery": true,
+        "forward_query": false,
+    });
+    accept_camel(&mut req);
+    assert_eq!(
+        req,
+        json!({
+            "url": "https://example.com/",
+            "expires_in_secs": 60,
+            "platform_targets": { "ios": "https://apps.apple.com/app/id1" },
+            // left for the handler to refuse
+            "Url": "typo",
+            "forwardQuery": true,
+            "forward_query": false,
+        })
+    );
+
+    let mut res = json!([{ "created_at": "2024-05-01T12:00:00Z", "stats": { "p50_ms": 1.5 } }]);
+    mirror_camel(&mut res);
+    assert_eq!(
+        res,
+        json!([{
+            "created_at": "2024-05-01T12:00:00Z",
+            "createdAt": "2024-05-01T12:00:00Z",
+            "stats": { "p50_ms": 1.5, "p50Ms": 1.5 },
+        }])
+    );
+}
@@ -0,0 +1,46 @@
+use std::{
+    net::{IpAddr, Ipv4Addr},
+    time::{Duration, Instant},
+};
+
+use shortener::dedup::ClickDedup;
+
+const WINDOW: Duration = Duration::from_secs(10);
+const IP: IpAddr = IpAddr::V4(Ipv4Addr::new(203, 0, 113, 7));
+
+#[test]
+fn repeats_within_the_window_are_spotted() {
+    let dedup = ClickDedup::new(WINDOW, 100);
+    let now = Instant::now();
+    assert!(!dedup.is_repeat_at("abc", IP, Some("firefox"), now));
+    assert!(dedup.is_repeat_at("abc", IP, Some("firefox"), now + Duration::from_secs(1)));
+    assert!(dedup.is_repeat_at("abc", IP, Some("firefox"), now + Duration::from_secs(9)));
+    // the window runs from the click counted, not the last repeat
+    assert!(!dedup.is_repeat_at("abc", IP, Some("firefox"), now + WINDOW));
+}
+
+#[test]
+fn other_visitors_and_links_count() {
+    let dedup = ClickDedup::new(WINDOW, 100);
+    let now = Instant::now();
+    let other_ip = IpAddr::V4(Ipv4Addr::new(203, 0, 113, 8));
+    assert!(!dedup.is_repeat_at("abc", IP, Some("firefox"), now));
+    assert!(!dedup.is_repeat_at("abc", IP, Some("chrome"), now));
+    assert!(!dedup.is_repeat_at("abc", IP, None, now));
+    assert!(!dedup.is_repeat_at("abc", other_ip, Some("firefox"), now));
+    assert!(!dedup.is_repeat_at("def", IP, Some("firefox"), now));
+    assert_eq!(dedup.len(), 5);
+}
+
+#[test]
+fn memory_stays_bounded() {
+    let dedup = ClickDedup::new(WINDOW, 64);
+    let now = Instant::now();
+    for i in 0..10_000 {
+        dedup.is_repeat_at(&format!("link{}", i), IP, None, now);
+    }
+    assert!(dedup.len() <= 64, "{}", dedup.len());
+    // the oldest were forgotten, the latest are remembered
+    assert!(!dedup.is_repeat_at("link0", IP, None, now));
+    assert!(dedup.is_repeat_at("link9999", IP, None, now));
+}
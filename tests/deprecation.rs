@@ -0,0 +1,85 @@
+//! Telling callers of deprecated routes when they stop working.
+
+use axum::{
+    body::Body,
+    http::{Method, Request, StatusCode},
+    middleware,
+    routing::{get, post},
+    Router,
+};
+use chrono::{TimeZone, Utc};
+use shortener::deprecation::{self, Deprecation, Deprecations};
+use tower::ServiceExt;
+
+fn app(deprecations: &Deprecations) -> Router {
+    Router::new()
+        .route("/", post(|| async { "created" }))
+        .route("/:id", get(|| async { "found" }))
+        .route("/api/v1", post(|| async { "created" }))
+        .layer(middleware::from_fn_with_state(
+            deprecations.clone(),
+            deprecation::mark,
+        ))
+}
+
+fn request(method: Method, path: &str) -> Request<Body> {
+    Request::builder()
+        .method(method)
+        .uri(path)
+        .body(Body::empty())
+        .unwrap()
+}
+
+#[tokio::test]
+async fn deprecated_routes_say_so_and_count_calls() {
+    let deprecations = Deprecations::new(vec![
+        Deprecation {
+            method: Method::POST,
+            route: "/",
+            sunset: Utc.with_ymd_and_hms(2027, 1, 31, 0, 0, 0).unwrap(),
+            successor: Some("/api/v1".into()),
+        },
+        Deprecation {
+            method: Method::GET,
+            route: "/:id",
+            sunset: Utc.with_ymd_and_hms(2027, 6, 1, 12, 30, 0).unwrap(),
+            successor: None,
+        },
+    ]);
+
+    let res = app(&deprecations)
+        .oneshot(request(Method::POST, "/"))
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    let headers = res.headers();
+    assert_eq!(headers["deprecation"], "true");
+    assert_eq!(headers["sunset"], "Sun, 31 Jan 2027 00:00:00 GMT");
+    assert_eq!(headers["link"], "</api/v1>; rel=\"successor-version\"");
+
+    // matched by the route, HEAD counting as GET
+    for method in [Method::GET, Method::HEAD] {
+        let res = app(&deprecations)
+            .oneshot(request(method, "/abc123"))
+            .await
+            .unwrap();
+        assert_eq!(res.headers()["sunset"], "Tue, 01 Jun 2027 12:30:00 GMT");
+        assert!(res.headers().get("link").is_none());
+    }
+
+    let res = app(&deprecations)
+        .oneshot(request(Method::POST, "/api/v1"))
+        .await
+        .unwrap();
+    assert!(res.headers().get("deprecation").is_none());
+
+    let hits: Vec<(&str, String, u64)> = deprecations
+        .list()
+        .into_iter()
+        .map(|route| (route.route, route.method, route.hits))
+        .collect();
+    assert_eq!(
+        hits,
+        [("/", "POST".to_string(), 1), ("/:id", "GET".to_string(), 2)]
+    );
+}
@@ -0,0 +1,7860 @@
+//! End-to-end tests running the full router against a real Postgres.
+//!
+//! Skipped unless `E2E=1` is set. Each test gets its own database: with
+//! `E2E_DATABASE_URL` a fresh database is created on that server, otherwise a
+//! throwaway Postgres container is started through Docker.
+
+use std::{
+    collections::HashSet,
+    future::IntoFuture,
+    net::SocketAddr,
+    sync::{Arc, Mutex, OnceLock},
+    time::{Duration, Instant},
+};
+
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use futures::future::join_all;
+use metrics_exporter_prometheus::{PrometheusBuilder, PrometheusHandle, PrometheusRecorder};
+use nanoid::nanoid;
+use reqwest::{header::LOCATION, redirect::Policy, Client, StatusCode};
+use serde_json::{json, Value};
+use shortener::{
+    config::{Config, RepairMode},
+    jobs::{self, Job, Worker},
+    merge, qr,
+    slug::IdGenerator,
+    AppState, PgState,
+};
+use sqlx::PgPool;
+use testcontainers_modules::{
+    postgres::Postgres,
+    testcontainers::{runners::AsyncRunner, ContainerAsync},
+};
+use tokio::net::TcpListener;
+use url::Url;
+
+const ADMIN_KEY: &str = "e2e-admin";
+const LOWER_ALPHABET: [char; 26] = [
+    'a', 'b', 'c', 'd', 'e', 'f', 'g', 'h', 'i', 'j', 'k', 'l', 'm', 'n', 'o', 'p', 'q', 'r', 's',
+    't', 'u', 'v', 'w', 'x', 'y', 'z',
+];
+
+struct TestApp {
+    base: String,
+    client: Client,
+    state: AppState,
+    pool: PgPool,
+    db_url: String,
+    /// Dropping the container stops it.
+    _container: Option<ContainerAsync<Postgres>>,
+}
+
+impl TestApp {
+    /// Returns `None` when end-to-end tests are disabled.
+    async fn spawn() -> Option<Self> {
+        Self::spawn_with(|_, db| db).await
+    }
+
+    /// Like [`spawn`](Self::spawn), letting the test adjust the config and
+    /// the store first.
+    async fn spawn_with(customize: impl FnOnce(&mut Config, PgState) -> PgState) -> Option<Self> {
+        Self::spawn_configured(|_| {}, customize).await
+    }
+
+    /// Like [`spawn_with`](Self::spawn_with), with `configure` called before
+    /// the store is opened.
+    async fn spawn_configured(
+        configure: impl FnOnce(&mut Config),
+        customize: impl FnOnce(&mut Config, PgState) -> PgState,
+    ) -> Option<Self> {
+        let (db_url, container) = database().await?;
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut config = Config::from_env().expect("invalid config in environment");
+        config.db_url = db_url.clone();
+        config.listen_addr = addr.to_string();
+        config.api_key = Some(ADMIN_KEY.into());
+        configure(&mut config);
+        let db_url = config.db_url.clone();
+        let db = PgState::try_new(&config).await.expect("failed to migrate");
+        let db = customize(&mut config, db);
+        let metrics = metrics();
+        let pool = db.pool().clone();
+        let state = AppState::new(db, config, metrics).unwrap();
+        let router = shortener::app(state.clone()).unwrap();
+        tokio::spawn(
+            axum::serve(
+                listener,
+                router.into_make_service_with_connect_info::<SocketAddr>(),
+            )
+            .into_future(),
+        );
+
+        Some(Self {
+            base: format!("http://{}", addr),
+            client: Client::builder().redirect(Policy::none()).build().unwrap(),
+            state,
+            pool,
+            db_url,
+            _container: container,
+        })
+    }
+
+    /// Shortens `url` and returns the link's id, new or existing.
+    async fn shorten(&self, url: &str) -> String {
+        let res = self.post_url(url).await;
+        assert!(res.status().is_success(), "{}", res.status());
+        let body: Value = res.json().await.unwrap();
+        let short = body["url"].as_str().unwrap();
+        short.rsplit('/').next().unwrap().to_string()
+    }
+
+    /// Creates an API key through the admin API and returns its secret.
+    async fn create_key(&self, label: &str, scopes: &[&str]) -> String {
+        let res = self
+            .client
+            .post(format!("{}/api/keys", self.base))
+            .bearer_auth(ADMIN_KEY)
+            .json(&json!({ "label": label, "scopes": scopes }))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::CREATED);
+        let body: Value = res.json().await.unwrap();
+        body["key"].as_str().unwrap().to_string()
+    }
+
+    async fn post_url(&self, url: &str) -> reqwest::Response {
+        self.client
+            .post(&self.base)
+            .json(&json!({ "url": url }))
+            .send()
+            .await
+            .unwrap()
+    }
+
+    async fn get(&self, path: &str) -> reqwest::Response {
+        self.client
+            .get(format!("{}{}", self.base, path))
+            .send()
+            .await
+            .unwrap()
+    }
+}
+
+/// The process-wide metrics recorder, which every app of the run shares.
+fn metrics() -> PrometheusHandle {
+    static HANDLE: OnceLock<PrometheusHandle> = OnceLock::new();
+    HANDLE
+        .get_or_init(|| PrometheusBuilder::new().install_recorder().unwrap())
+        .clone()
+}
+
+/// A recorder for the metrics of one test, whose counts the tests running
+/// alongside don't add to. It records while the guard of
+/// `metrics::set_default_local_recorder` lives: a test and the app it
+/// spawned share the test's thread.
+fn local_metrics() -> PrometheusRecorder {
+    PrometheusBuilder::new().build_recorder()
+}
+
+/// Returns a url to an empty database, and the container serving it if one
+/// was started. `None` when end-to-end tests are disabled.
+async fn database() -> Option<(String, Option<ContainerAsync<Postgres>>)> {
+    if std::env::var_os("E2E").is_none() {
+        eprintln!("skipping end-to-end test, set E2E=1 to run it");
+        return None;
+    }
+    if let Ok(url) = std::env::var("E2E_DATABASE_URL") {
+        return Some((fresh_database(&url).await, None));
+    }
+    // randomized so parallel jobs sharing a docker host can't connect to
+    // each other's database
+    let user = nanoid!(12, &LOWER_ALPHABET);
+    let password = nanoid!(24);
+    let container = Postgres::default()
+        .with_user(&user)
+        .with_password(&password)
+        .with_db_name("shortener")
+        .start()
+        .await
+        .expect("failed to start postgres container");
+    let port = container
+        .get_host_port_ipv4(5432)
+        .await
+        .expect("postgres port not mapped");
+    let url = format!(
+        "postgres://{}:{}@127.0.0.1:{}/shortener",
+        user, password, port
+    );
+    Some((url, Some(container)))
+}
+
+/// Creates an empty database on the server behind `url` and returns a url
+/// pointing at it.
+async fn fresh_database(url: &str) -> String {
+    let name = format!("e2e_{}", nanoid!(12, &LOWER_ALPHABET));
+    let pool = PgPool::connect(url)
+        .await
+        .expect("E2E_DATABASE_URL unreachable");
+    sqlx::query(&format!("CREATE DATABASE {}", name))
+        .execute(&pool)
+        .await
+        .unwrap();
+    let mut url = Url::parse(url).unwrap();
+    url.set_path(&name);
+    url.to_string()
+}
+
+fn location(res: &reqwest::Response) -> &str {
+    res.headers()[LOCATION].to_str().unwrap()
+}
+
+#[tokio::test]
+async fn link_lifecycle() {
+    let Some(app) = TestApp::spawn().await else {
+        return;
+    };
+
+    let id = app.shorten("https://www.rust-lang.org/").await;
+    assert_eq!(app.shorten("https://www.rust-lang.org/").await, id);
+
+    let res = app.get(&format!("/{}", id)).await;
+    assert_eq!(res.status(), StatusCode::FOUND);
+    assert_eq!(location(&res), "https://www.rust-lang.org/");
+
+    assert_eq!(app.get("/nope42").await.status(), StatusCode::NOT_FOUND);
+
+    let res = app
+        .client
+        .delete(format!("{}/{}", app.base, id))
+        .bearer_auth(ADMIN_KEY)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::NO_CONTENT);
+    assert_eq!(
+        app.get(&format!("/{}", id)).await.status(),
+        StatusCode::NOT_FOUND
+    );
+}
+
+#[tokio::test]
+async fn concurrent_creation() {
+    let Some(app) = TestApp::spawn().await else {
+        return;
+    };
+
+    let distinct = (0..20).map(|i| {
+        let app = &app;
+        async move { app.shorten(&format!("https://example.com/{}", i)).await }
+    });
+    let ids: HashSet<String> = join_all(distinct).await.into_iter().collect();
+    assert_eq!(ids.len(), 20);
+
+    let same = (0..10).map(|_| app.shorten("https://example.com/same"));
+    let ids: HashSet<String> = join_all(same).await.into_iter().collect();
+    assert_eq!(ids.len(), 1);
+}
+
+#[tokio::test]
+async fn count_live_links() {
+    let Some(app) = TestApp::spawn().await else {
+        return;
+    };
+
+    for i in 0..3 {
+        app.shorten(&format!("https://example.com/{}", i)).await;
+    }
+    let res = app
+        .client
+        .get(format!("{}/api/count", app.base))
+        .bearer_auth(ADMIN_KEY)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    let body: Value = res.json().await.unwrap();
+    assert_eq!(body["total"], 3);
+}
+
+#[tokio::test]
+async fn forwards_query_string() {
+    let Some(app) = TestApp::spawn().await else {
+        return;
+    };
+
+    let id = app.shorten("https://example.com/p?a=1&b=x%26y#top").await;
+    let res = app.get(&format!("/{}?a=2&ref=news&empty=", id)).await;
+    assert_eq!(
+        location(&res),
+        "https://example.com/p?a=1&b=x%26y&ref=news&empty=#top"
+    );
+}
+
+#[tokio::test]
+async fn exhausted_id_space() {
+    let Some(app) =
+        TestApp::spawn_with(|_, db| db.with_id_generator(IdGenerator::custom(|| "always".into())))
+            .await
+    else {
+        return;
+    };
+
+    assert_eq!(app.shorten("https://example.com/1").await, "always");
+    let res = app.post_url("https://example.com/2").await;
+    assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+    let body: Value = res.json().await.unwrap();
+    assert_eq!(body["error"], "id_space_exhausted");
+}
+
+#[tokio::test]
+async fn taken_generated_ids_are_retried() {
+    let ids = Arc::new(Mutex::new(vec!["dup", "promo", "dup", "fresh"].into_iter()));
+    let Some(app) = TestApp::spawn_with(|_, db| {
+        db.with_id_generator(IdGenerator::custom(move || {
+            ids.lock().unwrap().next().unwrap_or("spare").to_string()
+        }))
+    })
+    .await
+    else {
+        return;
+    };
+
+    assert_eq!(app.shorten("https://example.com/first").await, "dup");
+    let res = app
+        .client
+        .post(format!("{}/api/links/dup/aliases", app.base))
+        .bearer_auth(ADMIN_KEY)
+        .json(&json!({ "alias": "promo" }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::CREATED);
+    // "promo" clashes with an alias and "dup" with a link, both are skipped
+    assert_eq!(app.shorten("https://example.com/second").await, "fresh");
+    assert_eq!(
+        location(&app.get("/promo").await),
+        "https://example.com/first"
+    );
+    assert_eq!(
+        location(&app.get("/fresh").await),
+        "https://example.com/second"
+    );
+}
+
+#[tokio::test]
+async fn taken_aliases_conflict() {
+    // repeats sent at once would get the first result instead
+    let Some(app) = TestApp::spawn_with(|config, db| {
+        config.shorten_coalesce_window = Duration::ZERO;
+        db
+    })
+    .await
+    else {
+        return;
+    };
+    let create = |url: &'static str, alias: String| {
+        let app = &app;
+        async move {
+            app.client
+                .post(&app.base)
+                .json(&json!({ "url": url, "alias": alias }))
+                .send()
+                .await
+                .unwrap()
+        }
+    };
+
+    let res = create("https://example.com/a", "launch".into()).await;
+    assert_eq!(res.status(), StatusCode::CREATED);
+    let body: Value = res.json().await.unwrap();
+    assert!(body["url"].as_str().unwrap().ends_with("/launch"));
+
+    // the same url still gets a conflict rather than the existing link
+    for url in ["https://example.com/b", "https://example.com/a"] {
+        let res = create(url, "launch".into()).await;
+        assert_eq!(res.status(), StatusCode::CONFLICT);
+        let body: Value = res.json().await.unwrap();
+        assert_eq!(body["error"], "alias_taken");
+    }
+    let generated = app.shorten("https://example.com/c").await;
+    let res = create("https://example.com/d", generated).await;
+    assert_eq!(res.status(), StatusCode::CONFLICT);
+    let res = create("https://example.com/e", "not a slug".into()).await;
+    assert_eq!(res.status(), StatusCode::UNPROCESSABLE_ENTITY);
+
+    // nothing of the rejected links was left behind
+    let (links,): (i64,) = sqlx::query_as("SELECT COUNT(*) FROM urls")
+        .fetch_one(&app.pool)
+        .await
+        .unwrap();
+    assert_eq!(links, 2);
+    let res = app.get("/launch").await;
+    assert_eq!(location(&res), "https://example.com/a");
+}
+
+#[tokio::test]
+async fn legacy_schema() {
+    let Some((db_url, _container)) = database().await else {
+        return;
+    };
+    let pool = PgPool::connect(&db_url).await.unwrap();
+    sqlx::query("CREATE TABLE urls (id VARCHAR(6), url TEXT NOT NULL UNIQUE)")
+        .execute(&pool)
+        .await
+        .unwrap();
+    let mut config = Config::from_env().unwrap();
+    config.db_url = db_url;
+
+    let err = PgState::try_new(&config).await.unwrap_err().to_string();
+    assert!(err.contains("urls.id is character varying(6), expected text"));
+    assert!(err.contains("missing PRIMARY KEY (id) on urls"));
+
+    config.fix_schema = true;
+    assert!(PgState::try_new(&config).await.is_ok());
+}
+
+#[tokio::test]
+async fn repair_gives_legacy_rows_fresh_ids() {
+    let Some((db_url, _container)) = database().await else {
+        return;
+    };
+    let pool = PgPool::connect(&db_url).await.unwrap();
+    sqlx::query("CREATE TABLE urls (id VARCHAR(6), url TEXT NOT NULL)")
+        .execute(&pool)
+        .await
+        .unwrap();
+    for (id, url) in [
+        (Some(""), "https://example.com/empty"),
+        (Some(""), "https://example.com/empty-again"),
+        (None, "https://example.com/null"),
+        (Some("dup"), "https://example.com/first"),
+        (Some("dup"), "https://example.com/second"),
+        (Some("a b"), "https://example.com/space"),
+        (Some("UPPER"), "https://example.com/upper"),
+        (Some("fine"), "https://example.com/fine"),
+    ] {
+        sqlx::query("INSERT INTO urls (id, url) VALUES ($1, $2)")
+            .bind(id)
+            .bind(url)
+            .execute(&pool)
+            .await
+            .unwrap();
+    }
+    let mut config = Config::from_env().unwrap();
+    config.db_url = db_url;
+    config.id_alphabet = "abcdefghijklmnopqrstuvwxyz0123456789".into();
+    config.skip_schema_check = true;
+    config.repair = Some(RepairMode::DryRun);
+    PgState::try_new(&config).await.unwrap();
+    for (id, clicks) in ["", "dup", "a b"].iter().zip([3, 5, 7]) {
+        sqlx::query(
+            "INSERT INTO link_uniques (link_id, day, clicks) VALUES ($1, '2026-01-01', $2)",
+        )
+        .bind(id)
+        .bind(clicks)
+        .execute(&pool)
+        .await
+        .unwrap();
+    }
+    // moves the row to the end of the table, so the other empty id comes
+    // first and keeps what's under the id
+    sqlx::query("UPDATE urls SET clicks = 11 WHERE url = 'https://example.com/empty-again'")
+        .execute(&pool)
+        .await
+        .unwrap();
+    let ids = || async {
+        sqlx::query_as::<_, (String, Option<String>, i64)>(
+            "SELECT url, id::TEXT, clicks FROM urls ORDER BY url",
+        )
+        .fetch_all(&pool)
+        .await
+        .unwrap()
+    };
+    let before = ids().await;
+    let (repairs,): (i64,) = sqlx::query_as("SELECT COUNT(*) FROM repairs")
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+    assert_eq!(repairs, 0, "a dry run changes nothing");
+
+    // the primary key waits for clean data
+    config.repair = None;
+    config.fix_schema = true;
+    let err = PgState::try_new(&config).await.unwrap_err().to_string();
+    assert!(
+        err.contains("3 rows without an id, 1 duplicate ids, run with --repair"),
+        "{}",
+        err
+    );
+    assert_eq!(ids().await, before);
+
+    config.repair = Some(RepairMode::Apply);
+    config.skip_schema_check = false;
+    PgState::try_new(&config).await.unwrap();
+    let after = ids().await;
+    let id_of = |url: &str| {
+        after
+            .iter()
+            .find(|(u, _, _)| u == url)
+            .and_then(|(_, id, _)| id.clone())
+            .unwrap()
+    };
+    assert_eq!(id_of("https://example.com/first"), "dup");
+    assert_eq!(id_of("https://example.com/upper"), "UPPER");
+    assert_eq!(id_of("https://example.com/fine"), "fine");
+    let fresh: Vec<String> = [
+        "https://example.com/empty",
+        "https://example.com/empty-again",
+        "https://example.com/null",
+        "https://example.com/second",
+        "https://example.com/space",
+    ]
+    .iter()
+    .map(|url| id_of(url))
+    .collect();
+    assert_eq!(fresh.iter().collect::<HashSet<_>>().len(), 5);
+    for id in &fresh {
+        assert!(!["", "dup", "a b"].contains(&id.as_str()), "{}", id);
+        let (link_id,): (String,) = sqlx::query_as("SELECT link_id FROM slugs WHERE slug = $1")
+            .bind(id)
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(&link_id, id);
+    }
+    let (_, _, clicks) = after
+        .iter()
+        .find(|(url, _, _)| url == "https://example.com/empty-again")
+        .unwrap();
+    assert_eq!(*clicks, 11, "a row keeps its counts");
+    for (id, clicks) in [(fresh[0].as_str(), 3), ("dup", 5), (fresh[4].as_str(), 7)] {
+        let (found,): (i64,) = sqlx::query_as("SELECT clicks FROM link_uniques WHERE link_id = $1")
+            .bind(id)
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(found, clicks, "{}", id);
+    }
+    let recorded: Vec<(String, Option<String>, String)> =
+        sqlx::query_as("SELECT problem, old_id, new_id FROM repairs ORDER BY id")
+            .fetch_all(&pool)
+            .await
+            .unwrap();
+    let recorded: Vec<(&str, Option<&str>, &str)> = recorded
+        .iter()
+        .map(|(p, old, new)| (p.as_str(), old.as_deref(), new.as_str()))
+        .collect();
+    assert_eq!(
+        recorded,
+        [
+            ("missing", None, fresh[2].as_str()),
+            ("missing", Some(""), fresh[0].as_str()),
+            ("missing", Some(""), fresh[1].as_str()),
+            ("unreachable", Some("a b"), fresh[4].as_str()),
+            ("duplicate", Some("dup"), fresh[3].as_str()),
+        ]
+    );
+    let (id_type,): (String,) = sqlx::query_as(
+        "SELECT data_type::TEXT FROM information_schema.columns
+         WHERE table_name = 'urls' AND column_name = 'id'",
+    )
+    .fetch_one(&pool)
+    .await
+    .unwrap();
+    assert_eq!(id_type, "text");
+
+    // nothing left to do
+    PgState::try_new(&config).await.unwrap();
+    let (repairs,): (i64,) = sqlx::query_as("SELECT COUNT(*) FROM repairs")
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+    assert_eq!(repairs, 5);
+}
+
+#[tokio::test]
+async fn skipping_schema_init_checks_the_migrations_ran() {
+    let Some((db_url, _container)) = database().await else {
+        return;
+    };
+    let pool = PgPool::connect(&db_url).await.unwrap();
+    let mut config = Config::from_env().unwrap();
+    config.db_url = db_url;
+    config.skip_schema_init = true;
+
+    let err = PgState::try_new(&config).await.unwrap_err().to_string();
+    assert!(err.contains("SKIP_SCHEMA_INIT is set"), "{}", err);
+    assert!(err.contains("missing table urls"), "{}", err);
+    assert!(!err.contains("missing column urls."), "{}", err);
+    let (tables,): (i64,) = sqlx::query_as(
+        "SELECT COUNT(*) FROM information_schema.tables WHERE table_schema = current_schema()",
+    )
+    .fetch_one(&pool)
+    .await
+    .unwrap();
+    assert_eq!(tables, 0);
+
+    // migrated, here by a regular start
+    config.skip_schema_init = false;
+    PgState::try_new(&config).await.unwrap();
+    config.skip_schema_init = true;
+    assert!(PgState::try_new(&config).await.is_ok());
+
+    sqlx::query("DROP TABLE shadow_diffs")
+        .execute(&pool)
+        .await
+        .unwrap();
+    sqlx::query("ALTER TABLE urls DROP COLUMN redirect_status")
+        .execute(&pool)
+        .await
+        .unwrap();
+    let err = PgState::try_new(&config).await.unwrap_err().to_string();
+    assert!(err.contains("missing table shadow_diffs"), "{}", err);
+    assert!(
+        err.contains("missing column urls.redirect_status"),
+        "{}",
+        err
+    );
+    assert!(!err.contains("missing table urls"), "{}", err);
+}
+
+#[tokio::test]
+async fn disabled_links_can_be_enabled_again() {
+    let Some(app) = TestApp::spawn().await else {
+        return;
+    };
+    let id = app.shorten("https://example.com/paused").await;
+    let set_enabled = |enabled: bool| {
+        app.client
+            .patch(format!("{}/{}", app.base, id))
+            .bearer_auth(ADMIN_KEY)
+            .json(&json!({ "enabled": enabled }))
+            .send()
+    };
+
+    assert_eq!(
+        set_enabled(false).await.unwrap().status(),
+        StatusCode::NO_CONTENT
+    );
+    let res = app.get(&format!("/{}", id)).await;
+    assert_eq!(res.status(), StatusCode::NOT_FOUND);
+    assert!(res.headers().get(LOCATION).is_none());
+
+    assert_eq!(
+        set_enabled(true).await.unwrap().status(),
+        StatusCode::NO_CONTENT
+    );
+    let res = app.get(&format!("/{}", id)).await;
+    assert_eq!(res.status(), StatusCode::FOUND);
+    assert_eq!(location(&res), "https://example.com/paused");
+}
+
+#[tokio::test]
+async fn refused_updates_change_nothing() {
+    let Some(app) = TestApp::spawn().await else {
+        return;
+    };
+    let id = app.shorten("https://example.com/before").await;
+    let patch = |body: Value| {
+        app.client
+            .patch(format!("{}/{}", app.base, id))
+            .bearer_auth(ADMIN_KEY)
+            .json(&body)
+            .send()
+    };
+    let stored = || async {
+        let row: (String, bool, bool, Option<i16>, Option<String>, Option<i64>) = sqlx::query_as(
+            "SELECT url, enabled, forward_query, redirect_status, notes, click_cap
+             FROM urls WHERE id = $1",
+        )
+        .bind(&id)
+        .fetch_one(&app.pool)
+        .await
+        .unwrap();
+        row
+    };
+    let before = stored().await;
+
+    // each refused for its last field, after the others would have applied
+    for bad in [
+        json!({ "notes": "x".repeat(5000) }),
+        json!({ "redirect_status": 303 }),
+        json!({ "click_cap": 0 }),
+    ] {
+        let mut body = json!({
+            "url": "https://example.com/after",
+            "enabled": false,
+            "forward_query": false,
+        });
+        body.as_object_mut()
+            .unwrap()
+            .extend(bad.as_object().unwrap().clone());
+        let res = patch(body).await.unwrap();
+        assert_eq!(res.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(stored().await, before);
+    }
+    let res = app.get(&format!("/{}", id)).await;
+    assert_eq!(location(&res), "https://example.com/before");
+    let (changes,): (i64,) = sqlx::query_as("SELECT COUNT(*) FROM url_history WHERE link_id = $1")
+        .bind(&id)
+        .fetch_one(&app.pool)
+        .await
+        .unwrap();
+    assert_eq!(changes, 0);
+
+    let res = patch(json!({
+        "url": "https://example.com/after",
+        "enabled": false,
+        "notes": "moved",
+        "click_cap": 10,
+    }))
+    .await
+    .unwrap();
+    assert_eq!(res.status(), StatusCode::NO_CONTENT);
+    let after = stored().await;
+    assert_eq!(after.0, "https://example.com/after");
+    assert!(!after.1);
+    assert_eq!(after.4.as_deref(), Some("moved"));
+    assert_eq!(after.5, Some(10));
+}
+
+#[tokio::test]
+async fn cors_headers_only_on_the_api() {
+    let Some(app) = TestApp::spawn_configured(
+        |config| config.cors_allow_origins = vec!["https://app.example".into()],
+        |_, db| db,
+    )
+    .await
+    else {
+        return;
+    };
+    let id = app.shorten("https://example.com/cors").await;
+    let origin = "https://app.example";
+    let res = app
+        .client
+        .get(format!("{}/api/links/{}/stats", app.base, id))
+        .bearer_auth(ADMIN_KEY)
+        .header("origin", origin)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(res.headers()["access-control-allow-origin"], origin);
+
+    let res = app
+        .client
+        .get(format!("{}/{}", app.base, id))
+        .header("origin", origin)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::FOUND);
+    assert!(res.headers().get("access-control-allow-origin").is_none());
+}
+
+#[tokio::test]
+async fn aliases_share_one_link() {
+    let Some(app) = TestApp::spawn().await else {
+        return;
+    };
+
+    let id = app.shorten("https://example.com/black-friday").await;
+    let aliases = format!("{}/api/links/{}/aliases", app.base, id);
+    let res = app
+        .client
+        .post(&aliases)
+        .bearer_auth(ADMIN_KEY)
+        .json(&json!({ "alias": "bf24" }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::CREATED);
+    let body: Value = res.json().await.unwrap();
+    assert_eq!(body["slugs"], json!([id, "bf24"]));
+
+    let res = app.get("/bf24").await;
+    assert_eq!(location(&res), "https://example.com/black-friday");
+
+    // taken by the link's own id
+    let res = app
+        .client
+        .post(&aliases)
+        .bearer_auth(ADMIN_KEY)
+        .json(&json!({ "alias": id }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::CONFLICT);
+
+    // re-shortening reports every slug
+    let res = app.post_url("https://example.com/black-friday").await;
+    let body: Value = res.json().await.unwrap();
+    assert_eq!(body["slugs"], json!([id, "bf24"]));
+
+    // disabling through the alias disables the shared record
+    let res = app
+        .client
+        .patch(format!("{}/bf24", app.base))
+        .bearer_auth(ADMIN_KEY)
+        .json(&json!({ "enabled": false }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::NO_CONTENT);
+    assert_eq!(
+        app.get(&format!("/{}", id)).await.status(),
+        StatusCode::NOT_FOUND
+    );
+
+    let res = app
+        .client
+        .delete(format!("{}/bf24", aliases))
+        .bearer_auth(ADMIN_KEY)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::NO_CONTENT);
+    let res = app
+        .client
+        .delete(format!("{}/{}", aliases, id))
+        .bearer_auth(ADMIN_KEY)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn click_stream() {
+    let Some(app) = TestApp::spawn().await else {
+        return;
+    };
+
+    let id = app.shorten("https://example.com/live").await;
+    let mut stream = app
+        .client
+        .get(format!("{}/api/stream/clicks", app.base))
+        .bearer_auth(ADMIN_KEY)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(stream.status(), StatusCode::OK);
+    assert_eq!(
+        app.get(&format!("/{}", id)).await.status(),
+        StatusCode::FOUND
+    );
+
+    let mut received = String::new();
+    let event = tokio::time::timeout(Duration::from_secs(5), async {
+        while let Some(chunk) = stream.chunk().await.unwrap() {
+            received.push_str(std::str::from_utf8(&chunk).unwrap());
+            if let Some(data) = received
+                .lines()
+                .find_map(|l| l.strip_prefix("data: "))
+                .map(str::to_string)
+            {
+                return data;
+            }
+        }
+        panic!("stream ended without an event");
+    })
+    .await
+    .expect("no click event within 5s");
+    let event: Value = serde_json::from_str(&event).unwrap();
+    assert_eq!(event["id"], id);
+    assert!(received.contains("event: click"));
+}
+
+#[tokio::test]
+async fn oversized_body() {
+    let Some(app) = TestApp::spawn().await else {
+        return;
+    };
+
+    let url = format!("https://example.com/{}", "a".repeat(32 * 1024));
+    let res = app.post_url(&url).await;
+    assert_eq!(res.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    let body: Value = res.json().await.unwrap();
+    assert_eq!(body["error"], "payload_too_large");
+}
+
+#[tokio::test]
+async fn build_version() {
+    let Some(app) = TestApp::spawn().await else {
+        return;
+    };
+
+    let res = app.get("/version").await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let server = res.headers()[reqwest::header::SERVER].to_str().unwrap();
+    assert_eq!(server, concat!("shortener/", env!("CARGO_PKG_VERSION")));
+    let body: Value = res.json().await.unwrap();
+    assert_eq!(body["version"], env!("CARGO_PKG_VERSION"));
+    assert!(body["git_sha"].is_string());
+    let build_time = body["build_time"].as_str().unwrap();
+    assert!(
+        chrono::DateTime::parse_from_rfc3339(build_time).is_ok(),
+        "{}",
+        build_time
+    );
+    assert!(body.get("built_at").is_none());
+
+    let Some(app) = TestApp::spawn_with(|config, db| {
+        config.version_requires_auth = true;
+        config.server_header = false;
+        db
+    })
+    .await
+    else {
+        return;
+    };
+    let res = app.get("/version").await;
+    assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+    assert!(res.headers().get(reqwest::header::SERVER).is_none());
+}
+
+#[tokio::test]
+async fn security_headers() {
+    let Some(app) =
+        TestApp::spawn_configured(|config| config.security_headers = true, |_, db| db).await
+    else {
+        return;
+    };
+    let id = app.shorten("https://example.com/hardened").await;
+    let res = app.get(&format!("/{}", id)).await;
+    assert_eq!(res.status(), StatusCode::FOUND);
+    for (name, expected) in [
+        ("strict-transport-security", "max-age=31536000"),
+        ("x-content-type-options", "nosniff"),
+        // redirects keep theirs
+        ("referrer-policy", "no-referrer"),
+        ("x-frame-options", "DENY"),
+    ] {
+        assert_eq!(res.headers()[name], expected, "{}", name);
+    }
+    let res = app.get("/healthz").await;
+    assert_eq!(
+        res.headers()["referrer-policy"],
+        "strict-origin-when-cross-origin"
+    );
+
+    let Some(app) = TestApp::spawn().await else {
+        return;
+    };
+    let id = app.shorten("https://example.com/plain").await;
+    let res = app.get(&format!("/{}", id)).await;
+    for name in [
+        "strict-transport-security",
+        "x-content-type-options",
+        "x-frame-options",
+    ] {
+        assert!(!res.headers().contains_key(name), "{}", name);
+    }
+}
+
+/// The headers [`shortener::hygiene`] sets, in order, with their values.
+fn hygiene_headers(res: &reqwest::Response) -> [Option<&str>; 4] {
+    [
+        "referrer-policy",
+        "x-content-type-options",
+        "x-frame-options",
+        "content-security-policy",
+    ]
+    .map(|name| res.headers().get(name).map(|v| v.to_str().unwrap()))
+}
+
+#[tokio::test]
+async fn redirects_and_pages_get_hygiene_headers() {
+    let Some(app) = TestApp::spawn().await else {
+        return;
+    };
+    let id = app.shorten("https://example.com/quiet").await;
+    let res = app.get(&format!("/{}", id)).await;
+    assert_eq!(res.status(), StatusCode::FOUND);
+    assert_eq!(
+        hygiene_headers(&res),
+        [Some("no-referrer"), None, None, None]
+    );
+
+    let res = app.post_url("https://example.com/quiet").await;
+    assert_eq!(
+        res.headers()[reqwest::header::CONTENT_TYPE],
+        "application/json"
+    );
+    assert_eq!(hygiene_headers(&res), [None; 4]);
+
+    let res = app.get("/").await;
+    assert!(res.headers()[reqwest::header::CONTENT_TYPE]
+        .to_str()
+        .unwrap()
+        .starts_with("text/html"));
+    assert_eq!(
+        hygiene_headers(&res),
+        [
+            Some("no-referrer"),
+            Some("nosniff"),
+            Some("DENY"),
+            Some("default-src 'none'; base-uri 'none'; form-action 'none'; frame-ancestors 'none'"),
+        ]
+    );
+
+    // the link pages style themselves inline
+    let res = app
+        .client
+        .get(format!("{}/nope42", app.base))
+        .header(reqwest::header::ACCEPT, "text/html")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::NOT_FOUND);
+    assert_eq!(
+        hygiene_headers(&res),
+        [
+            Some("no-referrer"),
+            Some("nosniff"),
+            Some("DENY"),
+            Some(
+                "default-src 'none'; style-src 'unsafe-inline'; img-src 'self' data:; \
+                 base-uri 'none'; form-action 'none'; frame-ancestors 'none'"
+            ),
+        ]
+    );
+
+    let Some(app) = TestApp::spawn_configured(
+        |config| {
+            config.headers_redirect_referrer_policy = "origin".into();
+            config.headers_content_security_policy = "default-src 'self'".into();
+            config.headers_frame_options = "SAMEORIGIN".into();
+        },
+        |_, db| db,
+    )
+    .await
+    else {
+        return;
+    };
+    let res = app.get("/").await;
+    assert_eq!(
+        hygiene_headers(&res),
+        [
+            Some("origin"),
+            Some("nosniff"),
+            Some("SAMEORIGIN"),
+            Some("default-src 'self'"),
+        ]
+    );
+}
+
+#[tokio::test]
+async fn click_counts_survive_shutdown() {
+    let Some(app) = TestApp::spawn().await else {
+        return;
+    };
+
+    let id = app.shorten("https://example.com/viral").await;
+    app.client
+        .post(format!("{}/api/links/{}/aliases", app.base, id))
+        .bearer_auth(ADMIN_KEY)
+        .json(&json!({ "alias": "viral" }))
+        .send()
+        .await
+        .unwrap();
+    let burst = (0..100).map(|i| {
+        let path = if i % 2 == 0 {
+            format!("/{}", id)
+        } else {
+            "/viral".into()
+        };
+        let app = &app;
+        async move { app.get(&path).await.status() }
+    });
+    assert!(join_all(burst)
+        .await
+        .into_iter()
+        .all(|s| s == StatusCode::FOUND));
+
+    let stats = || async {
+        let res = app
+            .client
+            .get(format!("{}/api/links/viral/stats", app.base))
+            .bearer_auth(ADMIN_KEY)
+            .send()
+            .await
+            .unwrap();
+        res.json::<Value>().await.unwrap()["clicks"].clone()
+    };
+    // nothing flushed yet, the count comes from memory
+    assert_eq!(stats().await, 100);
+
+    app.state.shutdown().await.unwrap();
+    let (stored,): (i64,) = sqlx::query_as("SELECT clicks FROM urls WHERE id = $1")
+        .bind(&id)
+        .fetch_one(&app.pool)
+        .await
+        .unwrap();
+    assert_eq!(stored, 100);
+    assert_eq!(stats().await, 100);
+}
+
+#[tokio::test]
+async fn unavailable_sets_retry_after() {
+    let Some(app) = TestApp::spawn_with(|config, db| {
+        config.retry_after_secs = 7;
+        db
+    })
+    .await
+    else {
+        return;
+    };
+
+    assert_eq!(app.get("/healthz").await.status(), StatusCode::OK);
+    app.pool.close().await;
+    let res = app.get("/healthz").await;
+    assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(res.headers()[reqwest::header::RETRY_AFTER], "7");
+
+    let Some(app) = TestApp::spawn_with(|config, db| {
+        config.request_timeout = Duration::from_millis(200);
+        config.retry_after_secs = 3;
+        db
+    })
+    .await
+    else {
+        return;
+    };
+    // a writer stuck behind this lock outlives the request timeout
+    let mut tx = app.pool.begin().await.unwrap();
+    sqlx::query("LOCK TABLE urls IN ACCESS EXCLUSIVE MODE")
+        .execute(&mut *tx)
+        .await
+        .unwrap();
+    let res = app.post_url("https://example.com/slow").await;
+    assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(res.headers()[reqwest::header::RETRY_AFTER], "3");
+    tx.rollback().await.unwrap();
+}
+
+#[tokio::test]
+async fn concurrency_limits_shed_load() {
+    let Some(app) = TestApp::spawn_configured(
+        |config| {
+            config.write_concurrency_limit = 2;
+            config.redirect_concurrency_limit = 1;
+            config.anonymous_redirect_delay = Duration::from_millis(300);
+        },
+        |_, db| db,
+    )
+    .await
+    else {
+        return;
+    };
+    let app = &app;
+    // the writers let in wait behind the lock, holding their places
+    let mut tx = app.pool.begin().await.unwrap();
+    sqlx::query("LOCK TABLE urls IN EXCLUSIVE MODE")
+        .execute(&mut *tx)
+        .await
+        .unwrap();
+    let shortens = join_all(
+        (0..6).map(|i| async move { app.post_url(&format!("https://example.com/{}", i)).await }),
+    );
+    let (responses, _) = tokio::join!(shortens, async {
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        tx.rollback().await.unwrap();
+    });
+    let count = |status: StatusCode| responses.iter().filter(|r| r.status() == status).count();
+    assert_eq!(count(StatusCode::CREATED), 2);
+    assert_eq!(count(StatusCode::SERVICE_UNAVAILABLE), 4);
+    let shed = responses
+        .iter()
+        .find(|r| r.status() == StatusCode::SERVICE_UNAVAILABLE)
+        .unwrap();
+    assert!(shed.headers().contains_key(reqwest::header::RETRY_AFTER));
+    // reads aren't writes
+    let res = app
+        .client
+        .get(format!("{}/api/count", app.base))
+        .bearer_auth(ADMIN_KEY)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+
+    let id = app.shorten("https://example.com/redirected").await;
+    let path = format!("/{}", id);
+    let responses = join_all((0..3).map(|_| app.get(&path))).await;
+    let found = responses
+        .iter()
+        .filter(|r| r.status() == StatusCode::FOUND)
+        .count();
+    assert_eq!(found, 1);
+    assert!(responses.iter().all(|r| matches!(
+        r.status(),
+        StatusCode::FOUND | StatusCode::SERVICE_UNAVAILABLE
+    )));
+}
+
+#[tokio::test]
+async fn filter_links_by_tag() {
+    let Some(app) = TestApp::spawn().await else {
+        return;
+    };
+    let tagged = |url: &str, tags: Value| {
+        app.client
+            .post(&app.base)
+            .json(&json!({ "url": url, "tags": tags }))
+            .send()
+    };
+
+    let res = tagged("https://example.com/sale", json!(["promo", "Spring"]))
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::CREATED);
+    let body: Value = res.json().await.unwrap();
+    assert_eq!(body["tags"], json!(["promo", "spring"]));
+    let sale = body["slugs"][0].as_str().unwrap().to_string();
+    let res = tagged("https://example.com/docs", json!(["docs"]))
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::CREATED);
+    // re-shortening keeps the earlier tags
+    let res = tagged("https://example.com/sale", json!(["summer"]))
+        .await
+        .unwrap();
+    let body: Value = res.json().await.unwrap();
+    assert_eq!(body["tags"], json!(["promo", "spring", "summer"]));
+
+    let res = app
+        .client
+        .get(format!("{}/api/links?tag=promo", app.base))
+        .bearer_auth(ADMIN_KEY)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    let body: Value = res.json().await.unwrap();
+    assert_eq!(body.as_array().unwrap().len(), 1);
+    assert_eq!(body[0]["id"], sale.as_str());
+    assert_eq!(body[0]["url"], "https://example.com/sale");
+    assert_eq!(body[0]["enabled"], true);
+    assert_eq!(body[0]["tags"], json!(["promo", "spring", "summer"]));
+
+    let res = tagged("https://example.com/bad", json!(["no spaces"]))
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    let many: Vec<String> = (0..11).map(|i| format!("t{}", i)).collect();
+    let res = tagged("https://example.com/many", json!(many))
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    // the cap counts the tags the link already has
+    let res = tagged("https://example.com/sale", json!(many[..8]))
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::UNPROCESSABLE_ENTITY);
+
+    let res = app
+        .client
+        .get(format!("{}/api/links?tag=promo", app.base))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn traffic_spike_alerts() {
+    let Some(app) = TestApp::spawn_with(|config, db| {
+        config.spike_window = Duration::from_millis(300);
+        config.spike_min_clicks = 5;
+        config.spike_ratio = 5.0;
+        // nothing listens here; the job just stays queued for retry
+        config.webhook_url = Some("http://127.0.0.1:9/hook".into());
+        db
+    })
+    .await
+    else {
+        return;
+    };
+    app.state.spawn_workers();
+
+    let quiet = app.shorten("https://example.com/quiet").await;
+    let viral = app.shorten("https://example.com/viral").await;
+    app.get(&format!("/{}", quiet)).await;
+    for _ in 0..30 {
+        app.get(&format!("/{}", viral)).await;
+    }
+
+    let mut hot = Value::Null;
+    for _ in 0..20 {
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let res = app
+            .client
+            .get(format!("{}/api/links/hot", app.base))
+            .bearer_auth(ADMIN_KEY)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        hot = res.json().await.unwrap();
+        if hot.as_array().is_some_and(|links| !links.is_empty()) {
+            break;
+        }
+    }
+    let hot = hot.as_array().unwrap();
+    assert_eq!(hot.len(), 1);
+    assert_eq!(hot[0]["id"], viral.as_str());
+
+    let queued: Vec<(Value,)> = sqlx::query_as(
+        "SELECT payload FROM jobs WHERE kind = 'webhook'
+         AND payload->>'event' = 'link_traffic_spike'",
+    )
+    .fetch_all(&app.pool)
+    .await
+    .unwrap();
+    assert_eq!(queued.len(), 1);
+    assert_eq!(queued[0].0["event"], "link_traffic_spike");
+    assert_eq!(queued[0].0["id"], viral.as_str());
+
+    let res = app.get("/api/links/hot").await;
+    assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn dedupe_opt_out() {
+    let Some(app) = TestApp::spawn().await else {
+        return;
+    };
+    let url = "https://example.com/campaign";
+    let shared = app.shorten(url).await;
+    assert_eq!(app.shorten(url).await, shared);
+
+    let res = app
+        .client
+        .post(&app.base)
+        .json(&json!({ "url": url, "dedupe": false }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::CREATED);
+    let body: Value = res.json().await.unwrap();
+    let own = body["slugs"][0].as_str().unwrap().to_string();
+    assert_ne!(own, shared);
+    // deduped requests keep getting the shared link
+    assert_eq!(app.shorten(url).await, shared);
+
+    app.get(&format!("/{}", own)).await;
+    let res = app
+        .client
+        .get(format!("{}/api/links?url={}", app.base, url))
+        .bearer_auth(ADMIN_KEY)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    let body: Value = res.json().await.unwrap();
+    let ids: Vec<&str> = body
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|l| l["id"].as_str().unwrap())
+        .collect();
+    // newest first
+    assert_eq!(ids, [own.as_str(), shared.as_str()]);
+
+    let res = app
+        .client
+        .get(format!("{}/api/links", app.base))
+        .bearer_auth(ADMIN_KEY)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn root_page() {
+    let Some(app) = TestApp::spawn().await else {
+        return;
+    };
+    let res = app.get("/").await;
+    assert_eq!(res.status(), StatusCode::OK);
+    assert!(res.headers()["content-type"]
+        .to_str()
+        .unwrap()
+        .starts_with("text/html"));
+    app.shorten("https://example.com/still-shortens").await;
+
+    let Some(app) = TestApp::spawn_with(|config, db| {
+        config.homepage_url = Some("https://example.com/".into());
+        db
+    })
+    .await
+    else {
+        return;
+    };
+    let res = app.get("/").await;
+    assert_eq!(res.status(), StatusCode::FOUND);
+    assert_eq!(location(&res), "https://example.com/");
+    app.shorten("https://example.com/still-shortens").await;
+}
+
+#[tokio::test]
+async fn internationalized_urls() {
+    let Some(app) = TestApp::spawn().await else {
+        return;
+    };
+    let cases = [
+        (
+            "https://пример.рф/страница",
+            "https://xn--e1afmkfd.xn--p1ai/%D1%81%D1%82%D1%80%D0%B0%D0%BD%D0%B8%D1%86%D0%B0",
+        ),
+        ("https://☕.example/menu", "https://xn--53h.example/menu"),
+        (
+            "https://example.com/café/日本?q=ünï#frag",
+            "https://example.com/caf%C3%A9/%E6%97%A5%E6%9C%AC?q=%C3%BCn%C3%AF#frag",
+        ),
+    ];
+    for (submitted, stored) in cases {
+        let id = app.shorten(submitted).await;
+        let res = app.get(&format!("/{}", id)).await;
+        assert_eq!(res.status(), StatusCode::FOUND);
+        assert_eq!(location(&res), stored);
+        // the ASCII form is the same destination, so it's the same link
+        assert_eq!(app.shorten(stored).await, id);
+
+        let res = app
+            .client
+            .get(format!("{}/api/links", app.base))
+            .query(&[("url", stored)])
+            .bearer_auth(ADMIN_KEY)
+            .send()
+            .await
+            .unwrap();
+        let body: Value = res.json().await.unwrap();
+        assert_eq!(body[0]["id"], id.as_str());
+        assert_eq!(body[0]["url"], submitted);
+    }
+
+    let res = app.post_url("not a url").await;
+    assert_eq!(res.status(), StatusCode::UNPROCESSABLE_ENTITY);
+}
+
+/// Collects log output written through it.
+#[derive(Clone, Default)]
+struct CapturedLogs(Arc<Mutex<Vec<u8>>>);
+
+impl std::io::Write for CapturedLogs {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[tokio::test]
+async fn logs_slow_queries() {
+    let logs = CapturedLogs::default();
+    let writer = logs.clone();
+    // the test runtime is single threaded, so the server's tasks log here too
+    let _guard = tracing::subscriber::set_default(
+        tracing_subscriber::fmt()
+            .json()
+            .with_writer(move || writer.clone())
+            .finish(),
+    );
+    let Some(app) =
+        TestApp::spawn_with(|_, db| db.with_slow_query(Duration::from_millis(50))).await
+    else {
+        return;
+    };
+
+    app.shorten("https://example.com/fast").await;
+    assert!(!String::from_utf8_lossy(&logs.0.lock().unwrap()).contains("Slow query"));
+
+    let mut tx = app.pool.begin().await.unwrap();
+    sqlx::query("LOCK TABLE urls IN ACCESS EXCLUSIVE MODE")
+        .execute(&mut *tx)
+        .await
+        .unwrap();
+    let release = async {
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        tx.rollback().await.unwrap();
+    };
+    let (res, ()) = tokio::join!(app.post_url("https://example.com/slow"), release);
+    assert_eq!(res.status(), StatusCode::CREATED);
+
+    let logs = String::from_utf8_lossy(&logs.0.lock().unwrap()).into_owned();
+    let slow: Vec<Value> = logs
+        .lines()
+        .filter_map(|line| serde_json::from_str::<Value>(line).ok())
+        .filter(|line| line["fields"]["message"] == "Slow query")
+        .collect();
+    assert_eq!(slow.len(), 1, "{}", logs);
+    assert_eq!(slow[0]["level"], "WARN");
+    assert_eq!(slow[0]["fields"]["query"], "shorten");
+    assert!(slow[0]["fields"]["elapsed_ms"].as_u64().unwrap() >= 50);
+}
+
+#[tokio::test]
+async fn targets_are_absolute_unless_configured() {
+    let Some(app) = TestApp::spawn().await else {
+        return;
+    };
+    let recorder = local_metrics();
+    let _recording = metrics::set_default_local_recorder(&recorder);
+    let res = app.post_url("/pricing").await;
+    assert_eq!(res.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    let body: Value = res.json().await.unwrap();
+    assert_eq!(body["error"], "invalid_url");
+    let id = app.shorten("https://example.com/pricing").await;
+    let res = app.get(&format!("/{}", id)).await;
+    assert_eq!(location(&res), "https://example.com/pricing");
+    // a relative target written around the API doesn't redirect
+    sqlx::query("UPDATE urls SET url = '/pricing' WHERE id = $1")
+        .bind(&id)
+        .execute(&app.pool)
+        .await
+        .unwrap();
+    let res = app.get(&format!("/{}", id)).await;
+    assert_eq!(res.status(), StatusCode::NOT_FOUND);
+    assert!(res.headers().get("location").is_none());
+    let counted = recorder.handle().render();
+    for series in [
+        r#"redirect_total{outcome="found"} 1"#,
+        r#"redirect_total{outcome="not_found"} 1"#,
+    ] {
+        assert!(counted.contains(series), "{} in {}", series, counted);
+    }
+    // nor does rolling back to it bring it back
+    let res = app
+        .client
+        .patch(format!("{}/{}", app.base, id))
+        .bearer_auth(ADMIN_KEY)
+        .json(&json!({ "url": "https://example.com/plans" }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::NO_CONTENT);
+    let res = app
+        .client
+        .post(format!("{}/api/links/{}/rollback", app.base, id))
+        .bearer_auth(ADMIN_KEY)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    let body: Value = res.json().await.unwrap();
+    assert_eq!(body["error"], "invalid_url");
+    let res = app.get(&format!("/{}", id)).await;
+    assert_eq!(location(&res), "https://example.com/plans");
+
+    let Some(app) =
+        TestApp::spawn_configured(|config| config.require_absolute_targets = false, |_, db| db)
+            .await
+    else {
+        return;
+    };
+    let res = app.post_url("/pricing?plan=pro").await;
+    assert_eq!(res.status(), StatusCode::CREATED);
+    let body: Value = res.json().await.unwrap();
+    let id = body["url"].as_str().unwrap().rsplit('/').next().unwrap();
+    let res = app.get(&format!("/{}?ref=ad", id)).await;
+    assert_eq!(res.status(), StatusCode::FOUND);
+    assert_eq!(location(&res), "/pricing?plan=pro&ref=ad");
+    for url in ["//example.com/", "pricing"] {
+        let res = app.post_url(url).await;
+        assert_eq!(res.status(), StatusCode::UNPROCESSABLE_ENTITY, "{}", url);
+    }
+}
+
+#[tokio::test]
+async fn reports_whether_link_was_created() {
+    let Some(app) = TestApp::spawn().await else {
+        return;
+    };
+    let res = app.post_url("https://example.com/once").await;
+    assert_eq!(res.status(), StatusCode::CREATED);
+    let first: Value = res.json().await.unwrap();
+    assert_eq!(first["created"], true);
+    assert!(first["created_at"].is_string());
+
+    let res = app.post_url("https://example.com/once").await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let again: Value = res.json().await.unwrap();
+    assert_eq!(again["created"], false);
+    assert_eq!(again["url"], first["url"]);
+    assert_eq!(again["created_at"], first["created_at"]);
+
+    let res = app
+        .client
+        .post(&app.base)
+        .json(&json!({ "url": "https://example.com/once", "dedupe": false }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::CREATED);
+    let own: Value = res.json().await.unwrap();
+    assert_eq!(own["created"], true);
+}
+
+#[tokio::test]
+async fn shortening_includes_related_urls() {
+    let Some(app) = TestApp::spawn().await else {
+        return;
+    };
+    let shorten = |query: &'static str| {
+        app.client
+            .post(format!("{}/{}", app.base, query))
+            .json(&json!({ "url": "https://example.com/related" }))
+            .send()
+    };
+    let body: Value = shorten("").await.unwrap().json().await.unwrap();
+    assert!(body.get("stats_url").is_none());
+    let id = body["url"].as_str().unwrap().rsplit('/').next().unwrap();
+
+    let res = shorten("?include=stats").await.unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    let body: Value = res.json().await.unwrap();
+    let stats_url = format!("{}/api/links/{}/stats", app.base, id);
+    assert_eq!(body["stats_url"], stats_url.as_str());
+    let res = app
+        .client
+        .get(&stats_url)
+        .bearer_auth(ADMIN_KEY)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+
+    let res = shorten("?include=stats,qr").await.unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    let body: Value = res.json().await.unwrap();
+    assert_eq!(body["stats_url"], stats_url.as_str());
+    let qr_url = format!("{}/api/links/{}/qr", app.base, id);
+    assert_eq!(body["qr_url"], qr_url.as_str());
+    let res = app.client.get(&qr_url).send().await.unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(res.headers()[reqwest::header::CONTENT_TYPE], "image/png");
+    let png = res.bytes().await.unwrap();
+    assert!(png.starts_with(b"\x89PNG\r\n\x1a\n"));
+    let side = u32::from_be_bytes(png[16..20].try_into().unwrap());
+    assert_eq!(side % qr::DEFAULT_SCALE, 0);
+
+    let res = app
+        .client
+        .get(format!("{}/api/links/nope404/qr", app.base))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+    let res = shorten("?include=stats,map").await.unwrap();
+    assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn link_kits_bundle_assets() {
+    let Some(app) = TestApp::spawn().await else {
+        return;
+    };
+    let kit = |body: Value| {
+        app.client
+            .post(format!("{}/api/link-kits", app.base))
+            .json(&body)
+            .send()
+    };
+    let res = kit(json!({
+        "link": { "url": "https://example.com/kit" },
+        "include": ["qr"],
+        "qr_scale": 1000,
+    }))
+    .await
+    .unwrap();
+    assert_eq!(res.status(), StatusCode::CREATED);
+    let body: Value = res.json().await.unwrap();
+    let id = body["id"].as_str().unwrap().to_string();
+    assert_eq!(body["url"], format!("{}/{}", app.base, id).as_str());
+    assert_eq!(body["slugs"], json!([id]));
+    assert_eq!(body["qr"]["scale"], qr::MAX_SCALE);
+    let png = STANDARD
+        .decode(body["qr"]["png_base64"].as_str().unwrap())
+        .unwrap();
+    assert!(png.starts_with(b"\x89PNG\r\n\x1a\n"));
+    let side = u32::from_be_bytes(png[16..20].try_into().unwrap());
+    assert_eq!(side % qr::MAX_SCALE, 0);
+    let res = app.get(&format!("/{}", id)).await;
+    assert_eq!(location(&res), "https://example.com/kit");
+
+    // the same shorten as ever, so the link is reused
+    let res = kit(json!({ "link": { "url": "https://example.com/kit" } }))
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    let body: Value = res.json().await.unwrap();
+    assert_eq!(body["id"], id.as_str());
+    assert!(body.get("qr").is_none());
+
+    let res = kit(json!({ "link": { "url": "nope" }, "include": ["qr"] }))
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    let body: Value = res.json().await.unwrap();
+    assert_eq!(body["error"], "invalid_url");
+    // destination metadata isn't fetched, so can't be asked for
+    for part in ["pdf", "metadata"] {
+        let res = kit(json!({ "link": { "url": "https://example.com/kit" }, "include": [part] }))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        let body: Value = res.json().await.unwrap();
+        assert_eq!(body["error"], "invalid_body");
+        assert_eq!(body["path"], "include[0]");
+    }
+}
+
+#[tokio::test]
+async fn notes_stay_private() {
+    let Some(app) = TestApp::spawn().await else {
+        return;
+    };
+    let notes = "created for the March press release, owner: dana";
+    let res = app
+        .client
+        .post(&app.base)
+        .json(&json!({ "url": "https://example.com/press", "tags": ["press"], "notes": notes }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::CREATED);
+    let text = res.text().await.unwrap();
+    assert!(!text.contains("March"), "{}", text);
+    let body: Value = serde_json::from_str(&text).unwrap();
+    let id = body["slugs"][0].as_str().unwrap().to_string();
+
+    let stats = || async {
+        let res = app
+            .client
+            .get(format!("{}/api/links/{}/stats", app.base, id))
+            .bearer_auth(ADMIN_KEY)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        res.json::<Value>().await.unwrap()
+    };
+    assert_eq!(stats().await["notes"], notes);
+    let res = app
+        .client
+        .get(format!("{}/api/links?tag=press", app.base))
+        .bearer_auth(ADMIN_KEY)
+        .send()
+        .await
+        .unwrap();
+    let body: Value = res.json().await.unwrap();
+    assert_eq!(body[0]["notes"], notes);
+
+    // public responses never carry them
+    let res = app.post_url("https://example.com/press").await;
+    assert!(!res.text().await.unwrap().contains("March"));
+    let res = app.get(&format!("/{}", id)).await;
+    assert!(!res.text().await.unwrap().contains("March"));
+
+    let patch = |body: Value| {
+        app.client
+            .patch(format!("{}/{}", app.base, id))
+            .bearer_auth(ADMIN_KEY)
+            .json(&body)
+            .send()
+    };
+    let res = patch(json!({ "notes": "x".repeat(4097) })).await.unwrap();
+    assert_eq!(res.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    let res = patch(json!({ "enabled": true })).await.unwrap();
+    assert_eq!(res.status(), StatusCode::NO_CONTENT);
+    assert_eq!(stats().await["notes"], notes);
+    let res = patch(json!({ "notes": null })).await.unwrap();
+    assert_eq!(res.status(), StatusCode::NO_CONTENT);
+    assert_eq!(stats().await["notes"], Value::Null);
+}
+
+#[tokio::test]
+async fn crossing_the_click_cap_alerts_once() {
+    let Some(app) = TestApp::spawn_configured(
+        // never delivered: the workers aren't running
+        |config| config.webhook_url = Some("http://127.0.0.1:9/hook".into()),
+        |_, db| db,
+    )
+    .await
+    else {
+        return;
+    };
+    let id = app.shorten("https://example.com/capped").await;
+    let patch = |body: Value| {
+        app.client
+            .patch(format!("{}/{}", app.base, id))
+            .bearer_auth(ADMIN_KEY)
+            .json(&body)
+            .send()
+    };
+    let res = patch(json!({ "click_cap": 0 })).await.unwrap();
+    assert_eq!(res.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    let res = patch(json!({ "click_cap": 3 })).await.unwrap();
+    assert_eq!(res.status(), StatusCode::NO_CONTENT);
+
+    // set on creation too, checked the same way
+    let create = |body: Value| app.client.post(&app.base).json(&body).send();
+    for cap in [json!(0), json!(u64::MAX)] {
+        let res = create(json!({ "url": "https://example.com/born-capped", "click_cap": cap }))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::UNPROCESSABLE_ENTITY, "{}", cap);
+    }
+    let res = create(json!({ "url": "https://example.com/born-capped", "click_cap": 2 }))
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::CREATED);
+    let body: Value = res.json().await.unwrap();
+    let born = body["url"].as_str().unwrap().rsplit('/').next().unwrap();
+    let cap: Option<i64> = sqlx::query_scalar("SELECT click_cap FROM urls WHERE id = $1")
+        .bind(born)
+        .fetch_one(&app.pool)
+        .await
+        .unwrap();
+    assert_eq!(cap, Some(2));
+    // the url's link already has a cap, which stays
+    let res = create(json!({ "url": "https://example.com/capped", "click_cap": 9 }))
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+
+    let alerts = || async {
+        let alerts: Vec<Value> = sqlx::query_scalar(
+            "SELECT payload FROM jobs
+             WHERE kind = 'webhook' AND payload->>'event' = 'link_click_cap_reached'",
+        )
+        .fetch_all(&app.pool)
+        .await
+        .unwrap();
+        alerts
+    };
+    // flushed in batches, the cap crossed inside the second
+    for _ in 0..3 {
+        for _ in 0..2 {
+            let res = app.get(&format!("/{}", id)).await;
+            assert_eq!(res.status(), StatusCode::FOUND);
+        }
+        app.state.shutdown().await.unwrap();
+    }
+    let sent = alerts().await;
+    assert_eq!(sent.len(), 1);
+    assert_eq!(sent[0]["id"], id.as_str());
+    assert_eq!(sent[0]["cap"], 3);
+    assert_eq!(sent[0]["clicks"], 4);
+
+    // cleared, and set again below the clicks it has: nothing left to cross
+    let res = patch(json!({ "click_cap": null })).await.unwrap();
+    assert_eq!(res.status(), StatusCode::NO_CONTENT);
+    let res = patch(json!({ "click_cap": 5 })).await.unwrap();
+    assert_eq!(res.status(), StatusCode::NO_CONTENT);
+    app.get(&format!("/{}", id)).await;
+    app.state.shutdown().await.unwrap();
+    assert_eq!(alerts().await.len(), 1);
+}
+
+#[tokio::test]
+async fn merging_another_instance_renames_what_collides() {
+    let (Some(source), Some(target)) = (TestApp::spawn().await, TestApp::spawn().await) else {
+        return;
+    };
+    let res = source
+        .client
+        .post(&source.base)
+        .json(&json!({ "url": "https://example.com/kept", "tags": ["launch"] }))
+        .send()
+        .await
+        .unwrap();
+    let body: Value = res.json().await.unwrap();
+    let kept = body["url"].as_str().unwrap().rsplit('/').next().unwrap();
+    let res = source
+        .client
+        .post(format!("{}/api/links/{}/aliases", source.base, kept))
+        .bearer_auth(ADMIN_KEY)
+        .json(&json!({ "alias": "promo" }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::CREATED);
+    sqlx::query("UPDATE urls SET clicks = 7 WHERE id = $1")
+        .bind(kept)
+        .execute(&source.pool)
+        .await
+        .unwrap();
+    let clash = source.shorten("https://example.com/clash").await;
+
+    // the target has the second id and the alias already
+    for insert in [
+        "INSERT INTO urls (id, url) VALUES ($1, 'https://example.com/theirs')",
+        "INSERT INTO slugs (slug, link_id) VALUES ($1, $1)",
+    ] {
+        sqlx::query(insert)
+            .bind(&clash)
+            .execute(&target.pool)
+            .await
+            .unwrap();
+    }
+    let theirs = target.shorten("https://example.com/promo").await;
+    let res = target
+        .client
+        .post(format!("{}/api/links/{}/aliases", target.base, theirs))
+        .bearer_auth(ADMIN_KEY)
+        .json(&json!({ "alias": "promo" }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::CREATED);
+
+    let mut config = Config::from_env().unwrap();
+    config.db_url = target.db_url.clone();
+    let store = PgState::try_new(&config).await.unwrap();
+    let pool = merge::connect_source(&source.db_url).await.unwrap();
+    // a link per batch
+    let summary = merge::run(&store, &pool, &source.db_url, 1).await.unwrap();
+    assert_eq!(
+        (summary.copied, summary.renamed, summary.skipped),
+        (2, 2, 0)
+    );
+
+    let res = target.get(&format!("/{}", kept)).await;
+    assert_eq!(location(&res), "https://example.com/kept");
+    let (clicks, deduped, tags): (i64, bool, Vec<String>) = sqlx::query_as(
+        "SELECT clicks, deduped, ARRAY(SELECT tag FROM link_tags WHERE link_id = id)
+         FROM urls WHERE id = $1",
+    )
+    .bind(kept)
+    .fetch_one(&target.pool)
+    .await
+    .unwrap();
+    assert_eq!(
+        (clicks, deduped, tags),
+        (7, false, vec!["launch".to_string()])
+    );
+    // the alias stays with the target's link, the id keeps its own
+    let res = target.get("/promo").await;
+    assert_eq!(location(&res), "https://example.com/promo");
+    let res = target.get(&format!("/{}", clash)).await;
+    assert_eq!(location(&res), "https://example.com/theirs");
+
+    let res = target
+        .client
+        .get(format!("{}/api/admin/merged-ids", target.base))
+        .bearer_auth(ADMIN_KEY)
+        .send()
+        .await
+        .unwrap();
+    let renamed: Value = res.json().await.unwrap();
+    let renamed: Vec<(&str, &str)> = renamed
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|r| (r["old_id"].as_str().unwrap(), r["new_id"].as_str().unwrap()))
+        .collect();
+    assert_eq!(renamed.len(), 2);
+    let fresh = renamed.iter().find(|(old, _)| *old == clash).unwrap().1;
+    assert_ne!(fresh, clash);
+    assert!(renamed.contains(&("promo", kept)));
+    let res = target.get(&format!("/{}", fresh)).await;
+    assert_eq!(location(&res), "https://example.com/clash");
+    let res = target
+        .client
+        .get(format!("{}/api/admin/merged-ids?format=csv", target.base))
+        .bearer_auth(ADMIN_KEY)
+        .send()
+        .await
+        .unwrap();
+    let csv = res.text().await.unwrap();
+    assert!(
+        csv.starts_with("source,old_id,new_id,merged_at\r\n"),
+        "{}",
+        csv
+    );
+    assert!(csv.contains(&format!(",{},{},", clash, fresh)), "{}", csv);
+
+    // running it again copies only what's new
+    let summary = merge::run(&store, &pool, &source.db_url, 1).await.unwrap();
+    assert_eq!((summary.copied, summary.skipped), (0, 2));
+    let later = source.shorten("https://example.com/later").await;
+    let summary = merge::run(&store, &pool, &source.db_url, 10).await.unwrap();
+    assert_eq!((summary.copied, summary.skipped), (1, 2));
+    let res = target.get(&format!("/{}", later)).await;
+    assert_eq!(location(&res), "https://example.com/later");
+
+    // the source is left as it was, and couldn't be written to anyway
+    let count: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM urls")
+        .fetch_one(&source.pool)
+        .await
+        .unwrap();
+    assert_eq!(count, 3);
+    assert!(sqlx::query("DELETE FROM urls")
+        .execute(&pool)
+        .await
+        .is_err());
+}
+
+#[tokio::test]
+async fn interrupted_merges_resume_from_their_last_batch() {
+    let (Some(source), Some(target)) = (TestApp::spawn().await, TestApp::spawn().await) else {
+        return;
+    };
+    let mut ids = Vec::new();
+    for i in 0..3 {
+        ids.push(source.shorten(&format!("https://example.com/{}", i)).await);
+    }
+    ids.sort();
+    let mut config = Config::from_env().unwrap();
+    config.db_url = target.db_url.clone();
+    let store = PgState::try_new(&config).await.unwrap();
+    let pool = merge::connect_source(&source.db_url).await.unwrap();
+    let name = shortener::config::redact_url(&source.db_url);
+    // as a run stopped after the first batch leaves it
+    sqlx::query("INSERT INTO merges (source, last_id) VALUES ($1, $2)")
+        .bind(&name)
+        .bind(&ids[0])
+        .execute(&target.pool)
+        .await
+        .unwrap();
+    let summary = merge::run(&store, &pool, &source.db_url, 1).await.unwrap();
+    assert_eq!(summary.copied, 2);
+    assert_eq!(
+        target.get(&format!("/{}", ids[0])).await.status(),
+        StatusCode::NOT_FOUND
+    );
+    let (state, last_id, copied): (String, String, i64) =
+        sqlx::query_as("SELECT state, last_id, copied FROM merges WHERE source = $1")
+            .bind(&name)
+            .fetch_one(&target.pool)
+            .await
+            .unwrap();
+    assert_eq!(
+        (state.as_str(), last_id.as_str(), copied),
+        ("completed", ids[2].as_str(), 2)
+    );
+}
+
+#[tokio::test]
+async fn lowercase_ids_resolve_however_they_are_typed() {
+    let Some(app) = TestApp::spawn_configured(
+        |config| config.id_case = shortener::slug::IdCase::Lower,
+        |_, db| db,
+    )
+    .await
+    else {
+        return;
+    };
+    for i in 0..20 {
+        let id = app.shorten(&format!("https://example.com/{}", i)).await;
+        assert_eq!(id, id.to_ascii_lowercase());
+        let res = app.get(&format!("/{}", id.to_ascii_uppercase())).await;
+        assert_eq!(res.status(), StatusCode::FOUND, "{}", id);
+        assert_eq!(location(&res), format!("https://example.com/{}", i));
+    }
+
+    // aliases are folded on the way in too
+    let body: Value = app
+        .client
+        .post(&app.base)
+        .bearer_auth(ADMIN_KEY)
+        .json(&json!({ "url": "https://example.com/summer", "alias": "Summer-Sale" }))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert!(body["url"].as_str().unwrap().ends_with("/summer-sale"));
+    for typed in ["/summer-sale", "/SUMMER-SALE"] {
+        let res = app.get(typed).await;
+        assert_eq!(location(&res), "https://example.com/summer");
+    }
+    let res = app.get("/Summer-Sale.json").await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let body: Value = app
+        .get("/api/alias-available?alias=SUMMER-sale")
+        .await
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(body["available"], false);
+}
+
+#[tokio::test]
+async fn links_per_owner() {
+    let Some(app) = TestApp::spawn().await else {
+        return;
+    };
+    let alice = app.create_key("alice", &["write"]).await;
+    let bob = app.create_key("bob", &["write"]).await;
+    let shorten_as = |key: &str, url: &str| {
+        app.client
+            .post(&app.base)
+            .bearer_auth(key)
+            .json(&json!({ "url": url }))
+            .send()
+    };
+    let mine = |key: &str| {
+        app.client
+            .get(format!("{}/api/my/links", app.base))
+            .bearer_auth(key)
+            .send()
+    };
+    let urls = |body: Value| -> Vec<String> {
+        body.as_array()
+            .unwrap()
+            .iter()
+            .map(|l| l["url"].as_str().unwrap().to_string())
+            .collect()
+    };
+
+    let short = |res: reqwest::Response| async move {
+        let body: Value = res.json().await.unwrap();
+        body["url"].as_str().unwrap().to_string()
+    };
+
+    let mut alice_a1 = String::new();
+    for url in ["https://example.com/a1", "https://example.com/a2"] {
+        let res = shorten_as(&alice, url).await.unwrap();
+        assert_eq!(res.status(), StatusCode::CREATED);
+        if alice_a1.is_empty() {
+            alice_a1 = short(res).await;
+        }
+    }
+    let res = shorten_as(&bob, "https://example.com/b1").await.unwrap();
+    assert_eq!(res.status(), StatusCode::CREATED);
+    // each owner gets a link of their own to the same url, and their own
+    // back when shortening it again
+    let res = shorten_as(&bob, "https://example.com/a1").await.unwrap();
+    assert_eq!(res.status(), StatusCode::CREATED);
+    assert_ne!(short(res).await, alice_a1);
+    let res = shorten_as(&alice, "https://example.com/a1").await.unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(short(res).await, alice_a1);
+    app.shorten("https://example.com/anonymous").await;
+    // anonymous links are still shared
+    let res = app.post_url("https://example.com/anonymous").await;
+    assert_eq!(res.status(), StatusCode::OK);
+
+    let res = mine(&alice).await.unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    let mut alice_urls = urls(res.json().await.unwrap());
+    alice_urls.sort();
+    assert_eq!(
+        alice_urls,
+        ["https://example.com/a1", "https://example.com/a2"]
+    );
+    let res = mine(&bob).await.unwrap();
+    let mut bob_urls = urls(res.json().await.unwrap());
+    bob_urls.sort();
+    assert_eq!(
+        bob_urls,
+        ["https://example.com/a1", "https://example.com/b1"]
+    );
+
+    let anonymous: (Option<String>,) =
+        sqlx::query_as("SELECT owner FROM urls WHERE url = 'https://example.com/anonymous'")
+            .fetch_one(&app.pool)
+            .await
+            .unwrap();
+    assert_eq!(anonymous.0, None);
+
+    let res = app
+        .client
+        .get(format!("{}/api/my/links", app.base))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn stable_listing_order() {
+    let Some(app) = TestApp::spawn().await else {
+        return;
+    };
+    // one statement, so every row gets the same created_at
+    sqlx::query(
+        "WITH new AS (
+            INSERT INTO urls (id, url, clicks)
+            SELECT 'same' || n, 'https://example.com/same/' || n, n % 3 FROM generate_series(1, 6) n
+            RETURNING id
+        )
+        INSERT INTO link_tags (link_id, tag) SELECT id, 'batch' FROM new",
+    )
+    .execute(&app.pool)
+    .await
+    .unwrap();
+    let list = |params: &'static str| {
+        let (client, url) = (
+            &app.client,
+            format!("{}/api/links?tag=batch{}", app.base, params),
+        );
+        async move {
+            let res = client.get(url).bearer_auth(ADMIN_KEY).send().await.unwrap();
+            assert_eq!(res.status(), StatusCode::OK);
+            let body: Value = res.json().await.unwrap();
+            body.as_array()
+                .unwrap()
+                .iter()
+                .map(|l| l["id"].as_str().unwrap().to_string())
+                .collect::<Vec<_>>()
+        }
+    };
+
+    let first = list("").await;
+    assert_eq!(
+        first,
+        ["same6", "same5", "same4", "same3", "same2", "same1"]
+    );
+    assert_eq!(list("").await, first);
+    assert_eq!(
+        list("&sort=clicks&order=asc").await,
+        ["same3", "same6", "same1", "same4", "same2", "same5"]
+    );
+    assert_eq!(
+        list("&sort=created_at&order=asc").await,
+        ["same1", "same2", "same3", "same4", "same5", "same6"]
+    );
+
+    let res = app
+        .client
+        .get(format!("{}/api/links?tag=batch&sort=url", app.base))
+        .bearer_auth(ADMIN_KEY)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn rejects_implausible_ids() {
+    let Some(app) = TestApp::spawn().await else {
+        return;
+    };
+    let id = app.shorten("https://example.com/plausible").await;
+    let garbage = [
+        "a%00b".to_string(),
+        "a".repeat(10_000),
+        "%D0%BF%D1%80%D0%B8".to_string(),
+        "%F0%9F%92%A9".to_string(),
+        "..%2F..%2Fetc%2Fpasswd".to_string(),
+        "%FF".to_string(),
+    ];
+
+    // with the pool gone, only answers that never reach it can still be 404s
+    app.pool.close().await;
+    for slug in &garbage {
+        let res = app.get(&format!("/{}", slug)).await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND, "{}", slug);
+        let res = app
+            .client
+            .get(format!("{}/api/links/{}/stats", app.base, slug))
+            .bearer_auth(ADMIN_KEY)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::NOT_FOUND, "{}", slug);
+        let res = app
+            .client
+            .post(format!("{}/{}/report", app.base, slug))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::NOT_FOUND, "{}", slug);
+    }
+    let res = app.get(&format!("/{}", id)).await;
+    assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+}
+
+#[tokio::test]
+async fn selftest_round_trip() {
+    let Some((db_url, _container)) = database().await else {
+        return;
+    };
+    let mut config = Config::from_env().unwrap();
+    config.db_url = db_url;
+    let db = PgState::try_new(&config).await.unwrap();
+
+    shortener::selftest::run(&db).await.unwrap();
+    let (links, slugs): (i64, i64) =
+        sqlx::query_as("SELECT (SELECT COUNT(*) FROM urls), (SELECT COUNT(*) FROM slugs)")
+            .fetch_one(db.pool())
+            .await
+            .unwrap();
+    assert_eq!((links, slugs), (0, 0), "selftest left its link behind");
+
+    db.pool().close().await;
+    let err = shortener::selftest::run(&db).await.unwrap_err();
+    assert!(err.to_string().contains("closed"), "{}", err);
+}
+
+#[tokio::test]
+async fn estimates_unique_visitors() {
+    let Some(app) = TestApp::spawn_with(|config, db| {
+        config.uniques_salt = Some("e2e".into());
+        db
+    })
+    .await
+    else {
+        return;
+    };
+    let id = app.shorten("https://example.com/uniques").await;
+    let visit = |user_agent: &'static str| {
+        let (app, id) = (&app, &id);
+        async move {
+            let res = app
+                .client
+                .get(format!("{}/{}", app.base, id))
+                .header("user-agent", user_agent)
+                .send()
+                .await
+                .unwrap();
+            assert_eq!(res.status(), StatusCode::FOUND);
+        }
+    };
+    let stats = |path: &'static str| {
+        let (app, id) = (&app, &id);
+        async move {
+            app.client
+                .get(format!("{}/api/links/{}/stats{}", app.base, id, path))
+                .bearer_auth(ADMIN_KEY)
+                .send()
+                .await
+                .unwrap()
+                .json::<Value>()
+                .await
+                .unwrap()
+        }
+    };
+    for user_agent in ["phone", "phone", "phone", "laptop"] {
+        visit(user_agent).await;
+    }
+    let today = chrono::Utc::now().date_naive().to_string();
+    // nothing flushed yet
+    assert_eq!(stats("").await["uniques"], 2);
+    let daily = json!([{ "day": today, "clicks": 4, "uniques": 2 }]);
+    assert_eq!(stats("/daily").await, daily);
+
+    app.state.shutdown().await.unwrap();
+    let (rows,): (i64,) =
+        sqlx::query_as("SELECT COUNT(*) FROM link_uniques WHERE registers IS NOT NULL")
+            .fetch_one(&app.pool)
+            .await
+            .unwrap();
+    assert_eq!(rows, 1);
+    // a returning visitor merges into the stored sketch without counting twice
+    visit("phone").await;
+    visit("tablet").await;
+    app.state.shutdown().await.unwrap();
+    assert_eq!(stats("").await["uniques"], 3);
+    let daily = json!([{ "day": today, "clicks": 6, "uniques": 3 }]);
+    assert_eq!(stats("/daily").await, daily);
+
+    let res = app
+        .client
+        .get(format!("{}/api/links/missing/stats/daily", app.base))
+        .bearer_auth(ADMIN_KEY)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn uniques_off_without_salt() {
+    let Some(app) = TestApp::spawn().await else {
+        return;
+    };
+    let id = app.shorten("https://example.com/no-uniques").await;
+    app.get(&format!("/{}", id)).await;
+    app.state.shutdown().await.unwrap();
+    let res = app
+        .client
+        .get(format!("{}/api/links/{}/stats/daily", app.base, id))
+        .bearer_auth(ADMIN_KEY)
+        .send()
+        .await
+        .unwrap();
+    let daily: Value = res.json().await.unwrap();
+    assert_eq!(daily[0]["clicks"], 1);
+    assert_eq!(daily[0]["uniques"], Value::Null);
+    let (stored,): (i64,) =
+        sqlx::query_as("SELECT COUNT(*) FROM link_uniques WHERE registers IS NOT NULL")
+            .fetch_one(&app.pool)
+            .await
+            .unwrap();
+    assert_eq!(stored, 0);
+}
+
+#[tokio::test]
+async fn canonical_host_redirect() {
+    let Some(app) = TestApp::spawn_with(|config, db| {
+        config.canonical_host = Some("sho.rt".into());
+        db
+    })
+    .await
+    else {
+        return;
+    };
+    let get = |path: &'static str, host: &'static str| {
+        let app = &app;
+        async move {
+            app.client
+                .get(format!("{}{}", app.base, path))
+                .header("host", host)
+                .header("x-forwarded-proto", "https")
+                .send()
+                .await
+                .unwrap()
+        }
+    };
+
+    let res = get("/abc?utm=1", "short.example.com").await;
+    assert_eq!(res.status(), StatusCode::MOVED_PERMANENTLY);
+    assert_eq!(location(&res), "https://sho.rt/abc?utm=1");
+    // the api is redirected too
+    let res = get("/api/count", "short.example.com").await;
+    assert_eq!(res.status(), StatusCode::MOVED_PERMANENTLY);
+    let res = get("/missing", "SHO.RT").await;
+    assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+    for path in ["/healthz", "/metrics"] {
+        let res = get(path, "10.0.0.7:8080").await;
+        assert_eq!(res.status(), StatusCode::OK, "{}", path);
+    }
+}
+
+#[tokio::test]
+async fn rotate_link_id() {
+    let Some(app) = TestApp::spawn().await else {
+        return;
+    };
+    let res = app
+        .client
+        .post(&app.base)
+        .json(&json!({ "url": "https://example.com/leaked", "tags": ["launch"] }))
+        .send()
+        .await
+        .unwrap();
+    let body: Value = res.json().await.unwrap();
+    let old = body["url"].as_str().unwrap().rsplit('/').next().unwrap();
+    let res = app
+        .client
+        .post(format!("{}/api/links/{}/aliases", app.base, old))
+        .bearer_auth(ADMIN_KEY)
+        .json(&json!({ "alias": "spring" }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::CREATED);
+    app.get(&format!("/{}", old)).await;
+
+    let rotate = |id: String| {
+        let app = &app;
+        async move {
+            app.client
+                .post(format!("{}/{}/rotate", app.base, id))
+                .bearer_auth(ADMIN_KEY)
+                .send()
+                .await
+                .unwrap()
+        }
+    };
+    let res = rotate(old.to_string()).await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let rotated: Value = res.json().await.unwrap();
+    let new = rotated["url"].as_str().unwrap().rsplit('/').next().unwrap();
+    assert_ne!(new, old);
+    assert_eq!(rotated["slugs"], json!([new, "spring"]));
+    assert_eq!(rotated["tags"], json!(["launch"]));
+    assert_eq!(rotated["created_at"], body["created_at"]);
+
+    assert_eq!(
+        app.get(&format!("/{}", old)).await.status(),
+        StatusCode::NOT_FOUND
+    );
+    let res = app.get(&format!("/{}", new)).await;
+    assert_eq!(location(&res), "https://example.com/leaked");
+    let res = app.get("/spring").await;
+    assert_eq!(location(&res), "https://example.com/leaked");
+    let stats: Value = app
+        .client
+        .get(format!("{}/api/links/{}/stats", app.base, new))
+        .bearer_auth(ADMIN_KEY)
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(stats["clicks"], 3);
+
+    assert_eq!(
+        rotate(old.to_string()).await.status(),
+        StatusCode::NOT_FOUND
+    );
+    let res = app
+        .client
+        .post(format!("{}/{}/rotate", app.base, new))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn unreachable_replicas_sit_out() {
+    let Some(app) = TestApp::spawn_configured(
+        |config| {
+            // nothing listens there, so connecting fails until it times out
+            config.replica_urls = vec![
+                "postgres://postgres@127.0.0.1:1/postgres".into(),
+                config.db_url.clone(),
+            ]
+        },
+        |_, db| db,
+    )
+    .await
+    else {
+        return;
+    };
+    let id = app.shorten("https://example.com/around").await;
+    let first = Instant::now();
+    for _ in 0..2 {
+        let res = app.get(&format!("/{}", id)).await;
+        assert_eq!(location(&res), "https://example.com/around");
+    }
+    assert!(first.elapsed() < Duration::from_secs(3));
+    // the failed replica isn't waited on again
+    let after = Instant::now();
+    for _ in 0..10 {
+        let res = app.get(&format!("/{}", id)).await;
+        assert_eq!(location(&res), "https://example.com/around");
+        let res = app
+            .client
+            .get(format!("{}/api/links/{}/stats", app.base, id))
+            .bearer_auth(ADMIN_KEY)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+    }
+    assert!(
+        after.elapsed() < Duration::from_millis(900),
+        "{:?}",
+        after.elapsed()
+    );
+}
+
+#[tokio::test]
+async fn reads_from_replica() {
+    let Some((replica_url, _replica_container)) = database().await else {
+        return;
+    };
+    let mut config = Config::from_env().unwrap();
+    config.db_url = replica_url.clone();
+    let replica = PgState::try_new(&config).await.unwrap();
+    // stands in for a link the primary lost, so reads show where they went
+    for query in [
+        "INSERT INTO urls (id, url) VALUES ('replicated', 'https://example.com/replica')",
+        "INSERT INTO slugs (slug, link_id) VALUES ('replicated', 'replicated')",
+        "INSERT INTO link_tags (link_id, tag) VALUES ('replicated', 'routing')",
+    ] {
+        sqlx::query(query).execute(replica.pool()).await.unwrap();
+    }
+    replica.pool().close().await;
+    let Some(app) = TestApp::spawn_configured(
+        |config| config.replica_urls = vec![replica_url.clone()],
+        |_, db| db,
+    )
+    .await
+    else {
+        return;
+    };
+    let listed = |app: &TestApp| {
+        let req = app
+            .client
+            .get(format!("{}/api/links?tag=routing", app.base))
+            .bearer_auth(ADMIN_KEY);
+        async move {
+            let links: Vec<Value> = req.send().await.unwrap().json().await.unwrap();
+            links
+                .iter()
+                .map(|l| l["url"].as_str().unwrap().to_string())
+                .collect::<Vec<_>>()
+        }
+    };
+    // reading back a new link goes to the primary
+    let res = app
+        .client
+        .post(&app.base)
+        .json(&json!({ "url": "https://example.com/primary", "tags": ["routing"] }))
+        .send()
+        .await
+        .unwrap();
+    let body: Value = res.json().await.unwrap();
+    assert_eq!(body["tags"], json!(["routing"]));
+    let id = body["url"].as_str().unwrap().rsplit('/').next().unwrap();
+    assert_eq!(listed(&app).await, ["https://example.com/replica"]);
+    let res = app
+        .client
+        .get(format!("{}/api/links/replicated/stats", app.base))
+        .bearer_auth(ADMIN_KEY)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    // redirects read the replica, and the primary for what it lacks
+    let res = app.get("/replicated").await;
+    assert_eq!(location(&res), "https://example.com/replica");
+    let res = app.get(&format!("/{}", id)).await;
+    assert_eq!(location(&res), "https://example.com/primary");
+    // writes only ever go to the primary
+    for req in [
+        app.client
+            .patch(format!("{}/replicated", app.base))
+            .json(&json!({ "url": "https://example.com/moved" })),
+        app.client.delete(format!("{}/replicated", app.base)),
+    ] {
+        let res = req.bearer_auth(ADMIN_KEY).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+    }
+    let (stored,): (i64,) = sqlx::query_as("SELECT COUNT(*) FROM urls WHERE id = $1")
+        .bind(id)
+        .fetch_one(&app.pool)
+        .await
+        .unwrap();
+    assert_eq!(stored, 1);
+
+    // reads take the replicas in turn, here the replica and the primary
+    let Some(balanced) = TestApp::spawn_configured(
+        |config| config.replica_urls = vec![replica_url.clone(), config.db_url.clone()],
+        |_, db| db,
+    )
+    .await
+    else {
+        return;
+    };
+    balanced.shorten("https://example.com/balanced").await;
+    sqlx::query("INSERT INTO link_tags (link_id, tag) SELECT id, 'routing' FROM urls")
+        .execute(&balanced.pool)
+        .await
+        .unwrap();
+    let mut seen = HashSet::new();
+    for _ in 0..2 {
+        seen.extend(listed(&balanced).await);
+    }
+    let expected = [
+        "https://example.com/balanced",
+        "https://example.com/replica",
+    ];
+    assert_eq!(seen, expected.map(String::from).into());
+
+    // with the replica gone reads fall back to the primary
+    let mut server = Url::parse(&replica_url).unwrap();
+    let name = server.path().trim_start_matches('/').to_string();
+    server.set_path("/postgres");
+    let admin = PgPool::connect(server.as_str()).await.unwrap();
+    sqlx::query(&format!("DROP DATABASE {} WITH (FORCE)", name))
+        .execute(&admin)
+        .await
+        .unwrap();
+    assert_eq!(listed(&app).await, ["https://example.com/primary"]);
+    assert_eq!(app.get("/replicated").await.status(), StatusCode::NOT_FOUND);
+    let res = app.get(&format!("/{}", id)).await;
+    assert_eq!(location(&res), "https://example.com/primary");
+}
+
+#[tokio::test]
+async fn link_descriptions() {
+    let Some(app) = TestApp::spawn().await else {
+        return;
+    };
+    let res = app
+        .client
+        .post(&app.base)
+        .json(&json!({
+            "url": "https://example.com/described",
+            "description": "  Spring\u{7}launch\r\nlanding page ",
+            "tags": ["described"],
+        }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::CREATED);
+    let body: Value = res.json().await.unwrap();
+    assert_eq!(body["description"], "Spring launch  landing page");
+    let id = body["url"].as_str().unwrap().rsplit('/').next().unwrap();
+
+    let info: Value = app
+        .get(&format!("/{}/info", id))
+        .await
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(info["url"], "https://example.com/described");
+    assert_eq!(info["description"], "Spring launch  landing page");
+    assert_eq!(info["created_at"], body["created_at"]);
+    assert!(info.get("notes").is_none());
+    let res = app
+        .client
+        .get(format!("{}/api/links?tag=described", app.base))
+        .bearer_auth(ADMIN_KEY)
+        .send()
+        .await
+        .unwrap();
+    let links: Value = res.json().await.unwrap();
+    assert_eq!(links[0]["description"], "Spring launch  landing page");
+
+    let res = app
+        .client
+        .post(&app.base)
+        .json(&json!({
+            "url": "https://example.com/long",
+            "description": "x".repeat(501),
+        }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    assert_eq!(
+        app.get("/missing/info").await.status(),
+        StatusCode::NOT_FOUND
+    );
+}
+
+#[tokio::test]
+async fn signed_links() {
+    let Some(app) = TestApp::spawn_configured(
+        |config| config.signing_key = Some("e2e-signing".into()),
+        |_, db| db,
+    )
+    .await
+    else {
+        return;
+    };
+    let create = |body: Value| {
+        let app = &app;
+        async move { app.client.post(&app.base).json(&body).send().await.unwrap() }
+    };
+
+    let unsigned = app.shorten("https://example.com/internal").await;
+    let res = create(json!({ "url": "https://example.com/internal", "signed": true })).await;
+    assert_eq!(res.status(), StatusCode::CREATED);
+    let body: Value = res.json().await.unwrap();
+    let slug = body["url"].as_str().unwrap().rsplit('/').next().unwrap();
+    let (id, signature) = slug.split_once('.').unwrap();
+    assert_ne!(slug, unsigned);
+    assert_eq!(signature.len(), 16);
+    let res = app.get(&format!("/{}", slug)).await;
+    assert_eq!(location(&res), "https://example.com/internal");
+    // the bare id isn't a link of its own
+    assert_eq!(
+        app.get(&format!("/{}", id)).await.status(),
+        StatusCode::NOT_FOUND
+    );
+
+    let res = create(json!({ "url": "https://example.com/x", "signed": true, "alias": "x" })).await;
+    assert_eq!(res.status(), StatusCode::UNPROCESSABLE_ENTITY);
+
+    // forged slugs are turned away without touching the database
+    app.pool.close().await;
+    let forged = format!("{}.{}", id, "0".repeat(16));
+    assert_eq!(
+        app.get(&format!("/{}", forged)).await.status(),
+        StatusCode::NOT_FOUND
+    );
+    let res = app.get(&format!("/{}", slug)).await;
+    assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+}
+
+#[tokio::test]
+async fn signing_needs_a_key() {
+    let Some(app) = TestApp::spawn().await else {
+        return;
+    };
+    let res = app
+        .client
+        .post(&app.base)
+        .json(&json!({ "url": "https://example.com/internal", "signed": true }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::UNPROCESSABLE_ENTITY);
+}
+
+#[tokio::test]
+async fn anonymous_redirects_are_delayed() {
+    let delay = Duration::from_millis(300);
+    let Some(app) = TestApp::spawn_with(|config, db| {
+        config.anonymous_redirect_delay = delay;
+        db
+    })
+    .await
+    else {
+        return;
+    };
+    let anonymous = app.shorten("https://example.com/anonymous").await;
+    let res = app
+        .client
+        .post(&app.base)
+        .bearer_auth(ADMIN_KEY)
+        .json(&json!({ "url": "https://example.com/owned" }))
+        .send()
+        .await
+        .unwrap();
+    let body: Value = res.json().await.unwrap();
+    let owned = body["url"].as_str().unwrap().rsplit('/').next().unwrap();
+
+    let timed = |id: String| {
+        let app = &app;
+        async move {
+            let started = std::time::Instant::now();
+            let res = app.get(&format!("/{}", id)).await;
+            assert_eq!(res.status(), StatusCode::FOUND);
+            started.elapsed()
+        }
+    };
+    assert!(timed(anonymous).await >= delay);
+    assert!(timed(owned.to_string()).await < delay);
+}
+
+#[tokio::test]
+async fn maintenance_mode() {
+    let Some(app) = TestApp::spawn_with(|config, db| {
+        config.retry_after_secs = 30;
+        db
+    })
+    .await
+    else {
+        return;
+    };
+    let id = app.shorten("https://example.com/before").await;
+    let set = |enabled: bool| {
+        app.client
+            .post(format!("{}/api/maintenance", app.base))
+            .bearer_auth(ADMIN_KEY)
+            .json(&json!({ "enabled": enabled, "message": "migrating, back soon" }))
+            .send()
+    };
+
+    // a write already under way when the mode is turned on goes through
+    let mut tx = app.pool.begin().await.unwrap();
+    sqlx::query("LOCK TABLE urls IN ACCESS EXCLUSIVE MODE")
+        .execute(&mut *tx)
+        .await
+        .unwrap();
+    let in_flight = tokio::spawn(
+        app.client
+            .post(&app.base)
+            .json(&json!({ "url": "https://example.com/in-flight" }))
+            .send(),
+    );
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    assert_eq!(set(true).await.unwrap().status(), StatusCode::OK);
+    tx.rollback().await.unwrap();
+    assert_eq!(
+        in_flight.await.unwrap().unwrap().status(),
+        StatusCode::CREATED
+    );
+
+    let res = app.post_url("https://example.com/during").await;
+    assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(res.headers()[reqwest::header::RETRY_AFTER], "30");
+    let body: Value = res.json().await.unwrap();
+    assert_eq!(body["message"], "migrating, back soon");
+    let res = app
+        .client
+        .delete(format!("{}/{}", app.base, id))
+        .bearer_auth(ADMIN_KEY)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+    // reads keep working
+    let res = app.get(&format!("/{}", id)).await;
+    assert_eq!(res.status(), StatusCode::FOUND);
+    assert_eq!(location(&res), "https://example.com/before");
+    let res = app.get(&format!("/{}/info", id)).await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let res = app.get("/healthz").await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let body: Value = res.json().await.unwrap();
+    assert_eq!(body["status"], "read-only");
+    let res = app
+        .client
+        .get(format!("{}/api/maintenance", app.base))
+        .bearer_auth(ADMIN_KEY)
+        .send()
+        .await
+        .unwrap();
+    let body: Value = res.json().await.unwrap();
+    assert_eq!(body["enabled"], true);
+
+    // only admins may flip it
+    let res = app
+        .client
+        .post(format!("{}/api/maintenance", app.base))
+        .json(&json!({ "enabled": false }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+
+    assert_eq!(set(false).await.unwrap().status(), StatusCode::OK);
+    assert_eq!(
+        app.post_url("https://example.com/after").await.status(),
+        StatusCode::CREATED
+    );
+    let body: Value = app.get("/healthz").await.json().await.unwrap();
+    assert_eq!(body["status"], "ok");
+}
+
+#[tokio::test]
+async fn maintenance_mode_reaches_other_instances() {
+    let Some(first) = TestApp::spawn().await else {
+        return;
+    };
+    let db_url = first.db_url.clone();
+    let Some(second) = TestApp::spawn_configured(
+        |config| {
+            config.db_url = db_url;
+            config.maintenance_poll = Duration::from_millis(100);
+        },
+        |_, db| db,
+    )
+    .await
+    else {
+        return;
+    };
+    second.state.spawn_workers();
+    let set = |enabled: bool| {
+        first
+            .client
+            .post(format!("{}/api/maintenance", first.base))
+            .bearer_auth(ADMIN_KEY)
+            .json(&json!({ "enabled": enabled }))
+            .send()
+    };
+
+    assert_eq!(set(true).await.unwrap().status(), StatusCode::OK);
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    let res = second.post_url("https://example.com/elsewhere").await;
+    assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+    let body: Value = second.get("/healthz").await.json().await.unwrap();
+    assert_eq!(body["status"], "read-only");
+
+    assert_eq!(set(false).await.unwrap().status(), StatusCode::OK);
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    let res = second.post_url("https://example.com/elsewhere").await;
+    assert_eq!(res.status(), StatusCode::CREATED);
+
+    // the environment wins over what was stored before the start
+    let db_url = first.db_url.clone();
+    let Some(third) = TestApp::spawn_configured(
+        |config| {
+            config.db_url = db_url;
+            config.maintenance = Some(true);
+            config.maintenance_poll = Duration::from_millis(100);
+        },
+        |_, db| db,
+    )
+    .await
+    else {
+        return;
+    };
+    third.state.spawn_workers();
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    let res = third.post_url("https://example.com/third").await;
+    assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+}
+
+#[tokio::test]
+async fn suspended_owners_links_stop_redirecting() {
+    let Some(app) = TestApp::spawn().await else {
+        return;
+    };
+    let app = &app;
+    let alice = app.create_key("alice", &["write"]).await;
+    let bob = app.create_key("bob", &["write"]).await;
+    let shorten_as = |key: String, url: &'static str| async move {
+        let res = app
+            .client
+            .post(&app.base)
+            .bearer_auth(key)
+            .json(&json!({ "url": url }))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::CREATED);
+        let body: Value = res.json().await.unwrap();
+        body["url"]
+            .as_str()
+            .unwrap()
+            .rsplit('/')
+            .next()
+            .unwrap()
+            .to_string()
+    };
+    let alices = shorten_as(alice.clone(), "https://example.com/alice").await;
+    let bobs = shorten_as(bob, "https://example.com/bob").await;
+    let anonymous = app.shorten("https://example.com/anonymous").await;
+    let owner: String = sqlx::query_scalar("SELECT owner FROM urls WHERE id = $1")
+        .bind(&alices)
+        .fetch_one(&app.pool)
+        .await
+        .unwrap();
+    let set = |key: String, body: Value| {
+        app.client
+            .put(format!("{}/api/owners/{}/status", app.base, owner))
+            .bearer_auth(key)
+            .json(&body)
+            .send()
+    };
+
+    let res = set(alice.clone(), json!({ "status": "suspended" }))
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::FORBIDDEN);
+    let res = set(ADMIN_KEY.into(), json!({ "status": "suspended" }))
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    let res = app.get(&format!("/{}", alices)).await;
+    assert_eq!(res.status(), StatusCode::PAYMENT_REQUIRED);
+    assert!(res.headers().get(LOCATION).is_none());
+    let body: Value = res.json().await.unwrap();
+    assert_eq!(body["error"], "owner_suspended");
+    assert_eq!(body["message"], "the owner of this link is suspended");
+    // everyone else's links are unaffected
+    for id in [&bobs, &anonymous] {
+        assert_eq!(
+            app.get(&format!("/{}", id)).await.status(),
+            StatusCode::FOUND
+        );
+    }
+
+    let body = json!({ "status": "suspended", "message": "billing overdue" });
+    let res = set(ADMIN_KEY.into(), body).await.unwrap();
+    let status: Value = res.json().await.unwrap();
+    assert_eq!(status["status"], "suspended");
+    assert!(status["suspended_at"].is_string());
+    let res = app.get(&format!("/{}", alices)).await;
+    let body: Value = res.json().await.unwrap();
+    assert_eq!(body["message"], "billing overdue");
+    let res = app
+        .client
+        .get(format!("{}/api/owners/{}/status", app.base, owner))
+        .bearer_auth(ADMIN_KEY)
+        .send()
+        .await
+        .unwrap();
+    let status: Value = res.json().await.unwrap();
+    assert_eq!(status["message"], "billing overdue");
+
+    let res = set(ADMIN_KEY.into(), json!({ "status": "active" }))
+        .await
+        .unwrap();
+    let status: Value = res.json().await.unwrap();
+    assert_eq!(status, json!({ "status": "active" }));
+    let res = app.get(&format!("/{}", alices)).await;
+    assert_eq!(res.status(), StatusCode::FOUND);
+}
+
+#[tokio::test]
+async fn suspended_owners_get_the_configured_status() {
+    let Some(app) = TestApp::spawn_configured(
+        |config| {
+            config.suspended_owner_status = 403;
+            config.suspended_owner_message = "account on hold".into();
+        },
+        |_, db| db,
+    )
+    .await
+    else {
+        return;
+    };
+    let res = app
+        .client
+        .post(&app.base)
+        .bearer_auth(ADMIN_KEY)
+        .json(&json!({ "url": "https://example.com/held" }))
+        .send()
+        .await
+        .unwrap();
+    let body: Value = res.json().await.unwrap();
+    let id = body["url"].as_str().unwrap().rsplit('/').next().unwrap();
+    let res = app
+        .client
+        .put(format!("{}/api/owners/API_KEY/status", app.base))
+        .bearer_auth(ADMIN_KEY)
+        .json(&json!({ "status": "suspended" }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    let res = app.get(&format!("/{}", id)).await;
+    assert_eq!(res.status(), StatusCode::FORBIDDEN);
+    let body: Value = res.json().await.unwrap();
+    assert_eq!(body["message"], "account on hold");
+}
+
+#[tokio::test]
+async fn warms_up_the_pool() {
+    let Some((db_url, _container)) = database().await else {
+        return;
+    };
+    let mut config = Config::from_env().unwrap();
+    config.db_url = db_url;
+    config.db_min_connections = 4;
+    // the pool may open a spare while checking idle ones otherwise
+    config.db_max_connections = 4;
+    let db = PgState::try_new(&config).await.unwrap();
+    db.warm_up(config.db_min_connections).await.unwrap();
+    assert_eq!(db.pool().num_idle(), 4);
+}
+
+#[tokio::test]
+async fn short_links_to_short_links() {
+    let Some(app) = TestApp::spawn().await else {
+        return;
+    };
+    let end = app.shorten("https://example.com/end").await;
+    let shorten = |body: Value| app.client.post(&app.base).json(&body).send();
+    let pool = &app.pool;
+    let point = |id: String, to: String| async move {
+        sqlx::query("UPDATE urls SET url = $2 WHERE id = $1")
+            .bind(id)
+            .bind(to)
+            .execute(pool)
+            .await
+            .unwrap();
+    };
+
+    // shortening a short link gives the link itself back
+    let res = shorten(json!({ "url": format!("{}/{}", app.base, end) }))
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    let body: Value = res.json().await.unwrap();
+    assert!(body["url"].as_str().unwrap().ends_with(&end));
+
+    // chains from before are followed to where they end
+    let middle = app.shorten("https://example.com/middle").await;
+    let start = app.shorten("https://example.com/start").await;
+    point(middle.clone(), format!("{}/{}", app.base, end)).await;
+    point(start.clone(), format!("{}/{}", app.base, middle)).await;
+    let res = shorten(json!({ "url": format!("{}/{}", app.base, start), "dedupe": false }))
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::CREATED);
+    let body: Value = res.json().await.unwrap();
+    let id = body["url"].as_str().unwrap().rsplit('/').next().unwrap();
+    let res = app.get(&format!("/{}", id)).await;
+    assert_eq!(location(&res), "https://example.com/end");
+
+    // a loop never gets anywhere
+    point(middle.clone(), format!("{}/{}", app.base, start)).await;
+    let res = shorten(json!({ "url": format!("{}/{}", app.base, start) }))
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    let body: Value = res.json().await.unwrap();
+    assert_eq!(body["error"], "self_reference");
+
+    // neither does a link to itself
+    let res = shorten(json!({ "url": format!("{}/mine", app.base), "alias": "mine" }))
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    let body: Value = res.json().await.unwrap();
+    assert_eq!(body["error"], "self_reference");
+    assert_eq!(app.get("/mine").await.status(), StatusCode::NOT_FOUND);
+    let res = shorten(json!({ "url": format!("{}/api/links", app.base) }))
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::UNPROCESSABLE_ENTITY);
+}
+
+#[tokio::test]
+async fn unsupported_methods_list_allowed_ones() {
+    let Some(app) = TestApp::spawn().await else {
+        return;
+    };
+    for (method, path, allowed) in [
+        (reqwest::Method::PUT, "/", "GET,HEAD,POST"),
+        (reqwest::Method::POST, "/abc", "GET,HEAD,PATCH,DELETE"),
+        (reqwest::Method::GET, "/abc/rotate", "POST"),
+    ] {
+        let res = app
+            .client
+            .request(method, format!("{}{}", app.base, path))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(res.headers()[reqwest::header::ALLOW], allowed);
+        let body: Value = res.json().await.unwrap();
+        assert_eq!(body["error"], "method_not_allowed");
+    }
+}
+
+#[tokio::test]
+async fn bursts_of_identical_shortens_create_one_link() {
+    let Some(app) = TestApp::spawn().await else {
+        return;
+    };
+    let send = |key: Option<&str>| {
+        let mut req = app
+            .client
+            .post(&app.base)
+            .json(&json!({ "url": "https://example.com/burst", "dedupe": false }));
+        if let Some(key) = key {
+            req = req.bearer_auth(key);
+        }
+        req.send()
+    };
+    let responses = join_all((0..50).map(|_| send(None))).await;
+    let mut ids = HashSet::new();
+    let mut created = 0;
+    for res in responses {
+        let res = res.unwrap();
+        assert!(res.status().is_success(), "{}", res.status());
+        if res.status() == StatusCode::CREATED {
+            created += 1;
+        }
+        let body: Value = res.json().await.unwrap();
+        ids.insert(body["url"].as_str().unwrap().to_string());
+    }
+    assert_eq!(ids.len(), 1);
+    assert_eq!(created, 1);
+    let (links,): (i64,) = sqlx::query_as("SELECT COUNT(*) FROM urls")
+        .fetch_one(&app.pool)
+        .await
+        .unwrap();
+    assert_eq!(links, 1);
+
+    // another caller's burst gets a link of its own, paid for once
+    let key = app.create_key("script", &["write"]).await;
+    let responses = join_all((0..5).map(|_| send(Some(&key)))).await;
+    let ids: HashSet<String> = join_all(responses.into_iter().map(|res| async {
+        let body: Value = res.unwrap().json().await.unwrap();
+        body["url"].as_str().unwrap().to_string()
+    }))
+    .await
+    .into_iter()
+    .collect();
+    assert_eq!(ids.len(), 1);
+    let res = app
+        .client
+        .get(format!("{}/api/usage", app.base))
+        .bearer_auth(&key)
+        .send()
+        .await
+        .unwrap();
+    let usage: Value = res.json().await.unwrap();
+    assert_eq!(usage["day"]["count"], 1, "{}", usage);
+    let (links,): (i64,) = sqlx::query_as("SELECT COUNT(*) FROM urls")
+        .fetch_one(&app.pool)
+        .await
+        .unwrap();
+    assert_eq!(links, 2);
+}
+
+/// Reads a CSV body back into rows of the JSON shape, empty cells as null.
+fn csv_rows(body: &str) -> Vec<serde_json::Map<String, Value>> {
+    let mut reader = csv::Reader::from_reader(body.as_bytes());
+    let headers = reader.headers().unwrap().clone();
+    reader
+        .records()
+        .map(|record| {
+            let record = record.unwrap();
+            headers
+                .iter()
+                .zip(record.iter())
+                .map(|(h, v)| (h.to_string(), Value::String(v.to_string())))
+                .collect()
+        })
+        .collect()
+}
+
+/// A JSON value as the CSV writes it.
+fn as_cell(value: &Value) -> Value {
+    Value::String(match value {
+        Value::Null => String::new(),
+        Value::String(s) => s.clone(),
+        value => value.to_string(),
+    })
+}
+
+#[tokio::test]
+async fn csv_listings() {
+    let Some(app) = TestApp::spawn().await else {
+        return;
+    };
+    for (url, notes) in [
+        ("https://example.com/plain", None),
+        (
+            "https://example.com/awkward",
+            Some("commas, \"quotes\"\nand a second line"),
+        ),
+    ] {
+        let res = app
+            .client
+            .post(&app.base)
+            .bearer_auth(ADMIN_KEY)
+            .json(&json!({ "url": url, "tags": ["export", "sheet"], "notes": notes }))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::CREATED);
+    }
+    let get = |path: &str, accept: Option<&str>| {
+        let mut req = app
+            .client
+            .get(format!("{}{}", app.base, path))
+            .bearer_auth(ADMIN_KEY);
+        if let Some(accept) = accept {
+            req = req.header(reqwest::header::ACCEPT, accept);
+        }
+        req.send()
+    };
+
+    let res = get("/api/links?tag=export", None).await.unwrap();
+    assert_eq!(
+        res.headers()[reqwest::header::CONTENT_TYPE],
+        "application/json"
+    );
+    let links: Vec<Value> = res.json().await.unwrap();
+    assert_eq!(links.len(), 2);
+    for (path, accept) in [
+        ("/api/links?tag=export", Some("text/csv")),
+        ("/api/links?tag=export&format=csv", None),
+        (
+            "/api/links?tag=export",
+            Some("application/json;q=0.5, text/csv"),
+        ),
+    ] {
+        let res = get(path, accept).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert!(res.headers()[reqwest::header::CONTENT_TYPE]
+            .to_str()
+            .unwrap()
+            .starts_with("text/csv"));
+        assert_eq!(
+            res.headers()[reqwest::header::CONTENT_DISPOSITION],
+            "attachment; filename=\"links.csv\""
+        );
+        let body = res.text().await.unwrap();
+        assert!(body.ends_with("\r\n"));
+        let rows = csv_rows(&body);
+        assert_eq!(rows.len(), links.len());
+        for (row, link) in rows.iter().zip(&links) {
+            let link = link.as_object().unwrap();
+            assert_eq!(
+                row.keys().collect::<Vec<_>>(),
+                link.keys().collect::<Vec<_>>()
+            );
+            for (column, value) in link {
+                assert_eq!(row[column], as_cell(value), "{}", column);
+            }
+        }
+    }
+    // JSON stays the default for anything else
+    let res = get("/api/links?tag=export", Some("*/*")).await.unwrap();
+    assert_eq!(
+        res.headers()[reqwest::header::CONTENT_TYPE],
+        "application/json"
+    );
+    let res = get("/api/links?tag=export&format=xml", None).await.unwrap();
+    assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+
+    let id = links[0]["id"].as_str().unwrap();
+    for _ in 0..3 {
+        app.get(&format!("/{}", id)).await;
+    }
+    let path = format!("/api/links/{}/stats/daily", id);
+    let daily: Vec<Value> = get(&path, None).await.unwrap().json().await.unwrap();
+    let res = get(&path, Some("text/csv")).await.unwrap();
+    assert_eq!(
+        res.headers()[reqwest::header::CONTENT_DISPOSITION],
+        format!("attachment; filename=\"daily-stats-{}.csv\"", id).as_str()
+    );
+    let rows = csv_rows(&res.text().await.unwrap());
+    assert_eq!(rows.len(), daily.len());
+    for (row, day) in rows.iter().zip(&daily) {
+        for (column, value) in day.as_object().unwrap() {
+            assert_eq!(row[column], as_cell(value), "{}", column);
+        }
+    }
+
+    let res = get("/api/links/hot?format=csv", None).await.unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(
+        res.headers()[reqwest::header::CONTENT_DISPOSITION],
+        "attachment; filename=\"hot-links.csv\""
+    );
+}
+
+/// Makes the next `times` inserts into `urls` fail with SQLSTATE `code`.
+async fn fail_inserts(pool: &PgPool, code: &str, times: i64) {
+    sqlx::query("CREATE SEQUENCE IF NOT EXISTS failed_inserts")
+        .execute(pool)
+        .await
+        .unwrap();
+    sqlx::query("ALTER SEQUENCE failed_inserts RESTART")
+        .execute(pool)
+        .await
+        .unwrap();
+    sqlx::query(&format!(
+        "CREATE OR REPLACE FUNCTION fail_insert() RETURNS trigger AS $$
+         BEGIN
+             IF nextval('failed_inserts') <= {} THEN
+                 RAISE EXCEPTION 'injected failure' USING ERRCODE = '{}';
+             END IF;
+             RETURN NEW;
+         END $$ LANGUAGE plpgsql",
+        times, code
+    ))
+    .execute(pool)
+    .await
+    .unwrap();
+    sqlx::query(
+        "CREATE OR REPLACE TRIGGER fail_insert BEFORE INSERT ON urls
+         FOR EACH ROW EXECUTE FUNCTION fail_insert()",
+    )
+    .execute(pool)
+    .await
+    .unwrap();
+}
+
+#[tokio::test]
+async fn shorten_failures_are_told_apart() {
+    let Some(app) = TestApp::spawn_with(|config, db| {
+        config.retry_after_secs = 4;
+        db
+    })
+    .await
+    else {
+        return;
+    };
+    let shorten = |body: Value| app.client.post(&app.base).json(&body).send();
+
+    // lost serialization races are retried
+    fail_inserts(&app.pool, "40001", 2).await;
+    let res = shorten(json!({ "url": "https://example.com/retried" }))
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::CREATED);
+
+    // and given up on after that, for the client to retry later
+    fail_inserts(&app.pool, "40P01", 3).await;
+    let res = shorten(json!({ "url": "https://example.com/deadlocked" }))
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(res.headers()[reqwest::header::RETRY_AFTER], "4");
+    // only the three attempts used the sequence up
+    let res = shorten(json!({ "url": "https://example.com/deadlocked" }))
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::CREATED);
+
+    // a constraint a custom alias breaks is a conflict
+    fail_inserts(&app.pool, "23514", 1).await;
+    let res = shorten(json!({ "url": "https://example.com/checked", "alias": "checked" }))
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::CONFLICT);
+
+    // a value the database won't take is the client's to fix
+    fail_inserts(&app.pool, "22001", 1).await;
+    let res = shorten(json!({ "url": "https://example.com/too-long" }))
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::UNPROCESSABLE_ENTITY);
+
+    // anything else is ours, with the id it's logged under
+    for (code, body) in [
+        ("23514", json!({ "url": "https://example.com/generated" })),
+        ("XX000", json!({ "url": "https://example.com/broken" })),
+    ] {
+        fail_inserts(&app.pool, code, 1).await;
+        let res = shorten(body).await.unwrap();
+        assert_eq!(res.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        let request_id = res.headers()["x-request-id"].to_str().unwrap().to_string();
+        let body: Value = res.json().await.unwrap();
+        assert_eq!(body["request_id"], request_id.as_str());
+    }
+}
+
+#[tokio::test]
+async fn resolves_as_json() {
+    let Some(app) = TestApp::spawn().await else {
+        return;
+    };
+    let id = app.shorten("https://example.com/integration").await;
+
+    let res = app.get(&format!("/{}.json", id)).await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let body: Value = res.json().await.unwrap();
+    assert_eq!(
+        body,
+        json!({ "id": id, "url": "https://example.com/integration" })
+    );
+
+    let res = app.get(&format!("/{}", id)).await;
+    assert_eq!(res.status(), StatusCode::FOUND);
+    assert_eq!(location(&res), "https://example.com/integration");
+
+    assert_eq!(
+        app.get("/unknown.json").await.status(),
+        StatusCode::NOT_FOUND
+    );
+    assert_eq!(app.get("/.json").await.status(), StatusCode::NOT_FOUND);
+
+    // through an alias the link's own id is reported, and no alias can end
+    // in the suffix itself
+    let aliases = format!("{}/api/links/{}/aliases", app.base, id);
+    for (alias, status) in [
+        ("docs", StatusCode::CREATED),
+        ("docs.json", StatusCode::UNPROCESSABLE_ENTITY),
+    ] {
+        let res = app
+            .client
+            .post(&aliases)
+            .bearer_auth(ADMIN_KEY)
+            .json(&json!({ "alias": alias }))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), status, "{}", alias);
+    }
+    let body: Value = app.get("/docs.json").await.json().await.unwrap();
+    assert_eq!(body["id"], id);
+}
+
+#[tokio::test]
+async fn sweeps_expired_links() {
+    let Some(app) = TestApp::spawn().await else {
+        return;
+    };
+    let mut expired = Vec::new();
+    for path in ["a", "b"] {
+        let id = app
+            .shorten(&format!("https://example.com/expired/{}", path))
+            .await;
+        sqlx::query("UPDATE urls SET expires_at = now() - interval '1 hour' WHERE id = $1")
+            .bind(&id)
+            .execute(&app.pool)
+            .await
+            .unwrap();
+        expired.push(id);
+    }
+    expired.sort();
+    let live = app.shorten("https://example.com/live").await;
+    let sweep = |dry_run: bool| {
+        app.client
+            .post(format!(
+                "{}/api/admin/sweep-expired?dry_run={}",
+                app.base, dry_run
+            ))
+            .bearer_auth(ADMIN_KEY)
+            .send()
+    };
+
+    let res = sweep(true).await.unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    let body: Value = res.json().await.unwrap();
+    assert_eq!(
+        body,
+        json!({ "dry_run": true, "count": 2, "sample": expired })
+    );
+    for id in &expired {
+        assert_eq!(
+            app.get(&format!("/{}", id)).await.status(),
+            StatusCode::GONE
+        );
+    }
+
+    let body: Value = sweep(false).await.unwrap().json().await.unwrap();
+    assert_eq!(
+        body,
+        json!({ "dry_run": false, "count": 2, "sample": expired })
+    );
+    for id in &expired {
+        assert_eq!(
+            app.get(&format!("/{}", id)).await.status(),
+            StatusCode::NOT_FOUND
+        );
+    }
+    assert_eq!(
+        app.get(&format!("/{}", live)).await.status(),
+        StatusCode::FOUND
+    );
+    let body: Value = sweep(true).await.unwrap().json().await.unwrap();
+    assert_eq!(body["count"], 0);
+}
+
+#[tokio::test]
+async fn dry_run_delete() {
+    let Some(app) = TestApp::spawn().await else {
+        return;
+    };
+    let id = app.shorten("https://example.com/keep-me").await;
+    let delete = |slug: &str, dry_run: bool| {
+        app.client
+            .delete(format!("{}/{}?dry_run={}", app.base, slug, dry_run))
+            .bearer_auth(ADMIN_KEY)
+            .send()
+    };
+
+    let res = delete(&id, true).await.unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    let body: Value = res.json().await.unwrap();
+    assert_eq!(body, json!({ "dry_run": true, "count": 1, "sample": [id] }));
+    assert_eq!(
+        app.get(&format!("/{}", id)).await.status(),
+        StatusCode::FOUND
+    );
+    assert_eq!(
+        delete("unknown", true).await.unwrap().status(),
+        StatusCode::NOT_FOUND
+    );
+
+    let res = delete(&id, false).await.unwrap();
+    assert_eq!(res.status(), StatusCode::NO_CONTENT);
+    assert_eq!(
+        app.get(&format!("/{}", id)).await.status(),
+        StatusCode::NOT_FOUND
+    );
+}
+
+#[tokio::test]
+async fn forces_https_targets() {
+    let Some(app) = TestApp::spawn_with(|config, db| {
+        config.force_https_targets = true;
+        db
+    })
+    .await
+    else {
+        return;
+    };
+    for (target, expected) in [
+        (
+            "http://example.com/plain?a=1",
+            "https://example.com/plain?a=1",
+        ),
+        ("http://example.com:80/port", "https://example.com/port"),
+        (
+            "http://example.com:8080/other",
+            "https://example.com:8080/other",
+        ),
+        ("https://example.com/secure", "https://example.com/secure"),
+        ("ftp://example.com/file", "ftp://example.com/file"),
+    ] {
+        let id = app.shorten(target).await;
+        let res = app.get(&format!("/{}", id)).await;
+        assert_eq!(location(&res), expected, "{}", target);
+    }
+}
+
+#[tokio::test]
+async fn destination_history() {
+    let Some(app) = TestApp::spawn_with(|config, db| {
+        config.shorten_coalesce_window = Duration::ZERO;
+        db
+    })
+    .await
+    else {
+        return;
+    };
+    let id = app.shorten("https://example.com/v1").await;
+    let patch = |url: String| {
+        app.client
+            .patch(format!("{}/{}", app.base, id))
+            .bearer_auth(ADMIN_KEY)
+            .json(&json!({ "url": url }))
+            .send()
+    };
+    let rollback = |slug: &str| {
+        app.client
+            .post(format!("{}/api/links/{}/rollback", app.base, slug))
+            .bearer_auth(ADMIN_KEY)
+            .send()
+    };
+    let history_in = |query: &'static str| {
+        let url = format!("{}/api/links/{}/history{}", app.base, id, query);
+        async {
+            let res = app
+                .client
+                .get(url)
+                .bearer_auth(ADMIN_KEY)
+                .send()
+                .await
+                .unwrap();
+            assert_eq!(res.status(), StatusCode::OK);
+            res.json::<Vec<Value>>().await.unwrap()
+        }
+    };
+    let history = || history_in("?order=asc");
+
+    // the creation, with no url before it and no owner
+    let changes = history().await;
+    assert_eq!(changes.len(), 1);
+    assert_eq!(
+        changes[0].as_object().unwrap().keys().collect::<Vec<_>>(),
+        ["new_url", "changed_at"]
+    );
+    assert_eq!(changes[0]["new_url"], "https://example.com/v1");
+
+    assert_eq!(rollback(&id).await.unwrap().status(), StatusCode::CONFLICT);
+    assert_eq!(
+        rollback("unknown").await.unwrap().status(),
+        StatusCode::NOT_FOUND
+    );
+    for v in ["v2", "v3"] {
+        let res = patch(format!("https://example.com/{}", v)).await.unwrap();
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+    }
+    let res = app.get(&format!("/{}", id)).await;
+    assert_eq!(location(&res), "https://example.com/v3");
+    let changes = history().await;
+    assert_eq!(changes.len(), 3);
+    assert_eq!(changes[0]["new_url"], "https://example.com/v1");
+    assert!(changes[0].get("old_url").is_none());
+    assert_eq!(changes[1]["old_url"], "https://example.com/v1");
+    assert_eq!(changes[1]["new_url"], "https://example.com/v2");
+    assert_eq!(changes[2]["new_url"], "https://example.com/v3");
+    assert_eq!(changes[2]["actor"], "API_KEY");
+    // newest first unless asked otherwise
+    let newest_first = history_in("").await;
+    assert_eq!(newest_first.len(), 3);
+    assert_eq!(newest_first[0]["new_url"], "https://example.com/v3");
+    assert_eq!(newest_first[2]["new_url"], "https://example.com/v1");
+    assert_eq!(history_in("?order=desc").await, newest_first);
+    let res = app
+        .client
+        .get(format!("{}/api/links/{}/history?order=up", app.base, id))
+        .bearer_auth(ADMIN_KEY)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+
+    // the original url no longer finds the link when shortened again
+    assert_ne!(app.shorten("https://example.com/v1").await, id);
+
+    let res = rollback(&id).await.unwrap();
+    assert_eq!(res.status(), StatusCode::NO_CONTENT);
+    let res = app.get(&format!("/{}", id)).await;
+    assert_eq!(location(&res), "https://example.com/v2");
+    let changes = history().await;
+    assert_eq!(changes.len(), 4);
+    assert_eq!(changes[3]["old_url"], "https://example.com/v3");
+    assert_eq!(changes[3]["new_url"], "https://example.com/v2");
+
+    // setting the current url again isn't a change
+    patch("https://example.com/v2".into()).await.unwrap();
+    assert_eq!(history().await.len(), 4);
+
+    for n in 0..50 {
+        patch(format!("https://example.com/n{}", n)).await.unwrap();
+    }
+    // the creation is trimmed along with the first change
+    let changes = history().await;
+    assert_eq!(changes.len(), 50);
+    assert_eq!(changes[0]["old_url"], "https://example.com/v2");
+    assert_eq!(changes[49]["new_url"], "https://example.com/n49");
+
+    let res = patch("not a url".into()).await.unwrap();
+    assert_eq!(res.status(), StatusCode::UNPROCESSABLE_ENTITY);
+
+    app.client
+        .delete(format!("{}/{}", app.base, id))
+        .bearer_auth(ADMIN_KEY)
+        .send()
+        .await
+        .unwrap();
+    let (left,): (i64,) = sqlx::query_as("SELECT COUNT(*) FROM url_history WHERE link_id = $1")
+        .bind(&id)
+        .fetch_one(&app.pool)
+        .await
+        .unwrap();
+    assert_eq!(left, 0);
+}
+
+#[tokio::test]
+async fn counts_requests_per_route() {
+    let Some(app) = TestApp::spawn().await else {
+        return;
+    };
+    let id = app.shorten("https://example.com/counted").await;
+    app.get(&format!("/{}", id)).await;
+
+    let metrics = app.get("/metrics").await.text().await.unwrap();
+    for series in [
+        r#"requests_total{route="/",method="POST"}"#,
+        r#"requests_total{route="/:id",method="GET"}"#,
+        r#"request_size_bytes_count{route="/"}"#,
+        r#"response_size_bytes_count{route="/"}"#,
+    ] {
+        assert!(metrics.contains(series), "{} in {}", series, metrics);
+    }
+    // ids don't get a series of their own, only the busiest links a
+    // latency histogram
+    assert!(!metrics.contains(&format!("route=\"/{}\"", id)));
+    assert!(metrics.contains(&format!(
+        r#"redirect_duration_seconds_count{{link="{}"}}"#,
+        id
+    )));
+}
+
+#[tokio::test]
+async fn counts_redirects_by_outcome() {
+    let Some(app) = TestApp::spawn().await else {
+        return;
+    };
+    let id = app.shorten("https://example.com/outcomes").await;
+    assert_eq!(
+        app.get(&format!("/{}", id)).await.status(),
+        StatusCode::FOUND
+    );
+    let res = app.get("/nosuchlink").await;
+    assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+    let metrics = app.get("/metrics").await.text().await.unwrap();
+    for series in [
+        r#"redirect_total{outcome="found"}"#,
+        r#"redirect_total{outcome="not_found"}"#,
+    ] {
+        assert!(metrics.contains(series), "{} in {}", series, metrics);
+    }
+}
+
+#[tokio::test]
+async fn signed_redirects() {
+    let Some(app) = TestApp::spawn_with(|config, db| {
+        config.response_signing_key = Some("cdn-debugging".into());
+        db
+    })
+    .await
+    else {
+        return;
+    };
+    let id = app.shorten("https://example.com/signed").await;
+    let res = app.get(&format!("/{}", id)).await;
+    let header = res.headers()["x-shortener-signature"].to_str().unwrap();
+    let (timestamp, signature) = header
+        .strip_prefix("t=")
+        .and_then(|h| h.split_once(",v1="))
+        .unwrap();
+    let verify = |url: &str| {
+        let mut verify = Url::parse(&format!("{}/api/verify", app.base)).unwrap();
+        verify
+            .query_pairs_mut()
+            .append_pair("id", &id)
+            .append_pair("url", url)
+            .append_pair("status", "302")
+            .append_pair("timestamp", timestamp)
+            .append_pair("signature", signature);
+        app.client.get(verify).send()
+    };
+
+    let body: Value = verify(location(&res)).await.unwrap().json().await.unwrap();
+    assert_eq!(body, json!({ "valid": true }));
+    let body: Value = verify("https://evil.example/")
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(body, json!({ "valid": false }));
+
+    // off unless configured
+    let Some(plain) = TestApp::spawn().await else {
+        return;
+    };
+    let res = plain
+        .get(&format!("/{}", plain.shorten("https://example.com/").await))
+        .await;
+    assert!(!res.headers().contains_key("x-shortener-signature"));
+    let query = "id=a&url=https%3A%2F%2Fexample.com%2F&status=302&timestamp=0&signature=00";
+    assert_eq!(
+        plain.get(&format!("/api/verify?{}", query)).await.status(),
+        StatusCode::NOT_FOUND
+    );
+}
+
+#[tokio::test]
+async fn read_only_at_startup() {
+    let Some(first) = TestApp::spawn().await else {
+        return;
+    };
+    let id = first.shorten("https://example.com/read-only").await;
+    let db_url = first.db_url.clone();
+    let Some(app) = TestApp::spawn_configured(
+        |config| {
+            config.db_url = db_url;
+            config.maintenance = Some(true);
+        },
+        |_, db| db,
+    )
+    .await
+    else {
+        return;
+    };
+
+    let res = app.post_url("https://example.com/refused").await;
+    assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+    assert!(res.headers().contains_key(reqwest::header::RETRY_AFTER));
+    let res = app.get(&format!("/{}", id)).await;
+    assert_eq!(res.status(), StatusCode::FOUND);
+    assert_eq!(location(&res), "https://example.com/read-only");
+    let res = app.get(&format!("/{}/info", id)).await;
+    assert_eq!(res.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn platform_targets() {
+    let Some(app) = TestApp::spawn().await else {
+        return;
+    };
+    let create = |targets: Value| {
+        app.client
+            .post(&app.base)
+            .json(&json!({ "url": "https://example.com/app", "platform_targets": targets }))
+            .send()
+    };
+    let res = create(json!({
+        "ios": "https://apps.apple.com/app/id123",
+        "android": "https://play.google.com/store/apps/details?id=com.example",
+    }))
+    .await
+    .unwrap();
+    assert_eq!(res.status(), StatusCode::CREATED);
+    let body: Value = res.json().await.unwrap();
+    let id = body["url"].as_str().unwrap().rsplit('/').next().unwrap();
+
+    let visit = |agent: &'static str| {
+        app.client
+            .get(format!("{}/{}", app.base, id))
+            .header(reqwest::header::USER_AGENT, agent)
+            .send()
+    };
+    for (agent, expected) in [
+        (
+            "Mozilla/5.0 (iPhone; CPU iPhone OS 17_4 like Mac OS X)",
+            "https://apps.apple.com/app/id123",
+        ),
+        (
+            "Mozilla/5.0 (Linux; Android 14; Pixel 8)",
+            "https://play.google.com/store/apps/details?id=com.example",
+        ),
+        (
+            "Mozilla/5.0 (Windows NT 10.0; Win64; x64)",
+            "https://example.com/app",
+        ),
+    ] {
+        let res = visit(agent).await.unwrap();
+        assert_eq!(location(&res), expected, "{}", agent);
+        assert_eq!(res.headers()[reqwest::header::VARY], "user-agent");
+    }
+
+    // the same url without targets is a link of its own, on the fast path
+    let plain = app.shorten("https://example.com/app").await;
+    assert_ne!(plain, id);
+    let res = app.get(&format!("/{}", plain)).await;
+    assert_eq!(location(&res), "https://example.com/app");
+    assert!(!res.headers().contains_key(reqwest::header::VARY));
+
+    let res = create(json!({ "ios": "not a url" })).await.unwrap();
+    assert_eq!(res.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    let res = create(json!({ "windows": "https://example.com/" }))
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::UNPROCESSABLE_ENTITY);
+}
+
+#[tokio::test]
+async fn geo_targets_pick_by_country() {
+    let geoip = std::env::temp_dir().join(format!("geoip-{}.csv", std::process::id()));
+    std::fs::write(
+        &geoip,
+        "# test ranges\n203.0.113.0/24,DE\n198.51.100.0/24,us\n",
+    )
+    .unwrap();
+    let Some(app) = TestApp::spawn_configured(
+        |config| {
+            config.geoip_path = Some(geoip.clone());
+            config.trusted_proxies = vec!["127.0.0.1/32".parse().unwrap()];
+        },
+        |_, db| db,
+    )
+    .await
+    else {
+        return;
+    };
+    let create = |targets: Value| {
+        app.client
+            .post(&app.base)
+            .json(&json!({ "url": "https://example.com/store", "geo_targets": targets }))
+            .send()
+    };
+    let res = create(json!({
+        "de": "https://example.de/store",
+        "US": "https://example.com/us/store",
+        "default": "https://example.com/store",
+    }))
+    .await
+    .unwrap();
+    assert_eq!(res.status(), StatusCode::CREATED);
+    let body: Value = res.json().await.unwrap();
+    let id = body["url"].as_str().unwrap().rsplit('/').next().unwrap();
+
+    let visit = |from: &'static str| {
+        app.client
+            .get(format!("{}/{}", app.base, id))
+            .header("x-forwarded-for", from)
+            .send()
+    };
+    for (from, expected) in [
+        ("203.0.113.7", "https://example.de/store"),
+        ("198.51.100.20", "https://example.com/us/store"),
+        ("198.51.100.21", "https://example.com/us/store"),
+        // no range has it
+        ("192.0.2.1", "https://example.com/store"),
+    ] {
+        let res = visit(from).await.unwrap();
+        assert_eq!(location(&res), expected, "{}", from);
+        assert_eq!(res.headers()[reqwest::header::CACHE_CONTROL], "private");
+    }
+    let stats: Value = app
+        .client
+        .get(format!("{}/api/links/{}/stats", app.base, id))
+        .bearer_auth(ADMIN_KEY)
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(stats["clicks"], 4);
+    assert_eq!(
+        stats["geo_clicks"],
+        json!({ "DE": 1, "US": 2, "default": 1 })
+    );
+
+    // links without targets don't get a breakdown
+    let plain = app.shorten("https://example.com/store").await;
+    let res = app.get(&format!("/{}", plain)).await;
+    assert!(!res.headers().contains_key(reqwest::header::CACHE_CONTROL));
+    let stats: Value = app
+        .client
+        .get(format!("{}/api/links/{}/stats", app.base, plain))
+        .bearer_auth(ADMIN_KEY)
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert!(stats.get("geo_clicks").is_none());
+
+    let res = create(json!({
+        "DE": "https://example.de/",
+        "EU": "https://example.eu/",
+        "germany": "https://example.de/",
+        "default": "https://example.com/",
+    }))
+    .await
+    .unwrap();
+    assert_eq!(res.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    let body: Value = res.json().await.unwrap();
+    assert_eq!(body["error"], "invalid_geo_targets");
+    assert_eq!(body["invalid"], json!(["EU", "germany"]));
+    let res = create(json!({ "DE": "https://example.de/" }))
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    let body: Value = res.json().await.unwrap();
+    assert_eq!(body["error"], "invalid_geo_targets");
+    assert_eq!(body["invalid"], json!([]));
+    let res = app
+        .client
+        .post(&app.base)
+        .json(&json!({
+            "url": "https://example.com/store",
+            "geo_targets": { "default": "https://example.com/" },
+            "platform_targets": { "ios": "https://apps.apple.com/app/id1" },
+        }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    std::fs::remove_file(geoip).unwrap();
+}
+
+#[tokio::test]
+async fn geo_targets_default_without_a_lookup() {
+    let Some(app) = TestApp::spawn().await else {
+        return;
+    };
+    let res = app
+        .client
+        .post(&app.base)
+        .json(&json!({
+            "url": "https://example.com/store",
+            "geo_targets": {
+                "DE": "https://example.de/store",
+                "default": "https://example.com/intl/store",
+            },
+        }))
+        .send()
+        .await
+        .unwrap();
+    let body: Value = res.json().await.unwrap();
+    let id = body["url"].as_str().unwrap().rsplit('/').next().unwrap();
+    let res = app.get(&format!("/{}", id)).await;
+    assert_eq!(location(&res), "https://example.com/intl/store");
+}
+
+#[tokio::test]
+async fn warms_the_most_clicked_links() {
+    let Some(app) = TestApp::spawn().await else {
+        return;
+    };
+    let mut config = Config::from_env().unwrap();
+    config.db_url = app.db_url.clone();
+    let db = PgState::try_new(&config).await.unwrap();
+    let set = |id: String, clicks: i64| {
+        sqlx::query("UPDATE urls SET clicks = $2 WHERE id = $1")
+            .bind(id)
+            .bind(clicks)
+            .execute(&app.pool)
+    };
+    let popular = app.shorten("https://example.com/popular").await;
+    let expired = app.shorten("https://example.com/expired").await;
+    let quiet = app.shorten("https://example.com/quiet").await;
+    set(popular, 100).await.unwrap();
+    set(expired.clone(), 50).await.unwrap();
+    set(quiet, 1).await.unwrap();
+    sqlx::query("UPDATE urls SET expires_at = now() - interval '1 hour' WHERE id = $1")
+        .bind(expired)
+        .execute(&app.pool)
+        .await
+        .unwrap();
+    assert_eq!(db.warm_links(1).await.unwrap(), 1);
+    assert_eq!(db.warm_links(10).await.unwrap(), 2);
+}
+
+#[tokio::test]
+async fn aliases_cannot_shadow_suffixes() {
+    let Some(app) = TestApp::spawn().await else {
+        return;
+    };
+    for alias in ["qr", "stats", "info", "launch.json"] {
+        let res = app
+            .client
+            .post(&app.base)
+            .json(&json!({ "url": "https://example.com/", "alias": alias }))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::UNPROCESSABLE_ENTITY, "{}", alias);
+    }
+    let id = app.shorten("https://example.com/").await;
+    let res = app
+        .client
+        .post(format!("{}/api/links/{}/aliases", app.base, id))
+        .bearer_auth(ADMIN_KEY)
+        .json(&json!({ "alias": "qr" }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::UNPROCESSABLE_ENTITY);
+}
+
+#[tokio::test]
+async fn aliases_are_plain_ascii() {
+    let Some(app) = TestApp::spawn().await else {
+        return;
+    };
+    let shorten = |alias: &str| {
+        app.client
+            .post(&app.base)
+            .json(&json!({ "url": "https://example.com/menu", "alias": alias }))
+            .send()
+    };
+    // Unicode and its percent-encoding would be two slugs for one path
+    for alias in ["café", "caf%C3%A9", "caf%c3%a9"] {
+        let res = shorten(alias).await.unwrap();
+        assert_eq!(res.status(), StatusCode::UNPROCESSABLE_ENTITY, "{}", alias);
+        let body: Value = res.json().await.unwrap();
+        assert_eq!(body["error"], "invalid_alias");
+        assert_eq!(body["attempted"], alias);
+        assert!(body["message"].as_str().unwrap().contains("ASCII"));
+    }
+    let id = app.shorten("https://example.com/").await;
+    let res = app
+        .client
+        .post(format!("{}/api/links/{}/aliases", app.base, id))
+        .bearer_auth(ADMIN_KEY)
+        .json(&json!({ "alias": "menü" }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    assert_eq!(
+        app.get("/api/alias-available?alias=caf%25C3%25A9")
+            .await
+            .status(),
+        StatusCode::BAD_REQUEST
+    );
+    assert_eq!(app.get("/caf%C3%A9").await.status(), StatusCode::NOT_FOUND);
+
+    // a plain one round-trips
+    let res = shorten("cafe-menu").await.unwrap();
+    assert_eq!(res.status(), StatusCode::CREATED);
+    let body: Value = res.json().await.unwrap();
+    assert_eq!(body["url"], format!("{}/cafe-menu", app.base));
+    let res = app.get("/cafe-menu").await;
+    assert_eq!(res.status(), StatusCode::FOUND);
+    assert_eq!(location(&res), "https://example.com/menu");
+}
+
+#[tokio::test]
+async fn missing_slugs_skip_the_database() {
+    let mut store = None;
+    let Some(app) = TestApp::spawn_configured(
+        |config| config.slug_filter_refresh = Duration::from_secs(3600),
+        |_, db| {
+            store = Some(db.clone());
+            db
+        },
+    )
+    .await
+    else {
+        return;
+    };
+    let store = store.unwrap();
+    let id = app.shorten("https://example.com/").await;
+    assert_eq!(store.rebuild_slug_filter().await.unwrap(), 1);
+    let fresh = app.shorten("https://example.com/fresh").await;
+    assert_eq!(
+        location(&app.get(&format!("/{}", fresh)).await),
+        "https://example.com/fresh"
+    );
+
+    // with the database gone only lookups the filter lets through fail
+    app.pool.close().await;
+    assert_eq!(
+        app.get("/nevercreated").await.status(),
+        StatusCode::NOT_FOUND
+    );
+    assert_eq!(
+        app.get(&format!("/{}", id)).await.status(),
+        StatusCode::SERVICE_UNAVAILABLE
+    );
+}
+
+#[tokio::test]
+async fn warns_of_missing_indexes() {
+    let Some(app) = TestApp::spawn_configured(
+        |config| {
+            config.index_advisor = true;
+            config.index_advisor_min_rows = 1000;
+        },
+        |_, db| db,
+    )
+    .await
+    else {
+        return;
+    };
+    sqlx::query("DROP INDEX urls_owner")
+        .execute(&app.pool)
+        .await
+        .unwrap();
+    sqlx::query(
+        "INSERT INTO urls (id, url, owner)
+         SELECT 'seed' || i, 'https://example.com/' || i, (i % 100)::TEXT
+         FROM generate_series(1, 5000) i",
+    )
+    .execute(&app.pool)
+    .await
+    .unwrap();
+    sqlx::query("ANALYZE urls")
+        .execute(&app.pool)
+        .await
+        .unwrap();
+
+    let res = app
+        .client
+        .get(format!("{}/api/admin/db-health", app.base))
+        .bearer_auth(ADMIN_KEY)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    let health: Value = res.json().await.unwrap();
+    // the other tables are too small to matter
+    assert_eq!(
+        health["warnings"],
+        json!([{
+            "query": "owned_links",
+            "table": "urls",
+            "rows": 5000,
+            "index": "urls_owner",
+            "index_exists": false,
+        }])
+    );
+
+    let Some(app) = TestApp::spawn().await else {
+        return;
+    };
+    let res = app
+        .client
+        .get(format!("{}/api/admin/db-health", app.base))
+        .bearer_auth(ADMIN_KEY)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn extends_and_clears_expiry() {
+    let Some(app) = TestApp::spawn().await else {
+        return;
+    };
+    let alice = app.create_key("alice", &["write"]).await;
+    let bob = app.create_key("bob", &["write"]).await;
+    let create = |url: &str| {
+        app.client
+            .post(&app.base)
+            .bearer_auth(&alice)
+            .json(&json!({ "url": url, "expires_in_secs": 1 }))
+            .send()
+    };
+    let set_expiry = |key: &str, id: &str, body: Value| {
+        app.client
+            .patch(format!("{}/{}/expiry", app.base, id))
+            .bearer_auth(key)
+            .json(&body)
+            .send()
+    };
+    let mut ids = Vec::new();
+    for url in [
+        "https://example.com/renewed",
+        "https://example.com/permanent",
+    ] {
+        let body: Value = create(url).await.unwrap().json().await.unwrap();
+        let short = body["url"].as_str().unwrap();
+        ids.push(short.rsplit('/').next().unwrap().to_string());
+    }
+    let (renewed, permanent) = (&ids[0], &ids[1]);
+
+    // only the owner and admins may, and leaving the field out is an error
+    let res = set_expiry(&bob, renewed, json!({ "expires_in_secs": 3600 }))
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::FORBIDDEN);
+    let res = set_expiry(&alice, renewed, json!({})).await.unwrap();
+    assert_eq!(res.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    let res = set_expiry(&alice, "missing", json!({ "expires_in_secs": null }))
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+    let res = set_expiry(&alice, renewed, json!({ "expires_in_secs": 3600 }))
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    let body: Value = res.json().await.unwrap();
+    let expires_at: chrono::DateTime<chrono::Utc> =
+        body["expires_at"].as_str().unwrap().parse().unwrap();
+    assert!(expires_at > chrono::Utc::now() + chrono::Duration::minutes(59));
+    let res = set_expiry(ADMIN_KEY, permanent, json!({ "expires_in_secs": null }))
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    let body: Value = res.json().await.unwrap();
+    assert_eq!(body["expires_at"], Value::Null);
+
+    // past the original expiry both still resolve
+    tokio::time::sleep(Duration::from_millis(1500)).await;
+    for (id, url) in [
+        (renewed, "https://example.com/renewed"),
+        (permanent, "https://example.com/permanent"),
+    ] {
+        assert_eq!(location(&app.get(&format!("/{}", id)).await), url);
+    }
+}
+
+#[tokio::test]
+async fn expiry_follows_the_servers_clock() {
+    let Some(app) = TestApp::spawn().await else {
+        return;
+    };
+    // a client whose clock is a day behind
+    let skewed = (chrono::Utc::now() - chrono::Duration::days(1)).to_rfc2822();
+    let before = chrono::Utc::now();
+    let res = app
+        .client
+        .post(&app.base)
+        .header(reqwest::header::DATE, &skewed)
+        .json(&json!({ "url": "https://example.com/skewed", "expires_in_secs": 600 }))
+        .send()
+        .await
+        .unwrap();
+    let after = chrono::Utc::now();
+    assert_eq!(res.status(), StatusCode::CREATED);
+    let body: Value = res.json().await.unwrap();
+    let expires_at: chrono::DateTime<chrono::Utc> =
+        body["expires_at"].as_str().unwrap().parse().unwrap();
+    let ttl = chrono::Duration::seconds(600);
+    assert!(before + ttl - chrono::Duration::seconds(1) <= expires_at);
+    assert!(expires_at <= after + ttl);
+
+    // nor can it say when the link expires
+    let res = app
+        .client
+        .post(&app.base)
+        .json(&json!({
+            "url": "https://example.com/skewed",
+            "expires_at": (chrono::Utc::now() - chrono::Duration::days(1)).to_rfc3339(),
+        }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::UNPROCESSABLE_ENTITY);
+}
+
+#[tokio::test]
+async fn expiry_has_a_grace_for_skew() {
+    let Some(app) = TestApp::spawn_configured(
+        |config| config.expiry_skew_grace = Duration::from_secs(30),
+        |_, db| db,
+    )
+    .await
+    else {
+        return;
+    };
+    let expire = |id: String, ago: i32| {
+        sqlx::query("UPDATE urls SET expires_at = now() - make_interval(secs => $2) WHERE id = $1")
+            .bind(id)
+            .bind(ago)
+            .execute(&app.pool)
+    };
+    let lately = app.shorten("https://example.com/lately").await;
+    let long_ago = app.shorten("https://example.com/long-ago").await;
+    expire(lately.clone(), 10).await.unwrap();
+    expire(long_ago.clone(), 60).await.unwrap();
+    let res = app.get(&format!("/{}", lately)).await;
+    assert_eq!(location(&res), "https://example.com/lately");
+    let res = app.get(&format!("/{}", long_ago)).await;
+    assert_eq!(res.status(), StatusCode::GONE);
+
+    // the sweep leaves links within the grace too
+    let res = app
+        .client
+        .post(format!("{}/api/admin/sweep-expired", app.base))
+        .bearer_auth(ADMIN_KEY)
+        .send()
+        .await
+        .unwrap();
+    let body: Value = res.json().await.unwrap();
+    assert_eq!(body["sample"], json!([long_ago]));
+}
+
+#[tokio::test]
+async fn imports_report_failed_rows() {
+    let Some(app) = TestApp::spawn().await else {
+        return;
+    };
+    let taken = app.shorten("https://example.com/taken").await;
+    let import = |rows: Value| {
+        app.client
+            .post(format!("{}/api/imports", app.base))
+            .bearer_auth(ADMIN_KEY)
+            .json(&json!({ "rows": rows }))
+            .send()
+    };
+    let res = import(json!([
+        { "id": "legacy1", "url": "https://example.com/one" },
+        { "id": "not valid", "url": "https://example.com/two" },
+        { "id": "legacy3", "url": "not a url" },
+        { "id": taken, "url": "https://example.com/elsewhere" },
+        { "id": "legacy1", "url": "https://example.com/one" },
+    ]))
+    .await
+    .unwrap();
+    assert_eq!(res.status(), StatusCode::CREATED);
+    let body: Value = res.json().await.unwrap();
+    assert_eq!(body["state"], "completed");
+    assert_eq!(body["total_rows"], 5);
+    assert_eq!(body["created_rows"], 1);
+    assert_eq!(body["unchanged_rows"], 1);
+    assert_eq!(body["failed_rows"], 3);
+    assert_eq!(
+        location(&app.get("/legacy1").await),
+        "https://example.com/one"
+    );
+
+    let errors = format!("{}/api/imports/{}/errors", app.base, body["id"]);
+    let res = app
+        .client
+        .get(&errors)
+        .bearer_auth(ADMIN_KEY)
+        .send()
+        .await
+        .unwrap();
+    let errors_json: Value = res.json().await.unwrap();
+    let codes: Vec<_> = errors_json
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|e| (e["line"].as_i64().unwrap(), e["code"].as_str().unwrap()))
+        .collect();
+    assert_eq!(
+        codes,
+        [(2, "invalid_id"), (3, "invalid_url"), (4, "id_taken")]
+    );
+    let res = app
+        .client
+        .get(format!("{}?format=csv", errors))
+        .bearer_auth(ADMIN_KEY)
+        .send()
+        .await
+        .unwrap();
+    let csv = res.text().await.unwrap();
+    let mut lines = csv.lines();
+    assert_eq!(lines.next(), Some("line,id,url,code,message"));
+    assert!(lines
+        .next()
+        .unwrap()
+        .starts_with("2,not valid,https://example.com/two,invalid_id,"));
+    assert_eq!(lines.count(), 2);
+}
+
+#[tokio::test]
+async fn oversized_imports_say_how_many_fit() {
+    let Some(app) =
+        TestApp::spawn_configured(|config| config.import_max_rows = 2, |_, db| db).await
+    else {
+        return;
+    };
+    let rows: Vec<Value> = (0..3)
+        .map(|i| json!({ "id": format!("big{}", i), "url": "https://example.com/" }))
+        .collect();
+    let res = app
+        .client
+        .post(format!("{}/api/imports", app.base))
+        .bearer_auth(ADMIN_KEY)
+        .json(&json!({ "rows": rows }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    let body: Value = res.json().await.unwrap();
+    assert_eq!(body["error"], "too_many_items");
+    assert_eq!(body["limit"], 2);
+    assert_eq!(body["received"], 3);
+    let imports: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM imports")
+        .fetch_one(&app.pool)
+        .await
+        .unwrap();
+    assert_eq!(imports, 0);
+}
+
+#[tokio::test]
+async fn large_imports_run_in_the_background() {
+    let Some(app) =
+        TestApp::spawn_configured(|config| config.import_sync_rows = 1, |_, db| db).await
+    else {
+        return;
+    };
+    let res = app
+        .client
+        .post(format!("{}/api/imports", app.base))
+        .bearer_auth(ADMIN_KEY)
+        .json(&json!({ "rows": [
+            { "id": "bg1", "url": "https://example.com/1" },
+            { "id": "bg2", "url": "https://example.com/2" },
+        ] }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::ACCEPTED);
+    let body: Value = res.json().await.unwrap();
+    assert_eq!(body["state"], "running");
+    assert_eq!(body["total_rows"], 2);
+
+    app.state.spawn_workers();
+    let import = format!("{}/api/imports/{}", app.base, body["id"]);
+    let mut state = String::new();
+    for _ in 0..50 {
+        let res = app
+            .client
+            .get(&import)
+            .bearer_auth(ADMIN_KEY)
+            .send()
+            .await
+            .unwrap();
+        let body: Value = res.json().await.unwrap();
+        state = body["state"].as_str().unwrap().to_string();
+        if state != "running" {
+            assert_eq!(body["created_rows"], 2);
+            assert!(body["finished_at"].is_string());
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    assert_eq!(state, "completed");
+
+    let res = app
+        .client
+        .get(format!("{}/api/imports", app.base))
+        .bearer_auth(ADMIN_KEY)
+        .send()
+        .await
+        .unwrap();
+    let imports: Value = res.json().await.unwrap();
+    assert_eq!(imports.as_array().unwrap().len(), 1);
+    assert_eq!(location(&app.get("/bg2").await), "https://example.com/2");
+}
+
+#[tokio::test]
+async fn refuses_internal_destinations_when_probing() {
+    let Some(app) = TestApp::spawn_with(|config, db| {
+        config.shorten_coalesce_window = Duration::ZERO;
+        db
+    })
+    .await
+    else {
+        return;
+    };
+    let shorten = |url: &str, upgrade: bool| {
+        app.client
+            .post(&app.base)
+            .json(&json!({ "url": url, "upgrade_insecure": upgrade }))
+            .send()
+    };
+    for url in [
+        "http://169.254.169.254/latest/meta-data/",
+        "http://10.0.0.1/admin",
+        "http://localhost/",
+    ] {
+        let res = shorten(url, true).await.unwrap();
+        assert_eq!(res.status(), StatusCode::UNPROCESSABLE_ENTITY, "{}", url);
+        let body: Value = res.json().await.unwrap();
+        assert_eq!(body["error"], "blocked_domain");
+        // nothing is fetched without the upgrade, so nothing is refused
+        let res = shorten(url, false).await.unwrap();
+        assert_eq!(res.status(), StatusCode::CREATED, "{}", url);
+    }
+}
+
+#[tokio::test]
+async fn browsers_get_pages_for_unavailable_links() {
+    let Some(app) = TestApp::spawn().await else {
+        return;
+    };
+    let expired = app.shorten("https://example.com/old-sale").await;
+    sqlx::query("UPDATE urls SET expires_at = now() - interval '1 hour' WHERE id = $1")
+        .bind(&expired)
+        .execute(&app.pool)
+        .await
+        .unwrap();
+    let disabled = app.shorten("https://example.com/pulled").await;
+    sqlx::query("UPDATE urls SET enabled = false WHERE id = $1")
+        .bind(&disabled)
+        .execute(&app.pool)
+        .await
+        .unwrap();
+    let browse = |path: String, language: &'static str| {
+        app.client
+            .get(format!("{}{}", app.base, path))
+            .header(
+                "accept",
+                "text/html,application/xhtml+xml,application/xml;q=0.9,*/*;q=0.8",
+            )
+            .header("accept-language", language)
+            .send()
+    };
+
+    for (path, language, status, expected_language, text) in [
+        (
+            format!("/{}", expired),
+            "zh-CN,zh;q=0.9",
+            StatusCode::GONE,
+            "zh",
+            "链接已过期",
+        ),
+        (
+            format!("/{}", disabled),
+            "es-ES,es;q=0.9,en;q=0.8",
+            StatusCode::NOT_FOUND,
+            "es",
+            "Enlace no disponible",
+        ),
+        (
+            "/nope404".to_string(),
+            "fr-FR",
+            StatusCode::NOT_FOUND,
+            "en",
+            "Link not found",
+        ),
+    ] {
+        let res = browse(path.clone(), language).await.unwrap();
+        assert_eq!(res.status(), status, "{}", path);
+        assert_eq!(res.headers()["content-type"], "text/html; charset=utf-8");
+        assert_eq!(res.headers()["content-language"], expected_language);
+        assert_eq!(res.headers()["vary"], "accept, accept-language");
+        let body = res.text().await.unwrap();
+        assert!(body.contains(text), "{}: {}", path, body);
+    }
+
+    // API clients and .json lookups keep the structured errors
+    let res = app
+        .client
+        .get(format!("{}/{}", app.base, expired))
+        .header("accept", "application/json")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::GONE);
+    assert!(res.headers()["content-type"]
+        .to_str()
+        .unwrap()
+        .starts_with("application/json"));
+    let res = browse(format!("/{}.json", expired), "en").await.unwrap();
+    assert_eq!(res.status(), StatusCode::GONE);
+    assert!(res.headers()["content-type"]
+        .to_str()
+        .unwrap()
+        .starts_with("application/json"));
+}
+
+#[tokio::test]
+async fn misses_render_the_configured_template() {
+    let template = std::env::temp_dir().join(format!("miss-{}.html", std::process::id()));
+    std::fs::write(
+        &template,
+        "<h1>No {{id}} here ({{status}})</h1><a href=\"{{homepage}}\">Home</a>",
+    )
+    .unwrap();
+    let path = template.clone();
+    let Some(app) = TestApp::spawn_configured(
+        move |config| {
+            config.miss_template_path = Some(path);
+            config.homepage_url = Some("https://example.com/".into());
+        },
+        |_, db| db,
+    )
+    .await
+    else {
+        return;
+    };
+    std::fs::remove_file(&template).unwrap();
+    let expired = app.shorten("https://example.com/old-sale").await;
+    sqlx::query("UPDATE urls SET expires_at = now() - interval '1 hour' WHERE id = $1")
+        .bind(&expired)
+        .execute(&app.pool)
+        .await
+        .unwrap();
+    let browse = |id: &str| {
+        app.client
+            .get(format!("{}/{}", app.base, id))
+            .header("accept", "text/html")
+            .send()
+    };
+
+    let res = browse("nope404").await.unwrap();
+    assert_eq!(res.status(), StatusCode::NOT_FOUND);
+    assert_eq!(res.headers()["content-type"], "text/html; charset=utf-8");
+    assert_eq!(
+        res.text().await.unwrap(),
+        "<h1>No nope404 here (404)</h1><a href=\"https://example.com/\">Home</a>"
+    );
+    let res = browse(&expired).await.unwrap();
+    assert_eq!(res.status(), StatusCode::GONE);
+    let body = res.text().await.unwrap();
+    assert!(
+        body.contains(&format!("No {} here (410)", expired)),
+        "{}",
+        body
+    );
+
+    // API clients keep the structured errors
+    let res = app.get("/nope404").await;
+    assert_eq!(res.status(), StatusCode::NOT_FOUND);
+    let body: Value = res.json().await.unwrap();
+    assert_eq!(body["error"], "not_found");
+}
+
+#[tokio::test]
+async fn seeded_apps_issue_the_same_ids() {
+    let mut issued = Vec::new();
+    for _ in 0..2 {
+        let Some(app) =
+            TestApp::spawn_configured(|config| config.id_seed = Some(42), |_, db| db).await
+        else {
+            return;
+        };
+        let mut ids = Vec::new();
+        for n in 0..3 {
+            ids.push(
+                app.shorten(&format!("https://example.com/seeded/{}", n))
+                    .await,
+            );
+        }
+        issued.push(ids);
+    }
+    assert_eq!(issued[0], issued[1]);
+}
+
+#[tokio::test]
+async fn generates_ids_from_the_configured_alphabet() {
+    let Some(app) = TestApp::spawn_configured(
+        |config| {
+            config.id_alphabet = "0123456789abcdef".into();
+            config.id_length = 9;
+        },
+        |_, db| db,
+    )
+    .await
+    else {
+        return;
+    };
+    for n in 0..5 {
+        let id = app
+            .shorten(&format!("https://example.com/digits/{}", n))
+            .await;
+        assert_eq!(id.len(), 9, "{}", id);
+        assert!(id.bytes().all(|b| b.is_ascii_hexdigit()), "{}", id);
+        assert!(!id.bytes().any(|b| b.is_ascii_uppercase()), "{}", id);
+        assert_eq!(
+            app.get(&format!("/{}", id)).await.status(),
+            StatusCode::FOUND
+        );
+    }
+}
+
+#[tokio::test]
+async fn nests_inside_another_app() {
+    let Some((db_url, _container)) = database().await else {
+        return;
+    };
+    let mut config = Config::from_env().expect("invalid config in environment");
+    config.db_url = db_url;
+    config.canonical_host = Some("sho.rt".into());
+    config.public_url = Some("http://sho.rt/s".into());
+    let store = PgState::try_new(&config).await.expect("failed to migrate");
+    let app = axum::Router::new()
+        .route("/", axum::routing::get(|| async { "host" }))
+        .nest("/s", shortener::router(store, config).unwrap());
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let base = format!("http://{}", listener.local_addr().unwrap());
+    tokio::spawn(
+        axum::serve(
+            listener,
+            app.into_make_service_with_connect_info::<SocketAddr>(),
+        )
+        .into_future(),
+    );
+    let client = Client::builder().redirect(Policy::none()).build().unwrap();
+
+    let res = client
+        .post(format!("{}/s", base))
+        .header("host", "sho.rt")
+        .json(&json!({ "url": "https://example.com/nested" }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::CREATED);
+    let body: Value = res.json().await.unwrap();
+    let short = body["url"].as_str().unwrap();
+    let id = short.strip_prefix("http://sho.rt/s/").unwrap();
+    // shortening a short link collapses it, under the prefix too
+    let res = client
+        .post(format!("{}/s", base))
+        .header("host", "sho.rt")
+        .json(&json!({ "url": short }))
+        .send()
+        .await
+        .unwrap();
+    let body: Value = res.json().await.unwrap();
+    assert_eq!(body["url"], short);
+    let res = client
+        .get(format!("{}/s/{}", base, id))
+        .header("host", "sho.rt")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::FOUND);
+    assert_eq!(res.headers()[LOCATION], "https://example.com/nested");
+    // the host's routes stay its own
+    let res = client.get(&base).send().await.unwrap();
+    assert_eq!(res.text().await.unwrap(), "host");
+    // redirects to the canonical host keep the prefix
+    let res = client
+        .get(format!("{}/s/{}", base, id))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::MOVED_PERMANENTLY);
+    assert_eq!(
+        res.headers()[LOCATION],
+        format!("http://sho.rt/s/{}", id).as_str()
+    );
+}
+
+#[tokio::test]
+async fn buckets_clicks_over_time() {
+    let Some(app) = TestApp::spawn().await else {
+        return;
+    };
+    let id = app.shorten("https://example.com/trend").await;
+    let today = chrono::Utc::now().date_naive();
+    let yesterday = today.pred_opt().unwrap();
+    let long_ago = today - chrono::Days::new(90);
+    for (day, clicks) in [(yesterday, 3), (long_ago, 7)] {
+        sqlx::query("INSERT INTO link_uniques (link_id, day, clicks) VALUES ($1, $2, $3)")
+            .bind(&id)
+            .bind(day)
+            .bind(clicks as i64)
+            .execute(&app.pool)
+            .await
+            .unwrap();
+    }
+    // one flushed and one still in memory today
+    app.get(&format!("/{}", id)).await;
+    app.state.shutdown().await.unwrap();
+    app.get(&format!("/{}", id)).await;
+    let timeseries = |query: &'static str| {
+        app.client
+            .get(format!("{}/api/links/{}/timeseries{}", app.base, id, query))
+            .bearer_auth(ADMIN_KEY)
+            .send()
+    };
+
+    let res = timeseries("?interval=day&days=30").await.unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    let body: Value = res.json().await.unwrap();
+    assert_eq!(
+        body,
+        json!([
+            { "bucket": yesterday.to_string(), "count": 3 },
+            { "bucket": today.to_string(), "count": 2 },
+        ])
+    );
+    let body: Value = timeseries("?days=1").await.unwrap().json().await.unwrap();
+    assert_eq!(body, json!([{ "bucket": today.to_string(), "count": 2 }]));
+    let body: Value = timeseries("?interval=month&days=365")
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    let total: i64 = body
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|b| b["count"].as_i64().unwrap())
+        .sum();
+    assert_eq!(total, 12);
+    assert!(body
+        .as_array()
+        .unwrap()
+        .iter()
+        .all(|b| b["bucket"].as_str().unwrap().ends_with("-01")));
+
+    for query in [
+        "?interval=hour;drop",
+        "?interval=minute",
+        "?days=0",
+        "?days=99999",
+    ] {
+        let res = timeseries(query).await.unwrap();
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST, "{}", query);
+    }
+    let res = app
+        .client
+        .get(format!("{}/api/links/nope404/timeseries", app.base))
+        .bearer_auth(ADMIN_KEY)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn snapshots_metrics_once_per_bucket() {
+    let store = Arc::new(Mutex::new(None));
+    let Some(app) = TestApp::spawn_configured(
+        |_| {},
+        |_, db| {
+            *store.lock().unwrap() = Some(db.clone());
+            db
+        },
+    )
+    .await
+    else {
+        return;
+    };
+    let db = store.lock().unwrap().take().unwrap();
+    let hour = Duration::from_secs(3600);
+    let a = app.shorten("https://example.com/snap-a").await;
+    app.shorten("https://example.com/snap-b").await;
+    // one link from the hour that ended last, with 5 clicks
+    sqlx::query(
+        "UPDATE urls SET clicks = 5,
+         created_at = date_trunc('hour', now()) - interval '30 minutes' WHERE id = $1",
+    )
+    .bind(&a)
+    .execute(&app.pool)
+    .await
+    .unwrap();
+    sqlx::query(
+        "INSERT INTO metrics_snapshots (bucket, total_links, total_clicks, clicks, new_links)
+         VALUES (date_trunc('hour', now()) - interval '1 hour', 0, 2, NULL, 0)",
+    )
+    .execute(&app.pool)
+    .await
+    .unwrap();
+
+    let snapshot = db.snapshot_metrics(hour).await.unwrap().unwrap();
+    assert_eq!(snapshot.total_links, 2);
+    assert_eq!(snapshot.total_clicks, 5);
+    assert_eq!(snapshot.clicks, Some(3));
+    assert_eq!(snapshot.new_links, 1);
+    // taking it again replaces it
+    db.snapshot_metrics(hour).await.unwrap().unwrap();
+    let (rows,): (i64,) = sqlx::query_as("SELECT COUNT(*) FROM metrics_snapshots")
+        .fetch_one(&app.pool)
+        .await
+        .unwrap();
+    assert_eq!(rows, 2);
+
+    // another instance holding the lock takes it instead
+    let mut other = app.pool.acquire().await.unwrap();
+    sqlx::query("SELECT pg_advisory_lock($1)")
+        .bind(0x736e_6170_7368_6f74_i64)
+        .execute(&mut *other)
+        .await
+        .unwrap();
+    assert!(db.snapshot_metrics(hour).await.unwrap().is_none());
+    sqlx::query("SELECT pg_advisory_unlock_all()")
+        .execute(&mut *other)
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn serves_metrics_history() {
+    let Some(app) = TestApp::spawn().await else {
+        return;
+    };
+    for (bucket, total_links, total_clicks, clicks, new_links) in [
+        ("2026-03-01T22:00:00Z", 10, 100, None::<i64>, 1),
+        ("2026-03-01T23:00:00Z", 12, 130, Some(30), 2),
+        ("2026-03-02T00:00:00Z", 15, 170, Some(40), 3),
+        ("2026-03-05T00:00:00Z", 20, 200, Some(1), 1),
+    ] {
+        sqlx::query(
+            "INSERT INTO metrics_snapshots
+             (bucket, total_links, total_clicks, clicks, new_links)
+             VALUES ($1::TIMESTAMPTZ, $2, $3, $4, $5)",
+        )
+        .bind(bucket)
+        .bind(total_links as i64)
+        .bind(total_clicks as i64)
+        .bind(clicks)
+        .bind(new_links as i64)
+        .execute(&app.pool)
+        .await
+        .unwrap();
+    }
+    let history = |query: &'static str| {
+        app.client
+            .get(format!("{}/api/stats/history{}", app.base, query))
+            .bearer_auth(ADMIN_KEY)
+            .send()
+    };
+
+    let res = history("?from=2026-03-01T00:00:00Z&to=2026-03-03T00:00:00Z&granularity=day")
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    let body: Value = res.json().await.unwrap();
+    assert_eq!(
+        body,
+        json!([
+            {
+                "bucket": "2026-03-01T00:00:00Z",
+                "total_links": 12,
+                "total_clicks": 130,
+                "clicks": 30,
+                "new_links": 3,
+            },
+            {
+                "bucket": "2026-03-02T00:00:00Z",
+                "total_links": 15,
+                "total_clicks": 170,
+                "clicks": 40,
+                "new_links": 3,
+            },
+        ])
+    );
+    let body: Value = history("?from=2026-03-01T00:00:00Z&to=2026-03-02T00:00:00Z")
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(body.as_array().unwrap().len(), 2);
+    assert_eq!(body[0]["clicks"], Value::Null);
+
+    for query in [
+        "?from=2026-03-02T00:00:00Z&to=2026-03-01T00:00:00Z",
+        "?granularity=minute",
+    ] {
+        assert_eq!(
+            history(query).await.unwrap().status(),
+            StatusCode::BAD_REQUEST,
+            "{}",
+            query
+        );
+    }
+    let res = app
+        .client
+        .get(format!("{}/api/stats/history", app.base))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn abandoned_lookups_give_their_connection_back() {
+    let Some(app) = TestApp::spawn_configured(
+        |config| {
+            config.db_max_connections = 1;
+            config.request_timeout = Duration::from_secs(30);
+        },
+        |_, db| db,
+    )
+    .await
+    else {
+        return;
+    };
+    let id = app.shorten("https://example.com/abandoned").await;
+    let aborted = || {
+        let rendered = metrics().render();
+        rendered
+            .lines()
+            .find(|l| l.starts_with("queries_aborted_total{reason=\"disconnect\"}"))
+            .and_then(|l| l.rsplit(' ').next()?.parse::<u64>().ok())
+            .unwrap_or(0)
+    };
+    let before = aborted();
+    // a lock held elsewhere keeps the lookup waiting
+    let mut locker = <sqlx::PgConnection as sqlx::Connection>::connect(&app.db_url)
+        .await
+        .unwrap();
+    sqlx::query("BEGIN").execute(&mut locker).await.unwrap();
+    sqlx::query("LOCK TABLE slugs IN ACCESS EXCLUSIVE MODE")
+        .execute(&mut locker)
+        .await
+        .unwrap();
+
+    let res = Client::new()
+        .get(format!("{}/{}", app.base, id))
+        .timeout(Duration::from_millis(300))
+        .send()
+        .await;
+    assert!(res.unwrap_err().is_timeout());
+    // the only connection is free again while the lock is still held
+    let conn = tokio::time::timeout(Duration::from_secs(2), app.pool.acquire())
+        .await
+        .expect("connection stuck behind the abandoned query")
+        .unwrap();
+    drop(conn);
+    assert!(aborted() > before);
+    // and the server stops waiting on the lock itself
+    let mut waiting = 1_i64;
+    for _ in 0..50 {
+        (waiting,) = sqlx::query_as(
+            "SELECT COUNT(*) FROM pg_stat_activity
+             WHERE wait_event_type = 'Lock' AND datname = current_database()",
+        )
+        .fetch_one(&mut locker)
+        .await
+        .unwrap();
+        if waiting == 0 {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    assert_eq!(waiting, 0);
+    sqlx::query("ROLLBACK").execute(&mut locker).await.unwrap();
+    assert_eq!(
+        app.get(&format!("/{}", id)).await.status(),
+        StatusCode::FOUND
+    );
+}
+
+#[tokio::test]
+async fn lookups_stop_at_the_request_budget() {
+    let Some(app) = TestApp::spawn_configured(
+        |config| config.request_timeout = Duration::from_millis(500),
+        |_, db| db,
+    )
+    .await
+    else {
+        return;
+    };
+    let id = app.shorten("https://example.com/over-budget").await;
+    let mut locker = <sqlx::PgConnection as sqlx::Connection>::connect(&app.db_url)
+        .await
+        .unwrap();
+    sqlx::query("BEGIN").execute(&mut locker).await.unwrap();
+    sqlx::query("LOCK TABLE slugs IN ACCESS EXCLUSIVE MODE")
+        .execute(&mut locker)
+        .await
+        .unwrap();
+    let res = app.get(&format!("/{}", id)).await;
+    assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+    let body: Value = res.json().await.unwrap();
+    assert_eq!(body["message"], "request timed out");
+    sqlx::query("ROLLBACK").execute(&mut locker).await.unwrap();
+}
+
+#[tokio::test]
+async fn stores_long_urls_compressed() {
+    let Some(app) =
+        TestApp::spawn_configured(|config| config.compress_urls_over = 1024, |_, db| db).await
+    else {
+        return;
+    };
+    let signature: String = (0..1500).map(|n| format!("{:02x}", n % 256)).collect();
+    let url = format!(
+        "https://bucket.s3.amazonaws.com/report.pdf?X-Amz-Expires=3600&X-Amz-Signature={}",
+        signature
+    );
+    assert!(url.len() > 3000);
+    let id = app.shorten(&url).await;
+    let (stored, compressed, deflated): (String, bool, i32) = sqlx::query_as(
+        "SELECT url, url_compressed, octet_length(url_deflated) FROM urls WHERE id = $1",
+    )
+    .bind(&id)
+    .fetch_one(&app.pool)
+    .await
+    .unwrap();
+    assert!(compressed);
+    assert!(stored.len() < 100, "{}", stored);
+    assert!((deflated as usize) < url.len(), "{}", deflated);
+
+    let res = app.get(&format!("/{}", id)).await;
+    assert_eq!(res.status(), StatusCode::FOUND);
+    assert_eq!(location(&res), url);
+    // deduped like any other url
+    assert_eq!(app.shorten(&url).await, id);
+    let res = app
+        .client
+        .get(format!("{}/api/links", app.base))
+        .query(&[("url", &url)])
+        .bearer_auth(ADMIN_KEY)
+        .send()
+        .await
+        .unwrap();
+    let links: Value = res.json().await.unwrap();
+    assert_eq!(links[0]["id"], id);
+    assert_eq!(links[0]["url"], url);
+
+    // short ones are stored as they are
+    let id = app.shorten("https://example.com/short").await;
+    let (stored, compressed): (String, bool) =
+        sqlx::query_as("SELECT url, url_compressed FROM urls WHERE id = $1")
+            .bind(&id)
+            .fetch_one(&app.pool)
+            .await
+            .unwrap();
+    assert!(!compressed);
+    assert_eq!(stored, "https://example.com/short");
+}
+
+#[tokio::test]
+async fn webhooks_announce_new_links_and_milestones() {
+    use axum::{body::Bytes, http::HeaderMap};
+    use hmac::{Hmac, Mac};
+    use tokio::sync::mpsc::UnboundedReceiver;
+
+    /// The next delivery's payload, once its signature checks out.
+    async fn next(rx: &mut UnboundedReceiver<(HeaderMap, Bytes)>) -> Value {
+        let (headers, body) = tokio::time::timeout(Duration::from_secs(10), rx.recv())
+            .await
+            .expect("no webhook delivered")
+            .unwrap();
+        let signature = headers["x-shortener-webhook-signature"].to_str().unwrap();
+        let (t, v1) = signature.split_once(",v1=").unwrap();
+        let mut mac = Hmac::<sha2::Sha256>::new_from_slice(b"hook-secret").unwrap();
+        mac.update(format!("{}.", t.strip_prefix("t=").unwrap()).as_bytes());
+        mac.update(&body);
+        let expected: String = mac
+            .finalize()
+            .into_bytes()
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+        assert_eq!(v1, expected);
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    // a receiver handing over what it got
+    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+    let receiver = axum::Router::new().route(
+        "/hook",
+        axum::routing::post(move |headers: HeaderMap, body: Bytes| async move {
+            tx.send((headers, body)).unwrap();
+        }),
+    );
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let hook = format!("http://{}/hook", listener.local_addr().unwrap());
+    tokio::spawn(axum::serve(listener, receiver).into_future());
+    let Some(app) = TestApp::spawn_configured(
+        |config| {
+            config.webhook_url = Some(hook);
+            config.webhook_secret = Some("hook-secret".into());
+            config.click_flush_interval = Duration::from_millis(100);
+        },
+        |_, db| db,
+    )
+    .await
+    else {
+        return;
+    };
+    app.state.spawn_workers();
+    let id = app.shorten("https://example.com/launch").await;
+    let event = next(&mut rx).await;
+    assert_eq!(event["event"], "link_created");
+    assert_eq!(event["id"], id.as_str());
+    assert_eq!(event["url"], "https://example.com/launch");
+    assert!(event["short_url"].as_str().unwrap().ends_with(&id));
+    assert!(event["created_at"].is_string());
+    // handing back the link creates nothing
+    assert_eq!(app.shorten("https://example.com/launch").await, id);
+
+    sqlx::query("UPDATE urls SET clicks = 98 WHERE id = $1")
+        .bind(&id)
+        .execute(&app.pool)
+        .await
+        .unwrap();
+    for _ in 0..3 {
+        app.get(&format!("/{}", id)).await;
+    }
+    let event = next(&mut rx).await;
+    assert_eq!(event["event"], "link_click_milestone");
+    assert_eq!(event["id"], id.as_str());
+    assert_eq!(event["milestone"], 100);
+    assert_eq!(event["clicks"], 101);
+    let created: i64 = sqlx::query_scalar(
+        "SELECT COUNT(*) FROM jobs WHERE kind = 'webhook' AND payload->>'event' = 'link_created'",
+    )
+    .fetch_one(&app.pool)
+    .await
+    .unwrap();
+    assert_eq!(created, 1);
+}
+
+#[tokio::test]
+async fn batch_deletes_listed_links() {
+    let Some(app) = TestApp::spawn().await else {
+        return;
+    };
+    let mut ids = Vec::new();
+    for n in 0..5 {
+        ids.push(
+            app.shorten(&format!("https://example.com/batch/{}", n))
+                .await,
+        );
+    }
+    let batch_delete = |body: Value, key: &str| {
+        app.client
+            .post(format!("{}/api/links/batch-delete", app.base))
+            .bearer_auth(key)
+            .json(&body)
+            .send()
+    };
+    let writer = app.create_key("writer", &["write"]).await;
+    let res = batch_delete(json!([ids[0]]), &writer).await.unwrap();
+    assert_eq!(res.status(), StatusCode::FORBIDDEN);
+
+    // ids of no link are skipped
+    let res = batch_delete(json!([ids[0], ids[2], ids[4], "nosuchlink"]), ADMIN_KEY)
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    let body: Value = res.json().await.unwrap();
+    assert_eq!(body["count"], 3);
+    for (n, id) in ids.iter().enumerate() {
+        let expected = match n % 2 {
+            0 => StatusCode::NOT_FOUND,
+            _ => StatusCode::FOUND,
+        };
+        assert_eq!(
+            app.get(&format!("/{}", id)).await.status(),
+            expected,
+            "{}",
+            n
+        );
+    }
+    let (action, details): (String, Value) =
+        sqlx::query_as("SELECT action, details FROM audit_log")
+            .fetch_one(&app.pool)
+            .await
+            .unwrap();
+    assert_eq!(action, "batch_delete");
+    assert_eq!(details["count"], 3);
+
+    let res = batch_delete(json!(vec!["abc"; 501]), ADMIN_KEY)
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::PAYLOAD_TOO_LARGE);
+}
+
+#[tokio::test]
+async fn bulk_operations_on_matching_links() {
+    let Some(app) = TestApp::spawn().await else {
+        return;
+    };
+    let spammer = app.create_key("spammer", &["write"]).await;
+    let mut spam = Vec::new();
+    for n in 0..3 {
+        let res = app
+            .client
+            .post(&app.base)
+            .bearer_auth(&spammer)
+            .json(&json!({ "url": format!("https://spam.example/{}", n) }))
+            .send()
+            .await
+            .unwrap();
+        let body: Value = res.json().await.unwrap();
+        spam.push(
+            body["url"]
+                .as_str()
+                .unwrap()
+                .rsplit('/')
+                .next()
+                .unwrap()
+                .to_string(),
+        );
+    }
+    let keep = app.shorten("https://example.com/keep").await;
+    let owner: String = sqlx::query_scalar("SELECT owner FROM urls WHERE id = $1")
+        .bind(&spam[0])
+        .fetch_one(&app.pool)
+        .await
+        .unwrap();
+    let bulk = |body: Value, query: &'static str| {
+        app.client
+            .post(format!("{}/api/links/bulk{}", app.base, query))
+            .bearer_auth(ADMIN_KEY)
+            .json(&body)
+            .send()
+    };
+    let enabled = || async {
+        let ids: Vec<String> = sqlx::query_scalar("SELECT id FROM urls WHERE enabled ORDER BY id")
+            .fetch_all(&app.pool)
+            .await
+            .unwrap();
+        ids.len()
+    };
+
+    let res = bulk(
+        json!({ "filter": { "owner": owner }, "action": "disable" }),
+        "?dry_run=true",
+    )
+    .await
+    .unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    let body: Value = res.json().await.unwrap();
+    assert_eq!(body["dry_run"], true);
+    assert_eq!(body["count"], 3);
+    assert_eq!(enabled().await, 4);
+
+    let res = bulk(
+        json!({ "filter": { "owner": owner, "url_contains": "spam.example" }, "action": "disable" }),
+        "",
+    )
+    .await
+    .unwrap();
+    let body: Value = res.json().await.unwrap();
+    assert_eq!(body["count"], 3);
+    assert_eq!(enabled().await, 1);
+    assert_eq!(
+        app.get(&format!("/{}", keep)).await.status(),
+        StatusCode::FOUND
+    );
+    let (actor, action, details): (String, String, Value) =
+        sqlx::query_as("SELECT actor, action, details FROM audit_log")
+            .fetch_one(&app.pool)
+            .await
+            .unwrap();
+    assert_eq!(actor, "API_KEY");
+    assert_eq!(action, "bulk_disable");
+    assert_eq!(details["filter"]["owner"], owner.as_str());
+    assert_eq!(details["count"], 3);
+
+    // an empty filter would take everything
+    for filter in [json!({}), json!({ "all": false })] {
+        let res = bulk(json!({ "filter": filter, "action": "delete" }), "")
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+    }
+    let res = bulk(
+        json!({ "filter": { "all": true }, "action": "add_tag", "tag": "Reviewed" }),
+        "",
+    )
+    .await
+    .unwrap();
+    let body: Value = res.json().await.unwrap();
+    assert_eq!(body["count"], 4);
+
+    let res = bulk(
+        json!({ "filter": { "tag": "reviewed", "ids": [spam[0], spam[1]] }, "action": "delete" }),
+        "",
+    )
+    .await
+    .unwrap();
+    let body: Value = res.json().await.unwrap();
+    assert_eq!(body["count"], 2);
+    let mut deleted = vec![spam[0].clone(), spam[1].clone()];
+    deleted.sort();
+    assert_eq!(body["sample"], json!(deleted));
+    let left: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM urls")
+        .fetch_one(&app.pool)
+        .await
+        .unwrap();
+    assert_eq!(left, 2);
+    let audited: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM audit_log")
+        .fetch_one(&app.pool)
+        .await
+        .unwrap();
+    assert_eq!(audited, 3);
+
+    let res = app
+        .client
+        .post(format!("{}/api/links/bulk", app.base))
+        .bearer_auth(&spammer)
+        .json(&json!({ "filter": { "all": true }, "action": "delete" }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::FORBIDDEN);
+}
+
+#[tokio::test]
+async fn management_tokens_manage_their_link_only() {
+    let Some(app) = TestApp::spawn_configured(
+        |config| {
+            config.management_tokens = true;
+            // a burst of the same call would share a link
+            config.shorten_coalesce_window = Duration::ZERO;
+        },
+        |_, db| db,
+    )
+    .await
+    else {
+        return;
+    };
+    let app = &app;
+    let create = |url: &'static str| async move {
+        let res = app.post_url(url).await;
+        assert_eq!(res.status(), StatusCode::CREATED);
+        let body: Value = res.json().await.unwrap();
+        let id = body["url"].as_str().unwrap().rsplit('/').next().unwrap();
+        let token = body["management_token"].as_str().unwrap();
+        (id.to_string(), token.to_string())
+    };
+    let (id, token) = create("https://example.com/mine").await;
+    assert!(token.starts_with("mt_"), "{}", token);
+    // its own link, not one another creator was handed
+    let (other, other_token) = create("https://example.com/mine").await;
+    assert_ne!(id, other);
+    let stored: String = sqlx::query_scalar("SELECT management_token_hash FROM urls WHERE id = $1")
+        .bind(&id)
+        .fetch_one(&app.pool)
+        .await
+        .unwrap();
+    assert!(!stored.contains(&token[3..]));
+
+    let res = app
+        .client
+        .patch(format!("{}/{}", app.base, id))
+        .bearer_auth(&token)
+        .json(&json!({ "url": "https://example.com/moved" }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::NO_CONTENT);
+    assert_eq!(
+        location(&app.get(&format!("/{}", id)).await),
+        "https://example.com/moved"
+    );
+
+    let delete = |id: &str, token: &str| {
+        app.client
+            .delete(format!("{}/{}", app.base, id))
+            .bearer_auth(token)
+            .send()
+    };
+    for wrong in [other_token.as_str(), "mt_guessed"] {
+        let res = delete(&id, wrong).await.unwrap();
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+    }
+    assert_eq!(
+        app.get(&format!("/{}", id)).await.status(),
+        StatusCode::FOUND
+    );
+    let res = delete(&id, &token).await.unwrap();
+    assert_eq!(res.status(), StatusCode::NO_CONTENT);
+    assert_eq!(
+        app.get(&format!("/{}", id)).await.status(),
+        StatusCode::NOT_FOUND
+    );
+    let res = delete(&id, &token).await.unwrap();
+    assert_eq!(res.status(), StatusCode::FORBIDDEN);
+
+    // keyed callers manage their links with their key
+    let res = app
+        .client
+        .post(&app.base)
+        .bearer_auth(ADMIN_KEY)
+        .json(&json!({ "url": "https://example.com/keyed" }))
+        .send()
+        .await
+        .unwrap();
+    let body: Value = res.json().await.unwrap();
+    assert!(body.get("management_token").is_none());
+}
+
+#[tokio::test]
+async fn noise_paths_skip_the_database() {
+    let robots = std::env::temp_dir().join(format!("robots-{}.txt", std::process::id()));
+    std::fs::write(&robots, "User-agent: *\nDisallow: /api/\n").unwrap();
+    let path = robots.clone();
+    let Some(app) = TestApp::spawn_configured(
+        move |config| config.robots_txt_path = Some(path),
+        |_, db| db,
+    )
+    .await
+    else {
+        return;
+    };
+    std::fs::remove_file(&robots).unwrap();
+    let noise = || {
+        let rendered = metrics().render();
+        rendered
+            .lines()
+            .filter(|l| l.starts_with("noise_requests_total{"))
+            .filter_map(|l| l.rsplit(' ').next()?.parse::<u64>().ok())
+            .sum::<u64>()
+    };
+    let before = noise();
+    // nothing these answer with comes from the database
+    app.pool.close().await;
+    assert!(app.get("/abc123").await.status().is_server_error());
+
+    let res = app.get("/favicon.ico").await;
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(res.headers()["content-type"], "image/x-icon");
+    assert!(res.bytes().await.unwrap().starts_with(&[0, 0, 1, 0]));
+    let res = app.get("/robots.txt").await;
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(
+        res.text().await.unwrap(),
+        "User-agent: *\nDisallow: /api/\n"
+    );
+    for path in [
+        "/apple-touch-icon.png",
+        "/apple-touch-icon-precomposed.png",
+        "/.well-known/security.txt",
+        "/.well-known/acme-challenge/token",
+    ] {
+        assert_eq!(
+            app.get(path).await.status(),
+            StatusCode::NOT_FOUND,
+            "{}",
+            path
+        );
+    }
+    assert!(noise() >= before + 6);
+}
+
+#[tokio::test]
+async fn links_redirect_with_their_status() {
+    let Some(app) = TestApp::spawn().await else {
+        return;
+    };
+    let shorten = |body: Value| app.client.post(&app.base).json(&body).send();
+    let res = shorten(json!({ "url": "https://example.com/moved", "redirect_status": 308 }))
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::CREATED);
+    let body: Value = res.json().await.unwrap();
+    let id = body["url"].as_str().unwrap().rsplit('/').next().unwrap();
+    let res = app.get(&format!("/{}", id)).await;
+    assert_eq!(res.status(), StatusCode::PERMANENT_REDIRECT);
+    assert_eq!(location(&res), "https://example.com/moved");
+    // a plain link to the same url keeps its own, default status
+    let plain = app.shorten("https://example.com/moved").await;
+    assert_ne!(plain, id);
+    assert_eq!(
+        app.get(&format!("/{}", plain)).await.status(),
+        StatusCode::FOUND
+    );
+
+    let res = shorten(json!({ "url": "https://example.com/other", "redirect_status": 303 }))
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::UNPROCESSABLE_ENTITY);
+
+    let patch = |status: u16| {
+        app.client
+            .patch(format!("{}/{}", app.base, id))
+            .bearer_auth(ADMIN_KEY)
+            .json(&json!({ "redirect_status": status }))
+            .send()
+    };
+    assert_eq!(
+        patch(200).await.unwrap().status(),
+        StatusCode::UNPROCESSABLE_ENTITY
+    );
+    assert_eq!(patch(307).await.unwrap().status(), StatusCode::NO_CONTENT);
+    let res = app.get(&format!("/{}", id)).await;
+    assert_eq!(res.status(), StatusCode::TEMPORARY_REDIRECT);
+    assert_eq!(location(&res), "https://example.com/moved");
+}
+
+#[tokio::test]
+async fn one_time_links_are_used_up() {
+    let Some(app) = TestApp::spawn_configured(
+        |config| config.exhausted_link_url = Some("https://example.com/used".into()),
+        |_, db| db,
+    )
+    .await
+    else {
+        return;
+    };
+    let shorten = |body: Value| app.client.post(&app.base).json(&body).send();
+    let res = shorten(json!({ "url": "https://example.com/once", "max_uses": 1 }))
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::CREATED);
+    let body: Value = res.json().await.unwrap();
+    let id = body["url"].as_str().unwrap().rsplit('/').next().unwrap();
+    // never deduped into a plain link to the same url
+    assert_ne!(app.shorten("https://example.com/once").await, id);
+
+    let res = app.get(&format!("/{}", id)).await;
+    assert_eq!(res.status(), StatusCode::FOUND);
+    assert_eq!(location(&res), "https://example.com/once");
+    let res = app.get(&format!("/{}", id)).await;
+    assert_eq!(res.status(), StatusCode::FOUND);
+    assert_eq!(location(&res), "https://example.com/used");
+    assert_eq!(
+        app.get(&format!("/{}/info", id)).await.status(),
+        StatusCode::GONE
+    );
+
+    for body in [
+        json!({ "url": "https://example.com/never", "max_uses": 0 }),
+        json!({ "url": "https://example.com/cached", "max_uses": 1, "redirect_status": 301 }),
+    ] {
+        assert_eq!(
+            shorten(body).await.unwrap().status(),
+            StatusCode::UNPROCESSABLE_ENTITY
+        );
+    }
+
+    // nor made permanent later
+    let res = shorten(json!({ "url": "https://example.com/twice", "max_uses": 2 }))
+        .await
+        .unwrap();
+    let body: Value = res.json().await.unwrap();
+    let id = body["slugs"][0].as_str().unwrap().to_string();
+    let set_status = |status: u16| {
+        app.client
+            .patch(format!("{}/{}", app.base, id))
+            .bearer_auth(ADMIN_KEY)
+            .json(&json!({ "redirect_status": status }))
+            .send()
+    };
+    for status in [301, 308] {
+        assert_eq!(
+            set_status(status).await.unwrap().status(),
+            StatusCode::UNPROCESSABLE_ENTITY
+        );
+    }
+    assert_eq!(
+        set_status(307).await.unwrap().status(),
+        StatusCode::NO_CONTENT
+    );
+    let res = app.get(&format!("/{}", id)).await;
+    assert_eq!(res.status(), StatusCode::TEMPORARY_REDIRECT);
+    assert_eq!(location(&res), "https://example.com/twice");
+}
+
+#[tokio::test]
+async fn multiplexed_links_pick_a_destination() {
+    let Some(app) = TestApp::spawn().await else {
+        return;
+    };
+    let shorten = |body: Value| app.client.post(&app.base).json(&body).send();
+    let res = shorten(json!({
+        "url": "https://example.com/first",
+        "urls": ["https://example.com/second", "https://example.com/third?page=1"],
+    }))
+    .await
+    .unwrap();
+    assert_eq!(res.status(), StatusCode::CREATED);
+    let body: Value = res.json().await.unwrap();
+    let id = body["url"].as_str().unwrap().rsplit('/').next().unwrap();
+    // never deduped into a plain link to the same url
+    assert_ne!(app.shorten("https://example.com/first").await, id);
+
+    for (query, expected) in [
+        ("", "https://example.com/first"),
+        ("?i=0", "https://example.com/first"),
+        ("?i=1", "https://example.com/second"),
+        ("?i=2", "https://example.com/third?page=1"),
+        ("?ref=mail&i=2", "https://example.com/third?page=1&ref=mail"),
+    ] {
+        let res = app.get(&format!("/{}{}", id, query)).await;
+        assert_eq!(res.status(), StatusCode::FOUND, "{}", query);
+        assert_eq!(location(&res), expected, "{}", query);
+    }
+    let recorder = local_metrics();
+    let recording = metrics::set_default_local_recorder(&recorder);
+    for query in ["?i=3", "?i=-1", "?i=last"] {
+        let res = app.get(&format!("/{}{}", id, query)).await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND, "{}", query);
+    }
+    drop(recording);
+    let counted = recorder.handle().render();
+    assert!(counted.contains(r#"redirect_total{outcome="not_found"} 3"#));
+    assert!(!counted.contains(r#"outcome="found""#), "{}", counted);
+
+    // `i` means nothing to other links
+    let plain = app.shorten("https://example.com/plain").await;
+    let res = app.get(&format!("/{}?i=2", plain)).await;
+    assert_eq!(location(&res), "https://example.com/plain?i=2");
+
+    let res = shorten(json!({ "url": "https://example.com/", "urls": ["not a url"] }))
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::UNPROCESSABLE_ENTITY);
+}
+
+#[tokio::test]
+async fn previews_count_nothing() {
+    let Some(app) = TestApp::spawn().await else {
+        return;
+    };
+    let reader = app.create_key("slack bot", &["read"]).await;
+    let res = app
+        .client
+        .post(&app.base)
+        .bearer_auth(ADMIN_KEY)
+        .json(&json!({
+            "url": "https://example.com/once",
+            "max_uses": 1,
+            "tags": ["docs"],
+            "description": "Read me",
+        }))
+        .send()
+        .await
+        .unwrap();
+    let body: Value = res.json().await.unwrap();
+    let id = body["url"].as_str().unwrap().rsplit('/').next().unwrap();
+    let preview = |key: &str, query: &str| {
+        app.client
+            .get(format!("{}/api/links/{}/preview{}", app.base, id, query))
+            .bearer_auth(key)
+            .send()
+    };
+    for _ in 0..3 {
+        let res = preview(&reader, "").await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        let body: Value = res.json().await.unwrap();
+        assert_eq!(body["url"], "https://example.com/once");
+        assert_eq!(body["description"], "Read me");
+        assert_eq!(body["tags"], json!(["docs"]));
+        assert_eq!(body["owner"], "API_KEY");
+        assert_eq!(body["status"], "active");
+    }
+    let stats: Value = app
+        .client
+        .get(format!("{}/api/links/{}/stats", app.base, id))
+        .bearer_auth(ADMIN_KEY)
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(stats["clicks"], 0);
+    // its one use is still there
+    let res = app.get(&format!("/{}", id)).await;
+    assert_eq!(location(&res), "https://example.com/once");
+    let body: Value = preview(&reader, "?fields=status,url")
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(
+        body,
+        json!({ "status": "exhausted", "url": "https://example.com/once" })
+    );
+    let res = preview(&reader, "?fields=status,clicks").await.unwrap();
+    assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+
+    // disabled links exist for admins only
+    app.client
+        .patch(format!("{}/{}", app.base, id))
+        .bearer_auth(ADMIN_KEY)
+        .json(&json!({ "enabled": false }))
+        .send()
+        .await
+        .unwrap();
+    let res = preview(&reader, "").await.unwrap();
+    assert_eq!(res.status(), StatusCode::NOT_FOUND);
+    let body: Value = preview(ADMIN_KEY, "?fields=enabled,status")
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(body, json!({ "enabled": false, "status": "disabled" }));
+    let res = app
+        .client
+        .get(format!("{}/api/links/missing/preview", app.base))
+        .bearer_auth(&reader)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn one_time_links_redirect_once() {
+    let Some(app) = TestApp::spawn().await else {
+        return;
+    };
+    let res = app
+        .client
+        .post(&app.base)
+        .json(&json!({ "url": "https://example.com/once", "max_uses": 1 }))
+        .send()
+        .await
+        .unwrap();
+    let body: Value = res.json().await.unwrap();
+    let id = body["url"].as_str().unwrap().rsplit('/').next().unwrap();
+    let path = format!("/{}", id);
+    let statuses = join_all((0..8).map(|_| app.get(&path))).await;
+    let found = statuses
+        .iter()
+        .filter(|res| res.status() == StatusCode::FOUND)
+        .count();
+    assert_eq!(found, 1);
+    assert!(statuses
+        .iter()
+        .all(|res| matches!(res.status(), StatusCode::FOUND | StatusCode::GONE)));
+    assert_eq!(app.get(&path).await.status(), StatusCode::GONE);
+    let clicks: i64 = sqlx::query_scalar("SELECT clicks FROM urls WHERE id = $1")
+        .bind(id)
+        .fetch_one(&app.pool)
+        .await
+        .unwrap();
+    assert_eq!(clicks, 1);
+}
+
+#[tokio::test]
+async fn link_latency_is_tracked() {
+    let Some(app) = TestApp::spawn().await else {
+        return;
+    };
+    let id = app.shorten("https://example.com/timed").await;
+    let latency = |id: String| {
+        app.client
+            .get(format!("{}/api/links/{}/latency", app.base, id))
+            .bearer_auth(ADMIN_KEY)
+            .send()
+    };
+    let body: Value = latency(id.clone()).await.unwrap().json().await.unwrap();
+    assert_eq!(body["tracked"], false);
+    assert_eq!(body["p50_ms"], Value::Null);
+
+    for _ in 0..3 {
+        assert_eq!(
+            app.get(&format!("/{}", id)).await.status(),
+            StatusCode::FOUND
+        );
+    }
+    let res = latency(id.clone()).await.unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    let body: Value = res.json().await.unwrap();
+    assert_eq!(body["tracked"], true);
+    assert_eq!(body["samples"], 3);
+    assert!(body["p50_ms"].as_f64().unwrap() > 0.0);
+    assert!(body["p99_ms"].as_f64().unwrap() >= body["p50_ms"].as_f64().unwrap());
+    assert_eq!(body["window_secs"], 15 * 60);
+    assert_eq!(
+        latency("missing".into()).await.unwrap().status(),
+        StatusCode::NOT_FOUND
+    );
+}
+
+#[tokio::test]
+async fn link_latency_can_be_turned_off() {
+    let Some(app) =
+        TestApp::spawn_configured(|config| config.link_latency = false, |_, db| db).await
+    else {
+        return;
+    };
+    let id = app.shorten("https://example.com/untimed").await;
+    let res = app
+        .client
+        .get(format!("{}/api/links/{}/latency", app.base, id))
+        .bearer_auth(ADMIN_KEY)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn traces_refuse_private_destinations() {
+    let Some(app) = TestApp::spawn().await else {
+        return;
+    };
+    let id = app.shorten("https://example.com/traced").await;
+    sqlx::query("UPDATE urls SET url = 'http://127.0.0.1:9/page' WHERE id = $1")
+        .bind(&id)
+        .execute(&app.pool)
+        .await
+        .unwrap();
+    let reader = app.create_key("reader", &["read"]).await;
+    let trace = |id: String, key: &str| {
+        app.client
+            .get(format!("{}/api/links/{}/trace", app.base, id))
+            .bearer_auth(key)
+            .send()
+    };
+    let res = trace(id.clone(), ADMIN_KEY).await.unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    let body: Value = res.json().await.unwrap();
+    assert_eq!(body["outcome"], "failed");
+    assert_eq!(body["final_status"], Value::Null);
+    assert_eq!(body["hops"][0]["url"], "http://127.0.0.1:9/page");
+    assert!(body["hops"][0]["error"]
+        .as_str()
+        .unwrap()
+        .contains("non-public address"));
+
+    assert_eq!(
+        trace(id, &reader).await.unwrap().status(),
+        StatusCode::FORBIDDEN
+    );
+    assert_eq!(
+        trace("missing".into(), ADMIN_KEY).await.unwrap().status(),
+        StatusCode::NOT_FOUND
+    );
+}
+
+#[tokio::test]
+async fn health_checks_report_each_link() {
+    let Some(app) = TestApp::spawn().await else {
+        return;
+    };
+    let private = app.shorten("https://example.com/private").await;
+    sqlx::query("UPDATE urls SET url = 'http://127.0.0.1:9/page' WHERE id = $1")
+        .bind(&private)
+        .execute(&app.pool)
+        .await
+        .unwrap();
+    let check = |body: Value, key: &str| {
+        app.client
+            .post(format!("{}/api/links/healthcheck", app.base))
+            .bearer_auth(key)
+            .json(&body)
+            .send()
+    };
+    let res = check(json!({ "ids": [private, "missing"] }), ADMIN_KEY)
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    let body: Value = res.json().await.unwrap();
+    assert_eq!(body.as_array().unwrap().len(), 1);
+    assert_eq!(body[0]["id"], private.as_str());
+    assert_eq!(body[0]["status"], Value::Null);
+    assert!(body[0]["error"]
+        .as_str()
+        .unwrap()
+        .contains("non-public address"));
+
+    let body: Value = check(json!({ "all": true }), ADMIN_KEY)
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(body.as_array().unwrap().len(), 1);
+    for body in [json!({}), json!({ "ids": [private], "all": true })] {
+        let res = check(body, ADMIN_KEY).await.unwrap();
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+    }
+    let reader = app.create_key("reader", &["read"]).await;
+    let res = check(json!({ "all": true }), &reader).await.unwrap();
+    assert_eq!(res.status(), StatusCode::FORBIDDEN);
+}
+
+#[tokio::test]
+async fn redirect_policies_ask_to_confirm() {
+    let Some(app) = TestApp::spawn().await else {
+        return;
+    };
+    let app = &app;
+    let download = app.shorten("https://downloads.example.net/tool.EXE").await;
+    let flagged = app.shorten("https://example.com/flagged").await;
+    let plain = app.shorten("https://example.com/plain").await;
+
+    let add = |body: Value| {
+        app.client
+            .post(format!("{}/api/policies", app.base))
+            .bearer_auth(ADMIN_KEY)
+            .json(&body)
+            .send()
+    };
+    for (body, expected) in [
+        (
+            json!({ "pattern": "https://*" }),
+            StatusCode::UNPROCESSABLE_ENTITY,
+        ),
+        (
+            json!({ "pattern": "*.exe", "link_id": plain }),
+            StatusCode::UNPROCESSABLE_ENTITY,
+        ),
+        (json!({ "link_id": "nosuchlink" }), StatusCode::NOT_FOUND),
+    ] {
+        assert_eq!(add(body).await.unwrap().status(), expected);
+    }
+    let res = add(json!({ "pattern": "*/*.exe", "reason": "downloads" }))
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::CREATED);
+    let pattern: Value = res.json().await.unwrap();
+    let res = add(json!({ "link_id": flagged })).await.unwrap();
+    assert_eq!(res.status(), StatusCode::CREATED);
+    let writer = app.create_key("writer", &["write"]).await;
+    let res = app
+        .client
+        .post(format!("{}/api/policies", app.base))
+        .bearer_auth(&writer)
+        .json(&json!({ "pattern": "*" }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::FORBIDDEN);
+
+    let page = |path: String, key: Option<&str>| {
+        let mut req = app.client.get(format!("{}{}", app.base, path));
+        if let Some(key) = key {
+            req = req.bearer_auth(key);
+        }
+        req.send()
+    };
+    let res = page(format!("/{}?ref=mail", download), None).await.unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    assert!(res.headers().get(LOCATION).is_none());
+    assert_eq!(res.headers()["cache-control"], "no-store");
+    let body = res.text().await.unwrap();
+    assert!(body.contains("https://downloads.example.net/tool.EXE?ref=mail"));
+    let href = body.split("href=\"").nth(1).unwrap();
+    let href = href[..href.find('"').unwrap()].replace("&amp;", "&");
+    let continue_url = format!("{}/{}", app.base, href.trim_start_matches("./"));
+
+    let res = app.client.get(&continue_url).send().await.unwrap();
+    assert_eq!(res.status(), StatusCode::FOUND);
+    assert_eq!(
+        location(&res),
+        "https://downloads.example.net/tool.EXE?ref=mail"
+    );
+    let tampered = continue_url.replace("ref%3Dmail", "ref%3Dspam");
+    assert_ne!(tampered, continue_url);
+    let res = app.client.get(&tampered).send().await.unwrap();
+    assert_eq!(res.status(), StatusCode::FORBIDDEN);
+    let res = app
+        .client
+        .get(format!(
+            "{}/{}/continue?exp=99999999999&sig=00",
+            app.base, flagged
+        ))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::FORBIDDEN);
+
+    // no way around the page without a key that may skip it
+    let res = page(format!("/{}.json", flagged), None).await.unwrap();
+    assert_eq!(res.status(), StatusCode::FORBIDDEN);
+    assert_eq!(
+        page(format!("/{}", flagged), Some(&writer))
+            .await
+            .unwrap()
+            .status(),
+        StatusCode::OK
+    );
+    assert_eq!(
+        page(format!("/{}", flagged), Some("sk_bogus_key"))
+            .await
+            .unwrap()
+            .status(),
+        StatusCode::UNAUTHORIZED
+    );
+    let direct = app.create_key("direct", &["direct"]).await;
+    for key in [direct.as_str(), ADMIN_KEY] {
+        let res = page(format!("/{}", flagged), Some(key)).await.unwrap();
+        assert_eq!(res.status(), StatusCode::FOUND);
+        assert_eq!(location(&res), "https://example.com/flagged");
+    }
+    // direct alone grants nothing else
+    let res = app
+        .client
+        .get(format!("{}/api/links", app.base))
+        .bearer_auth(&direct)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::FORBIDDEN);
+    assert_eq!(
+        app.get(&format!("/{}", plain)).await.status(),
+        StatusCode::FOUND
+    );
+
+    let res = app
+        .client
+        .get(format!("{}/api/policies", app.base))
+        .bearer_auth(ADMIN_KEY)
+        .send()
+        .await
+        .unwrap();
+    let listed: Vec<Value> = res.json().await.unwrap();
+    assert_eq!(listed.len(), 2);
+    let res = app
+        .client
+        .delete(format!("{}/api/policies/{}", app.base, pattern["id"]))
+        .bearer_auth(ADMIN_KEY)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::NO_CONTENT);
+    assert_eq!(
+        app.get(&format!("/{}", download)).await.status(),
+        StatusCode::FOUND
+    );
+}
+
+#[tokio::test]
+async fn pasted_urls_are_cleaned_up() {
+    let Some(app) = TestApp::spawn().await else {
+        return;
+    };
+    let id = app
+        .shorten(" <“https://example.com/a%20b c”>\u{200b}\n")
+        .await;
+    let res = app.get(&format!("/{}", id)).await;
+    assert_eq!(location(&res), "https://example.com/a%20b%20c");
+    assert_eq!(app.shorten("https://example.com/a%20b%20c").await, id);
+
+    let res = app.post_url(" “www.example.com/page” ").await;
+    assert_eq!(res.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    let body: Value = res.json().await.unwrap();
+    assert_eq!(body["error"], "invalid_url");
+    assert_eq!(body["attempted"], "www.example.com/page");
+    assert!(body["message"]
+        .as_str()
+        .unwrap()
+        .contains("www.example.com/page"));
+}
+
+#[tokio::test]
+async fn shadow_traffic_is_compared() {
+    use axum::{
+        http::{HeaderMap, Method, Uri},
+        response::IntoResponse,
+    };
+    use shortener::config::ShadowRoute;
+
+    // a shadow sending every link elsewhere, remembering what it was sent
+    let seen = Arc::new(Mutex::new(Vec::new()));
+    let shadow = axum::Router::new().fallback({
+        let seen = seen.clone();
+        move |method: Method, uri: Uri, headers: HeaderMap| async move {
+            seen.lock().unwrap().push((uri.path().to_string(), headers));
+            match method {
+                Method::POST => axum::http::StatusCode::CREATED.into_response(),
+                _ => (
+                    axum::http::StatusCode::FOUND,
+                    [("location", "https://example.com/shadow")],
+                )
+                    .into_response(),
+            }
+        }
+    });
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let shadow_url = format!("http://{}", listener.local_addr().unwrap());
+    tokio::spawn(axum::serve(listener, shadow).into_future());
+    let Some(app) = TestApp::spawn_configured(
+        |config| {
+            config.shadow_base_url = Some(shadow_url.clone());
+            config.shadow_sample_percent = 100.0;
+            config.shadow_routes = vec![ShadowRoute::Redirect, ShadowRoute::Shorten];
+            config.shadow_mutations = true;
+        },
+        |_, db| db,
+    )
+    .await
+    else {
+        return;
+    };
+    let same = app.shorten("https://example.com/shadow").await;
+    let other = app.shorten("https://example.com/primary").await;
+    // the client's answers are this instance's
+    let res = app.get(&format!("/{}", same)).await;
+    assert_eq!(location(&res), "https://example.com/shadow");
+    let res = app.get(&format!("/{}", other)).await;
+    assert_eq!(location(&res), "https://example.com/primary");
+    assert_eq!(app.get("/nope").await.status(), StatusCode::NOT_FOUND);
+
+    let diffs = || async {
+        let diffs: Vec<Value> = app
+            .client
+            .get(format!("{}/api/admin/shadow-diffs", app.base))
+            .bearer_auth(ADMIN_KEY)
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        diffs
+    };
+    let recorded = async {
+        while diffs().await.len() < 2 {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    };
+    tokio::time::timeout(Duration::from_secs(10), recorded)
+        .await
+        .expect("no shadow diffs recorded");
+    // and none for the link both send to the same place
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    let mut diffs = diffs().await;
+    diffs.sort_by_key(|diff| diff["path"].as_str().unwrap().to_string());
+    assert_eq!(diffs.len(), 2, "{:?}", diffs);
+    let (moved, missing) = if diffs[0]["path"] == "/nope" {
+        (&diffs[1], &diffs[0])
+    } else {
+        (&diffs[0], &diffs[1])
+    };
+    assert_eq!(moved["route"], "redirect");
+    assert_eq!(moved["path"], format!("/{}", other));
+    assert_eq!(moved["primary_status"], 302);
+    assert_eq!(moved["shadow_status"], 302);
+    assert_eq!(moved["primary_location"], "https://example.com/primary");
+    assert_eq!(moved["shadow_location"], "https://example.com/shadow");
+    assert_eq!(missing["primary_status"], 404);
+    assert_eq!(missing["shadow_location"], "https://example.com/shadow");
+
+    // both shortens went over, marked and without credentials
+    let seen = seen.lock().unwrap();
+    assert_eq!(seen.iter().filter(|(path, _)| path == "/").count(), 2);
+    assert!(seen
+        .iter()
+        .all(|(_, headers)| headers.contains_key("x-shortener-shadow")
+            && !headers.contains_key("authorization")));
+}
+
+#[tokio::test]
+async fn shadow_traffic_is_rate_limited() {
+    let hits = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+    let shadow = axum::Router::new().fallback({
+        let hits = hits.clone();
+        move || async move {
+            hits.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            axum::http::StatusCode::NOT_FOUND
+        }
+    });
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let shadow_url = format!("http://{}", listener.local_addr().unwrap());
+    tokio::spawn(axum::serve(listener, shadow).into_future());
+    let Some(app) = TestApp::spawn_configured(
+        |config| {
+            config.shadow_base_url = Some(shadow_url.clone());
+            config.shadow_sample_percent = 100.0;
+            config.shadow_max_per_sec = 2;
+        },
+        |_, db| db,
+    )
+    .await
+    else {
+        return;
+    };
+    for _ in 0..10 {
+        assert_eq!(app.get("/nope").await.status(), StatusCode::NOT_FOUND);
+    }
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    // two in each window the requests spanned
+    let hits = hits.load(std::sync::atomic::Ordering::SeqCst);
+    assert!((1..=4).contains(&hits), "{} mirrored", hits);
+}
+
+#[tokio::test]
+async fn unowned_links_are_claimed_and_owned_ones_transferred() {
+    let Some(app) = TestApp::spawn().await else {
+        return;
+    };
+    let app = &app;
+    let alice = app.create_key("alice", &["write"]).await;
+    let bob = app.create_key("bob", &["write"]).await;
+    let key_id = |label: &'static str| async move {
+        let id: i64 = sqlx::query_scalar("SELECT id FROM api_keys WHERE label = $1")
+            .bind(label)
+            .fetch_one(&app.pool)
+            .await
+            .unwrap();
+        id.to_string()
+    };
+    let (alice_id, bob_id) = (key_id("alice").await, key_id("bob").await);
+    let post = |path: String, key: &str, body: Value| {
+        app.client
+            .post(format!("{}{}", app.base, path))
+            .bearer_auth(key)
+            .json(&body)
+            .send()
+    };
+    let owner = |id: String| async move {
+        let owner: Option<String> = sqlx::query_scalar("SELECT owner FROM urls WHERE id = $1")
+            .bind(id)
+            .fetch_one(&app.pool)
+            .await
+            .unwrap();
+        owner
+    };
+
+    // the first claim hands out the token, the next looks for it
+    let id = app.shorten("https://example.com/unclaimed").await;
+    sqlx::query("UPDATE urls SET url = 'http://127.0.0.1:9/page' WHERE id = $1")
+        .bind(&id)
+        .execute(&app.pool)
+        .await
+        .unwrap();
+    let claim = format!("/api/links/{}/claim", id);
+    let res = post(claim.clone(), &alice, json!({})).await.unwrap();
+    assert_eq!(res.status(), StatusCode::ACCEPTED);
+    let challenge: Value = res.json().await.unwrap();
+    assert!(challenge["token"]
+        .as_str()
+        .unwrap()
+        .starts_with("shortener-claim-"));
+    assert_eq!(
+        challenge["verification_url"],
+        "http://127.0.0.1:9/.well-known/shortener-claim.txt"
+    );
+    let res = post(claim.clone(), &alice, json!({})).await.unwrap();
+    assert_eq!(res.status(), StatusCode::FORBIDDEN);
+    let body: Value = res.json().await.unwrap();
+    assert_eq!(body["error"], "unverified");
+    assert!(body["message"].as_str().unwrap().contains("non-public"));
+    let again: Value = post(claim.clone(), &bob, json!({}))
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_ne!(again["token"], challenge["token"]);
+    assert_eq!(owner(id.clone()).await, None);
+
+    // of claims racing for a link, one gets it
+    let (first, second) = tokio::join!(
+        shortener::claims::take(&app.pool, &id, &alice_id),
+        shortener::claims::take(&app.pool, &id, &bob_id),
+    );
+    assert!(first.unwrap() ^ second.unwrap());
+    let res = post(claim, &alice, json!({})).await.unwrap();
+    assert_eq!(res.status(), StatusCode::CONFLICT);
+
+    // admins hand links to anyone
+    let assign = format!("/api/links/{}/owner", id);
+    let res = post(assign.clone(), &alice, json!({ "owner": alice_id }))
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::FORBIDDEN);
+    let res = post(assign.clone(), ADMIN_KEY, json!({ "owner": "999999" }))
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    let res = post(assign, ADMIN_KEY, json!({ "owner": alice_id }))
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(owner(id.clone()).await.as_ref(), Some(&alice_id));
+
+    // transfers take the receiver's word
+    let transfer = format!("/api/links/{}/transfer", id);
+    let res = post(transfer.clone(), &bob, json!({ "to": bob_id }))
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::FORBIDDEN);
+    let res = post(transfer.clone(), &alice, json!({ "to": bob_id }))
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::ACCEPTED);
+    assert_eq!(owner(id.clone()).await.as_ref(), Some(&alice_id));
+    let pending: Value = app
+        .client
+        .get(format!("{}/api/transfers", app.base))
+        .bearer_auth(&bob)
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(pending[0]["link_id"], id.as_str());
+    assert_eq!(pending[0]["from_owner"], alice_id.as_str());
+    let accept = format!("{}/accept", transfer);
+    let res = post(accept.clone(), &alice, json!({})).await.unwrap();
+    assert_eq!(res.status(), StatusCode::NOT_FOUND);
+    let res = post(accept.clone(), &bob, json!({})).await.unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(owner(id.clone()).await.as_ref(), Some(&bob_id));
+    let res = post(accept, &bob, json!({})).await.unwrap();
+    assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+    // declined offers go away
+    let res = post(transfer.clone(), &bob, json!({ "to": alice_id }))
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::ACCEPTED);
+    let res = app
+        .client
+        .delete(format!("{}{}", app.base, transfer))
+        .bearer_auth(&alice)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::NO_CONTENT);
+    let res = post(format!("{}/accept", transfer), &alice, json!({}))
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+    let actions: Vec<String> =
+        sqlx::query_scalar("SELECT action FROM audit_log WHERE details->>'id' = $1 ORDER BY id")
+            .bind(&id)
+            .fetch_all(&app.pool)
+            .await
+            .unwrap();
+    assert_eq!(
+        actions,
+        [
+            "assign_owner",
+            "offer_transfer",
+            "accept_transfer",
+            "offer_transfer",
+            "withdraw_transfer"
+        ]
+    );
+}
+
+#[cfg(feature = "client")]
+#[tokio::test]
+async fn the_client_calls_the_api() {
+    use shortener::{
+        api::ShortReq,
+        client::{ClientError, ShortenerClient},
+    };
+
+    let Some(app) = TestApp::spawn().await else {
+        return;
+    };
+    let key = app.create_key("sdk", &["read", "write", "admin"]).await;
+    let client = ShortenerClient::new(format!("{}/", app.base), key);
+    let req = |url: &str| ShortReq {
+        url: url.into(),
+        ..Default::default()
+    };
+
+    let link = client
+        .shorten(&req("https://example.com/sdk"))
+        .await
+        .unwrap();
+    assert!(link.created);
+    let id = link.slugs[0].clone();
+    let resolved = client.resolve(&id).await.unwrap();
+    assert_eq!(resolved.url, "https://example.com/sdk");
+    assert_eq!(client.stats(&id).await.unwrap().clicks, 1);
+
+    let batch = client
+        .shorten_batch(&[
+            req("https://example.com/a"),
+            req("not a url"),
+            req("https://example.com/b"),
+        ])
+        .await;
+    assert_eq!(batch.len(), 3);
+    assert!(batch[0]
+        .as_ref()
+        .unwrap()
+        .url
+        .ends_with(&batch[0].as_ref().unwrap().slugs[0]));
+    assert!(matches!(&batch[1], Err(ClientError::InvalidUrl(url)) if url == "not a url"));
+    assert!(batch[2].is_ok());
+
+    let taken = ShortReq {
+        alias: Some(id.clone()),
+        ..req("https://example.com/other")
+    };
+    assert!(matches!(
+        client.shorten(&taken).await,
+        Err(ClientError::AliasTaken)
+    ));
+
+    client.delete(&id).await.unwrap();
+    assert!(matches!(
+        client.resolve(&id).await,
+        Err(ClientError::NotFound)
+    ));
+    assert!(matches!(
+        client.delete(&id).await,
+        Err(ClientError::NotFound)
+    ));
+
+    let stranger = ShortenerClient::new(&app.base, "not-a-key");
+    assert!(matches!(
+        stranger.stats(&batch[2].as_ref().unwrap().slugs[0]).await,
+        Err(ClientError::Unauthorized)
+    ));
+}
+
+/// Every row of the tables backed up, each table's in a stable order.
+async fn backed_up_rows(pool: &PgPool) -> Vec<Vec<String>> {
+    let mut tables = Vec::new();
+    for table in shortener::backup::TABLES {
+        let query = format!(
+            "SELECT row_to_json(t)::text AS row FROM {} t ORDER BY row",
+            table.name
+        );
+        tables.push(sqlx::query_scalar(&query).fetch_all(pool).await.unwrap());
+    }
+    tables
+}
+
+impl TestApp {
+    async fn backup(&self) -> Vec<u8> {
+        let res = self
+            .client
+            .get(format!("{}/api/admin/backup", self.base))
+            .bearer_auth(ADMIN_KEY)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers()["content-type"], "application/x-tar");
+        res.bytes().await.unwrap().to_vec()
+    }
+
+    async fn restore(&self, archive: Vec<u8>, force: bool) -> reqwest::Response {
+        self.client
+            .post(format!("{}/api/admin/restore?force={}", self.base, force))
+            .bearer_auth(ADMIN_KEY)
+            .body(archive)
+            .send()
+            .await
+            .unwrap()
+    }
+
+    /// Waits for restore `job` to end, returning the job.
+    async fn finished_restore(&self, job: &Value) -> Value {
+        for _ in 0..100 {
+            for state in ["done", "failed"] {
+                let res = self
+                    .client
+                    .get(format!("{}/api/jobs?state={}", self.base, state))
+                    .bearer_auth(ADMIN_KEY)
+                    .send()
+                    .await
+                    .unwrap();
+                let jobs: Vec<Value> = res.json().await.unwrap();
+                if let Some(found) = jobs.into_iter().find(|j| j["id"] == *job) {
+                    return found;
+                }
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        panic!("restore {} didn't finish", job);
+    }
+}
+
+#[tokio::test]
+async fn backups_restore_into_an_empty_database() {
+    let Some(app) = TestApp::spawn_configured(
+        |config| {
+            config.max_body_bytes = 1 << 20;
+            config.import_sync_rows = 2000;
+        },
+        |_, db| db,
+    )
+    .await
+    else {
+        return;
+    };
+    // more rows than fit in one file of the archive
+    let rows: Vec<Value> = (0..1500)
+        .map(|i| json!({ "id": format!("bulk{}", i), "url": format!("https://example.com/{}", i) }))
+        .collect();
+    let res = app
+        .client
+        .post(format!("{}/api/imports", app.base))
+        .bearer_auth(ADMIN_KEY)
+        .json(&json!({ "rows": rows }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::CREATED);
+    let res = app
+        .client
+        .post(&app.base)
+        .json(&json!({ "url": "https://example.com/sale", "tags": ["promo", "spring"] }))
+        .send()
+        .await
+        .unwrap();
+    let body: Value = res.json().await.unwrap();
+    let id = body["url"].as_str().unwrap().rsplit('/').next().unwrap();
+    app.client
+        .post(format!("{}/api/links/{}/aliases", app.base, id))
+        .bearer_auth(ADMIN_KEY)
+        .json(&json!({ "alias": "sale" }))
+        .send()
+        .await
+        .unwrap();
+    let res = app
+        .client
+        .post(format!("{}/api/policies", app.base))
+        .bearer_auth(ADMIN_KEY)
+        .json(&json!({ "link_id": "bulk7" }))
+        .send()
+        .await
+        .unwrap();
+    let policy: Value = res.json().await.unwrap();
+    let before = backed_up_rows(&app.pool).await;
+    assert_eq!(before[0].len(), 1501);
+
+    let archive = app.backup().await;
+    let manifest = shortener::backup::verify(archive.as_slice()).unwrap();
+    let again = shortener::backup::verify(app.backup().await.as_slice()).unwrap();
+    assert_eq!(
+        again.files, manifest.files,
+        "the same data made other files"
+    );
+    assert_eq!(manifest.rows()["urls"], 1501);
+    assert_eq!(
+        manifest.files.iter().filter(|f| f.table == "urls").count(),
+        2
+    );
+    let res = app.restore(archive.clone(), false).await;
+    assert_eq!(res.status(), StatusCode::CONFLICT);
+
+    sqlx::query("TRUNCATE urls, slugs, link_tags, redirect_policies")
+        .execute(&app.pool)
+        .await
+        .unwrap();
+    let res = app.restore(archive, false).await;
+    assert_eq!(res.status(), StatusCode::ACCEPTED);
+    let body: Value = res.json().await.unwrap();
+    let rows: usize = before.iter().map(Vec::len).sum();
+    assert_eq!(body["rows"], rows);
+    let job = app.finished_restore(&body["job_id"]).await;
+    assert_eq!(job["state"], "done", "{}", job);
+    assert_eq!(job["kind"], "restore");
+    assert_eq!(job["payload"]["rows_restored"], body["rows"]);
+
+    assert_eq!(backed_up_rows(&app.pool).await, before);
+    assert_eq!(
+        location(&app.get("/sale").await),
+        "https://example.com/sale"
+    );
+    // policies added after the restore don't collide with restored ones
+    let res = app
+        .client
+        .post(format!("{}/api/policies", app.base))
+        .bearer_auth(ADMIN_KEY)
+        .json(&json!({ "pattern": "*.exe" }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::CREATED);
+    let added: Value = res.json().await.unwrap();
+    assert!(added["id"].as_i64() > policy["id"].as_i64());
+}
+
+#[tokio::test]
+async fn forced_restores_replace_the_data() {
+    let Some(app) = TestApp::spawn().await else {
+        return;
+    };
+    let kept = app.shorten("https://example.com/kept").await;
+    let archive = app.backup().await;
+    let before = backed_up_rows(&app.pool).await;
+    let added = app.shorten("https://example.com/added").await;
+    app.client
+        .delete(format!("{}/{}", app.base, kept))
+        .bearer_auth(ADMIN_KEY)
+        .send()
+        .await
+        .unwrap();
+
+    let mut damaged = archive.clone();
+    let at = damaged.windows(4).position(|w| w == b"kept").unwrap();
+    damaged[at] = b'K';
+    let res = app.restore(damaged, true).await;
+    assert_eq!(res.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    let body: Value = res.json().await.unwrap();
+    assert_eq!(body["error"], "invalid_archive");
+    assert_eq!(
+        app.get(&format!("/{}", added)).await.status(),
+        StatusCode::FOUND
+    );
+
+    let res = app.restore(archive, true).await;
+    assert_eq!(res.status(), StatusCode::ACCEPTED);
+    let body: Value = res.json().await.unwrap();
+    let job = app.finished_restore(&body["job_id"]).await;
+    assert_eq!(job["state"], "done", "{}", job);
+    assert_eq!(backed_up_rows(&app.pool).await, before);
+    assert_eq!(
+        location(&app.get(&format!("/{}", kept)).await),
+        "https://example.com/kept"
+    );
+}
+
+#[tokio::test]
+async fn alias_availability() {
+    let Some(app) = TestApp::spawn().await else {
+        return;
+    };
+    let id = app.shorten("https://example.com/taken").await;
+    app.client
+        .post(format!("{}/api/links/{}/aliases", app.base, id))
+        .bearer_auth(ADMIN_KEY)
+        .json(&json!({ "alias": "summer" }))
+        .send()
+        .await
+        .unwrap();
+    let available = |alias: &str| {
+        let url = format!("{}/api/alias-available", app.base);
+        let req = app.client.get(url).query(&[("alias", alias)]);
+        async move { req.send().await.unwrap() }
+    };
+
+    let res = available("winter").await;
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(
+        res.json::<Value>().await.unwrap(),
+        json!({ "available": true })
+    );
+    for taken in ["summer", id.as_str()] {
+        let res = available(taken).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let body: Value = res.json().await.unwrap();
+        assert_eq!(body["available"], false, "{}", taken);
+    }
+    for invalid in ["two words", "a.json", "api", "info", ""] {
+        let res = available(invalid).await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST, "{:?}", invalid);
+    }
+}
+
+#[tokio::test]
+async fn repeated_redirects_count_once() {
+    let Some(app) = TestApp::spawn_configured(
+        |config| config.click_dedup_window = Duration::from_secs(10),
+        |_, db| db,
+    )
+    .await
+    else {
+        return;
+    };
+    let id = app.shorten("https://example.com/double-click").await;
+    let follow = |user_agent: &'static str| {
+        let req = app
+            .client
+            .get(format!("{}/{}", app.base, id))
+            .header("user-agent", user_agent);
+        async move { req.send().await.unwrap() }
+    };
+    for user_agent in ["firefox", "firefox", "firefox", "chrome"] {
+        let res = follow(user_agent).await;
+        assert_eq!(location(&res), "https://example.com/double-click");
+    }
+    let stats = |app: &TestApp, id: String| {
+        let req = app
+            .client
+            .get(format!("{}/api/links/{}/stats", app.base, id))
+            .bearer_auth(ADMIN_KEY);
+        async move { req.send().await.unwrap().json::<Value>().await.unwrap() }
+    };
+    let body = stats(&app, id.clone()).await;
+    assert_eq!(body["clicks"], 2, "{}", body);
+    assert_eq!(body["click_dedup_secs"], 10);
+
+    let Some(plain) = TestApp::spawn().await else {
+        return;
+    };
+    let id = plain.shorten("https://example.com/double-click").await;
+    plain.get(&format!("/{}", id)).await;
+    plain.get(&format!("/{}", id)).await;
+    let body = stats(&plain, id).await;
+    assert_eq!(body["clicks"], 2, "{}", body);
+    assert_eq!(body["click_dedup_secs"], Value::Null);
+}
+
+#[tokio::test]
+async fn queries_stop_at_the_database_timeout() {
+    let Some(app) = TestApp::spawn_configured(
+        |config| config.db_query_timeout = Duration::from_millis(200),
+        |_, db| db,
+    )
+    .await
+    else {
+        return;
+    };
+    let id = app.shorten("https://example.com/slow-database").await;
+    let mut locker = <sqlx::PgConnection as sqlx::Connection>::connect(&app.db_url)
+        .await
+        .unwrap();
+    sqlx::query("BEGIN").execute(&mut locker).await.unwrap();
+    sqlx::query("LOCK TABLE slugs IN ACCESS EXCLUSIVE MODE")
+        .execute(&mut locker)
+        .await
+        .unwrap();
+    let started = std::time::Instant::now();
+    let res = app.get(&format!("/{}", id)).await;
+    assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+    let body: Value = res.json().await.unwrap();
+    assert_eq!(body["message"], "database query timed out");
+    let res = app.post_url("https://example.com/blocked").await;
+    assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+    // well within the request timeout
+    assert!(
+        started.elapsed() < Duration::from_secs(3),
+        "{:?}",
+        started.elapsed()
+    );
+    sqlx::query("ROLLBACK").execute(&mut locker).await.unwrap();
+
+    // the abandoned queries gave their connections back
+    for _ in 0..20 {
+        assert_eq!(
+            app.get(&format!("/{}", id)).await.status(),
+            StatusCode::FOUND
+        );
+    }
+    app.shorten("https://example.com/blocked").await;
+}
+
+#[tokio::test]
+async fn failing_lookups_open_the_breaker() {
+    let Some(app) = TestApp::spawn_configured(
+        |config| {
+            config.db_query_timeout = Duration::from_millis(200);
+            config.db_breaker_error_percent = 50.0;
+            config.db_breaker_min_calls = 3;
+            config.db_breaker_cooldown = Duration::from_secs(1);
+        },
+        |_, db| db,
+    )
+    .await
+    else {
+        return;
+    };
+    let id = app.shorten("https://example.com/overloaded").await;
+    let mut locker = <sqlx::PgConnection as sqlx::Connection>::connect(&app.db_url)
+        .await
+        .unwrap();
+    sqlx::query("BEGIN").execute(&mut locker).await.unwrap();
+    sqlx::query("LOCK TABLE slugs IN ACCESS EXCLUSIVE MODE")
+        .execute(&mut locker)
+        .await
+        .unwrap();
+    // two failures of three calls, counting the shorten
+    for _ in 0..2 {
+        let body: Value = app.get(&format!("/{}", id)).await.json().await.unwrap();
+        assert_eq!(body["message"], "database query timed out");
+    }
+
+    // open: answered without waiting on the database
+    let started = std::time::Instant::now();
+    let res = app.get(&format!("/{}", id)).await;
+    assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(res.headers()[reqwest::header::RETRY_AFTER], "1");
+    let body: Value = res.json().await.unwrap();
+    assert_eq!(body["message"], "database unavailable");
+    let res = app.post_url("https://example.com/refused").await;
+    assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+    assert!(
+        started.elapsed() < Duration::from_millis(150),
+        "{:?}",
+        started.elapsed()
+    );
+    sqlx::query("ROLLBACK").execute(&mut locker).await.unwrap();
+
+    // the probe after the cooldown closes it again
+    tokio::time::sleep(Duration::from_millis(1100)).await;
+    for _ in 0..5 {
+        assert_eq!(
+            app.get(&format!("/{}", id)).await.status(),
+            StatusCode::FOUND
+        );
+    }
+    app.shorten("https://example.com/refused").await;
+}
+
+/// Takes one mail as a relay without STARTTLS would, answering with the
+/// recipients and the data.
+async fn accept_mail(listener: TcpListener) -> (Vec<String>, String) {
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufStream};
+
+    let (conn, _) = listener.accept().await.unwrap();
+    let mut conn = BufStream::new(conn);
+    conn.write_all(b"220 fake ESMTP\r\n").await.unwrap();
+    conn.flush().await.unwrap();
+    let (mut to, mut data) = (Vec::new(), String::new());
+    loop {
+        let mut line = String::new();
+        if conn.read_line(&mut line).await.unwrap() == 0 {
+            return (to, data);
+        }
+        let line = line.trim_end().to_string();
+        let answer: &[u8] = if line.starts_with("EHLO") {
+            b"250-fake\r\n250 8BITMIME\r\n"
+        } else if let Some(rcpt) = line.strip_prefix("RCPT TO:") {
+            to.push(rcpt.trim_matches(['<', '>']).to_string());
+            b"250 ok\r\n"
+        } else if line == "DATA" {
+            conn.write_all(b"354 go on\r\n").await.unwrap();
+            conn.flush().await.unwrap();
+            loop {
+                let mut line = String::new();
+                conn.read_line(&mut line).await.unwrap();
+                if line == ".\r\n" {
+                    break;
+                }
+                data.push_str(&line);
+            }
+            b"250 queued\r\n"
+        } else if line == "QUIT" {
+            conn.write_all(b"221 bye\r\n").await.unwrap();
+            conn.flush().await.unwrap();
+            return (to, data);
+        } else {
+            b"250 ok\r\n"
+        };
+        conn.write_all(answer).await.unwrap();
+        conn.flush().await.unwrap();
+    }
+}
+
+#[tokio::test]
+async fn digests_are_mailed_on_schedule() {
+    let relay = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let relay_addr = relay.local_addr().unwrap();
+    let Some(app) = TestApp::spawn_with(|config, db| {
+        config.smtp_url = Some(format!("smtp://{}", relay_addr));
+        config.digest_from = Some("links@example.com".into());
+        config.digest_to = vec!["ops@example.com".into()];
+        config.digest_schedule = "daily@09:00".parse().unwrap();
+        config.public_url = Some("https://sho.rt".into());
+        db
+    })
+    .await
+    else {
+        return;
+    };
+    let id = app.shorten("https://example.com/popular").await;
+    app.state.spawn_workers();
+
+    // the first one waits for the next 09:00
+    let mut queued = None;
+    for _ in 0..50 {
+        queued = sqlx::query_as::<_, (i64, Value)>(
+            "SELECT id, payload FROM jobs WHERE kind = 'digest' AND state = 'pending'",
+        )
+        .fetch_optional(&app.pool)
+        .await
+        .unwrap();
+        if queued.is_some() {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    let (job, payload) = queued.expect("no digest scheduled");
+    let until: chrono::DateTime<chrono::Utc> =
+        serde_json::from_value(payload["until"].clone()).unwrap();
+    assert!(until > chrono::Utc::now());
+    assert_eq!(until.format("%H:%M").to_string(), "09:00");
+
+    let yesterday = until.date_naive().pred_opt().unwrap();
+    sqlx::query("INSERT INTO link_uniques (link_id, day, clicks) VALUES ($1, $2, 7), ($1, $3, 4)")
+        .bind(&id)
+        .bind(yesterday)
+        .bind(yesterday.pred_opt().unwrap())
+        .execute(&app.pool)
+        .await
+        .unwrap();
+    sqlx::query("UPDATE jobs SET run_at = now() WHERE id = $1")
+        .bind(job)
+        .execute(&app.pool)
+        .await
+        .unwrap();
+
+    let (to, data) = tokio::time::timeout(Duration::from_secs(10), accept_mail(relay))
+        .await
+        .expect("no digest mailed");
+    assert_eq!(to, ["ops@example.com"]);
+    assert!(data.contains("From: links@example.com\r\n"), "{}", data);
+    assert!(data.contains(&format!(
+        "Subject: Link digest {} to {}\r\n",
+        yesterday, yesterday
+    )));
+    let text = data
+        .split("Content-Transfer-Encoding: base64\r\n\r\n")
+        .nth(1)
+        .and_then(|part| part.split("--").next())
+        .unwrap()
+        .replace("\r\n", "");
+    use base64::Engine as _;
+    let text = base64::engine::general_purpose::STANDARD
+        .decode(text)
+        .unwrap();
+    let text = String::from_utf8(text).unwrap();
+    assert!(
+        text.contains("Clicks: 7 (+75% on the 1 days before)"),
+        "{}",
+        text
+    );
+    assert!(text.contains(&format!(
+        "- https://sho.rt/{} -> https://example.com/popular: 7",
+        id
+    )));
+
+    // the next one is queued once this one is sent
+    let next: Vec<(Value,)> =
+        sqlx::query_as("SELECT payload FROM jobs WHERE kind = 'digest' AND state = 'pending'")
+            .fetch_all(&app.pool)
+            .await
+            .unwrap();
+    assert_eq!(next.len(), 1);
+    let next: chrono::DateTime<chrono::Utc> =
+        serde_json::from_value(next[0].0["until"].clone()).unwrap();
+    assert_eq!(next, until + chrono::Duration::days(1));
+}
+
+#[tokio::test]
+async fn throttled_click_flushes_write_in_chunks() {
+    let Some(app) = TestApp::spawn_with(|config, db| {
+        // one link right away, then one every 50ms
+        config.click_flush_rate = 20.0;
+        config.click_flush_burst = 1;
+        db
+    })
+    .await
+    else {
+        return;
+    };
+    let mut ids = Vec::new();
+    for n in 0..4 {
+        let id = app
+            .shorten(&format!("https://example.com/paced/{}", n))
+            .await;
+        for _ in 0..=n {
+            app.get(&format!("/{}", id)).await;
+        }
+        ids.push(id);
+    }
+
+    let started = std::time::Instant::now();
+    app.state.shutdown().await.unwrap();
+    assert!(
+        started.elapsed() >= Duration::from_millis(140),
+        "{:?}",
+        started.elapsed()
+    );
+    for (n, id) in ids.iter().enumerate() {
+        let (clicks,): (i64,) = sqlx::query_as("SELECT clicks FROM urls WHERE id = $1")
+            .bind(id)
+            .fetch_one(&app.pool)
+            .await
+            .unwrap();
+        assert_eq!(clicks, n as i64 + 1, "{}", id);
+    }
+}
+
+#[tokio::test]
+async fn configured_domains_ask_to_confirm() {
+    let Some(app) = TestApp::spawn_with(|config, db| {
+        config.confirm_domains = vec!["casino.example".into(), "*.casino.example".into()];
+        db
+    })
+    .await
+    else {
+        return;
+    };
+    // no policy in the table, nor has it been read
+    for url in ["https://casino.example/", "https://www.casino.example/spin"] {
+        let id = app.shorten(url).await;
+        let res = app.get(&format!("/{}", id)).await;
+        assert_eq!(res.status(), StatusCode::OK, "{}", url);
+        assert!(res.headers().get(LOCATION).is_none());
+        let body = res.text().await.unwrap();
+        assert!(body.contains(url), "{}", body);
+        assert!(body.contains("/continue?"), "{}", body);
+    }
+    let id = app.shorten("https://example.com/casino.example").await;
+    let res = app.get(&format!("/{}", id)).await;
+    assert_eq!(res.status(), StatusCode::FOUND);
+    assert_eq!(location(&res), "https://example.com/casino.example");
+
+    // they stay once the table is read
+    let res = app
+        .client
+        .post(format!("{}/api/policies", app.base))
+        .bearer_auth(ADMIN_KEY)
+        .json(&json!({ "pattern": "*.exe" }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::CREATED);
+    let id = app.shorten("https://casino.example/again").await;
+    assert_eq!(app.get(&format!("/{}", id)).await.status(), StatusCode::OK);
+}
+
+/// The keys of a JSON object, sorted.
+fn keys(body: &Value) -> Vec<&str> {
+    let mut keys: Vec<&str> = body
+        .as_object()
+        .unwrap()
+        .keys()
+        .map(String::as_str)
+        .collect();
+    keys.sort();
+    keys
+}
+
+#[tokio::test]
+async fn bodies_keep_their_fields() {
+    let Some(app) = TestApp::spawn().await else {
+        return;
+    };
+    let send = |req: reqwest::RequestBuilder| async move {
+        let res = req.bearer_auth(ADMIN_KEY).send().await.unwrap();
+        let status = res.status();
+        (status, res.json::<Value>().await.unwrap())
+    };
+
+    // typos fail loudly, naming the field
+    let (status, body) = send(
+        app.client
+            .post(&app.base)
+            .json(&json!({ "Url": "https://example.com/" })),
+    )
+    .await;
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+    assert_eq!(body["error"], "invalid_body");
+    assert!(
+        body["message"].as_str().unwrap().contains("`Url`"),
+        "{}",
+        body
+    );
+
+    let (_, body) = send(app.client.post(&app.base).json(&json!({
+        "url": "https://example.com/fields",
+        "tags": ["a"],
+    })))
+    .await;
+    assert_eq!(
+        keys(&body),
+        [
+            "clamped",
+            "created",
+            "created_at",
+            "description",
+            "expires_at",
+            "slugs",
+            "tags",
+            "upgraded",
+            "url"
+        ]
+    );
+    let id = body["slugs"][0].as_str().unwrap().to_string();
+    let (status, body) = send(
+        app.client
+            .patch(format!("{}/{}", app.base, id))
+            .json(&json!({ "enabld": false })),
+    )
+    .await;
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+    assert!(
+        body["message"].as_str().unwrap().contains("`enabld`"),
+        "{}",
+        body
+    );
+
+    let (_, body) = send(app.client.get(format!("{}/{}/info", app.base, id))).await;
+    assert_eq!(keys(&body), ["created_at", "description", "id", "url"]);
+    let (_, body) = send(
+        app.client
+            .get(format!("{}/api/links/{}/stats", app.base, id)),
+    )
+    .await;
+    assert_eq!(
+        keys(&body),
+        [
+            "click_dedup_secs",
+            "clicks",
+            "id",
+            "notes",
+            "slugs",
+            "uniques"
+        ]
+    );
+    let (_, body) = send(
+        app.client
+            .patch(format!("{}/{}/expiry", app.base, id))
+            .json(&json!({ "expires_in_secs": 60 })),
+    )
+    .await;
+    assert_eq!(keys(&body), ["expires_at"]);
+    let (_, body) = send(app.client.get(format!("{}/api/count", app.base))).await;
+    assert_eq!(keys(&body), ["total"]);
+    let (_, body) = send(
+        app.client
+            .post(format!("{}/api/keys", app.base))
+            .json(&json!({ "label": "fields", "scopes": ["read"] })),
+    )
+    .await;
+    assert_eq!(keys(&body), ["id", "key"]);
+}
+
+#[tokio::test]
+async fn camel_case_is_accepted_and_mirrored_in_compat_mode() {
+    let Some(app) = TestApp::spawn_with(|config, db| {
+        config.compat_camel_case = true;
+        db
+    })
+    .await
+    else {
+        return;
+    };
+    let res = app
+        .client
+        .post(&app.base)
+        .json(&json!({ "url": "https://example.com/camel?q=1", "forwardQuery": false }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::CREATED);
+    let body: Value = res.json().await.unwrap();
+    assert_eq!(body["created_at"], body["createdAt"]);
+    assert!(body["created_at"].is_string());
+    let id = app.shorten("https://example.com/camel?q=1").await;
+    assert_eq!(body["slugs"][0], id);
+    let res = app.get(&format!("/{}?utm=x", id)).await;
+    assert_eq!(location(&res), "https://example.com/camel?q=1");
+
+    let res = app
+        .client
+        .patch(format!("{}/{}/expiry", app.base, id))
+        .bearer_auth(ADMIN_KEY)
+        .json(&json!({ "expiresInSecs": null }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    let body: Value = res.json().await.unwrap();
+    assert_eq!(body, json!({ "expires_at": null, "expiresAt": null }));
+
+    // a capitalized key still isn't camelCase, and both forms at once is one too many
+    for typo in [
+        json!({ "Url": "https://example.com/" }),
+        json!({ "url": "https://example.com/", "forwardQuery": false, "forward_query": true }),
+    ] {
+        let res = app.client.post(&app.base).json(&typo).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::UNPROCESSABLE_ENTITY, "{}", typo);
+    }
+}
+
+#[tokio::test]
+async fn reindex_picks_up_rows_written_directly() {
+    let Some(app) = TestApp::spawn_configured(
+        |config| config.slug_filter_refresh = Duration::from_secs(3600),
+        |_, db| db,
+    )
+    .await
+    else {
+        return;
+    };
+    let reindex = || {
+        app.client
+            .post(format!("{}/api/admin/reindex", app.base))
+            .bearer_auth(ADMIN_KEY)
+            .send()
+    };
+    app.shorten("https://example.com/").await;
+    let res = reindex().await.unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    let body: Value = res.json().await.unwrap();
+    assert_eq!(body, json!({ "slugs": 1, "policies": 0 }));
+
+    // written behind the filter's back
+    sqlx::query("INSERT INTO urls (id, url) VALUES ('direct', 'https://example.com/direct')")
+        .execute(&app.pool)
+        .await
+        .unwrap();
+    sqlx::query("INSERT INTO slugs (slug, link_id) VALUES ('direct', 'direct')")
+        .execute(&app.pool)
+        .await
+        .unwrap();
+    sqlx::query("INSERT INTO redirect_policies (pattern) VALUES ('*.example.org')")
+        .execute(&app.pool)
+        .await
+        .unwrap();
+    assert_eq!(app.get("/direct").await.status(), StatusCode::NOT_FOUND);
+
+    let body: Value = reindex().await.unwrap().json().await.unwrap();
+    assert_eq!(body, json!({ "slugs": 2, "policies": 1 }));
+    let res = app.get("/direct").await;
+    assert_eq!(res.status(), StatusCode::FOUND);
+    assert_eq!(location(&res), "https://example.com/direct");
+
+    let key = app.create_key("writer", &["write"]).await;
+    let res = app
+        .client
+        .post(format!("{}/api/admin/reindex", app.base))
+        .bearer_auth(key)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::FORBIDDEN);
+}
+
+#[tokio::test]
+async fn anonymous_links_expire_by_default() {
+    let Some(app) = TestApp::spawn_with(|config, db| {
+        config.anonymous_link_ttl = Duration::from_secs(3600);
+        db
+    })
+    .await
+    else {
+        return;
+    };
+    let key = app.create_key("writer", &["write"]).await;
+    let shorten = |key: Option<&str>, body: Value| {
+        let req = app.client.post(&app.base).json(&body);
+        let req = match key {
+            Some(key) => req.bearer_auth(key),
+            None => req,
+        };
+        async move {
+            let res = req.send().await.unwrap();
+            assert_eq!(res.status(), StatusCode::CREATED);
+            res.json::<Value>().await.unwrap()
+        }
+    };
+    let lives = |body: &Value| {
+        let expires_at: chrono::DateTime<chrono::Utc> =
+            body["expires_at"].as_str()?.parse().unwrap();
+        Some((expires_at - chrono::Utc::now()).num_seconds())
+    };
+
+    // anonymous without an expiry: the default
+    let body = shorten(None, json!({ "url": "https://example.com/a" })).await;
+    assert!((3590..=3600).contains(&lives(&body).unwrap()), "{}", body);
+    assert_eq!(body["clamped"], false);
+    // anonymous asking for longer: cut to the default
+    let body = shorten(
+        None,
+        json!({ "url": "https://example.com/b", "expires_in_secs": 86400 }),
+    )
+    .await;
+    assert!((3590..=3600).contains(&lives(&body).unwrap()), "{}", body);
+    assert_eq!(body["clamped"], true);
+    // anonymous asking for less gets it
+    let body = shorten(
+        None,
+        json!({ "url": "https://example.com/c", "expires_in_secs": 60 }),
+    )
+    .await;
+    assert!((50..=60).contains(&lives(&body).unwrap()), "{}", body);
+    assert_eq!(body["clamped"], false);
+
+    // with a key without an expiry: permanent
+    let body = shorten(Some(&key), json!({ "url": "https://example.com/d" })).await;
+    assert_eq!(body["expires_at"], Value::Null);
+    assert_eq!(body["clamped"], false);
+    // with a key asking for any expiry gets it
+    let body = shorten(
+        Some(&key),
+        json!({ "url": "https://example.com/e", "expires_in_secs": 86400 }),
+    )
+    .await;
+    assert!((86390..=86400).contains(&lives(&body).unwrap()), "{}", body);
+    assert_eq!(body["clamped"], false);
+    let id = body["slugs"][0].as_str().unwrap();
+    let expires_at: Option<chrono::DateTime<chrono::Utc>> =
+        sqlx::query_scalar("SELECT expires_at FROM urls WHERE id = $1")
+            .bind(id)
+            .fetch_one(&app.pool)
+            .await
+            .unwrap();
+    let sent: chrono::DateTime<chrono::Utc> = body["expires_at"].as_str().unwrap().parse().unwrap();
+    assert_eq!(expires_at, Some(sent));
+}
+
+#[tokio::test]
+async fn short_links_carry_the_forwarded_prefix() {
+    let Some(app) = TestApp::spawn_with(|config, db| {
+        config.public_url = Some("http://sho.rt".into());
+        config.trusted_proxies = vec!["127.0.0.1/32".parse().unwrap()];
+        db
+    })
+    .await
+    else {
+        return;
+    };
+    let res = app
+        .client
+        .post(&app.base)
+        .header("x-forwarded-prefix", "/links")
+        .header("x-forwarded-host", "gateway.example")
+        .header("x-forwarded-proto", "https")
+        .json(&json!({ "url": "https://example.com/" }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::CREATED);
+    let body: Value = res.json().await.unwrap();
+    let id = body["slugs"][0].as_str().unwrap();
+    assert_eq!(body["url"], format!("https://gateway.example/links/{}", id));
+
+    let res = app
+        .client
+        .post(&app.base)
+        .header("x-forwarded-prefix", "/links")
+        .json(&json!({ "url": "https://example.com/" }))
+        .send()
+        .await
+        .unwrap();
+    let body: Value = res.json().await.unwrap();
+    assert_eq!(body["url"], format!("http://sho.rt/links/{}", id));
+}
+
+#[tokio::test]
+async fn forwarded_headers_of_untrusted_peers_are_ignored() {
+    let Some(app) = TestApp::spawn_with(|config, db| {
+        config.public_url = Some("http://sho.rt".into());
+        db
+    })
+    .await
+    else {
+        return;
+    };
+    let res = app
+        .client
+        .post(&app.base)
+        .header("x-forwarded-prefix", "/links")
+        .header("x-forwarded-host", "evil.example")
+        .json(&json!({ "url": "https://example.com/" }))
+        .send()
+        .await
+        .unwrap();
+    let body: Value = res.json().await.unwrap();
+    let id = body["slugs"][0].as_str().unwrap();
+    assert_eq!(body["url"], format!("http://sho.rt/{}", id));
+}
+
+#[tokio::test]
+async fn deprecated_routes_are_listed_for_admins() {
+    let Some(app) = TestApp::spawn().await else {
+        return;
+    };
+    let url = format!("{}/api/admin/deprecations", app.base);
+    let res = app.client.get(&url).send().await.unwrap();
+    assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+    let res = app
+        .client
+        .get(&url)
+        .bearer_auth(ADMIN_KEY)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    // nothing is on its way out until /api/v1 lands
+    assert_eq!(res.json::<Value>().await.unwrap(), json!([]));
+    let res = app.post_url("https://example.com/").await;
+    assert!(res.headers().get("deprecation").is_none());
+}
+
+/// A job recording which worker ran it, or failing when `fail` is set.
+#[derive(serde::Serialize, serde::Deserialize)]
+struct Chore {
+    n: u32,
+    fail: bool,
+}
+
+impl Job for Chore {
+    const KIND: &'static str = "e2e_chore";
+}
+
+#[tokio::test]
+async fn workers_claim_each_job_once() {
+    let Some(app) = TestApp::spawn().await else {
+        return;
+    };
+    for n in 0..20 {
+        jobs::enqueue(&app.pool, &Chore { n, fail: false })
+            .await
+            .unwrap();
+    }
+    let runs: Arc<Mutex<Vec<u32>>> = Arc::default();
+    let worker = || {
+        let runs = runs.clone();
+        Worker::new(app.pool.clone(), 3).register(move |chore: Chore| {
+            let runs = runs.clone();
+            async move {
+                // long enough that the two workers overlap
+                tokio::time::sleep(Duration::from_millis(20)).await;
+                runs.lock().unwrap().push(chore.n);
+                Ok(())
+            }
+        })
+    };
+    let drain = |worker: Worker| async move {
+        let mut ran = 0;
+        while worker.run_once().await.unwrap() {
+            ran += 1;
+        }
+        ran
+    };
+    let (first, second) = tokio::join!(drain(worker()), drain(worker()));
+    assert_eq!(first + second, 20);
+    assert!(first > 0 && second > 0, "{} and {}", first, second);
+    let mut runs = runs.lock().unwrap().clone();
+    runs.sort();
+    assert_eq!(runs, (0..20).collect::<Vec<_>>());
+
+    // a failure is retried after the backoff for its attempts so far
+    let id = jobs::enqueue(&app.pool, &Chore { n: 20, fail: true })
+        .await
+        .unwrap();
+    let failing = Worker::new(app.pool.clone(), 3).register(|chore: Chore| async move {
+        if chore.fail {
+            Err("failed on purpose".to_string())
+        } else {
+            Ok(())
+        }
+    });
+    assert!(failing.run_once().await.unwrap());
+    let (state, attempts, error, wait): (String, i32, Option<String>, f64) = sqlx::query_as(
+        "SELECT state, attempts, last_error, EXTRACT(EPOCH FROM run_at - now())::float8
+         FROM jobs WHERE id = $1",
+    )
+    .bind(id)
+    .fetch_one(&app.pool)
+    .await
+    .unwrap();
+    assert_eq!(state, "pending");
+    assert_eq!(attempts, 1);
+    assert_eq!(error.as_deref(), Some("failed on purpose"));
+    let expected = jobs::backoff(1).as_secs_f64();
+    assert!(wait > expected - 1.0 && wait <= expected, "{}", wait);
+    // not due yet, so nothing to claim
+    assert!(!failing.run_once().await.unwrap());
+}
+
+/// Creates a key allowed to shorten, limited to `quota`, returning its id
+/// and secret.
+async fn metered_key(app: &TestApp, quota: Value) -> (i64, String) {
+    let res = app
+        .client
+        .post(format!("{}/api/keys", app.base))
+        .bearer_auth(ADMIN_KEY)
+        .json(&json!({ "label": "metered", "scopes": ["write", "read"] }))
+        .send()
+        .await
+        .unwrap();
+    let body: Value = res.json().await.unwrap();
+    let id = body["id"].as_i64().unwrap();
+    let res = app
+        .client
+        .put(format!("{}/api/keys/{}/quota", app.base, id))
+        .bearer_auth(ADMIN_KEY)
+        .json(&quota)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::NO_CONTENT);
+    (id, body["key"].as_str().unwrap().to_string())
+}
+
+#[tokio::test]
+async fn quotas_count_created_links() {
+    let Some(app) = TestApp::spawn().await else {
+        return;
+    };
+    let (id, key) = metered_key(&app, json!({ "daily_limit": 2, "monthly_limit": 10 })).await;
+    let res = app
+        .client
+        .get(format!("{}/api/keys/{}/quota", app.base, id))
+        .bearer_auth(ADMIN_KEY)
+        .send()
+        .await
+        .unwrap();
+    let quota: Value = res.json().await.unwrap();
+    assert_eq!(quota, json!({ "daily_limit": 2, "monthly_limit": 10 }));
+
+    let shorten = |body: Value| {
+        app.client
+            .post(&app.base)
+            .bearer_auth(&key)
+            .json(&body)
+            .send()
+    };
+    let usage = || async {
+        let res = app
+            .client
+            .get(format!("{}/api/usage", app.base))
+            .bearer_auth(&key)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        res.json::<Value>().await.unwrap()
+    };
+    let res = app
+        .client
+        .post(&app.base)
+        .bearer_auth(ADMIN_KEY)
+        .json(&json!({ "url": "https://example.com/unmetered", "alias": "metered" }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::CREATED);
+    let res = shorten(json!({ "url": "https://example.com/metered" }))
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::CREATED);
+    // neither the existing link nor a refused one counts
+    let res = shorten(json!({ "url": "https://example.com/metered" }))
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    let res = shorten(json!({ "url": "https://example.com/other", "alias": "metered" }))
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::CONFLICT);
+    assert_eq!(usage().await["day"]["count"], 1);
+    let res = shorten(json!({ "url": "https://example.com/other" }))
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::CREATED);
+    let used = usage().await;
+    assert_eq!(used["day"]["count"], 2);
+    assert_eq!(used["day"]["limit"], 2);
+    assert_eq!(used["month"]["count"], 2);
+    assert_eq!(used["month"]["limit"], 10);
+
+    let midnight = (chrono::Utc::now().date_naive() + chrono::Duration::days(1))
+        .and_hms_opt(0, 0, 0)
+        .unwrap()
+        .and_utc();
+    let reset_at = |v: &Value| {
+        chrono::DateTime::parse_from_rfc3339(v.as_str().unwrap())
+            .unwrap()
+            .to_utc()
+    };
+    assert_eq!(reset_at(&used["day"]["reset_at"]), midnight);
+    let res = shorten(json!({ "url": "https://example.com/c" }))
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+    let body: Value = res.json().await.unwrap();
+    assert_eq!(body["error"], "quota_exceeded");
+    assert_eq!(body["period"], "day");
+    assert_eq!(body["limit"], 2);
+    assert_eq!(reset_at(&body["reset_at"]), midnight);
+    assert_eq!(usage().await["day"]["count"], 2);
+}
+
+#[tokio::test]
+async fn quotas_hold_under_concurrent_shortens() {
+    let Some(app) = TestApp::spawn().await else {
+        return;
+    };
+    let (_, key) = metered_key(&app, json!({ "daily_limit": 3 })).await;
+    let shortens = (0..12).map(|i| {
+        app.client
+            .post(&app.base)
+            .bearer_auth(&key)
+            .json(&json!({ "url": format!("https://example.com/race/{}", i) }))
+            .send()
+    });
+    let statuses: Vec<StatusCode> = join_all(shortens)
+        .await
+        .into_iter()
+        .map(|res| res.unwrap().status())
+        .collect();
+    let created = statuses
+        .iter()
+        .filter(|s| **s == StatusCode::CREATED)
+        .count();
+    let refused = statuses
+        .iter()
+        .filter(|s| **s == StatusCode::TOO_MANY_REQUESTS)
+        .count();
+    assert_eq!((created, refused), (3, 9), "{:?}", statuses);
+    let (links,): (i64,) = sqlx::query_as("SELECT COUNT(*) FROM urls WHERE url LIKE '%/race/%'")
+        .fetch_one(&app.pool)
+        .await
+        .unwrap();
+    assert_eq!(links, 3);
+}
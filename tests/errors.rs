@@ -0,0 +1,112 @@
+//! What database errors are answered with.
+
+use std::{borrow::Cow, error::Error, fmt};
+
+use axum::{http::StatusCode, response::IntoResponse};
+use shortener::error::{classify_sql_error, ShortenError};
+use sqlx::error::{DatabaseError, ErrorKind};
+
+/// A Postgres error with SQLSTATE `code`.
+#[derive(Debug)]
+struct Failed {
+    code: &'static str,
+    constraint: Option<&'static str>,
+}
+
+impl fmt::Display for Failed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "error {}", self.code)
+    }
+}
+
+impl Error for Failed {}
+
+impl DatabaseError for Failed {
+    fn message(&self) -> &str {
+        "failed"
+    }
+
+    fn code(&self) -> Option<Cow<'_, str>> {
+        Some(self.code.into())
+    }
+
+    fn constraint(&self) -> Option<&str> {
+        self.constraint
+    }
+
+    fn as_error(&self) -> &(dyn Error + Send + Sync + 'static) {
+        self
+    }
+
+    fn as_error_mut(&mut self) -> &mut (dyn Error + Send + Sync + 'static) {
+        self
+    }
+
+    fn into_error(self: Box<Self>) -> Box<dyn Error + Send + Sync + 'static> {
+        self
+    }
+
+    fn kind(&self) -> ErrorKind {
+        ErrorKind::Other
+    }
+}
+
+fn failed(code: &'static str, constraint: Option<&'static str>) -> sqlx::Error {
+    sqlx::Error::Database(Box::new(Failed { code, constraint }))
+}
+
+fn status(e: sqlx::Error) -> StatusCode {
+    ShortenError::SqlError(e).into_response().status()
+}
+
+#[test]
+fn violations_are_conflicts() {
+    match classify_sql_error(&failed("23505", Some("slugs_pkey"))) {
+        ShortenError::UniqueViolation { constraint } => {
+            assert_eq!(constraint.as_deref(), Some("slugs_pkey"))
+        }
+        other => panic!("{:?}", other),
+    }
+    match classify_sql_error(&failed("23503", None)) {
+        ShortenError::ForeignKeyViolation { constraint } => assert_eq!(constraint, None),
+        other => panic!("{:?}", other),
+    }
+    assert_eq!(status(failed("23505", None)), StatusCode::CONFLICT);
+    assert_eq!(status(failed("23503", None)), StatusCode::CONFLICT);
+}
+
+#[test]
+fn lost_connections_are_unavailable() {
+    for e in [
+        failed("08006", None),
+        failed("53300", None),
+        failed("57P01", None),
+        sqlx::Error::PoolTimedOut,
+        sqlx::Error::PoolClosed,
+        sqlx::Error::Io(std::io::ErrorKind::ConnectionReset.into()),
+    ] {
+        assert!(
+            matches!(classify_sql_error(&e), ShortenError::Unavailable { .. }),
+            "{}",
+            e
+        );
+        let res = ShortenError::SqlError(e).into_response();
+        assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert!(res.headers().contains_key("retry-after"));
+    }
+}
+
+#[test]
+fn anything_else_is_internal() {
+    for e in [
+        failed("23514", None),
+        failed("42P01", None),
+        sqlx::Error::RowNotFound,
+    ] {
+        assert!(matches!(
+            classify_sql_error(&e),
+            ShortenError::StatusCode(_)
+        ));
+        assert_eq!(status(e), StatusCode::INTERNAL_SERVER_ERROR);
+    }
+}
@@ -0,0 +1,73 @@
+//! Building the short links' base from the headers of a proxy.
+
+use axum::http::{HeaderMap, HeaderValue};
+use shortener::forwarded::external_base;
+
+fn headers(pairs: &[(&'static str, &str)]) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    for (name, value) in pairs {
+        headers.append(*name, HeaderValue::from_str(value).unwrap());
+    }
+    headers
+}
+
+#[test]
+fn prefix_host_and_proto_combine() {
+    let base = "http://127.0.0.1:8080";
+    assert_eq!(external_base(&HeaderMap::new(), base), base);
+    assert_eq!(
+        external_base(&headers(&[("x-forwarded-prefix", "/links")]), base),
+        "http://127.0.0.1:8080/links"
+    );
+    assert_eq!(
+        external_base(
+            &headers(&[
+                ("x-forwarded-proto", "https"),
+                ("x-forwarded-host", "sho.rt"),
+                ("x-forwarded-prefix", "/links/"),
+            ]),
+            base
+        ),
+        "https://sho.rt/links"
+    );
+    // the first value is the one the client's side of the chain set
+    assert_eq!(
+        external_base(
+            &headers(&[
+                ("x-forwarded-proto", "HTTPS, http"),
+                ("x-forwarded-host", "sho.rt:8443, internal"),
+            ]),
+            base
+        ),
+        "https://sho.rt:8443"
+    );
+    // the gateway's prefix is the path, whatever PUBLIC_URL nests under
+    assert_eq!(
+        external_base(
+            &headers(&[("x-forwarded-prefix", "/links")]),
+            "https://example.com/s"
+        ),
+        "https://example.com/links"
+    );
+}
+
+#[test]
+fn odd_values_are_ignored() {
+    let base = "https://sho.rt";
+    for (name, value) in [
+        ("x-forwarded-proto", "ftp"),
+        ("x-forwarded-host", "sho.rt/elsewhere"),
+        ("x-forwarded-host", ""),
+        ("x-forwarded-prefix", "links"),
+        ("x-forwarded-prefix", "/links?x=1"),
+        ("x-forwarded-prefix", "/a/../b"),
+    ] {
+        assert_eq!(
+            external_base(&headers(&[(name, value)]), base),
+            base,
+            "{}: {}",
+            name,
+            value
+        );
+    }
+}
@@ -0,0 +1,122 @@
+//! Picking a destination by the visitor's country.
+
+use std::collections::BTreeMap;
+
+use shortener::{
+    geo::{GeoIp, GeoTargets},
+    ShortenError,
+};
+
+fn targets(pairs: &[(&str, &str)]) -> BTreeMap<String, String> {
+    pairs
+        .iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect()
+}
+
+#[test]
+fn countries_are_checked() {
+    let parsed = GeoTargets::parse(targets(&[
+        ("de", "https://example.de/"),
+        ("US", "https://example.com/us"),
+        ("default", "https://example.com/"),
+    ]))
+    .unwrap();
+    assert_eq!(parsed.default, "https://example.com/");
+    assert_eq!(
+        parsed.countries.keys().collect::<Vec<_>>(),
+        ["DE", "US"],
+        "codes are kept in upper case"
+    );
+
+    let err = GeoTargets::parse(targets(&[
+        ("UK", "https://example.co.uk/"),
+        ("USA", "https://example.com/us"),
+        ("GB", "https://example.co.uk/"),
+        ("default", "https://example.com/"),
+    ]))
+    .unwrap_err();
+    let ShortenError::InvalidGeoTargets { invalid, .. } = err else {
+        panic!("{}", err);
+    };
+    assert_eq!(invalid, ["UK", "USA"]);
+
+    let err = GeoTargets::parse(targets(&[("DE", "https://example.de/")])).unwrap_err();
+    assert!(err.to_string().contains("default"), "{}", err);
+}
+
+#[test]
+fn unmatched_countries_go_to_the_default() {
+    let parsed = GeoTargets::parse(targets(&[
+        ("DE", "https://example.de/"),
+        ("default", "https://example.com/"),
+    ]))
+    .unwrap();
+    assert_eq!(parsed.pick(Some("DE")), ("DE", "https://example.de/"));
+    assert_eq!(parsed.pick(Some("FR")), ("default", "https://example.com/"));
+    assert_eq!(parsed.pick(None), ("default", "https://example.com/"));
+}
+
+#[test]
+fn targets_are_stored_as_one_object() {
+    let parsed = GeoTargets::parse(targets(&[
+        ("DE", "https://example.de/"),
+        ("default", "https://example.com/"),
+    ]))
+    .unwrap();
+    let stored = serde_json::to_value(&parsed).unwrap();
+    assert_eq!(
+        stored,
+        serde_json::json!({ "DE": "https://example.de/", "default": "https://example.com/" })
+    );
+    assert_eq!(
+        serde_json::from_value::<GeoTargets>(stored).unwrap(),
+        parsed
+    );
+}
+
+fn database(name: &str, contents: &str) -> std::path::PathBuf {
+    let path = std::env::temp_dir().join(format!("geoip-{}-{}.csv", name, std::process::id()));
+    std::fs::write(&path, contents).unwrap();
+    path
+}
+
+#[test]
+fn addresses_are_looked_up() {
+    let path = database(
+        "lookup",
+        "# network,country\n\
+         81.2.69.0/24,GB\n\
+         \n\
+         2001:db8::/32, de\n\
+         203.0.113.128/25,US\n",
+    );
+    let geoip = GeoIp::load(&path).unwrap();
+    for (ip, expected) in [
+        ("81.2.69.0", Some("GB")),
+        ("81.2.69.255", Some("GB")),
+        ("81.2.70.0", None),
+        ("2001:db8::1", Some("DE")),
+        ("2001:db9::1", None),
+        ("203.0.113.200", Some("US")),
+        ("203.0.113.1", None),
+        ("10.0.0.1", None),
+    ] {
+        assert_eq!(geoip.country(ip.parse().unwrap()), expected, "{}", ip);
+    }
+    std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn bad_lines_are_refused() {
+    for (name, contents, problem) in [
+        ("fields", "81.2.69.0/24\n", ":1: expected network,country"),
+        ("network", "# header\n81.2.69/24,GB\n", ":2: not a network"),
+        ("country", "81.2.69.0/24,XX\n", ":1: not a country code"),
+    ] {
+        let path = database(name, contents);
+        let err = GeoIp::load(&path).unwrap_err();
+        assert!(err.to_string().contains(problem), "{}: {}", name, err);
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,225 @@
+//! Pages for links that don't redirect.
+
+use std::{fs, path::PathBuf};
+
+use axum::http::{
+    header::{ACCEPT, ACCEPT_LANGUAGE, CONTENT_LANGUAGE, CONTENT_TYPE},
+    HeaderMap, StatusCode,
+};
+use shortener::interstitial::{Interstitials, Page};
+
+const BROWSER: &str = "text/html,application/xhtml+xml,application/xml;q=0.9,*/*;q=0.8";
+
+fn dir(name: &str, files: &[(&str, &str)]) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("interstitial-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    for (file, contents) in files {
+        fs::write(dir.join(file), contents).unwrap();
+    }
+    dir
+}
+
+#[test]
+fn negotiates_the_language() {
+    let pages = Interstitials::new(None, "en").unwrap();
+    for (accept_language, expected) in [
+        ("zh-CN,zh;q=0.9,en;q=0.8", "zh"),
+        ("es-MX", "es"),
+        ("fr-FR, es;q=0.5, en;q=0.7", "en"),
+        ("de;q=1.0, es;q=0.1", "es"),
+        ("en;q=0, zh;q=0.2", "zh"),
+        ("fr, *", "en"),
+        ("", "en"),
+    ] {
+        assert_eq!(
+            pages.language([accept_language].into_iter()),
+            expected,
+            "{}",
+            accept_language
+        );
+    }
+    let pages = Interstitials::new(None, "ES").unwrap();
+    assert_eq!(pages.language(["fr"].into_iter()), "es");
+}
+
+#[test]
+fn only_browsers_get_pages() {
+    let pages = Interstitials::new(None, "en").unwrap();
+    for accept in [
+        "",
+        "*/*",
+        "application/json",
+        "application/json, text/html;q=0.5",
+    ] {
+        let mut headers = HeaderMap::new();
+        headers.insert(ACCEPT, accept.parse().unwrap());
+        assert!(
+            pages
+                .respond(Page::Expired, StatusCode::GONE, "abc", &headers)
+                .is_none(),
+            "{}",
+            accept
+        );
+    }
+    let mut headers = HeaderMap::new();
+    headers.insert(ACCEPT, BROWSER.parse().unwrap());
+    headers.insert(ACCEPT_LANGUAGE, "zh-TW".parse().unwrap());
+    let res = pages
+        .respond(Page::Expired, StatusCode::GONE, "abc", &headers)
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::GONE);
+    assert_eq!(res.headers()[CONTENT_TYPE], "text/html; charset=utf-8");
+    assert_eq!(res.headers()[CONTENT_LANGUAGE], "zh");
+}
+
+#[test]
+fn renders_each_outcome() {
+    let pages = Interstitials::new(None, "en").unwrap();
+    let page = pages.render(Page::Expired, StatusCode::GONE, "en").unwrap();
+    assert!(page.contains("<html lang=\"en\">"));
+    assert!(page.contains("<title>Link expired</title>"));
+    assert!(page.contains("class=\"expired\""));
+    for language in ["en", "es", "zh"] {
+        for outcome in [Page::NotFound, Page::Expired, Page::Disabled] {
+            let page = pages
+                .render(outcome, StatusCode::NOT_FOUND, language)
+                .unwrap();
+            assert!(!page.contains("{{"), "{} {:?}", language, outcome);
+        }
+    }
+}
+
+#[test]
+fn overrides_layout_and_texts() {
+    let dir = dir(
+        "override",
+        &[
+            (
+                "layout.html",
+                "<p class=\"{{ outcome }}\">{{title}}: {{message}} ({{status}})</p>",
+            ),
+            ("en.toml", "[expired]\ntitle = \"Gone <for good>\"\n"),
+            ("fr.toml", "[expired]\ntitle = \"Lien expiré\"\n"),
+        ],
+    );
+    let pages = Interstitials::new(Some(&dir), "en").unwrap();
+    assert_eq!(
+        pages.render(Page::Expired, StatusCode::GONE, "en").unwrap(),
+        "<p class=\"expired\">Gone &lt;for good&gt;: \
+         This short link has expired and no longer leads anywhere. (410)</p>"
+    );
+    // missing texts come from the default language
+    assert_eq!(pages.language(["fr-CA"].into_iter()), "fr");
+    assert!(pages
+        .render(Page::Expired, StatusCode::GONE, "fr")
+        .unwrap()
+        .starts_with("<p class=\"expired\">Lien expiré: This short link has expired"));
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn broken_layouts_fall_back() {
+    for (name, layout) in [
+        ("unknown", "<h1>{{title}}</h1>{{password}}"),
+        ("unclosed", "<h1>{{title</h1>"),
+    ] {
+        let dir = dir(name, &[("layout.html", layout)]);
+        let pages = Interstitials::new(Some(&dir), "en").unwrap();
+        assert!(pages
+            .render(Page::NotFound, StatusCode::NOT_FOUND, "en")
+            .is_err());
+        let mut headers = HeaderMap::new();
+        headers.insert(ACCEPT, BROWSER.parse().unwrap());
+        let res = pages
+            .respond(Page::NotFound, StatusCode::NOT_FOUND, "abc", &headers)
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
+
+/// The miss page a browser gets for `id`.
+async fn miss_page(pages: &Interstitials, id: &str) -> String {
+    let mut headers = HeaderMap::new();
+    headers.insert(ACCEPT, BROWSER.parse().unwrap());
+    let res = pages
+        .respond(Page::NotFound, StatusCode::NOT_FOUND, id, &headers)
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::NOT_FOUND);
+    let body = axum::body::to_bytes(res.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    String::from_utf8(body.to_vec()).unwrap()
+}
+
+#[tokio::test]
+async fn misses_use_the_template() {
+    let dir = dir(
+        "miss",
+        &[(
+            "miss.html",
+            "<p>{{title}}: {{id}}</p><a href=\"{{homepage}}\">home</a>",
+        )],
+    );
+    let pages = Interstitials::new(None, "en")
+        .unwrap()
+        .with_miss_template(Some(&dir.join("miss.html")), None);
+    assert_eq!(
+        miss_page(&pages, "<abc>").await,
+        "<p>Link not found: &lt;abc&gt;</p><a href=\"./\">home</a>"
+    );
+    let pages = pages.with_miss_template(None, Some("https://example.com/"));
+    assert!(miss_page(&pages, "abc")
+        .await
+        .contains("href=\"https://example.com/\""));
+
+    // an unreadable template leaves the layout
+    let pages = Interstitials::new(None, "en")
+        .unwrap()
+        .with_miss_template(Some(&dir.join("gone.html")), None);
+    assert!(miss_page(&pages, "abc")
+        .await
+        .contains("<title>Link not found</title>"));
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn refuses_bad_configuration() {
+    assert!(Interstitials::new(None, "fr").is_err());
+    let dir = dir("invalid", &[("es.toml", "[expired\n")]);
+    assert!(Interstitials::new(Some(&dir), "en").is_err());
+    fs::remove_dir_all(&dir).unwrap();
+    assert!(Interstitials::new(Some(&dir), "en").is_err());
+}
+
+#[test]
+fn confirm_page_links_on() {
+    let pages = Interstitials::new(None, "en").unwrap();
+    let page = pages
+        .render_confirm(
+            "es",
+            "https://example.com/?a=1&b=<2>",
+            "./abc/continue?exp=1&sig=ff",
+        )
+        .unwrap();
+    assert!(page.contains("<html lang=\"es\">"));
+    assert!(page.contains("<title>Estás saliendo de este sitio</title>"));
+    assert!(page.contains("https://example.com/?a=1&amp;b=&lt;2&gt;"));
+    assert!(page.contains("href=\"./abc/continue?exp=1&amp;sig=ff\""));
+    assert!(page.contains(">Continuar</a>"));
+
+    let mut headers = HeaderMap::new();
+    headers.insert(ACCEPT, "application/json".parse().unwrap());
+    let res = pages.confirm(&headers, "https://example.com/", "./abc/continue");
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(res.headers()[CONTENT_TYPE], "text/html; charset=utf-8");
+
+    // the button text is the confirm page's own
+    let dir = dir("confirm", &[("layout.html", "{{continue}}")]);
+    let pages = Interstitials::new(Some(&dir), "en").unwrap();
+    assert!(pages
+        .render(Page::NotFound, StatusCode::NOT_FOUND, "en")
+        .is_err());
+    fs::remove_dir_all(dir).unwrap();
+}
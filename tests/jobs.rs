@@ -0,0 +1,24 @@
+//! Scheduling retries of failed jobs.
+
+use std::time::Duration;
+
+use shortener::jobs::backoff;
+
+#[test]
+fn retries_back_off_exponentially() {
+    let delays: Vec<u64> = (1..=6)
+        .map(|attempts| backoff(attempts).as_secs())
+        .collect();
+    assert_eq!(delays, [2, 4, 8, 16, 32, 64]);
+    // a job claimed without a recorded attempt waits as long as after one
+    assert_eq!(backoff(0), backoff(1));
+}
+
+#[test]
+fn backoff_is_capped_at_an_hour() {
+    let hour = Duration::from_secs(60 * 60);
+    assert_eq!(backoff(12), hour);
+    assert_eq!(backoff(40), hour);
+    assert_eq!(backoff(u32::MAX), hour);
+    assert!(backoff(11) < hour);
+}
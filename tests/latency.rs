@@ -0,0 +1,79 @@
+use std::time::{Duration, Instant};
+
+use shortener::latency::{LatencyTracker, SLOT};
+
+const WINDOW: Duration = Duration::from_secs(5 * 60);
+
+fn ms(ms: u64) -> Duration {
+    Duration::from_millis(ms)
+}
+
+fn close(actual: Option<f64>, expected: f64) -> bool {
+    actual.is_some_and(|actual| (actual - expected).abs() <= expected * 0.1)
+}
+
+#[test]
+fn percentiles_come_within_a_tenth() {
+    let tracker = LatencyTracker::new(10, WINDOW);
+    let now = Instant::now();
+    for i in 1..=100 {
+        tracker.record_at("abc", ms(i), now);
+    }
+    let p = tracker.percentiles_at("abc", WINDOW, now).unwrap();
+    assert_eq!(p.samples, 100);
+    assert!(close(p.p50_ms, 50.0), "{:?}", p);
+    assert!(close(p.p95_ms, 95.0), "{:?}", p);
+    assert!(close(p.p99_ms, 99.0), "{:?}", p);
+}
+
+#[test]
+fn untracked_and_quiet_links() {
+    let tracker = LatencyTracker::new(10, WINDOW);
+    assert_eq!(tracker.percentiles("abc", WINDOW), None);
+    let now = Instant::now();
+    tracker.record_at("abc", ms(5), now);
+    let later = now + WINDOW + SLOT;
+    let p = tracker.percentiles_at("abc", WINDOW, later).unwrap();
+    assert_eq!(p.samples, 0);
+    assert_eq!(p.p50_ms, None);
+}
+
+#[test]
+fn old_slots_slide_out_of_the_window() {
+    let tracker = LatencyTracker::new(10, WINDOW);
+    let now = Instant::now();
+    tracker.record_at("abc", ms(500), now);
+    let later = now + 2 * SLOT;
+    tracker.record_at("abc", ms(5), later);
+    let all = tracker.percentiles_at("abc", WINDOW, later).unwrap();
+    assert_eq!(all.samples, 2);
+    assert!(close(all.p99_ms, 500.0), "{:?}", all);
+    let last_minute = tracker.percentiles_at("abc", SLOT, later).unwrap();
+    assert_eq!(last_minute.samples, 1);
+    assert!(close(last_minute.p99_ms, 5.0), "{:?}", last_minute);
+}
+
+#[test]
+fn memory_is_bounded_by_the_tracked_links() {
+    let tracker = LatencyTracker::new(4, WINDOW);
+    let now = Instant::now();
+    for i in 0..4 {
+        for _ in 0..=i {
+            assert!(tracker.record_at(&format!("busy{}", i), ms(1), now));
+        }
+    }
+    for i in 0..1000 {
+        assert!(!tracker.record_at(&format!("cold{}", i), ms(1), now));
+    }
+    assert_eq!(tracker.len(), 4);
+
+    // full, so the quietest make room
+    tracker.evict_at(now + SLOT);
+    assert_eq!(tracker.len(), 3);
+    assert_eq!(tracker.percentiles_at("busy0", WINDOW, now), None);
+    assert!(tracker.record_at("new", ms(1), now + SLOT));
+
+    // and after a window without traffic, every link goes
+    tracker.evict_at(now + 2 * WINDOW);
+    assert!(tracker.is_empty());
+}
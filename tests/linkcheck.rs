@@ -0,0 +1,67 @@
+//! Checking whether destinations still answer.
+
+use std::{future::IntoFuture, time::Duration};
+
+use axum::{http::StatusCode, routing::get, Router};
+use shortener::{fetch::Fetcher, linkcheck::check};
+use tokio::net::TcpListener;
+
+/// Serves `/ok`, and `/gone` answering 404.
+async fn destination() -> String {
+    let app = Router::new()
+        .route("/ok", get(|| async { "here" }))
+        .route("/gone", get(|| async { StatusCode::NOT_FOUND }));
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let base = format!("http://{}", listener.local_addr().unwrap());
+    tokio::spawn(axum::serve(listener, app).into_future());
+    base
+}
+
+/// An address nothing listens on.
+async fn unreachable() -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    format!("http://{}", listener.local_addr().unwrap())
+}
+
+fn loopback() -> Fetcher {
+    Fetcher::allowing(vec!["127.0.0.0/8".parse().unwrap()])
+}
+
+#[tokio::test]
+async fn reports_each_status_in_order() {
+    let base = destination().await;
+    let dead = unreachable().await;
+    let links = vec![
+        ("a".to_string(), format!("{}/ok", base)),
+        ("b".to_string(), format!("{}/ok", dead)),
+        ("c".to_string(), format!("{}/gone", base)),
+        ("d".to_string(), "not a url".to_string()),
+    ];
+    let checked = check(&loopback(), links, 2, Duration::from_secs(5)).await;
+    let got: Vec<(&str, Option<u16>, bool)> = checked
+        .iter()
+        .map(|c| (c.id.as_str(), c.status, c.error.is_some()))
+        .collect();
+    assert_eq!(
+        got,
+        [
+            ("a", Some(200), false),
+            ("b", None, true),
+            ("c", Some(404), false),
+            ("d", None, true),
+        ]
+    );
+}
+
+#[tokio::test]
+async fn private_destinations_are_not_reached() {
+    let base = destination().await;
+    let links = vec![("a".to_string(), format!("{}/ok", base))];
+    let checked = check(&Fetcher::new(), links, 1, Duration::from_secs(5)).await;
+    assert_eq!(checked[0].status, None);
+    assert!(checked[0]
+        .error
+        .as_deref()
+        .unwrap()
+        .contains("non-public address"));
+}
@@ -0,0 +1,160 @@
+//! What the request log keeps of redirects and urls.
+
+use std::{
+    io::Write,
+    sync::{Arc, Mutex},
+};
+
+use axum::{
+    body::Body,
+    extract::{Path, Request},
+    http::{header::LOCATION, StatusCode},
+    middleware,
+    response::IntoResponse as _,
+    routing::{get, post},
+    Router,
+};
+use shortener::{config::Config, logging};
+use tower::ServiceExt as _;
+use tower_http::{
+    request_id::{MakeRequestUuid, PropagateRequestIdLayer, SetRequestIdLayer},
+    trace::TraceLayer,
+};
+use tracing::info;
+use tracing_subscriber::fmt::MakeWriter;
+
+/// Everything logged, to search.
+#[derive(Clone, Default)]
+struct Captured(Arc<Mutex<Vec<u8>>>);
+
+impl Captured {
+    fn text(&self) -> String {
+        String::from_utf8_lossy(&self.0.lock().unwrap()).into_owned()
+    }
+}
+
+impl Write for Captured {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<'a> MakeWriter<'a> for Captured {
+    type Writer = Self;
+
+    fn make_writer(&'a self) -> Self {
+        self.clone()
+    }
+}
+
+fn settings(sample: u32, redact: bool) -> logging::Logging {
+    let mut config = Config::from_env().expect("invalid config in environment");
+    config.log_redirect_sample = sample;
+    config.log_redact_queries = redact;
+    logging::Logging::new(&config)
+}
+
+/// `/:id` redirects, and 404s for `missing`, like the redirect route.
+/// `POST /` logs the url it's sent, like shortening.
+fn app(logging: logging::Logging) -> Router {
+    let redirect = get(|Path(id): Path<String>| async move {
+        match id.as_str() {
+            "missing" => StatusCode::NOT_FOUND.into_response(),
+            _ => (StatusCode::FOUND, [(LOCATION, "https://example.com/")]).into_response(),
+        }
+    })
+    .layer(middleware::map_response(logging::sampled));
+    Router::new()
+        .route(
+            "/",
+            post(move |url: String| async move {
+                info!("Shortened {} as abc", logging.url(&url));
+                StatusCode::CREATED
+            }),
+        )
+        .route("/:id", redirect)
+        .layer(PropagateRequestIdLayer::x_request_id())
+        .layer(
+            TraceLayer::new_for_http()
+                .make_span_with(logging.spans(Vec::new()))
+                .on_response(logging),
+        )
+        .layer(SetRequestIdLayer::x_request_id(MakeRequestUuid))
+}
+
+/// Sends `requests` through the app, returning what was logged.
+async fn logged(logging: logging::Logging, requests: Vec<Request>) -> String {
+    let captured = Captured::default();
+    let subscriber = tracing_subscriber::fmt()
+        .with_writer(captured.clone())
+        .with_ansi(false)
+        .finish();
+    let _default = tracing::subscriber::set_default(subscriber);
+    for req in requests {
+        app(logging).oneshot(req).await.unwrap();
+    }
+    captured.text()
+}
+
+fn get_req(uri: &str) -> Request {
+    Request::get(uri).body(Body::empty()).unwrap()
+}
+
+fn post_req(url: &str) -> Request {
+    Request::post("/")
+        .body(Body::from(url.to_string()))
+        .unwrap()
+}
+
+#[tokio::test]
+async fn redacted_urls_leave_out_their_tokens() {
+    let log = logged(
+        settings(1, true),
+        vec![
+            get_req("/abc?token=secret"),
+            get_req("/missing?token=secret"),
+            post_req("https://example.com/login?token=secret#token=secret"),
+        ],
+    )
+    .await;
+    assert!(log.contains("/abc"), "{}", log);
+    assert!(log.contains("/missing"), "{}", log);
+    assert!(log.contains("https://example.com/login"), "{}", log);
+    assert!(!log.contains("secret"), "{}", log);
+
+    let log = logged(settings(1, false), vec![get_req("/abc?token=secret")]).await;
+    assert!(log.contains("/abc?token=secret"), "{}", log);
+}
+
+#[tokio::test]
+async fn successful_redirects_are_sampled() {
+    let requests = (0..200).map(|_| get_req("/abc")).collect();
+    let log = logged(settings(10, false), requests).await;
+    let finished = log.matches("finished processing request").count();
+    assert!((1..100).contains(&finished), "{} of 200 logged", finished);
+
+    // failures and other routes are all logged
+    let mut requests: Vec<Request> = (0..20).map(|_| get_req("/missing")).collect();
+    requests.extend((0..20).map(|_| post_req("https://example.com/")));
+    let log = logged(settings(1000, false), requests).await;
+    assert_eq!(log.matches("finished processing request").count(), 40);
+}
+
+#[test]
+fn sampling_follows_the_request_id() {
+    let logging = settings(4, false);
+    let ids: Vec<String> = (0..1000).map(|i| format!("request-{}", i)).collect();
+    let kept: Vec<bool> = ids.iter().map(|id| logging.keeps(id)).collect();
+    assert_eq!(
+        kept,
+        ids.iter().map(|id| logging.keeps(id)).collect::<Vec<_>>()
+    );
+    let count = kept.iter().filter(|&&k| k).count();
+    assert!((150..350).contains(&count), "{} of 1000 kept", count);
+    assert!(ids.iter().all(|id| settings(1, false).keeps(id)));
+}
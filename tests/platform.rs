@@ -0,0 +1,66 @@
+//! Picking a platform from the `User-Agent`.
+
+use shortener::platform::{Platform, PlatformTargets};
+
+const IOS: &[&str] = &[
+    "Mozilla/5.0 (iPhone; CPU iPhone OS 17_4 like Mac OS X) AppleWebKit/605.1.15 (KHTML, like Gecko) Version/17.4 Mobile/15E148 Safari/604.1",
+    "Mozilla/5.0 (iPad; CPU OS 16_6 like Mac OS X) AppleWebKit/605.1.15 (KHTML, like Gecko) CriOS/120.0.6099.119 Mobile/15E148 Safari/604.1",
+    "Mozilla/5.0 (iPod touch; CPU iPhone OS 15_7 like Mac OS X) AppleWebKit/605.1.15 (KHTML, like Gecko) Version/15.6 Mobile/15E148 Safari/604.1",
+    "Mozilla/5.0 (iPhone; CPU iPhone OS 17_1 like Mac OS X) AppleWebKit/605.1.15 (KHTML, like Gecko) Mobile/15E148 Instagram 312.0.0.32.112",
+];
+
+const ANDROID: &[&str] = &[
+    "Mozilla/5.0 (Linux; Android 14; Pixel 8) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/124.0.0.0 Mobile Safari/537.36",
+    "Mozilla/5.0 (Linux; Android 13; SM-S918B) AppleWebKit/537.36 (KHTML, like Gecko) SamsungBrowser/23.0 Chrome/115.0.0.0 Mobile Safari/537.36",
+    "Mozilla/5.0 (Android 14; Mobile; rv:125.0) Gecko/125.0 Firefox/125.0",
+    "Dalvik/2.1.0 (Linux; U; Android 12; M2101K6G Build/SKQ1.210908.001)",
+];
+
+const OTHER: &[&str] = &[
+    "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/124.0.0.0 Safari/537.36",
+    "Mozilla/5.0 (Macintosh; Intel Mac OS X 14_4) AppleWebKit/605.1.15 (KHTML, like Gecko) Version/17.4 Safari/605.1.15",
+    "Mozilla/5.0 (X11; Linux x86_64; rv:125.0) Gecko/20100101 Firefox/125.0",
+    "Mozilla/5.0 (Windows Phone 10.0; Android 6.0.1; Microsoft; Lumia 950) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/52.0.2743.116 Mobile Safari/537.36 Edge/15.15063",
+    "curl/8.5.0",
+    "",
+];
+
+#[test]
+fn common_agents() {
+    for (agents, expected) in [
+        (IOS, Platform::Ios),
+        (ANDROID, Platform::Android),
+        (OTHER, Platform::Other),
+    ] {
+        for agent in agents {
+            assert_eq!(Platform::detect(agent), expected, "{}", agent);
+        }
+    }
+}
+
+#[test]
+fn targets_fall_back_to_default() {
+    let targets = PlatformTargets {
+        ios: Some("https://apps.apple.com/app/id1".into()),
+        android: None,
+        default: Some("https://example.com/web".into()),
+    };
+    assert_eq!(
+        targets.pick(Platform::Ios),
+        Some("https://apps.apple.com/app/id1")
+    );
+    assert_eq!(
+        targets.pick(Platform::Android),
+        Some("https://example.com/web")
+    );
+    assert_eq!(
+        targets.pick(Platform::Other),
+        Some("https://example.com/web")
+    );
+
+    let ios_only = PlatformTargets {
+        default: None,
+        ..targets
+    };
+    assert_eq!(ios_only.pick(Platform::Other), None);
+}
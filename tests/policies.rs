@@ -0,0 +1,96 @@
+//! Which redirects are confirmed first, and the urls that skip past it.
+
+use shortener::policies::{is_valid_pattern, Continuations, Rules, CONTINUE_TTL};
+use url::Url;
+
+#[test]
+fn rules_match_links_hosts_and_paths() {
+    let rules = Rules::new(
+        ["abc123"],
+        ["*.dropbox.com", "files.example.org", "*/*.EXE"],
+    );
+    assert!(rules.applies("abc123", "https://www.rust-lang.org/"));
+    for url in [
+        "https://www.dropbox.com/s/x",
+        "https://dl.DROPBOX.com./s/x",
+        "http://files.example.org/report.pdf",
+        "https://cdn.example.net/setup.exe",
+        "https://cdn.example.net/a/b/Setup.exe",
+    ] {
+        assert!(rules.applies("other", url), "{}", url);
+    }
+    for url in [
+        "https://dropbox.com/s/x",
+        "https://www.dropbox.com.evil.example/",
+        "https://example.org/files.example.org",
+        "https://cdn.example.net/setup.exe.html",
+        "https://cdn.example.net/?download=setup.exe",
+        "not a url",
+    ] {
+        assert!(!rules.applies("other", url), "{}", url);
+    }
+    assert!(Rules::default().is_empty());
+    assert!(!Rules::default().applies("abc123", "https://example.com/"));
+}
+
+#[test]
+fn exceptions_carve_out_an_allowlist() {
+    let rules = Rules::new([], ["*", "!corp.example", "!*.corp.example"]);
+    assert!(rules.applies("x", "https://example.com/"));
+    assert!(!rules.applies("x", "https://corp.example/"));
+    assert!(!rules.applies("x", "https://wiki.corp.example/page"));
+    // exceptions alone confirm nothing
+    let rules = Rules::new([], ["!corp.example"]);
+    assert!(!rules.applies("x", "https://example.com/"));
+}
+
+#[test]
+fn validates_patterns() {
+    for pattern in ["example.com", "*.example.com", "!*.corp.example", "*/*.zip"] {
+        assert!(is_valid_pattern(pattern), "{}", pattern);
+    }
+    for pattern in [
+        "",
+        "!",
+        "!!example.com",
+        "https://example.com",
+        "exa mple.com",
+    ] {
+        assert!(!is_valid_pattern(pattern), "{}", pattern);
+    }
+    // left out rather than matching everything
+    assert!(Rules::new([], ["", "https://*"]).is_empty());
+}
+
+#[test]
+fn continuations_verify_only_their_fields() {
+    let continuations = Continuations::new(Some("secret"));
+    let now = 1_700_000_000;
+    let url = continuations.url("abc123", Some("utm=a&b=c"), now);
+    assert!(url.starts_with("./abc123/continue?"), "{}", url);
+    let page = Url::parse("https://sho.rt/s/abc123").unwrap();
+    let url = page.join(&url).unwrap();
+    assert_eq!(url.path(), "/s/abc123/continue");
+    let param = |name: &str| {
+        url.query_pairs()
+            .find(|(k, _)| k == name)
+            .map(|(_, v)| v.into_owned())
+    };
+    let (exp, sig) = (
+        param("exp").unwrap().parse().unwrap(),
+        param("sig").unwrap(),
+    );
+    let q = param("q");
+    assert_eq!(q.as_deref(), Some("utm=a&b=c"));
+    assert!(continuations.verify("abc123", q.as_deref(), exp, &sig, now));
+
+    assert!(!continuations.verify("abc124", q.as_deref(), exp, &sig, now));
+    assert!(!continuations.verify("abc123", None, exp, &sig, now));
+    assert!(!continuations.verify("abc123", q.as_deref(), exp + 1, &sig, now));
+    assert!(!continuations.verify("abc123", q.as_deref(), exp, &sig[1..], now));
+    let later = now + CONTINUE_TTL.as_secs() as i64 + 1;
+    assert!(!continuations.verify("abc123", q.as_deref(), exp, &sig, later));
+    // another key, or a made up one, doesn't take it
+    assert!(!Continuations::new(Some("other")).verify("abc123", q.as_deref(), exp, &sig, now));
+    assert!(!Continuations::new(None).verify("abc123", q.as_deref(), exp, &sig, now));
+}
@@ -0,0 +1,76 @@
+use shortener::qr::{QrCode, DEFAULT_SCALE, MAX_SCALE};
+
+fn finder_at(code: &QrCode, left: usize, top: usize) -> bool {
+    (0..7).all(|y| {
+        (0..7).all(|x| {
+            let ring = x.min(y).min(6 - x).min(6 - y);
+            code.is_dark(left + x, top + y) == (ring != 1)
+        })
+    })
+}
+
+#[test]
+fn short_urls_fit_the_smallest_version() {
+    let code = QrCode::encode("https://sho.rt/abc123").unwrap();
+    assert_eq!(code.size(), 25);
+    let code = QrCode::encode("http://s.io/ab").unwrap();
+    assert_eq!(code.size(), 21);
+}
+
+#[test]
+fn codes_grow_with_the_text() {
+    let long = "x".repeat(100);
+    // 100 bytes needs version 6 at level M
+    assert_eq!(QrCode::encode(&long).unwrap().size(), 41);
+    assert_eq!(QrCode::encode(&"x".repeat(213)).unwrap().size(), 57);
+    assert_eq!(QrCode::encode(&"x".repeat(214)), None);
+}
+
+#[test]
+fn finder_patterns_sit_in_three_corners() {
+    let code = QrCode::encode("http://localhost:8080/abc123").unwrap();
+    let far = code.size() - 7;
+    assert!(finder_at(&code, 0, 0));
+    assert!(finder_at(&code, far, 0));
+    assert!(finder_at(&code, 0, far));
+    assert!(!finder_at(&code, far, far));
+    // timing pattern between the top two
+    for x in 8..far - 1 {
+        assert_eq!(code.is_dark(x, 6), x % 2 == 0, "{}", x);
+    }
+}
+
+#[test]
+fn encoding_is_deterministic() {
+    let url = "http://localhost:8080/abc123";
+    assert_eq!(QrCode::encode(url), QrCode::encode(url));
+    assert_ne!(
+        QrCode::encode(url),
+        QrCode::encode("http://localhost:8080/abc124")
+    );
+}
+
+#[test]
+fn pngs_are_scaled_with_a_quiet_zone() {
+    let code = QrCode::encode("http://s.io/ab").unwrap();
+    let png = code.png(DEFAULT_SCALE);
+    assert!(png.starts_with(b"\x89PNG\r\n\x1a\n"));
+    assert_eq!(&png[12..16], b"IHDR");
+    let width = u32::from_be_bytes(png[16..20].try_into().unwrap());
+    let height = u32::from_be_bytes(png[20..24].try_into().unwrap());
+    assert_eq!(
+        (width, height),
+        ((21 + 8) * DEFAULT_SCALE, (21 + 8) * DEFAULT_SCALE)
+    );
+    // 8 bit grayscale
+    assert_eq!(&png[24..26], &[8, 0]);
+    assert!(png.ends_with(b"IEND\xae\x42\x60\x82"));
+}
+
+#[test]
+fn scales_are_clamped() {
+    let code = QrCode::encode("http://s.io/ab").unwrap();
+    let width = |png: Vec<u8>| u32::from_be_bytes(png[16..20].try_into().unwrap());
+    assert_eq!(width(code.png(0)), 29);
+    assert_eq!(width(code.png(1000)), 29 * MAX_SCALE);
+}
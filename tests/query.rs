@@ -0,0 +1,89 @@
+//! Merging a short link's query string into its destination's.
+
+use shortener::query::{
+    merge,
+    QueryPrecedence::{Destination, Incoming},
+};
+
+#[test]
+fn appends_to_destinations_without_a_query() {
+    for precedence in [Incoming, Destination] {
+        assert_eq!(
+            merge("https://example.com/page", "ref=mail", precedence),
+            "https://example.com/page?ref=mail"
+        );
+        // nothing to add leaves the destination alone
+        assert_eq!(
+            merge("https://example.com/page?a=1", "", precedence),
+            "https://example.com/page?a=1"
+        );
+        assert_eq!(
+            merge("https://example.com/page", "&&", precedence),
+            "https://example.com/page"
+        );
+    }
+}
+
+#[test]
+fn merges_with_an_existing_query() {
+    let destination = "https://example.com/page?utm_source=site&id=7";
+    assert_eq!(
+        merge(destination, "utm_source=mail&ref=x", Incoming),
+        "https://example.com/page?id=7&utm_source=mail&ref=x"
+    );
+    assert_eq!(
+        merge(destination, "utm_source=mail&ref=x", Destination),
+        "https://example.com/page?utm_source=site&id=7&ref=x"
+    );
+    // names are compared decoded
+    assert_eq!(
+        merge(destination, "utm%5Fsource=mail", Destination),
+        destination
+    );
+    // a trailing `?` is an empty query
+    assert_eq!(
+        merge("https://example.com/page?", "ref=x", Destination),
+        "https://example.com/page?ref=x"
+    );
+}
+
+#[test]
+fn keeps_encoded_ampersands_in_values() {
+    assert_eq!(
+        merge("https://example.com/?q=a%26b", "tag=x%26y&q=c", Destination),
+        "https://example.com/?q=a%26b&tag=x%26y"
+    );
+    assert_eq!(
+        merge("https://example.com/?q=a%26b", "tag=x%26y&q=c", Incoming),
+        "https://example.com/?tag=x%26y&q=c"
+    );
+}
+
+#[test]
+fn keeps_empty_values() {
+    assert_eq!(
+        merge("https://example.com/?a=", "b=&c", Destination),
+        "https://example.com/?a=&b=&c"
+    );
+    // an empty incoming value still wins, and an empty one is still kept
+    assert_eq!(
+        merge("https://example.com/?a=1", "a=", Incoming),
+        "https://example.com/?a="
+    );
+    assert_eq!(
+        merge("https://example.com/?a=", "a=1", Destination),
+        "https://example.com/?a="
+    );
+}
+
+#[test]
+fn keeps_the_fragment_last() {
+    assert_eq!(
+        merge("https://example.com/docs?v=2#install", "ref=x", Destination),
+        "https://example.com/docs?v=2&ref=x#install"
+    );
+    assert_eq!(
+        merge("https://example.com/docs#install", "v=3", Incoming),
+        "https://example.com/docs?v=3#install"
+    );
+}
@@ -0,0 +1,118 @@
+//! What malformed request bodies are answered with.
+
+use axum::{
+    body::{to_bytes, Body},
+    extract::{FromRequest, Request},
+    http::{header::CONTENT_TYPE, StatusCode},
+    response::IntoResponse,
+};
+use serde::Deserialize;
+use serde_json::Value;
+use shortener::error::AppJson;
+
+#[derive(Debug, Deserialize)]
+struct Link {
+    url: String,
+    clicks: u32,
+}
+
+#[derive(Debug, Deserialize)]
+struct Req {
+    link: Link,
+    tags: Vec<String>,
+}
+
+/// Extracts `body`, sent as `content_type`, returning the status and body
+/// of the rejection.
+async fn reject(content_type: Option<&str>, body: impl Into<Body>) -> (StatusCode, Value) {
+    let mut req = Request::builder().method("POST").uri("/");
+    if let Some(content_type) = content_type {
+        req = req.header(CONTENT_TYPE, content_type);
+    }
+    let req = req.body(body.into()).unwrap();
+    let Err(e) = AppJson::<Req>::from_request(req, &()).await else {
+        panic!("extracted");
+    };
+    let res = e.into_response();
+    let status = res.status();
+    let body = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+    (status, serde_json::from_slice(&body).unwrap())
+}
+
+async fn reject_json(body: impl Into<Body>) -> (StatusCode, Value) {
+    reject(Some("application/json"), body).await
+}
+
+#[tokio::test]
+async fn well_formed_bodies_are_extracted() {
+    let req = Request::builder()
+        .method("POST")
+        .uri("/")
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(
+            r#"{"link": {"url": "https://example.com/", "clicks": 3}, "tags": ["a"]}"#,
+        ))
+        .unwrap();
+    let AppJson(req) = AppJson::<Req>::from_request(req, &()).await.unwrap();
+    assert_eq!(req.link.url, "https://example.com/");
+    assert_eq!(req.link.clicks, 3);
+    assert_eq!(req.tags, ["a"]);
+}
+
+#[tokio::test]
+async fn missing_fields_name_where() {
+    let (status, body) =
+        reject_json(r#"{"link": {"url": "https://example.com/"}, "tags": []}"#).await;
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+    assert_eq!(body["error"], "invalid_body");
+    assert_eq!(body["path"], "link");
+    assert!(body["message"]
+        .as_str()
+        .unwrap()
+        .contains("missing field `clicks`"));
+
+    // one missing from the top level has no path to give
+    let (status, body) = reject_json(r#"{"tags": []}"#).await;
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+    assert_eq!(body["error"], "invalid_body");
+    assert!(body.get("path").is_none(), "{}", body);
+}
+
+#[tokio::test]
+async fn wrong_types_name_the_field() {
+    let body = r#"{"link": {"url": "https://example.com/", "clicks": "many"}, "tags": []}"#;
+    let (status, body) = reject_json(body).await;
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+    assert_eq!(body["error"], "invalid_body");
+    assert_eq!(body["path"], "link.clicks");
+
+    let body = r#"{"link": {"url": "https://example.com/", "clicks": 1}, "tags": ["a", 2]}"#;
+    let (_, body) = reject_json(body).await;
+    assert_eq!(body["path"], "tags[1]");
+}
+
+#[tokio::test]
+async fn invalid_utf8_is_malformed() {
+    let mut body = br#"{"link": {"url": "https://example.com/"#.to_vec();
+    body.extend([0xff, 0xfe]);
+    body.extend(br#"", "clicks": 1}, "tags": []}"#);
+    let (status, body) = reject_json(body).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(body["error"], "malformed_json");
+    assert!(body.get("path").is_none(), "{}", body);
+}
+
+#[tokio::test]
+async fn empty_bodies_are_malformed() {
+    let (status, body) = reject_json("").await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(body["error"], "malformed_json");
+    assert!(body.get("path").is_none(), "{}", body);
+
+    // and without a content type the body isn't looked at
+    let (status, body) = reject(None, "").await;
+    assert_eq!(status, StatusCode::UNSUPPORTED_MEDIA_TYPE);
+    assert_eq!(body["error"], "unsupported_media_type");
+    let (status, _) = reject(Some("text/plain"), "{}").await;
+    assert_eq!(status, StatusCode::UNSUPPORTED_MEDIA_TYPE);
+}
@@ -0,0 +1,112 @@
+//! Signed `<id>.<signature>` slugs and redirect signatures.
+
+use shortener::signing::{ResponseSigner, Signer};
+
+#[test]
+fn signed_slugs_verify() {
+    let signer = Signer::new(Some("current"), None);
+    let slug = signer.sign("abc123").unwrap();
+    let (id, signature) = slug.split_once('.').unwrap();
+    assert_eq!(id, "abc123");
+    assert_eq!(signature.len(), 16);
+    assert!(signer.verify(&slug));
+    // deterministic, so a link's slug can be recomputed
+    assert_eq!(signer.sign("abc123").unwrap(), slug);
+}
+
+#[test]
+fn forgeries_are_rejected() {
+    let signer = Signer::new(Some("current"), None);
+    let slug = signer.sign("abc123").unwrap();
+    let signature = slug.split_once('.').unwrap().1;
+    assert!(!signer.verify(&format!("abc124.{}", signature)));
+    assert!(!signer.verify(&slug.to_uppercase()));
+    assert!(!signer.verify(&slug[..slug.len() - 1]));
+    assert!(!signer.verify("abc123"));
+    assert!(!signer.verify("abc123."));
+    assert!(!signer.verify("abc123.+00000000000000f"));
+    // signed with someone else's key
+    let other = Signer::new(Some("other"), None).sign("abc123").unwrap();
+    assert!(!signer.verify(&other));
+}
+
+#[test]
+fn previous_key_keeps_working() {
+    let old = Signer::new(Some("2024"), None).sign("abc123").unwrap();
+    let rotated = Signer::new(Some("2025"), Some("2024"));
+    assert!(rotated.verify(&old));
+    let new = rotated.sign("abc123").unwrap();
+    assert_ne!(new, old);
+    assert!(rotated.verify(&new));
+    // once the previous key is dropped its links stop resolving
+    assert!(!Signer::new(Some("2025"), None).verify(&old));
+}
+
+#[test]
+fn signing_needs_a_current_key() {
+    let old = Signer::new(Some("2024"), None).sign("abc123").unwrap();
+    let winding_down = Signer::new(None, Some("2024"));
+    assert!(!winding_down.can_sign());
+    assert_eq!(winding_down.sign("abc123"), None);
+    assert!(winding_down.verify(&old));
+    assert!(!Signer::new(None, None).verify(&old));
+}
+
+#[test]
+fn redirect_signatures() {
+    let signer = ResponseSigner::new("cdn-debugging");
+    let header = signer.sign("abc123", "https://example.com/", 302, 1_700_000_000);
+    let signature = header.strip_prefix("t=1700000000,v1=").unwrap();
+    assert_eq!(signature.len(), 64);
+    assert!(signer.verify(
+        "abc123",
+        "https://example.com/",
+        302,
+        1_700_000_000,
+        signature
+    ));
+
+    assert!(!signer.verify(
+        "abc124",
+        "https://example.com/",
+        302,
+        1_700_000_000,
+        signature
+    ));
+    assert!(!signer.verify(
+        "abc123",
+        "https://example.org/",
+        302,
+        1_700_000_000,
+        signature
+    ));
+    assert!(!signer.verify(
+        "abc123",
+        "https://example.com/",
+        301,
+        1_700_000_000,
+        signature
+    ));
+    assert!(!signer.verify(
+        "abc123",
+        "https://example.com/",
+        302,
+        1_700_000_001,
+        signature
+    ));
+    assert!(!signer.verify(
+        "abc123",
+        "https://example.com/",
+        302,
+        1_700_000_000,
+        &signature[1..]
+    ));
+    let other = ResponseSigner::new("someone else");
+    assert!(!other.verify(
+        "abc123",
+        "https://example.com/",
+        302,
+        1_700_000_000,
+        signature
+    ));
+}
@@ -0,0 +1,46 @@
+use std::time::{Duration, Instant};
+
+use shortener::throttle::TokenBucket;
+
+#[test]
+fn a_burst_goes_out_at_the_rate() {
+    let now = Instant::now();
+    // 10 a second, 5 saved up
+    let bucket = TokenBucket::new_at("test", 10.0, 5, now);
+    for _ in 0..5 {
+        assert_eq!(bucket.reserve_at(1, now), Duration::ZERO);
+    }
+    assert_eq!(bucket.reserve_at(1, now), Duration::from_millis(100));
+    assert_eq!(bucket.reserve_at(1, now), Duration::from_millis(200));
+
+    // the waiting ones had their tokens by now, nothing is saved up
+    let later = now + Duration::from_millis(200);
+    assert_eq!(bucket.reserve_at(2, later), Duration::from_millis(200));
+}
+
+#[test]
+fn idle_time_saves_up_to_the_burst() {
+    let now = Instant::now();
+    let bucket = TokenBucket::new_at("test", 2.0, 4, now);
+    assert_eq!(bucket.reserve_at(4, now), Duration::ZERO);
+    let later = now + Duration::from_secs(1);
+    assert_eq!(bucket.reserve_at(2, later), Duration::ZERO);
+    assert_eq!(bucket.reserve_at(1, later), Duration::from_millis(500));
+
+    let much_later = later + Duration::from_secs(60);
+    assert_eq!(bucket.reserve_at(4, much_later), Duration::ZERO);
+    assert_eq!(bucket.reserve_at(1, much_later), Duration::from_millis(500));
+}
+
+#[test]
+fn more_than_the_burst_waits_for_the_rest() {
+    let now = Instant::now();
+    let bucket = TokenBucket::new_at("test", 100.0, 10, now);
+    assert_eq!(bucket.burst(), 10);
+    assert_eq!(bucket.reserve_at(60, now), Duration::from_millis(500));
+    // a clock going backwards, as another thread's may, adds nothing
+    assert_eq!(
+        bucket.reserve_at(10, now - Duration::from_secs(1)),
+        Duration::from_millis(600)
+    );
+}
@@ -0,0 +1,119 @@
+//! Tracing destinations through their redirects.
+
+use std::{future::IntoFuture, time::Duration};
+
+use axum::{http::header::LOCATION, http::StatusCode, routing::get, Router};
+use shortener::{
+    fetch::Fetcher,
+    trace::{follow, Outcome},
+};
+use tokio::net::TcpListener;
+use url::Url;
+
+/// Serves `/a` redirecting to `/b`, `/b` to `/c`, which answers, and `/x`
+/// and `/y` redirecting to each other. `/slow` takes its time.
+async fn destination() -> String {
+    let app = Router::new()
+        .route(
+            "/a",
+            get(|| async { (StatusCode::MOVED_PERMANENTLY, [(LOCATION, "/b")]) }),
+        )
+        .route(
+            "/b",
+            get(|| async { (StatusCode::FOUND, [(LOCATION, "c")]) }),
+        )
+        .route("/c", get(|| async { "here" }))
+        .route(
+            "/x",
+            get(|| async { (StatusCode::FOUND, [(LOCATION, "/y")]) }),
+        )
+        .route(
+            "/y",
+            get(|| async { (StatusCode::FOUND, [(LOCATION, "/x")]) }),
+        )
+        .route(
+            "/slow",
+            get(|| async {
+                tokio::time::sleep(Duration::from_secs(5)).await;
+                "late"
+            }),
+        );
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let base = format!("http://{}", listener.local_addr().unwrap());
+    tokio::spawn(axum::serve(listener, app).into_future());
+    base
+}
+
+fn loopback() -> Fetcher {
+    Fetcher::allowing(vec!["127.0.0.0/8".parse().unwrap()])
+}
+
+fn url(base: &str, path: &str) -> Url {
+    Url::parse(&format!("{}{}", base, path)).unwrap()
+}
+
+#[tokio::test]
+async fn both_hops_are_reported() {
+    let base = destination().await;
+    let trace = follow(&loopback(), url(&base, "/a"), 10, Duration::from_secs(5)).await;
+    assert_eq!(trace.outcome, Outcome::Completed);
+    assert_eq!(trace.final_status, Some(200));
+    let hops: Vec<(&str, Option<u16>)> = trace
+        .hops
+        .iter()
+        .map(|hop| (hop.url.as_str(), hop.status))
+        .collect();
+    assert_eq!(
+        hops,
+        [
+            (url(&base, "/a").as_str(), Some(301)),
+            (url(&base, "/b").as_str(), Some(302)),
+            (url(&base, "/c").as_str(), Some(200)),
+        ]
+    );
+    assert_eq!(trace.hops[0].location.as_deref(), Some("/b"));
+    assert_eq!(trace.hops[2].location, None);
+}
+
+#[tokio::test]
+async fn loops_and_long_chains_stop() {
+    let base = destination().await;
+    let trace = follow(&loopback(), url(&base, "/x"), 10, Duration::from_secs(5)).await;
+    assert_eq!(trace.outcome, Outcome::Loop);
+    assert_eq!(trace.hops.len(), 2);
+
+    let trace = follow(&loopback(), url(&base, "/a"), 2, Duration::from_secs(5)).await;
+    assert_eq!(trace.outcome, Outcome::TooManyHops);
+    assert_eq!(trace.hops.len(), 2);
+    assert_eq!(trace.final_status, Some(302));
+}
+
+#[tokio::test]
+async fn slow_and_private_hops_fail() {
+    let base = destination().await;
+    let trace = follow(
+        &loopback(),
+        url(&base, "/slow"),
+        10,
+        Duration::from_millis(100),
+    )
+    .await;
+    assert_eq!(trace.outcome, Outcome::Failed);
+    assert_eq!(trace.final_status, None);
+    assert!(trace.hops[0].error.is_some());
+
+    // without the loopback allowed, nothing is fetched
+    let trace = follow(
+        &Fetcher::new(),
+        url(&base, "/a"),
+        10,
+        Duration::from_secs(5),
+    )
+    .await;
+    assert_eq!(trace.outcome, Outcome::Failed);
+    assert!(trace.hops[0]
+        .error
+        .as_deref()
+        .unwrap()
+        .contains("non-public address"));
+}
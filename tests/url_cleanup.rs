@@ -0,0 +1,169 @@
+//! Urls as users paste them, and what gets stored.
+
+use shortener::idn::{clean, is_relative, normalize, relative};
+
+/// Submitted, then stored; `None` is refused.
+const CASES: &[(&str, Option<&str>)] = &[
+    // already clean
+    ("https://example.com/", Some("https://example.com/")),
+    (
+        "https://example.com/a?b=c#d",
+        Some("https://example.com/a?b=c#d"),
+    ),
+    // surrounding whitespace
+    ("  https://example.com/  ", Some("https://example.com/")),
+    ("\thttps://example.com/\r\n", Some("https://example.com/")),
+    (
+        "\u{a0}https://example.com/\u{a0}",
+        Some("https://example.com/"),
+    ),
+    ("\u{3000}https://example.com/", Some("https://example.com/")),
+    // invisible characters, anywhere
+    ("\u{feff}https://example.com/", Some("https://example.com/")),
+    ("https://exam\u{200b}ple.com/", Some("https://example.com/")),
+    (
+        "https://example.com/pa\u{200d}th\u{2060}",
+        Some("https://example.com/path"),
+    ),
+    (
+        "\u{200e}https://example.com/\u{200f}",
+        Some("https://example.com/"),
+    ),
+    (
+        "https://example.com/\u{ad}docs",
+        Some("https://example.com/docs"),
+    ),
+    (
+        "\u{202a}https://example.com/\u{202c}",
+        Some("https://example.com/"),
+    ),
+    // quotes and brackets around it
+    ("\"https://example.com/\"", Some("https://example.com/")),
+    ("'https://example.com/'", Some("https://example.com/")),
+    ("“https://example.com/”", Some("https://example.com/")),
+    ("‘https://example.com/’", Some("https://example.com/")),
+    ("«https://example.com/»", Some("https://example.com/")),
+    ("`https://example.com/`", Some("https://example.com/")),
+    ("<https://example.com/>", Some("https://example.com/")),
+    ("<URL:https://example.com/>", Some("https://example.com/")),
+    (" “ <https://example.com/> ” ", Some("https://example.com/")),
+    ("\"https://example.com/", Some("https://example.com/")),
+    // line breaks of wrapped emails
+    (
+        "https://example.com/very/\nlong/path",
+        Some("https://example.com/very/long/path"),
+    ),
+    // raw characters are encoded
+    ("https://example.com/a b", Some("https://example.com/a%20b")),
+    (
+        "https://example.com/?q=a b",
+        Some("https://example.com/?q=a%20b"),
+    ),
+    (
+        "https://example.com/café",
+        Some("https://example.com/caf%C3%A9"),
+    ),
+    (
+        "https://example.com/“quoted”/page",
+        Some("https://example.com/%E2%80%9Cquoted%E2%80%9D/page"),
+    ),
+    // mixed raw and encoded, each encoded once
+    (
+        "https://example.com/a%20b c",
+        Some("https://example.com/a%20b%20c"),
+    ),
+    (
+        "https://example.com/caf%C3%A9/café",
+        Some("https://example.com/caf%C3%A9/caf%C3%A9"),
+    ),
+    (
+        "https://example.com/?discount=100%",
+        Some("https://example.com/?discount=100%25"),
+    ),
+    (
+        "https://example.com/50%off",
+        Some("https://example.com/50%25off"),
+    ),
+    (
+        "https://example.com/%zz%2",
+        Some("https://example.com/%25zz%252"),
+    ),
+    (
+        "https://example.com/%2Fencoded",
+        Some("https://example.com/%2Fencoded"),
+    ),
+    // hosts
+    ("HTTPS://EXAMPLE.COM/Path", Some("https://example.com/Path")),
+    (
+        "https://bücher.example/",
+        Some("https://xn--bcher-kva.example/"),
+    ),
+    ("https://example.com./", Some("https://example.com./")),
+    // still not a url
+    ("", None),
+    ("   ", None),
+    ("\"\"", None),
+    ("<>", None),
+    ("example.com", None),
+    ("www.example.com/page", None),
+    ("not a url", None),
+    ("https://", None),
+    ("https://exa mple.com/", None),
+];
+
+#[test]
+fn cleans_up_pasted_urls() {
+    assert!(CASES.len() >= 30);
+    for (submitted, stored) in CASES {
+        assert_eq!(normalize(submitted).as_deref(), *stored, "{:?}", submitted);
+    }
+}
+
+#[test]
+fn stored_forms_are_stable() {
+    for (_, stored) in CASES {
+        if let Some(stored) = stored {
+            assert_eq!(normalize(stored).as_deref(), Some(*stored));
+        }
+    }
+}
+
+#[test]
+fn reports_what_was_attempted() {
+    assert_eq!(clean(" <“example.com”>\u{200b} "), "example.com");
+    assert_eq!(clean("\u{feff}"), "");
+    // inner quotes and spaces are the url's
+    assert_eq!(clean("\"a \"b\" c\""), "a \"b\" c");
+}
+
+#[test]
+fn relative_targets_are_paths() {
+    for (submitted, stored) in [
+        ("/pricing", Some("/pricing")),
+        (" </docs?tab=api#auth> ", Some("/docs?tab=api#auth")),
+        ("./next", Some("./next")),
+        ("../up", Some("../up")),
+        ("//example.com/", None),
+        ("pricing", None),
+        ("not a url", None),
+        ("/with space", None),
+        ("/caf\u{e9}", None),
+        ("", None),
+    ] {
+        assert_eq!(relative(submitted).as_deref(), stored, "{:?}", submitted);
+    }
+}
+
+#[test]
+fn stored_targets_tell_relative_apart() {
+    for (stored, relative) in [
+        ("https://example.com/", false),
+        ("mailto:someone@example.com", false),
+        ("/pricing", true),
+        ("./next", true),
+        ("../up", true),
+        ("//example.com/", true),
+    ] {
+        assert_eq!(is_relative(stored), relative, "{:?}", stored);
+    }
+}